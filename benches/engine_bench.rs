@@ -1,15 +1,18 @@
-use breakout1_kv_store::Engine;
+use breakout1_kv_store::{Engine, Options};
 use criterion::{BatchSize, Criterion, black_box, criterion_group, criterion_main};
 use std::sync::Arc;
 use std::thread;
 use tempfile::NamedTempFile;
 
+// Stores are loaded with size-triggered auto-compaction off, so no
+// benchmark but `compact_after_overwrites` times a compaction.
+
 fn bench_set(c: &mut Criterion) {
     c.bench_function("set_single_key", |b| {
         b.iter_batched(
             || {
                 let file = NamedTempFile::new().unwrap();
-                let engine = Engine::load(file.path()).unwrap();
+                let engine = Engine::load_with_threshold(file.path(), u64::MAX).unwrap();
                 let key = b"bench_set_key".to_vec();
                 (engine, file, key)
             },
@@ -22,30 +25,43 @@ fn bench_set(c: &mut Criterion) {
 }
 
 fn bench_get_existing(c: &mut Criterion) {
-    c.bench_function("get_existing_key", |b| {
-        let file = NamedTempFile::new().unwrap();
-        let engine = Engine::load(file.path()).unwrap();
-        for i in 0..1000u32 {
-            engine
-                .set(
-                    format!("key{}", i).as_bytes(),
-                    format!("value{}", i).as_bytes(),
-                )
-                .unwrap();
-        }
-        let mut i = 0u32;
-        b.iter(|| {
-            let key = format!("key{}", i % 1000);
-            black_box(engine.get(black_box(key.as_bytes())).unwrap());
-            i = i.wrapping_add(1);
+    for (name, latency_histograms) in [
+        ("get_existing_key", false),
+        ("get_existing_key_latency_histograms", true),
+    ] {
+        c.bench_function(name, |b| {
+            let file = NamedTempFile::new().unwrap();
+            let engine = Engine::load_with_options(
+                file.path(),
+                Options {
+                    latency_histograms,
+                    compact_threshold: Some(u64::MAX),
+                    ..Options::default()
+                },
+            )
+            .unwrap();
+            for i in 0..1000u32 {
+                engine
+                    .set(
+                        format!("key{}", i).as_bytes(),
+                        format!("value{}", i).as_bytes(),
+                    )
+                    .unwrap();
+            }
+            let mut i = 0u32;
+            b.iter(|| {
+                let key = format!("key{}", i % 1000);
+                black_box(engine.get(black_box(key.as_bytes())).unwrap());
+                i = i.wrapping_add(1);
+            });
         });
-    });
+    }
 }
 
 fn bench_get_missing(c: &mut Criterion) {
     c.bench_function("get_missing_key", |b| {
         let file = NamedTempFile::new().unwrap();
-        let engine = Engine::load(file.path()).unwrap();
+        let engine = Engine::load_with_threshold(file.path(), u64::MAX).unwrap();
         engine.set(b"exists", b"yes").unwrap();
         b.iter(|| {
             black_box(engine.get(black_box(b"nonexistent")).unwrap());
@@ -53,10 +69,40 @@ fn bench_get_missing(c: &mut Criterion) {
     });
 }
 
+fn bench_contains_key(c: &mut Criterion) {
+    let file = NamedTempFile::new().unwrap();
+    let engine = Engine::load_with_threshold(file.path(), u64::MAX).unwrap();
+    for i in 0..1000u32 {
+        engine
+            .set(format!("key{}", i).as_bytes(), &[7u8; 256])
+            .unwrap();
+    }
+    // Each against `get` of the same keys, which reads and decodes the
+    // record on a hit.
+    for (case, prefix) in [("existing", "key"), ("missing", "absent")] {
+        c.bench_function(&format!("contains_key_{}", case), |b| {
+            let mut i = 0u32;
+            b.iter(|| {
+                let key = format!("{}{}", prefix, i % 1000);
+                black_box(engine.contains_key(black_box(key.as_bytes())).unwrap());
+                i = i.wrapping_add(1);
+            });
+        });
+        c.bench_function(&format!("get_{}_256b_value", case), |b| {
+            let mut i = 0u32;
+            b.iter(|| {
+                let key = format!("{}{}", prefix, i % 1000);
+                black_box(engine.get(black_box(key.as_bytes())).unwrap());
+                i = i.wrapping_add(1);
+            });
+        });
+    }
+}
+
 fn bench_overwrite(c: &mut Criterion) {
     c.bench_function("overwrite_same_key", |b| {
         let file = NamedTempFile::new().unwrap();
-        let engine = Engine::load(file.path()).unwrap();
+        let engine = Engine::load_with_threshold(file.path(), u64::MAX).unwrap();
         let mut i = 0u64;
         b.iter(|| {
             engine
@@ -70,7 +116,7 @@ fn bench_overwrite(c: &mut Criterion) {
 fn bench_delete(c: &mut Criterion) {
     c.bench_function("delete_key", |b| {
         let file = NamedTempFile::new().unwrap();
-        let engine = Engine::load(file.path()).unwrap();
+        let engine = Engine::load_with_threshold(file.path(), u64::MAX).unwrap();
 
         for idx in 0..100_000u64 {
             let key = format!("key{}", idx);
@@ -90,7 +136,7 @@ fn bench_delete(c: &mut Criterion) {
 fn bench_set_delete(c: &mut Criterion) {
     c.bench_function("set_delete_key", |b| {
         let file = NamedTempFile::new().unwrap();
-        let engine = Engine::load(file.path()).unwrap();
+        let engine = Engine::load_with_threshold(file.path(), u64::MAX).unwrap();
         let mut i = 0u64;
         b.iter(|| {
             let key = format!("key{}", i);
@@ -104,7 +150,7 @@ fn bench_set_delete(c: &mut Criterion) {
 fn bench_set_then_get(c: &mut Criterion) {
     c.bench_function("set_then_get", |b| {
         let file = NamedTempFile::new().unwrap();
-        let engine = Engine::load(file.path()).unwrap();
+        let engine = Engine::load_with_threshold(file.path(), u64::MAX).unwrap();
         let mut i = 0u64;
         b.iter(|| {
             let key = format!("key{}", i);
@@ -120,7 +166,7 @@ fn bench_compact(c: &mut Criterion) {
         b.iter_batched(
             || {
                 let file = NamedTempFile::new().unwrap();
-                let engine = Engine::load(file.path()).unwrap();
+                let engine = Engine::load_with_threshold(file.path(), u64::MAX).unwrap();
                 for i in 0..500u32 {
                     engine.set(b"k", &i.to_le_bytes()).unwrap();
                 }
@@ -139,7 +185,7 @@ fn bench_load_rebuild_index(c: &mut Criterion) {
         let file = NamedTempFile::new().unwrap();
         let path = file.path().to_owned();
         {
-            let engine = Engine::load(&path).unwrap();
+            let engine = Engine::load_with_threshold(&path, u64::MAX).unwrap();
             for i in 0..1000u32 {
                 engine
                     .set(
@@ -158,7 +204,7 @@ fn bench_load_rebuild_index(c: &mut Criterion) {
 fn bench_large_value(c: &mut Criterion) {
     c.bench_function("set_get_4kb_value", |b| {
         let file = NamedTempFile::new().unwrap();
-        let engine = Engine::load(file.path()).unwrap();
+        let engine = Engine::load_with_threshold(file.path(), u64::MAX).unwrap();
         let large_val = vec![0xABu8; 4096];
         let mut i = 0u64;
         b.iter(|| {
@@ -170,12 +216,35 @@ fn bench_large_value(c: &mut Criterion) {
     });
 }
 
+fn bench_range_read(c: &mut Criterion) {
+    let file = NamedTempFile::new().unwrap();
+    let engine = Engine::load_with_threshold(file.path(), u64::MAX).unwrap();
+    let value = vec![0xABu8; 4 * 1024 * 1024];
+    engine.set(b"big", &value).unwrap();
+
+    c.bench_function("get_full_4mb_value", |b| {
+        b.iter(|| {
+            black_box(engine.get(black_box(b"big")).unwrap());
+        });
+    });
+
+    c.bench_function("get_range_4kb_of_4mb_value", |b| {
+        b.iter(|| {
+            black_box(
+                engine
+                    .get_range(black_box(b"big"), black_box(1024 * 1024), 4096)
+                    .unwrap(),
+            );
+        });
+    });
+}
+
 fn bench_concurrent_reads(c: &mut Criterion) {
     c.bench_function("concurrent_reads_8_threads", |b| {
         b.iter_batched(
             || {
                 let file = NamedTempFile::new().unwrap();
-                let engine = Arc::new(Engine::load(file.path()).unwrap());
+                let engine = Arc::new(Engine::load_with_threshold(file.path(), u64::MAX).unwrap());
                 for i in 0..1000u32 {
                     engine
                         .set(
@@ -210,7 +279,7 @@ fn bench_concurrent_writes(c: &mut Criterion) {
         b.iter_batched(
             || {
                 let file = NamedTempFile::new().unwrap();
-                let engine = Arc::new(Engine::load(file.path()).unwrap());
+                let engine = Arc::new(Engine::load_with_threshold(file.path(), u64::MAX).unwrap());
                 (engine, file)
             },
             |(engine, _file)| {
@@ -233,12 +302,43 @@ fn bench_concurrent_writes(c: &mut Criterion) {
     });
 }
 
+fn bench_sustained_writes(c: &mut Criterion) {
+    for (name, chunk) in [
+        ("sustained_writes_10k_no_prealloc", None),
+        ("sustained_writes_10k_prealloc_16mb", Some(16 * 1024 * 1024)),
+    ] {
+        c.bench_function(name, |b| {
+            b.iter_batched(
+                || {
+                    let file = NamedTempFile::new().unwrap();
+                    let engine = Engine::load_with_options(
+                        file.path(),
+                        Options {
+                            preallocate_chunk: chunk,
+                            compact_threshold: Some(u64::MAX),
+                            ..Options::default()
+                        },
+                    )
+                    .unwrap();
+                    (engine, file)
+                },
+                |(engine, _file)| {
+                    for i in 0..10_000u32 {
+                        engine.set(&i.to_le_bytes(), b"sustained-value").unwrap();
+                    }
+                },
+                BatchSize::PerIteration,
+            );
+        });
+    }
+}
+
 fn bench_mixed_workload(c: &mut Criterion) {
     c.bench_function("mixed_set_get_del_1000_ops", |b| {
         b.iter_batched(
             || {
                 let file = NamedTempFile::new().unwrap();
-                let engine = Engine::load(file.path()).unwrap();
+                let engine = Engine::load_with_threshold(file.path(), u64::MAX).unwrap();
                 (engine, file)
             },
             |(engine, _file)| {
@@ -258,11 +358,43 @@ fn bench_mixed_workload(c: &mut Criterion) {
     });
 }
 
+fn bench_dump(c: &mut Criterion) {
+    for (name, readahead_bytes) in [
+        (
+            "dump_100k_keys_readahead",
+            Options::default().readahead_bytes,
+        ),
+        ("dump_100k_keys_record_by_record", 0),
+    ] {
+        c.bench_function(name, |b| {
+            let file = NamedTempFile::new().unwrap();
+            let engine = Engine::load_with_options(
+                file.path(),
+                Options {
+                    readahead_bytes,
+                    compact_threshold: Some(u64::MAX),
+                    ..Options::default()
+                },
+            )
+            .unwrap();
+            for i in 0..100_000u32 {
+                engine.set(&i.to_be_bytes(), &[0u8; 100]).unwrap();
+            }
+            let mut out = Vec::new();
+            b.iter(|| {
+                out.clear();
+                black_box(engine.dump(&mut out).unwrap());
+            });
+        });
+    }
+}
+
 criterion_group!(
     benches,
     bench_set,
     bench_get_existing,
     bench_get_missing,
+    bench_contains_key,
     bench_overwrite,
     bench_delete,
     bench_set_delete,
@@ -270,8 +402,11 @@ criterion_group!(
     bench_compact,
     bench_load_rebuild_index,
     bench_large_value,
+    bench_range_read,
     bench_concurrent_reads,
     bench_concurrent_writes,
     bench_mixed_workload,
+    bench_sustained_writes,
+    bench_dump,
 );
 criterion_main!(benches);
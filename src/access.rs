@@ -0,0 +1,95 @@
+//! Approximate per-key read tracking behind `Options::track_access`.
+//!
+//! Read counts and last-read times live in two count-min sketches: `DEPTH`
+//! rows of `WIDTH` slots, each key hashing to one slot per row. A read bumps
+//! the key's slot in every row, and a lookup takes the smallest of them, so
+//! a collision can only make a key look hotter or more recently read than
+//! it is, never colder. Memory is fixed however many keys there are, reads
+//! update it with atomics and no lock, and nothing is written to the log:
+//! the sketches start empty on every load.
+
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::mem::size_of;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::clock::Clock;
+
+/// Slots per row; a power of two.
+const WIDTH: usize = 1 << 14;
+const DEPTH: usize = 4;
+
+pub(crate) struct AccessTracker {
+    /// One independently seeded hash per row.
+    rows: [RandomState; DEPTH],
+    counts: Box<[AtomicU64]>,
+    /// Milliseconds from `start` to the last read, plus one; 0 for never.
+    last_read: Box<[AtomicU64]>,
+    clock: Arc<dyn Clock>,
+    start: Instant,
+}
+
+impl AccessTracker {
+    pub(crate) fn new(clock: Arc<dyn Clock>) -> Self {
+        let slots = || (0..WIDTH * DEPTH).map(|_| AtomicU64::new(0)).collect();
+        AccessTracker {
+            rows: std::array::from_fn(|_| RandomState::new()),
+            counts: slots(),
+            last_read: slots(),
+            start: clock.now(),
+            clock,
+        }
+    }
+
+    /// Notes one read of `key`, now.
+    pub(crate) fn record(&self, key: &[u8]) {
+        let now = self.millis_since_start() + 1;
+        for slot in self.slots(key) {
+            self.counts[slot].fetch_add(1, Ordering::Relaxed);
+            self.last_read[slot].fetch_max(now, Ordering::Relaxed);
+        }
+    }
+
+    /// Reads of `key` so far, possibly overcounted.
+    pub(crate) fn reads(&self, key: &[u8]) -> u64 {
+        self.slots(key)
+            .map(|slot| self.counts[slot].load(Ordering::Relaxed))
+            .min()
+            .unwrap_or(0)
+    }
+
+    /// How long ago `key` was last read, possibly underestimated; `None` if
+    /// it never was.
+    pub(crate) fn idle_for(&self, key: &[u8]) -> Option<Duration> {
+        let last = self
+            .slots(key)
+            .map(|slot| self.last_read[slot].load(Ordering::Relaxed))
+            .min()?;
+        let last = last.checked_sub(1)?;
+        Some(Duration::from_millis(
+            self.millis_since_start().saturating_sub(last),
+        ))
+    }
+
+    /// Heap bytes held by the sketches.
+    pub(crate) fn memory_bytes(&self) -> u64 {
+        (2 * WIDTH * DEPTH * size_of::<AtomicU64>()) as u64
+    }
+
+    fn millis_since_start(&self) -> u64 {
+        self.clock
+            .now()
+            .saturating_duration_since(self.start)
+            .as_millis() as u64
+    }
+
+    /// The slot `key` hashes to in each row, as indexes into the sketches.
+    fn slots<'a>(&'a self, key: &'a [u8]) -> impl Iterator<Item = usize> + 'a {
+        self.rows
+            .iter()
+            .enumerate()
+            .map(move |(row, hasher)| row * WIDTH + (hasher.hash_one(key) as usize & (WIDTH - 1)))
+    }
+}
@@ -0,0 +1,185 @@
+//! The cold tier kept under `Options::archive_path`.
+//!
+//! `Engine::archive` copies values out of the data file into the archive
+//! and replaces each key's record with an `Archived` stub naming where its
+//! value went. The archive is laid out like a data file, a header and then
+//! `Put` records, and is only ever appended to: a record stays in it after
+//! its key is written again or deleted, and compacting the data file keeps
+//! stubs as they are without touching it. Records are fsynced before the
+//! stubs pointing at them are appended, so a crash can leave a record
+//! nobody points at, never a stub pointing at nothing.
+//!
+//! Reads go through one handle under a mutex; the tier is meant for values
+//! that are rarely read.
+
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::codec;
+use crate::constants::{FILE_HEADER_MAGIC, FORMAT_VERSION};
+use crate::error::EngineError;
+use crate::format::{self, Header};
+use crate::storage::{OpenMode, Storage, StorageFile};
+use crate::types::{DataFileEntry, LogIndex};
+
+pub(crate) struct Archive {
+    path: PathBuf,
+    /// `None` until the file is first opened or created.
+    file: Mutex<Option<Box<dyn StorageFile>>>,
+    /// Bytes in the file, header included; 0 while there is none.
+    size: AtomicU64,
+}
+
+impl Archive {
+    pub(crate) fn new(path: PathBuf) -> Self {
+        Archive {
+            path,
+            file: Mutex::new(None),
+            size: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Opens the archive file if it exists. With `required`, because the
+    /// data file holds stubs, a missing file fails with
+    /// `EngineError::ArchiveMissing`. Call with the file mutex held.
+    pub(crate) fn open(&self, storage: &dyn Storage, required: bool) -> io::Result<()> {
+        let mut state = self.file.lock().unwrap();
+        *state = None;
+        self.size.store(0, Ordering::Release);
+        match storage.open(&self.path, OpenMode::Read) {
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound && required => {
+                return Err(EngineError::ArchiveMissing(format!(
+                    "{} does not exist",
+                    self.path.display()
+                ))
+                .into());
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        }
+        *state = Some(self.open_file(storage)?);
+        Ok(())
+    }
+
+    /// Opens the file for appending, creating it with a header if it is
+    /// missing or empty, and checks the header of one that is not.
+    fn open_file(&self, storage: &dyn Storage) -> io::Result<Box<dyn StorageFile>> {
+        let mut file = storage.open(&self.path, OpenMode::ReadWrite)?;
+        let len = match file.len()? {
+            0 => {
+                let header = Header {
+                    format_version: FORMAT_VERSION,
+                    compact_threshold: 0,
+                    epoch: 0,
+                    flags: 0,
+                };
+                format::write_header(&mut *file, &header)?;
+                file.flush()?;
+                file.sync_all()?;
+                header.size()
+            }
+            len => {
+                file.seek(SeekFrom::Start(0))?;
+                let header = format::read_header(&mut *file)?;
+                // KVS7 only adds origins to the KVS6 layout, so a KVS6
+                // archive is relabelled rather than refused.
+                if header.format_version == 6 {
+                    file.seek(SeekFrom::Start(0))?;
+                    file.write_all(&FILE_HEADER_MAGIC)?;
+                    file.sync_all()?;
+                } else if header.format_version != FORMAT_VERSION {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!(
+                            "{}: archive is in format version {}, not {}",
+                            self.path.display(),
+                            header.format_version,
+                            FORMAT_VERSION
+                        ),
+                    ));
+                }
+                len
+            }
+        };
+        self.size.store(len, Ordering::Release);
+        Ok(file)
+    }
+
+    /// Appends `records` and fsyncs them, creating the file on first use.
+    /// Returns where each record landed. Call with the file mutex held.
+    pub(crate) fn append(
+        &self,
+        storage: &dyn Storage,
+        records: &[DataFileEntry],
+    ) -> io::Result<Vec<LogIndex>> {
+        let mut state = self.file.lock().unwrap();
+        if state.is_none() {
+            *state = Some(self.open_file(storage)?);
+        }
+        let file = state.as_mut().unwrap();
+
+        let mut buf = Vec::new();
+        let mut spans = Vec::with_capacity(records.len());
+        for record in records {
+            spans.push(format::encode_record(FORMAT_VERSION, record, &mut buf)?);
+        }
+        let start = self.size.load(Ordering::Acquire);
+        let written = file
+            .seek(SeekFrom::Start(start))
+            .and_then(|_| file.write_all(&buf))
+            .and_then(|()| file.flush())
+            .and_then(|()| file.sync_all());
+        if let Err(e) = written {
+            // Best effort: nothing points past `start` yet either way.
+            let _ = file.set_len(start);
+            return Err(e);
+        }
+        self.size.store(start + buf.len() as u64, Ordering::Release);
+
+        Ok(spans
+            .into_iter()
+            .map(|span| LogIndex {
+                pos: start + span.pos,
+                len: span.len,
+            })
+            .collect())
+    }
+
+    /// Reads the archived record at `at`.
+    pub(crate) fn read(&self, at: &LogIndex) -> io::Result<DataFileEntry> {
+        let mut state = self.file.lock().unwrap();
+        let Some(file) = &mut *state else {
+            return Err(EngineError::ArchiveMissing(format!(
+                "{} is not open",
+                self.path.display()
+            ))
+            .into());
+        };
+        if at.pos.saturating_add(at.len) > self.size.load(Ordering::Acquire) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{}: archived record at offset {} runs past the end of the file",
+                    self.path.display(),
+                    at.pos
+                ),
+            ));
+        }
+        file.seek(SeekFrom::Start(at.pos))?;
+        let mut data = vec![0u8; at.len as usize];
+        file.read_exact(&mut data)?;
+        codec::for_version(FORMAT_VERSION).decode(&data)
+    }
+
+    /// Bytes in the archive file, header included.
+    pub(crate) fn size(&self) -> u64 {
+        self.size.load(Ordering::Acquire)
+    }
+}
@@ -0,0 +1,134 @@
+//! Record-by-record reading of the whole log, behind `Engine::audit_scan`.
+//!
+//! Under `Options::audit_mode` nothing is ever compacted away, so the scan
+//! sees every write the store has accepted: overwritten values, tombstones,
+//! and the records of every batch, in the order they were appended.
+//! [`PendingTombstones`] filters the same scan down to the deletes that
+//! compaction would still drop.
+
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+
+use crate::codec;
+use crate::engine::{Engine, FileHandle};
+use crate::framing;
+use crate::types::{DataFileEntry, EntryKind, LogIndex};
+
+/// One record of the log.
+#[derive(Debug, Clone)]
+pub struct AuditRecord {
+    /// Position in the log, counting from 0 at the first record. Stable
+    /// across reloads as long as the file is never compacted.
+    pub seq: u64,
+    /// Where the record sits, past its length prefix.
+    pub index: LogIndex,
+    pub entry: DataFileEntry,
+}
+
+/// Every record up to the end of the log when the scan started, oldest
+/// first; see [`crate::Engine::audit_scan`]. Reads through its own handle,
+/// so writes are never blocked. Stops after the first error.
+pub struct AuditScan {
+    reader: BufReader<FileHandle>,
+    format_version: u8,
+    pos: u64,
+    end: u64,
+    seq: u64,
+    failed: bool,
+}
+
+impl AuditScan {
+    pub(crate) fn new(
+        mut reader: FileHandle,
+        format_version: u8,
+        start: u64,
+        end: u64,
+        readahead_bytes: usize,
+    ) -> io::Result<Self> {
+        reader.seek(SeekFrom::Start(start))?;
+        Ok(AuditScan {
+            reader: BufReader::with_capacity(
+                readahead_bytes.min(end.saturating_sub(start) as usize),
+                reader,
+            ),
+            format_version,
+            pos: start,
+            end,
+            seq: 0,
+            failed: false,
+        })
+    }
+
+    fn read_next(&mut self) -> io::Result<AuditRecord> {
+        let (len, prefix_len) = framing::read_len(self.format_version, &mut self.reader)?
+            .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+        let data_pos = self.pos + prefix_len;
+        if len == 0 || data_pos + len > self.end {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("bad record length {} at offset {}", len, self.pos),
+            ));
+        }
+        let mut data = vec![0u8; len as usize];
+        self.reader.read_exact(&mut data)?;
+        let entry = codec::for_version(self.format_version)
+            .decode(&data)
+            .map_err(|e| {
+                io::Error::new(e.kind(), format!("record at offset {}: {}", data_pos, e))
+            })?;
+
+        let record = AuditRecord {
+            seq: self.seq,
+            index: LogIndex { pos: data_pos, len },
+            entry,
+        };
+        self.seq += 1;
+        self.pos = data_pos + len;
+        Ok(record)
+    }
+}
+
+impl Iterator for AuditScan {
+    type Item = io::Result<AuditRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed || self.pos >= self.end {
+            return None;
+        }
+        let record = self.read_next();
+        self.failed = record.is_err();
+        Some(record)
+    }
+}
+
+/// Tombstones in the log whose key is not live, as `(key, deletion tstamp)`,
+/// oldest first; see [`crate::Engine::pending_tombstones`]. A key deleted
+/// several times yields each tombstone. Liveness is checked as each record
+/// is reached, so a key set again in the meantime is skipped. Stops after
+/// the first error.
+pub struct PendingTombstones<'a> {
+    engine: &'a Engine,
+    scan: AuditScan,
+}
+
+impl<'a> PendingTombstones<'a> {
+    pub(crate) fn new(engine: &'a Engine, scan: AuditScan) -> Self {
+        PendingTombstones { engine, scan }
+    }
+}
+
+impl Iterator for PendingTombstones<'_> {
+    type Item = io::Result<(Vec<u8>, i64)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let record = match self.scan.next()? {
+                Ok(record) => record,
+                Err(e) => return Some(Err(e)),
+            };
+            if record.entry.kind != EntryKind::Tombstone || self.engine.is_live(&record.entry.key) {
+                continue;
+            }
+            return Some(Ok((record.entry.key, record.entry.tstamp)));
+        }
+    }
+}
@@ -0,0 +1,284 @@
+use std::collections::HashMap;
+use std::io;
+use std::time::Duration;
+
+use crate::codec;
+use crate::constants::FORMAT_VERSION;
+use crate::engine::{Engine, now_millis};
+use crate::framing;
+use crate::types::{DataFileEntry, EntryKind, ORIGIN_SIZE};
+
+/// A set of writes applied together by [`Engine::apply_batch`].
+///
+/// Entries are encoded as they are added, into one buffer that `apply_batch`
+/// appends with a single write. The buffer starts with a `BatchBegin` record
+/// counting the entries, and replay skips a batch whose entries are not all
+/// on disk, so a crash never leaves half a batch applied.
+///
+/// When several operations name the same key, the last one wins, as if they
+/// had been applied one by one. Unless [`dedup`](Self::dedup) is turned off,
+/// only that last operation is written.
+#[derive(Debug)]
+pub struct WriteBatch {
+    buf: Vec<u8>,
+    /// Length of the leading `BatchBegin` record, and of its prefix.
+    header_len: usize,
+    header_prefix_len: usize,
+    pub(crate) ops: Vec<BatchOp>,
+    /// First encoding failure, reported by `apply_batch`.
+    error: Option<String>,
+    dedup: bool,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct BatchOp {
+    pub(crate) kind: EntryKind,
+    pub(crate) key: Vec<u8>,
+    pub(crate) expires_at: Option<i64>,
+    /// The TTL `put_with_ttl` was given, in ms, which `Options::ttl_jitter`
+    /// spreads. `None` for absolute expiries, which are never moved.
+    pub(crate) ttl: Option<i64>,
+    pub(crate) tstamp: i64,
+    /// Offset of the record (after its length prefix) within the buffer.
+    pub(crate) offset: u64,
+    pub(crate) len: u64,
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        let mut batch = WriteBatch {
+            buf: Vec::new(),
+            header_len: 0,
+            header_prefix_len: 0,
+            ops: Vec::new(),
+            error: None,
+            dedup: true,
+        };
+        batch.clear();
+        batch
+    }
+
+    pub fn put(&mut self, key: &[u8], value: &[u8]) -> &mut Self {
+        self.push(DataFileEntry::put(
+            now_millis(),
+            key.to_vec(),
+            value.to_vec(),
+        ))
+    }
+
+    /// Puts `key` so that it reads as absent once `ttl` has passed. Expired
+    /// keys are dropped by the next compaction.
+    pub fn put_with_ttl(&mut self, key: &[u8], value: &[u8], ttl: Duration) -> &mut Self {
+        let now = now_millis();
+        let ttl = ttl.as_millis().min(i64::MAX as u128) as i64;
+        let entry = DataFileEntry::expiring_put(now, key.to_vec(), value, now.saturating_add(ttl));
+        self.push_op(entry, Some(ttl))
+    }
+
+    /// `put_with_ttl` with an absolute expiry, in ms since the epoch.
+    pub(crate) fn put_expiring_at(
+        &mut self,
+        key: &[u8],
+        value: &[u8],
+        expires_at: i64,
+    ) -> &mut Self {
+        self.push(DataFileEntry::expiring_put(
+            now_millis(),
+            key.to_vec(),
+            value,
+            expires_at,
+        ))
+    }
+
+    pub fn delete(&mut self, key: &[u8]) -> &mut Self {
+        self.push(DataFileEntry::tombstone(now_millis(), key.to_vec()))
+    }
+
+    /// Whether `apply_batch` writes only the last operation on each key,
+    /// which is the default. Turned off, every operation is written and
+    /// applied in order, with the same outcome, so an `Options::audit_mode`
+    /// store keeps a record of each.
+    pub fn dedup(&mut self, on: bool) -> &mut Self {
+        self.dedup = on;
+        self
+    }
+
+    /// Empties the batch, keeping its buffer for reuse.
+    pub fn clear(&mut self) {
+        self.buf.clear();
+        self.ops.clear();
+        self.error = None;
+        self.header_len = 0;
+        self.write_header();
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    /// Bytes `apply_batch` will append, framing included, before `dedup`
+    /// drops any superseded operations.
+    pub fn encoded_size(&self) -> usize {
+        self.buf.len()
+    }
+
+    pub(crate) fn encoded(&self) -> io::Result<&[u8]> {
+        match &self.error {
+            Some(e) => Err(io::Error::other(e.clone())),
+            None => Ok(&self.buf),
+        }
+    }
+
+    /// Offset and length of the leading `BatchBegin` record within the
+    /// buffer, prefix excluded.
+    pub(crate) fn header_span(&self) -> (u64, u64) {
+        (
+            self.header_prefix_len as u64,
+            (self.header_len - self.header_prefix_len) as u64,
+        )
+    }
+
+    /// This batch with only the last operation on each key, and for each of
+    /// this batch's operations, the position of the one that stands for it
+    /// there. `None` when nothing would be dropped, or `dedup` is off.
+    pub(crate) fn collapsed(&self) -> Option<(WriteBatch, Vec<usize>)> {
+        if !self.dedup || self.error.is_some() {
+            return None;
+        }
+        let mut last: HashMap<&[u8], usize> = HashMap::with_capacity(self.ops.len());
+        for (i, op) in self.ops.iter().enumerate() {
+            last.insert(&op.key, i);
+        }
+        if last.len() == self.ops.len() {
+            return None;
+        }
+
+        let mut collapsed = WriteBatch::new();
+        let mut positions = vec![0; self.ops.len()];
+        for (i, op) in self.ops.iter().enumerate() {
+            if last[op.key.as_slice()] == i {
+                positions[i] = collapsed.ops.len();
+                let data = &self.buf[op.offset as usize..][..op.len as usize];
+                collapsed.push_encoded(op.clone(), data);
+            }
+        }
+        let stands_for = self
+            .ops
+            .iter()
+            .map(|op| positions[last[op.key.as_slice()]])
+            .collect();
+        Some((collapsed, stands_for))
+    }
+
+    /// This batch with every key replaced by `f` of it, for
+    /// `Options::key_transform`.
+    pub(crate) fn with_keys(&self, f: impl Fn(&[u8]) -> Vec<u8>) -> io::Result<WriteBatch> {
+        self.map_entries(|entry| entry.key = f(&entry.key))
+    }
+
+    /// This batch with `origin` as the origin of every entry, for
+    /// `Options::writer_id`.
+    pub(crate) fn with_origin(&self, origin: [u8; ORIGIN_SIZE]) -> io::Result<WriteBatch> {
+        self.map_entries(|entry| entry.origin = Some(origin))
+    }
+
+    /// This batch with `f` applied to every entry.
+    fn map_entries(&self, f: impl Fn(&mut DataFileEntry)) -> io::Result<WriteBatch> {
+        let mut mapped = WriteBatch::new();
+        mapped.dedup = self.dedup;
+        mapped.error = self.error.clone();
+        for op in &self.ops {
+            let data = &self.buf[op.offset as usize..][..op.len as usize];
+            let mut entry = codec::for_version(FORMAT_VERSION).decode(data)?;
+            f(&mut entry);
+            mapped.push_op(entry, op.ttl);
+        }
+        Ok(mapped)
+    }
+
+    fn push(&mut self, entry: DataFileEntry) -> &mut Self {
+        self.push_op(entry, None)
+    }
+
+    fn push_op(&mut self, entry: DataFileEntry, ttl: Option<i64>) -> &mut Self {
+        let data = match Engine::encode_entry(FORMAT_VERSION, &entry) {
+            Ok(data) => data,
+            Err(e) => {
+                self.error.get_or_insert(e.to_string());
+                return self;
+            }
+        };
+        let op = BatchOp {
+            kind: entry.kind,
+            expires_at: entry.expires_at(),
+            ttl,
+            tstamp: entry.tstamp,
+            key: entry.key,
+            offset: 0,
+            len: 0,
+        };
+        self.push_encoded(op, &data)
+    }
+
+    /// Appends `op`, whose encoded record is `data`, placing it at the end
+    /// of the buffer.
+    fn push_encoded(&mut self, mut op: BatchOp, data: &[u8]) -> &mut Self {
+        framing::write_len(FORMAT_VERSION, data.len() as u64, &mut self.buf);
+        op.offset = self.buf.len() as u64;
+        op.len = data.len() as u64;
+        self.ops.push(op);
+        self.buf.extend_from_slice(data);
+        self.write_header();
+        self
+    }
+
+    /// Rewrites the leading `BatchBegin` record with the current count. Its
+    /// encoding has a fixed size, so entries never move.
+    fn write_header(&mut self) {
+        let header = batch_begin(self.ops.len() as u64);
+        let data =
+            Engine::encode_entry(FORMAT_VERSION, &header).expect("a batch header always encodes");
+        let mut record = framing::encode_len(FORMAT_VERSION, data.len() as u64);
+        let prefix_len = record.len();
+        record.extend_from_slice(&data);
+
+        if self.header_len == 0 {
+            self.header_len = record.len();
+            self.header_prefix_len = prefix_len;
+            self.buf.splice(0..0, record);
+        } else {
+            self.buf[..self.header_len].copy_from_slice(&record);
+        }
+    }
+}
+
+impl Default for WriteBatch {
+    fn default() -> Self {
+        WriteBatch::new()
+    }
+}
+
+/// The `BatchBegin` record announcing `count` entries after it.
+pub(crate) fn batch_begin(count: u64) -> DataFileEntry {
+    DataFileEntry {
+        tstamp: 0,
+        key: Vec::new(),
+        value: Some(count.to_le_bytes().to_vec()),
+        kind: EntryKind::BatchBegin,
+        origin: None,
+    }
+}
+
+/// Number of entries announced by a `BatchBegin` record.
+pub(crate) fn batch_len(entry: &DataFileEntry) -> u64 {
+    entry
+        .value
+        .as_deref()
+        .and_then(|v| v.try_into().ok())
+        .map(u64::from_le_bytes)
+        .unwrap_or(0)
+}
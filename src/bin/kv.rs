@@ -0,0 +1,336 @@
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Write};
+use std::process::ExitCode;
+
+use breakout1_kv_store::dump::{self, DumpWriter};
+use breakout1_kv_store::patch::PatchChange;
+use breakout1_kv_store::workload::{self, WorkloadSpec};
+use breakout1_kv_store::{ConflictPolicy, Engine};
+
+const USAGE: &str = "usage:
+  kv dump <db> <out>      write a logical dump of <db> to <out>
+  kv restore <db> <in>    restore the dump <in> into <db>
+  kv export <db> --prefix <p> --out <out>
+                          dump the keys under <p> to <out> as of one point in
+                          time, check the written file against its manifest,
+                          and print the manifest
+  kv sample <db> --fraction <f> --out <out> [--seed <n>] [--max-bytes <n>]
+                          write a deterministic sample of <db> to <out> as a dump
+  kv patch apply <db> <patch> [--dry-run] [--force]
+                          check and apply the patch file <patch> to <db> as one
+                          batch, printing what each entry does; any entry whose
+                          key does not hold what it expects refuses the whole
+                          patch unless --force
+  kv patch generate <db> <target> --out <patch>
+                          write a patch that turns <db> into <target>, each
+                          entry expecting what <db> holds now
+  kv stats <db>           print key counts, sizes, and the disk usage forecast
+  kv tombstones <db>      list the tombstones compaction would drop, oldest
+                          first, as <deleted at ms>\t<key>
+  kv ingest --from <src> --into <db> [--policy <accept|reject-older|keep-newest>]
+                          recover what records <src> still holds, stepping over
+                          damage, and write them into <db>; conflicts with
+                          newer values in <db> keep the newer by default
+  kv inspect-format <db>  describe the on-disk format of <db>, even one this
+                          build cannot open
+  kv bench --db <db> [--threads <n>] [--ops <n>] [--mix <70get/25set/5del>]
+           [--value-size <n>] [--key-space <n>] [--distribution <uniform|zipfian>]
+           [--seed <n>] [--json]
+                          run a load against <db> and report throughput,
+                          latency, file size, and compactions; counts take
+                          k/m/g suffixes or exponents (1m, 1e6)";
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+
+    let result = match args.as_slice() {
+        ["dump", db, out] => dump(db, out),
+        ["restore", db, input] => restore(db, input),
+        ["export", db, flags @ ..] => export(db, flags),
+        ["sample", db, flags @ ..] => sample(db, flags),
+        ["patch", "apply", db, patch, flags @ ..] => patch_apply(db, patch, flags),
+        ["patch", "generate", db, target, "--out", out] => patch_generate(db, target, out),
+        ["stats", db] => stats(db),
+        ["tombstones", db] => tombstones(db),
+        ["ingest", flags @ ..] => ingest(flags),
+        ["inspect-format", db] => inspect_format(db),
+        ["bench", flags @ ..] => bench(flags),
+        _ => {
+            eprintln!("{}", USAGE);
+            return ExitCode::from(2);
+        }
+    };
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn dump(db: &str, out: &str) -> io::Result<()> {
+    let engine = Engine::load(db)?;
+    let count = engine.dump(BufWriter::new(File::create(out)?))?;
+    println!("dumped {} records to {}", count, out);
+    Ok(())
+}
+
+fn restore(db: &str, input: &str) -> io::Result<()> {
+    let engine = Engine::load(db)?;
+    let count = engine.load_dump(BufReader::new(File::open(input)?))?;
+    println!("restored {} records into {}", count, db);
+    Ok(())
+}
+
+fn export(db: &str, flags: &[&str]) -> io::Result<()> {
+    let mut prefix = None;
+    let mut out = None;
+    for pair in flags.chunks(2) {
+        match pair {
+            ["--prefix", v] => prefix = Some(*v),
+            ["--out", v] => out = Some(*v),
+            _ => return Err(usage_error()),
+        }
+    }
+    let (Some(prefix), Some(out)) = (prefix, out) else {
+        return Err(usage_error());
+    };
+
+    let engine = Engine::load(db)?;
+    let report = engine.export_prefix(prefix.as_bytes(), BufWriter::new(File::create(out)?))?;
+    dump::verify_subset(BufReader::new(File::open(out)?), &report)?;
+    println!("store:      {}", report.store.display());
+    println!("prefix:     {}", prefix);
+    println!("keys:       {}", report.keys);
+    println!("bytes:      {}", report.bytes);
+    println!("generation: {}", report.generation);
+    println!("log end:    {}", report.log_end);
+    println!("crc32:      {:08x}", report.crc);
+    Ok(())
+}
+
+fn sample(db: &str, flags: &[&str]) -> io::Result<()> {
+    let mut fraction = None;
+    let mut out = None;
+    let mut seed = 0;
+    let mut max_bytes = None;
+    for pair in flags.chunks(2) {
+        match pair {
+            ["--fraction", v] => fraction = Some(parse_flag(v, "--fraction")?),
+            ["--out", v] => out = Some(*v),
+            ["--seed", v] => seed = parse_flag(v, "--seed")?,
+            ["--max-bytes", v] => max_bytes = Some(parse_flag(v, "--max-bytes")?),
+            _ => return Err(usage_error()),
+        }
+    }
+    let (Some(fraction), Some(out)) = (fraction, out) else {
+        return Err(usage_error());
+    };
+
+    let engine = Engine::load(db)?;
+    let mut records = engine.sample(fraction, seed)?;
+    if let Some(max_bytes) = max_bytes {
+        records = records.max_bytes(max_bytes);
+    }
+
+    let mut dump = DumpWriter::new(BufWriter::new(File::create(out)?))?;
+    for record in records {
+        let record = record?;
+        dump.write_record(&record.key, &record.value, record.tstamp)?;
+    }
+    let count = dump.finish()?;
+    println!("sampled {} records to {}", count, out);
+    Ok(())
+}
+
+fn patch_apply(db: &str, patch: &str, flags: &[&str]) -> io::Result<()> {
+    let mut dry_run = false;
+    let mut force = false;
+    for flag in flags {
+        match *flag {
+            "--dry-run" => dry_run = true,
+            "--force" => force = true,
+            _ => return Err(usage_error()),
+        }
+    }
+
+    let engine = Engine::load(db)?;
+    let input = BufReader::new(File::open(patch)?);
+    let report = match force {
+        true => engine.apply_patch_forced(input, dry_run)?,
+        false => engine.apply_patch(input, dry_run)?,
+    };
+    for outcome in &report.outcomes {
+        let change = match outcome.change {
+            PatchChange::Created => "create",
+            PatchChange::Replaced => "replace",
+            PatchChange::Deleted => "delete",
+            PatchChange::AlreadyAbsent => "absent",
+        };
+        let mismatch = match outcome.matched {
+            true => String::new(),
+            false => format!("\tMISMATCH found {}", outcome.found),
+        };
+        println!("{}\t{}{}", change, outcome.key.escape_ascii(), mismatch);
+    }
+    match (report.applied, dry_run) {
+        (true, _) => println!("applied {} entries to {}", report.outcomes.len(), db),
+        (false, true) => println!("dry run: nothing written"),
+        (false, false) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{} entries do not match; nothing written (--force applies anyway)",
+                    report.mismatches()
+                ),
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn patch_generate(db: &str, target: &str, out: &str) -> io::Result<()> {
+    let engine = Engine::load(db)?;
+    let target = Engine::load(target)?;
+    let patch = engine.diff_to_patch(&target)?;
+    patch.write_to(BufWriter::new(File::create(out)?))?;
+    println!("wrote {} entries to {}", patch.entries.len(), out);
+    Ok(())
+}
+
+fn stats(db: &str) -> io::Result<()> {
+    let engine = Engine::load(db)?;
+    let stats = engine.stats();
+    let forecast = engine.peak_disk_forecast()?;
+    println!("live keys:                {}", stats.live_keys);
+    println!("largest key:              {}", stats.largest_key_len);
+    println!("file size:                {}", stats.file_size);
+    println!("dead bytes:               {}", stats.dead_bytes);
+    println!("tombstones:               {}", stats.tombstones);
+    println!("compact threshold:        {}", stats.compact_threshold);
+    println!("disk usage now:           {}", forecast.current);
+    println!(
+        "after compaction:         {}",
+        forecast.post_compaction_estimate
+    );
+    println!(
+        "peak during compaction:   {}",
+        forecast.peak_during_compaction
+    );
+    Ok(())
+}
+
+fn tombstones(db: &str) -> io::Result<()> {
+    let engine = Engine::load(db)?;
+    let mut out = BufWriter::new(io::stdout().lock());
+    let mut count = 0u64;
+    for tombstone in engine.pending_tombstones()? {
+        let (key, tstamp) = tombstone?;
+        writeln!(out, "{}\t{}", tstamp, key.escape_ascii())?;
+        count += 1;
+    }
+    writeln!(out, "{} pending tombstones", count)?;
+    out.flush()
+}
+
+fn ingest(flags: &[&str]) -> io::Result<()> {
+    let mut from = None;
+    let mut into = None;
+    let mut policy = ConflictPolicy::KeepNewest;
+    for pair in flags.chunks(2) {
+        match pair {
+            ["--from", v] => from = Some(*v),
+            ["--into", v] => into = Some(*v),
+            ["--policy", "accept"] => policy = ConflictPolicy::AlwaysAccept,
+            ["--policy", "reject-older"] => policy = ConflictPolicy::RejectOlder,
+            ["--policy", "keep-newest"] => policy = ConflictPolicy::KeepNewest,
+            _ => return Err(usage_error()),
+        }
+    }
+    let (Some(from), Some(into)) = (from, into) else {
+        return Err(usage_error());
+    };
+
+    let engine = Engine::load(into)?;
+    let report = engine.ingest_raw_log(from, policy)?;
+    println!("recovered:  {}", report.recovered);
+    println!("applied:    {}", report.applied);
+    println!("skipped:    {}", report.skipped);
+    println!("conflicted: {}", report.conflicted);
+    for region in &report.corrupt_regions {
+        println!("corrupt:    {}..{}", region.start, region.end);
+    }
+    Ok(())
+}
+
+fn inspect_format(db: &str) -> io::Result<()> {
+    print!("{}", Engine::describe_format_of(db)?);
+    Ok(())
+}
+
+fn bench(flags: &[&str]) -> io::Result<()> {
+    let mut db = None;
+    let mut spec = WorkloadSpec::default();
+    let mut json = false;
+    let mut flags = flags.iter();
+    while let Some(flag) = flags.next() {
+        if *flag == "--json" {
+            json = true;
+            continue;
+        }
+        let value = flags.next().ok_or_else(usage_error)?;
+        match *flag {
+            "--db" => db = Some(*value),
+            "--threads" => spec.threads = parse_count(value, flag)? as usize,
+            "--ops" => spec.ops = parse_count(value, flag)?,
+            "--mix" => spec.mix = value.parse()?,
+            "--value-size" => spec.value_size = parse_count(value, flag)? as usize,
+            "--key-space" => spec.key_space = parse_count(value, flag)?,
+            "--distribution" => spec.distribution = value.parse()?,
+            "--seed" => spec.seed = parse_flag(value, flag)?,
+            _ => return Err(usage_error()),
+        }
+    }
+    let db = db.ok_or_else(usage_error)?;
+
+    let engine = Engine::load(db)?;
+    let report = workload::run(&engine, spec)?;
+    if json {
+        println!("{}", report.to_json());
+    } else {
+        print!("{}", report);
+    }
+    Ok(())
+}
+
+/// Parses a count such as `1000`, `10k`, `1m`, or `1e6`.
+fn parse_count(value: &str, flag: &str) -> io::Result<u64> {
+    let (number, scale) = match value.char_indices().last() {
+        Some((i, 'k' | 'K')) => (&value[..i], 1e3),
+        Some((i, 'm' | 'M')) => (&value[..i], 1e6),
+        Some((i, 'g' | 'G')) => (&value[..i], 1e9),
+        _ => (value, 1.0),
+    };
+    match number.parse::<f64>() {
+        Ok(n) if n >= 0.0 && (n * scale).fract() == 0.0 && n * scale <= u64::MAX as f64 => {
+            Ok((n * scale) as u64)
+        }
+        _ => parse_flag(value, flag),
+    }
+}
+
+fn parse_flag<T: std::str::FromStr>(value: &str, flag: &str) -> io::Result<T> {
+    value.parse().map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid value for {}: {}", flag, value),
+        )
+    })
+}
+
+fn usage_error() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, USAGE)
+}
@@ -0,0 +1,276 @@
+//! Named key namespaces sharing one store.
+//!
+//! A key `k` in bucket `name` is stored as
+//! `[BUCKET_KEY_PREFIX][name length u8][name][k]`. The length prefix keeps
+//! buckets apart even when one name is a prefix of another (`a` + `bx` vs.
+//! `ab` + `x`), and the raw API refuses keys starting with
+//! `BUCKET_KEY_PREFIX`, so no raw key can alias a bucketed one.
+//!
+//! A bucket's [`Quota`] is stored under an internal key,
+//! `[INTERNAL_KEY_PREFIX]quota/[name]`, so it is written, compacted,
+//! dumped, and reloaded like any record. Internal keys are not user data:
+//! `Stats::live_keys`, key exports, shared snapshots, and samples leave
+//! them out unless `Options::include_internal_keys` is set.
+//!
+//! Keys starting with `BUCKET_KEY_PREFIX` that are neither bucketed nor
+//! internal can only have been written as raw keys before the prefix was
+//! reserved. A store holding one fails to load with
+//! `EngineError::StrayReservedKey` rather than misreading it, and dumps and
+//! logs holding one are refused the same way.
+//!
+//! The `name` in a key's prefix is the namespace the key was written in,
+//! which is the bucket's own name until [`Engine::swap_buckets`] points the
+//! bucket at another one. A bucket mapped elsewhere has its namespace
+//! stored under `[INTERNAL_KEY_PREFIX]ns/[name]`; a swap rewrites the two
+//! mappings in one write batch, and no key moves. Every operation on a
+//! [`Bucket`] looks its namespace up afresh, so it sees one namespace or the
+//! other, whole, and never a mix of the two.
+
+use std::io;
+
+use crate::constants::{BUCKET_KEY_PREFIX, INTERNAL_KEY_PREFIX, MAX_BUCKET_NAME_LEN};
+use crate::engine::Engine;
+use crate::error::EngineError;
+use crate::scan::KeysSnapshot;
+use crate::stats::{BucketStats, PrefixStats};
+
+/// Limits on one bucket's live data, from [`Bucket::set_quota`]. `None`
+/// leaves that limit off.
+///
+/// Quotas count logical data: live keys and the length of their current
+/// values, as [`Bucket::usage`] reports them. Keys and record overhead are
+/// not counted, and neither are the dead records that overwrites and
+/// deletes leave in the log until compaction, so a delete frees its quota
+/// at once.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Quota {
+    pub max_keys: Option<u64>,
+    pub max_bytes: Option<u64>,
+}
+
+impl Quota {
+    /// Two u64 LE limits, `u64::MAX` for none.
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        [self.max_keys, self.max_bytes]
+            .iter()
+            .flat_map(|limit| limit.unwrap_or(u64::MAX).to_le_bytes())
+            .collect()
+    }
+
+    pub(crate) fn decode(value: &[u8]) -> Option<Quota> {
+        let limit = |at: usize| {
+            let limit = u64::from_le_bytes(value.get(at..at + 8)?.try_into().ok()?);
+            Some((limit != u64::MAX).then_some(limit))
+        };
+        Some(Quota {
+            max_keys: limit(0)?,
+            max_bytes: limit(8)?,
+        })
+    }
+}
+
+/// A handle on one bucket, from [`Engine::bucket`]. Keys passed to it are
+/// relative to the bucket and never see other buckets' or raw keys.
+#[derive(Clone)]
+pub struct Bucket<'a> {
+    engine: &'a Engine,
+    name: Vec<u8>,
+}
+
+impl<'a> Bucket<'a> {
+    pub(crate) fn new(engine: &'a Engine, name: &[u8]) -> io::Result<Self> {
+        validate_name(name)?;
+        Ok(Bucket {
+            engine,
+            name: name.to_vec(),
+        })
+    }
+
+    pub fn name(&self) -> &[u8] {
+        &self.name
+    }
+
+    pub fn get(&self, key: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        self.engine.get_key(&self.encode(key)?)
+    }
+
+    pub fn set(&self, key: &[u8], value: &[u8]) -> io::Result<()> {
+        self.engine.set_key(&self.encode(key)?, value)
+    }
+
+    pub fn del(&self, key: &[u8]) -> io::Result<()> {
+        self.engine.del_key(&self.encode(key)?)
+    }
+
+    /// Live pairs of this bucket whose key starts with `prefix`, in key
+    /// order, with the bucket prefix stripped from the keys. A point-in-time
+    /// view, like [`Engine::scan_prefix`].
+    pub fn scan_prefix(&self, prefix: &[u8]) -> io::Result<KeysSnapshot<(Vec<u8>, Vec<u8>)>> {
+        let bucket_prefix = self.prefix();
+        let mut pairs = self
+            .engine
+            .scan_encoded(
+                &[&bucket_prefix[..], &self.engine.transform_key(prefix)].concat(),
+                false,
+            )?
+            .into_vec();
+        for (key, _) in &mut pairs {
+            key.drain(..bucket_prefix.len());
+        }
+        Ok(KeysSnapshot::new(pairs))
+    }
+
+    /// Deletes every key of this bucket starting with `prefix`; an empty
+    /// prefix clears the bucket. Returns how many keys were deleted.
+    pub fn delete_prefix(&self, prefix: &[u8]) -> io::Result<usize> {
+        let prefix = self.engine.transform_key(prefix);
+        self.engine
+            .delete_encoded_prefix(&[&self.prefix()[..], &prefix].concat(), false)
+    }
+
+    /// Removes every key of this bucket; the same as an empty
+    /// [`Bucket::delete_prefix`]. The records it leaves behind show up in
+    /// this bucket's [`BucketStats::dead_bytes`].
+    pub fn clear(&self) -> io::Result<usize> {
+        self.delete_prefix(b"")
+    }
+
+    /// Key count and live and dead bytes of this bucket alone. Dead bytes
+    /// are tracked per bucket as records are overwritten and deleted, so
+    /// this is one pass over the index and reads nothing from disk.
+    pub fn stats(&self) -> BucketStats {
+        let prefix = self.prefix();
+        self.engine.bucket_stats(&prefix, &prefix[2..])
+    }
+
+    /// Refuses writes that would take this bucket past `quota` with
+    /// `EngineError::QuotaExceeded`, from now on and across reloads. Keys
+    /// and bytes already over a lowered quota stay, and writes that do not
+    /// grow them still succeed. Usage is counted incrementally from here on,
+    /// after one scan of the index.
+    pub fn set_quota(&self, quota: Quota) -> io::Result<()> {
+        self.engine.set_bucket_quota(&self.name, Some(quota))
+    }
+
+    /// Removes this bucket's quota, if any.
+    pub fn clear_quota(&self) -> io::Result<()> {
+        self.engine.set_bucket_quota(&self.name, None)
+    }
+
+    pub fn quota(&self) -> Option<Quota> {
+        self.engine.bucket_quota(&self.prefix())
+    }
+
+    /// Live keys and value bytes of this bucket, as its quota counts them.
+    /// Kept up to date on every write once the bucket has a quota or this
+    /// has been called; the first call on a bucket without a quota scans the
+    /// index once. `prefix` is the encoded key prefix of the bucket's
+    /// namespace.
+    pub fn usage(&self) -> PrefixStats {
+        self.engine.prefix_usage(&self.prefix())
+    }
+
+    /// The encoded prefix every key of this bucket starts with right now.
+    fn prefix(&self) -> Vec<u8> {
+        self.engine.bucket_prefix(&self.name)
+    }
+
+    fn encode(&self, key: &[u8]) -> io::Result<Vec<u8>> {
+        if key.is_empty() {
+            return Err(EngineError::EmptyKey.into());
+        }
+        Ok([&self.prefix()[..], &self.engine.transform_key(key)].concat())
+    }
+}
+
+impl std::fmt::Debug for Bucket<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Bucket")
+            .field("name", &String::from_utf8_lossy(self.name()))
+            .finish()
+    }
+}
+
+/// The encoded prefix of every key in bucket `name`.
+pub(crate) fn prefix(name: &[u8]) -> Vec<u8> {
+    let mut prefix = Vec::with_capacity(2 + name.len());
+    prefix.push(BUCKET_KEY_PREFIX);
+    prefix.push(name.len() as u8);
+    prefix.extend_from_slice(name);
+    prefix
+}
+
+/// Tags following `INTERNAL_KEY_PREFIX` in the internal keys, one per kind
+/// of record the engine keeps about itself.
+const QUOTA_TAG: &[u8] = b"quota/";
+const NAMESPACE_TAG: &[u8] = b"ns/";
+const INTERNAL_TAGS: [&[u8]; 2] = [QUOTA_TAG, NAMESPACE_TAG];
+
+/// The internal key holding bucket `name`'s quota; with an empty name, the
+/// prefix of every quota key.
+pub(crate) fn quota_key(name: &[u8]) -> Vec<u8> {
+    [&INTERNAL_KEY_PREFIX[..], QUOTA_TAG, name].concat()
+}
+
+/// The internal key holding the namespace bucket `name` is mapped to; with
+/// an empty name, the prefix of every such key.
+pub(crate) fn namespace_key(name: &[u8]) -> Vec<u8> {
+    [&INTERNAL_KEY_PREFIX[..], NAMESPACE_TAG, name].concat()
+}
+
+/// Whether `key` lives in the bucket namespace rather than the raw one.
+/// Internal keys count as bucketed.
+pub(crate) fn is_bucket_key(key: &[u8]) -> bool {
+    key.first() == Some(&BUCKET_KEY_PREFIX)
+}
+
+/// Whether `key` is one of the engine's own, such as a quota.
+pub(crate) fn is_internal_key(key: &[u8]) -> bool {
+    key.starts_with(&INTERNAL_KEY_PREFIX)
+}
+
+/// Whether `key` is one the engine could have written: a raw key, a key in
+/// a validly named bucket, or an internal key with a known tag. Any other
+/// key starting with `BUCKET_KEY_PREFIX` was written as a raw key before
+/// the prefix was reserved, and would be misread as one of those.
+pub(crate) fn is_well_formed(key: &[u8]) -> bool {
+    if !is_bucket_key(key) {
+        return true;
+    }
+    if let Some(rest) = key.strip_prefix(&INTERNAL_KEY_PREFIX[..]) {
+        return INTERNAL_TAGS.iter().any(|tag| rest.starts_with(tag));
+    }
+    split_key(key).is_some_and(|(name, _)| validate_name(name).is_ok())
+}
+
+/// Fails with `EngineError::StrayReservedKey` unless `key` is well formed.
+pub(crate) fn check_well_formed(key: &[u8]) -> io::Result<()> {
+    if is_well_formed(key) {
+        return Ok(());
+    }
+    Err(EngineError::StrayReservedKey(key.to_vec()).into())
+}
+
+/// Splits a bucketed key into its bucket name and the key within the
+/// bucket; `None` for raw keys.
+pub(crate) fn split_key(key: &[u8]) -> Option<(&[u8], &[u8])> {
+    if !is_bucket_key(key) {
+        return None;
+    }
+    let name_len = *key.get(1)? as usize;
+    let name = key.get(2..2 + name_len)?;
+    Some((name, &key[2 + name_len..]))
+}
+
+pub(crate) fn validate_name(name: &[u8]) -> io::Result<()> {
+    let reason = if name.is_empty() {
+        "name is empty"
+    } else if name.len() > MAX_BUCKET_NAME_LEN {
+        "name is longer than 255 bytes"
+    } else if name.contains(&BUCKET_KEY_PREFIX) {
+        "name contains the reserved byte 0xFF"
+    } else {
+        return Ok(());
+    };
+    Err(EngineError::InvalidBucketName(reason.to_string()).into())
+}
@@ -0,0 +1,61 @@
+//! What [`Engine::audit_caches`](crate::Engine::audit_caches) finds, behind
+//! the `cache-audit` feature.
+//!
+//! Beside the live-key map, the index keeps structures derived from it and
+//! updated along with it on every write: the read view `get` is served
+//! from, the ordered key set, cached value hashes, tracked prefix counters
+//! (which bucket quotas are checked against), blob reference counts, and
+//! key length counters; the engine keeps the bucket namespace mappings.
+//! A missed update in any of them serves or counts stale data without an
+//! error, so the audit recomputes each from the live keys and the log and
+//! reports every difference.
+
+use crate::stats::PrefixStats;
+
+/// One derived structure disagreeing with the index or the log.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CacheInconsistency {
+    /// The read view `get` is served from disagrees with the index about
+    /// `key`.
+    ReadView { key: Vec<u8>, detail: String },
+    /// The read view holds `view` entries of `what` ("keys" or "blobs")
+    /// where the index has `index`.
+    ReadViewSize {
+        what: &'static str,
+        view: usize,
+        index: usize,
+    },
+    /// The `Options::ordered_index` key set has `key` though it is not
+    /// live (`live == false`), or lacks it though it is.
+    OrderedIndex { key: Vec<u8>, live: bool },
+    /// The `Options::value_hashes` hash cached for `key` is not the hash
+    /// of its value read back from disk.
+    ValueHash {
+        key: Vec<u8>,
+        cached: u64,
+        on_disk: u64,
+    },
+    /// A tracked prefix's counters, and what recounting the live keys
+    /// gives.
+    TrackedPrefix {
+        counted: PrefixStats,
+        recounted: PrefixStats,
+    },
+    /// A blob's reference count, 0 for a blob the index lacks, and how many
+    /// live keys reference it.
+    BlobRefs {
+        hash: Vec<u8>,
+        refs: u64,
+        referenced_by: u64,
+    },
+    /// The key length counters behind `Stats::index_bytes` and
+    /// `Stats::largest_key_len` disagree with the live keys.
+    KeyLengths { detail: String },
+    /// Bucket `bucket`'s namespace in memory, `None` for its own, is not
+    /// what its mapping record on disk says.
+    Namespace {
+        bucket: Vec<u8>,
+        cached: Option<Vec<u8>>,
+        on_disk: Option<Vec<u8>>,
+    },
+}
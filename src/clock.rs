@@ -0,0 +1,20 @@
+//! The time source behind `Options::idle_after`, replaceable so tests can
+//! move time forward without sleeping.
+
+use std::fmt;
+use std::time::Instant;
+
+/// A monotonic clock.
+pub trait Clock: Send + Sync + fmt::Debug {
+    fn now(&self) -> Instant;
+}
+
+/// [`Instant::now`]; the default.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
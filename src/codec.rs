@@ -0,0 +1,201 @@
+//! How a `DataFileEntry` becomes the bytes of a record, by format version.
+//!
+//! KVS1 to KVS4 records are whatever `wincode` produces for the entry
+//! types. From KVS5 on they are written and read by [`ExplicitCodec`], which
+//! spells the layout out byte by byte, so the format no longer depends on
+//! a serialization library and can be implemented from this file alone.
+//! The two agree byte for byte on every entry, which is pinned by tests.
+//! KVS7 extends the explicit layout with an optional origin; a record
+//! without one is encoded exactly as in KVS5 and KVS6.
+
+use std::io;
+
+use crate::format_info::{self, Codec};
+use crate::types::{DataFileEntry, DataFileEntryV1, DataFileEntryV2, EntryKind, ORIGIN_SIZE};
+
+/// Bit of the explicit layout's value flag: a value follows.
+pub(crate) const VALUE_FLAG_SOME: u8 = 1;
+/// Bit of the explicit layout's value flag, from KVS7: an origin follows
+/// the value.
+pub(crate) const VALUE_FLAG_ORIGIN: u8 = 2;
+
+/// Encodes and decodes the records of one format version.
+pub(crate) trait EntryCodec: Sync {
+    fn encode(&self, entry: &DataFileEntry) -> io::Result<Vec<u8>>;
+    fn decode(&self, data: &[u8]) -> io::Result<DataFileEntry>;
+}
+
+/// The codec for records of `format_version`, as the format table says.
+pub(crate) fn for_version(format_version: u8) -> &'static dyn EntryCodec {
+    let spec = format_info::by_version(format_version);
+    match spec.codec {
+        Codec::Wincode if !spec.entry_kinds => &WincodeV1Codec,
+        Codec::Wincode => &WincodeCodec,
+        Codec::ExplicitLe if !spec.origins => &ExplicitCodec { origins: false },
+        Codec::ExplicitLe => &ExplicitCodec { origins: true },
+    }
+}
+
+fn invalid(msg: impl ToString) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+/// Fails for an entry with an origin, in a version that has none.
+fn refuse_origin(entry: &DataFileEntry) -> io::Result<()> {
+    match entry.origin {
+        Some(_) => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "record origins need a KVS7 file; run compact() to upgrade",
+        )),
+        None => Ok(()),
+    }
+}
+
+/// KVS1: `DataFileEntryV1` through wincode; only puts and tombstones.
+struct WincodeV1Codec;
+
+impl EntryCodec for WincodeV1Codec {
+    fn encode(&self, entry: &DataFileEntry) -> io::Result<Vec<u8>> {
+        if !matches!(entry.kind, EntryKind::Put | EntryKind::Tombstone) {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "soft deletes, deduplicated values, TTLs, and batches need a KVS2 file; run compact() to upgrade",
+            ));
+        }
+        refuse_origin(entry)?;
+        wincode::serialize(&DataFileEntryV1 {
+            tstamp: entry.tstamp,
+            key: entry.key.clone(),
+            value: entry.value.clone(),
+        })
+        .map_err(|e| io::Error::other(e.to_string()))
+    }
+
+    fn decode(&self, data: &[u8]) -> io::Result<DataFileEntry> {
+        let entry = wincode::deserialize::<DataFileEntryV1>(data).map_err(invalid)?;
+        Ok(match entry.value {
+            Some(value) => DataFileEntry::put(entry.tstamp, entry.key, value),
+            None => DataFileEntry::tombstone(entry.tstamp, entry.key),
+        })
+    }
+}
+
+/// KVS2 to KVS4: `DataFileEntryV2` through wincode.
+struct WincodeCodec;
+
+impl EntryCodec for WincodeCodec {
+    fn encode(&self, entry: &DataFileEntry) -> io::Result<Vec<u8>> {
+        refuse_origin(entry)?;
+        wincode::serialize(&DataFileEntryV2::from(entry))
+            .map_err(|e| io::Error::other(e.to_string()))
+    }
+
+    fn decode(&self, data: &[u8]) -> io::Result<DataFileEntry> {
+        wincode::deserialize::<DataFileEntryV2>(data)
+            .map(DataFileEntry::from)
+            .map_err(invalid)
+    }
+}
+
+/// KVS5 on. All integers little-endian:
+///
+/// ```text
+/// tstamp      i64, ms since the epoch
+/// key_len     u64
+/// key         key_len bytes
+/// value_flag  u8: bit 0 a value follows, bit 1 (KVS7 on) an origin
+///             follows the value
+/// value_len   u64, only with bit 0
+/// value       value_len bytes, only with bit 0
+/// origin      16 bytes, only with bit 1
+/// kind        u8, the EntryKind tag
+/// ```
+///
+/// Decoding rejects unknown flags and kinds. Like wincode, it ignores bytes
+/// after the kind, which keeps torn-tail recovery the same in every version.
+struct ExplicitCodec {
+    /// Whether the version has origins (KVS7 on).
+    origins: bool,
+}
+
+impl EntryCodec for ExplicitCodec {
+    fn encode(&self, entry: &DataFileEntry) -> io::Result<Vec<u8>> {
+        if !self.origins {
+            refuse_origin(entry)?;
+        }
+        let value_len = entry.value.as_ref().map_or(0, |v| 8 + v.len());
+        let origin_len = entry.origin.map_or(0, |_| ORIGIN_SIZE);
+        let mut buf = Vec::with_capacity(8 + 8 + entry.key.len() + 1 + value_len + origin_len + 1);
+        buf.extend_from_slice(&entry.tstamp.to_le_bytes());
+        buf.extend_from_slice(&(entry.key.len() as u64).to_le_bytes());
+        buf.extend_from_slice(&entry.key);
+        let origin_flag = match entry.origin {
+            Some(_) => VALUE_FLAG_ORIGIN,
+            None => 0,
+        };
+        match &entry.value {
+            Some(value) => {
+                buf.push(VALUE_FLAG_SOME | origin_flag);
+                buf.extend_from_slice(&(value.len() as u64).to_le_bytes());
+                buf.extend_from_slice(value);
+            }
+            None => buf.push(origin_flag),
+        }
+        if let Some(origin) = &entry.origin {
+            buf.extend_from_slice(origin);
+        }
+        buf.push(entry.kind as u8);
+        Ok(buf)
+    }
+
+    fn decode(&self, data: &[u8]) -> io::Result<DataFileEntry> {
+        let mut rest = data;
+        let tstamp = i64::from_le_bytes(take_array(&mut rest)?);
+        let key = take_bytes(&mut rest)?.to_vec();
+        let known = match self.origins {
+            true => VALUE_FLAG_SOME | VALUE_FLAG_ORIGIN,
+            false => VALUE_FLAG_SOME,
+        };
+        let [flag] = take_array(&mut rest)?;
+        if flag & !known != 0 {
+            return Err(invalid(format!("invalid value flag {}", flag)));
+        }
+        let value = match flag & VALUE_FLAG_SOME {
+            0 => None,
+            _ => Some(take_bytes(&mut rest)?.to_vec()),
+        };
+        let origin = match flag & VALUE_FLAG_ORIGIN {
+            0 => None,
+            _ => Some(take_array(&mut rest)?),
+        };
+        let [tag] = take_array(&mut rest)?;
+        let kind = EntryKind::from_tag(tag)
+            .ok_or_else(|| invalid(format!("unknown entry kind {}", tag)))?;
+        Ok(DataFileEntry {
+            tstamp,
+            key,
+            value,
+            kind,
+            origin,
+        })
+    }
+}
+
+fn take_array<const N: usize>(rest: &mut &[u8]) -> io::Result<[u8; N]> {
+    let Some((head, tail)) = rest.split_first_chunk::<N>() else {
+        return Err(invalid("record ends early"));
+    };
+    *rest = tail;
+    Ok(*head)
+}
+
+/// A u64 LE length and that many bytes.
+fn take_bytes<'a>(rest: &mut &'a [u8]) -> io::Result<&'a [u8]> {
+    let len = u64::from_le_bytes(take_array(rest)?);
+    if len > rest.len() as u64 {
+        return Err(invalid("record ends early"));
+    }
+    let (bytes, tail) = rest.split_at(len as usize);
+    *rest = tail;
+    Ok(bytes)
+}
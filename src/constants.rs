@@ -1,4 +1,50 @@
+use std::time::Duration;
+
 pub const DEFAULT_COMPACT_THRESHOLD: u64 = 1024 * 1024;
+/// Once live bytes alone reach the compaction threshold, auto-compaction
+/// waits until the file is this many times the live bytes instead.
+pub const LIVE_COMPACT_FACTOR: u64 = 2;
+pub const DEFAULT_SOFT_DELETE_WINDOW: Duration = Duration::from_secs(24 * 60 * 60);
+/// Default `Options::shutdown_timeout`.
+pub const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+/// Default `Options::readahead_bytes`.
+pub const DEFAULT_READAHEAD_BYTES: usize = 4 * 1024 * 1024;
+/// Size of a record's length prefix in KVS1 and KVS2 files. KVS3 files use
+/// a varint instead.
 pub const LEN_PREFIX_SIZE: u64 = 8;
-pub const FILE_HEADER_MAGIC: [u8; 4] = *b"KVS1";
-pub const FILE_HEADER_SIZE: u64 = 12;
+pub const FILE_HEADER_MAGIC: [u8; 4] = *b"KVS7";
+pub const FILE_HEADER_MAGIC_V6: [u8; 4] = *b"KVS6";
+pub const FILE_HEADER_MAGIC_V5: [u8; 4] = *b"KVS5";
+pub const FILE_HEADER_MAGIC_V4: [u8; 4] = *b"KVS4";
+pub const FILE_HEADER_MAGIC_V3: [u8; 4] = *b"KVS3";
+pub const FILE_HEADER_MAGIC_V2: [u8; 4] = *b"KVS2";
+pub const FILE_HEADER_MAGIC_V1: [u8; 4] = *b"KVS1";
+/// Header of KVS6 and later files: magic, compaction threshold, writer
+/// epoch, flags.
+pub const FILE_HEADER_SIZE: u64 = 28;
+/// Header of KVS4 and KVS5 files, which have no flags.
+pub const EPOCH_HEADER_SIZE: u64 = 20;
+/// Header of KVS1 to KVS3 files, which have no writer epoch.
+pub const LEGACY_HEADER_SIZE: u64 = 12;
+pub const FORMAT_VERSION: u8 = 7;
+/// Header flag: every open of the file so far was in `Options::audit_mode`.
+pub const HEADER_FLAG_AUDIT_ONLY: u64 = 1;
+/// Header flag: the compaction threshold was set by `set_compact_threshold`
+/// and is never auto-tuned.
+pub const HEADER_FLAG_THRESHOLD_OPERATOR: u64 = 2;
+/// Header flag: the compaction threshold was doubled by a compaction that
+/// barely shrank the file.
+pub const HEADER_FLAG_THRESHOLD_AUTO: u64 = 4;
+/// Header flags: bits from this one up hold the id of the
+/// `Options::key_transform` the file was created with, 0 for none.
+pub const HEADER_KEY_TRANSFORM_SHIFT: u32 = 32;
+/// First byte of every bucketed key; raw keys may not start with it. See
+/// [`crate::bucket`].
+pub const BUCKET_KEY_PREFIX: u8 = 0xFF;
+pub const MAX_BUCKET_NAME_LEN: usize = u8::MAX as usize;
+/// First bytes of the engine's own keys, such as bucket quotas. No bucket
+/// has an empty name, so these never alias a bucketed key, and the raw API
+/// refuses them like any key starting with `BUCKET_KEY_PREFIX`.
+pub const INTERNAL_KEY_PREFIX: [u8; 2] = [BUCKET_KEY_PREFIX, 0];
+/// Bytes of each key `Engine::largest_keys` returns.
+pub const LARGEST_KEYS_PREFIX: usize = 64;
@@ -0,0 +1,58 @@
+//! Caller-supplied context for operations, set for a scope with
+//! `Engine::with_context`. The engine never interprets it; it only passes
+//! it on to `Options::on_set`, `Options::on_slow_op`, and the operation
+//! journal, so those can be tied back to the request that caused them.
+
+use std::cell::RefCell;
+
+/// An opaque tag and id, such as a request name and a request id.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct OpContext {
+    pub tag: String,
+    pub id: u64,
+}
+
+impl OpContext {
+    pub fn new(tag: impl Into<String>, id: u64) -> Self {
+        OpContext {
+            tag: tag.into(),
+            id,
+        }
+    }
+}
+
+/// Passed to `Options::on_set` after a set makes its value current.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SetEvent {
+    pub key: Vec<u8>,
+    pub value_len: usize,
+    /// The context of the `Engine::with_context` scope the set ran in.
+    pub context: Option<OpContext>,
+    /// The writer recorded as the value's origin, if any; see
+    /// `Options::writer_id`.
+    pub origin: Option<[u8; 16]>,
+}
+
+thread_local! {
+    static CURRENT: RefCell<Option<OpContext>> = const { RefCell::new(None) };
+}
+
+/// Runs `f` with `context` as this thread's context, restoring the outer
+/// one afterwards, even if `f` panics.
+pub(crate) fn scope<T>(context: OpContext, f: impl FnOnce() -> T) -> T {
+    struct Restore(Option<OpContext>);
+
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            CURRENT.set(self.0.take());
+        }
+    }
+
+    let _restore = Restore(CURRENT.replace(Some(context)));
+    f()
+}
+
+/// This thread's context. Only called when something will report it.
+pub(crate) fn current() -> Option<OpContext> {
+    CURRENT.with_borrow(Clone::clone)
+}
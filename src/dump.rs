@@ -0,0 +1,253 @@
+//! Logical dump format for long-term archival.
+//!
+//! This format is FROZEN. It must stay readable independently of the
+//! engine's internal record layout, so any change to it requires a new
+//! `DUMP_VERSION` and a decoder for every older version.
+//!
+//! All integers are little-endian.
+//!
+//! ```text
+//! [4 bytes: magic "KVDP"][4 bytes: version u32]
+//! record*:
+//!     [8 bytes: record length u64 (key_len + value_len + 24)]
+//!     [8 bytes: key length u64][key bytes]
+//!     [8 bytes: value length u64][value bytes]
+//!     [8 bytes: tstamp i64]
+//! [8 bytes: 0u64 end-of-records marker]
+//! [8 bytes: record count u64]
+//! [4 bytes: CRC-32 (IEEE) of every preceding byte of the stream]
+//! ```
+//!
+//! A record body is never shorter than 24 bytes, so a zero record length
+//! unambiguously marks the end of the records.
+
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+
+pub const DUMP_MAGIC: [u8; 4] = *b"KVDP";
+pub const DUMP_VERSION: u32 = 1;
+
+const RECORD_FIXED_SIZE: u64 = 24;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DumpRecord {
+    pub key: Vec<u8>,
+    pub value: Vec<u8>,
+    pub tstamp: i64,
+}
+
+pub struct DumpWriter<W: Write> {
+    inner: W,
+    hasher: crc32fast::Hasher,
+    count: u64,
+}
+
+impl<W: Write> DumpWriter<W> {
+    pub fn new(inner: W) -> io::Result<Self> {
+        let mut writer = DumpWriter {
+            inner,
+            hasher: crc32fast::Hasher::new(),
+            count: 0,
+        };
+        writer.put(&DUMP_MAGIC)?;
+        writer.put(&DUMP_VERSION.to_le_bytes())?;
+        Ok(writer)
+    }
+
+    fn put(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.hasher.update(bytes);
+        self.inner.write_all(bytes)
+    }
+
+    pub fn write_record(&mut self, key: &[u8], value: &[u8], tstamp: i64) -> io::Result<()> {
+        let record_len = RECORD_FIXED_SIZE + key.len() as u64 + value.len() as u64;
+        self.put(&record_len.to_le_bytes())?;
+        self.put(&(key.len() as u64).to_le_bytes())?;
+        self.put(key)?;
+        self.put(&(value.len() as u64).to_le_bytes())?;
+        self.put(value)?;
+        self.put(&tstamp.to_le_bytes())?;
+        self.count += 1;
+        Ok(())
+    }
+
+    pub fn finish(self) -> io::Result<u64> {
+        self.finish_with_crc().map(|(count, _)| count)
+    }
+
+    /// [`DumpWriter::finish`], also returning the CRC written as the trailer.
+    pub fn finish_with_crc(mut self) -> io::Result<(u64, u32)> {
+        self.put(&0u64.to_le_bytes())?;
+        let count = self.count;
+        self.put(&count.to_le_bytes())?;
+        let crc = self.hasher.clone().finalize();
+        self.inner.write_all(&crc.to_le_bytes())?;
+        self.inner.flush()?;
+        Ok((count, crc))
+    }
+}
+
+fn invalid(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.into())
+}
+
+struct HashingReader<R: Read> {
+    inner: R,
+    hasher: crc32fast::Hasher,
+}
+
+impl<R: Read> HashingReader<R> {
+    fn take(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        self.inner.read_exact(buf).map_err(|e| {
+            if e.kind() == io::ErrorKind::UnexpectedEof {
+                invalid("invalid dump: truncated stream")
+            } else {
+                e
+            }
+        })?;
+        self.hasher.update(buf);
+        Ok(())
+    }
+
+    fn take_u64(&mut self) -> io::Result<u64> {
+        let mut buf = [0u8; 8];
+        self.take(&mut buf)?;
+        Ok(u64::from_le_bytes(buf))
+    }
+
+    fn take_vec(&mut self, len: u64) -> io::Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        (&mut self.inner).take(len).read_to_end(&mut buf)?;
+        if buf.len() as u64 != len {
+            return Err(invalid("invalid dump: truncated stream"));
+        }
+        self.hasher.update(&buf);
+        Ok(buf)
+    }
+}
+
+/// Reads and validates a complete dump stream.
+///
+/// Nothing is returned unless the whole stream, including the trailing CRC,
+/// checks out, so callers never apply half of a damaged dump.
+pub fn read_dump(reader: impl Read) -> io::Result<Vec<DumpRecord>> {
+    read_dump_with_crc(reader).map(|(records, _)| records)
+}
+
+/// [`read_dump`], also returning the stream's CRC trailer.
+fn read_dump_with_crc(reader: impl Read) -> io::Result<(Vec<DumpRecord>, u32)> {
+    let mut r = HashingReader {
+        inner: reader,
+        hasher: crc32fast::Hasher::new(),
+    };
+
+    let mut magic = [0u8; 4];
+    r.take(&mut magic)?;
+    if magic != DUMP_MAGIC {
+        return Err(invalid("invalid dump: missing KVDP magic"));
+    }
+
+    let mut version = [0u8; 4];
+    r.take(&mut version)?;
+    let version = u32::from_le_bytes(version);
+    if version != DUMP_VERSION {
+        return Err(invalid(format!("unsupported dump version {}", version)));
+    }
+
+    let mut records = Vec::new();
+    loop {
+        let record_len = r.take_u64()?;
+        if record_len == 0 {
+            break;
+        }
+        if record_len < RECORD_FIXED_SIZE {
+            return Err(invalid("invalid dump: record shorter than its framing"));
+        }
+
+        let key_len = r.take_u64()?;
+        if key_len > record_len - RECORD_FIXED_SIZE {
+            return Err(invalid("invalid dump: key length exceeds record"));
+        }
+        let key = r.take_vec(key_len)?;
+
+        let value_len = r.take_u64()?;
+        if value_len != record_len - RECORD_FIXED_SIZE - key_len {
+            return Err(invalid("invalid dump: value length does not match record"));
+        }
+        let value = r.take_vec(value_len)?;
+
+        let mut tstamp = [0u8; 8];
+        r.take(&mut tstamp)?;
+
+        records.push(DumpRecord {
+            key,
+            value,
+            tstamp: i64::from_le_bytes(tstamp),
+        });
+    }
+
+    let count = r.take_u64()?;
+    let expected_crc = r.hasher.clone().finalize();
+
+    let mut crc = [0u8; 4];
+    r.inner.read_exact(&mut crc).map_err(|e| {
+        if e.kind() == io::ErrorKind::UnexpectedEof {
+            invalid("invalid dump: missing CRC trailer")
+        } else {
+            e
+        }
+    })?;
+    if u32::from_le_bytes(crc) != expected_crc {
+        return Err(invalid("invalid dump: CRC mismatch"));
+    }
+    if count != records.len() as u64 {
+        return Err(invalid("invalid dump: record count mismatch"));
+    }
+
+    Ok((records, expected_crc))
+}
+
+/// The manifest of a dump written by [`crate::Engine::export_subset`]: what
+/// went into it and the point in time it was read at.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SubsetReport {
+    /// The data file the subset was read from.
+    pub store: PathBuf,
+    /// Records in the dump, and the key and value bytes they hold.
+    pub keys: u64,
+    pub bytes: u64,
+    /// The data file's generation and the end of its log when the subset
+    /// was read: every write acknowledged before that point is in it, and
+    /// none made after.
+    pub generation: u64,
+    pub log_end: u64,
+    /// The dump's CRC-32 trailer.
+    pub crc: u32,
+}
+
+/// Re-reads a dump written by `export_subset` and checks it against its
+/// `report`: the stream must be intact, its keys strictly ascending, and
+/// its record count, byte total, and CRC the report's. Fails with
+/// `InvalidData` saying what differs.
+pub fn verify_subset(reader: impl Read, report: &SubsetReport) -> io::Result<()> {
+    let (records, crc) = read_dump_with_crc(reader)?;
+    if let Some(pair) = records.windows(2).find(|pair| pair[0].key >= pair[1].key) {
+        return Err(invalid(format!(
+            "subset dump is out of key order at {}",
+            pair[1].key.escape_ascii()
+        )));
+    }
+    let bytes: u64 = records
+        .iter()
+        .map(|record| (record.key.len() + record.value.len()) as u64)
+        .sum();
+    let found = (records.len() as u64, bytes, crc);
+    let expected = (report.keys, report.bytes, report.crc);
+    if found != expected {
+        return Err(invalid(format!(
+            "subset dump holds {} keys, {} bytes, CRC {:08x}; its report says {} keys, {} bytes, CRC {:08x}",
+            found.0, found.1, found.2, expected.0, expected.1, expected.2
+        )));
+    }
+    Ok(())
+}
@@ -1,336 +1,5511 @@
-use std::collections::HashMap;
-use std::fs::{File, OpenOptions};
-use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::borrow::Cow;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet, VecDeque};
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::ops::{Bound, Deref, DerefMut, RangeBounds};
 use std::path::{Path, PathBuf};
-use std::sync::{Mutex, RwLock};
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::sync::atomic::{AtomicBool, AtomicU8, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard, TryLockError};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
+use sha1::{Digest, Sha1};
+
+use crate::access::AccessTracker;
+use crate::archive::Archive;
+use crate::audit::{AuditScan, PendingTombstones};
+use crate::batch::{self, WriteBatch, batch_len};
+use crate::bucket::{self, Bucket, Quota};
+#[cfg(feature = "cache-audit")]
+use crate::cache_audit::CacheInconsistency;
+use crate::codec;
 use crate::constants::{
-    DEFAULT_COMPACT_THRESHOLD, FILE_HEADER_MAGIC, FILE_HEADER_SIZE, LEN_PREFIX_SIZE,
+    BUCKET_KEY_PREFIX, DEFAULT_COMPACT_THRESHOLD, DEFAULT_READAHEAD_BYTES, EPOCH_HEADER_SIZE,
+    FILE_HEADER_MAGIC, FILE_HEADER_SIZE, FORMAT_VERSION, HEADER_FLAG_AUDIT_ONLY,
+    HEADER_FLAG_THRESHOLD_AUTO, HEADER_FLAG_THRESHOLD_OPERATOR, HEADER_KEY_TRANSFORM_SHIFT,
+    LARGEST_KEYS_PREFIX, LEGACY_HEADER_SIZE, LEN_PREFIX_SIZE, LIVE_COMPACT_FACTOR,
+};
+use crate::context::{self, OpContext, SetEvent};
+use crate::dump::{self, DumpRecord, DumpWriter, SubsetReport};
+use crate::error::{self, EngineError};
+use crate::external_sort::{self, ExternalSort};
+use crate::format::{self, Header};
+use crate::format_info::{self, FormatDescription};
+use crate::framing;
+use crate::idle::{IdleReclaimer, IdleState};
+use crate::index::{self, Index, ReadView};
+use crate::key_locks::KeyLocks;
+use crate::limits::LimitWatch;
+use crate::lock_metrics::LockCounters;
+use crate::mirror::Mirror;
+#[cfg(feature = "oplog-debug")]
+use crate::oplog::{JournalOp, OpJournal};
+use crate::options::{
+    ConflictPolicy, Durability, Hook, KeyTransform, OpenMode as LoadMode, Options, ReclaimOptions,
+    RenameConflict, ThresholdSource,
+};
+use crate::patch::{Patch, PatchChange, PatchEntry, PatchOp, PatchOutcome, PatchReport, Prior};
+use crate::read_limiter::ReadLimiter;
+use crate::readahead::Readahead;
+use crate::reader_pool::{Reader, ReaderPool};
+use crate::retry::{RetryCounters, RetryingStorage};
+use crate::runtime::{EngineSlot, KvRuntime};
+use crate::salvage;
+use crate::sample::{self, Sample};
+use crate::scan::{KeysSnapshot, LiveScan};
+use crate::shared_snapshot::SnapshotWriter;
+use crate::single_flight::SingleFlight;
+use crate::slow_op::{self, SlowOp, SlowOpKind};
+use crate::snapshot::Published;
+use crate::stats::{
+    BucketStats, CompactProgress, CompactionEstimate, CompactionReport, DiskForecast,
+    ExportProgress, IngestReport, LatencyHistogram, LoadReport, Metrics, MirrorStatus,
+    OverlapReport, PrefixStats, ReadSource, ReloadReport, Stats,
+};
+use crate::storage::{CloneMethod, FsStorage, OpenMode, Storage, StorageFile};
+use crate::syncer::{SyncState, Syncer};
+use crate::types::{
+    DataFileEntry, EXPIRY_SIZE, EntryKind, KeyStat, LogIndex, ORIGIN_SIZE, RecordRef, SoftDeleted,
 };
-use crate::types::{DataFileEntry, LogIndex};
+use crate::workers::{Stage, WorkerSet};
+use crate::write_queue::{WriteQueue, Written};
+
+pub(crate) type FileHandle = Box<dyn StorageFile>;
+
+/// Bytes past the key that `OpenMode::Fast` reads before seeking to the
+/// kind byte: enough for the option tag, value length, and an expiry.
+const FAST_TAIL_READ: u64 = 64;
+
+/// Keys `rename_prefix` moves per write batch.
+const RENAME_CHUNK_KEYS: usize = 1024;
+
+/// Records `ingest_raw_log` appends and fsyncs at a time.
+const INGEST_CHUNK_RECORDS: usize = 1024;
+
+/// Values `Engine::archive` moves per append to the archive file.
+const ARCHIVE_CHUNK_RECORDS: usize = 1024;
+
+/// Key bytes `Engine::dump` sorts in memory before spilling sorted runs.
+const DUMP_SORT_CHUNK_BYTES: usize = 16 << 20;
+
+/// Tries at cutting a failed append back off the log, and the pause before
+/// the first retry, doubled for each one after.
+const TRUNCATE_ATTEMPTS: u32 = 3;
+const TRUNCATE_BACKOFF: Duration = Duration::from_millis(5);
+
+type SharedRead = Result<Vec<u8>, (io::ErrorKind, String)>;
+
+/// A record compaction copies: kind, key, location, and the soft-delete
+/// time for `SoftDelete` records. A key's history and live records share
+/// one copy of the key.
+type CompactRecord = (EntryKind, Arc<[u8]>, LogIndex, i64);
+
+/// The time an automatic compaction stamped as its start, and the stamp it
+/// replaced; `None` without `Options::min_compaction_interval`.
+type AutoCompactionStart = Option<(Instant, Option<Instant>)>;
+
+/// A batch's records with their expiries jittered, and every op's expiry.
+type JitteredBatch<'a> = (Cow<'a, [u8]>, Vec<Option<i64>>);
+
+/// Sorted live pairs, and whether there may be more past the last.
+type LivePage = (Vec<(Vec<u8>, Vec<u8>)>, bool);
+
+/// A compaction's output so far, and the records it has yet to copy.
+struct Compaction {
+    tmp_path: PathBuf,
+    tmp_file: FileHandle,
+    records: VecDeque<CompactRecord>,
+    remaining_bytes: u64,
+    new_index: Index,
+    new_file_size: u64,
+    old_version: u8,
+    /// Size of the log when the records were snapshotted.
+    snapshot_end: u64,
+    compact_threshold: u64,
+    /// Records written after the snapshot and copied at the end.
+    carried_over: u64,
+    /// Bytes of records kept from the start of the old file rather than
+    /// copied; see `Engine::packed_prefix`.
+    preserved_bytes: u64,
+}
+
+struct SteppedCompaction {
+    compaction: Compaction,
+    /// Read handle on the data file, so steps do not hold the file mutex.
+    source: FileHandle,
+    generation: u64,
+}
+
+/// Write access to the index that publishes its read view and dead bytes on
+/// drop, still under the lock, so they are published in the order changes
+/// were made.
+/// Also notes the soft-limit warnings the changes raised or cleared.
+struct IndexWriteGuard<'a> {
+    index: RwLockWriteGuard<'a, Index>,
+    view: &'a Published<ReadView>,
+    dead_bytes: &'a AtomicU64,
+    limits: Option<&'a LimitWatch>,
+}
+
+impl Deref for IndexWriteGuard<'_> {
+    type Target = Index;
+
+    fn deref(&self) -> &Index {
+        &self.index
+    }
+}
+
+impl DerefMut for IndexWriteGuard<'_> {
+    fn deref_mut(&mut self) -> &mut Index {
+        &mut self.index
+    }
+}
+
+impl Drop for IndexWriteGuard<'_> {
+    fn drop(&mut self) {
+        self.view.store(self.index.view.clone());
+        self.dead_bytes
+            .store(self.index.dead_bytes, Ordering::Release);
+        if let Some(limits) = self.limits {
+            limits.observe(self.index.live.len() as u64, self.index.live_bytes());
+        }
+    }
+}
+
+/// What `Engine::reclaim_idle` can release, shared with the background
+/// reclaimer.
+struct IdleResources {
+    reader_pool: Arc<ReaderPool>,
+    in_flight_reads: Arc<SingleFlight<(u64, u64), SharedRead>>,
+    write_queue: Arc<WriteQueue>,
+    idle: Arc<IdleState>,
+}
+
+impl IdleResources {
+    fn reclaim(&self, resources: ReclaimOptions) -> usize {
+        let closed = match resources.readers {
+            true => self.reader_pool.reclaim(),
+            false => 0,
+        };
+        if resources.buffers {
+            self.write_queue.shrink();
+            self.in_flight_reads.shrink();
+        }
+        self.idle.mark_reclaimed();
+        closed
+    }
+}
+
+pub struct Engine {
+    path: PathBuf,
+    options: Options,
+    storage: Arc<dyn Storage>,
+    file: Arc<Mutex<FileHandle>>,
+    /// The handle header reads and rewrites go through once loaded, so they
+    /// never move the cursor of `file`, which appends and the index rebuild
+    /// read records through. Opened on first use and dropped whenever
+    /// `file` is replaced. Used under the file mutex.
+    header_file: Mutex<Option<FileHandle>>,
+    file_lock: LockCounters,
+    format_version: AtomicU8,
+    index: RwLock<Index>,
+    index_lock: LockCounters,
+    /// `index.view` as of the last write, so `get` never takes the index
+    /// lock.
+    view: Published<ReadView>,
+    file_size: Mutex<u64>,
+    allocated_size: AtomicU64,
+    /// Changed only under the file mutex, and read without a lock by the
+    /// auto-compaction check on every write.
+    compact_threshold: AtomicU64,
+    /// `index.dead_bytes` as of the last write, for the same check.
+    dead_bytes: AtomicU64,
+    threshold_source: Mutex<ThresholdSource>,
+    /// The writer epoch claimed at load under `Options::fencing`.
+    epoch: AtomicU64,
+    /// Another writer claimed `fenced_by`; writes fail from then on.
+    fenced: AtomicBool,
+    fenced_by: AtomicU64,
+    /// When the header epoch was last checked; `None` forces a check.
+    last_fence_check: Mutex<Option<Instant>>,
+    reader_pool: Arc<ReaderPool>,
+    /// Keyed by generation and offset.
+    in_flight_reads: Arc<SingleFlight<(u64, u64), SharedRead>>,
+    key_locks: KeyLocks,
+    /// Combines concurrent single-record writes into one append.
+    write_queue: Arc<WriteQueue>,
+    idle: Arc<IdleState>,
+    /// `Options::soft_limits` that watch a hard limit.
+    limits: Option<LimitWatch>,
+    /// `Options::slow_op_threshold` and `on_slow_op`, when both are set.
+    slow_ops: Option<(Duration, Hook<SlowOp>)>,
+    /// Details of the first corruption detected since the last clean
+    /// `verify` or acknowledgement.
+    corruption: Mutex<Option<String>>,
+    corrupted: AtomicBool,
+    /// Set by `set_read_only`; checked under the file mutex before every
+    /// append and compaction.
+    read_only: AtomicBool,
+    /// Set when a failed append could not be cut back off the log; writes
+    /// fail with `EngineError::DiskFull` until a `reload` truncates it.
+    torn_tail: AtomicBool,
+    /// Set once by `close`, under the file mutex like `read_only`.
+    closed: AtomicBool,
+    read_limiter: Option<ReadLimiter>,
+    /// Read counts and times under `Options::track_access`.
+    access: Option<AccessTracker>,
+    /// Bucket quotas by encoded bucket prefix, as stored under their
+    /// internal keys. Changed and reloaded under the file mutex.
+    quotas: RwLock<HashMap<Vec<u8>, Quota>>,
+    /// Bucket name to the namespace its keys are stored under, for the
+    /// buckets `swap_buckets` has pointed elsewhere; every other bucket's
+    /// keys are under its own name. Changed and reloaded under the file
+    /// mutex.
+    namespaces: RwLock<HashMap<Vec<u8>, Vec<u8>>>,
+    /// The copy kept under `Options::mirror_path`. Written under the file
+    /// mutex.
+    mirror: Option<Mirror>,
+    /// The cold tier under `Options::archive_path`.
+    archive: Option<Archive>,
+    /// Bumped to odd under the index write lock before the data file is
+    /// replaced, and back to even once the new file and index are
+    /// published. A stepped compaction uses it to tell its snapshot no
+    /// longer matches the file, and a lock-free `get` to tell the file it
+    /// read from may not be the one its view describes.
+    generation: AtomicU64,
+    /// The compaction `compact_step` is working through. Taken before the
+    /// file mutex.
+    stepped: Mutex<Option<SteppedCompaction>>,
+    /// When the last automatic compaction started, by `Options::clock`,
+    /// for `Options::min_compaction_interval`. One abandoned or failed
+    /// puts back the one before it.
+    last_auto_compaction: Mutex<Option<Instant>>,
+    /// A trigger was put off by `Options::min_compaction_interval` and no
+    /// compaction has run since, so every write checks the triggers again.
+    compaction_deferred: AtomicBool,
+    metrics: Metrics,
+    retry_counters: Arc<RetryCounters>,
+    sync_state: Arc<SyncState>,
+    /// The `Durability::Interval` syncer and the `Options::idle_after`
+    /// reclaimer, stopped in order by `close`.
+    workers: WorkerSet,
+    load_report: LoadReport,
+    _runtime_slot: Option<EngineSlot>,
+}
+
+impl std::fmt::Debug for Engine {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Engine")
+            .field("path", &self.path)
+            .field("closed", &self.is_closed())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Engine {
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::load_with_options(path, Options::default())
+    }
+
+    /// Loads `path` with `compact_threshold` as its auto-compaction
+    /// threshold. Like `set_compact_threshold`, it is written into the
+    /// header, of a new file or over an existing file's own, marked as set
+    /// by an operator, so later loads keep it and compaction never doubles
+    /// it. Fails with `InvalidInput` for 0; a threshold below
+    /// `FILE_HEADER_SIZE`, which every file already reaches, is raised to
+    /// it. `u64::MAX` turns size-triggered auto-compaction off.
+    ///
+    /// The threshold goes into the header as it is read or created, before
+    /// the log is replayed, so a crash during the load leaves either the
+    /// old header or the new one.
+    pub fn load_with_threshold(path: impl AsRef<Path>, compact_threshold: u64) -> io::Result<Self> {
+        if compact_threshold == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "compact_threshold must be above 0",
+            ));
+        }
+        Self::open(
+            path,
+            Options::default(),
+            Some(compact_threshold.max(FILE_HEADER_SIZE)),
+        )
+    }
+
+    pub fn load_with_options(path: impl AsRef<Path>, options: Options) -> io::Result<Self> {
+        Self::open(path, options, None)
+    }
+
+    /// Loads `path` with `options`, first writing `operator_threshold`, if
+    /// given, into the header as `set_compact_threshold` would.
+    fn open(
+        path: impl AsRef<Path>,
+        options: Options,
+        operator_threshold: Option<u64>,
+    ) -> io::Result<Self> {
+        if options.dedup_min_value_len.is_some() && options.history_depth > 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "dedup_min_value_len cannot be combined with history_depth",
+            ));
+        }
+        if options.soft_limits.iter().any(|(_, soft)| {
+            soft.clear_at.partial_cmp(&soft.warn_at) != Some(std::cmp::Ordering::Less)
+        }) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "a soft limit's clear_at must be below its warn_at",
+            ));
+        }
+        if options.key_transform.as_ref().is_some_and(|t| t.id() == 0) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "key_transform id 0 means no transform",
+            ));
+        }
+        if !(0.0..=1.0).contains(&options.ttl_jitter) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "ttl_jitter must be between 0.0 and 1.0",
+            ));
+        }
+        if options.mirror_path.as_deref() == Some(path.as_ref()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "mirror_path must not be the data file itself",
+            ));
+        }
+        if let Some(archive_path) = &options.archive_path
+            && (archive_path == path.as_ref() || options.mirror_path.as_ref() == Some(archive_path))
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "archive_path must not be the data file or its mirror",
+            ));
+        }
+
+        let path = path.as_ref().to_path_buf();
+        let retry_counters = Arc::new(RetryCounters::default());
+        let storage: Arc<dyn Storage> = match &options.io_retry {
+            Some(policy) => Arc::new(RetryingStorage::new(
+                Arc::clone(&options.storage),
+                policy.clone(),
+                Arc::clone(&retry_counters),
+            )),
+            None => Arc::clone(&options.storage),
+        };
+        if let Some(dir) = &options.compaction_dir {
+            storage.create_dir_all(dir)?;
+        }
+        let header = Self::ensure_header(
+            storage.as_ref(),
+            &path,
+            operator_threshold,
+            options.fencing.is_some(),
+            options.audit_mode,
+            key_transform_id(&options),
+        )?;
+        let Header {
+            format_version,
+            epoch,
+            ..
+        } = header;
+        let (compact_threshold, threshold_source) = Self::resolve_threshold(&options, &header);
+        let file = storage.open(&path, OpenMode::ReadWrite)?;
+
+        let mut engine = Engine {
+            path,
+            options: options.clone(),
+            storage,
+            file: Arc::new(Mutex::new(file)),
+            header_file: Mutex::new(None),
+            file_lock: LockCounters::default(),
+            format_version: AtomicU8::new(format_version),
+            index: RwLock::new(Index::new(
+                options.history_depth,
+                format_version,
+                options.ordered_index,
+                options.value_hashes,
+            )),
+            index_lock: LockCounters::default(),
+            view: Published::new(ReadView::default()),
+            file_size: Mutex::new(0),
+            allocated_size: AtomicU64::new(0),
+            compact_threshold: AtomicU64::new(compact_threshold),
+            dead_bytes: AtomicU64::new(0),
+            threshold_source: Mutex::new(threshold_source),
+            epoch: AtomicU64::new(epoch),
+            fenced: AtomicBool::new(false),
+            fenced_by: AtomicU64::new(0),
+            last_fence_check: Mutex::new(None),
+            reader_pool: Arc::new(ReaderPool::new(
+                options.reader_pool_size,
+                options.runtime.clone(),
+            )),
+            in_flight_reads: Arc::new(SingleFlight::new()),
+            key_locks: KeyLocks::new(),
+            write_queue: Arc::new(WriteQueue::new()),
+            idle: Arc::new(IdleState::new(
+                Arc::clone(&options.clock),
+                options.idle_after.is_some(),
+            )),
+            limits: LimitWatch::new(&options),
+            slow_ops: options.slow_op_threshold.zip(options.on_slow_op.clone()),
+            corruption: Mutex::new(None),
+            corrupted: AtomicBool::new(false),
+            read_only: AtomicBool::new(false),
+            torn_tail: AtomicBool::new(false),
+            closed: AtomicBool::new(false),
+            read_limiter: options.max_concurrent_reads.map(ReadLimiter::new),
+            access: options
+                .track_access
+                .then(|| AccessTracker::new(Arc::clone(&options.clock))),
+            quotas: RwLock::new(HashMap::new()),
+            namespaces: RwLock::new(HashMap::new()),
+            mirror: options.mirror_path.clone().map(|path| {
+                Mirror::new(
+                    path,
+                    options.mirror_failure,
+                    options.on_mirror_error.clone(),
+                )
+            }),
+            archive: options.archive_path.clone().map(Archive::new),
+            generation: AtomicU64::new(0),
+            stepped: Mutex::new(None),
+            last_auto_compaction: Mutex::new(None),
+            compaction_deferred: AtomicBool::new(false),
+            metrics: Metrics::default(),
+            retry_counters,
+            sync_state: Arc::new(SyncState::default()),
+            workers: WorkerSet::default(),
+            load_report: LoadReport::default(),
+            _runtime_slot: options.runtime.as_ref().map(KvRuntime::register_engine),
+        };
+
+        for prefix in &options.tracked_prefixes {
+            let prefix = engine.transform_key(prefix);
+            engine.index_lock.write(&engine.index).track_prefix(&prefix);
+        }
+        {
+            let mut file = engine.lock_file();
+            let load_report = engine.rebuild_index(&mut file, format_version, options.open_mode)?;
+            // Marks where this writer's records start, so a load can tell
+            // when a fenced writer appended after a newer one took over.
+            if options.fencing.is_some() {
+                engine.append_locked(&mut file, &DataFileEntry::epoch(now_millis(), epoch))?;
+            }
+            drop(file);
+            engine.load_report = load_report;
+        }
+        engine.deliver_limit_warnings();
+
+        if let Durability::Interval { period, jitter } = options.durability {
+            let file = Arc::clone(&engine.file);
+            let state = Arc::clone(&engine.sync_state);
+            let on_error = options.on_sync_error.clone();
+            let syncer = match &options.runtime {
+                Some(runtime) => Syncer::schedule(runtime, file, state, period, jitter, on_error),
+                None => Syncer::spawn(file, state, period, jitter, on_error)?,
+            };
+            engine.workers.register(Stage::Flush, syncer);
+        }
+
+        if let Some(idle_after) = options.idle_after {
+            let resources = engine.idle_resources();
+            let reclaim = move || {
+                resources.reclaim(ReclaimOptions::default());
+            };
+            let state = Arc::clone(&engine.idle);
+            let reclaimer = match &options.runtime {
+                Some(runtime) => IdleReclaimer::schedule(runtime, state, idle_after, reclaim),
+                None => IdleReclaimer::spawn(state, idle_after, reclaim)?,
+            };
+            engine.workers.register(Stage::Reader, reclaimer);
+        }
+
+        Ok(engine)
+    }
+
+    /// Converts a data file written before files had a header, with KVS1
+    /// records from offset 0, into a current-format file in place. Returns
+    /// the number of records it held.
+    ///
+    /// The whole file must scan as KVS1 records ending exactly at its end;
+    /// anything else is refused with `InvalidData` and the file is left
+    /// alone. So is a file that starts with a known header magic, even when
+    /// it would also scan without one: there is no telling which it is. The
+    /// header is prepended in a staged copy renamed over `path`, and the
+    /// result is then compacted to the current format.
+    pub fn migrate_legacy(path: impl AsRef<Path>) -> io::Result<u64> {
+        let path = path.as_ref();
+        let storage = FsStorage;
+        let mut file = storage.open(path, OpenMode::Read)?;
+        let file_len = file.len()?;
+        let mut magic = [0u8; FILE_HEADER_MAGIC.len()];
+        let has_magic = file_len >= magic.len() as u64
+            && file.read_exact(&mut magic).is_ok()
+            && format_info::by_magic(magic).is_some();
+        let scanned = AuditScan::new(file, 1, 0, file_len, DEFAULT_READAHEAD_BYTES)?
+            .try_fold(0u64, |count, record| record.map(|_| count + 1));
+
+        let refuse = |reason: String| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("cannot migrate {}: {}", path.display(), reason),
+            )
+        };
+        let count = match (has_magic, scanned) {
+            (true, Ok(_)) => {
+                return Err(refuse(
+                    "it scans as headerless records but also starts with a KVS header magic"
+                        .to_string(),
+                ));
+            }
+            (true, Err(_)) => return Err(refuse("it already has a KVS header".to_string())),
+            (false, Err(e)) => return Err(refuse(format!("not a headerless data file: {}", e))),
+            (false, Ok(0)) => return Err(refuse("the file is empty".to_string())),
+            (false, Ok(count)) => count,
+        };
+
+        let staged_path = path.with_extension("migrate");
+        let staged = (|| {
+            let mut out = storage.open(&staged_path, OpenMode::Truncate)?;
+            let header = Header {
+                format_version: 1,
+                compact_threshold: DEFAULT_COMPACT_THRESHOLD,
+                epoch: 0,
+                flags: 0,
+            };
+            Self::write_header(&mut *out, &header)?;
+            io::copy(&mut storage.open(path, OpenMode::Read)?, &mut out)?;
+            out.sync_all()
+        })();
+        if let Err(e) = staged {
+            let _ = storage.remove_file(&staged_path);
+            return Err(e);
+        }
+        storage.rename(&staged_path, path)?;
+
+        Self::load(path)?.compact()?;
+        Ok(count)
+    }
+
+    /// Whether a file without a known magic reads as KVS1 records from
+    /// offset 0; see [`format_info::looks_headerless`].
+    fn plausible_headerless(file: &mut FileHandle, file_len: u64) -> io::Result<bool> {
+        let mut head = [0u8; LEN_PREFIX_SIZE as usize];
+        if file_len < LEN_PREFIX_SIZE {
+            return Ok(false);
+        }
+        file.seek(SeekFrom::Start(0))?;
+        file.read_exact(&mut head)?;
+        Ok(format_info::looks_headerless(&head, file_len))
+    }
+
+    /// Reads the header of the file at `path`, writing one first if the file
+    /// is empty. With `operator_threshold`, also stores that threshold as
+    /// set by an operator. With `claim`, also takes the next writer epoch for
+    /// `Options::fencing`. Without `audit`, clears the audit-only flag.
+    /// Fails unless the file was created with the `key_transform` id given.
+    fn ensure_header(
+        storage: &dyn Storage,
+        path: &Path,
+        operator_threshold: Option<u64>,
+        claim: bool,
+        audit: bool,
+        key_transform: u32,
+    ) -> io::Result<Header> {
+        let mut file = storage.open(path, OpenMode::ReadWrite)?;
+        // Several loaders may race on a fresh path. Under the lock exactly
+        // one of them sees it empty and writes the header; the rest wait and
+        // read it, rather than rewriting it after the winner has moved on.
+        file.lock()?;
+        let header =
+            Self::read_or_init_header(&mut file, operator_threshold, claim, audit, key_transform);
+        file.unlock()?;
+        header
+    }
+
+    fn read_or_init_header(
+        file: &mut FileHandle,
+        operator_threshold: Option<u64>,
+        claim: bool,
+        audit: bool,
+        key_transform: u32,
+    ) -> io::Result<Header> {
+        let file_len = file.len()?;
+        let mut header = if file_len == 0 {
+            let audit_flag = if audit { HEADER_FLAG_AUDIT_ONLY } else { 0 };
+            let flags = audit_flag | key_transform_flags(key_transform);
+            let header = Header {
+                format_version: FORMAT_VERSION,
+                compact_threshold: operator_threshold.unwrap_or(DEFAULT_COMPACT_THRESHOLD),
+                epoch: 0,
+                flags: match operator_threshold {
+                    Some(_) => Self::threshold_flags(flags, ThresholdSource::Operator),
+                    None => flags,
+                },
+            };
+            Self::write_header(&mut **file, &header)?;
+            header
+        } else {
+            Self::read_header(file, file_len)?
+        };
+
+        let has_flags = format_info::by_version(header.format_version).header_flags();
+        if key_transform != 0 && !has_flags {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "key_transform needs a KVS6 or later file; its keys would have to be rewritten",
+            ));
+        }
+        let stored = (header.flags >> HEADER_KEY_TRANSFORM_SHIFT) as u32;
+        if stored != key_transform {
+            return Err(EngineError::KeyTransformMismatch {
+                stored,
+                configured: key_transform,
+            }
+            .into());
+        }
+
+        if audit && !has_flags {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "audit mode needs a KVS6 or later file; load without audit_mode and run compact() to upgrade",
+            ));
+        }
+
+        if claim {
+            if !format_info::by_version(header.format_version).writer_epoch() {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "fencing needs a KVS4 or later file; load without fencing and run compact() to upgrade",
+                ));
+            }
+            header.epoch += 1;
+            file.seek(SeekFrom::Start(LEGACY_HEADER_SIZE))?;
+            file.write_all(&header.epoch.to_le_bytes())?;
+            file.sync_all()?;
+        }
+        // Written over an existing header only once the file has passed the
+        // checks above, so a refused load leaves it alone.
+        if let Some(threshold) = operator_threshold
+            && file_len > 0
+        {
+            header.compact_threshold = threshold;
+            file.seek(SeekFrom::Start(FILE_HEADER_MAGIC.len() as u64))?;
+            file.write_all(&threshold.to_le_bytes())?;
+            if has_flags {
+                header.flags = Self::threshold_flags(header.flags, ThresholdSource::Operator);
+                file.seek(SeekFrom::Start(EPOCH_HEADER_SIZE))?;
+                file.write_all(&header.flags.to_le_bytes())?;
+            }
+            file.sync_all()?;
+        }
+        // Cleared for good: this open may compact away history.
+        if !audit && header.flags & HEADER_FLAG_AUDIT_ONLY != 0 {
+            header.flags &= !HEADER_FLAG_AUDIT_ONLY;
+            file.seek(SeekFrom::Start(EPOCH_HEADER_SIZE))?;
+            file.write_all(&header.flags.to_le_bytes())?;
+            file.sync_all()?;
+        }
+        Ok(header)
+    }
+
+    /// The threshold in effect for a file with `header`, and where it came
+    /// from: `Options::compact_threshold` if set, the header's otherwise.
+    fn resolve_threshold(options: &Options, header: &Header) -> (u64, ThresholdSource) {
+        match options.compact_threshold {
+            Some(threshold) => (threshold, ThresholdSource::Options),
+            None => (
+                header.compact_threshold,
+                Self::persisted_threshold_source(header),
+            ),
+        }
+    }
+
+    /// Who set the threshold in `header`, by its flags.
+    fn persisted_threshold_source(header: &Header) -> ThresholdSource {
+        if header.flags & HEADER_FLAG_THRESHOLD_OPERATOR != 0 {
+            ThresholdSource::Operator
+        } else if header.flags & HEADER_FLAG_THRESHOLD_AUTO != 0 {
+            ThresholdSource::AutoTuned
+        } else if header.compact_threshold == DEFAULT_COMPACT_THRESHOLD {
+            ThresholdSource::Default
+        } else {
+            ThresholdSource::Unmarked
+        }
+    }
+
+    /// `flags` with the threshold bits saying a threshold came from
+    /// `source`. `Options` and `Default` set neither.
+    fn threshold_flags(flags: u64, source: ThresholdSource) -> u64 {
+        let flags = flags & !(HEADER_FLAG_THRESHOLD_OPERATOR | HEADER_FLAG_THRESHOLD_AUTO);
+        match source {
+            ThresholdSource::Operator => flags | HEADER_FLAG_THRESHOLD_OPERATOR,
+            ThresholdSource::AutoTuned => flags | HEADER_FLAG_THRESHOLD_AUTO,
+            _ => flags,
+        }
+    }
+
+    fn read_header(file: &mut FileHandle, file_len: u64) -> io::Result<Header> {
+        let missing = || {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "invalid data.db: missing header",
+            )
+        };
+        if file_len < LEGACY_HEADER_SIZE {
+            return Err(missing());
+        }
+
+        file.seek(SeekFrom::Start(0))?;
+        let mut magic = [0u8; FILE_HEADER_MAGIC.len()];
+        file.read_exact(&mut magic)?;
+        let spec = match format_info::by_magic(magic) {
+            Some(spec) => spec,
+            None if Self::plausible_headerless(file, file_len)? => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "invalid data.db: missing KVS header; it looks like a file from before headers existed, which Engine::migrate_legacy converts",
+                ));
+            }
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "invalid data.db: unsupported format (missing KVS header)",
+                ));
+            }
+        };
+        if file_len < spec.header_size {
+            return Err(missing());
+        }
+
+        file.seek(SeekFrom::Start(0))?;
+        format::read_header(file)
+    }
+
+    fn write_header(file: &mut dyn StorageFile, header: &Header) -> io::Result<()> {
+        file.seek(SeekFrom::Start(0))?;
+        format::write_header(file, header)?;
+        file.flush()
+    }
+
+    /// Runs `f` on the header handle, opening it if this is its first use
+    /// since load or since the data file was replaced. The caller must hold
+    /// the file mutex.
+    fn with_header_file<T>(
+        &self,
+        f: impl FnOnce(&mut FileHandle) -> io::Result<T>,
+    ) -> io::Result<T> {
+        let mut header_file = self.header_file.lock().unwrap();
+        if header_file.is_none() {
+            *header_file = Some(self.storage.open(&self.path, OpenMode::ReadWrite)?);
+        }
+        f(header_file.as_mut().unwrap())
+    }
+
+    /// The writer epoch in the header of the open data file; 0 for formats
+    /// without one. The caller must hold the file mutex.
+    fn read_epoch(&self) -> io::Result<u64> {
+        if !format_info::by_version(self.format_version.load(Ordering::Acquire)).writer_epoch() {
+            return Ok(0);
+        }
+        self.with_header_file(|file| {
+            let mut buf = [0u8; 8];
+            file.seek(SeekFrom::Start(LEGACY_HEADER_SIZE))?;
+            file.read_exact(&mut buf)?;
+            Ok(u64::from_le_bytes(buf))
+        })
+    }
+
+    /// Under `Options::fencing`, fails with `EngineError::Fenced` once another
+    /// writer has claimed a newer epoch. The header is re-read when `force`
+    /// is set or the fencing interval has passed since the last check; once
+    /// fenced, the engine stays fenced. The caller must hold the file mutex.
+    fn check_fence(&self, force: bool) -> io::Result<()> {
+        let Some(interval) = self.options.fencing else {
+            return Ok(());
+        };
+        let epoch = self.epoch.load(Ordering::Acquire);
+        if self.fenced.load(Ordering::Acquire) {
+            return Err(EngineError::Fenced {
+                epoch,
+                current: self.fenced_by.load(Ordering::Acquire),
+            }
+            .into());
+        }
+        {
+            let mut last_check = self.last_fence_check.lock().unwrap();
+            if !force && last_check.is_some_and(|at| at.elapsed() < interval) {
+                return Ok(());
+            }
+            *last_check = Some(Instant::now());
+        }
+
+        let current = self.read_epoch()?;
+        if current == epoch {
+            return Ok(());
+        }
+        self.fenced_by.store(current, Ordering::Release);
+        self.fenced.store(true, Ordering::Release);
+        Err(EngineError::Fenced { epoch, current }.into())
+    }
+
+    /// Every record in the log, oldest first and numbered from 0, including
+    /// overwritten values and tombstones. Only under `Options::audit_mode`
+    /// is that the store's full history; otherwise compaction has dropped
+    /// whatever it no longer needed.
+    pub fn audit_scan(&self) -> io::Result<AuditScan> {
+        let _file = self.lock_file();
+        let format_version = self.format_version.load(Ordering::Acquire);
+        AuditScan::new(
+            self.storage.open(&self.path, OpenMode::Read)?,
+            format_version,
+            format_info::by_version(format_version).header_size,
+            *self.file_size.lock().unwrap(),
+            self.options.readahead_bytes,
+        )
+    }
+
+    /// Tombstones still in the log for keys that are not live: the deletes
+    /// compaction would drop, as `(key, deletion tstamp)` in log order.
+    /// Scans the log through its own handle up to its length now, so writes
+    /// are not blocked and later records are not seen.
+    pub fn pending_tombstones(&self) -> io::Result<PendingTombstones<'_>> {
+        Ok(PendingTombstones::new(self, self.audit_scan()?))
+    }
+
+    /// Whether `key` has an unexpired value.
+    pub(crate) fn is_live(&self, key: &[u8]) -> bool {
+        let index = self.index_read();
+        index.live.contains_key(key) && !index.is_expired(key)
+    }
+
+    /// Describes the format of this store's data file, from its header and
+    /// the format table in code; see [`format_info`].
+    pub fn describe_format(&self) -> io::Result<FormatDescription> {
+        let _file = self.lock_file();
+        format_info::describe(&*self.storage, &self.path)
+    }
+
+    /// Like [`Engine::describe_format`], for a file that need not be
+    /// loadable: newer, headerless, and unrecognized files are identified
+    /// rather than rejected.
+    pub fn describe_format_of(path: impl AsRef<Path>) -> io::Result<FormatDescription> {
+        format_info::describe(&FsStorage, path.as_ref())
+    }
+
+    /// Changes the auto-compaction threshold and persists it in the file
+    /// header, marked as set by an operator so no compaction ever doubles
+    /// it. It takes over from `Options::compact_threshold` for the rest of
+    /// this run; a later load with that option set still prefers the
+    /// option. Files from before KVS6 have no header flags to carry the
+    /// mark, so across a restart their threshold is auto-tuned again until
+    /// a compaction upgrades them.
+    ///
+    /// Like every header mutation this happens under the file mutex, so it
+    /// cannot race a compaction swapping the file, and on the header
+    /// handle, so it never moves the cursor appends go through.
+    pub fn set_compact_threshold(&self, compact_threshold: u64) -> io::Result<()> {
+        let _file = self.lock_file();
+        self.check_fence(true)?;
+        let has_flags =
+            format_info::by_version(self.format_version.load(Ordering::Acquire)).header_flags();
+        let writes = self.with_header_file(|file| {
+            let mut writes = vec![(
+                FILE_HEADER_MAGIC.len() as u64,
+                compact_threshold.to_le_bytes(),
+            )];
+            if has_flags {
+                let len = file.len()?;
+                let flags = Self::read_header(file, len)?.flags;
+                let flags = Self::threshold_flags(flags, ThresholdSource::Operator);
+                writes.push((EPOCH_HEADER_SIZE, flags.to_le_bytes()));
+            }
+            for (pos, bytes) in &writes {
+                file.seek(SeekFrom::Start(*pos))?;
+                file.write_all(bytes)?;
+            }
+            file.flush()?;
+            Ok(writes)
+        })?;
+        self.compact_threshold
+            .store(compact_threshold, Ordering::Release);
+        *self.threshold_source.lock().unwrap() = ThresholdSource::Operator;
+        if let Some(mirror) = &self.mirror {
+            for (pos, bytes) in &writes {
+                mirror.write_at(*pos, bytes)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Where the compaction threshold in effect came from. It is, in order
+    /// of precedence: `Options::compact_threshold`, then the header's, set
+    /// by an operator or auto-tuned, then `DEFAULT_COMPACT_THRESHOLD`. Only
+    /// `AutoTuned`, `Unmarked`, and `Default` thresholds are ever doubled.
+    pub fn threshold_source(&self) -> ThresholdSource {
+        *self.threshold_source.lock().unwrap()
+    }
+
+    pub(crate) fn encode_entry(format_version: u8, entry: &DataFileEntry) -> io::Result<Vec<u8>> {
+        codec::for_version(format_version).encode(entry)
+    }
+
+    fn decode_entry(format_version: u8, data: &[u8]) -> io::Result<DataFileEntry> {
+        codec::for_version(format_version).decode(data)
+    }
+
+    /// Locates the value bytes of a `Put` record without decoding it, as
+    /// `(offset from the record start, value length)`. The length runs to
+    /// the kind, so for a record with an origin it is `ORIGIN_SIZE` long;
+    /// [`Index::origin_len`] says which records have one.
+    ///
+    /// Relies on the layout every codec shares: `tstamp: i64`, `key: u64 len
+    /// + bytes`, `value: u8 flag + u64 len + bytes`, then (from KVS7, if the
+    /// flag says so) an origin, then (from KVS2) `kind: u8`.
+    pub(crate) fn value_span(
+        format_version: u8,
+        key_len: u64,
+        entry_len: u64,
+    ) -> io::Result<(u64, u64)> {
+        let value_offset = 8 + 8 + key_len + 1 + 8;
+        let trailer = if format_version == 1 { 0 } else { 1 };
+        entry_len
+            .checked_sub(value_offset + trailer)
+            .map(|value_len| (value_offset, value_len))
+            .ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, "record shorter than its key")
+            })
+    }
+
+    /// Replays the log into a fresh index and installs it, along with
+    /// `format_version`, checking records as `mode` asks. The caller must
+    /// hold the file mutex and pass its handle.
+    fn rebuild_index(
+        &self,
+        file: &mut FileHandle,
+        format_version: u8,
+        mode: LoadMode,
+    ) -> io::Result<LoadReport> {
+        // KVS1 records have no trailing kind to find without decoding, and
+        // value hashes need the values.
+        let fast = mode == LoadMode::Fast && format_version != 1 && !self.options.value_hashes;
+        let mut corrupt_records = 0;
+        let physical_len = file.len()?;
+        let header_size = format_info::by_version(format_version).header_size;
+        file.seek(SeekFrom::Start(header_size))?;
+        let mut entries_scanned = 0;
+        let mut rebuilt_index = Index::new(
+            self.options.history_depth,
+            format_version,
+            self.options.ordered_index,
+            self.options.value_hashes,
+        );
+        for prefix in self.index_read().tracked_prefixes() {
+            rebuilt_index.track_prefix(&prefix);
+        }
+        let mut end = header_size;
+        // Highest writer epoch seen so far.
+        let mut epoch = 0;
+        // Records of a write batch are held back until the whole batch has
+        // been read; a batch cut short ends the log at its `BatchBegin`.
+        let mut batch: Vec<(DataFileEntry, LogIndex)> = Vec::new();
+        let mut batch_remaining = 0u64;
+
+        // The log ends at physical EOF or at a torn tail: a length prefix
+        // that does not parse, is zero, or runs past EOF, with no record
+        // after it. Real records are never empty, so zeros mark
+        // pre-allocated space that was never written. Such a prefix with
+        // records after it is corruption, which truncating would lose.
+        let mut reader = BufReader::new(&mut *file);
+        let mut pos = header_size;
+        loop {
+            let framed = match framing::read_len(format_version, &mut reader) {
+                Ok(Some((len, prefix_len)))
+                    if len > 0 && pos + prefix_len + len <= physical_len =>
+                {
+                    Some((len, prefix_len))
+                }
+                Ok(Some(_)) => None,
+                Ok(None) => break,
+                Err(e) if e.kind() == io::ErrorKind::InvalidData => None,
+                Err(e) => return Err(e),
+            };
+            let Some((entry_len, prefix_len)) = framed else {
+                if Self::is_torn_tail(reader.get_mut(), pos, format_version)? {
+                    break;
+                }
+                let details = format!(
+                    "record at offset {}: bad length prefix with records after it",
+                    pos
+                );
+                self.mark_corrupted(details.clone());
+                return Err(io::Error::new(io::ErrorKind::InvalidData, details));
+            };
+            let data_pos = pos + prefix_len;
+
+            let read = match fast {
+                true => Self::read_index_fields(&mut reader, entry_len),
+                false => format::read_body(&mut reader, format_version, entry_len),
+            };
+            let entry = match read {
+                Ok(Some(entry)) => entry,
+                Ok(None) => break,
+                Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                    self.mark_corrupted(format!("record at offset {}: {}", data_pos, e));
+                    if mode != LoadMode::Verify {
+                        return Err(e);
+                    }
+                    // Lengths frame every record, so the next one can still
+                    // be found.
+                    corrupt_records += 1;
+                    pos = data_pos + entry_len;
+                    batch_remaining = batch_remaining.saturating_sub(1);
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            pos = data_pos + entry_len;
+            let log_index = LogIndex {
+                pos: data_pos,
+                len: entry_len,
+            };
+            if let Some(writer) = entry.writer_epoch() {
+                if writer < epoch {
+                    self.mark_corrupted(format!(
+                        "record at offset {}: writer epoch {} follows epoch {}; a fenced writer kept appending",
+                        data_pos, writer, epoch
+                    ));
+                }
+                epoch = epoch.max(writer);
+            }
+
+            batch_remaining = match entry.kind {
+                EntryKind::BatchBegin => batch_len(&entry),
+                _ => batch_remaining.saturating_sub(1),
+            };
+            batch.push((entry, log_index));
+            if batch_remaining > 0 {
+                continue;
+            }
+
+            entries_scanned += batch.len() as u64;
+            for (entry, log_index) in batch.drain(..) {
+                rebuilt_index.apply_entry(&entry, log_index);
+            }
+            end = pos;
+        }
+        drop(reader);
+
+        // A raw key from before 0xFF was reserved would be misread as a
+        // bucketed or internal one.
+        if let Some(key) = rebuilt_index
+            .live
+            .keys()
+            .find(|key| !bucket::is_well_formed(key))
+        {
+            return Err(EngineError::StrayReservedKey(key.clone()).into());
+        }
+
+        // Stubs are only readable with their archive file.
+        let required = !rebuilt_index.archived.is_empty();
+        match &self.archive {
+            Some(archive) => archive.open(self.storage.as_ref(), required)?,
+            None if required => {
+                return Err(EngineError::ArchiveMissing(format!(
+                    "{} values are archived but Options::archive_path is not set",
+                    rebuilt_index.archived.len()
+                ))
+                .into());
+            }
+            None => {}
+        }
+
+        // Drop a torn tail or unused pre-allocation so the next append starts
+        // on clean, zeroed space.
+        if end < physical_len {
+            file.set_len(end)?;
+        }
+
+        let report = LoadReport {
+            mode,
+            replay: ReloadReport {
+                entries_scanned,
+                live_keys: rebuilt_index.live.len(),
+                truncated_bytes: physical_len.saturating_sub(end),
+                format_version,
+            },
+            corrupt_records,
+        };
+
+        // The file may have been replaced since pooled handles were opened,
+        // so this counts as a swap for readers.
+        let mut index = self.index_mut();
+        self.generation.fetch_add(1, Ordering::AcqRel);
+        *index = rebuilt_index;
+        self.format_version.store(format_version, Ordering::Release);
+        drop(index);
+        let generation = self.generation.fetch_add(1, Ordering::AcqRel) + 1;
+        self.reader_pool
+            .reset(self.storage.as_ref(), &self.path, generation);
+        *self.file_size.lock().unwrap() = end;
+        self.allocated_size.store(end, Ordering::Release);
+        self.load_namespaces()?;
+        self.load_quotas()?;
+        if let Some(mirror) = &self.mirror {
+            mirror.sync_from(self.storage.as_ref(), &self.path, end, false)?;
+        }
+
+        Ok(report)
+    }
+
+    /// Whether the record at `pos`, whose length prefix could not frame it,
+    /// is a torn tail: no record starts anywhere after it. A write cut
+    /// short leaves only its own bytes, and pre-allocated zeros, behind.
+    fn is_torn_tail(
+        file: &mut (impl Read + Seek + ?Sized),
+        pos: u64,
+        format_version: u8,
+    ) -> io::Result<bool> {
+        file.seek(SeekFrom::Start(pos))?;
+        let mut rest = Vec::new();
+        file.read_to_end(&mut rest)?;
+        let data_end = rest.iter().rposition(|&b| b != 0).map_or(0, |i| i + 1);
+        Ok((1..data_end).all(|at| salvage::record_at(&rest, at as u64, format_version).is_none()))
+    }
+
+    /// Reads what the index needs from one KVS2+ record for
+    /// `OpenMode::Fast`: timestamp, key, kind, and the value of the kinds
+    /// that keep data for the load in it (only the expiry of an
+    /// `ExpiringPut`, and all of the short value of the others).
+    /// Seeks past all other value bytes, and leaves the value `None`.
+    ///
+    /// Relies on the layout described at [`Engine::value_span`].
+    fn read_index_fields<R: Read + Seek>(
+        reader: &mut BufReader<R>,
+        entry_len: u64,
+    ) -> io::Result<Option<DataFileEntry>> {
+        let mut head = [0u8; 16];
+        if !read_full(reader, &mut head)? {
+            return Ok(None);
+        }
+        let tstamp = i64::from_le_bytes(head[..8].try_into().unwrap());
+        let key_len = u64::from_le_bytes(head[8..].try_into().unwrap());
+        // Left after the key: the value's option tag, the value, the kind.
+        let rest = entry_len
+            .checked_sub(16)
+            .and_then(|rest| rest.checked_sub(key_len))
+            .filter(|rest| *rest >= 2)
+            .ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, "record shorter than its key")
+            })?;
+        let mut key = vec![0u8; key_len as usize];
+        if !read_full(reader, &mut key)? {
+            return Ok(None);
+        }
+
+        // Small tails are read whole; large ones only up to an expiry, and
+        // then from the origin, if any, on.
+        let mut tail = vec![0u8; rest.min(FAST_TAIL_READ) as usize];
+        if !read_full(reader, &mut tail)? {
+            return Ok(None);
+        }
+        let origin_len = match tail[0] & codec::VALUE_FLAG_ORIGIN {
+            0 => 0,
+            _ => ORIGIN_SIZE as u64,
+        };
+        if rest > FAST_TAIL_READ {
+            let end_len = (origin_len + 1).min(rest - FAST_TAIL_READ);
+            reader.seek_relative((rest - FAST_TAIL_READ - end_len) as i64)?;
+            tail.resize(FAST_TAIL_READ as usize + end_len as usize, 0);
+            if !read_full(reader, &mut tail[FAST_TAIL_READ as usize..])? {
+                return Ok(None);
+            }
+        }
+        let kind_byte = tail.pop().unwrap();
+        let origin = match origin_len {
+            0 => None,
+            _ => {
+                let at = tail.len().checked_sub(ORIGIN_SIZE).ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidData, "record shorter than its origin")
+                })?;
+                Some(tail.split_off(at).try_into().unwrap())
+            }
+        };
+        let kind = EntryKind::from_tag(kind_byte).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unknown entry kind {}", kind_byte),
+            )
+        })?;
+
+        let value = match kind {
+            EntryKind::BlobRef
+            | EntryKind::BatchBegin
+            | EntryKind::ExpiringPut
+            | EntryKind::Epoch
+            | EntryKind::Archived => {
+                // `tail` is now the option tag, the length, and the value.
+                let body = tail.get(9..).unwrap_or_default();
+                if kind != EntryKind::ExpiringPut && rest > FAST_TAIL_READ {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("{:?} value too long", kind),
+                    ));
+                }
+                Some(body.to_vec())
+            }
+            _ => None,
+        };
+        Ok(Some(DataFileEntry {
+            tstamp,
+            key,
+            value,
+            kind,
+            origin,
+        }))
+    }
+
+    /// Re-reads the data file from disk and replaces all in-memory state
+    /// with it, keeping this `Engine` value (and every `Arc` to it) usable.
+    ///
+    /// Header validation, torn-tail truncation, and index replay run as on
+    /// [`Engine::load`], with writes and compaction blocked. Reads already in
+    /// flight finish against the old state first. A file that was removed is
+    /// recreated empty, as `load` would.
+    pub fn reload(&self) -> io::Result<ReloadReport> {
+        let mut file = self.lock_file();
+        self.check_open()?;
+        let header = Self::ensure_header(
+            self.storage.as_ref(),
+            &self.path,
+            None,
+            false,
+            self.options.audit_mode,
+            key_transform_id(&self.options),
+        )?;
+        *file = self.storage.open(&self.path, OpenMode::ReadWrite)?;
+        *self.header_file.lock().unwrap() = None;
+
+        let report = self.rebuild_index(&mut file, header.format_version, LoadMode::Standard)?;
+        // The rebuild cut off any torn tail.
+        self.torn_tail.store(false, Ordering::Release);
+        let (compact_threshold, threshold_source) = Self::resolve_threshold(&self.options, &header);
+        self.compact_threshold
+            .store(compact_threshold, Ordering::Release);
+        *self.threshold_source.lock().unwrap() = threshold_source;
+        // The file may have changed hands; check before the next write.
+        *self.last_fence_check.lock().unwrap() = None;
+        drop(file);
+        self.deliver_limit_warnings();
+        Ok(report.replay)
+    }
+
+    /// What the load that opened this engine found, and in which
+    /// `OpenMode`. Later `reload`s report through their return value.
+    pub fn load_report(&self) -> &LoadReport {
+        &self.load_report
+    }
+
+    pub fn set(&self, key: &[u8], value: &[u8]) -> io::Result<()> {
+        let key = &*self.raw_key(key)?;
+        self.set_key(key, value)
+    }
+
+    /// Runs `f` with `context` attached to every operation it makes on this
+    /// thread, on any engine: `Options::on_set`, `Options::on_slow_op`, and
+    /// the operation journal report it. Scopes nest; the inner context wins
+    /// until its scope ends. The engine reads the context only when one of
+    /// those is set.
+    pub fn with_context<T>(&self, context: OpContext, f: impl FnOnce() -> T) -> T {
+        context::scope(context, f)
+    }
+
+    /// Calls `Options::on_set`, if set, for a set of `key` to `value` from
+    /// `origin`, or `Options::writer_id` without one. Call with no engine
+    /// lock held.
+    fn notify_set(&self, key: &[u8], value: &[u8], origin: Option<[u8; ORIGIN_SIZE]>) {
+        if let Some(hook) = &self.options.on_set {
+            hook.call(&SetEvent {
+                key: key.to_vec(),
+                value_len: value.len(),
+                context: context::current(),
+                origin: origin.or(self.options.writer_id),
+            });
+        }
+    }
+
+    /// Whether exports and counts leave `key` out: an internal key, without
+    /// `Options::include_internal_keys`.
+    fn is_hidden_key(&self, key: &[u8]) -> bool {
+        !self.options.include_internal_keys && bucket::is_internal_key(key)
+    }
+
+    /// `key` as `Options::key_transform` rewrites it, which is how it is
+    /// stored.
+    pub(crate) fn transform_key<'a>(&self, key: &'a [u8]) -> Cow<'a, [u8]> {
+        match &self.options.key_transform {
+            Some(transform) => Cow::Owned(transform.apply(key)),
+            None => Cow::Borrowed(key),
+        }
+    }
+
+    /// `key` transformed, and checked as a raw key to read or write.
+    fn raw_key<'a>(&self, key: &'a [u8]) -> io::Result<Cow<'a, [u8]>> {
+        let key = self.transform_key(key);
+        check_raw_key(&key)?;
+        Ok(key)
+    }
+
+    /// `prefix` transformed, and checked as a raw key or prefix to read.
+    fn raw_prefix<'a>(&self, prefix: &'a [u8]) -> io::Result<Cow<'a, [u8]>> {
+        let prefix = self.transform_key(prefix);
+        check_raw_prefix(&prefix)?;
+        Ok(prefix)
+    }
+
+    /// Checks a put of `key` against `Options::max_key_size`.
+    fn check_key_size(&self, key: &[u8]) -> io::Result<()> {
+        match self.options.max_key_size {
+            Some(max) if key.len() > max => Err(EngineError::KeyTooLarge {
+                len: key.len(),
+                max,
+            }
+            .into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks a put of `key` against `Options::max_key_size`, then runs the
+    /// validator that applies to `key` (its bucket's, or
+    /// `Options::validate`) on `value`.
+    fn validate_value(&self, key: &[u8], value: &[u8]) -> io::Result<()> {
+        self.check_key_size(key)?;
+        let (validator, key) = match bucket::split_key(key) {
+            Some((namespace, key)) => {
+                let validators = &self.options.bucket_validators;
+                let own = match validators.is_empty() {
+                    true => None,
+                    false => {
+                        let name = self.bucket_of_namespace(namespace);
+                        validators
+                            .iter()
+                            .find(|(bucket, _)| *bucket == name)
+                            .map(|(_, validator)| validator)
+                    }
+                };
+                (own.or(self.options.validate.as_ref()), key)
+            }
+            None => (self.options.validate.as_ref(), key),
+        };
+        match validator {
+            Some(validator) => validator
+                .call(key, value)
+                .map_err(|reason| EngineError::ValidationFailed(reason).into()),
+            None => Ok(()),
+        }
+    }
+
+    /// `set` without the raw-namespace check, for [`Bucket`].
+    pub(crate) fn set_key(&self, key: &[u8], value: &[u8]) -> io::Result<()> {
+        self.set_key_from(key, value, None)
+    }
+
+    /// `set`, recording `origin` as the writer the value came from instead
+    /// of `Options::writer_id`, as a replica applying another writer's
+    /// changes does. Needs a KVS7 file.
+    pub fn set_with_origin(
+        &self,
+        key: &[u8],
+        value: &[u8],
+        origin: [u8; ORIGIN_SIZE],
+    ) -> io::Result<()> {
+        let key = &*self.raw_key(key)?;
+        self.set_key_from(key, value, Some(origin))
+    }
+
+    /// `set_key` from `origin`, or `Options::writer_id` without one.
+    fn set_key_from(
+        &self,
+        key: &[u8],
+        value: &[u8],
+        origin: Option<[u8; ORIGIN_SIZE]>,
+    ) -> io::Result<()> {
+        Metrics::incr(&self.metrics.sets);
+        self.timed(
+            SlowOpKind::Set,
+            key,
+            Some(&self.metrics.set_latency),
+            || self.set_inner(key, value, origin),
+        )?;
+        self.notify_set(key, value, origin);
+        Ok(())
+    }
+
+    fn set_inner(
+        &self,
+        key: &[u8],
+        value: &[u8],
+        origin: Option<[u8; ORIGIN_SIZE]>,
+    ) -> io::Result<()> {
+        check_key(key)?;
+        self.validate_value(key, value)?;
+        let _key = self.key_locks.lock(key);
+        let result = if self
+            .options
+            .dedup_min_value_len
+            .is_some_and(|min| value.len() >= min)
+            && self.format_version.load(Ordering::Acquire) != 1
+        {
+            self.set_dedup(key, value, origin)
+        } else {
+            self.write_entry(
+                DataFileEntry::put(now_millis(), key.to_vec(), value.to_vec()).with_origin(origin),
+            )
+        };
+        #[cfg(feature = "oplog-debug")]
+        if result.is_ok()
+            && let Some(journal) = self.journal()
+        {
+            journal.record(JournalOp::Set, key, Some(value));
+        }
+        result
+    }
+
+    /// `set`, returning where the record was written so an external index
+    /// can read it back with [`Engine::get_at_index`]. The value is always
+    /// stored inline, never deduplicated.
+    pub fn set_indexed(&self, key: &[u8], value: &[u8]) -> io::Result<RecordRef> {
+        let key = &*self.raw_key(key)?;
+        Metrics::incr(&self.metrics.sets);
+        self.timed(
+            SlowOpKind::Set,
+            key,
+            Some(&self.metrics.set_latency),
+            || {
+                check_key(key)?;
+                self.validate_value(key, value)?;
+                let _key = self.key_locks.lock(key);
+                let mut file = self.lock_file();
+                let entry = DataFileEntry::put(now_millis(), key.to_vec(), value.to_vec());
+                let index = self
+                    .append_entries_locked(&mut file, std::slice::from_ref(&entry))?
+                    .remove(0);
+                // The file only changes generation under the file mutex.
+                let generation = self.generation.load(Ordering::Acquire);
+                let new_file_size = *self.file_size.lock().unwrap();
+                #[cfg(feature = "oplog-debug")]
+                if let Some(journal) = self.journal() {
+                    journal.record(JournalOp::Set, key, Some(value));
+                }
+                self.finish_write(file, EntryKind::Put, new_file_size)?;
+                Ok(RecordRef { index, generation })
+            },
+        )
+        .inspect(|_| self.notify_set(key, value, None))
+    }
+
+    /// Reads the record `record` refers to, whatever has been written to
+    /// its key since. Fails with `EngineError::RecordMoved` once compaction
+    /// or a reload has replaced the file it was written to. References are
+    /// only meaningful to the engine that returned them.
+    pub fn get_at_index(&self, record: &RecordRef) -> io::Result<DataFileEntry> {
+        let moved = |current| {
+            io::Error::from(EngineError::RecordMoved {
+                generation: record.generation,
+                current,
+            })
+        };
+        let current = self.generation.load(Ordering::Acquire);
+        if current != record.generation {
+            return Err(moved(current));
+        }
+        let format_version = self.format_version.load(Ordering::Acquire);
+        let data = self.read_at(record.index.pos, record.index.len);
+        // A swap may have started while reading.
+        let current = self.generation.load(Ordering::Acquire);
+        if current != record.generation {
+            return Err(moved(current));
+        }
+        Self::decode_entry(format_version, &data?)
+    }
+
+    /// Sets `key` with a caller-supplied timestamp (ms since the epoch), as
+    /// replication does. When the stored value is newer, `Options::
+    /// conflict_policy` decides the outcome. Returns whether `value` became
+    /// current. Values are stored inline, never deduplicated.
+    pub fn set_with_tstamp(&self, key: &[u8], value: &[u8], tstamp: i64) -> io::Result<bool> {
+        let key = &*self.raw_key(key)?;
+        let current = self.set_resolved(key, value, tstamp, self.options.conflict_policy)?;
+        if current {
+            self.notify_set(key, value, None);
+        }
+        Ok(current)
+    }
+
+    fn set_resolved(
+        &self,
+        key: &[u8],
+        value: &[u8],
+        tstamp: i64,
+        policy: ConflictPolicy,
+    ) -> io::Result<bool> {
+        check_key(key)?;
+        self.validate_value(key, value)?;
+        let _key = self.key_locks.lock(key);
+        // Held across the check so no other write lands in between.
+        let mut file = self.lock_file();
+        let stored = match policy {
+            ConflictPolicy::AlwaysAccept => None,
+            _ => self.stored_tstamp(key)?,
+        };
+        let kind = match stored {
+            Some(stored) if tstamp < stored => match policy {
+                ConflictPolicy::RejectOlder => {
+                    return Err(EngineError::StaleWrite {
+                        stored,
+                        attempted: tstamp,
+                    }
+                    .into());
+                }
+                _ => EntryKind::StalePut,
+            },
+            _ => EntryKind::Put,
+        };
+        // KVS1 records have no kind to mark the loser with.
+        if kind == EntryKind::StalePut && self.format_version.load(Ordering::Acquire) == 1 {
+            return Ok(false);
+        }
+
+        let entry = DataFileEntry {
+            kind,
+            ..DataFileEntry::put(tstamp, key.to_vec(), value.to_vec())
+        };
+        let new_file_size = self.append_locked(&mut file, &entry)?;
+        #[cfg(feature = "oplog-debug")]
+        if kind == EntryKind::Put
+            && let Some(journal) = self.journal()
+        {
+            journal.record(JournalOp::Set, key, Some(value));
+        }
+        self.finish_write(file, kind, new_file_size)?;
+        Ok(kind == EntryKind::Put)
+    }
+
+    /// Timestamp of `key`'s current value, if it has one.
+    fn stored_tstamp(&self, key: &[u8]) -> io::Result<Option<i64>> {
+        let index = self.index_read();
+        if index.is_expired(key) {
+            return Ok(None);
+        }
+        match index.live.get(key) {
+            Some(log_index) => Ok(Some(self.read_entry(log_index)?.tstamp)),
+            None => Ok(None),
+        }
+    }
+
+    /// Writes `value` as a shared blob (unless an identical one is already
+    /// stored) and `key`, from `origin`, as a reference to it.
+    fn set_dedup(
+        &self,
+        key: &[u8],
+        value: &[u8],
+        origin: Option<[u8; ORIGIN_SIZE]>,
+    ) -> io::Result<()> {
+        let hash = Sha1::digest(value).to_vec();
+        let tstamp = now_millis();
+        let mut file = self.lock_file();
+        // Checked up front so a refused key does not leave an orphan blob.
+        self.check_index_room(std::iter::once((EntryKind::BlobRef, key)))?;
+        self.check_quotas(std::iter::once((key, value.len() as u64)))?;
+
+        // A matching hash is only trusted once the stored bytes compare
+        // equal; on a collision the value is stored inline instead.
+        let existing = {
+            let index = self.index_read();
+            match index.blobs.get(&hash) {
+                Some(blob) => Some(self.read_entry(&blob.index)?.value.as_deref() == Some(value)),
+                None => None,
+            }
+        };
+
+        let entry = match existing {
+            Some(false) => DataFileEntry::put(tstamp, key.to_vec(), value.to_vec()),
+            matched => {
+                if matched.is_none() {
+                    let blob = DataFileEntry {
+                        tstamp,
+                        key: hash.clone(),
+                        value: Some(value.to_vec()),
+                        kind: EntryKind::Blob,
+                        origin: None,
+                    };
+                    self.append_locked(&mut file, &blob)?;
+                }
+                DataFileEntry {
+                    tstamp,
+                    key: key.to_vec(),
+                    value: Some(hash),
+                    kind: EntryKind::BlobRef,
+                    origin: None,
+                }
+            }
+        }
+        .with_origin(origin);
+
+        let new_file_size = self.append_locked(&mut file, &entry)?;
+        self.finish_write(file, entry.kind, new_file_size)
+    }
+
+    pub fn del(&self, key: &[u8]) -> io::Result<()> {
+        let key = &*self.raw_key(key)?;
+        self.del_key(key)
+    }
+
+    pub(crate) fn del_key(&self, key: &[u8]) -> io::Result<()> {
+        self.timed(SlowOpKind::Del, key, None, || {
+            check_key(key)?;
+            let _key = self.key_locks.lock(key);
+            self.write_entry(DataFileEntry::tombstone(now_millis(), key.to_vec()))?;
+            #[cfg(feature = "oplog-debug")]
+            if let Some(journal) = self.journal() {
+                journal.record(JournalOp::Del, key, None);
+            }
+            Ok(())
+        })
+    }
+
+    /// Reads `keys`, passes their current values to `f`, and writes back what
+    /// it returns, position for position; `None` deletes the key. No other
+    /// write to any of the keys can land in between, and readers see either
+    /// none or all of the new values.
+    ///
+    /// The new records are appended with one write, but a crash partway
+    /// through it can still leave a prefix of them in the log.
+    pub fn update_many(
+        &self,
+        keys: &[&[u8]],
+        f: impl FnOnce(&[Option<Vec<u8>>]) -> Vec<Option<Vec<u8>>>,
+    ) -> io::Result<()> {
+        let keys = keys
+            .iter()
+            .map(|key| self.raw_key(key))
+            .collect::<io::Result<Vec<_>>>()?;
+        let keys: Vec<&[u8]> = keys.iter().map(|key| &**key).collect();
+        let _keys = self.key_locks.lock_all(&keys);
+
+        let current = keys
+            .iter()
+            .map(|key| Ok(self.get_inner(key)?.map(|(value, _)| value)))
+            .collect::<io::Result<Vec<_>>>()?;
+        let updated = f(&current);
+        if updated.len() != keys.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "update_many: got {} values for {} keys",
+                    updated.len(),
+                    keys.len()
+                ),
+            ));
+        }
+        if keys.is_empty() {
+            return Ok(());
+        }
+        for (key, value) in keys.iter().zip(&updated) {
+            if let Some(value) = value {
+                self.validate_value(key, value)?;
+            }
+        }
+
+        let tstamp = now_millis();
+        let entries: Vec<DataFileEntry> = keys
+            .iter()
+            .zip(updated)
+            .map(|(key, value)| match value {
+                Some(value) => DataFileEntry::put(tstamp, key.to_vec(), value),
+                None => DataFileEntry::tombstone(tstamp, key.to_vec()),
+            })
+            .collect();
+        let kind = match entries.iter().any(|e| e.kind == EntryKind::Put) {
+            true => EntryKind::Put,
+            false => EntryKind::Tombstone,
+        };
+
+        let mut file = self.lock_file();
+        let new_file_size = self.append_batch_locked(&mut file, &entries)?;
+        #[cfg(feature = "oplog-debug")]
+        if let Some(journal) = self.journal() {
+            journal.record_group(&entries);
+        }
+        self.finish_write(file, kind, new_file_size)
+    }
+
+    /// Deletes `key` but keeps its value recoverable through [`Engine::restore`]
+    /// for `Options::soft_delete_window`. Soft-deleting an absent key is a no-op.
+    pub fn soft_del(&self, key: &[u8]) -> io::Result<()> {
+        let key = &*self.raw_key(key)?;
+        let _key = self.key_locks.lock(key);
+        let mut file = self.lock_file();
+
+        let value = {
+            let index = self.index_read();
+            let log_index = match index.live.get(key) {
+                Some(idx) if !index.is_expired(key) => idx,
+                _ => return Ok(()),
+            };
+            let (_, _, value_index) = index.value_location(key, log_index);
+            self.read_entry(value_index)?.into_value()
+        };
+
+        let entry = DataFileEntry {
+            tstamp: now_millis(),
+            key: key.to_vec(),
+            value,
+            kind: EntryKind::SoftDelete,
+            origin: None,
+        };
+        self.append_locked(&mut file, &entry)?;
+        #[cfg(feature = "oplog-debug")]
+        if let Some(journal) = self.journal() {
+            journal.record(JournalOp::SoftDel, key, None);
+        }
+        drop(file);
+        self.deliver_limit_warnings();
+
+        Ok(())
+    }
+
+    /// Brings back a soft-deleted key. Fails with `NotFound` when the key was
+    /// never soft-deleted, was overwritten or hard-deleted since, or its
+    /// restore window has passed.
+    pub fn restore(&self, key: &[u8]) -> io::Result<()> {
+        let key = &*self.raw_key(key)?;
+        let _key = self.key_locks.lock(key);
+        let mut file = self.lock_file();
+
+        let soft_deleted = match self.index_read().soft_deleted.get(key) {
+            Some(sd) if !self.soft_delete_expired(sd) => sd.clone(),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "no recoverable soft-deleted entry for key",
+                ));
+            }
+        };
+        let value = self
+            .read_entry(&soft_deleted.index)?
+            .value
+            .unwrap_or_default();
+
+        let entry = DataFileEntry::put(now_millis(), key.to_vec(), value);
+        self.append_locked(&mut file, &entry)?;
+        #[cfg(feature = "oplog-debug")]
+        if let Some(journal) = self.journal() {
+            journal.record(JournalOp::Restore, key, None);
+        }
+        drop(file);
+        self.deliver_limit_warnings();
+
+        Ok(())
+    }
+
+    /// Lists soft-deleted keys that can still be restored, with the
+    /// timestamp (ms since the epoch) at which each was deleted.
+    pub fn scan_deleted(&self) -> Vec<(Vec<u8>, i64)> {
+        let mut deleted: Vec<(Vec<u8>, i64)> = self
+            .index
+            .read()
+            .unwrap()
+            .soft_deleted
+            .iter()
+            .filter(|(_, sd)| !self.soft_delete_expired(sd))
+            .map(|(k, sd)| (k.clone(), sd.deleted_at))
+            .collect();
+        deleted.sort();
+        deleted
+    }
+
+    /// Turns soft-deleted keys whose restore window has passed into real
+    /// tombstones. Returns how many were expired.
+    pub fn expire_soft_deleted(&self) -> io::Result<usize> {
+        let mut file = self.lock_file();
+
+        let expired: Vec<Vec<u8>> = self
+            .index
+            .read()
+            .unwrap()
+            .soft_deleted
+            .iter()
+            .filter(|(_, sd)| self.soft_delete_expired(sd))
+            .map(|(k, _)| k.clone())
+            .collect();
+
+        for key in &expired {
+            self.append_locked(
+                &mut file,
+                &DataFileEntry::tombstone(now_millis(), key.clone()),
+            )?;
+        }
+        #[cfg(feature = "oplog-debug")]
+        if let Some(journal) = self.journal() {
+            journal.record(JournalOp::ExpireSoftDeleted, &[], None);
+        }
+        drop(file);
+        self.deliver_limit_warnings();
+
+        Ok(expired.len())
+    }
+
+    fn soft_delete_expired(&self, soft_deleted: &SoftDeleted) -> bool {
+        let window = self.options.soft_delete_window.as_millis() as i64;
+        now_millis().saturating_sub(soft_deleted.deleted_at) > window
+    }
+
+    /// Moves the values of keys last written more than `older_than` ago to
+    /// the archive file under `Options::archive_path`; see
+    /// [`Engine::archive_where`]. Returns how many were moved.
+    pub fn archive(&self, older_than: Duration) -> io::Result<usize> {
+        let cutoff = now_millis().saturating_sub(older_than.as_millis() as i64);
+        self.archive_selected(|_| true, cutoff)
+    }
+
+    /// Moves the values of the keys `select` picks to the archive file
+    /// under `Options::archive_path`, the cold tier, and returns how many
+    /// were moved. Each key's record in the data file is replaced by a stub
+    /// naming where its value went, which `get` and scans follow to the
+    /// archive, and which compaction keeps in place of the value. Writing
+    /// the key again brings it back to the data file.
+    ///
+    /// Only plain puts move: keys with a TTL, deduplicated values, keys
+    /// already archived, and bucketed keys stay where they are. Writes wait
+    /// until it is done. Fails with `InvalidInput` without an
+    /// `archive_path` and `Unsupported` on a KVS1 file.
+    pub fn archive_where(&self, select: impl FnMut(&[u8]) -> bool) -> io::Result<usize> {
+        self.archive_selected(select, i64::MAX)
+    }
+
+    /// Archives the plain puts `select` picks among those written before
+    /// `cutoff` (ms since the epoch), `ARCHIVE_CHUNK_RECORDS` at a time.
+    fn archive_selected(
+        &self,
+        mut select: impl FnMut(&[u8]) -> bool,
+        cutoff: i64,
+    ) -> io::Result<usize> {
+        let Some(archive) = &self.archive else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "archiving needs Options::archive_path",
+            ));
+        };
+        // Held throughout, so no write lands between reading a value and
+        // replacing it with a stub.
+        let mut file = self.lock_file();
+        self.check_writable()?;
+        if self.format_version.load(Ordering::Acquire) == 1 {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "archiving needs a KVS2 or later file",
+            ));
+        }
+
+        let keys: Vec<Vec<u8>> = {
+            let index = self.index_read();
+            let mut keys: Vec<Vec<u8>> = index
+                .live
+                .keys()
+                .filter(|key| {
+                    !bucket::is_bucket_key(key)
+                        && !index.refs.contains_key(*key)
+                        && !index.expiries.contains_key(*key)
+                        && !index.archived.contains_key(*key)
+                })
+                .filter(|key| select(key))
+                .cloned()
+                .collect();
+            keys.sort();
+            keys
+        };
+
+        let mut moved = 0;
+        let mut new_file_size = *self.file_size.lock().unwrap();
+        for chunk in keys.chunks(ARCHIVE_CHUNK_RECORDS) {
+            let mut records = Vec::with_capacity(chunk.len());
+            {
+                let index = self.index_read();
+                for key in chunk {
+                    let entry = self.read_entry(&index.live[key])?;
+                    if entry.kind == EntryKind::Put && entry.tstamp < cutoff {
+                        records.push(entry);
+                    }
+                }
+            }
+            if records.is_empty() {
+                continue;
+            }
+            let archived_at = archive.append(self.storage.as_ref(), &records)?;
+            let stubs: Vec<DataFileEntry> = records
+                .into_iter()
+                .zip(&archived_at)
+                .map(|(record, at)| {
+                    DataFileEntry::archived(record.tstamp, record.key, at)
+                        .with_origin(record.origin)
+                })
+                .collect();
+            new_file_size = self.append_batch_locked(&mut file, &stubs)?;
+            moved += stubs.len();
+        }
+        self.finish_write(file, EntryKind::Archived, new_file_size)?;
+        Ok(moved)
+    }
+
+    /// Appends one record through the write queue, so concurrent writers on
+    /// different keys share a file lock and a write instead of taking turns.
+    pub(crate) fn write_entry(&self, entry: DataFileEntry) -> io::Result<()> {
+        let kind = entry.kind;
+        let written = self
+            .write_queue
+            .write(entry, |group| self.append_group(group))?;
+        if self.slow_ops.is_some() {
+            slow_op::note(|d| d.offset = Some(written.pos));
+        }
+        self.deliver_limit_warnings();
+        if self.compaction_due(kind, written.end) {
+            self.auto_compact()?;
+        }
+        Ok(())
+    }
+
+    /// Appends a group from the write queue with one write. With index
+    /// limits set, each record is checked and appended on its own, so one
+    /// refused key does not fail the rest of the group.
+    fn append_group(&self, entries: &[DataFileEntry]) -> Vec<io::Result<Written>> {
+        let mut file = self.lock_file();
+        if self.options.max_index_entries.is_some()
+            || self.options.max_index_bytes.is_some()
+            || !self.quotas.read().unwrap().is_empty()
+        {
+            return entries
+                .iter()
+                .map(|entry| {
+                    let log_indexes =
+                        self.append_entries_locked(&mut file, std::slice::from_ref(entry))?;
+                    Ok(Written::from(&log_indexes[0]))
+                })
+                .collect();
+        }
+
+        match self.append_entries_locked(&mut file, entries) {
+            Ok(log_indexes) => log_indexes
+                .into_iter()
+                .map(|log_index| Ok(Written::from(&log_index)))
+                .collect(),
+            Err(e) => entries.iter().map(|_| Err(error::duplicate(&e))).collect(),
+        }
+    }
+
+    /// Releases the file mutex after an append and runs auto-compaction if
+    /// the append tripped a trigger.
+    fn finish_write(
+        &self,
+        file: MutexGuard<'_, FileHandle>,
+        kind: EntryKind,
+        new_file_size: u64,
+    ) -> io::Result<()> {
+        let due = self.compaction_due(kind, new_file_size);
+        drop(file);
+        self.deliver_limit_warnings();
+        if due {
+            self.auto_compact()?;
+        }
+        Ok(())
+    }
+
+    /// Whether a write of `kind` that left the file at `new_file_size`
+    /// trips auto-compaction.
+    fn compaction_due(&self, kind: EntryKind, new_file_size: u64) -> bool {
+        if self.options.audit_mode {
+            return false;
+        }
+        // Only sets trigger size-based auto-compaction; a tombstone never grows
+        // the live set, but enough of them trip the tombstone trigger instead.
+        // A trigger put off by the minimum interval is due again on any
+        // write, so the write after the interval ends runs it.
+        let is_set = matches!(
+            kind,
+            EntryKind::Put | EntryKind::BlobRef | EntryKind::ExpiringPut
+        ) || self.compaction_deferred.load(Ordering::Acquire);
+        (is_set && new_file_size >= self.effective_threshold(new_file_size))
+            || self.tombstone_trigger_hit(new_file_size)
+    }
+
+    /// The file size at which a set trips auto-compaction. Normally the
+    /// configured threshold, but once live bytes alone reach it, compacting
+    /// would barely shrink the file, so it waits for the file to reach
+    /// `LIVE_COMPACT_FACTOR` times the live bytes instead. It runs on every
+    /// set, so it reads both from atomics rather than taking a lock.
+    fn effective_threshold(&self, file_size: u64) -> u64 {
+        let threshold = self.compact_threshold.load(Ordering::Acquire);
+        let live = file_size.saturating_sub(self.dead_bytes.load(Ordering::Acquire));
+        if live >= threshold {
+            live.saturating_mul(LIVE_COMPACT_FACTOR)
+        } else {
+            threshold
+        }
+    }
+
+    fn auto_compact(&self) -> io::Result<()> {
+        let Some(start) = self.start_auto_compaction() else {
+            Metrics::incr(&self.metrics.compactions_deferred);
+            self.compaction_deferred.store(true, Ordering::Release);
+            return Ok(());
+        };
+        // The write itself already succeeded; running short of space for the
+        // compacted copy only postpones compaction.
+        slow_op::note(|d| d.auto_compaction = true);
+        let deadline = self
+            .options
+            .auto_compact_deadline
+            .map(|limit| Instant::now() + limit);
+        let result = self.compact_until(deadline);
+        if !matches!(result, Ok(true)) {
+            // Nothing was compacted, so the next trigger need not wait.
+            self.undo_auto_compaction_start(start);
+        }
+        match result {
+            Ok(_) => Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::StorageFull => Ok(()),
+            Err(e) if EngineError::from_io(&e) == Some(&EngineError::ReadOnlyMode) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Whether `Options::min_compaction_interval` lets an automatic
+    /// compaction start now; if so, the interval starts over, and the
+    /// start is returned for `undo_auto_compaction_start`.
+    fn start_auto_compaction(&self) -> Option<AutoCompactionStart> {
+        let Some(interval) = self.options.min_compaction_interval else {
+            return Some(None);
+        };
+        let now = self.options.clock.now();
+        let mut last = self.last_auto_compaction.lock().unwrap();
+        if last.is_some_and(|last| now.saturating_duration_since(last) < interval) {
+            return None;
+        }
+        let previous = last.replace(now);
+        Some(Some((now, previous)))
+    }
+
+    /// Puts back the last start from before `start`, for an automatic
+    /// compaction that was abandoned or failed, unless another has been
+    /// stamped since.
+    fn undo_auto_compaction_start(&self, start: AutoCompactionStart) {
+        let Some((stamped, previous)) = start else {
+            return;
+        };
+        let mut last = self.last_auto_compaction.lock().unwrap();
+        if *last == Some(stamped) {
+            *last = previous;
+        }
+    }
+
+    fn tombstone_trigger_hit(&self, file_size: u64) -> bool {
+        let (count, ratio) = (
+            self.options.tombstone_compact_count,
+            self.options.tombstone_compact_ratio,
+        );
+        if count.is_none() && ratio.is_none() {
+            return false;
+        }
+
+        let index = self.index_read();
+        let header_size =
+            format_info::by_version(self.format_version.load(Ordering::Acquire)).header_size;
+        let log_bytes = file_size.saturating_sub(header_size);
+        count.is_some_and(|max| index.tombstones >= max)
+            || ratio.is_some_and(|max| {
+                log_bytes > 0 && index.tombstone_bytes as f64 / log_bytes as f64 >= max
+            })
+    }
+
+    /// Gives back the disk reserved by `Options::preallocate_chunk` beyond
+    /// the end of the log plus one chunk, and returns how many bytes that
+    /// released. The chunk kept is room for the next appends, which do not
+    /// allocate again until they fill it. Reads and writes carry on around
+    /// it. Compaction does the same to the allocation it carries over.
+    pub fn shrink_to_fit(&self) -> io::Result<u64> {
+        let file = self.lock_file();
+        self.check_open()?;
+        // The file may belong to another writer, with records past our end.
+        self.check_fence(true)?;
+        let end = *self.file_size.lock().unwrap();
+        let physical = file.len()?;
+        let keep = self.allocation_to_keep(end, physical);
+        if physical <= keep && !self.torn_tail.load(Ordering::Acquire) {
+            return Ok(0);
+        }
+        // Cut at the end of the log first, so the room kept reads as zeros
+        // whatever a failed append left past the end.
+        file.set_len(end)?;
+        self.allocated_size.store(end, Ordering::Release);
+        self.torn_tail.store(false, Ordering::Release);
+        if keep > end {
+            file.allocate(keep)?;
+            self.allocated_size.store(keep, Ordering::Release);
+        }
+        Ok(physical.saturating_sub(keep))
+    }
+
+    /// How much of an `allocated` file to keep for a log ending at `end`:
+    /// all of it, up to one `Options::preallocate_chunk` past the end, and
+    /// just the log without pre-allocation.
+    fn allocation_to_keep(&self, end: u64, allocated: u64) -> u64 {
+        match self.options.preallocate_chunk {
+            Some(chunk) if chunk > 0 => allocated.min(end.saturating_add(chunk)).max(end),
+            _ => end,
+        }
+    }
+
+    /// Grows the physical file in `Options::preallocate_chunk` steps so it can
+    /// hold `needed` bytes. The caller must hold the file mutex.
+    fn ensure_allocated(&self, file: &mut FileHandle, needed: u64) -> io::Result<()> {
+        let chunk = match self.options.preallocate_chunk {
+            Some(chunk) if chunk > 0 => chunk,
+            _ => return Ok(()),
+        };
+        if needed <= self.allocated_size.load(Ordering::Acquire) {
+            return Ok(());
+        }
+
+        let target = needed.div_ceil(chunk).saturating_mul(chunk);
+        file.allocate(target)?;
+        self.allocated_size.store(target, Ordering::Release);
+        Ok(())
+    }
+
+    /// Appends `entry` to the log and applies it to the in-memory index.
+    /// Returns the new file size. The caller must hold the file mutex.
+    fn append_locked(&self, file: &mut FileHandle, entry: &DataFileEntry) -> io::Result<u64> {
+        self.append_batch_locked(file, std::slice::from_ref(entry))
+    }
+
+    /// Appends `entries` back to back with a single write, then applies them
+    /// to the index under one write lock, so readers see all or none of them.
+    /// Returns the new file size. The caller must hold the file mutex.
+    fn append_batch_locked(
+        &self,
+        file: &mut FileHandle,
+        entries: &[DataFileEntry],
+    ) -> io::Result<u64> {
+        let log_indexes = self.append_entries_locked(file, entries)?;
+        if self.slow_ops.is_some() {
+            slow_op::note(|d| d.offset = log_indexes.last().map(|l| l.pos));
+        }
+        Ok(*self.file_size.lock().unwrap())
+    }
+
+    /// `append_batch_locked`, returning where each entry landed.
+    fn append_entries_locked(
+        &self,
+        file: &mut FileHandle,
+        entries: &[DataFileEntry],
+    ) -> io::Result<Vec<LogIndex>> {
+        let format_version = self.format_version.load(Ordering::Acquire);
+        let entries = &*self.stamp_origins(entries);
+        self.check_index_room(entries.iter().map(|e| (e.kind, e.key.as_slice())))?;
+        self.check_quotas(
+            entries
+                .iter()
+                .filter_map(|e| Some((e.key.as_slice(), put_value_len(e)?))),
+        )?;
+
+        let mut buf = Vec::new();
+        let mut spans = Vec::with_capacity(entries.len());
+        for entry in entries {
+            spans.push(format::encode_record(format_version, entry, &mut buf)?);
+        }
+
+        let start = self.append_raw_locked(file, &buf)?;
+        let log_indexes: Vec<LogIndex> = spans
+            .into_iter()
+            .map(|span| LogIndex {
+                pos: start + span.pos,
+                len: span.len,
+            })
+            .collect();
+
+        let mut index = self.index_mut();
+        for (entry, log_index) in entries.iter().zip(&log_indexes) {
+            index.apply_entry(entry, log_index.clone());
+        }
+
+        Ok(log_indexes)
+    }
+
+    /// `entries` with `Options::writer_id` as the origin of each that writes
+    /// a key and names no origin of its own.
+    fn stamp_origins<'a>(&self, entries: &'a [DataFileEntry]) -> Cow<'a, [DataFileEntry]> {
+        let Some(writer_id) = self.options.writer_id else {
+            return Cow::Borrowed(entries);
+        };
+        Cow::Owned(
+            entries
+                .iter()
+                .map(
+                    |entry| match entry.origin.is_none() && takes_origin(entry.kind) {
+                        true => entry.clone().with_origin(Some(writer_id)),
+                        false => entry.clone(),
+                    },
+                )
+                .collect(),
+        )
+    }
+
+    /// Fails with `IndexFull` if writing records of these kinds and keys
+    /// would grow the live index past `Options::max_index_entries` or
+    /// `max_index_bytes`. Call with the file mutex held, so the index cannot
+    /// grow between the check and the append.
+    fn check_index_room<'a>(
+        &self,
+        records: impl Iterator<Item = (EntryKind, &'a [u8])>,
+    ) -> io::Result<()> {
+        let (max_entries, max_bytes) =
+            (self.options.max_index_entries, self.options.max_index_bytes);
+        if max_entries.is_none() && max_bytes.is_none() {
+            return Ok(());
+        }
+
+        let index = self.index_read();
+        let mut new_keys: Vec<&[u8]> = records
+            .filter(|(kind, _)| {
+                matches!(
+                    kind,
+                    EntryKind::Put | EntryKind::BlobRef | EntryKind::ExpiringPut
+                )
+            })
+            .map(|(_, key)| key)
+            .filter(|key| !index.live.contains_key(*key))
+            .collect();
+        new_keys.sort_unstable();
+        new_keys.dedup();
+        if new_keys.is_empty() {
+            return Ok(());
+        }
+
+        let entries = index.live.len() + new_keys.len();
+        if let Some(max) = max_entries.filter(|max| entries > *max) {
+            return Err(EngineError::IndexFull(format!(
+                "{} live keys would exceed max_index_entries ({})",
+                entries, max
+            ))
+            .into());
+        }
+        let bytes = index.live_bytes()
+            + new_keys
+                .iter()
+                .map(|key| index.entry_bytes(key.len()))
+                .sum::<u64>();
+        if let Some(max) = max_bytes.filter(|max| bytes > *max) {
+            return Err(EngineError::IndexFull(format!(
+                "{} index bytes would exceed max_index_bytes ({})",
+                bytes, max
+            ))
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Appends already framed records at the end of the log and returns the
+    /// offset they start at. Does not touch the index. The caller must hold
+    /// the file mutex.
+    fn append_raw_locked(&self, file: &mut FileHandle, buf: &[u8]) -> io::Result<u64> {
+        self.check_writable()?;
+        self.check_fence(false)?;
+        if let Some(mirror) = &self.mirror {
+            mirror.check_writable()?;
+        }
+        self.idle.touch();
+        let end = *self.file_size.lock().unwrap();
+        let new_file_size = end + buf.len() as u64;
+        if let Err(e) = self.ensure_allocated(file, new_file_size) {
+            return Err(match is_disk_full(&e) {
+                true => EngineError::DiskFull { rolled_back: true }.into(),
+                false => e,
+            });
+        }
+
+        file.seek(SeekFrom::Start(end))?;
+        if let Err(e) = file.write_all(buf) {
+            // Cut off whatever part of `buf` made it down, so a shorter next
+            // append cannot leave the rest behind it as garbage, and a reload
+            // does not stop at it.
+            let rolled_back = self.truncate_failed_append(file, end);
+            if !rolled_back {
+                self.torn_tail.store(true, Ordering::Release);
+            }
+            return Err(match is_disk_full(&e) {
+                true => EngineError::DiskFull { rolled_back }.into(),
+                false => e,
+            });
+        }
+        if let Some(mirror) = &self.mirror
+            && let Err(e) = mirror.write_at(end, buf)
+        {
+            if !self.truncate_failed_append(file, end) {
+                self.torn_tail.store(true, Ordering::Release);
+            }
+            return Err(e);
+        }
+        self.sync_state.mark_dirty();
+
+        *self.file_size.lock().unwrap() = new_file_size;
+        Metrics::incr(&self.metrics.appends);
+        Ok(end)
+    }
+
+    /// Truncates the log back to `end` after a failed append, retrying
+    /// briefly: truncating needs no free space, so it can succeed where the
+    /// write did not. Returns whether it did.
+    fn truncate_failed_append(&self, file: &mut FileHandle, end: u64) -> bool {
+        let mut backoff = TRUNCATE_BACKOFF;
+        for attempt in 1..=TRUNCATE_ATTEMPTS {
+            if file.set_len(end).is_ok() {
+                self.allocated_size.store(end, Ordering::Release);
+                return true;
+            }
+            if attempt < TRUNCATE_ATTEMPTS {
+                thread::sleep(backoff);
+                backoff *= 2;
+            }
+        }
+        false
+    }
+
+    /// Applies every write in `batch` as one unit: a single append, with the
+    /// index updated under one lock so readers see all or none of it, and
+    /// framed so that replay after a crash also applies all or none of it.
+    /// Of several writes to one key, the last wins, and only it is written
+    /// unless the batch turned `WriteBatch::dedup` off. Needs a KVS2 file.
+    pub fn apply_batch(&self, batch: &WriteBatch) -> io::Result<()> {
+        self.apply_batch_indexed(batch).map(drop)
+    }
+
+    /// `apply_batch`, returning one [`RecordRef`] per operation, in order,
+    /// so a caller that queued writes from several requests can answer each
+    /// of them. A failure fails every operation: nothing was applied. An
+    /// operation superseded by a later one on the same key was not written,
+    /// and gets the reference of the one that was.
+    pub fn apply_batch_indexed(&self, batch: &WriteBatch) -> io::Result<Vec<RecordRef>> {
+        match &self.options.key_transform {
+            Some(transform) => {
+                self.apply_stored_batch(&batch.with_keys(|key| transform.apply(key))?)
+            }
+            None => self.apply_stored_batch(batch),
+        }
+    }
+
+    /// `apply_batch_indexed` of a batch whose keys are as stored.
+    fn apply_stored_batch(&self, batch: &WriteBatch) -> io::Result<Vec<RecordRef>> {
+        if batch.is_empty() {
+            return Ok(Vec::new());
+        }
+        if let Some((collapsed, stands_for)) = batch.collapsed() {
+            let refs = self.apply_stored_batch(&collapsed)?;
+            return Ok(stands_for.into_iter().map(|i| refs[i].clone()).collect());
+        }
+        let keys: Vec<&[u8]> = batch.ops.iter().map(|op| op.key.as_slice()).collect();
+        let _keys = self.key_locks.lock_all(&keys);
+        self.apply_batch_keys_locked(batch)
+    }
+
+    /// `apply_batch_indexed` for a non-empty batch whose keys the caller
+    /// has locked.
+    fn apply_batch_keys_locked(&self, batch: &WriteBatch) -> io::Result<Vec<RecordRef>> {
+        let stamped;
+        let batch = match self.options.writer_id {
+            Some(writer_id) => {
+                if !format_info::by_version(self.format_version.load(Ordering::Acquire)).origins {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Unsupported,
+                        "record origins need a KVS7 file; run compact() to upgrade",
+                    ));
+                }
+                stamped = batch.with_origin(writer_id)?;
+                &stamped
+            }
+            None => batch,
+        };
+        let (jittered, expiries) = self.jitter_expiries(batch, batch.encoded()?)?;
+        let buf: &[u8] = &jittered;
+        for op in &batch.ops {
+            check_raw_key(&op.key)?;
+            if matches!(op.kind, EntryKind::Put | EntryKind::ExpiringPut) {
+                self.check_key_size(&op.key)?;
+            }
+        }
+        if self.options.validate.is_some() || !self.options.bucket_validators.is_empty() {
+            for op in &batch.ops {
+                if matches!(op.kind, EntryKind::Put | EntryKind::ExpiringPut) {
+                    let record = &buf[op.offset as usize..][..op.len as usize];
+                    let entry = Self::decode_entry(FORMAT_VERSION, record)?;
+                    self.validate_value(&op.key, &entry.into_value().unwrap_or_default())?;
+                }
+            }
+        }
+        if !framing::varint_framing(self.format_version.load(Ordering::Acquire)) {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "write batches need a KVS3 file or newer; run compact() to upgrade",
+            ));
+        }
+
+        // Value hashes and origins are taken from the decoded records.
+        let entries = match self.options.value_hashes || self.options.writer_id.is_some() {
+            true => Some(
+                batch
+                    .ops
+                    .iter()
+                    .map(|op| {
+                        Self::decode_entry(
+                            FORMAT_VERSION,
+                            &buf[op.offset as usize..][..op.len as usize],
+                        )
+                    })
+                    .collect::<io::Result<Vec<_>>>()?,
+            ),
+            false => None,
+        };
+
+        let mut file = self.lock_file();
+        self.check_index_room(batch.ops.iter().map(|op| (op.kind, op.key.as_slice())))?;
+        let start = self.append_raw_locked(&mut file, buf)?;
+        let new_file_size = start + buf.len() as u64;
+        #[cfg(feature = "oplog-debug")]
+        if let Some(journal) = self.journal() {
+            let entries = batch
+                .ops
+                .iter()
+                .map(|op| {
+                    Self::decode_entry(
+                        FORMAT_VERSION,
+                        &buf[op.offset as usize..][..op.len as usize],
+                    )
+                })
+                .collect::<io::Result<Vec<_>>>()?;
+            journal.record_group(&entries);
+        }
+        // The file only changes generation under the file mutex.
+        let generation = self.generation.load(Ordering::Acquire);
+        let mut refs = Vec::with_capacity(batch.ops.len());
+
+        {
+            let mut index = self.index_mut();
+            let (header_offset, header_len) = batch.header_span();
+            let header = LogIndex {
+                pos: start + header_offset,
+                len: header_len,
+            };
+            index.apply(EntryKind::BatchBegin, Vec::new(), header, 0);
+            for (i, op) in batch.ops.iter().enumerate() {
+                let log_index = LogIndex {
+                    pos: start + op.offset,
+                    len: op.len,
+                };
+                refs.push(RecordRef {
+                    index: log_index.clone(),
+                    generation,
+                });
+                if let Some(entries) = &entries {
+                    index.apply_entry(&entries[i], log_index);
+                    continue;
+                }
+                match expiries[i] {
+                    Some(at) => index.apply_expiring(op.key.clone(), log_index, op.tstamp, at),
+                    None => index.apply(op.kind, op.key.clone(), log_index, op.tstamp),
+                }
+            }
+        }
+
+        let kind = match batch.ops.iter().any(|op| op.kind != EntryKind::Tombstone) {
+            true => EntryKind::Put,
+            false => EntryKind::Tombstone,
+        };
+        self.finish_write(file, kind, new_file_size)?;
+        Ok(refs)
+    }
+
+    /// `buf`, the records of `batch`, with `Options::ttl_jitter` applied to
+    /// the expiries of its `put_with_ttl`s, and the expiry of every op.
+    /// Absolute expiries, such as those `rename_prefix` carries over, are
+    /// left as they are.
+    fn jitter_expiries<'a>(
+        &self,
+        batch: &WriteBatch,
+        buf: &'a [u8],
+    ) -> io::Result<JitteredBatch<'a>> {
+        let jitter = self.options.ttl_jitter;
+        let mut expiries: Vec<Option<i64>> = batch.ops.iter().map(|op| op.expires_at).collect();
+        if jitter == 0.0 || batch.ops.iter().all(|op| op.ttl.is_none()) {
+            return Ok((Cow::Borrowed(buf), expiries));
+        }
+        // The expiry is the first bytes of the value, so moving it changes
+        // no record's length.
+        let mut jittered = buf.to_vec();
+        for (op, expiry) in batch.ops.iter().zip(&mut expiries) {
+            let (Some(ttl), Some(at)) = (op.ttl, *expiry) else {
+                continue;
+            };
+            let at = at.saturating_add(ttl_jitter_offset(&op.key, ttl, jitter));
+            let (value_offset, _) = Self::value_span(FORMAT_VERSION, op.key.len() as u64, op.len)?;
+            let pos = (op.offset + value_offset) as usize;
+            jittered[pos..pos + EXPIRY_SIZE].copy_from_slice(&at.to_le_bytes());
+            *expiry = Some(at);
+        }
+        Ok((Cow::Owned(jittered), expiries))
+    }
+
+    /// Releases the `resources` held only to speed up later operations:
+    /// pooled read handles and spare buffer capacity. The next read or write
+    /// re-acquires what it needs. Returns how many read handles were closed.
+    /// `Options::idle_after` does the same automatically.
+    pub fn reclaim_idle(&self, resources: ReclaimOptions) -> usize {
+        self.idle_resources().reclaim(resources)
+    }
+
+    fn idle_resources(&self) -> IdleResources {
+        IdleResources {
+            reader_pool: Arc::clone(&self.reader_pool),
+            in_flight_reads: Arc::clone(&self.in_flight_reads),
+            write_queue: Arc::clone(&self.write_queue),
+            idle: Arc::clone(&self.idle),
+        }
+    }
+
+    /// Flushes and fsyncs the data file if anything was written since the
+    /// last sync, whether explicit or from the background thread.
+    pub fn sync(&self) -> io::Result<()> {
+        self.check_open()?;
+        self.sync_state.sync(&self.file)?;
+        self.sync_mirror()
+    }
+
+    /// Fsyncs the `Options::mirror_path` copy, if there is one. The
+    /// background sync thread leaves it to the OS.
+    fn sync_mirror(&self) -> io::Result<()> {
+        match &self.mirror {
+            Some(mirror) => mirror.sync(),
+            None => Ok(()),
+        }
+    }
+
+    /// Shuts the engine down for everyone sharing it: waits for the write
+    /// or compaction in progress, stops the background sync thread (which
+    /// syncs one last time), syncs the data file and any mirror, and only
+    /// then stops the idle thread and closes the pooled read handles. From
+    /// then on every read, write, sync, reload, and compaction fails with
+    /// `EngineError::Closed`, on any thread; calls answered from the
+    /// in-memory index alone, like `keys` and `stats`, keep answering from
+    /// it as it was. Closing again does
+    /// nothing and returns `Ok`. The writer's own handle is released on
+    /// drop.
+    ///
+    /// Background threads get `Options::shutdown_timeout` between them to
+    /// stop. Any still running then are left to finish on their own, the
+    /// rest of the shutdown goes ahead, and `EngineError::ShutdownTimedOut`
+    /// names them.
+    pub fn close(&self) -> io::Result<()> {
+        {
+            let _file = self.lock_file();
+            if self.closed.swap(true, Ordering::SeqCst) {
+                return Ok(());
+            }
+        }
+        // Outside the file mutex: the sync thread's last sync takes it.
+        let deadline = Instant::now() + self.options.shutdown_timeout;
+        let mut running = self.workers.stop(Stage::Flush, Some(deadline));
+        let synced = self
+            .sync_state
+            .sync(&self.file)
+            .and_then(|_| self.sync_mirror());
+        running.extend(self.workers.stop(Stage::Reader, Some(deadline)));
+        self.reader_pool.reclaim();
+        synced?;
+        match running.is_empty() {
+            true => Ok(()),
+            false => Err(EngineError::ShutdownTimedOut(running.join(", ")).into()),
+        }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+
+    fn check_open(&self) -> io::Result<()> {
+        match self.closed.load(Ordering::SeqCst) {
+            true => Err(EngineError::Closed.into()),
+            false => Ok(()),
+        }
+    }
+
+    /// Describes the corruption detected since the last clean
+    /// [`Engine::verify`] or [`Engine::acknowledge_corruption`], if any.
+    pub fn corruption(&self) -> Option<String> {
+        self.corruption.lock().unwrap().clone()
+    }
+
+    /// Clears the corruption flag without checking the file, re-allowing
+    /// writes under `Options::fail_closed`. Returns what had been detected.
+    pub fn acknowledge_corruption(&self) -> Option<String> {
+        let mut corruption = self.corruption.lock().unwrap();
+        self.corrupted.store(false, Ordering::Release);
+        corruption.take()
+    }
+
+    /// Decodes every record in the log. On success the corruption flag is
+    /// cleared and the number of records checked is returned; otherwise the
+    /// flag is set and a `StoreCorrupted` error returned. Writes are blocked
+    /// while it runs.
+    pub fn verify(&self) -> io::Result<u64> {
+        let mut file = self.lock_file();
+        self.check_open()?;
+        let end = *self.file_size.lock().unwrap();
+        let format_version = self.format_version.load(Ordering::Acquire);
+
+        let mut pos = format_info::by_version(format_version).header_size;
+        let mut records = 0;
+        file.seek(SeekFrom::Start(pos))?;
+        let readahead = self
+            .options
+            .readahead_bytes
+            .min(end.saturating_sub(pos) as usize);
+        let mut reader = BufReader::with_capacity(readahead, &mut *file);
+        while pos < end {
+            // A missing or overlong prefix inside the log is reported as a
+            // bad length, like a zero one.
+            let (entry_len, prefix_len) = match framing::read_len(format_version, &mut reader) {
+                Ok(Some(len)) => len,
+                Ok(None) => (0, 0),
+                Err(e) if e.kind() == io::ErrorKind::InvalidData => (0, 0),
+                Err(e) => return Err(e),
+            };
+            let data_pos = pos + prefix_len;
+            if entry_len == 0 || data_pos + entry_len > end {
+                let details = format!("bad record length {} at offset {}", entry_len, pos);
+                self.mark_corrupted(details.clone());
+                return Err(EngineError::StoreCorrupted(details).into());
+            }
+
+            let mut data = vec![0u8; entry_len as usize];
+            reader.read_exact(&mut data)?;
+            if let Err(e) = Self::decode_entry(format_version, &data) {
+                let details = format!("record at offset {}: {}", data_pos, e);
+                self.mark_corrupted(details.clone());
+                return Err(EngineError::StoreCorrupted(details).into());
+            }
+
+            records += 1;
+            pos = data_pos + entry_len;
+        }
+
+        self.acknowledge_corruption();
+        Ok(records)
+    }
+
+    /// Records a detected corruption. The first one is kept until cleared.
+    fn mark_corrupted(&self, details: String) {
+        let mut corruption = self.corruption.lock().unwrap();
+        corruption.get_or_insert(details);
+        self.corrupted.store(true, Ordering::Release);
+    }
+
+    /// Puts the store into (or takes it out of) maintenance mode. While read
+    /// only, every write and compaction fails with
+    /// `EngineError::ReadOnlyMode` and reads keep working. A write that has
+    /// already started appending completes; use [`Engine::drain_writes`] to
+    /// wait for it.
+    pub fn set_read_only(&self, read_only: bool) {
+        self.read_only.store(read_only, Ordering::SeqCst);
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only.load(Ordering::SeqCst)
+    }
+
+    /// Waits up to `timeout` for writes in flight to finish, so that after
+    /// `set_read_only(true)` nothing more reaches the log once this returns
+    /// true. Returns false if a write (or a compaction) still held the file
+    /// at the deadline.
+    pub fn drain_writes(&self, timeout: Duration) -> bool {
+        // Appends check the flag under the file mutex, so once the mutex has
+        // been free for a moment every later append sees the flag.
+        let deadline = Instant::now() + timeout;
+        loop {
+            match self.file.try_lock() {
+                Ok(_) | Err(TryLockError::Poisoned(_)) => return true,
+                Err(TryLockError::WouldBlock) if Instant::now() >= deadline => return false,
+                Err(TryLockError::WouldBlock) => thread::sleep(Duration::from_millis(1)),
+            }
+        }
+    }
+
+    fn check_writable(&self) -> io::Result<()> {
+        self.check_open()?;
+        if self.read_only.load(Ordering::SeqCst) {
+            return Err(EngineError::ReadOnlyMode.into());
+        }
+        if self.torn_tail.load(Ordering::Acquire) {
+            return Err(EngineError::DiskFull { rolled_back: false }.into());
+        }
+        if !self.options.fail_closed || !self.corrupted.load(Ordering::Acquire) {
+            return Ok(());
+        }
+        let details = self.corruption().unwrap_or_default();
+        Err(EngineError::StoreCorrupted(details).into())
+    }
+
+    pub fn get(&self, key: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        let key = &*self.raw_key(key)?;
+        self.get_key(key)
+    }
+
+    /// `get`, also saying where the value came from. Plain `get` classifies
+    /// its reads the same way into `Stats::get_pool_hits`,
+    /// `get_pool_misses`, `coalesced_reads`, and `archive_gets`.
+    pub fn get_traced(&self, key: &[u8]) -> io::Result<Option<(Vec<u8>, ReadSource)>> {
+        let key = &*self.raw_key(key)?;
+        self.get_key_traced(key)
+    }
+
+    /// Whether `key` has a live, unexpired value: what `get(key).is_some()`
+    /// would say, answered from the index without touching the data file.
+    pub fn contains_key(&self, key: &[u8]) -> io::Result<bool> {
+        let key = &*self.raw_key(key)?;
+        self.check_open()?;
+        Ok(self.is_live(key))
+    }
+
+    /// How long until `key` expires; `None` if it is absent, already
+    /// expired, or was written without a TTL. Includes any
+    /// `Options::ttl_jitter` offset.
+    pub fn ttl(&self, key: &[u8]) -> io::Result<Option<Duration>> {
+        let key = &*self.raw_key(key)?;
+        let index = self.index_read();
+        if !index.live.contains_key(key) || index.is_expired(key) {
+            return Ok(None);
+        }
+        Ok(index
+            .expiries
+            .get(key)
+            .map(|&at| Duration::from_millis(at.saturating_sub(now_millis()).max(0) as u64)))
+    }
+
+    /// The value length, write time, expiry, and origin of `key`, and where
+    /// its record is, answered from the index without touching the data
+    /// file; `None` if it is absent or expired. For deciding whether to
+    /// `get` a value at all.
+    pub fn stat(&self, key: &[u8]) -> io::Result<Option<KeyStat>> {
+        let key = &*self.raw_key(key)?;
+        let index = self.index_read();
+        Ok(index
+            .stat(key)
+            .map(|(value_len, tstamp, expires_at, log_index)| KeyStat {
+                value_len,
+                tstamp,
+                expires_at,
+                record: RecordRef {
+                    index: log_index.clone(),
+                    generation: self.generation.load(Ordering::Acquire),
+                },
+                origin: index.origins.get(key).copied(),
+            }))
+    }
+
+    /// A 64-bit hash of `key`'s value, equal to [`Engine::hash_value`] of
+    /// what `get` returns; `None` if the key is absent. Under
+    /// `Options::value_hashes` it comes from the index without touching the
+    /// data file; otherwise the value is read and hashed.
+    pub fn value_hash(&self, key: &[u8]) -> io::Result<Option<u64>> {
+        self.stored_value_hash(&self.raw_key(key)?)
+    }
+
+    /// `value_hash` of a key as stored.
+    fn stored_value_hash(&self, key: &[u8]) -> io::Result<Option<u64>> {
+        {
+            let index = self.index_read();
+            if let Some(hashes) = &index.hashes {
+                if !index.live.contains_key(key) || index.is_expired(key) {
+                    return Ok(None);
+                }
+                if let Some(&hash) = hashes.get(key) {
+                    return Ok(Some(hash));
+                }
+            }
+        }
+        Ok(self.get_key(key)?.map(|value| index::value_hash(&value)))
+    }
+
+    /// Cross-checks every structure derived from the index against it and
+    /// the log, and returns what disagrees; see [`crate::cache_audit`].
+    /// Writes wait until it is done, and under `Options::value_hashes` it
+    /// reads every live value, so it is for tests and debugging.
+    #[cfg(feature = "cache-audit")]
+    pub fn audit_caches(&self) -> io::Result<Vec<CacheInconsistency>> {
+        let _file = self.lock_file();
+        self.check_open()?;
+        let index = self.index_read();
+        let view = self.view.load(ReadView::clone);
+        let mut found = index.audit(&view);
+
+        if let Some(hashes) = &index.hashes {
+            for (key, log_index) in &index.live {
+                // `OpenMode::Fast` loads leave hashes out; those are computed
+                // on demand.
+                let Some(&cached) = hashes.get(key) else {
+                    continue;
+                };
+                let (_, _, location) = index.value_location(key, log_index);
+                let value = self.read_entry(location)?.into_value().unwrap_or_default();
+                let on_disk = index::value_hash(&value);
+                if cached != on_disk {
+                    found.push(CacheInconsistency::ValueHash {
+                        key: key.clone(),
+                        cached,
+                        on_disk,
+                    });
+                }
+            }
+        }
+
+        let key_prefix = bucket::namespace_key(b"");
+        let mut on_disk = HashMap::new();
+        for (key, log_index) in &index.live {
+            if key.starts_with(&key_prefix) {
+                let namespace = self.read_entry(log_index)?.into_value().unwrap_or_default();
+                on_disk.insert(key[key_prefix.len()..].to_vec(), namespace);
+            }
+        }
+        let cached = self.namespaces.read().unwrap();
+        let buckets: HashSet<&Vec<u8>> = cached.keys().chain(on_disk.keys()).collect();
+        for bucket in buckets {
+            if cached.get(bucket) != on_disk.get(bucket) {
+                found.push(CacheInconsistency::Namespace {
+                    bucket: bucket.clone(),
+                    cached: cached.get(bucket).cloned(),
+                    on_disk: on_disk.get(bucket).cloned(),
+                });
+            }
+        }
+        Ok(found)
+    }
+
+    /// The hash [`Engine::value_hash`] reports for `value`: the first 8
+    /// bytes of its SHA-1, big-endian. Stable across versions and restarts,
+    /// so usable as an ETag.
+    pub fn hash_value(value: &[u8]) -> u64 {
+        index::value_hash(value)
+    }
+
+    pub(crate) fn get_key(&self, key: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        Ok(self.get_key_traced(key)?.map(|(value, _)| value))
+    }
+
+    fn get_key_traced(&self, key: &[u8]) -> io::Result<Option<(Vec<u8>, ReadSource)>> {
+        self.check_open()?;
+        Metrics::incr(&self.metrics.gets);
+        if let Some(access) = &self.access {
+            access.record(key);
+        }
+        #[cfg(feature = "oplog-debug")]
+        if let Some(journal) = self.journal() {
+            journal.record(JournalOp::Get, key, None);
+        }
+        self.timed(
+            SlowOpKind::Get,
+            key,
+            Some(&self.metrics.get_latency),
+            || self.get_inner(key),
+        )
+    }
+
+    /// Runs `f`, recording its latency into `histogram` when latency
+    /// histograms are on and reporting it to the slow-op hook when it takes
+    /// longer than the threshold. The measured time includes any wait on the
+    /// file or index lock. Neither costs a clock read when disabled.
+    fn timed<T>(
+        &self,
+        op: SlowOpKind,
+        key: &[u8],
+        histogram: Option<&LatencyHistogram>,
+        f: impl FnOnce() -> io::Result<T>,
+    ) -> io::Result<T> {
+        let histogram = histogram.filter(|_| self.options.latency_histograms);
+        if histogram.is_none() && self.slow_ops.is_none() {
+            return f();
+        }
+
+        if self.slow_ops.is_some() {
+            slow_op::begin();
+        }
+        let start = Instant::now();
+        let result = f();
+        let duration = start.elapsed();
+
+        if let Some(histogram) = histogram {
+            histogram.record(duration);
+        }
+        if let Some((threshold, hook)) = &self.slow_ops {
+            let detail = slow_op::end();
+            if duration >= *threshold {
+                hook.call(&SlowOp {
+                    op,
+                    key_len: key.len(),
+                    duration,
+                    detail,
+                    context: context::current(),
+                });
+            }
+        }
+        result
+    }
+
+    #[cfg(feature = "oplog-debug")]
+    fn journal(&self) -> Option<&OpJournal> {
+        self.options.op_journal.as_deref()
+    }
+
+    /// Write-locks the index for a change to the live keys; the change is
+    /// published to `get` when the guard drops.
+    fn index_mut(&self) -> IndexWriteGuard<'_> {
+        IndexWriteGuard {
+            index: self.index_lock.write(&self.index),
+            view: &self.view,
+            dead_bytes: &self.dead_bytes,
+            limits: self.limits.as_ref(),
+        }
+    }
+
+    /// Passes the soft-limit warnings noted so far to
+    /// `Options::on_limit_warning`. Call with no lock held.
+    fn deliver_limit_warnings(&self) {
+        if let Some(limits) = &self.limits {
+            limits.deliver();
+        }
+    }
+
+    /// Locks the file mutex, noting the wait for the slow-op hook when it
+    /// was contended.
+    fn lock_file(&self) -> MutexGuard<'_, FileHandle> {
+        if let Ok(file) = self.file.try_lock() {
+            self.file_lock.uncontended();
+            return file;
+        }
+        if self.slow_ops.is_none() && !LockCounters::TIMED {
+            return self.file.lock().unwrap();
+        }
+        let start = Instant::now();
+        let file = self.file.lock().unwrap();
+        let wait = start.elapsed();
+        self.file_lock.contended(wait);
+        if self.slow_ops.is_some() {
+            slow_op::note(|d| d.file_lock_wait += wait);
+        }
+        file
+    }
+
+    fn index_read(&self) -> RwLockReadGuard<'_, Index> {
+        self.index_lock.read(&self.index)
+    }
+
+    /// Looks `key` up in the published view and reads its record without
+    /// taking the index lock. The read is only trusted if the generation is
+    /// even and unchanged across it, which proves no file swap overlapped
+    /// it; otherwise it is retried against the next view.
+    fn get_inner(&self, key: &[u8]) -> io::Result<Option<(Vec<u8>, ReadSource)>> {
+        let mut swap_wait = None;
+        loop {
+            let generation = self.generation.load(Ordering::Acquire);
+            if generation % 2 == 1 {
+                swap_wait.get_or_insert_with(Instant::now);
+                thread::yield_now();
+                continue;
+            }
+            let Some((format_version, log_index)) = self
+                .view
+                .load(|view| Some((view.format_version(), view.get(key)?)))
+            else {
+                return Ok(None);
+            };
+
+            // Concurrent gets of the same record in the same file share one
+            // physical read.
+            let mut pool_hit = false;
+            let (result, coalesced) = self.in_flight_reads.run((generation, log_index.pos), || {
+                self.read_at_pooled(log_index.pos, log_index.len)
+                    .map(|(data, hit)| {
+                        pool_hit = hit;
+                        data
+                    })
+                    .map_err(|e| (e.kind(), e.to_string()))
+            });
+            if self.generation.load(Ordering::Acquire) != generation {
+                swap_wait.get_or_insert_with(Instant::now);
+                continue;
+            }
+
+            if let Some(start) = swap_wait {
+                slow_op::note(|d| d.index_lock_wait += start.elapsed());
+            }
+            if self.slow_ops.is_some() {
+                slow_op::note(|d| d.offset = Some(log_index.pos));
+            }
+            if coalesced {
+                slow_op::note(|d| d.coalesced = true);
+            }
+            let data = result.map_err(|(kind, msg)| io::Error::new(kind, msg))?;
+            let entry = Self::decode_entry(format_version, &data).inspect_err(|e| {
+                self.mark_corrupted(format!("record at offset {}: {}", log_index.pos, e))
+            })?;
+            let (source, counter) = match (entry.archived_at().is_some(), coalesced) {
+                (true, _) => (ReadSource::Archive, &self.metrics.archive_gets),
+                (false, true) => (ReadSource::Coalesced, &self.metrics.coalesced_reads),
+                (false, false) if pool_hit => {
+                    (ReadSource::Disk { pool_hit }, &self.metrics.get_pool_hits)
+                }
+                (false, false) => (ReadSource::Disk { pool_hit }, &self.metrics.get_pool_misses),
+            };
+            let value = self.follow_stub(entry)?.into_value();
+            Metrics::incr(counter);
+            return Ok(value.map(|value| (value, source)));
+        }
+    }
+
+    /// Reads only bytes `[offset, offset + len)` of the value stored under
+    /// `key`. The range is clamped to the end of the value, so it may come back
+    /// short (or empty); `None` means the key is absent.
+    pub fn get_range(&self, key: &[u8], offset: u64, len: u64) -> io::Result<Option<Vec<u8>>> {
+        let key = &*self.raw_key(key)?;
+        let index = self.index_read();
+
+        if index.archived.contains_key(key) {
+            drop(index);
+            // The stub holds no value to slice; the archived record does.
+            return Ok(self.get_key(key)?.map(|value| {
+                let start = offset.min(value.len() as u64) as usize;
+                let end = offset.saturating_add(len).min(value.len() as u64) as usize;
+                value[start..end].to_vec()
+            }));
+        }
+        let (key_len, log_index) = match index.live.get(key) {
+            Some(idx) if !index.is_expired(key) => {
+                let (_, key_len, log_index) = index.value_location(key, idx);
+                (key_len, log_index.clone())
+            }
+            _ => return Ok(None),
+        };
+
+        // Values are stored untransformed, so a slice of the record is a slice
+        // of the value, past the expiry prefix of a TTL'd record and short of
+        // any origin.
+        let format_version = self.format_version.load(Ordering::Acquire);
+        let (mut value_offset, mut value_len) =
+            Self::value_span(format_version, key_len, log_index.len)?;
+        value_len = value_len.saturating_sub(index.origin_len(key));
+        if index.expiries.contains_key(key) {
+            value_offset += EXPIRY_SIZE as u64;
+            value_len = value_len.saturating_sub(EXPIRY_SIZE as u64);
+        }
+
+        let start = offset.min(value_len);
+        let end = offset.saturating_add(len).min(value_len);
+        if start == end {
+            return Ok(Some(Vec::new()));
+        }
+
+        let data = self.read_at(log_index.pos + value_offset + start, end - start)?;
+        drop(index);
+
+        Ok(Some(data))
+    }
+
+    /// Returns up to `n` values `key` held before its current one, newest
+    /// first. Only as many as `Options::history_depth` are retained, and the
+    /// history is dropped once the key is deleted.
+    pub fn previous_versions(&self, key: &[u8], n: usize) -> io::Result<Vec<Vec<u8>>> {
+        let key = &*self.raw_key(key)?;
+        let index = self.index_read();
+
+        let ring = match index.history.get(key) {
+            Some(ring) => ring,
+            None => return Ok(Vec::new()),
+        };
+
+        let mut versions = Vec::new();
+        for log_index in ring.iter().rev().take(n) {
+            if let Some(value) = self.read_entry(log_index)?.into_value() {
+                versions.push(value);
+            }
+        }
+
+        Ok(versions)
+    }
+
+    /// Live keys in the index, as `stats().live_keys` counts them, without
+    /// gathering the rest of the stats. Keys past their expiry are left out,
+    /// as [`Engine::contains_key`] leaves them out, though `stats` counts
+    /// them until compaction drops them. Keys in buckets count.
+    pub fn len(&self) -> usize {
+        let index = self.index_read();
+        let now = now_millis();
+        let expired = index.expiries.values().filter(|&&at| at <= now).count();
+        self.live_key_count(&index).saturating_sub(expired)
+    }
+
+    /// Whether [`Engine::len`] is 0.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The index's live keys, less the engine's own unless
+    /// `Options::include_internal_keys` is set.
+    fn live_key_count(&self, index: &Index) -> usize {
+        if self.options.include_internal_keys {
+            index.live.len()
+        } else {
+            index.live.len() - index.internal_keys
+        }
+    }
+
+    pub fn stats(&self) -> Stats {
+        let index = self.index_read();
+        let file_size = *self.file_size.lock().unwrap();
+        Stats {
+            live_keys: self.live_key_count(&index),
+            internal_keys: index.internal_keys,
+            soft_deleted_keys: index.soft_deleted.len(),
+            file_size,
+            compact_threshold: self.compact_threshold.load(Ordering::Acquire),
+            index_bytes: index.live_bytes(),
+            largest_key_len: index.largest_key_len(),
+            access_tracker_bytes: self.access.as_ref().map_or(0, AccessTracker::memory_bytes),
+            max_index_entries: self.options.max_index_entries,
+            max_index_bytes: self.options.max_index_bytes,
+            history_entries: index.history_entries(),
+            history_bytes: index.history_bytes(),
+            blobs: index.blobs.len(),
+            blob_refs: index.refs.len(),
+            archived_keys: index.archived.len(),
+            archive_file_size: self.archive.as_ref().map_or(0, Archive::size),
+            dead_bytes: index.dead_bytes,
+            tombstones: index.tombstones,
+            tombstone_bytes: index.tombstone_bytes,
+            compactions: Metrics::get(&self.metrics.compactions),
+            compactions_aborted: Metrics::get(&self.metrics.compactions_aborted),
+            compactions_deferred: Metrics::get(&self.metrics.compactions_deferred),
+            allocated_size: self.allocated_size.load(Ordering::Acquire).max(file_size),
+            disk_reads: Metrics::get(&self.metrics.disk_reads),
+            coalesced_reads: Metrics::get(&self.metrics.coalesced_reads),
+            get_pool_hits: Metrics::get(&self.metrics.get_pool_hits),
+            get_pool_misses: Metrics::get(&self.metrics.get_pool_misses),
+            archive_gets: Metrics::get(&self.metrics.archive_gets),
+            stale_readers: Metrics::get(&self.metrics.stale_readers),
+            pooled_readers: self.reader_pool.len(),
+            generation: self.generation.load(Ordering::Acquire),
+            reader_fds: self.reader_pool.open_readers(),
+            idle_reclaims: Metrics::get(&self.idle.reclaims),
+            idle_reacquires: Metrics::get(&self.idle.reacquires),
+            read_waits: Metrics::get(&self.metrics.read_waits),
+            read_wait_micros: Metrics::get(&self.metrics.read_wait_micros),
+            syncs: Metrics::get(&self.sync_state.syncs),
+            sync_errors: Metrics::get(&self.sync_state.errors),
+            io_retries: Metrics::get(&self.retry_counters.retries),
+            io_retry_give_ups: Metrics::get(&self.retry_counters.give_ups),
+            gets: Metrics::get(&self.metrics.gets),
+            sets: Metrics::get(&self.metrics.sets),
+            appends: Metrics::get(&self.metrics.appends),
+            mirror_failures: self.mirror.as_ref().map_or(0, Mirror::failures),
+            mirror_stopped: self.mirror.as_ref().is_some_and(Mirror::is_stopped),
+            limit_warnings: self
+                .limits
+                .as_ref()
+                .map(LimitWatch::active)
+                .unwrap_or_default(),
+            #[cfg(feature = "lock-metrics")]
+            file_lock: self.file_lock.snapshot(),
+            #[cfg(feature = "lock-metrics")]
+            index_lock: self.index_lock.snapshot(),
+            #[cfg(feature = "lock-metrics")]
+            reader_pool_lock: self.reader_pool.lock_stats(),
+            get_latency: self.metrics.get_latency.snapshot(),
+            set_latency: self.metrics.set_latency.snapshot(),
+            last_sync_millis: match self.sync_state.last_sync_millis.load(Ordering::Relaxed) {
+                0 => None,
+                millis => Some(millis),
+            },
+        }
+    }
+
+    /// Where the `Options::mirror_path` copy stands: whether it is still
+    /// kept level with the data file, and if not why not. `None` without a
+    /// mirror.
+    pub fn mirror_status(&self) -> Option<MirrorStatus> {
+        self.mirror.as_ref().map(Mirror::status)
+    }
+
+    /// Clears the `get` and `set` latency histograms; the op counters keep
+    /// running.
+    pub fn reset_latency_stats(&self) {
+        self.metrics.get_latency.reset();
+        self.metrics.set_latency.reset();
+    }
+
+    /// Starts maintaining live key and value byte counters for `prefix`, as
+    /// `Options::key_transform` rewrites it. On a populated store this scans
+    /// the index once to initialise them.
+    pub fn track_prefix(&self, prefix: &[u8]) {
+        // Holding the file mutex keeps a concurrent compaction from building
+        // its replacement index without the new prefix.
+        let prefix = self.transform_key(prefix);
+        let _file = self.lock_file();
+        self.index_lock.write(&self.index).track_prefix(&prefix);
+    }
+
+    /// Current counters for every tracked prefix, in registration order.
+    pub fn tracked_prefix_stats(&self) -> Vec<PrefixStats> {
+        self.index_read().tracked.clone()
+    }
+
+    /// Predicts the outcome of [`Engine::compact`] without touching the data
+    /// file.
+    pub fn compaction_estimate(&self) -> CompactionEstimate {
+        let index = self.index_read();
+        let file_size = *self.file_size.lock().unwrap();
+
+        let copied = index
+            .live
+            .values()
+            .chain(index.history.values().flatten())
+            .chain(
+                index
+                    .soft_deleted
+                    .values()
+                    .filter(|sd| !self.soft_delete_expired(sd))
+                    .map(|sd| &sd.index),
+            )
+            .chain(
+                index
+                    .blobs
+                    .values()
+                    .filter(|blob| blob.refs > 0)
+                    .map(|blob| &blob.index),
+            );
+
+        // Records from a v1 file gain the one-byte entry kind when compaction
+        // rewrites them in the current format.
+        let upgrade_bytes = u64::from(self.format_version.load(Ordering::Acquire) == 1);
+
+        let mut live_entries = 0;
+        let mut expected_size = FILE_HEADER_SIZE;
+        for log_index in copied {
+            live_entries += 1;
+            let len = log_index.len + upgrade_bytes;
+            expected_size += framing::prefix_len(FORMAT_VERSION, len) + len;
+        }
+
+        CompactionEstimate {
+            expected_size,
+            reclaimable_bytes: file_size.saturating_sub(expected_size),
+            live_entries,
+        }
+    }
+
+    /// Key and byte counts for the bucket whose keys start with `prefix`,
+    /// for [`Bucket::stats`]. One pass over the index.
+    pub(crate) fn bucket_stats(&self, prefix: &[u8], name: &[u8]) -> BucketStats {
+        let index = self.index_read();
+        let format_version = self.format_version.load(Ordering::Acquire);
+        let size = |log_index: &LogIndex| index::record_size(format_version, log_index);
+
+        let mut stats = BucketStats {
+            dead_bytes: index.bucket_dead_bytes.get(name).copied().unwrap_or(0),
+            ..BucketStats::default()
+        };
+        for (key, log_index) in index.live.iter().filter(|(k, _)| k.starts_with(prefix)) {
+            let bytes = size(log_index)
+                + index
+                    .history
+                    .get(key)
+                    .map_or(0, |ring| ring.iter().map(size).sum());
+            // Compaction drops keys whose TTL has passed.
+            if index.is_expired(key) {
+                stats.dead_bytes += bytes;
+            } else {
+                stats.keys += 1;
+                stats.live_bytes += bytes;
+            }
+        }
+        for (key, sd) in &index.soft_deleted {
+            if key.starts_with(prefix) {
+                match self.soft_delete_expired(sd) {
+                    true => stats.dead_bytes += size(&sd.index),
+                    false => stats.live_bytes += size(&sd.index),
+                }
+            }
+        }
+        stats
+    }
+
+    /// Writes bucket `name`'s quota to its internal key, or deletes it for
+    /// `None`, and starts tracking the bucket's usage.
+    pub(crate) fn set_bucket_quota(&self, name: &[u8], quota: Option<Quota>) -> io::Result<()> {
+        let key = bucket::quota_key(name);
+        let entry = match quota {
+            Some(quota) => DataFileEntry::put(now_millis(), key, quota.encode()),
+            None => DataFileEntry::tombstone(now_millis(), key),
+        };
+        let mut file = self.lock_file();
+        // Looked up under the file mutex, so no swap moves the bucket
+        // before the quota is in place.
+        let prefix = self.bucket_prefix(name);
+        self.index_lock.write(&self.index).track_prefix(&prefix);
+        let new_file_size = self.append_locked(&mut file, &entry)?;
+        {
+            let mut quotas = self.quotas.write().unwrap();
+            match quota {
+                Some(quota) => quotas.insert(prefix, quota),
+                None => quotas.remove(&prefix),
+            };
+        }
+        self.finish_write(file, entry.kind, new_file_size)
+    }
+
+    pub(crate) fn bucket_quota(&self, prefix: &[u8]) -> Option<Quota> {
+        self.quotas.read().unwrap().get(prefix).copied()
+    }
+
+    /// The tracked counters for `prefix`, tracking it first if need be.
+    pub(crate) fn prefix_usage(&self, prefix: &[u8]) -> PrefixStats {
+        let tracked = |index: &Index| index.tracked.iter().find(|t| t.prefix == prefix).cloned();
+        if let Some(stats) = tracked(&self.index_read()) {
+            return stats;
+        }
+        self.track_prefix(prefix);
+        tracked(&self.index_read()).unwrap_or_default()
+    }
+
+    /// Re-reads every bucket's namespace mapping from the freshly rebuilt
+    /// index. The caller must hold the file mutex.
+    fn load_namespaces(&self) -> io::Result<()> {
+        let key_prefix = bucket::namespace_key(b"");
+        let mut namespaces = HashMap::new();
+        {
+            let index = self.index_read();
+            for (key, log_index) in &index.live {
+                if key.starts_with(&key_prefix) {
+                    let namespace = self.read_entry(log_index)?.into_value().unwrap_or_default();
+                    namespaces.insert(key[key_prefix.len()..].to_vec(), namespace);
+                }
+            }
+        }
+        *self.namespaces.write().unwrap() = namespaces;
+        Ok(())
+    }
+
+    /// The encoded prefix of the namespace bucket `name` is mapped to.
+    pub(crate) fn bucket_prefix(&self, name: &[u8]) -> Vec<u8> {
+        match self.namespaces.read().unwrap().get(name) {
+            Some(namespace) => bucket::prefix(namespace),
+            None => bucket::prefix(name),
+        }
+    }
+
+    /// The bucket whose keys are stored under `namespace`.
+    fn bucket_of_namespace(&self, namespace: &[u8]) -> Vec<u8> {
+        self.namespaces
+            .read()
+            .unwrap()
+            .iter()
+            .find(|(_, mapped)| mapped.as_slice() == namespace)
+            .map_or_else(|| namespace.to_vec(), |(name, _)| name.clone())
+    }
+
+    /// Re-reads every bucket quota from the freshly rebuilt index and tracks
+    /// the buckets they cover. The caller must hold the file mutex.
+    fn load_quotas(&self) -> io::Result<()> {
+        let key_prefix = bucket::quota_key(b"");
+        let mut quotas = HashMap::new();
+        {
+            let index = self.index_read();
+            for (key, log_index) in &index.live {
+                if !key.starts_with(&key_prefix) {
+                    continue;
+                }
+                let value = self.read_entry(log_index)?.into_value().unwrap_or_default();
+                if let Some(quota) = Quota::decode(&value) {
+                    quotas.insert(self.bucket_prefix(&key[key_prefix.len()..]), quota);
+                }
+            }
+        }
+        if !quotas.is_empty() {
+            let mut index = self.index_mut();
+            for prefix in quotas.keys() {
+                index.track_prefix(prefix);
+            }
+        }
+        *self.quotas.write().unwrap() = quotas;
+        Ok(())
+    }
+
+    /// Fails with `QuotaExceeded` if setting these `(key, value length)`
+    /// pairs would take a bucket past its quota. Only growth is refused, so
+    /// a bucket already over a lowered quota can still shrink. Call with the
+    /// file mutex held, so usage cannot change between the check and the
+    /// append.
+    fn check_quotas<'a>(&self, puts: impl Iterator<Item = (&'a [u8], u64)>) -> io::Result<()> {
+        let quotas = self.quotas.read().unwrap();
+        if quotas.is_empty() {
+            return Ok(());
+        }
+        // The last value each key gets, by bucket prefix.
+        let mut puts_by_bucket: HashMap<&[u8], HashMap<&[u8], u64>> = HashMap::new();
+        for (key, len) in puts {
+            if let Some((name, _)) = bucket::split_key(key) {
+                let prefix = &key[..2 + name.len()];
+                if quotas.contains_key(prefix) {
+                    puts_by_bucket.entry(prefix).or_default().insert(key, len);
+                }
+            }
+        }
+        if puts_by_bucket.is_empty() {
+            return Ok(());
+        }
+
+        let index = self.index_read();
+        for (prefix, puts) in puts_by_bucket {
+            let quota = quotas[prefix];
+            let usage = index
+                .tracked
+                .iter()
+                .find(|t| t.prefix == prefix)
+                .cloned()
+                .unwrap_or_default();
+            let (mut keys, mut bytes) = (usage.keys, usage.value_bytes);
+            for (key, len) in puts {
+                match index.live.get(key) {
+                    Some(log_index) => {
+                        bytes = bytes.saturating_sub(index.value_len(key, log_index))
+                    }
+                    None => keys += 1,
+                }
+                bytes += len;
+            }
+
+            let name = self.bucket_of_namespace(&prefix[2..]);
+            let name = String::from_utf8_lossy(&name);
+            if let Some(max) = quota
+                .max_keys
+                .filter(|&max| keys > max && keys > usage.keys)
+            {
+                return Err(EngineError::QuotaExceeded(format!(
+                    "bucket {:?} would hold {} keys, over its quota of {}",
+                    name, keys, max
+                ))
+                .into());
+            }
+            if let Some(max) = quota
+                .max_bytes
+                .filter(|&max| bytes > max && bytes > usage.value_bytes)
+            {
+                return Err(EngineError::QuotaExceeded(format!(
+                    "bucket {:?} would hold {} value bytes, over its quota of {}",
+                    name, bytes, max
+                ))
+                .into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads and decodes the entry at `log_index` through the reader pool.
+    ///
+    /// The caller must hold the index read lock so compaction cannot swap the
+    /// file underneath the read.
+    /// How much disk the store needs now and while compacting, for capacity
+    /// alerts. Built from `stats()` and `compaction_estimate()`, so it costs
+    /// one pass over the index.
+    pub fn peak_disk_forecast(&self) -> io::Result<DiskForecast> {
+        let stats = self.stats();
+        let current = stats.allocated_size.max(stats.file_size);
+        let expected = self.compaction_estimate().expected_size;
+
+        let scratch_on_other_filesystem = match &self.options.compaction_dir {
+            Some(dir) => {
+                let data_dir = match self.path.parent() {
+                    Some(parent) if !parent.as_os_str().is_empty() => parent,
+                    _ => Path::new("."),
+                };
+                self.storage
+                    .same_filesystem(data_dir, dir)?
+                    .map(|same| !same)
+            }
+            None => None,
+        };
+        // Output on another filesystem is copied next to the data file
+        // before the swap, so for a moment it exists twice.
+        let copies = match (&self.options.compaction_dir, scratch_on_other_filesystem) {
+            (None, _) | (Some(_), Some(false)) => 1,
+            _ => 2,
+        };
+
+        Ok(DiskForecast {
+            current,
+            post_compaction_estimate: expected,
+            peak_during_compaction: current + copies * expected,
+            scratch_on_other_filesystem,
+        })
+    }
+
+    pub(crate) fn read_entry(&self, log_index: &LogIndex) -> io::Result<DataFileEntry> {
+        let data = self.read_at(log_index.pos, log_index.len)?;
+        let entry = Self::decode_entry(self.format_version.load(Ordering::Acquire), &data)
+            .inspect_err(|e| {
+                self.mark_corrupted(format!("record at offset {}: {}", log_index.pos, e))
+            })?;
+        self.follow_stub(entry)
+    }
+
+    /// The record an `Archived` stub forwards to, read from the archive
+    /// file; any other record as it is.
+    fn follow_stub(&self, entry: DataFileEntry) -> io::Result<DataFileEntry> {
+        let Some(archived_at) = entry.archived_at() else {
+            return Ok(entry);
+        };
+        let Some(archive) = &self.archive else {
+            return Err(EngineError::ArchiveMissing(
+                "Options::archive_path is not set".to_string(),
+            )
+            .into());
+        };
+        let record = archive.read(&archived_at)?;
+        if record.key != entry.key {
+            let details = format!(
+                "{}: archived record at offset {} belongs to another key",
+                archive.path().display(),
+                archived_at.pos
+            );
+            self.mark_corrupted(details.clone());
+            return Err(io::Error::new(io::ErrorKind::InvalidData, details));
+        }
+        Ok(record)
+    }
+
+    /// A [`Readahead`] over the data file on a handle of its own, for a
+    /// scan that holds the index read lock until it is done.
+    fn readahead(&self) -> io::Result<Readahead<FileHandle>> {
+        self.check_open()?;
+        let file = self.storage.open(&self.path, OpenMode::Read)?;
+        let end = *self.file_size.lock().unwrap();
+        Ok(Readahead::new(file, self.options.readahead_bytes, end))
+    }
+
+    /// [`Engine::read_entry`] through `readahead`.
+    fn read_ahead(
+        &self,
+        readahead: &mut Readahead<FileHandle>,
+        log_index: &LogIndex,
+    ) -> io::Result<DataFileEntry> {
+        let data = readahead.read(log_index.pos, log_index.len)?;
+        let entry = Self::decode_entry(self.format_version.load(Ordering::Acquire), data)
+            .inspect_err(|e| {
+                self.mark_corrupted(format!("record at offset {}: {}", log_index.pos, e))
+            })?;
+        self.follow_stub(entry)
+    }
+
+    /// Reads `len` raw bytes at `pos` through the reader pool. Callers hold
+    /// the index lock, or check the generation afterwards as `get` does, so
+    /// `pos` is known to be in the file that was read.
+    fn read_at(&self, pos: u64, len: u64) -> io::Result<Vec<u8>> {
+        self.read_at_pooled(pos, len).map(|(data, _)| data)
+    }
+
+    /// `read_at`, also returning whether the read used a pooled handle.
+    fn read_at_pooled(&self, pos: u64, len: u64) -> io::Result<(Vec<u8>, bool)> {
+        self.check_open()?;
+        let _permit = self.read_limiter.as_ref().map(|limiter| {
+            let (permit, waited) = limiter.acquire();
+            if !waited.is_zero() {
+                Metrics::incr(&self.metrics.read_waits);
+                Metrics::add(&self.metrics.read_wait_micros, waited.as_micros() as u64);
+                slow_op::note(|d| d.read_permit_wait += waited);
+            }
+            permit
+        });
+
+        self.idle.touch();
+        let generation = self.generation.load(Ordering::Acquire);
+        let (mut reader, pool_hit) = match self.reader_pool.take(generation) {
+            Some((r, idle_since)) => self.revalidate_reader(r, idle_since, pos + len)?,
+            None => {
+                slow_op::note(|d| d.opened_reader = true);
+                let reader =
+                    self.reader_pool
+                        .open(self.storage.as_ref(), &self.path, generation)?;
+                (reader, false)
+            }
+        };
+
+        Metrics::incr(&self.metrics.disk_reads);
+        reader.seek(SeekFrom::Start(pos))?;
+
+        let mut data = vec![0u8; len as usize];
+        reader.read_exact(&mut data)?;
+
+        self.reader_pool.put(reader);
+
+        Ok((data, pool_hit))
+    }
+
+    /// Under `Options::paranoid_reads`, checks that a pooled handle idle
+    /// since `idle_since` for too long still sees this store's header magic
+    /// and a file reaching `end`, and swaps it for a fresh handle if not.
+    /// Returns the handle to read with and whether it is the pooled one.
+    fn revalidate_reader(
+        &self,
+        mut reader: Reader,
+        idle_since: Instant,
+        end: u64,
+    ) -> io::Result<(Reader, bool)> {
+        let Some(max_idle) = self.options.paranoid_reads else {
+            return Ok((reader, true));
+        };
+        if idle_since.elapsed() <= max_idle {
+            return Ok((reader, true));
+        }
+        let magic = format_info::by_version(self.format_version.load(Ordering::Acquire)).magic;
+        let mut head = [0u8; 4];
+        let valid = reader.len().is_ok_and(|len| len >= end)
+            && reader.seek(SeekFrom::Start(0)).is_ok()
+            && reader.read_exact(&mut head).is_ok()
+            && head == magic;
+        if valid {
+            return Ok((reader, true));
+        }
+        Metrics::incr(&self.metrics.stale_readers);
+        slow_op::note(|d| d.opened_reader = true);
+        let reader =
+            self.reader_pool
+                .open(self.storage.as_ref(), &self.path, reader.generation())?;
+        Ok((reader, false))
+    }
+
+    /// Opens the namespace `name`; see [`crate::bucket`]. Fails with
+    /// `EngineError::InvalidBucketName` for an empty name, one longer than
+    /// 255 bytes, or one containing `BUCKET_KEY_PREFIX`.
+    pub fn bucket(&self, name: &[u8]) -> io::Result<Bucket<'_>> {
+        Bucket::new(self, name)
+    }
+
+    /// Exchanges the contents of buckets `a` and `b`, quotas aside: every
+    /// key of one is from now on read, written, and scanned through the
+    /// other, while each quota stays with its bucket's name. No key is
+    /// copied; the two buckets' namespace mappings are rewritten in one
+    /// write batch, so a crash leaves both swapped or neither, and every
+    /// bucket operation sees one side of the swap in full. A write racing
+    /// the swap lands in whichever namespace its bucket had when the write
+    /// began. Needs a KVS3 file.
+    pub fn swap_buckets(&self, a: &[u8], b: &[u8]) -> io::Result<()> {
+        bucket::validate_name(a)?;
+        bucket::validate_name(b)?;
+        if a == b {
+            return Ok(());
+        }
+        if !framing::varint_framing(self.format_version.load(Ordering::Acquire)) {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "swapping buckets needs a KVS3 file or newer; run compact() to upgrade",
+            ));
+        }
+
+        let mut file = self.lock_file();
+        let namespace = |name: &[u8]| {
+            let namespaces = self.namespaces.read().unwrap();
+            namespaces
+                .get(name)
+                .cloned()
+                .unwrap_or_else(|| name.to_vec())
+        };
+        let (namespace_a, namespace_b) = (namespace(a), namespace(b));
+        let tstamp = now_millis();
+        // A bucket mapped back to its own name needs no mapping.
+        let mapping = |name: &[u8], namespace: &[u8]| match name == namespace {
+            true => DataFileEntry::tombstone(tstamp, bucket::namespace_key(name)),
+            false => DataFileEntry::put(tstamp, bucket::namespace_key(name), namespace.to_vec()),
+        };
+        let entries = [
+            batch::batch_begin(2),
+            mapping(a, &namespace_b),
+            mapping(b, &namespace_a),
+        ];
+        self.append_entries_locked(&mut file, &entries)?;
+
+        {
+            let mut namespaces = self.namespaces.write().unwrap();
+            for (name, namespace) in [(a, &namespace_b), (b, &namespace_a)] {
+                match name == namespace.as_slice() {
+                    true => namespaces.remove(name),
+                    false => namespaces.insert(name.to_vec(), namespace.clone()),
+                };
+            }
+        }
+        {
+            let (prefix_a, prefix_b) = (bucket::prefix(&namespace_a), bucket::prefix(&namespace_b));
+            let mut quotas = self.quotas.write().unwrap();
+            let (quota_a, quota_b) = (quotas.remove(&prefix_a), quotas.remove(&prefix_b));
+            let moved = [(quota_a, prefix_b), (quota_b, prefix_a)];
+            for (quota, prefix) in moved {
+                if let Some(quota) = quota {
+                    self.index_lock.write(&self.index).track_prefix(&prefix);
+                    quotas.insert(prefix, quota);
+                }
+            }
+        }
+        let new_file_size = *self.file_size.lock().unwrap();
+        self.finish_write(file, EntryKind::Put, new_file_size)
+    }
+
+    /// Every live key outside the bucket namespace, in ascending byte
+    /// order, as of one instant. Taken from the index without reading any
+    /// value.
+    pub fn keys(&self) -> KeysSnapshot {
+        let index = self.index_read();
+        let keep = |key: &&Vec<u8>| !bucket::is_bucket_key(key) && !index.is_expired(key);
+        let keys = match &index.ordered {
+            Some(ordered) => ordered.iter().filter(keep).cloned().collect(),
+            None => {
+                let mut keys: Vec<Vec<u8>> = index.live.keys().filter(keep).cloned().collect();
+                keys.sort_unstable();
+                keys
+            }
+        };
+        KeysSnapshot::new(keys)
+    }
+
+    /// The `n` longest live keys, longest first, bucketed ones included in
+    /// their stored form. Each comes as its length and its first
+    /// `LARGEST_KEYS_PREFIX` bytes, so finding a multi-megabyte key does not
+    /// copy it.
+    pub fn largest_keys(&self, n: usize) -> Vec<(usize, Vec<u8>)> {
+        let index = self.index_read();
+        let mut longest = BinaryHeap::with_capacity(n + 1);
+        for key in index.live.keys() {
+            longest.push(Reverse((key.len(), key)));
+            if longest.len() > n {
+                longest.pop();
+            }
+        }
+        longest
+            .into_sorted_vec()
+            .into_iter()
+            .map(|Reverse((len, key))| (len, key[..len.min(LARGEST_KEYS_PREFIX)].to_vec()))
+            .collect()
+    }
+
+    /// Up to `n` live keys that `get` has read most since the engine was
+    /// loaded, most read first, with their read counts. Bucketed keys are
+    /// included in their stored form and keys never read are left out.
+    /// Counts are estimates that may run high; needs
+    /// `Options::track_access`.
+    pub fn hottest_keys(&self, n: usize) -> io::Result<Vec<(Vec<u8>, u64)>> {
+        let access = self.access_tracker()?;
+        let index = self.index_read();
+        let mut hottest = BinaryHeap::with_capacity(n + 1);
+        for key in index.live.keys().filter(|key| !index.is_expired(key)) {
+            let reads = access.reads(key);
+            if reads > 0 {
+                hottest.push(Reverse((reads, key)));
+                if hottest.len() > n {
+                    hottest.pop();
+                }
+            }
+        }
+        Ok(hottest
+            .into_sorted_vec()
+            .into_iter()
+            .map(|Reverse((reads, key))| (key.clone(), reads))
+            .collect())
+    }
+
+    /// Up to `n` live keys that `get` has not read for at least
+    /// `older_than`, least recently read first, with how long ago that was;
+    /// `None` for keys never read since the engine was loaded, which come
+    /// first. Bucketed keys are included in their stored form. Times are
+    /// estimates that may run short, so a key is never reported colder than
+    /// it is; needs `Options::track_access`.
+    pub fn coldest_keys(
+        &self,
+        n: usize,
+        older_than: Duration,
+    ) -> io::Result<Vec<(Vec<u8>, Option<Duration>)>> {
+        let access = self.access_tracker()?;
+        let index = self.index_read();
+        let mut coldest = BinaryHeap::with_capacity(n + 1);
+        for key in index.live.keys().filter(|key| !index.is_expired(key)) {
+            // Never read sorts as idle the longest.
+            let idle = access.idle_for(key).unwrap_or(Duration::MAX);
+            if idle >= older_than {
+                coldest.push(Reverse((idle, key)));
+                if coldest.len() > n {
+                    coldest.pop();
+                }
+            }
+        }
+        Ok(coldest
+            .into_sorted_vec()
+            .into_iter()
+            .map(|Reverse((idle, key))| (key.clone(), (idle != Duration::MAX).then_some(idle)))
+            .collect())
+    }
+
+    fn access_tracker(&self) -> io::Result<&AccessTracker> {
+        self.access.as_ref().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::Unsupported,
+                "access statistics need Options::track_access",
+            )
+        })
+    }
+
+    /// [`Engine::keys`], as a `Vec`.
+    pub fn raw_keys_excluding_buckets(&self) -> Vec<Vec<u8>> {
+        self.keys().into_vec()
+    }
+
+    /// The smallest live raw key in `range`, answered from
+    /// `Options::ordered_index` without reading any value. Bucketed keys are
+    /// never included.
+    pub fn first_key_in_range(&self, range: impl RangeBounds<[u8]>) -> io::Result<Option<Vec<u8>>> {
+        self.key_in_range(range, false)
+    }
+
+    /// Like [`Engine::first_key_in_range`], for the largest key.
+    pub fn last_key_in_range(&self, range: impl RangeBounds<[u8]>) -> io::Result<Option<Vec<u8>>> {
+        self.key_in_range(range, true)
+    }
+
+    /// Whether no live raw key falls in `range`, e.g. to tell if there is a
+    /// next page without fetching it.
+    pub fn is_range_empty(&self, range: impl RangeBounds<[u8]>) -> io::Result<bool> {
+        Ok(self.key_in_range(range, false)?.is_none())
+    }
+
+    fn key_in_range(
+        &self,
+        range: impl RangeBounds<[u8]>,
+        last: bool,
+    ) -> io::Result<Option<Vec<u8>>> {
+        let index = self.index_read();
+        let Some(ordered) = &index.ordered else {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "key ranges need Options::ordered_index",
+            ));
+        };
+        // Bucketed keys all sort after every raw key.
+        let buckets = [BUCKET_KEY_PREFIX];
+        // Bounds are compared with keys as stored, transformed.
+        let start = range.start_bound().map(|key| self.transform_key(key));
+        let end = range.end_bound().map(|key| self.transform_key(key));
+        let start = start.as_ref().map(|key| &**key);
+        let end = match end.as_ref().map(|key| &**key) {
+            end @ (Bound::Included(key) | Bound::Excluded(key)) if key < &buckets[..] => end,
+            _ => Bound::Excluded(&buckets[..]),
+        };
+        let inverted = match (start, end) {
+            (Bound::Included(start), Bound::Included(end)) => start > end,
+            (
+                Bound::Included(start) | Bound::Excluded(start),
+                Bound::Included(end) | Bound::Excluded(end),
+            ) => start >= end,
+            _ => false,
+        };
+        if inverted {
+            return Ok(None);
+        }
+
+        let mut keys = ordered
+            .range::<[u8], _>((start, end))
+            .filter(|key| !index.is_expired(key));
+        let key = match last {
+            true => keys.next_back(),
+            false => keys.next(),
+        };
+        Ok(key.cloned())
+    }
+
+    /// Live raw pairs whose key starts with `prefix`, in key order, as of
+    /// one instant: the index read lock is held while the values are read,
+    /// so writers wait for it. Bucketed keys are never included; use
+    /// [`Bucket::scan_prefix`] for those.
+    pub fn scan_prefix(&self, prefix: &[u8]) -> io::Result<KeysSnapshot<(Vec<u8>, Vec<u8>)>> {
+        let prefix = &*self.raw_prefix(prefix)?;
+        self.scan_encoded(prefix, true)
+    }
+
+    /// Every live raw pair, in key order, as of one instant: `scan_prefix`
+    /// of the empty prefix. Values are read through the reader pool, and a
+    /// compaction waits for the scan rather than swapping the file under
+    /// it.
+    pub fn scan(&self) -> io::Result<KeysSnapshot<(Vec<u8>, Vec<u8>)>> {
+        self.scan_encoded(&[], true)
+    }
+
+    /// Streams the live raw pairs whose key starts with `prefix`, in key
+    /// order, a page at a time. Unlike [`Engine::scan_prefix`] it is not a
+    /// point-in-time view; see [`LiveScan`] for what it does guarantee.
+    pub fn scan_live(&self, prefix: &[u8]) -> io::Result<LiveScan<'_>> {
+        let prefix = &*self.raw_prefix(prefix)?;
+        Ok(LiveScan::new(self, prefix, None, None))
+    }
+
+    /// [`Engine::scan_live`] for at most `limit` pairs with keys after
+    /// `start_after`, e.g. the previous page's [`LiveScan::cursor`].
+    pub fn scan_page(
+        &self,
+        prefix: &[u8],
+        start_after: Option<&[u8]>,
+        limit: usize,
+    ) -> io::Result<LiveScan<'_>> {
+        let prefix = &*self.raw_prefix(prefix)?;
+        Ok(LiveScan::new(self, prefix, start_after, Some(limit)))
+    }
+
+    /// Calls `f` with every pair [`Engine::scan_live`] yields for `prefix`.
+    /// Returns how many there were, or the first read error.
+    pub fn for_each(&self, prefix: &[u8], mut f: impl FnMut(&[u8], &[u8])) -> io::Result<u64> {
+        let mut count = 0;
+        for pair in self.scan_live(prefix)? {
+            let (key, value) = pair?;
+            f(&key, &value);
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Deletes every raw key starting with `prefix`, leaving buckets alone.
+    /// Keys are deleted one at a time, not atomically. Returns how many were
+    /// deleted.
+    pub fn delete_prefix(&self, prefix: &[u8]) -> io::Result<usize> {
+        let prefix = &*self.raw_prefix(prefix)?;
+        self.delete_encoded_prefix(prefix, true)
+    }
+
+    /// Counts the live raw keys under `src_prefix` and `dst_prefix`, and the
+    /// conflicts a [`Engine::rename_prefix`] between them would meet: source
+    /// keys whose suffix also exists under `dst_prefix`. Answered from the
+    /// index without reading values.
+    pub fn key_overlap(&self, src_prefix: &[u8], dst_prefix: &[u8]) -> io::Result<OverlapReport> {
+        let src_prefix = &*self.raw_prefix(src_prefix)?;
+        let dst_prefix = &*self.raw_prefix(dst_prefix)?;
+        let index = self.index_read();
+        let is_live = |key: &[u8]| {
+            index.live.contains_key(key) && !index.is_expired(key) && !bucket::is_bucket_key(key)
+        };
+        let mut report = OverlapReport::default();
+        for key in index.live.keys().filter(|key| is_live(key)) {
+            if key.starts_with(dst_prefix) {
+                report.dst_count += 1;
+            }
+            if key.starts_with(src_prefix) {
+                report.src_count += 1;
+                if is_live(&renamed(key, src_prefix, dst_prefix)) {
+                    report.conflicts += 1;
+                }
+            }
+        }
+        Ok(report)
+    }
+
+    /// Moves every live raw key under `src_prefix` to the same suffix under
+    /// `dst_prefix`, keeping its value and expiry. `on_conflict` decides
+    /// what happens to a key whose destination already existed when the
+    /// rename started (the conflicts `key_overlap` counts). Returns how many
+    /// keys were moved.
+    ///
+    /// Keys move in chunks of write batches, each putting the new keys and
+    /// deleting the old ones atomically, so readers and a crash see every
+    /// key of a chunk under one prefix or the other. Either prefix may
+    /// extend the other: keys are moved in an order that never overwrites a
+    /// key before it has moved itself.
+    pub fn rename_prefix(
+        &self,
+        src_prefix: &[u8],
+        dst_prefix: &[u8],
+        on_conflict: RenameConflict,
+    ) -> io::Result<u64> {
+        let src_prefix = &*self.raw_prefix(src_prefix)?;
+        let dst_prefix = &*self.raw_prefix(dst_prefix)?;
+        if src_prefix == dst_prefix {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "rename_prefix: source and destination are the same",
+            ));
+        }
+
+        let mut keys = self.keys_with_prefix(src_prefix, true);
+        let mut conflicts = HashSet::new();
+        {
+            let index = self.index_read();
+            for key in &keys {
+                let dst = renamed(key, src_prefix, dst_prefix);
+                check_raw_key(&dst)?;
+                if index.live.contains_key(&dst) && !index.is_expired(&dst) {
+                    conflicts.insert(key.clone());
+                }
+            }
+        }
+        if on_conflict == RenameConflict::Fail && !conflicts.is_empty() {
+            return Err(EngineError::RenameConflicts(conflicts.len() as u64).into());
+        }
+        if on_conflict == RenameConflict::Skip {
+            keys.retain(|key| !conflicts.contains(key));
+        }
+        // A destination under the source prefix is itself a source key that
+        // is longer (or, renaming to a shorter prefix, shorter) than the key
+        // moving onto it, so moving that way round empties it first.
+        match dst_prefix.len() > src_prefix.len() {
+            true => keys.sort_by_key(|key| Reverse(key.len())),
+            false => keys.sort_by_key(|key| key.len()),
+        }
+
+        let mut moved = 0;
+        let mut batch = WriteBatch::new();
+        for chunk in keys.chunks(RENAME_CHUNK_KEYS) {
+            let dsts: Vec<Vec<u8>> = chunk
+                .iter()
+                .map(|key| renamed(key, src_prefix, dst_prefix))
+                .collect();
+            let locked: Vec<&[u8]> = chunk
+                .iter()
+                .chain(&dsts)
+                .map(|key| key.as_slice())
+                .collect();
+            let _keys = self.key_locks.lock_all(&locked);
+
+            batch.clear();
+            for (key, dst) in chunk.iter().zip(&dsts) {
+                // Deleted or expired since the keys were listed.
+                let Some(record) = self.read_live(key)? else {
+                    continue;
+                };
+                let expires_at = self.index_read().expiries.get(key).copied();
+                match expires_at {
+                    Some(at) => batch.put_expiring_at(dst, &record.value, at),
+                    None => batch.put(dst, &record.value),
+                };
+                batch.delete(key);
+                moved += 1;
+            }
+            if !batch.is_empty() {
+                self.apply_batch_keys_locked(&batch)?;
+            }
+        }
+        Ok(moved)
+    }
+
+    /// Every live key, bucketed ones included, sorted.
+    #[cfg(feature = "oplog-debug")]
+    pub(crate) fn live_keys(&self) -> Vec<Vec<u8>> {
+        self.keys_with_prefix(&[], false)
+    }
+
+    /// Live keys starting with `prefix`, sorted, skipping bucketed keys
+    /// when `raw_only`.
+    fn keys_with_prefix(&self, prefix: &[u8], raw_only: bool) -> Vec<Vec<u8>> {
+        let index = self.index_read();
+        let mut keys: Vec<Vec<u8>> = index
+            .live
+            .keys()
+            .filter(|key| key.starts_with(prefix))
+            .filter(|key| !(raw_only && bucket::is_bucket_key(key)))
+            .cloned()
+            .collect();
+        keys.sort();
+        keys
+    }
+
+    /// Live pairs under `prefix`, sorted, read under one hold of the index
+    /// read lock. Skips bucketed keys when `raw_only`.
+    pub(crate) fn scan_encoded(
+        &self,
+        prefix: &[u8],
+        raw_only: bool,
+    ) -> io::Result<KeysSnapshot<(Vec<u8>, Vec<u8>)>> {
+        let index = self.index_read();
+        let mut entries: Vec<(&Vec<u8>, &LogIndex)> = index
+            .live
+            .iter()
+            .filter(|(key, _)| key.starts_with(prefix))
+            .filter(|(key, _)| !(raw_only && bucket::is_bucket_key(key)))
+            .collect();
+        entries.sort_by(|a, b| a.0.cmp(b.0));
+
+        let mut pairs = Vec::with_capacity(entries.len());
+        for (key, log_index) in entries {
+            if let Some(record) = self.read_record(&index, key, log_index)? {
+                pairs.push((record.key, record.value));
+            }
+        }
+        Ok(KeysSnapshot::new(pairs))
+    }
+
+    /// Up to `limit` live raw pairs under `prefix` with keys after `after`,
+    /// sorted, for [`LiveScan`], and whether there may be more. Walks
+    /// `Options::ordered_index` from the cursor when there is one, and
+    /// otherwise picks the smallest keys from a pass over the whole index.
+    pub(crate) fn live_page(
+        &self,
+        prefix: &[u8],
+        after: Option<&[u8]>,
+        limit: usize,
+    ) -> io::Result<LivePage> {
+        let index = self.index_read();
+        let wanted = |key: &[u8]| {
+            key.starts_with(prefix)
+                && !bucket::is_bucket_key(key)
+                && after.is_none_or(|after| key > after)
+                && !index.is_expired(key)
+        };
+        let keys: Vec<&Vec<u8>> = match &index.ordered {
+            Some(ordered) => {
+                let start = match after {
+                    Some(after) if after >= prefix => Bound::Excluded(after),
+                    _ => Bound::Included(prefix),
+                };
+                ordered
+                    .range::<[u8], _>((start, Bound::Unbounded))
+                    .take_while(|key| key.starts_with(prefix))
+                    .filter(|key| wanted(key))
+                    .take(limit)
+                    .collect()
+            }
+            None => {
+                let mut smallest = BinaryHeap::with_capacity(limit + 1);
+                for key in index.live.keys().filter(|key| wanted(key)) {
+                    smallest.push(key);
+                    if smallest.len() > limit {
+                        smallest.pop();
+                    }
+                }
+                smallest.into_sorted_vec()
+            }
+        };
+
+        // A key that expires between the pick and the read is skipped
+        // without cutting the scan short.
+        let more = keys.len() == limit;
+        let mut pairs = Vec::with_capacity(keys.len());
+        for key in keys {
+            if let Some(record) = self.read_record(&index, key, &index.live[key])? {
+                pairs.push((record.key, record.value));
+            }
+        }
+        Ok((pairs, more))
+    }
+
+    pub(crate) fn delete_encoded_prefix(&self, prefix: &[u8], raw_only: bool) -> io::Result<usize> {
+        let keys = self.keys_with_prefix(prefix, raw_only);
+        for key in &keys {
+            self.del_key(key)?;
+        }
+        Ok(keys.len())
+    }
+
+    /// Writes every live key to `writer` in the frozen dump format (see
+    /// [`crate::dump`]), in ascending byte order of the keys, so dumping an
+    /// unchanged store gives the same bytes every time, whichever index it
+    /// uses. Returns the record count.
+    ///
+    /// The index read lock is held for the whole dump so the output is a
+    /// consistent point-in-time view; writers block until it finishes.
+    /// Under `Options::ordered_index` keys are taken in order from the
+    /// index; otherwise more than `DUMP_SORT_CHUNK_BYTES` of them are sorted
+    /// in runs spilled beside the data file, as `export_sorted_keys` does.
+    pub fn dump(&self, writer: impl Write) -> io::Result<u64> {
+        let index = self.index_read();
+
+        let mut readahead = self.readahead()?;
+        let mut dump = DumpWriter::new(writer)?;
+        let mut write = |key: &[u8]| -> io::Result<()> {
+            let read = |at: &LogIndex| self.read_ahead(&mut readahead, at);
+            if let Some(record) = Self::read_record_with(&index, key, &index.live[key], read)? {
+                dump.write_record(&record.key, &record.value, record.tstamp)?;
+            }
+            Ok(())
+        };
+        match &index.ordered {
+            Some(ordered) => {
+                for key in ordered {
+                    write(key)?;
+                }
+            }
+            None => {
+                self.for_each_key_sorted(&index, DUMP_SORT_CHUNK_BYTES, &mut write)?;
+            }
+        }
+        drop(index);
+
+        dump.finish()
+    }
 
-pub struct Engine {
-    path: PathBuf,
-    file: Mutex<File>,
-    index: RwLock<HashMap<Vec<u8>, LogIndex>>,
-    file_size: Mutex<u64>,
-    compact_threshold: Mutex<u64>,
-    reader_pool: Mutex<Vec<File>>,
-}
+    /// Writes the live raw keys `select` picks, with their values, to
+    /// `writer` in the dump format, in ascending byte order, and returns
+    /// the dump's manifest, which [`crate::dump::verify_subset`] checks the
+    /// output against. Bucketed keys and the engine's own are never passed
+    /// to `select`.
+    ///
+    /// Like [`Engine::dump`] it holds the index read lock throughout, so
+    /// the subset is as of one point in time, the one the report names,
+    /// and writers wait until it is written. The picked keys are sorted in
+    /// memory.
+    pub fn export_subset(
+        &self,
+        select: impl Fn(&[u8]) -> bool,
+        writer: impl Write,
+    ) -> io::Result<SubsetReport> {
+        // Taken under the file mutex, so no append is waiting to be indexed.
+        let (index, log_end) = {
+            let _file = self.lock_file();
+            self.check_open()?;
+            (self.index_read(), *self.file_size.lock().unwrap())
+        };
+        let generation = self.generation.load(Ordering::Acquire);
+        let mut keys: Vec<&Vec<u8>> = index
+            .live
+            .keys()
+            .filter(|key| !bucket::is_bucket_key(key) && select(key))
+            .collect();
+        keys.sort_unstable();
 
-impl Engine {
-    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
-        let path = path.as_ref().to_path_buf();
-        let compact_threshold = Self::ensure_header(&path, DEFAULT_COMPACT_THRESHOLD)?;
-        let file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .truncate(false)
-            .open(&path)?;
+        let mut readahead = self.readahead()?;
+        let mut dump = DumpWriter::new(writer)?;
+        let mut bytes = 0;
+        for key in keys {
+            let read = |at: &LogIndex| self.read_ahead(&mut readahead, at);
+            if let Some(record) = Self::read_record_with(&index, key, &index.live[key], read)? {
+                bytes += (record.key.len() + record.value.len()) as u64;
+                dump.write_record(&record.key, &record.value, record.tstamp)?;
+            }
+        }
+        drop(index);
+
+        let (keys, crc) = dump.finish_with_crc()?;
+        Ok(SubsetReport {
+            store: self.path.clone(),
+            keys,
+            bytes,
+            generation,
+            log_end,
+            crc,
+        })
+    }
+
+    /// [`Engine::export_subset`] of the raw keys starting with `prefix`.
+    pub fn export_prefix(&self, prefix: &[u8], writer: impl Write) -> io::Result<SubsetReport> {
+        let prefix = &*self.raw_prefix(prefix)?;
+        self.export_subset(|key| key.starts_with(prefix), writer)
+    }
+
+    /// Applies the patch read from `reader` (see [`crate::patch`]) as one
+    /// write batch, so readers and a crash see all of it or none. Every
+    /// entry's expected prior is checked first, with the locks of every key
+    /// the patch touches held through the write; if any does not match,
+    /// nothing is written. With `dry_run`, nothing is written either way.
+    /// The report has one outcome per entry and says whether the patch was
+    /// written. A patch naming a key twice fails with `InvalidInput`.
+    pub fn apply_patch(&self, reader: impl Read, dry_run: bool) -> io::Result<PatchReport> {
+        self.apply_patch_entries(Patch::read(reader)?, dry_run, false)
+    }
+
+    /// `apply_patch`, writing the patch even where an expected prior does
+    /// not match. The report still lists the mismatches.
+    pub fn apply_patch_forced(&self, reader: impl Read, dry_run: bool) -> io::Result<PatchReport> {
+        self.apply_patch_entries(Patch::read(reader)?, dry_run, true)
+    }
 
-        let mut readers = Vec::new();
-        for _ in 0..4 {
-            if let Ok(r) = OpenOptions::new().read(true).open(&path) {
-                readers.push(r);
+    fn apply_patch_entries(
+        &self,
+        patch: Patch,
+        dry_run: bool,
+        force: bool,
+    ) -> io::Result<PatchReport> {
+        self.check_open()?;
+        let mut entries = Vec::with_capacity(patch.entries.len());
+        let mut seen = HashSet::new();
+        for entry in patch.entries {
+            let key = self.raw_key(&entry.key)?.into_owned();
+            if !seen.insert(key.clone()) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("patch names {} twice", key.escape_ascii()),
+                ));
             }
+            entries.push(PatchEntry { key, ..entry });
         }
+        let keys: Vec<&[u8]> = entries.iter().map(|entry| entry.key.as_slice()).collect();
+        let _keys = self.key_locks.lock_all(&keys);
 
-        let engine = Engine {
-            path,
-            file: Mutex::new(file),
-            index: RwLock::new(HashMap::new()),
-            file_size: Mutex::new(0),
-            compact_threshold: Mutex::new(compact_threshold),
-            reader_pool: Mutex::new(readers),
+        let mut batch = WriteBatch::new();
+        let mut outcomes = Vec::with_capacity(entries.len());
+        for entry in &entries {
+            let found = match self.stored_value_hash(&entry.key)? {
+                Some(hash) => Prior::Hash(hash),
+                None => Prior::Absent,
+            };
+            let change = match (&entry.op, found) {
+                (PatchOp::Set(value), found) => {
+                    batch.put(&entry.key, value);
+                    match found {
+                        Prior::Absent => PatchChange::Created,
+                        Prior::Hash(_) => PatchChange::Replaced,
+                    }
+                }
+                (PatchOp::Del, Prior::Absent) => PatchChange::AlreadyAbsent,
+                (PatchOp::Del, Prior::Hash(_)) => {
+                    batch.delete(&entry.key);
+                    PatchChange::Deleted
+                }
+            };
+            outcomes.push(PatchOutcome {
+                key: entry.key.clone(),
+                found,
+                matched: entry.expect.is_none_or(|expect| expect == found),
+                change,
+            });
+        }
+
+        let mut report = PatchReport {
+            applied: false,
+            outcomes,
         };
+        if dry_run || (report.mismatches() > 0 && !force) {
+            return Ok(report);
+        }
+        if !batch.is_empty() {
+            self.apply_batch_keys_locked(&batch)?;
+        }
+        report.applied = true;
+        Ok(report)
+    }
 
-        engine.rebuild_index()?;
+    /// A patch that turns this store into `other`: sets of every key whose
+    /// value is missing here or differs, and deletes of every key `other`
+    /// lacks, each expecting what this store holds now. Bucketed and
+    /// internal keys are left out, and values carry no TTL. Neither store
+    /// is read at one instant; a write here in the meantime makes the patch
+    /// fail its checks rather than apply over it.
+    pub fn diff_to_patch(&self, other: &Engine) -> io::Result<Patch> {
+        let mut keys = self.keys().into_vec();
+        keys.extend(other.keys());
+        keys.sort_unstable();
+        keys.dedup();
+        let mut entries = Vec::new();
+        for key in keys {
+            let here = self.get_key(&key)?;
+            let there = other.get_key(&key)?;
+            let expect = match &here {
+                Some(value) => Prior::Hash(index::value_hash(value)),
+                None => Prior::Absent,
+            };
+            let op = match (here, there) {
+                (here, Some(there)) if here.as_ref() != Some(&there) => PatchOp::Set(there),
+                (Some(_), None) => PatchOp::Del,
+                // Unchanged, or gone from both since they were listed.
+                _ => continue,
+            };
+            entries.push(PatchEntry {
+                key,
+                op,
+                expect: Some(expect),
+            });
+        }
+        Ok(Patch { entries })
+    }
 
-        Ok(engine)
+    /// Calls `on_key` with every key of the hash index in ascending byte
+    /// order, holding at most `chunk_bytes` of keys in memory: keys that fit
+    /// in one chunk are sorted in place, and more are sorted in runs spilled
+    /// beside the data file (or in `Options::compaction_dir`) and merged.
+    fn for_each_key_sorted(
+        &self,
+        index: &Index,
+        chunk_bytes: usize,
+        on_key: &mut dyn FnMut(&[u8]) -> io::Result<()>,
+    ) -> io::Result<()> {
+        let mut sort = ExternalSort::new(self.storage.as_ref(), self.compaction_tmp_path("sort"));
+        let mut chunk = Vec::new();
+        let mut chunk_used = 0;
+        for key in index.live.keys() {
+            let cost = key.len() + external_sort::KEY_OVERHEAD;
+            if chunk_used + cost > chunk_bytes && !chunk.is_empty() {
+                sort.spill(&mut chunk, &mut |_| {})?;
+                chunk_used = 0;
+            }
+            chunk.push(key.clone());
+            chunk_used += cost;
+        }
+        if !sort.has_runs() {
+            chunk.sort_unstable();
+            return chunk.iter().try_for_each(|key| on_key(key));
+        }
+        sort.spill(&mut chunk, &mut |_| {})?;
+        sort.finish_with(on_key, &mut |_| {})?;
+        Ok(())
     }
 
-    fn ensure_header(path: &Path, compact_threshold: u64) -> io::Result<u64> {
-        let mut file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .truncate(false)
-            .open(path)?;
+    /// Writes every live key to a sealed snapshot at `path` that another
+    /// process can map and read with [`crate::SnapshotReader`] (see
+    /// [`crate::shared_snapshot`]), leaving out the engine's own keys unless
+    /// `Options::include_internal_keys` is set. Any file already at `path` is replaced
+    /// once the new one is complete. Returns the key count.
+    ///
+    /// Like [`Engine::dump`], the index read lock is held throughout so the
+    /// snapshot is a point-in-time view.
+    pub fn export_shm_snapshot(&self, path: impl AsRef<Path>) -> io::Result<u64> {
+        let index = self.index_read();
+        // The snapshot's layout does not depend on the order records are
+        // added in, so they are read in file order.
+        let mut entries: Vec<(&Vec<u8>, &LogIndex)> = index
+            .live
+            .iter()
+            .filter(|(key, _)| !self.is_hidden_key(key))
+            .collect();
+        entries.sort_by_key(|(_, log_index)| log_index.pos);
 
-        let file_len = file.metadata()?.len();
-        if file_len == 0 {
-            Self::write_header(&mut file, compact_threshold)?;
-            return Ok(compact_threshold);
+        let mut readahead = self.readahead()?;
+        let mut snapshot = SnapshotWriter::create(path.as_ref())?;
+        for (key, log_index) in entries {
+            let read = |at: &LogIndex| self.read_ahead(&mut readahead, at);
+            if let Some(record) = Self::read_record_with(&index, key, log_index, read)? {
+                snapshot.write_record(&record.key, &record.value)?;
+            }
         }
+        drop(index);
+
+        snapshot.finish()
+    }
+
+    /// Writes every live key, bucketed ones included and the engine's own
+    /// left out (see `Options::include_internal_keys`), to `writer` in
+    /// ascending byte order as `[key length u64 LE][key]`, holding at most
+    /// `chunk_bytes` of keys in memory: sorted runs are spilled to temporary
+    /// files beside the data file (or in `Options::compaction_dir`) and
+    /// merged. Under `Options::ordered_index` keys are written straight from
+    /// the index instead, with nothing spilled. Either way an unchanged
+    /// store exports the same bytes every time. Returns the number of keys
+    /// written.
+    ///
+    /// The index read lock is held while keys are gathered and spilled, so
+    /// the export is a point-in-time view and writers wait for that phase.
+    pub fn export_sorted_keys(&self, writer: impl Write, chunk_bytes: usize) -> io::Result<u64> {
+        self.export_sorted_keys_with_progress(writer, chunk_bytes, |_| {})
+    }
 
-        if file_len < FILE_HEADER_SIZE {
+    /// [`Engine::export_sorted_keys`], calling `on_progress` after each run
+    /// is spilled and periodically while the merged keys are written.
+    pub fn export_sorted_keys_with_progress(
+        &self,
+        mut writer: impl Write,
+        chunk_bytes: usize,
+        mut on_progress: impl FnMut(&ExportProgress),
+    ) -> io::Result<u64> {
+        if chunk_bytes == 0 {
             return Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                "invalid data.db: missing header",
+                io::ErrorKind::InvalidInput,
+                "chunk_bytes must be positive",
             ));
         }
 
-        file.seek(SeekFrom::Start(0))?;
-        let mut magic = [0u8; FILE_HEADER_MAGIC.len()];
-        file.read_exact(&mut magic)?;
-        if magic != FILE_HEADER_MAGIC {
+        let mut sort = ExternalSort::new(self.storage.as_ref(), self.compaction_tmp_path("sort"));
+        {
+            let index = self.index_read();
+            if let Some(ordered) = &index.ordered {
+                let mut written = 0;
+                for key in ordered
+                    .iter()
+                    .filter(|key| !index.is_expired(key) && !self.is_hidden_key(key))
+                {
+                    external_sort::write_key(&mut writer, key)?;
+                    written += 1;
+                }
+                writer.flush()?;
+                on_progress(&ExportProgress {
+                    keys_written: written,
+                    ..ExportProgress::default()
+                });
+                return Ok(written);
+            }
+            let mut chunk = Vec::new();
+            let mut chunk_used = 0;
+            for key in index
+                .live
+                .keys()
+                .filter(|key| !index.is_expired(key) && !self.is_hidden_key(key))
+            {
+                let cost = key.len() + external_sort::KEY_OVERHEAD;
+                if chunk_used + cost > chunk_bytes && !chunk.is_empty() {
+                    sort.spill(&mut chunk, &mut on_progress)?;
+                    chunk_used = 0;
+                }
+                chunk.push(key.clone());
+                chunk_used += cost;
+            }
+            sort.spill(&mut chunk, &mut on_progress)?;
+        }
+        sort.finish(&mut writer, &mut on_progress)
+    }
+
+    /// Picks about `fraction` (0.0..=1.0) of the live keys by hashing each
+    /// with `seed`; the same store, seed, and fraction always give the same
+    /// keys. See [`crate::sample`].
+    pub fn sample(&self, fraction: f64, seed: u64) -> io::Result<Sample<'_>> {
+        if !(0.0..=1.0).contains(&fraction) {
             return Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                "invalid data.db: unsupported format (missing KVS1 header)",
+                io::ErrorKind::InvalidInput,
+                "sample fraction must be between 0.0 and 1.0",
             ));
         }
 
-        let mut threshold_buf = [0u8; 8];
-        file.read_exact(&mut threshold_buf)?;
-        Ok(u64::from_le_bytes(threshold_buf))
+        let mut keys: Vec<Vec<u8>> = self
+            .index
+            .read()
+            .unwrap()
+            .live
+            .keys()
+            .filter(|key| !self.is_hidden_key(key) && sample::is_sampled(key, fraction, seed))
+            .cloned()
+            .collect();
+        keys.sort();
+        Ok(Sample::new(self, keys))
+    }
+
+    /// Reads the current record of `key`, or `None` if it is not live.
+    pub(crate) fn read_live(&self, key: &[u8]) -> io::Result<Option<DumpRecord>> {
+        let index = self.index_read();
+        match index.live.get(key) {
+            Some(log_index) => self.read_record(&index, key, log_index),
+            None => Ok(None),
+        }
     }
 
-    fn write_header(file: &mut File, compact_threshold: u64) -> io::Result<()> {
-        file.seek(SeekFrom::Start(0))?;
-        file.write_all(&FILE_HEADER_MAGIC)?;
-        file.write_all(&compact_threshold.to_le_bytes())?;
-        file.flush()?;
-        Ok(())
+    /// Reads the live record at `log_index`, resolving a blob reference to
+    /// its value. Expired keys read as `None`.
+    fn read_record(
+        &self,
+        index: &Index,
+        key: &[u8],
+        log_index: &LogIndex,
+    ) -> io::Result<Option<DumpRecord>> {
+        Self::read_record_with(index, key, log_index, |at| self.read_entry(at))
     }
 
-    fn persist_threshold(&self, compact_threshold: u64) -> io::Result<()> {
-        let mut file = OpenOptions::new().read(true).write(true).open(&self.path)?;
-        Self::write_header(&mut file, compact_threshold)
+    /// [`Engine::read_record`], reading entries with `read_entry`.
+    fn read_record_with(
+        index: &Index,
+        key: &[u8],
+        log_index: &LogIndex,
+        mut read_entry: impl FnMut(&LogIndex) -> io::Result<DataFileEntry>,
+    ) -> io::Result<Option<DumpRecord>> {
+        if index.is_expired(key) {
+            return Ok(None);
+        }
+        let entry = read_entry(log_index)?;
+        let tstamp = entry.tstamp;
+        let value = match index.refs.contains_key(key) {
+            true => read_entry(index.value_location(key, log_index).2)?.value,
+            false => entry.into_value(),
+        };
+        Ok(value.map(|value| DumpRecord {
+            key: key.to_vec(),
+            value,
+            tstamp,
+        }))
     }
 
-    fn rebuild_index(&self) -> io::Result<()> {
-        let mut file = self.file.lock().unwrap();
-        file.seek(SeekFrom::Start(FILE_HEADER_SIZE))?;
-        let mut rebuilt_index: HashMap<Vec<u8>, LogIndex> = HashMap::new();
+    /// Restores a dump produced by [`Engine::dump`] into this store, keeping
+    /// the original timestamps. The whole stream is validated before any
+    /// record is written. Returns the number of records restored.
+    pub fn load_dump(&self, reader: impl Read) -> io::Result<u64> {
+        let records = dump::read_dump(reader)?;
+        let count = records.len() as u64;
+        for record in &records {
+            check_key(&record.key)?;
+            bucket::check_well_formed(&record.key)?;
+        }
 
-        loop {
-            let mut len_buf = [0u8; LEN_PREFIX_SIZE as usize];
-            match file.read_exact(&mut len_buf) {
-                Ok(_) => {}
-                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
-                Err(e) => return Err(e),
-            }
+        for record in records {
+            self.set_resolved(
+                &record.key,
+                &record.value,
+                record.tstamp,
+                self.options.import_conflict_policy,
+            )?;
+        }
 
-            let entry_len = u64::from_le_bytes(len_buf);
-            let data_pos = file.stream_position()?;
+        Ok(count)
+    }
 
-            let mut data = vec![0u8; entry_len as usize];
-            match file.read_exact(&mut data) {
-                Ok(_) => {}
-                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
-                Err(e) => return Err(e),
+    /// Recovers what it can from the data file at `path`, typically a
+    /// damaged store, and writes it into this one: the record each key
+    /// ended on, with its original timestamp, resolved against this store's
+    /// values under `policy`. Damaged regions are stepped over rather than
+    /// ending the scan, and reported along with what happened to every
+    /// record recovered.
+    ///
+    /// Writes go out in chunks of 1024 records, each with one append and an
+    /// fsync, so a crash partway through loses at most the
+    /// chunk in flight. The file at `path` is only read.
+    pub fn ingest_raw_log(
+        &self,
+        path: impl AsRef<Path>,
+        policy: ConflictPolicy,
+    ) -> io::Result<IngestReport> {
+        let salvage = salvage::scan(self.storage.as_ref(), path.as_ref())?;
+        let recovered = salvage.records.len() as u64;
+        let (writes, skipped) = salvage::final_writes(salvage.records);
+        for entry in &writes {
+            bucket::check_well_formed(&entry.key)?;
+            if let Some(value) = entry.clone().into_value() {
+                self.validate_value(&entry.key, &value)?;
             }
+        }
+
+        let mut report = IngestReport {
+            recovered,
+            skipped,
+            corrupt_regions: salvage
+                .corrupt_regions
+                .into_iter()
+                .map(|(start, end)| start..end)
+                .collect(),
+            ..IngestReport::default()
+        };
+        for chunk in writes.chunks(INGEST_CHUNK_RECORDS) {
+            self.ingest_chunk(chunk, policy, &mut report)?;
+            self.sync()?;
+        }
+        Ok(report)
+    }
 
-            let entry: DataFileEntry = wincode::deserialize(&data)
-                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+    /// Resolves `chunk` against the stored values and appends what survives
+    /// with one write, counting the outcome of each record into `report`.
+    fn ingest_chunk(
+        &self,
+        chunk: &[DataFileEntry],
+        policy: ConflictPolicy,
+        report: &mut IngestReport,
+    ) -> io::Result<()> {
+        let keys: Vec<&[u8]> = chunk.iter().map(|entry| entry.key.as_slice()).collect();
+        let _keys = self.key_locks.lock_all(&keys);
+        // Held across the checks so no other write lands in between.
+        let mut file = self.lock_file();
+        // KVS1 records have no kind to mark a loser with.
+        let keep_stale = policy == ConflictPolicy::KeepNewest
+            && self.format_version.load(Ordering::Acquire) != 1;
 
-            match entry.value {
-                Some(_) => {
-                    rebuilt_index.insert(
-                        entry.key,
-                        LogIndex {
-                            pos: data_pos,
-                            len: entry_len,
-                        },
-                    );
-                }
-                None => {
-                    rebuilt_index.remove(&entry.key);
-                }
+        let mut entries = Vec::with_capacity(chunk.len());
+        for entry in chunk {
+            let stored = self.stored_tstamp(&entry.key)?;
+            if entry.kind == EntryKind::Tombstone && stored.is_none() {
+                report.skipped += 1;
+                continue;
+            }
+            let older = policy != ConflictPolicy::AlwaysAccept
+                && stored.is_some_and(|stored| entry.tstamp < stored);
+            if !older {
+                report.applied += 1;
+                entries.push(entry.clone());
+                continue;
+            }
+            report.conflicted += 1;
+            if keep_stale && entry.kind != EntryKind::Tombstone {
+                let value = entry.clone().into_value().unwrap_or_default();
+                entries.push(DataFileEntry {
+                    kind: EntryKind::StalePut,
+                    ..DataFileEntry::put(entry.tstamp, entry.key.clone(), value)
+                });
             }
         }
+        if entries.is_empty() {
+            return Ok(());
+        }
 
-        *self.index.write().unwrap() = rebuilt_index;
-        *self.file_size.lock().unwrap() = file.stream_position()?;
-
-        Ok(())
+        let new_file_size = self.append_batch_locked(&mut file, &entries)?;
+        #[cfg(feature = "oplog-debug")]
+        if let Some(journal) = self.journal() {
+            let current: Vec<DataFileEntry> = entries
+                .iter()
+                .filter(|entry| entry.kind != EntryKind::StalePut)
+                .cloned()
+                .collect();
+            journal.record_group(&current);
+        }
+        let kind = match entries.iter().any(|e| e.kind != EntryKind::Tombstone) {
+            true => EntryKind::Put,
+            false => EntryKind::Tombstone,
+        };
+        self.finish_write(file, kind, new_file_size)
     }
 
-    pub fn set(&self, key: &[u8], value: &[u8]) -> io::Result<()> {
-        let tstamp = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .map(|d| d.as_millis() as i64)
-            .unwrap_or(0);
+    /// Writes a self-contained, immediately loadable copy of the store into
+    /// `dir` under the data file's name, using a reflink where the platform
+    /// supports one and a byte copy otherwise. The copy and the directory
+    /// entry are fsynced before returning.
+    ///
+    /// Writes and compaction are blocked while the copy is taken.
+    pub fn checkpoint(&self, dir: impl AsRef<Path>) -> io::Result<CloneMethod> {
+        let dir = dir.as_ref();
+        let file_name = self.path.file_name().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "data path has no file name")
+        })?;
+        let target = dir.join(file_name);
 
-        let entry = DataFileEntry {
-            tstamp,
-            key: key.to_vec(),
-            value: Some(value.to_vec()),
-        };
+        let mut file = self.lock_file();
+        file.flush()?;
+        let len = *self.file_size.lock().unwrap();
 
-        let data = wincode::serialize(&entry).map_err(|e| io::Error::other(e.to_string()))?;
+        self.storage.create_dir_all(dir)?;
+        let method = self.storage.clone_file(&self.path, &target, len)?;
+        drop(file);
 
-        let entry_len = data.len() as u64;
+        self.storage.sync_dir(dir)?;
+        Ok(method)
+    }
 
-        let mut file = self.file.lock().unwrap();
-        file.seek(SeekFrom::End(0))?;
-        file.write_all(&entry_len.to_le_bytes())?;
+    pub fn compact(&self) -> io::Result<()> {
+        let result = self.compact_until(None).map(|_| ());
+        self.deliver_limit_warnings();
+        result
+    }
 
-        let data_pos = file.stream_position()?;
-        file.write_all(&data)?;
+    /// Compacts, unless copying is still going at `deadline`: then the
+    /// output is removed and the data file left as it was. Returns whether
+    /// it compacted.
+    fn compact_until(&self, deadline: Option<Instant>) -> io::Result<bool> {
+        let mut file = self.lock_file();
+        self.check_writable()?;
 
-        let new_file_size = *self.file_size.lock().unwrap() + LEN_PREFIX_SIZE + entry_len;
-        *self.file_size.lock().unwrap() = new_file_size;
+        let mut compaction = self.begin_compaction(&mut file, self.compaction_tmp_path("tmp"))?;
+        while let Some(record) = compaction.records.pop_front() {
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                let tmp_path = compaction.tmp_path.clone();
+                drop(compaction);
+                Metrics::incr(&self.metrics.compactions_aborted);
+                return self.storage.remove_file(&tmp_path).map(|()| false);
+            }
+            compaction.copy_record(&mut file, record)?;
+        }
+        self.finish_compaction(&mut file, compaction)?;
+        #[cfg(feature = "oplog-debug")]
+        if let Some(journal) = self.journal() {
+            journal.record(JournalOp::Compact, &[], None);
+        }
+        Ok(true)
+    }
 
-        self.index.write().unwrap().insert(
-            key.to_vec(),
-            LogIndex {
-                pos: data_pos,
-                len: entry_len,
-            },
-        );
+    /// Runs a compaction in slices: copies records into the output until
+    /// `budget` has passed (at least one per call) and returns, picking up
+    /// where it stopped on the next call. Writes keep going in between; the
+    /// call that copies the last record also carries over everything written
+    /// since the compaction started, under the file lock, then swaps the
+    /// output in and returns `Done`.
+    ///
+    /// If the data file is replaced meanwhile (by `compact`, an automatic
+    /// compaction, or `reload`), the partial output is discarded and the next
+    /// call starts over.
+    pub fn compact_step(&self, budget: Duration) -> io::Result<CompactProgress> {
+        let started = Instant::now();
+        let mut stepped = self.stepped.lock().unwrap();
+        let result = self.compact_step_locked(&mut stepped, started, budget);
+        if result.is_err() {
+            self.discard_stepped(&mut stepped)?;
+        }
+        drop(stepped);
+        self.deliver_limit_warnings();
+        result
+    }
 
-        let current_threshold = *self.compact_threshold.lock().unwrap();
-        let should_compact = new_file_size >= current_threshold;
-        drop(file);
+    /// Abandons the stepped compaction in progress, if any, and removes its
+    /// output. Returns whether there was one.
+    pub fn compact_abort(&self) -> io::Result<bool> {
+        let mut stepped = self.stepped.lock().unwrap();
+        let in_progress = stepped.is_some();
+        self.discard_stepped(&mut stepped)?;
+        Ok(in_progress)
+    }
 
-        if should_compact {
-            self.compact()?;
+    fn compact_step_locked(
+        &self,
+        stepped: &mut Option<SteppedCompaction>,
+        started: Instant,
+        budget: Duration,
+    ) -> io::Result<CompactProgress> {
+        if stepped
+            .as_ref()
+            .is_some_and(|s| s.generation != self.generation.load(Ordering::Acquire))
+        {
+            self.discard_stepped(stepped)?;
         }
+        let state = match stepped {
+            Some(state) => state,
+            None => {
+                let mut file = self.lock_file();
+                self.check_writable()?;
+                let compaction =
+                    self.begin_compaction(&mut file, self.compaction_tmp_path("step"))?;
+                let source = self.storage.open(&self.path, OpenMode::Read)?;
+                stepped.insert(SteppedCompaction {
+                    compaction,
+                    source,
+                    generation: self.generation.load(Ordering::Acquire),
+                })
+            }
+        };
 
-        Ok(())
+        while let Some(record) = state.compaction.records.pop_front() {
+            state.compaction.copy_record(&mut state.source, record)?;
+            if started.elapsed() >= budget {
+                break;
+            }
+        }
+        if !state.compaction.records.is_empty() {
+            return Ok(state.compaction.progress());
+        }
+
+        let mut file = self.lock_file();
+        if state.generation != self.generation.load(Ordering::Acquire) {
+            self.discard_stepped(stepped)?;
+            return Ok(CompactProgress::InProgress {
+                copied: 0,
+                remaining_estimate: self.compaction_estimate().expected_size,
+            });
+        }
+        self.check_writable()?;
+        let mut compaction = stepped.take().unwrap().compaction;
+        if let Err(e) = self.carry_over(&mut file, &mut compaction) {
+            let _ = self.storage.remove_file(&compaction.tmp_path);
+            return Err(e);
+        }
+        let report = self.finish_compaction(&mut file, compaction)?;
+        Ok(CompactProgress::Done(report))
     }
 
-    pub fn del(&self, key: &[u8]) -> io::Result<()> {
-        let tstamp = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .map(|d| d.as_millis() as i64)
-            .unwrap_or(0);
+    fn discard_stepped(&self, stepped: &mut Option<SteppedCompaction>) -> io::Result<()> {
+        let Some(state) = stepped.take() else {
+            return Ok(());
+        };
+        let tmp_path = state.compaction.tmp_path.clone();
+        drop(state);
+        match self.storage.remove_file(&tmp_path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
 
-        let entry = DataFileEntry {
-            tstamp,
-            key: key.to_vec(),
-            value: None,
+    /// Snapshots what a compaction has to copy and creates its output file,
+    /// starting with the log's packed prefix. Call with the file lock held
+    /// and its handle, so the snapshot matches the log up to the current
+    /// file size.
+    fn begin_compaction(&self, file: &mut FileHandle, tmp_path: PathBuf) -> io::Result<Compaction> {
+        if self.options.audit_mode {
+            return Err(EngineError::AuditMode.into());
+        }
+        let compact_threshold = self.compact_threshold.load(Ordering::Acquire);
+        let expected_size = self.compaction_estimate().expected_size;
+        self.check_space(&tmp_path, expected_size)?;
+
+        // Each key's retained history is written oldest first, ahead of its
+        // live version, so replaying the compacted file rebuilds the same rings.
+        // Soft-deleted entries past their window are dropped here, which is
+        // equivalent to turning them into tombstones. Referenced blobs go
+        // first so every BlobRef finds its blob on replay; unreferenced ones
+        // are dropped, and so are keys whose TTL has passed.
+        let mut records: VecDeque<CompactRecord> = VecDeque::new();
+        let mut new_index = Index::new(
+            self.options.history_depth,
+            FORMAT_VERSION,
+            self.options.ordered_index,
+            self.options.value_hashes,
+        );
+        {
+            let index = self.index_read();
+            for (hash, blob) in &index.blobs {
+                if blob.refs > 0 {
+                    records.push_back((
+                        EntryKind::Blob,
+                        Arc::from(&hash[..]),
+                        blob.index.clone(),
+                        0,
+                    ));
+                }
+            }
+            for (key, log_index) in &index.live {
+                if index.is_expired(key) {
+                    continue;
+                }
+                let key: Arc<[u8]> = Arc::from(&key[..]);
+                if let Some(ring) = index.history.get(&key[..]) {
+                    for old in ring {
+                        records.push_back((EntryKind::Put, Arc::clone(&key), old.clone(), 0));
+                    }
+                }
+                let kind = if index.refs.contains_key(&key[..]) {
+                    EntryKind::BlobRef
+                } else if index.expiries.contains_key(&key[..]) {
+                    EntryKind::ExpiringPut
+                } else if index.archived.contains_key(&key[..]) {
+                    EntryKind::Archived
+                } else {
+                    EntryKind::Put
+                };
+                // Carried so a copied `Put` keeps its write time in the
+                // new index.
+                let tstamp = index.tstamp(&key).unwrap_or(0);
+                records.push_back((kind, key, log_index.clone(), tstamp));
+            }
+            for (key, sd) in &index.soft_deleted {
+                if !self.soft_delete_expired(sd) {
+                    records.push_back((
+                        EntryKind::SoftDelete,
+                        Arc::from(&key[..]),
+                        sd.index.clone(),
+                        sd.deleted_at,
+                    ));
+                }
+            }
+            for prefix in index.tracked_prefixes() {
+                new_index.track_prefix(&prefix);
+            }
+        }
+
+        let packed_end = self.packed_prefix(file, &mut records, &mut new_index)?;
+        let mode = match packed_end > FILE_HEADER_SIZE {
+            true => {
+                file.flush()?;
+                self.storage.clone_file(&self.path, &tmp_path, packed_end)?;
+                OpenMode::ReadWrite
+            }
+            false => OpenMode::Truncate,
         };
+        let mut tmp_file = self.storage.open(&tmp_path, mode)?;
+        // The epoch is filled in at the swap.
+        Self::write_header(
+            &mut *tmp_file,
+            &Header {
+                format_version: FORMAT_VERSION,
+                compact_threshold,
+                epoch: 0,
+                flags: 0,
+            },
+        )?;
+        tmp_file.seek(SeekFrom::Start(packed_end))?;
 
-        let data = wincode::serialize(&entry).map_err(|e| io::Error::other(e.to_string()))?;
+        let remaining_bytes = records
+            .iter()
+            .map(|(_, _, log_index, _)| {
+                framing::prefix_len(FORMAT_VERSION, log_index.len) + log_index.len
+            })
+            .sum();
 
-        let entry_len = data.len() as u64;
+        Ok(Compaction {
+            tmp_path,
+            tmp_file,
+            records,
+            remaining_bytes,
+            new_index,
+            new_file_size: packed_end,
+            old_version: self.format_version.load(Ordering::Acquire),
+            snapshot_end: *self.file_size.lock().unwrap(),
+            compact_threshold,
+            carried_over: 0,
+            preserved_bytes: packed_end - FILE_HEADER_SIZE,
+        })
+    }
 
-        let mut file = self.file.lock().unwrap();
-        file.seek(SeekFrom::End(0))?;
-        file.write_all(&entry_len.to_le_bytes())?;
+    /// Finds the log's packed prefix: the longest run of records from the
+    /// header on that compaction would write unchanged, at the same
+    /// offsets, in an order that replays the same. Such a run is kept by
+    /// cloning it into the output, which is a reflink where the filesystem
+    /// supports one, so a store compacted recently does not copy its old
+    /// records again record by record. Indexes the run into `new_index`,
+    /// drops it from `records`, and returns where it ends.
+    ///
+    /// The run ends at the first record compaction would drop or rewrite:
+    /// an overwritten or deleted value, a tombstone, a batch marker, a
+    /// `StalePut` in a key's history, a record written ahead of a key's
+    /// older history, or a reference whose blob is not already in the run.
+    /// Files in an older format have none.
+    fn packed_prefix(
+        &self,
+        file: &mut FileHandle,
+        records: &mut VecDeque<CompactRecord>,
+        new_index: &mut Index,
+    ) -> io::Result<u64> {
+        if self.format_version.load(Ordering::Acquire) != FORMAT_VERSION {
+            return Ok(FILE_HEADER_SIZE);
+        }
+        // Each key's records in the order compaction writes them: a key
+        // whose history sits after its live value in the log, as a
+        // `StalePut` or a copy of one does, cannot be kept where it is.
+        let mut key_order: HashMap<&[u8], VecDeque<u64>> = HashMap::new();
+        for (kind, key, log_index, _) in records.iter() {
+            if *kind != EntryKind::Blob {
+                key_order.entry(key).or_default().push_back(log_index.pos);
+            }
+        }
+        let mut by_pos: Vec<&CompactRecord> = records.iter().collect();
+        by_pos.sort_unstable_by_key(|(_, _, log_index, _)| log_index.pos);
 
-        file.write_all(&data)?;
+        file.seek(SeekFrom::Start(FILE_HEADER_SIZE))?;
+        let mut reader = BufReader::new(&mut **file);
+        let mut end = FILE_HEADER_SIZE;
+        let mut kept = HashSet::new();
+        for (kind, key, log_index, tstamp) in by_pos {
+            if log_index.pos != end + framing::prefix_len(FORMAT_VERSION, log_index.len) {
+                break;
+            }
+            if *kind != EntryKind::Blob {
+                let order = key_order
+                    .get_mut(&key[..])
+                    .expect("every record is ordered");
+                if order.front() != Some(&log_index.pos) {
+                    break;
+                }
+                order.pop_front();
+            }
+            let read = match framing::read_len(FORMAT_VERSION, &mut reader) {
+                Ok(Some((len, _))) if len == log_index.len => {
+                    format::read_body(&mut reader, FORMAT_VERSION, len)
+                }
+                Ok(_) => Ok(None),
+                Err(e) => Err(e),
+            };
+            let entry = match read {
+                Ok(Some(entry)) => entry,
+                Ok(None) => break,
+                Err(e) if e.kind() == io::ErrorKind::InvalidData => break,
+                Err(e) => return Err(e),
+            };
+            let in_place = match kind {
+                EntryKind::BlobRef => entry
+                    .value
+                    .as_ref()
+                    .is_some_and(|hash| new_index.blobs.contains_key(hash)),
+                _ => true,
+            };
+            if entry.kind != *kind || !in_place {
+                break;
+            }
+            // Indexed as `Compaction::copy_record` indexes a copy.
+            match kind {
+                EntryKind::BlobRef | EntryKind::ExpiringPut | EntryKind::Archived => {
+                    new_index.apply_entry(&entry, log_index.clone())
+                }
+                EntryKind::Put if new_index.hashes.is_some() || entry.origin.is_some() => {
+                    new_index.apply_entry(&entry, log_index.clone())
+                }
+                _ => new_index.apply(*kind, key.to_vec(), log_index.clone(), *tstamp),
+            }
+            kept.insert(log_index.pos);
+            end = log_index.pos + log_index.len;
+        }
+        records.retain(|(_, _, log_index, _)| !kept.contains(&log_index.pos));
+        Ok(end)
+    }
 
-        *self.file_size.lock().unwrap() += LEN_PREFIX_SIZE + entry_len;
-        self.index.write().unwrap().remove(key);
+    /// Copies every record appended since `compaction` took its snapshot.
+    /// Call with the file lock held.
+    fn carry_over(&self, file: &mut FileHandle, compaction: &mut Compaction) -> io::Result<()> {
+        let end = *self.file_size.lock().unwrap();
+        let mut pos = compaction.snapshot_end;
+        while pos < end {
+            file.seek(SeekFrom::Start(pos))?;
+            let (entry_len, prefix_len) =
+                framing::read_len(compaction.old_version, &mut **file)?
+                    .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+            let mut data = vec![0u8; entry_len as usize];
+            file.read_exact(&mut data)?;
+            pos += prefix_len + entry_len;
 
+            let entry = Self::decode_entry(compaction.old_version, &data)?;
+            // A reference written since the snapshot may point at a blob that
+            // had no references then, and so was not copied.
+            if entry.kind == EntryKind::BlobRef {
+                let hash = entry.value.clone().unwrap_or_default();
+                if !compaction.new_index.blobs.contains_key(&hash) {
+                    let blob = self.index_read().blobs.get(&hash).cloned();
+                    if let Some(blob) = blob {
+                        compaction
+                            .copy_record(file, (EntryKind::Blob, hash.into(), blob.index, 0))?;
+                    }
+                }
+            }
+            compaction.append(Self::encode_entry(FORMAT_VERSION, &entry)?, &entry)?;
+            compaction.carried_over += 1;
+        }
         Ok(())
     }
 
-    pub fn get(&self, key: &[u8]) -> io::Result<Option<Vec<u8>>> {
-        let index = self.index.read().unwrap();
+    /// Swaps the finished output of `compaction` in for the data file. Call
+    /// with the file lock held.
+    fn finish_compaction(
+        &self,
+        file: &mut FileHandle,
+        compaction: Compaction,
+    ) -> io::Result<CompactionReport> {
+        let Compaction {
+            tmp_path,
+            mut tmp_file,
+            new_index,
+            new_file_size,
+            compact_threshold,
+            carried_over,
+            old_version,
+            preserved_bytes,
+            ..
+        } = compaction;
+        let old_file_size = *self.file_size.lock().unwrap();
+        let old_allocated = self.allocated_size.load(Ordering::Acquire);
+        let staged_path = self.path.with_extension("tmp");
 
-        let log_index = match index.get(key) {
-            Some(idx) => idx.clone(),
-            None => return Ok(None),
+        // If compaction barely shrank the file, double the threshold, unless
+        // an operator chose it. The new value goes into the tmp file's header
+        // before the rename, so the header and the swap are one atomic step.
+        let source = self.threshold_source();
+        let (new_threshold, new_source) = match source {
+            ThresholdSource::Options | ThresholdSource::Operator => {
+                (self.compact_threshold.load(Ordering::Acquire), source)
+            }
+            _ if new_file_size * 100 > old_file_size * 75 => (
+                compact_threshold.saturating_mul(2),
+                ThresholdSource::AutoTuned,
+            ),
+            _ => (compact_threshold, source),
         };
-
-        let mut reader = {
-            let mut pool = self.reader_pool.lock().unwrap();
-            match pool.pop() {
-                Some(r) => r,
-                None => OpenOptions::new().read(true).open(&self.path)?,
+        // A fenced writer must not swap in its file; anyone else carries the
+        // current epoch over.
+        self.check_fence(true)?;
+        let epoch = self.read_epoch()?;
+        // An option's threshold is never persisted: the header keeps what
+        // it had, for loads without the option.
+        let (header_threshold, header_source) = match new_source {
+            ThresholdSource::Options => {
+                let old = self.with_header_file(|file| {
+                    let len = file.len()?;
+                    Self::read_header(file, len)
+                })?;
+                (
+                    old.compact_threshold,
+                    Self::persisted_threshold_source(&old),
+                )
             }
+            _ => (new_threshold, new_source),
         };
+        Self::write_header(
+            &mut *tmp_file,
+            &Header {
+                format_version: FORMAT_VERSION,
+                compact_threshold: header_threshold,
+                epoch,
+                // Audit mode never compacts, so the output is not audit-only.
+                flags: Self::threshold_flags(0, header_source)
+                    | key_transform_flags(key_transform_id(&self.options)),
+            },
+        )?;
 
-        reader.seek(SeekFrom::Start(log_index.pos))?;
+        tmp_file.flush()?;
+        drop(tmp_file);
 
-        let mut data = vec![0u8; log_index.len as usize];
-        reader.read_exact(&mut data)?;
+        if tmp_path != staged_path {
+            self.stage_compacted(&tmp_path, &staged_path, new_file_size)?;
+        }
 
-        {
-            let mut pool = self.reader_pool.lock().unwrap();
-            if pool.len() < 8 {
-                pool.push(reader);
+        let mut index = self.index_mut();
+        if cfg!(debug_assertions) {
+            index.check_compacted(&new_index, old_version == FORMAT_VERSION);
+        }
+        self.generation.fetch_add(1, Ordering::AcqRel);
+        let swapped = self
+            .storage
+            .rename(&staged_path, &self.path)
+            .and_then(|()| self.storage.open(&self.path, OpenMode::ReadWrite));
+        let new_file = match swapped {
+            Ok(new_file) => new_file,
+            Err(e) => {
+                drop(index);
+                self.generation.fetch_add(1, Ordering::AcqRel);
+                return Err(e);
             }
+        };
+        *file = new_file;
+        *self.header_file.lock().unwrap() = None;
+        let report = CompactionReport {
+            old_size: old_file_size,
+            new_size: new_file_size,
+            live_keys: new_index.live.len(),
+            carried_over,
+            preserved_bytes,
+            copied_bytes: new_file_size - FILE_HEADER_SIZE - preserved_bytes,
+        };
+        *index = new_index;
+        self.format_version.store(FORMAT_VERSION, Ordering::Release);
+        drop(index);
+        let generation = self.generation.fetch_add(1, Ordering::AcqRel) + 1;
+        Metrics::incr(&self.metrics.compactions);
+        self.compaction_deferred.store(false, Ordering::Release);
+        *self.file_size.lock().unwrap() = new_file_size;
+        self.compact_threshold
+            .store(new_threshold, Ordering::Release);
+        *self.threshold_source.lock().unwrap() = new_source;
+        self.allocated_size.store(new_file_size, Ordering::Release);
+        // The output is written at exactly the size of the log. It gets the
+        // old file's allocation back, so a steady workload does not
+        // allocate again straight away, but as `shrink_to_fit` leaves it:
+        // no more than one chunk past the end. Without the room the next
+        // append allocates it.
+        let keep = self.allocation_to_keep(new_file_size, old_allocated);
+        if keep > new_file_size && file.allocate(keep).is_ok() {
+            self.allocated_size.store(keep, Ordering::Release);
+        }
+        self.sync_state.mark_dirty();
+        self.reader_pool
+            .reset(self.storage.as_ref(), &self.path, generation);
+        if let Some(mirror) = &self.mirror {
+            // The compaction stands either way: a failed copy stops the
+            // mirror, which under `MirrorFailure::Fatal` fails the writes
+            // after it.
+            let _ = mirror.sync_from(self.storage.as_ref(), &self.path, new_file_size, true);
         }
 
-        drop(index);
+        Ok(report)
+    }
 
-        let entry: DataFileEntry = wincode::deserialize(&data)
-            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+    /// Where compaction writes its output: beside the data file, or in
+    /// `Options::compaction_dir` under a name unique to this data file.
+    /// Stepped compactions use their own extension, so a full compaction
+    /// running meanwhile cannot clobber their output.
+    fn compaction_tmp_path(&self, extension: &str) -> PathBuf {
+        let Some(dir) = &self.options.compaction_dir else {
+            return self.path.with_extension(extension);
+        };
+        let path_hash = Sha1::digest(self.path.as_os_str().as_encoded_bytes());
+        let suffix: String = path_hash[..8]
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        let name = self.path.file_name().unwrap_or_default().to_string_lossy();
+        dir.join(format!("{}.{}.{}", name, suffix, extension))
+    }
 
-        Ok(entry.value)
+    /// Fails with `StorageFull` if the filesystem holding `path` reports less
+    /// than `needed` bytes free.
+    fn check_space(&self, path: &Path, needed: u64) -> io::Result<()> {
+        let dir = path
+            .parent()
+            .filter(|p| !p.as_os_str().is_empty())
+            .unwrap_or(Path::new("."));
+        match self.storage.available_space(dir)? {
+            Some(free) if free < needed => Err(io::Error::new(
+                io::ErrorKind::StorageFull,
+                format!(
+                    "compaction needs about {} bytes in {} but only {} are free",
+                    needed,
+                    dir.display(),
+                    free
+                ),
+            )),
+            _ => Ok(()),
+        }
     }
 
-    pub fn compact(&self) -> io::Result<()> {
-        let mut file = self.file.lock().unwrap();
-        let old_file_size = *self.file_size.lock().unwrap();
-        let compact_threshold = *self.compact_threshold.lock().unwrap();
+    /// Moves compaction output from the scratch directory to `staged`, beside
+    /// the data file, so the final swap is a same-directory rename. Across
+    /// filesystems `rename` fails, so the output is copied and fsynced there
+    /// instead.
+    fn stage_compacted(&self, tmp: &Path, staged: &Path, len: u64) -> io::Result<()> {
+        match self.storage.rename(tmp, staged) {
+            Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+                self.check_space(staged, len)?;
+                self.storage.clone_file(tmp, staged, len)?;
+                self.storage.remove_file(tmp)
+            }
+            result => result,
+        }
+    }
+}
 
-        let tmp_path = self.path.with_extension("tmp");
+/// Rejects keys the engine does not store. Empty keys were never reliably
+/// supported by tombstones, prefix scans, or the tooling, so they are refused
+/// at every write entry point; records already on disk still load.
+/// Fills `buf`, returning `false` if the reader ended first.
+fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<bool> {
+    match reader.read_exact(buf) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    }
+}
 
-        let mut tmp_file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .truncate(true)
-            .open(&tmp_path)?;
-        Self::write_header(&mut tmp_file, compact_threshold)?;
-        tmp_file.seek(SeekFrom::Start(FILE_HEADER_SIZE))?;
+/// How far `Options::ttl_jitter` moves the expiry of `key` written with a
+/// TTL of `ttl` ms: up to `jitter` of the TTL either way, picked by a SHA-1
+/// of the key, so the same write always lands on the same expiry offset.
+fn ttl_jitter_offset(key: &[u8], ttl: i64, jitter: f64) -> i64 {
+    let hash = Sha1::digest(key);
+    let bits = u64::from_le_bytes(hash[..8].try_into().unwrap());
+    let unit = bits as f64 / u64::MAX as f64 * 2.0 - 1.0;
+    (ttl as f64 * jitter * unit).round() as i64
+}
 
-        let entries: Vec<(Vec<u8>, LogIndex)> = self
-            .index
-            .read()
-            .unwrap()
-            .iter()
-            .map(|(k, v)| (k.clone(), v.clone()))
-            .collect();
+/// `key`, which starts with `src_prefix`, moved under `dst_prefix`.
+fn renamed(key: &[u8], src_prefix: &[u8], dst_prefix: &[u8]) -> Vec<u8> {
+    [dst_prefix, &key[src_prefix.len()..]].concat()
+}
 
-        let mut new_index: HashMap<Vec<u8>, LogIndex> = HashMap::new();
-        let mut new_file_size: u64 = FILE_HEADER_SIZE;
+fn check_key(key: &[u8]) -> io::Result<()> {
+    if key.is_empty() {
+        return Err(EngineError::EmptyKey.into());
+    }
+    Ok(())
+}
 
-        for (key, log_index) in entries {
-            file.seek(SeekFrom::Start(log_index.pos))?;
-            let mut data = vec![0u8; log_index.len as usize];
-            file.read_exact(&mut data)?;
+/// The id of `options.key_transform`, 0 for none.
+fn key_transform_id(options: &Options) -> u32 {
+    options.key_transform.as_ref().map_or(0, KeyTransform::id)
+}
 
-            let entry_len = data.len() as u64;
-            tmp_file.write_all(&entry_len.to_le_bytes())?;
-            let new_pos = tmp_file.stream_position()?;
-            tmp_file.write_all(&data)?;
+/// Header flags recording the key transform `id`.
+fn key_transform_flags(id: u32) -> u64 {
+    (id as u64) << HEADER_KEY_TRANSFORM_SHIFT
+}
 
-            new_file_size += LEN_PREFIX_SIZE + entry_len;
-            new_index.insert(
-                key,
-                LogIndex {
-                    pos: new_pos,
-                    len: entry_len,
-                },
-            );
-        }
+fn check_raw_prefix(prefix: &[u8]) -> io::Result<()> {
+    if bucket::is_bucket_key(prefix) {
+        return Err(EngineError::ReservedKey.into());
+    }
+    Ok(())
+}
 
-        tmp_file.flush()?;
-        drop(tmp_file);
+/// `check_key`, plus keeping raw callers out of the bucket namespace.
+fn check_raw_key(key: &[u8]) -> io::Result<()> {
+    check_key(key)?;
+    if bucket::is_bucket_key(key) {
+        return Err(EngineError::ReservedKey.into());
+    }
+    Ok(())
+}
 
-        self.reader_pool.lock().unwrap().clear();
+/// Whether records of `kind` write a key, and so take `Options::writer_id`
+/// as their origin. Blobs are shared between keys, and an archive stub
+/// keeps the origin of the record it replaces.
+fn takes_origin(kind: EntryKind) -> bool {
+    matches!(
+        kind,
+        EntryKind::Put
+            | EntryKind::Tombstone
+            | EntryKind::SoftDelete
+            | EntryKind::ExpiringPut
+            | EntryKind::BlobRef
+            | EntryKind::StalePut
+    )
+}
 
-        let mut index = self.index.write().unwrap();
+/// The value length `entry` makes current for its key, for quota checks;
+/// `None` if it makes nothing current. A `BlobRef` carries only a hash, so
+/// `set_dedup` checks its value before writing the blob.
+fn put_value_len(entry: &DataFileEntry) -> Option<u64> {
+    let len = entry.value.as_ref()?.len() as u64;
+    match entry.kind {
+        EntryKind::Put => Some(len),
+        EntryKind::ExpiringPut => Some(len.saturating_sub(EXPIRY_SIZE as u64)),
+        _ => None,
+    }
+}
 
-        std::fs::rename(&tmp_path, &self.path)?;
-        *file = OpenOptions::new().read(true).write(true).open(&self.path)?;
-        *index = new_index;
-        *self.file_size.lock().unwrap() = new_file_size;
+impl Compaction {
+    /// Copies `record` from `source`, the file the snapshot was taken of.
+    fn copy_record(&mut self, source: &mut FileHandle, record: CompactRecord) -> io::Result<()> {
+        let (kind, key, log_index, tstamp) = record;
+        source.seek(SeekFrom::Start(log_index.pos))?;
+        let mut data = vec![0u8; log_index.len as usize];
+        source.read_exact(&mut data)?;
+        self.remaining_bytes = self
+            .remaining_bytes
+            .saturating_sub(framing::prefix_len(FORMAT_VERSION, log_index.len) + log_index.len);
 
-        let mut pool = self.reader_pool.lock().unwrap();
-        for _ in 0..4 {
-            if let Ok(r) = OpenOptions::new().read(true).open(&self.path) {
-                pool.push(r);
-            }
+        let mut entry = Engine::decode_entry(self.old_version, &data)?;
+        // History is written ahead of the live record, where a `StalePut`
+        // would replay with no live key to attach to.
+        let stale = entry.kind == EntryKind::StalePut;
+        if stale {
+            entry.kind = EntryKind::Put;
         }
-
-        if new_file_size * 100 > old_file_size * 75 {
-            let mut threshold = self.compact_threshold.lock().unwrap();
-            *threshold = threshold.saturating_mul(2);
-            let updated_threshold = *threshold;
-            drop(threshold);
-            self.persist_threshold(updated_threshold)?;
+        if stale || self.old_version != FORMAT_VERSION {
+            data = Engine::encode_entry(FORMAT_VERSION, &entry)?;
+        }
+        // `kind` is what the record is for: a history record is copied as
+        // a `Put`, whatever kind it was written as.
+        let needs_entry = match kind {
+            EntryKind::BlobRef | EntryKind::ExpiringPut | EntryKind::Archived => true,
+            EntryKind::Put => self.new_index.hashes.is_some() || entry.origin.is_some(),
+            _ => false,
+        };
+        if needs_entry {
+            return self.append(data, &entry);
         }
+        let log_index = self.write(&data)?;
+        self.new_index.apply(kind, key.to_vec(), log_index, tstamp);
+        Ok(())
+    }
 
+    /// Writes the encoded `data` of `entry` and applies it to the new index.
+    fn append(&mut self, data: Vec<u8>, entry: &DataFileEntry) -> io::Result<()> {
+        let log_index = self.write(&data)?;
+        self.new_index.apply_entry(entry, log_index);
         Ok(())
     }
+
+    fn write(&mut self, data: &[u8]) -> io::Result<LogIndex> {
+        let entry_len = data.len() as u64;
+        let prefix_len = format::write_framed(&mut self.tmp_file, FORMAT_VERSION, data)?;
+        let pos = self.new_file_size + prefix_len;
+        self.new_file_size = pos + entry_len;
+        Ok(LogIndex {
+            pos,
+            len: entry_len,
+        })
+    }
+
+    fn progress(&self) -> CompactProgress {
+        CompactProgress::InProgress {
+            copied: self.new_file_size - FILE_HEADER_SIZE,
+            remaining_estimate: self.remaining_bytes,
+        }
+    }
+}
+
+/// Whether a failed write ran out of space: `ENOSPC`, a quota, or a write
+/// that stopped putting bytes down.
+fn is_disk_full(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::StorageFull | io::ErrorKind::QuotaExceeded | io::ErrorKind::WriteZero
+    )
+}
+
+pub(crate) fn now_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or(0)
 }
@@ -0,0 +1,221 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+/// Engine-specific failures. They reach callers wrapped in an `io::Error`
+/// like every other failure; use [`EngineError::from_io`] to tell them apart.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum EngineError {
+    /// Keys must be at least one byte long.
+    EmptyKey,
+    /// Corruption was detected and `Options::fail_closed` is set, so writes
+    /// are refused until `Engine::verify` passes or the corruption is
+    /// acknowledged. Carries what was detected.
+    StoreCorrupted(String),
+    /// Raw keys starting with `BUCKET_KEY_PREFIX` belong to the bucket
+    /// namespace and the engine's own keys, and can only be reached through
+    /// [`crate::Bucket`].
+    ReservedKey,
+    /// Bucket names must be 1 to 255 bytes and must not contain
+    /// `BUCKET_KEY_PREFIX`. Carries why the name was refused.
+    InvalidBucketName(String),
+    /// The write would add a key past `Options::max_index_entries` or
+    /// `Options::max_index_bytes`. Carries which limit was hit.
+    IndexFull(String),
+    /// An explicit-timestamp write was older than the stored value under
+    /// `ConflictPolicy::RejectOlder`. Both timestamps are ms since the epoch.
+    StaleWrite { stored: i64, attempted: i64 },
+    /// Another writer claimed the store with a newer epoch under
+    /// `Options::fencing`, so this engine refuses all further writes.
+    Fenced { epoch: u64, current: u64 },
+    /// `Engine::set_read_only(true)` put the store into maintenance mode.
+    ReadOnlyMode,
+    /// `Options::validate` or a bucket validator refused a value. Carries
+    /// the validator's reason.
+    ValidationFailed(String),
+    /// A `RecordRef` passed to `Engine::get_at_index` points into a file
+    /// that has since been replaced by compaction or a reload.
+    RecordMoved { generation: u64, current: u64 },
+    /// Compaction was requested under `Options::audit_mode`, which never
+    /// erases a record.
+    AuditMode,
+    /// `Engine::rename_prefix` under `RenameConflict::Fail` found this many
+    /// destination keys that already exist.
+    RenameConflicts(u64),
+    /// The filesystem ran out of space during an append. With
+    /// `rolled_back`, whatever part of the record was written has been cut
+    /// off again and the log is as it was. Without it, the partial record
+    /// is still there, and writes fail like this until `Engine::reload`
+    /// truncates it.
+    DiskFull { rolled_back: bool },
+    /// A put's key was longer than `Options::max_key_size`. Carries the
+    /// key's length and the limit, in bytes.
+    KeyTooLarge { len: usize, max: usize },
+    /// `Engine::close` has been called, by this holder of the engine or
+    /// another.
+    Closed,
+    /// The write would take a bucket past its `Quota`. Carries the bucket
+    /// and which limit was hit.
+    QuotaExceeded(String),
+    /// A write to `Options::mirror_path` failed under
+    /// `MirrorFailure::Fatal`, so the write was not applied, and no write
+    /// is until `Engine::reload` restores the mirror. Carries the failure.
+    MirrorFailed(String),
+    /// The data file holds stubs of values `Engine::archive` moved out,
+    /// but their archive file is not there, or `Options::archive_path` is
+    /// not set. Carries which.
+    ArchiveMissing(String),
+    /// A key in the log, a dump, or a log being ingested starts with
+    /// `BUCKET_KEY_PREFIX` but is neither a bucketed key nor one of the
+    /// engine's own: a raw key written before the prefix was reserved.
+    /// Carries the key.
+    StrayReservedKey(Vec<u8>),
+    /// `Engine::close` finished, but these background threads had not
+    /// stopped within `Options::shutdown_timeout` and were left running.
+    /// Carries their names.
+    ShutdownTimedOut(String),
+    /// The data file was created with a different `Options::key_transform`
+    /// than the engine was loaded with, by id, 0 meaning none. Loading it
+    /// anyway would make lookups silently miss.
+    KeyTransformMismatch { stored: u32, configured: u32 },
+}
+
+impl EngineError {
+    /// Returns the `EngineError` carried by `err`, if any.
+    pub fn from_io(err: &io::Error) -> Option<&EngineError> {
+        err.get_ref()?.downcast_ref::<EngineError>()
+    }
+
+    fn kind(&self) -> io::ErrorKind {
+        match self {
+            EngineError::EmptyKey => io::ErrorKind::InvalidInput,
+            EngineError::StoreCorrupted(_) => io::ErrorKind::InvalidData,
+            EngineError::ReservedKey => io::ErrorKind::InvalidInput,
+            EngineError::InvalidBucketName(_) => io::ErrorKind::InvalidInput,
+            EngineError::IndexFull(_) => io::ErrorKind::OutOfMemory,
+            EngineError::StaleWrite { .. } => io::ErrorKind::AlreadyExists,
+            EngineError::Fenced { .. } => io::ErrorKind::PermissionDenied,
+            EngineError::ReadOnlyMode => io::ErrorKind::ReadOnlyFilesystem,
+            EngineError::ValidationFailed(_) => io::ErrorKind::InvalidInput,
+            EngineError::RecordMoved { .. } => io::ErrorKind::NotFound,
+            EngineError::AuditMode => io::ErrorKind::Unsupported,
+            EngineError::RenameConflicts(_) => io::ErrorKind::AlreadyExists,
+            EngineError::DiskFull { .. } => io::ErrorKind::StorageFull,
+            EngineError::KeyTooLarge { .. } => io::ErrorKind::InvalidInput,
+            EngineError::Closed => io::ErrorKind::NotConnected,
+            EngineError::QuotaExceeded(_) => io::ErrorKind::QuotaExceeded,
+            EngineError::MirrorFailed(_) => io::ErrorKind::Other,
+            EngineError::ArchiveMissing(_) => io::ErrorKind::NotFound,
+            EngineError::StrayReservedKey(_) => io::ErrorKind::InvalidData,
+            EngineError::ShutdownTimedOut(_) => io::ErrorKind::TimedOut,
+            EngineError::KeyTransformMismatch { .. } => io::ErrorKind::InvalidInput,
+        }
+    }
+}
+
+impl fmt::Display for EngineError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EngineError::EmptyKey => f.write_str("keys must not be empty"),
+            EngineError::StoreCorrupted(details) => {
+                write!(f, "store is corrupted, writes refused: {}", details)
+            }
+            EngineError::ReservedKey => f.write_str(
+                "keys starting with 0xFF are reserved for buckets and the engine's own keys",
+            ),
+            EngineError::InvalidBucketName(reason) => {
+                write!(f, "invalid bucket name: {}", reason)
+            }
+            EngineError::IndexFull(limit) => write!(f, "index is full: {}", limit),
+            EngineError::StaleWrite { stored, attempted } => write!(
+                f,
+                "write at {} is older than the stored value at {}",
+                attempted, stored
+            ),
+            EngineError::Fenced { epoch, current } => write!(
+                f,
+                "fenced: this writer holds epoch {} but the store was claimed at epoch {}",
+                epoch, current
+            ),
+            EngineError::ReadOnlyMode => {
+                f.write_str("store is read only for maintenance, writes refused")
+            }
+            EngineError::ValidationFailed(reason) => write!(f, "validation failed: {}", reason),
+            EngineError::RecordMoved {
+                generation,
+                current,
+            } => write!(
+                f,
+                "record was written in generation {} but the file is now at generation {}",
+                generation, current
+            ),
+            EngineError::AuditMode => f.write_str("compaction is disabled in audit mode"),
+            EngineError::RenameConflicts(conflicts) => write!(
+                f,
+                "rename refused: {} destination keys already exist",
+                conflicts
+            ),
+            EngineError::DiskFull { rolled_back: true } => {
+                f.write_str("disk full: the write was not applied and the log is unchanged")
+            }
+            EngineError::DiskFull { rolled_back: false } => f.write_str(
+                "disk full: a partial record could not be removed; writes refused until reload",
+            ),
+            EngineError::KeyTooLarge { len, max } => {
+                write!(f, "key is {} bytes, over the {}-byte limit", len, max)
+            }
+            EngineError::Closed => f.write_str("engine is closed"),
+            EngineError::QuotaExceeded(limit) => write!(f, "quota exceeded: {}", limit),
+            EngineError::MirrorFailed(reason) => {
+                write!(f, "mirror failed, writes refused: {}", reason)
+            }
+            EngineError::ArchiveMissing(reason) => {
+                write!(f, "archived values cannot be read: {}", reason)
+            }
+            EngineError::StrayReservedKey(key) => write!(
+                f,
+                "key {} starts with the reserved byte 0xFF but is neither bucketed nor internal; \
+                 it was written before that prefix was reserved",
+                key.escape_ascii()
+            ),
+            EngineError::ShutdownTimedOut(workers) => write!(
+                f,
+                "background threads still running after the shutdown timeout: {}",
+                workers
+            ),
+            EngineError::KeyTransformMismatch { stored, configured } => {
+                let name = |id: &u32| match id {
+                    0 => "no key transform".to_string(),
+                    id => format!("key transform {}", id),
+                };
+                write!(
+                    f,
+                    "store was created with {} but is being loaded with {}",
+                    name(stored),
+                    name(configured)
+                )
+            }
+        }
+    }
+}
+
+impl Error for EngineError {}
+
+impl From<EngineError> for io::Error {
+    fn from(err: EngineError) -> Self {
+        io::Error::new(err.kind(), err)
+    }
+}
+
+/// A copy of `err` for handing one failure to several callers, keeping the
+/// kind, any OS error code, and any `EngineError`.
+pub(crate) fn duplicate(err: &io::Error) -> io::Error {
+    if let Some(code) = err.raw_os_error() {
+        return io::Error::from_raw_os_error(code);
+    }
+    match EngineError::from_io(err) {
+        Some(engine_err) => engine_err.clone().into(),
+        None => io::Error::new(err.kind(), err.to_string()),
+    }
+}
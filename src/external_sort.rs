@@ -0,0 +1,199 @@
+//! External sort behind `Engine::export_sorted_keys` and `Engine::dump`.
+//!
+//! Keys are gathered into chunks of at most `chunk_bytes`, each chunk is
+//! sorted and spilled to a temporary run file, and the runs are k-way merged,
+//! at most [`MERGE_FAN_IN`] at a time, so neither memory nor open files grow
+//! with the key count. Run files and the output use the same framing:
+//!
+//! ```text
+//! key*: [8 bytes: key length u64 LE][key bytes]
+//! ```
+//!
+//! Run files are removed when the sort finishes or fails.
+
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::ffi::OsString;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::stats::ExportProgress;
+use crate::storage::{OpenMode, Storage, StorageFile};
+
+/// Most runs merged in one pass.
+pub(crate) const MERGE_FAN_IN: usize = 64;
+
+/// Keys written between progress reports while merging.
+const PROGRESS_EVERY: u64 = 1 << 16;
+
+/// Memory charged per key on top of its bytes.
+pub(crate) const KEY_OVERHEAD: usize = std::mem::size_of::<Vec<u8>>();
+
+pub(crate) struct ExternalSort<'a> {
+    storage: &'a dyn Storage,
+    /// Run files are named `<base>.<n>`.
+    base: PathBuf,
+    runs: Vec<PathBuf>,
+    next_run: usize,
+    progress: ExportProgress,
+}
+
+impl<'a> ExternalSort<'a> {
+    /// `base` is extended with a per-sort suffix, so concurrent sorts beside
+    /// the same data file do not collide.
+    pub(crate) fn new(storage: &'a dyn Storage, base: PathBuf) -> Self {
+        static NEXT_SORT: AtomicU64 = AtomicU64::new(0);
+        let mut name = OsString::from(base);
+        name.push(format!(
+            ".{}-{}",
+            std::process::id(),
+            NEXT_SORT.fetch_add(1, Ordering::Relaxed)
+        ));
+        ExternalSort {
+            storage,
+            base: PathBuf::from(name),
+            runs: Vec::new(),
+            next_run: 0,
+            progress: ExportProgress::default(),
+        }
+    }
+
+    /// Sorts `chunk` and writes it out as a run, leaving it empty.
+    pub(crate) fn spill(
+        &mut self,
+        chunk: &mut Vec<Vec<u8>>,
+        on_progress: &mut dyn FnMut(&ExportProgress),
+    ) -> io::Result<()> {
+        if chunk.is_empty() {
+            return Ok(());
+        }
+        chunk.sort_unstable();
+        let mut run = self.create_run()?;
+        for key in chunk.iter() {
+            write_key(&mut run, key)?;
+        }
+        run.flush()?;
+
+        self.progress.keys_spilled += chunk.len() as u64;
+        self.progress.runs += 1;
+        chunk.clear();
+        on_progress(&self.progress);
+        Ok(())
+    }
+
+    /// Whether any run has been spilled yet.
+    pub(crate) fn has_runs(&self) -> bool {
+        !self.runs.is_empty()
+    }
+
+    /// Merges every run into `writer` and returns the number of keys
+    /// written.
+    pub(crate) fn finish(
+        self,
+        writer: &mut dyn Write,
+        on_progress: &mut dyn FnMut(&ExportProgress),
+    ) -> io::Result<u64> {
+        let written = self.finish_with(&mut |key| write_key(writer, key), on_progress)?;
+        writer.flush()?;
+        Ok(written)
+    }
+
+    /// Merges every run, calling `on_key` with each key in order, and
+    /// returns the number of keys merged.
+    pub(crate) fn finish_with(
+        mut self,
+        on_key: &mut dyn FnMut(&[u8]) -> io::Result<()>,
+        on_progress: &mut dyn FnMut(&ExportProgress),
+    ) -> io::Result<u64> {
+        // Inputs stay listed in `runs` until removed, so a failed pass still
+        // cleans them up.
+        while self.runs.len() > MERGE_FAN_IN {
+            let inputs = self.runs[..MERGE_FAN_IN].to_vec();
+            let mut output = self.create_run()?;
+            self.merge(&inputs, &mut |key| write_key(&mut output, key), &mut |_| {})?;
+            output.flush()?;
+            drop(output);
+            for path in self.runs.drain(..MERGE_FAN_IN) {
+                self.storage.remove_file(&path)?;
+            }
+        }
+
+        let inputs = self.runs.clone();
+        let mut progress = self.progress;
+        self.merge(&inputs, on_key, &mut |written| {
+            progress.keys_written = written;
+            on_progress(&progress);
+        })
+    }
+
+    /// Merges `inputs`, passing each key to `on_key`, calling `on_written`
+    /// with the running count every [`PROGRESS_EVERY`] keys and at the end.
+    fn merge(
+        &self,
+        inputs: &[PathBuf],
+        on_key: &mut dyn FnMut(&[u8]) -> io::Result<()>,
+        on_written: &mut dyn FnMut(u64),
+    ) -> io::Result<u64> {
+        let mut readers = Vec::with_capacity(inputs.len());
+        let mut heap = BinaryHeap::with_capacity(inputs.len());
+        for (i, path) in inputs.iter().enumerate() {
+            let mut reader = BufReader::new(self.storage.open(path, OpenMode::Read)?);
+            if let Some(key) = read_key(&mut reader)? {
+                heap.push(Reverse((key, i)));
+            }
+            readers.push(reader);
+        }
+
+        let mut written = 0;
+        while let Some(Reverse((key, i))) = heap.pop() {
+            on_key(&key)?;
+            written += 1;
+            if written % PROGRESS_EVERY == 0 {
+                on_written(written);
+            }
+            if let Some(next) = read_key(&mut readers[i])? {
+                heap.push(Reverse((next, i)));
+            }
+        }
+        on_written(written);
+        Ok(written)
+    }
+
+    fn create_run(&mut self) -> io::Result<BufWriter<Box<dyn StorageFile>>> {
+        let mut name = OsString::from(&self.base);
+        name.push(format!(".{}", self.next_run));
+        self.next_run += 1;
+        let path = PathBuf::from(name);
+        // Tracked before creation so a failure part way still cleans up.
+        self.runs.push(path.clone());
+        Ok(BufWriter::new(
+            self.storage.open(&path, OpenMode::Truncate)?,
+        ))
+    }
+}
+
+impl Drop for ExternalSort<'_> {
+    fn drop(&mut self) {
+        for path in &self.runs {
+            let _ = self.storage.remove_file(path);
+        }
+    }
+}
+
+pub(crate) fn write_key(writer: &mut (impl Write + ?Sized), key: &[u8]) -> io::Result<()> {
+    writer.write_all(&(key.len() as u64).to_le_bytes())?;
+    writer.write_all(key)
+}
+
+fn read_key(reader: &mut impl Read) -> io::Result<Option<Vec<u8>>> {
+    let mut len = [0u8; 8];
+    match reader.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let mut key = vec![0u8; u64::from_le_bytes(len) as usize];
+    reader.read_exact(&mut key)?;
+    Ok(Some(key))
+}
@@ -0,0 +1,173 @@
+//! Reading and writing data files without an `Engine`.
+//!
+//! Tools such as backup verifiers can parse and produce data files through
+//! these functions instead of copying the constants and the record layout.
+//! The engine reads and writes its own files through the same functions:
+//! `Engine::load` and `reload` replay records with them, and appends and
+//! compaction frame records with them. A file they accept is a file the
+//! engine accepts, and the other way round.
+//!
+//! A data file is a [`Header`] followed by records, each a length prefix and
+//! the encoded [`Record`]. The header's magic gives the format version,
+//! which the record functions take, since both the prefix and the encoding
+//! changed between versions.
+
+use std::io::{self, Read, Write};
+
+use crate::codec;
+pub use crate::constants::{FILE_HEADER_MAGIC, FILE_HEADER_SIZE, FORMAT_VERSION, LEN_PREFIX_SIZE};
+use crate::format_info::{self, VersionSpec};
+use crate::framing;
+use crate::types::{DataFileEntry, LogIndex};
+
+/// Record bytes `read_body` reserves up front; longer records grow the
+/// buffer as they are read.
+const MAX_PREALLOC: u64 = 1 << 20;
+
+/// One record of the log.
+pub type Record = DataFileEntry;
+
+/// The fields of a data file header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    pub format_version: u8,
+    pub compact_threshold: u64,
+    /// Always 0 in formats without a writer epoch.
+    pub epoch: u64,
+    /// `HEADER_FLAG_*` bits; always 0 in formats without flags.
+    pub flags: u64,
+}
+
+impl Header {
+    /// Bytes the header takes, and so where the first record starts.
+    pub fn size(&self) -> u64 {
+        format_info::by_version(self.format_version).header_size
+    }
+}
+
+/// Reads a header from the start of a data file. Fails with `InvalidData`
+/// for a magic no supported version uses.
+pub fn read_header(r: &mut (impl Read + ?Sized)) -> io::Result<Header> {
+    let mut magic = [0u8; FILE_HEADER_MAGIC.len()];
+    r.read_exact(&mut magic)?;
+    let spec = format_info::by_magic(magic).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            "unsupported format (missing KVS header)",
+        )
+    })?;
+
+    let mut buf = [0u8; 8];
+    let mut read_u64 = |present: bool| -> io::Result<u64> {
+        if !present {
+            return Ok(0);
+        }
+        r.read_exact(&mut buf)?;
+        Ok(u64::from_le_bytes(buf))
+    };
+    Ok(Header {
+        format_version: spec.version,
+        compact_threshold: read_u64(true)?,
+        epoch: read_u64(spec.writer_epoch())?,
+        flags: read_u64(spec.header_flags())?,
+    })
+}
+
+/// Writes `header` in the layout of its version. Fields the version has no
+/// room for are left out.
+pub fn write_header(w: &mut (impl Write + ?Sized), header: &Header) -> io::Result<()> {
+    let spec = spec(header.format_version)?;
+    w.write_all(&spec.magic)?;
+    w.write_all(&header.compact_threshold.to_le_bytes())?;
+    if spec.writer_epoch() {
+        w.write_all(&header.epoch.to_le_bytes())?;
+    }
+    if spec.header_flags() {
+        w.write_all(&header.flags.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+/// Reads the next record of a `format_version` log. Returns `None` where
+/// the log ends: at the end of the reader, at a record cut short by a
+/// crash, or at a zero length prefix, which marks pre-allocated space.
+/// Fails with `InvalidData` for a record that does not decode.
+pub fn read_record(r: &mut (impl Read + ?Sized), format_version: u8) -> io::Result<Option<Record>> {
+    spec(format_version)?;
+    match framing::read_len(format_version, r)? {
+        Some((0, _)) | None => Ok(None),
+        Some((len, _)) => read_body(r, format_version, len),
+    }
+}
+
+/// Writes `record` with its length prefix, as the engine appends it to a
+/// `format_version` log. Returns the bytes written.
+pub fn write_record(
+    w: &mut (impl Write + ?Sized),
+    format_version: u8,
+    record: &Record,
+) -> io::Result<u64> {
+    spec(format_version)?;
+    let mut buf = Vec::new();
+    encode_record(format_version, record, &mut buf)?;
+    w.write_all(&buf)?;
+    Ok(buf.len() as u64)
+}
+
+/// Appends `record` with its length prefix to `buf`, returning where the
+/// encoded record sits in `buf`, after the prefix.
+pub(crate) fn encode_record(
+    format_version: u8,
+    record: &Record,
+    buf: &mut Vec<u8>,
+) -> io::Result<LogIndex> {
+    let data = codec::for_version(format_version).encode(record)?;
+    framing::write_len(format_version, data.len() as u64, buf);
+    let pos = buf.len() as u64;
+    buf.extend_from_slice(&data);
+    Ok(LogIndex {
+        pos,
+        len: data.len() as u64,
+    })
+}
+
+/// Writes already encoded record bytes with their length prefix, returning
+/// the prefix's length.
+pub(crate) fn write_framed(
+    w: &mut (impl Write + ?Sized),
+    format_version: u8,
+    data: &[u8],
+) -> io::Result<u64> {
+    let prefix = framing::encode_len(format_version, data.len() as u64);
+    w.write_all(&prefix)?;
+    w.write_all(data)?;
+    Ok(prefix.len() as u64)
+}
+
+/// Reads and decodes the `len` record bytes after a length prefix. Returns
+/// `None` if the reader ends first. Memory grows with the bytes actually
+/// read, so a damaged prefix claiming a huge record cannot exhaust it.
+pub(crate) fn read_body(
+    r: &mut (impl Read + ?Sized),
+    format_version: u8,
+    len: u64,
+) -> io::Result<Option<Record>> {
+    let mut data = Vec::with_capacity(len.min(MAX_PREALLOC) as usize);
+    (&mut *r).take(len).read_to_end(&mut data)?;
+    if (data.len() as u64) < len {
+        return Ok(None);
+    }
+    codec::for_version(format_version).decode(&data).map(Some)
+}
+
+fn spec(format_version: u8) -> io::Result<&'static VersionSpec> {
+    format_info::VERSIONS
+        .iter()
+        .find(|spec| spec.version == format_version)
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("unsupported format version {}", format_version),
+            )
+        })
+}
@@ -0,0 +1,441 @@
+//! Self-description of the data file format, behind
+//! `Engine::describe_format` and `kv inspect-format`.
+//!
+//! [`VERSIONS`] is the table the engine itself uses to map header magics to
+//! format versions, and the layouts below are built from the same constants
+//! the reader and writer use, so a description cannot drift from the code.
+
+use std::fmt;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+
+use crate::constants::{
+    EPOCH_HEADER_SIZE, FILE_HEADER_MAGIC, FILE_HEADER_MAGIC_V1, FILE_HEADER_MAGIC_V2,
+    FILE_HEADER_MAGIC_V3, FILE_HEADER_MAGIC_V4, FILE_HEADER_MAGIC_V5, FILE_HEADER_MAGIC_V6,
+    FILE_HEADER_SIZE, FORMAT_VERSION, HEADER_FLAG_AUDIT_ONLY, HEADER_KEY_TRANSFORM_SHIFT,
+    LEGACY_HEADER_SIZE, LEN_PREFIX_SIZE,
+};
+use crate::framing;
+use crate::storage::{OpenMode, Storage};
+use crate::types::EntryKind;
+
+/// One supported on-disk format version.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VersionSpec {
+    pub version: u8,
+    pub magic: [u8; 4],
+    /// Whether records end with an `EntryKind` tag.
+    pub entry_kinds: bool,
+    /// Whether records may carry an origin.
+    pub origins: bool,
+    /// Bytes before the first record.
+    pub header_size: u64,
+    pub codec: Codec,
+}
+
+impl VersionSpec {
+    /// Whether the header ends with a writer epoch (u64 LE).
+    pub fn writer_epoch(&self) -> bool {
+        self.header_size > LEGACY_HEADER_SIZE
+    }
+
+    /// Whether the header ends with a flags word (u64 LE) after the epoch.
+    pub fn header_flags(&self) -> bool {
+        self.header_size > EPOCH_HEADER_SIZE
+    }
+}
+
+/// Every format version this build reads, oldest first.
+pub const VERSIONS: [VersionSpec; 7] = [
+    VersionSpec {
+        version: 1,
+        magic: FILE_HEADER_MAGIC_V1,
+        entry_kinds: false,
+        origins: false,
+        header_size: LEGACY_HEADER_SIZE,
+        codec: Codec::Wincode,
+    },
+    VersionSpec {
+        version: 2,
+        magic: FILE_HEADER_MAGIC_V2,
+        entry_kinds: true,
+        origins: false,
+        header_size: LEGACY_HEADER_SIZE,
+        codec: Codec::Wincode,
+    },
+    VersionSpec {
+        version: 3,
+        magic: FILE_HEADER_MAGIC_V3,
+        entry_kinds: true,
+        origins: false,
+        header_size: LEGACY_HEADER_SIZE,
+        codec: Codec::Wincode,
+    },
+    VersionSpec {
+        version: 4,
+        magic: FILE_HEADER_MAGIC_V4,
+        entry_kinds: true,
+        origins: false,
+        header_size: EPOCH_HEADER_SIZE,
+        codec: Codec::Wincode,
+    },
+    VersionSpec {
+        version: 5,
+        magic: FILE_HEADER_MAGIC_V5,
+        entry_kinds: true,
+        origins: false,
+        header_size: EPOCH_HEADER_SIZE,
+        codec: Codec::ExplicitLe,
+    },
+    VersionSpec {
+        version: 6,
+        magic: FILE_HEADER_MAGIC_V6,
+        entry_kinds: true,
+        origins: false,
+        header_size: FILE_HEADER_SIZE,
+        codec: Codec::ExplicitLe,
+    },
+    VersionSpec {
+        version: FORMAT_VERSION,
+        magic: FILE_HEADER_MAGIC,
+        entry_kinds: true,
+        origins: true,
+        header_size: FILE_HEADER_SIZE,
+        codec: Codec::ExplicitLe,
+    },
+];
+
+pub(crate) fn by_magic(magic: [u8; 4]) -> Option<&'static VersionSpec> {
+    VERSIONS.iter().find(|spec| spec.magic == magic)
+}
+
+/// The spec for `version`; unknown versions get the current one.
+pub(crate) fn by_version(version: u8) -> &'static VersionSpec {
+    VERSIONS
+        .iter()
+        .find(|spec| spec.version == version)
+        .unwrap_or(&VERSIONS[VERSIONS.len() - 1])
+}
+
+/// What a file's first bytes identify it as.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DetectedFormat {
+    /// A version this build reads.
+    Supported { version: u8, current: bool },
+    /// A `KVS<n>` header from a build newer than this one.
+    Newer { version: u8 },
+    /// No header, but the first 8 bytes read as a plausible record length:
+    /// most likely a log from before headers were added, which this build
+    /// cannot open until `Engine::migrate_legacy` converts it.
+    Headerless,
+    /// Empty, or nothing recognizable.
+    Unrecognized,
+}
+
+/// How records are length-prefixed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Framing {
+    /// A fixed 8-byte little-endian u64.
+    FixedU64Le,
+    /// An unsigned LEB128 varint of 1 to 10 bytes.
+    Leb128Varint,
+}
+
+/// What encodes records. Both produce the layout in
+/// [`FormatDescription::record_fields`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    /// `wincode` serialization of the entry types.
+    Wincode,
+    /// Hand-written little-endian encoding, independent of any library.
+    ExplicitLe,
+}
+
+/// One field of the header or of a record.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FieldLayout {
+    pub name: &'static str,
+    /// Byte offset from the start of the header or record, or `None` once
+    /// earlier fields have variable length.
+    pub offset: Option<u64>,
+    pub encoding: &'static str,
+}
+
+/// Structured description of a data file; see [`describe`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FormatDescription {
+    pub file_size: u64,
+    /// Up to the first four bytes of the file.
+    pub magic: Vec<u8>,
+    pub detected: DetectedFormat,
+    /// Header fields, records, and framing are only described for
+    /// supported versions.
+    pub header: Vec<FieldLayout>,
+    pub compact_threshold: Option<u64>,
+    /// The epoch of the last writer to claim the file under
+    /// `Options::fencing`, for versions that store one.
+    pub writer_epoch: Option<u64>,
+    /// Whether every open of the file so far was in `Options::audit_mode`,
+    /// for versions with header flags.
+    pub audit_only: Option<bool>,
+    /// Id of the `Options::key_transform` the file was created with, 0 for
+    /// none, for versions with header flags.
+    pub key_transform: Option<u32>,
+    /// Offset of the first record.
+    pub records_start: Option<u64>,
+    pub framing: Option<Framing>,
+    pub codec: Option<Codec>,
+    pub record_fields: Vec<FieldLayout>,
+    pub entry_kinds: Vec<String>,
+    /// Per-record checksum algorithm. No version has one.
+    pub checksum: Option<&'static str>,
+    /// Compression applied to values. No version compresses.
+    pub compression: Vec<&'static str>,
+}
+
+/// Reads the header of the file at `path` and describes its format. Files
+/// this build cannot open are identified as far as possible rather than
+/// rejected.
+pub fn describe(storage: &dyn Storage, path: &Path) -> io::Result<FormatDescription> {
+    let mut file = storage.open(path, OpenMode::Read)?;
+    let file_size = file.len()?;
+    let mut head = Vec::with_capacity(FILE_HEADER_SIZE as usize);
+    file.seek(SeekFrom::Start(0))?;
+    (&mut file).take(FILE_HEADER_SIZE).read_to_end(&mut head)?;
+
+    let magic = head[..head.len().min(4)].to_vec();
+    let spec = match <[u8; 4]>::try_from(magic.as_slice()) {
+        Ok(magic) => by_magic(magic).filter(|spec| head.len() as u64 >= spec.header_size),
+        _ => None,
+    };
+    let mut description = FormatDescription {
+        file_size,
+        magic,
+        detected: detect(&head, file_size, spec),
+        header: Vec::new(),
+        compact_threshold: None,
+        writer_epoch: None,
+        audit_only: None,
+        key_transform: None,
+        records_start: None,
+        framing: None,
+        codec: None,
+        record_fields: Vec::new(),
+        entry_kinds: Vec::new(),
+        checksum: None,
+        compression: Vec::new(),
+    };
+    let Some(spec) = spec else {
+        return Ok(description);
+    };
+
+    description.header = header_fields(spec);
+    description.compact_threshold = Some(u64::from_le_bytes(head[4..12].try_into().unwrap()));
+    if spec.writer_epoch() {
+        description.writer_epoch = Some(u64::from_le_bytes(head[12..20].try_into().unwrap()));
+    }
+    if spec.header_flags() {
+        let flags = u64::from_le_bytes(head[20..28].try_into().unwrap());
+        description.audit_only = Some(flags & HEADER_FLAG_AUDIT_ONLY != 0);
+        description.key_transform = Some((flags >> HEADER_KEY_TRANSFORM_SHIFT) as u32);
+    }
+    description.records_start = Some(spec.header_size);
+    description.framing = Some(if framing::varint_framing(spec.version) {
+        Framing::Leb128Varint
+    } else {
+        Framing::FixedU64Le
+    });
+    description.codec = Some(spec.codec);
+    description.record_fields = record_fields(spec);
+    description.entry_kinds = if spec.entry_kinds {
+        EntryKind::ALL.iter().map(|k| format!("{:?}", k)).collect()
+    } else {
+        // KVS1 records have no kind: a value is a put, none a tombstone.
+        vec!["Put".to_string(), "Tombstone".to_string()]
+    };
+    Ok(description)
+}
+
+fn detect(head: &[u8], file_size: u64, spec: Option<&VersionSpec>) -> DetectedFormat {
+    if let Some(spec) = spec {
+        return DetectedFormat::Supported {
+            version: spec.version,
+            current: spec.version == FORMAT_VERSION,
+        };
+    }
+    if let [b'K', b'V', b'S', digit, ..] = head
+        && let Some(version) = (*digit as char).to_digit(10)
+        && version as u8 > FORMAT_VERSION
+    {
+        return DetectedFormat::Newer {
+            version: version as u8,
+        };
+    }
+    if looks_headerless(head, file_size) {
+        return DetectedFormat::Headerless;
+    }
+    DetectedFormat::Unrecognized
+}
+
+/// Whether a file starting with `head` reads as KVS1 records from offset 0:
+/// its first 8 bytes are a nonzero length that fits in the file.
+pub(crate) fn looks_headerless(head: &[u8], file_size: u64) -> bool {
+    if (head.len() as u64) < LEN_PREFIX_SIZE {
+        return false;
+    }
+    let len = u64::from_le_bytes(head[..LEN_PREFIX_SIZE as usize].try_into().unwrap());
+    len > 0 && len <= file_size - LEN_PREFIX_SIZE
+}
+
+fn header_fields(spec: &VersionSpec) -> Vec<FieldLayout> {
+    let mut fields = vec![
+        FieldLayout {
+            name: "magic",
+            offset: Some(0),
+            encoding: "4 ASCII bytes",
+        },
+        FieldLayout {
+            name: "compact_threshold",
+            offset: Some(FILE_HEADER_MAGIC.len() as u64),
+            encoding: "u64 LE",
+        },
+    ];
+    if spec.writer_epoch() {
+        fields.push(FieldLayout {
+            name: "writer_epoch",
+            offset: Some(LEGACY_HEADER_SIZE),
+            encoding: "u64 LE",
+        });
+    }
+    if spec.header_flags() {
+        fields.push(FieldLayout {
+            name: "flags",
+            offset: Some(EPOCH_HEADER_SIZE),
+            encoding: "u64 LE, bit 0 audit-only, bits 32-63 key transform id",
+        });
+    }
+    fields
+}
+
+/// Fields of an encoded `DataFileEntry` (wincode), after its length prefix.
+fn record_fields(spec: &VersionSpec) -> Vec<FieldLayout> {
+    let tstamp_size = size_of::<i64>() as u64;
+    let mut fields = vec![
+        FieldLayout {
+            name: "tstamp",
+            offset: Some(0),
+            encoding: "i64 LE, ms since the epoch",
+        },
+        FieldLayout {
+            name: "key",
+            offset: Some(tstamp_size),
+            encoding: "u64 LE length, then bytes",
+        },
+    ];
+    if spec.origins {
+        fields.push(FieldLayout {
+            name: "value",
+            offset: None,
+            encoding: "u8 flags (bit 0 some, bit 1 origin), then u64 LE length and bytes",
+        });
+        fields.push(FieldLayout {
+            name: "origin",
+            offset: None,
+            encoding: "16 bytes, only with flag bit 1",
+        });
+    } else {
+        fields.push(FieldLayout {
+            name: "value",
+            offset: None,
+            encoding: "u8 tag (0 none, 1 some), then u64 LE length and bytes",
+        });
+    }
+    if spec.entry_kinds {
+        fields.push(FieldLayout {
+            name: "kind",
+            offset: None,
+            encoding: "u8 EntryKind tag, last byte of the record",
+        });
+    }
+    fields
+}
+
+impl fmt::Display for FormatDescription {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let format = match self.detected {
+            DetectedFormat::Supported { version, current } => format!(
+                "KVS{} (supported{})",
+                version,
+                if current { ", current" } else { "" }
+            ),
+            DetectedFormat::Newer { version } => {
+                format!("KVS{} (newer than this build, cannot open)", version)
+            }
+            DetectedFormat::Headerless => {
+                "headerless log from before KVS1 (cannot open; see migrate_legacy)".to_string()
+            }
+            DetectedFormat::Unrecognized => "unrecognized".to_string(),
+        };
+        writeln!(f, "format:            {}", format)?;
+        writeln!(f, "file size:         {}", self.file_size)?;
+        writeln!(f, "magic:             {}", self.magic.escape_ascii())?;
+        if let Some(threshold) = self.compact_threshold {
+            writeln!(f, "compact threshold: {}", threshold)?;
+        }
+        if let Some(epoch) = self.writer_epoch {
+            writeln!(f, "writer epoch:      {}", epoch)?;
+        }
+        if let Some(audit_only) = self.audit_only {
+            writeln!(f, "audit only:        {}", audit_only)?;
+        }
+        match self.key_transform {
+            Some(0) => writeln!(f, "key transform:     none")?,
+            Some(id) => writeln!(f, "key transform:     {}", id)?,
+            None => {}
+        }
+        if !self.header.is_empty() {
+            writeln!(f, "header:")?;
+            write_fields(f, &self.header)?;
+        }
+        if let Some(start) = self.records_start {
+            writeln!(f, "records start:     {}", start)?;
+        }
+        if let Some(framing) = self.framing {
+            let framing = match framing {
+                Framing::FixedU64Le => "fixed u64 LE length",
+                Framing::Leb128Varint => "LEB128 varint length",
+            };
+            writeln!(f, "record framing:    {}", framing)?;
+        }
+        if let Some(codec) = self.codec {
+            let codec = match codec {
+                Codec::Wincode => "wincode",
+                Codec::ExplicitLe => "explicit little-endian",
+            };
+            writeln!(f, "record codec:      {}", codec)?;
+        }
+        if !self.record_fields.is_empty() {
+            writeln!(f, "record:")?;
+            write_fields(f, &self.record_fields)?;
+            writeln!(f, "entry kinds:       {}", self.entry_kinds.join(", "))?;
+            writeln!(f, "checksum:          {}", self.checksum.unwrap_or("none"))?;
+            let compression = if self.compression.is_empty() {
+                "none".to_string()
+            } else {
+                self.compression.join(", ")
+            };
+            writeln!(f, "compression:       {}", compression)?;
+        }
+        Ok(())
+    }
+}
+
+fn write_fields(f: &mut fmt::Formatter<'_>, fields: &[FieldLayout]) -> fmt::Result {
+    for field in fields {
+        let offset = field
+            .offset
+            .map_or_else(|| "var".to_string(), |o| o.to_string());
+        writeln!(f, "  {:>4}  {:<18} {}", offset, field.name, field.encoding)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,87 @@
+//! Length prefixes in front of every log record.
+//!
+//! KVS1 and KVS2 files frame records with a fixed 8-byte little-endian
+//! length. From KVS3 on the length is an unsigned LEB128 varint, one byte for
+//! records under 128 bytes. A zero length never frames a real record in
+//! either scheme, so it still marks pre-allocated space.
+
+use std::io::{self, Read};
+
+use crate::constants::LEN_PREFIX_SIZE;
+
+/// Longest LEB128 encoding of a u64.
+pub(crate) const MAX_VARINT_LEN: usize = 10;
+
+pub(crate) fn varint_framing(format_version: u8) -> bool {
+    format_version >= 3
+}
+
+/// Bytes the prefix of a `len`-byte record takes.
+pub(crate) fn prefix_len(format_version: u8, len: u64) -> u64 {
+    if !varint_framing(format_version) {
+        return LEN_PREFIX_SIZE;
+    }
+    let bits = 64 - len.max(1).leading_zeros() as u64;
+    bits.div_ceil(7)
+}
+
+/// Appends the prefix for a `len`-byte record to `buf`.
+pub(crate) fn write_len(format_version: u8, len: u64, buf: &mut Vec<u8>) {
+    if !varint_framing(format_version) {
+        buf.extend_from_slice(&len.to_le_bytes());
+        return;
+    }
+    let mut rest = len;
+    while rest >= 0x80 {
+        buf.push(rest as u8 | 0x80);
+        rest >>= 7;
+    }
+    buf.push(rest as u8);
+}
+
+/// Prefix bytes for a `len`-byte record.
+pub(crate) fn encode_len(format_version: u8, len: u64) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(MAX_VARINT_LEN);
+    write_len(format_version, len, &mut buf);
+    buf
+}
+
+/// Reads one prefix, returning the record length and how many bytes the
+/// prefix took. Returns `None` when the reader ends before a complete prefix
+/// (a torn tail) and an `InvalidData` error for a varint that does not fit
+/// in a u64.
+pub(crate) fn read_len(
+    format_version: u8,
+    reader: &mut (impl Read + ?Sized),
+) -> io::Result<Option<(u64, u64)>> {
+    if !varint_framing(format_version) {
+        let mut len_buf = [0u8; LEN_PREFIX_SIZE as usize];
+        return match reader.read_exact(&mut len_buf) {
+            Ok(()) => Ok(Some((u64::from_le_bytes(len_buf), LEN_PREFIX_SIZE))),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+            Err(e) => Err(e),
+        };
+    }
+
+    let mut len = 0u64;
+    for i in 0..MAX_VARINT_LEN {
+        let mut byte = [0u8; 1];
+        match reader.read_exact(&mut byte) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        }
+        let bits = u64::from(byte[0] & 0x7F);
+        if i == MAX_VARINT_LEN - 1 && bits > 1 {
+            break;
+        }
+        len |= bits << (7 * i);
+        if byte[0] & 0x80 == 0 {
+            return Ok(Some((len, i as u64 + 1)));
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "record length prefix overflows u64",
+    ))
+}
@@ -0,0 +1,196 @@
+//! A persistent hash map: a hash array mapped trie whose nodes are shared
+//! between versions, so a clone is O(1) and an update copies only the path
+//! to the changed leaf. Behind the index's lock-free read view.
+
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::BuildHasher;
+use std::sync::Arc;
+
+/// Hash bits consumed per level.
+const BITS: u32 = 5;
+const MASK: u64 = (1 << BITS) - 1;
+
+#[derive(Clone)]
+enum Node<V> {
+    /// Children for the set bits of `bitmap`, in bit order.
+    Branch {
+        bitmap: u32,
+        children: Vec<Arc<Node<V>>>,
+    },
+    /// Every entry whose key hashes to `hash`; more than one only on a full
+    /// 64-bit collision.
+    Leaf {
+        hash: u64,
+        entries: Vec<(Box<[u8]>, V)>,
+    },
+}
+
+impl<V> Node<V> {
+    fn empty() -> Self {
+        Node::Branch {
+            bitmap: 0,
+            children: Vec::new(),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        match self {
+            Node::Branch { children, .. } => children.is_empty(),
+            Node::Leaf { entries, .. } => entries.is_empty(),
+        }
+    }
+}
+
+/// Slot of `hash` in a branch at `shift`, and its index among the children.
+fn slot(bitmap: u32, hash: u64, shift: u32) -> (u32, usize) {
+    let bit = 1u32 << ((hash >> shift) & MASK);
+    (bit, (bitmap & (bit - 1)).count_ones() as usize)
+}
+
+/// A map from byte-string keys to `V` with O(1) clones; see the module docs.
+#[derive(Clone)]
+pub(crate) struct PersistentMap<V> {
+    root: Arc<Node<V>>,
+    len: usize,
+    hasher: RandomState,
+}
+
+impl<V: Clone> Default for PersistentMap<V> {
+    fn default() -> Self {
+        PersistentMap {
+            root: Arc::new(Node::empty()),
+            len: 0,
+            hasher: RandomState::new(),
+        }
+    }
+}
+
+impl<V> fmt::Debug for PersistentMap<V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PersistentMap")
+            .field("len", &self.len)
+            .finish()
+    }
+}
+
+impl<V: Clone> PersistentMap<V> {
+    pub(crate) fn get(&self, key: &[u8]) -> Option<&V> {
+        let hash = self.hasher.hash_one(key);
+        let mut node = &*self.root;
+        let mut shift = 0;
+        loop {
+            match node {
+                Node::Branch { bitmap, children } => {
+                    let (bit, pos) = slot(*bitmap, hash, shift);
+                    if bitmap & bit == 0 {
+                        return None;
+                    }
+                    node = &children[pos];
+                    shift += BITS;
+                }
+                Node::Leaf { hash: h, entries } => {
+                    return match *h == hash {
+                        true => entries.iter().find(|(k, _)| **k == *key).map(|(_, v)| v),
+                        false => None,
+                    };
+                }
+            }
+        }
+    }
+
+    #[cfg(feature = "cache-audit")]
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    pub(crate) fn insert(&mut self, key: &[u8], value: V) {
+        let hash = self.hasher.hash_one(key);
+        if insert(&mut self.root, 0, hash, key, value) {
+            self.len += 1;
+        }
+    }
+
+    pub(crate) fn remove(&mut self, key: &[u8]) {
+        // Copying the path for a key that is not there would only waste it.
+        if self.get(key).is_none() {
+            return;
+        }
+        let hash = self.hasher.hash_one(key);
+        remove(&mut self.root, 0, hash, key);
+        self.len -= 1;
+    }
+}
+
+/// Inserts below `node` (at depth `shift`), copying shared nodes on the way
+/// down. Returns whether the key is new.
+fn insert<V: Clone>(node: &mut Arc<Node<V>>, shift: u32, hash: u64, key: &[u8], value: V) -> bool {
+    let node_mut = Arc::make_mut(node);
+    let split_hash = match node_mut {
+        Node::Branch { bitmap, children } => {
+            let (bit, pos) = slot(*bitmap, hash, shift);
+            if *bitmap & bit != 0 {
+                return insert(&mut children[pos], shift + BITS, hash, key, value);
+            }
+            *bitmap |= bit;
+            let leaf = Node::Leaf {
+                hash,
+                entries: vec![(key.into(), value)],
+            };
+            children.insert(pos, Arc::new(leaf));
+            return true;
+        }
+        Node::Leaf {
+            hash: leaf_hash,
+            entries,
+        } if *leaf_hash == hash => match entries.iter_mut().find(|(k, _)| **k == *key) {
+            Some(entry) => {
+                entry.1 = value;
+                return false;
+            }
+            None => {
+                entries.push((key.into(), value));
+                return true;
+            }
+        },
+        Node::Leaf { hash, .. } => *hash,
+    };
+    // A leaf for another hash: push it one level down under a new branch,
+    // then insert into that. Two different hashes part ways by bit 63, so
+    // this never needs a branch past the last level.
+    let leaf = std::mem::replace(node_mut, Node::empty());
+    *node_mut = Node::Branch {
+        bitmap: slot(0, split_hash, shift).0,
+        children: vec![Arc::new(leaf)],
+    };
+    insert(node, shift, hash, key, value)
+}
+
+/// Removes `key`, known to be present, from below `node`, dropping nodes
+/// that end up empty and lifting a lone leaf into its parent's slot.
+fn remove<V: Clone>(node: &mut Arc<Node<V>>, shift: u32, hash: u64, key: &[u8]) {
+    match Arc::make_mut(node) {
+        Node::Branch { bitmap, children } => {
+            let (bit, pos) = slot(*bitmap, hash, shift);
+            remove(&mut children[pos], shift + BITS, hash, key);
+            let lone_leaf = match &*children[pos] {
+                child if child.is_empty() => {
+                    children.remove(pos);
+                    *bitmap &= !bit;
+                    None
+                }
+                Node::Branch {
+                    children: grandchildren,
+                    ..
+                } if grandchildren.len() == 1 && matches!(*grandchildren[0], Node::Leaf { .. }) => {
+                    Some(Arc::clone(&grandchildren[0]))
+                }
+                _ => None,
+            };
+            if let Some(leaf) = lone_leaf {
+                children[pos] = leaf;
+            }
+        }
+        Node::Leaf { entries, .. } => entries.retain(|(k, _)| **k != *key),
+    }
+}
@@ -0,0 +1,190 @@
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::clock::Clock;
+use crate::runtime::{JobHandle, KvRuntime};
+use crate::workers::{self, BackgroundWorker};
+
+/// Longest the background reclaimer sleeps between idle checks.
+const MAX_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+const MIN_CHECK_INTERVAL: Duration = Duration::from_millis(10);
+
+/// When the engine last used a file handle, and what reclaiming has done.
+pub(crate) struct IdleState {
+    clock: Arc<dyn Clock>,
+    /// Touches are only timed under `Options::idle_after`, so engines
+    /// without it skip the clock read.
+    timed: bool,
+    started: Instant,
+    /// Nanoseconds after `started` of the last touch.
+    last_touch: AtomicU64,
+    /// Resources were released and nothing has used them since.
+    reclaimed: AtomicBool,
+    pub(crate) reclaims: AtomicU64,
+    pub(crate) reacquires: AtomicU64,
+    stop: Mutex<bool>,
+    wake: Condvar,
+}
+
+impl IdleState {
+    pub(crate) fn new(clock: Arc<dyn Clock>, timed: bool) -> Self {
+        let started = clock.now();
+        IdleState {
+            clock,
+            timed,
+            started,
+            last_touch: AtomicU64::new(0),
+            reclaimed: AtomicBool::new(false),
+            reclaims: AtomicU64::new(0),
+            reacquires: AtomicU64::new(0),
+            stop: Mutex::new(false),
+            wake: Condvar::new(),
+        }
+    }
+
+    /// Records a read or write. The first one after a reclaim counts as a
+    /// reacquire.
+    pub(crate) fn touch(&self) {
+        if self.timed {
+            self.last_touch
+                .store(self.elapsed_nanos(), Ordering::Relaxed);
+        }
+        if self.reclaimed.load(Ordering::Relaxed) && self.reclaimed.swap(false, Ordering::AcqRel) {
+            self.reacquires.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Time since the last touch, or since open if there was none.
+    pub(crate) fn idle_for(&self) -> Duration {
+        let last = self.last_touch.load(Ordering::Relaxed);
+        Duration::from_nanos(self.elapsed_nanos().saturating_sub(last))
+    }
+
+    /// Whether resources are released and still unused.
+    pub(crate) fn is_reclaimed(&self) -> bool {
+        self.reclaimed.load(Ordering::Acquire)
+    }
+
+    pub(crate) fn mark_reclaimed(&self) {
+        self.reclaimed.store(true, Ordering::Release);
+        self.reclaims.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn elapsed_nanos(&self) -> u64 {
+        self.clock
+            .now()
+            .saturating_duration_since(self.started)
+            .as_nanos() as u64
+    }
+}
+
+/// Drives `Options::idle_after`: checks every so often, on a thread of its
+/// own or as a job on a shared [`KvRuntime`], and calls `reclaim` once the
+/// engine has gone untouched for `idle_after`. Dropping it stops the checks.
+pub(crate) struct IdleReclaimer {
+    state: Arc<IdleState>,
+    worker: Worker,
+}
+
+enum Worker {
+    Thread(Option<JoinHandle<()>>),
+    Job(Option<JobHandle>),
+}
+
+impl IdleReclaimer {
+    pub(crate) fn spawn(
+        state: Arc<IdleState>,
+        idle_after: Duration,
+        reclaim: impl Fn() + Send + 'static,
+    ) -> io::Result<Self> {
+        let thread_state = Arc::clone(&state);
+        let interval = check_interval(idle_after);
+        let handle = thread::Builder::new()
+            .name("kv-idle".into())
+            .spawn(move || {
+                let state = thread_state;
+                let mut stopped = state.stop.lock().unwrap();
+                loop {
+                    stopped = state
+                        .wake
+                        .wait_timeout_while(stopped, interval, |stop| !*stop)
+                        .unwrap()
+                        .0;
+                    if *stopped {
+                        break;
+                    }
+                    drop(stopped);
+                    check(&state, idle_after, &reclaim);
+                    stopped = state.stop.lock().unwrap();
+                }
+            })?;
+
+        Ok(IdleReclaimer {
+            state,
+            worker: Worker::Thread(Some(handle)),
+        })
+    }
+
+    /// Like [`IdleReclaimer::spawn`], but runs the checks on `runtime`'s
+    /// worker thread.
+    pub(crate) fn schedule(
+        runtime: &KvRuntime,
+        state: Arc<IdleState>,
+        idle_after: Duration,
+        reclaim: impl Fn() + Send + 'static,
+    ) -> Self {
+        let job_state = Arc::clone(&state);
+        let interval = check_interval(idle_after);
+        let job = runtime.schedule(interval, move || {
+            check(&job_state, idle_after, &reclaim);
+            Some(interval)
+        });
+
+        IdleReclaimer {
+            state,
+            worker: Worker::Job(Some(job)),
+        }
+    }
+}
+
+impl BackgroundWorker for IdleReclaimer {
+    fn name(&self) -> &'static str {
+        "kv-idle"
+    }
+
+    fn signal_stop(&mut self) {
+        if let Worker::Thread(_) = self.worker {
+            *self.state.stop.lock().unwrap() = true;
+            self.state.wake.notify_all();
+        }
+    }
+
+    fn wait_stopped(&mut self, deadline: Option<Instant>) -> bool {
+        match &mut self.worker {
+            Worker::Thread(handle) => workers::join_until(handle, deadline),
+            Worker::Job(job) => job.take().is_none_or(|mut job| job.cancel(deadline)),
+        }
+    }
+}
+
+impl Drop for IdleReclaimer {
+    fn drop(&mut self) {
+        self.signal_stop();
+        self.wait_stopped(None);
+    }
+}
+
+fn check(state: &IdleState, idle_after: Duration, reclaim: &impl Fn()) {
+    if !state.is_reclaimed() && state.idle_for() >= idle_after {
+        reclaim();
+    }
+}
+
+/// A quarter of `idle_after`, so reclaiming lags the deadline by little,
+/// within bounds that keep the checks neither busy nor rare.
+fn check_interval(idle_after: Duration) -> Duration {
+    (idle_after / 4).clamp(MIN_CHECK_INTERVAL, MAX_CHECK_INTERVAL)
+}
@@ -0,0 +1,791 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::mem::size_of;
+
+use sha1::{Digest, Sha1};
+
+use crate::bucket;
+#[cfg(feature = "cache-audit")]
+use crate::cache_audit::CacheInconsistency;
+use crate::constants::FORMAT_VERSION;
+use crate::engine::{Engine, now_millis};
+use crate::framing;
+use crate::hamt::PersistentMap;
+use crate::stats::PrefixStats;
+use crate::types::{
+    Blob, DataFileEntry, EXPIRY_SIZE, EntryKind, LogIndex, ORIGIN_SIZE, SoftDeleted,
+};
+
+/// In-memory view of the log: where every live key, recoverable soft-deleted
+/// key, and retained prior version lives in the data file.
+#[derive(Debug, Default)]
+pub(crate) struct Index {
+    pub(crate) live: HashMap<Vec<u8>, LogIndex>,
+    pub(crate) soft_deleted: HashMap<Vec<u8>, SoftDeleted>,
+    pub(crate) history: HashMap<Vec<u8>, VecDeque<LogIndex>>,
+    /// Deduplicated values by content hash.
+    pub(crate) blobs: HashMap<Vec<u8>, Blob>,
+    /// Content hash for every live key whose record is a `BlobRef`.
+    pub(crate) refs: HashMap<Vec<u8>, Vec<u8>>,
+    /// Expiry (ms since the epoch) of every live key written with a TTL.
+    pub(crate) expiries: HashMap<Vec<u8>, i64>,
+    /// Where in the archive file the value of every live key whose record
+    /// is an `Archived` stub sits.
+    pub(crate) archived: HashMap<Vec<u8>, LogIndex>,
+    history_depth: usize,
+    /// Format of the file this index describes, needed to size values.
+    format_version: u8,
+    /// Live counters for prefixes registered through `track_prefix`.
+    pub(crate) tracked: Vec<PrefixStats>,
+    /// On-disk bytes (length prefix included) of records compaction would drop.
+    pub(crate) dead_bytes: u64,
+    /// The part of `dead_bytes` left by writes to bucketed keys, by bucket
+    /// name. Blobs are shared across keys and belong to no bucket.
+    pub(crate) bucket_dead_bytes: HashMap<Vec<u8>, u64>,
+    pub(crate) tombstones: u64,
+    pub(crate) tombstone_bytes: u64,
+    /// Total length of the keys in `live`.
+    live_key_bytes: u64,
+    /// How many keys in `live` are the engine's own.
+    pub(crate) internal_keys: usize,
+    /// How many keys in `live` have each length, for the longest one.
+    key_lens: BTreeMap<usize, usize>,
+    /// The keys of `live` in order, under `Options::ordered_index`.
+    pub(crate) ordered: Option<BTreeSet<Vec<u8>>>,
+    /// Content hash of every live key's value, under `Options::value_hashes`.
+    pub(crate) hashes: Option<HashMap<Vec<u8>, u64>>,
+    /// Origin of every live key whose record has one.
+    pub(crate) origins: HashMap<Vec<u8>, [u8; ORIGIN_SIZE]>,
+    /// What `get` needs of the above, in persistent maps, so the engine can
+    /// publish a snapshot after every write for lock-free reads.
+    pub(crate) view: ReadView,
+}
+
+/// A live key as `get` sees it.
+#[derive(Debug, Clone)]
+pub(crate) struct ViewEntry {
+    record: LogIndex,
+    /// Content hash, for a `BlobRef` record.
+    blob: Option<Box<[u8]>>,
+    expires_at: Option<i64>,
+    /// When the record was written, ms since the epoch.
+    tstamp: i64,
+}
+
+/// The live keys and blobs of an [`Index`], cheap to clone.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ReadView {
+    live: PersistentMap<ViewEntry>,
+    blobs: PersistentMap<LogIndex>,
+    format_version: u8,
+}
+
+impl ReadView {
+    /// Where the value of live, unexpired `key` is stored; the same answer
+    /// as [`Index::value_location`].
+    pub(crate) fn get(&self, key: &[u8]) -> Option<LogIndex> {
+        let entry = self.live.get(key)?;
+        if entry.expires_at.is_some_and(|at| at <= now_millis()) {
+            return None;
+        }
+        let blob = entry.blob.as_deref().and_then(|hash| self.blobs.get(hash));
+        Some(blob.unwrap_or(&entry.record).clone())
+    }
+
+    pub(crate) fn format_version(&self) -> u8 {
+        self.format_version
+    }
+}
+
+/// Estimated heap cost of one live index entry beyond its key bytes: the
+/// key's `Vec`, its `LogIndex`, and hash table overhead.
+const LIVE_ENTRY_OVERHEAD: u64 = (size_of::<Vec<u8>>() + size_of::<LogIndex>() + 8) as u64;
+
+/// Estimated heap cost of one read-view entry beyond its key bytes: the
+/// view keeps a copy of every live key for lock-free `get`s.
+const VIEW_ENTRY_OVERHEAD: u64 = (size_of::<Box<[u8]>>() + size_of::<ViewEntry>()) as u64;
+
+/// Estimated heap cost of one ordered-index entry beyond its key bytes: the
+/// key's `Vec` and B-tree node slack.
+const ORDERED_ENTRY_OVERHEAD: u64 = (size_of::<Vec<u8>>() + 8) as u64;
+
+/// Estimated heap cost of one value-hash entry beyond its key bytes.
+const HASH_ENTRY_OVERHEAD: u64 = (size_of::<Vec<u8>>() + size_of::<u64>() + 8) as u64;
+
+/// The content hash of a value: the first 8 bytes of its SHA-1, the digest
+/// deduplicated blobs are keyed by, so a `BlobRef` carries its value's hash.
+pub(crate) fn value_hash(value: &[u8]) -> u64 {
+    sha1_prefix(&Sha1::digest(value))
+}
+
+fn sha1_prefix(digest: &[u8]) -> u64 {
+    let mut prefix = [0u8; 8];
+    let n = digest.len().min(8);
+    prefix[..n].copy_from_slice(&digest[..n]);
+    u64::from_be_bytes(prefix)
+}
+
+/// The content hash of the value a live record holds, if the record has
+/// its value (`OpenMode::Fast` loads skip it).
+fn entry_hash(entry: &DataFileEntry) -> Option<u64> {
+    let value = entry.value.as_deref()?;
+    match entry.kind {
+        EntryKind::Put => Some(value_hash(value)),
+        EntryKind::ExpiringPut => Some(value_hash(value.get(EXPIRY_SIZE..).unwrap_or_default())),
+        EntryKind::BlobRef => Some(sha1_prefix(value)),
+        _ => None,
+    }
+}
+
+/// On-disk bytes of the record at `log_index`, length prefix included.
+pub(crate) fn record_size(format_version: u8, log_index: &LogIndex) -> u64 {
+    framing::prefix_len(format_version, log_index.len) + log_index.len
+}
+
+impl Index {
+    pub(crate) fn new(
+        history_depth: usize,
+        format_version: u8,
+        ordered: bool,
+        value_hashes: bool,
+    ) -> Self {
+        Index {
+            history_depth,
+            format_version,
+            ordered: ordered.then(BTreeSet::new),
+            hashes: value_hashes.then(HashMap::new),
+            view: ReadView {
+                format_version,
+                ..ReadView::default()
+            },
+            ..Index::default()
+        }
+    }
+
+    /// Starts maintaining counters for `prefix`, initialised with one scan
+    /// of the live keys. Registering the same prefix twice is a no-op.
+    pub(crate) fn track_prefix(&mut self, prefix: &[u8]) {
+        if self.tracked.iter().any(|t| t.prefix == prefix) {
+            return;
+        }
+        let mut stats = PrefixStats {
+            prefix: prefix.to_vec(),
+            ..PrefixStats::default()
+        };
+        for (key, log_index) in &self.live {
+            if key.starts_with(prefix) {
+                stats.keys += 1;
+                stats.value_bytes += self.value_len(key, log_index);
+            }
+        }
+        self.tracked.push(stats);
+    }
+
+    pub(crate) fn tracked_prefixes(&self) -> Vec<Vec<u8>> {
+        self.tracked.iter().map(|t| t.prefix.clone()).collect()
+    }
+
+    /// Length of live `key`'s value, blob, inline, or archived, without
+    /// any expiry.
+    pub(crate) fn value_len(&self, key: &[u8], log_index: &LogIndex) -> u64 {
+        if let Some(archived_at) = self.archived.get(key) {
+            // The archived record keeps the origin its stub names.
+            return Engine::value_span(FORMAT_VERSION, key.len() as u64, archived_at.len)
+                .map_or(0, |(_, len)| len.saturating_sub(self.origin_len(key)));
+        }
+        let (_, key_len, log_index) = self.value_location(key, log_index);
+        let expiry_len = match self.expiries.contains_key(key) {
+            true => EXPIRY_SIZE as u64,
+            false => 0,
+        };
+        Engine::value_span(self.format_version, key_len, log_index.len)
+            .map(|(_, len)| len.saturating_sub(expiry_len + self.origin_len(key)))
+            .unwrap_or(0)
+    }
+
+    /// Bytes of origin between the value of live `key` and the end of the
+    /// record it is read from. A blob names no origin, so for a `BlobRef`
+    /// that is none.
+    pub(crate) fn origin_len(&self, key: &[u8]) -> u64 {
+        if self.origins.is_empty() || self.refs.contains_key(key) {
+            return 0;
+        }
+        match self.origins.contains_key(key) {
+            true => ORIGIN_SIZE as u64,
+            false => 0,
+        }
+    }
+
+    /// The value length, write time, expiry, and record of live, unexpired
+    /// `key`, all from memory.
+    pub(crate) fn stat(&self, key: &[u8]) -> Option<(u64, i64, Option<i64>, &LogIndex)> {
+        let log_index = self.live.get(key)?;
+        if self.is_expired(key) {
+            return None;
+        }
+        let tstamp = self.tstamp(key)?;
+        let expires_at = self.expiries.get(key).copied();
+        Some((
+            self.value_len(key, log_index),
+            tstamp,
+            expires_at,
+            log_index,
+        ))
+    }
+
+    /// When live `key`'s record was written, ms since the epoch.
+    pub(crate) fn tstamp(&self, key: &[u8]) -> Option<i64> {
+        self.view.live.get(key).map(|entry| entry.tstamp)
+    }
+
+    /// Whether live `key` was written with a TTL that has passed. Expired
+    /// keys stay in `live` until overwritten or compacted away.
+    pub(crate) fn is_expired(&self, key: &[u8]) -> bool {
+        if self.expiries.is_empty() {
+            return false;
+        }
+        self.expiries.get(key).is_some_and(|&at| at <= now_millis())
+    }
+
+    /// Where the value bytes for live `key` (whose record is `log_index`)
+    /// actually live: the record itself, or the blob it references. Returns
+    /// the record's key, its length, and its location.
+    pub(crate) fn value_location<'a>(
+        &'a self,
+        key: &'a [u8],
+        log_index: &'a LogIndex,
+    ) -> (&'a [u8], u64, &'a LogIndex) {
+        match self
+            .refs
+            .get(key)
+            .and_then(|hash| Some((hash, self.blobs.get(hash)?)))
+        {
+            Some((hash, blob)) => (hash, hash.len() as u64, &blob.index),
+            None => (key, key.len() as u64, log_index),
+        }
+    }
+
+    /// Adds (`live == true`) or removes a live record from every tracked
+    /// prefix it falls under.
+    fn track_live(&mut self, key: &[u8], log_index: &LogIndex, live: bool) {
+        if self.tracked.is_empty() {
+            return;
+        }
+        let value_len = self.value_len(key, log_index);
+        for stats in self
+            .tracked
+            .iter_mut()
+            .filter(|t| key.starts_with(&t.prefix))
+        {
+            if live {
+                stats.keys += 1;
+                stats.value_bytes += value_len;
+            } else {
+                stats.keys -= 1;
+                stats.value_bytes -= value_len;
+            }
+        }
+    }
+
+    /// Applies a decoded log record. Used both when appending and when
+    /// replaying the log on load, so the two can never disagree.
+    pub(crate) fn apply_entry(&mut self, entry: &DataFileEntry, log_index: LogIndex) {
+        match entry.kind {
+            EntryKind::BlobRef => self.apply_ref(
+                entry.key.clone(),
+                entry.value.clone().unwrap_or_default(),
+                log_index,
+                entry.tstamp,
+            ),
+            EntryKind::ExpiringPut => self.apply_expiring(
+                entry.key.clone(),
+                log_index,
+                entry.tstamp,
+                entry.expires_at().unwrap_or(i64::MIN),
+            ),
+            EntryKind::Archived => {
+                let archived_at = entry.archived_at().unwrap_or(LogIndex { pos: 0, len: 0 });
+                self.apply_archived(entry.key.clone(), log_index, entry.tstamp, archived_at)
+            }
+            kind => self.apply(kind, entry.key.clone(), log_index, entry.tstamp),
+        }
+        if let Some(hashes) = &mut self.hashes
+            && self.live.contains_key(&entry.key)
+            && let Some(hash) = entry_hash(entry)
+        {
+            hashes.insert(entry.key.clone(), hash);
+        }
+        if let Some(origin) = entry.origin
+            && entry.kind != EntryKind::StalePut
+            && self.live.contains_key(&entry.key)
+        {
+            self.origins.insert(entry.key.clone(), origin);
+        }
+    }
+
+    /// Applies one log record of any kind but `BlobRef`, `ExpiringPut`, and
+    /// `Archived`, which carry data in the value and go through
+    /// [`Index::apply_ref`], [`Index::apply_expiring`], and
+    /// [`Index::apply_archived`].
+    pub(crate) fn apply(
+        &mut self,
+        kind: EntryKind,
+        key: Vec<u8>,
+        log_index: LogIndex,
+        tstamp: i64,
+    ) {
+        match kind {
+            EntryKind::Blob => self.apply_blob(key, log_index),
+            EntryKind::Put => {
+                self.clear_soft_deleted(&key);
+                self.insert_live(key, log_index, tstamp, None, None, None);
+            }
+            EntryKind::Tombstone => {
+                self.clear_soft_deleted(&key);
+                self.add_dead(&key, &log_index);
+                self.tombstones += 1;
+                self.tombstone_bytes += record_size(self.format_version, &log_index);
+                self.drop_key(&key);
+            }
+            EntryKind::SoftDelete => {
+                self.clear_soft_deleted(&key);
+                self.drop_key(&key);
+                self.soft_deleted.insert(
+                    key,
+                    SoftDeleted {
+                        index: log_index,
+                        deleted_at: tstamp,
+                    },
+                );
+            }
+            // Batch framing and writer epochs are never live.
+            EntryKind::BatchBegin | EntryKind::Epoch => {
+                self.dead_bytes += record_size(self.format_version, &log_index)
+            }
+            // History of a live key; with nothing live there is no history
+            // to keep it in.
+            EntryKind::StalePut => {
+                if self.live.contains_key(&key) {
+                    self.push_history(&key, log_index);
+                } else {
+                    self.add_dead(&key, &log_index);
+                }
+            }
+            EntryKind::BlobRef => unreachable!("blob refs are applied by apply_ref"),
+            EntryKind::ExpiringPut => unreachable!("expiring puts are applied by apply_expiring"),
+            EntryKind::Archived => unreachable!("archive stubs are applied by apply_archived"),
+        }
+    }
+
+    /// Applies an `ExpiringPut` record that stays visible until `expires_at`.
+    pub(crate) fn apply_expiring(
+        &mut self,
+        key: Vec<u8>,
+        log_index: LogIndex,
+        tstamp: i64,
+        expires_at: i64,
+    ) {
+        self.clear_soft_deleted(&key);
+        self.insert_live(key, log_index, tstamp, None, Some(expires_at), None);
+    }
+
+    /// Applies an `Archived` stub forwarding `key` to its value at
+    /// `archived_at` in the archive file.
+    pub(crate) fn apply_archived(
+        &mut self,
+        key: Vec<u8>,
+        log_index: LogIndex,
+        tstamp: i64,
+        archived_at: LogIndex,
+    ) {
+        self.clear_soft_deleted(&key);
+        self.insert_live(key, log_index, tstamp, None, None, Some(archived_at));
+    }
+
+    /// Applies a `BlobRef` record pointing `key` at the blob `hash`.
+    pub(crate) fn apply_ref(
+        &mut self,
+        key: Vec<u8>,
+        hash: Vec<u8>,
+        log_index: LogIndex,
+        tstamp: i64,
+    ) {
+        self.clear_soft_deleted(&key);
+        self.insert_live(key, log_index, tstamp, Some(hash), None, None);
+    }
+
+    fn apply_blob(&mut self, hash: Vec<u8>, log_index: LogIndex) {
+        let refs = match self.blobs.remove(&hash) {
+            Some(old) => {
+                self.dead_bytes += record_size(self.format_version, &old.index);
+                old.refs
+            }
+            None => 0,
+        };
+        self.view.blobs.insert(&hash, log_index.clone());
+        self.blobs.insert(
+            hash,
+            Blob {
+                index: log_index,
+                refs,
+            },
+        );
+    }
+
+    fn clear_soft_deleted(&mut self, key: &[u8]) {
+        if let Some(sd) = self.soft_deleted.remove(key) {
+            self.add_dead(key, &sd.index);
+        }
+    }
+
+    fn insert_live(
+        &mut self,
+        key: Vec<u8>,
+        log_index: LogIndex,
+        tstamp: i64,
+        blob_hash: Option<Vec<u8>>,
+        expires_at: Option<i64>,
+        archived_at: Option<LogIndex>,
+    ) {
+        let previous = self.live.remove(&key);
+        // Only `apply_entry` sees the value and origin, and fills them in.
+        if let Some(hashes) = &mut self.hashes {
+            hashes.remove(&key);
+        }
+        self.origins.remove(&key);
+        match &previous {
+            Some(previous) => self.track_live(&key, previous, false),
+            None => {
+                self.live_key_bytes += key.len() as u64;
+                *self.key_lens.entry(key.len()).or_default() += 1;
+                if bucket::is_internal_key(&key) {
+                    self.internal_keys += 1;
+                }
+                if let Some(ordered) = &mut self.ordered {
+                    ordered.insert(key.clone());
+                }
+            }
+        }
+        match expires_at {
+            Some(at) => self.expiries.insert(key.clone(), at),
+            None => self.expiries.remove(&key),
+        };
+        match archived_at {
+            Some(at) => self.archived.insert(key.clone(), at),
+            None => self.archived.remove(&key),
+        };
+
+        // Take the new reference before releasing the old one, so rewriting a
+        // key with the same content never frees the blob in between.
+        let old_hash = self.refs.remove(&key);
+        let entry = ViewEntry {
+            record: log_index.clone(),
+            blob: blob_hash.as_deref().map(Box::from),
+            expires_at,
+            tstamp,
+        };
+        self.view.live.insert(&key, entry);
+        if let Some(hash) = blob_hash {
+            if let Some(blob) = self.blobs.get_mut(&hash) {
+                blob.refs += 1;
+            }
+            self.refs.insert(key.clone(), hash);
+        }
+        if let Some(hash) = old_hash {
+            self.release_blob(&hash);
+        }
+
+        if let Some(previous) = previous {
+            self.push_history(&key, previous);
+        }
+        self.track_live(&key, &log_index, true);
+        self.live.insert(key, log_index);
+    }
+
+    /// Drops one reference to the blob `hash`; a blob nobody references any
+    /// more becomes dead and is left out of the next compaction.
+    fn release_blob(&mut self, hash: &[u8]) {
+        if let Some(blob) = self.blobs.get_mut(hash) {
+            blob.refs = blob.refs.saturating_sub(1);
+            if blob.refs == 0
+                && let Some(blob) = self.blobs.remove(hash)
+            {
+                self.view.blobs.remove(hash);
+                self.dead_bytes += record_size(self.format_version, &blob.index);
+            }
+        }
+    }
+
+    fn drop_key(&mut self, key: &[u8]) {
+        if let Some(previous) = self.live.remove(key) {
+            self.live_key_bytes -= key.len() as u64;
+            if bucket::is_internal_key(key) {
+                self.internal_keys -= 1;
+            }
+            if let Some(count) = self.key_lens.get_mut(&key.len()) {
+                *count -= 1;
+                if *count == 0 {
+                    self.key_lens.remove(&key.len());
+                }
+            }
+            if let Some(ordered) = &mut self.ordered {
+                ordered.remove(key);
+            }
+            if let Some(hashes) = &mut self.hashes {
+                hashes.remove(key);
+            }
+            self.track_live(key, &previous, false);
+            self.add_dead(key, &previous);
+        }
+        self.view.live.remove(key);
+        self.expiries.remove(key);
+        self.archived.remove(key);
+        self.origins.remove(key);
+        if let Some(hash) = self.refs.remove(key) {
+            self.release_blob(&hash);
+        }
+        if let Some(ring) = self.history.remove(key) {
+            for log_index in &ring {
+                self.add_dead(key, log_index);
+            }
+        }
+    }
+
+    fn push_history(&mut self, key: &[u8], previous: LogIndex) {
+        if self.history_depth == 0 {
+            self.add_dead(key, &previous);
+            return;
+        }
+        let Some(ring) = self.history.get_mut(key) else {
+            self.history
+                .insert(key.to_vec(), VecDeque::from([previous]));
+            return;
+        };
+        let evicted = match ring.len() == self.history_depth {
+            true => ring.pop_front(),
+            false => None,
+        };
+        ring.push_back(previous);
+        if let Some(evicted) = evicted {
+            self.add_dead(key, &evicted);
+        }
+    }
+
+    /// Counts the record of `key` at `log_index` as dead, against its
+    /// bucket too if `key` is bucketed.
+    fn add_dead(&mut self, key: &[u8], log_index: &LogIndex) {
+        let size = record_size(self.format_version, log_index);
+        self.dead_bytes += size;
+        if let Some((name, _)) = bucket::split_key(key) {
+            match self.bucket_dead_bytes.get_mut(name) {
+                Some(dead) => *dead += size,
+                None => {
+                    self.bucket_dead_bytes.insert(name.to_vec(), size);
+                }
+            }
+        }
+    }
+
+    /// Length of the longest live key, 0 with none.
+    pub(crate) fn largest_key_len(&self) -> usize {
+        self.key_lens.last_key_value().map_or(0, |(&len, _)| len)
+    }
+
+    /// Estimated heap bytes held by the live-key index and its read view.
+    pub(crate) fn live_bytes(&self) -> u64 {
+        let mut bytes = 2 * self.live_key_bytes
+            + self.live.len() as u64 * (LIVE_ENTRY_OVERHEAD + VIEW_ENTRY_OVERHEAD);
+        if self.ordered.is_some() {
+            bytes += self.live_key_bytes + self.live.len() as u64 * ORDERED_ENTRY_OVERHEAD;
+        }
+        if self.hashes.is_some() {
+            bytes += self.live_key_bytes + self.live.len() as u64 * HASH_ENTRY_OVERHEAD;
+        }
+        bytes
+    }
+
+    /// Estimated heap bytes one more live key of `key_len` bytes would add
+    /// to [`Index::live_bytes`].
+    pub(crate) fn entry_bytes(&self, key_len: usize) -> u64 {
+        let mut bytes = 2 * key_len as u64 + LIVE_ENTRY_OVERHEAD + VIEW_ENTRY_OVERHEAD;
+        if self.ordered.is_some() {
+            bytes += key_len as u64 + ORDERED_ENTRY_OVERHEAD;
+        }
+        if self.hashes.is_some() {
+            bytes += key_len as u64 + HASH_ENTRY_OVERHEAD;
+        }
+        bytes
+    }
+
+    /// Checks every structure derived from `live` against it, and `view`,
+    /// the read view `get` is served from, against the index. Value hashes
+    /// are left to the engine, which reads the values.
+    #[cfg(feature = "cache-audit")]
+    pub(crate) fn audit(&self, view: &ReadView) -> Vec<CacheInconsistency> {
+        let mut found = Vec::new();
+        let read_view = |key: &[u8], detail: String| CacheInconsistency::ReadView {
+            key: key.to_vec(),
+            detail,
+        };
+        for (key, log_index) in &self.live {
+            let Some(entry) = view.live.get(key) else {
+                found.push(read_view(key, "missing".to_string()));
+                continue;
+            };
+            if entry.record != *log_index {
+                found.push(read_view(
+                    key,
+                    format!(
+                        "record at offset {} where the index has {}",
+                        entry.record.pos, log_index.pos
+                    ),
+                ));
+            }
+            if entry.blob.as_deref() != self.refs.get(key).map(Vec::as_slice) {
+                found.push(read_view(key, "references another blob".to_string()));
+            }
+            let expires_at = self.expiries.get(key).copied();
+            if entry.expires_at != expires_at {
+                found.push(read_view(
+                    key,
+                    format!(
+                        "expires at {:?} where the index has {:?}",
+                        entry.expires_at, expires_at
+                    ),
+                ));
+            }
+        }
+        for (hash, blob) in &self.blobs {
+            if view.blobs.get(hash) != Some(&blob.index) {
+                found.push(read_view(hash, "blob missing or misplaced".to_string()));
+            }
+        }
+        let sizes = [
+            ("keys", view.live.len(), self.live.len()),
+            ("blobs", view.blobs.len(), self.blobs.len()),
+        ];
+        for (what, in_view, in_index) in sizes {
+            if in_view != in_index {
+                found.push(CacheInconsistency::ReadViewSize {
+                    what,
+                    view: in_view,
+                    index: in_index,
+                });
+            }
+        }
+
+        if let Some(ordered) = &self.ordered {
+            for key in self.live.keys().filter(|key| !ordered.contains(*key)) {
+                found.push(CacheInconsistency::OrderedIndex {
+                    key: key.clone(),
+                    live: true,
+                });
+            }
+            for key in ordered.iter().filter(|key| !self.live.contains_key(*key)) {
+                found.push(CacheInconsistency::OrderedIndex {
+                    key: key.clone(),
+                    live: false,
+                });
+            }
+        }
+
+        for counted in &self.tracked {
+            let mut recounted = PrefixStats {
+                prefix: counted.prefix.clone(),
+                ..PrefixStats::default()
+            };
+            for (key, log_index) in &self.live {
+                if key.starts_with(&counted.prefix) {
+                    recounted.keys += 1;
+                    recounted.value_bytes += self.value_len(key, log_index);
+                }
+            }
+            if recounted != *counted {
+                found.push(CacheInconsistency::TrackedPrefix {
+                    counted: counted.clone(),
+                    recounted,
+                });
+            }
+        }
+
+        let mut referenced_by: HashMap<&[u8], u64> = HashMap::new();
+        for hash in self.refs.values() {
+            *referenced_by.entry(hash.as_slice()).or_default() += 1;
+        }
+        for (hash, blob) in &self.blobs {
+            let n = referenced_by.remove(hash.as_slice()).unwrap_or(0);
+            if n != blob.refs {
+                found.push(CacheInconsistency::BlobRefs {
+                    hash: hash.clone(),
+                    refs: blob.refs,
+                    referenced_by: n,
+                });
+            }
+        }
+        for (hash, n) in referenced_by {
+            found.push(CacheInconsistency::BlobRefs {
+                hash: hash.to_vec(),
+                refs: 0,
+                referenced_by: n,
+            });
+        }
+
+        let key_bytes: u64 = self.live.keys().map(|key| key.len() as u64).sum();
+        if key_bytes != self.live_key_bytes {
+            found.push(CacheInconsistency::KeyLengths {
+                detail: format!(
+                    "{} key bytes counted, {} live",
+                    self.live_key_bytes, key_bytes
+                ),
+            });
+        }
+        let mut key_lens = BTreeMap::new();
+        for key in self.live.keys() {
+            *key_lens.entry(key.len()).or_default() += 1;
+        }
+        if key_lens != self.key_lens {
+            found.push(CacheInconsistency::KeyLengths {
+                detail: "key counts by length differ from the live keys".to_string(),
+            });
+        }
+        found
+    }
+
+    /// Panics unless `compacted`, built from this index by a compaction,
+    /// has the same live keys, bar those whose TTL has passed, each in a
+    /// record of the same length when the format did not change: no write
+    /// acknowledged before a swap may be lost or undone by it. Run on every
+    /// swap in debug builds.
+    pub(crate) fn check_compacted(&self, compacted: &Index, same_format: bool) {
+        for (key, log_index) in &self.live {
+            match compacted.live.get(key) {
+                Some(copy) => assert!(
+                    !same_format || copy.len == log_index.len,
+                    "compaction swapped in another record for {:?}",
+                    key
+                ),
+                None => assert!(self.is_expired(key), "compaction lost live {:?}", key),
+            }
+        }
+        for key in compacted.live.keys() {
+            assert!(
+                self.live.contains_key(key),
+                "compaction brought back {:?}",
+                key
+            );
+        }
+    }
+
+    pub(crate) fn history_entries(&self) -> usize {
+        self.history.values().map(VecDeque::len).sum()
+    }
+
+    /// Approximate heap bytes held by the history rings, keys included.
+    pub(crate) fn history_bytes(&self) -> usize {
+        self.history
+            .iter()
+            .map(|(k, ring)| {
+                k.len() + size_of::<VecDeque<LogIndex>>() + ring.len() * size_of::<LogIndex>()
+            })
+            .sum()
+    }
+}
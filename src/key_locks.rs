@@ -0,0 +1,52 @@
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::sync::{Mutex, MutexGuard};
+
+const STRIPES: usize = 64;
+
+/// Striped per-key write locks. Every write path holds the stripe of the key
+/// it modifies, so `update_many` can read several keys, compute, and write
+/// them back without another writer slipping in between.
+///
+/// Lock order: key stripes, then the file mutex, then the index lock.
+pub(crate) struct KeyLocks {
+    stripes: Vec<Mutex<()>>,
+    hasher: RandomState,
+}
+
+/// Holds the stripes of a set of keys until dropped.
+pub(crate) struct KeyGuard<'a> {
+    _guards: Vec<MutexGuard<'a, ()>>,
+}
+
+impl KeyLocks {
+    pub(crate) fn new() -> Self {
+        KeyLocks {
+            stripes: (0..STRIPES).map(|_| Mutex::new(())).collect(),
+            hasher: RandomState::new(),
+        }
+    }
+
+    pub(crate) fn lock(&self, key: &[u8]) -> KeyGuard<'_> {
+        self.lock_all(&[key])
+    }
+
+    /// Locks the stripes of every key in `keys`. Stripes are always taken in
+    /// ascending order and each at most once, so overlapping calls listing
+    /// keys in different orders cannot deadlock.
+    pub(crate) fn lock_all(&self, keys: &[&[u8]]) -> KeyGuard<'_> {
+        let mut stripes: Vec<usize> = keys
+            .iter()
+            .map(|key| self.hasher.hash_one(key) as usize % STRIPES)
+            .collect();
+        stripes.sort_unstable();
+        stripes.dedup();
+
+        KeyGuard {
+            _guards: stripes
+                .into_iter()
+                .map(|i| self.stripes[i].lock().unwrap())
+                .collect(),
+        }
+    }
+}
@@ -1,5 +1,77 @@
+mod access;
+mod archive;
+pub mod audit;
+pub mod batch;
+pub mod bucket;
+#[cfg(feature = "cache-audit")]
+pub mod cache_audit;
+pub mod clock;
+mod codec;
 pub mod constants;
+pub mod context;
+pub mod dump;
 pub mod engine;
+pub mod error;
+mod external_sort;
+pub mod format;
+pub mod format_info;
+mod framing;
+mod hamt;
+mod idle;
+mod index;
+mod key_locks;
+pub mod limits;
+mod lock_metrics;
+mod mirror;
+#[cfg(feature = "oplog-debug")]
+pub mod oplog;
+pub mod options;
+pub mod patch;
+mod read_limiter;
+mod readahead;
+mod reader_pool;
+pub mod retry;
+pub mod runtime;
+mod salvage;
+pub mod sample;
+pub mod scan;
+pub mod server;
+pub mod shared_snapshot;
+mod single_flight;
+mod slow_op;
+mod snapshot;
+pub mod stats;
+pub mod storage;
+mod syncer;
+#[cfg(feature = "testing")]
+pub mod testing;
 pub mod types;
+mod workers;
+pub mod workload;
+mod write_queue;
 
+pub use audit::{AuditRecord, AuditScan, PendingTombstones};
+pub use batch::WriteBatch;
+pub use bucket::{Bucket, Quota};
+#[cfg(feature = "cache-audit")]
+pub use cache_audit::CacheInconsistency;
+pub use clock::{Clock, SystemClock};
+pub use context::{OpContext, SetEvent};
 pub use engine::Engine;
+pub use error::EngineError;
+pub use limits::{Limit, LimitWarning, SoftLimit};
+pub use options::{
+    ConflictPolicy, Durability, Hook, KeyTransform, MirrorFailure, OpenMode, Options,
+    ReclaimOptions, RenameConflict, ThresholdSource, Validator,
+};
+pub use retry::RetryPolicy;
+pub use runtime::{KvRuntime, RuntimeStats};
+pub use scan::{KeysSnapshot, LiveScan};
+pub use shared_snapshot::SnapshotReader;
+pub use slow_op::{SlowOp, SlowOpDetail, SlowOpKind};
+pub use stats::{
+    BucketStats, CompactProgress, CompactionEstimate, CompactionReport, DiskForecast,
+    ExportProgress, IngestReport, LatencySnapshot, LoadReport, MirrorStatus, OverlapReport,
+    PrefixStats, ReadSource, ReloadReport, Stats,
+};
+pub use workload::{WorkloadReport, WorkloadSpec};
@@ -0,0 +1,148 @@
+//! Early warnings as the index nears `Options::max_index_entries` or
+//! `max_index_bytes`, so an operator can act before writes start failing
+//! with `EngineError::IndexFull`.
+
+use std::mem;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::options::{Hook, Options};
+
+/// A hard limit a soft limit can watch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Limit {
+    /// `Options::max_index_entries`, against `Stats::live_keys`.
+    IndexEntries,
+    /// `Options::max_index_bytes`, against `Stats::index_bytes`.
+    IndexBytes,
+}
+
+/// When a [`Limit`]'s warning is raised and cleared, as fractions of the
+/// hard limit. Clearing below a lower mark than raising keeps usage that
+/// hovers around `warn_at` from firing on every write.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SoftLimit {
+    /// Raise the warning once usage reaches this fraction.
+    pub warn_at: f64,
+    /// Clear a raised warning once usage falls below this fraction; must be
+    /// less than `warn_at`.
+    pub clear_at: f64,
+}
+
+impl Default for SoftLimit {
+    fn default() -> Self {
+        SoftLimit {
+            warn_at: 0.8,
+            clear_at: 0.75,
+        }
+    }
+}
+
+/// Passed to `Options::on_limit_warning` each time a warning is raised or
+/// cleared.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LimitWarning {
+    pub limit: Limit,
+    /// Usage when the warning changed state.
+    pub current: u64,
+    pub max: u64,
+    /// Whether the warning was raised (`true`) or cleared (`false`).
+    pub active: bool,
+}
+
+struct Watched {
+    limit: Limit,
+    soft: SoftLimit,
+    max: u64,
+    /// Only changed under the index write lock.
+    active: AtomicBool,
+}
+
+/// The soft limits an engine watches, and warnings not yet delivered.
+pub(crate) struct LimitWatch {
+    watched: Vec<Watched>,
+    /// Warnings are noted under the index lock and delivered after it is
+    /// released, so the hook may call back into the engine.
+    pending: Mutex<Vec<LimitWarning>>,
+    hook: Option<Hook<LimitWarning>>,
+}
+
+impl LimitWatch {
+    /// `None` unless a soft limit is set for a hard limit that is.
+    pub(crate) fn new(options: &Options) -> Option<Self> {
+        let watched: Vec<Watched> = options
+            .soft_limits
+            .iter()
+            .filter_map(|&(limit, soft)| {
+                let max = match limit {
+                    Limit::IndexEntries => options.max_index_entries? as u64,
+                    Limit::IndexBytes => options.max_index_bytes?,
+                };
+                Some(Watched {
+                    limit,
+                    soft,
+                    max,
+                    active: AtomicBool::new(false),
+                })
+            })
+            .collect();
+        if watched.is_empty() {
+            return None;
+        }
+        Some(LimitWatch {
+            watched,
+            pending: Mutex::new(Vec::new()),
+            hook: options.on_limit_warning.clone(),
+        })
+    }
+
+    /// Notes every warning that `live_keys` and `index_bytes` raise or
+    /// clear. Call with the index write lock held.
+    pub(crate) fn observe(&self, live_keys: u64, index_bytes: u64) {
+        for watched in &self.watched {
+            let current = match watched.limit {
+                Limit::IndexEntries => live_keys,
+                Limit::IndexBytes => index_bytes,
+            };
+            let fraction = current as f64 / watched.max.max(1) as f64;
+            let active = watched.active.load(Ordering::Relaxed);
+            let now_active = match active {
+                false => fraction >= watched.soft.warn_at,
+                true => fraction >= watched.soft.clear_at,
+            };
+            if now_active == active {
+                continue;
+            }
+            watched.active.store(now_active, Ordering::Relaxed);
+            if self.hook.is_some() {
+                self.pending.lock().unwrap().push(LimitWarning {
+                    limit: watched.limit,
+                    current,
+                    max: watched.max,
+                    active: now_active,
+                });
+            }
+        }
+    }
+
+    /// Delivers the warnings noted so far, oldest first. Call with no engine
+    /// lock held.
+    pub(crate) fn deliver(&self) {
+        let Some(hook) = &self.hook else {
+            return;
+        };
+        let pending = mem::take(&mut *self.pending.lock().unwrap());
+        for warning in &pending {
+            hook.call(warning);
+        }
+    }
+
+    /// The limits whose warning is raised right now.
+    pub(crate) fn active(&self) -> Vec<Limit> {
+        self.watched
+            .iter()
+            .filter(|w| w.active.load(Ordering::Relaxed))
+            .map(|w| w.limit)
+            .collect()
+    }
+}
@@ -0,0 +1,97 @@
+//! Contention counters for the engine's locks, behind the `lock-metrics`
+//! feature. Without it the counters are empty and every acquisition below
+//! is a plain `lock`, `read`, or `write`.
+
+#[cfg(feature = "lock-metrics")]
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+#[cfg(feature = "lock-metrics")]
+use std::time::Instant;
+
+#[cfg(feature = "lock-metrics")]
+use crate::stats::LockStats;
+
+/// Acquisitions of one lock, how many had to wait, and for how long.
+#[derive(Debug, Default)]
+pub(crate) struct LockCounters {
+    #[cfg(feature = "lock-metrics")]
+    acquisitions: AtomicU64,
+    #[cfg(feature = "lock-metrics")]
+    contended: AtomicU64,
+    #[cfg(feature = "lock-metrics")]
+    wait_nanos: AtomicU64,
+}
+
+impl LockCounters {
+    /// Whether waits are timed, so callers that time them anyway for
+    /// another purpose can share the clock reads.
+    pub(crate) const TIMED: bool = cfg!(feature = "lock-metrics");
+
+    pub(crate) fn lock<'a, T>(&self, mutex: &'a Mutex<T>) -> MutexGuard<'a, T> {
+        self.acquire(|| mutex.try_lock().ok(), || mutex.lock().unwrap())
+    }
+
+    pub(crate) fn read<'a, T>(&self, lock: &'a RwLock<T>) -> RwLockReadGuard<'a, T> {
+        self.acquire(|| lock.try_read().ok(), || lock.read().unwrap())
+    }
+
+    pub(crate) fn write<'a, T>(&self, lock: &'a RwLock<T>) -> RwLockWriteGuard<'a, T> {
+        self.acquire(|| lock.try_write().ok(), || lock.write().unwrap())
+    }
+
+    #[cfg(feature = "lock-metrics")]
+    fn acquire<G>(
+        &self,
+        try_acquire: impl FnOnce() -> Option<G>,
+        acquire: impl FnOnce() -> G,
+    ) -> G {
+        if let Some(guard) = try_acquire() {
+            self.uncontended();
+            return guard;
+        }
+        let start = Instant::now();
+        let guard = acquire();
+        self.contended(start.elapsed());
+        guard
+    }
+
+    #[cfg(not(feature = "lock-metrics"))]
+    #[inline(always)]
+    fn acquire<G>(
+        &self,
+        _try_acquire: impl FnOnce() -> Option<G>,
+        acquire: impl FnOnce() -> G,
+    ) -> G {
+        acquire()
+    }
+
+    /// Counts an acquisition that did not wait.
+    #[inline(always)]
+    pub(crate) fn uncontended(&self) {
+        #[cfg(feature = "lock-metrics")]
+        self.acquisitions.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts an acquisition that waited for `wait`.
+    #[inline(always)]
+    pub(crate) fn contended(&self, wait: std::time::Duration) {
+        #[cfg(feature = "lock-metrics")]
+        {
+            self.acquisitions.fetch_add(1, Ordering::Relaxed);
+            self.contended.fetch_add(1, Ordering::Relaxed);
+            self.wait_nanos
+                .fetch_add(wait.as_nanos() as u64, Ordering::Relaxed);
+        }
+        #[cfg(not(feature = "lock-metrics"))]
+        let _ = wait;
+    }
+
+    #[cfg(feature = "lock-metrics")]
+    pub(crate) fn snapshot(&self) -> LockStats {
+        LockStats {
+            acquisitions: self.acquisitions.load(Ordering::Relaxed),
+            contended: self.contended.load(Ordering::Relaxed),
+            wait_micros: self.wait_nanos.load(Ordering::Relaxed) / 1_000,
+        }
+    }
+}
@@ -1,55 +1,30 @@
-use actix_web::{App, HttpRequest, HttpResponse, HttpServer, Responder, web};
-use breakout1_kv_store::Engine;
-use serde::Deserialize;
-
-#[derive(Deserialize)]
-pub struct SetRequest {
-    key: String,
-    value: String,
-}
+use actix_web::{HttpServer, web};
+use breakout1_kv_store::server::{self, Auth};
+use breakout1_kv_store::{Engine, Options};
+use std::env;
 
+/// Configured through the environment: `KV_DATA`, the data file
+/// (`data.db`); `KV_ADDR`, the address to listen on (`127.0.0.1:8080`);
+/// and `KV_AUTH_TOKEN`, the bearer token every request must carry (none
+/// unless set).
 #[tokio::main]
 async fn main() -> std::io::Result<()> {
-    let db = web::Data::new(Engine::load("data.db")?);
-
-    HttpServer::new(move || {
-        App::new()
-            .app_data(db.clone())
-            .route("/", web::get().to(home))
-            .route("/set", web::post().to(set_handler))
-            .route("/get/{key}", web::get().to(get_handler))
-            .route("/del/{key}", web::delete().to(del_handler))
-    })
-    .bind("127.0.0.1:8080")?
-    .run()
-    .await
-}
-
-async fn home(_req: HttpRequest) -> impl Responder {
-    "Welcome!".to_string()
-}
-
-async fn set_handler(req: web::Json<SetRequest>, engine: web::Data<Engine>) -> impl Responder {
-    let op = engine.set(req.key.as_bytes(), req.value.as_bytes());
-    match op {
-        Ok(_) => HttpResponse::Ok().body("OK"),
-        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
-    }
-}
-
-async fn get_handler(req: web::Path<String>, engine: web::Data<Engine>) -> impl Responder {
-    let op = engine.get(req.as_bytes());
-    match op {
-        Ok(Some(val)) => HttpResponse::Ok().body(val),
-        Ok(None) => HttpResponse::NotFound().body("Key is not found"),
-        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
-    }
-}
+    let options = Options {
+        value_hashes: true,
+        ..Options::default()
+    };
+    let path = env::var("KV_DATA").unwrap_or_else(|_| "data.db".to_string());
+    let addr = env::var("KV_ADDR").unwrap_or_else(|_| "127.0.0.1:8080".to_string());
+    let db = web::Data::new(Engine::load_with_options(path, options)?);
+    let auth = web::Data::new(Auth::new(
+        env::var("KV_AUTH_TOKEN")
+            .ok()
+            .filter(|token| !token.is_empty())
+            .map(String::into_bytes),
+    ));
 
-async fn del_handler(req: web::Path<String>, engine: web::Data<Engine>) -> impl Responder {
-    let op = engine.del(req.as_bytes());
-    match op {
-        Ok(_) => HttpResponse::Ok().body("OK"),
-        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
-    }
+    HttpServer::new(move || server::app(db.clone(), auth.clone()))
+        .bind(addr)?
+        .run()
+        .await
 }
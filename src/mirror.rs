@@ -0,0 +1,215 @@
+//! The second copy of the data file kept under `Options::mirror_path`.
+//!
+//! The mirror is the data file byte for byte: every append and header
+//! change is written to it at the same offset, right after the data file,
+//! under the same file mutex. Whole files are only ever copied onto it
+//! through a temporary file renamed over it, at load or reload when it no
+//! longer matches, and after each compaction. So at any moment it is either
+//! the data file as of some earlier append, possibly with a torn last
+//! record that a load truncates, or a complete copy: always loadable on its
+//! own with `Engine::load`.
+//!
+//! Once a mirror write fails, nothing more is written to it until a reload
+//! copies the data file over it again.
+
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::error::EngineError;
+use crate::options::{Hook, MirrorFailure};
+use crate::stats::MirrorStatus;
+use crate::storage::{OpenMode, Storage, StorageFile};
+
+/// Bytes at the end of both files compared to tell whether a mirror left
+/// from an earlier run still matches.
+const TAIL_CHECK: u64 = 4096;
+
+pub(crate) struct Mirror {
+    path: PathBuf,
+    on_failure: MirrorFailure,
+    on_error: Option<Hook<io::Error>>,
+    state: Mutex<State>,
+    bytes_written: AtomicU64,
+    failures: AtomicU64,
+    copies: AtomicU64,
+}
+
+struct State {
+    /// `None` until the first sync, and once mirroring has stopped.
+    file: Option<Box<dyn StorageFile>>,
+    /// Why mirroring stopped.
+    stopped: Option<String>,
+}
+
+impl Mirror {
+    pub(crate) fn new(
+        path: PathBuf,
+        on_failure: MirrorFailure,
+        on_error: Option<Hook<io::Error>>,
+    ) -> Self {
+        Mirror {
+            path,
+            on_failure,
+            on_error,
+            state: Mutex::new(State {
+                file: None,
+                stopped: Some("not synced yet".to_string()),
+            }),
+            bytes_written: AtomicU64::new(0),
+            failures: AtomicU64::new(0),
+            copies: AtomicU64::new(0),
+        }
+    }
+
+    /// Brings the mirror level with the first `len` bytes of the data file
+    /// at `primary`, keeping what is there if it already matches and
+    /// copying the data file over it otherwise, and restarts mirroring.
+    /// With `force`, always copies. Call with the file mutex held.
+    pub(crate) fn sync_from(
+        &self,
+        storage: &dyn Storage,
+        primary: &Path,
+        len: u64,
+        force: bool,
+    ) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.file = None;
+        let synced = (|| {
+            if !force && self.matches(storage, primary, len)? {
+                return storage.open(&self.path, OpenMode::ReadWrite);
+            }
+            let tmp = self.path.with_extension("mirror-tmp");
+            if let Err(e) = storage.clone_file(primary, &tmp, len) {
+                let _ = storage.remove_file(&tmp);
+                return Err(e);
+            }
+            storage.rename(&tmp, &self.path)?;
+            self.copies.fetch_add(1, Ordering::Relaxed);
+            storage.open(&self.path, OpenMode::ReadWrite)
+        })();
+        match synced {
+            Ok(file) => {
+                state.file = Some(file);
+                state.stopped = None;
+                Ok(())
+            }
+            Err(e) => self.fail(&mut state, e),
+        }
+    }
+
+    /// Whether the mirror has the data file's length and the same header
+    /// and last bytes.
+    fn matches(&self, storage: &dyn Storage, primary: &Path, len: u64) -> io::Result<bool> {
+        let mut mirror = match storage.open(&self.path, OpenMode::Read) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e),
+        };
+        if mirror.len()? != len {
+            return Ok(false);
+        }
+        let mut primary = storage.open(primary, OpenMode::Read)?;
+        let head = TAIL_CHECK.min(len);
+        let tail_start = len.saturating_sub(TAIL_CHECK);
+        for (pos, n) in [(0, head), (tail_start, len - tail_start)] {
+            let mut a = vec![0u8; n as usize];
+            let mut b = vec![0u8; n as usize];
+            primary.seek(SeekFrom::Start(pos))?;
+            primary.read_exact(&mut a)?;
+            mirror.seek(SeekFrom::Start(pos))?;
+            mirror.read_exact(&mut b)?;
+            if a != b {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Fails with `EngineError::MirrorFailed` if mirroring has stopped
+    /// under `MirrorFailure::Fatal`. Checked before every append.
+    pub(crate) fn check_writable(&self) -> io::Result<()> {
+        if self.on_failure != MirrorFailure::Fatal {
+            return Ok(());
+        }
+        match &self.state.lock().unwrap().stopped {
+            Some(reason) => Err(EngineError::MirrorFailed(reason.clone()).into()),
+            None => Ok(()),
+        }
+    }
+
+    /// Writes `buf` at `pos`, as was just done to the data file. Fails only
+    /// under `MirrorFailure::Fatal`, and then the caller must undo its own
+    /// write. Call with the file mutex held.
+    pub(crate) fn write_at(&self, pos: u64, buf: &[u8]) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let Some(file) = &mut state.file else {
+            return Ok(());
+        };
+        let written = file
+            .seek(SeekFrom::Start(pos))
+            .and_then(|_| file.write_all(buf))
+            .and_then(|()| file.flush());
+        match written {
+            Ok(()) => {
+                self.bytes_written
+                    .fetch_add(buf.len() as u64, Ordering::Relaxed);
+                Ok(())
+            }
+            Err(e) => {
+                // Best effort: a load truncates a torn tail anyway.
+                let _ = file.set_len(pos);
+                self.fail(&mut state, e)
+            }
+        }
+    }
+
+    /// Fsyncs the mirror, if mirroring. A failure is handled like a failed
+    /// write.
+    pub(crate) fn sync(&self) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let Some(file) = &state.file else {
+            return Ok(());
+        };
+        match file.sync_all() {
+            Ok(()) => Ok(()),
+            Err(e) => self.fail(&mut state, e),
+        }
+    }
+
+    /// Stops mirroring over `err`: reports it to `on_error` and, under
+    /// `MirrorFailure::Fatal`, returns it as `EngineError::MirrorFailed`.
+    fn fail(&self, state: &mut State, err: io::Error) -> io::Result<()> {
+        let reason = format!("{}: {}", self.path.display(), err);
+        state.file = None;
+        state.stopped = Some(reason.clone());
+        self.failures.fetch_add(1, Ordering::Relaxed);
+        if let Some(on_error) = &self.on_error {
+            on_error.call(&err);
+        }
+        match self.on_failure {
+            MirrorFailure::Fatal => Err(EngineError::MirrorFailed(reason).into()),
+            MirrorFailure::Degrade => Ok(()),
+        }
+    }
+
+    pub(crate) fn failures(&self) -> u64 {
+        self.failures.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn is_stopped(&self) -> bool {
+        self.state.lock().unwrap().stopped.is_some()
+    }
+
+    pub(crate) fn status(&self) -> MirrorStatus {
+        MirrorStatus {
+            path: self.path.clone(),
+            on_failure: self.on_failure,
+            stopped: self.state.lock().unwrap().stopped.clone(),
+            bytes_written: self.bytes_written.load(Ordering::Relaxed),
+            failures: self.failures(),
+            copies: self.copies.load(Ordering::Relaxed),
+        }
+    }
+}
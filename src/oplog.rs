@@ -0,0 +1,314 @@
+//! Operation journal for reproducing bugs (feature `oplog-debug`).
+//!
+//! An [`OpJournal`] set in `Options::op_journal` records every public call
+//! that reads or writes a key, in the order the calls took effect, to a file
+//! of its own. [`replay`] re-executes a journal single-threaded against a
+//! fresh store, and [`diff`] lists the keys on which two stores disagree, so
+//! a damaged store can be compared with what its history says it should
+//! hold.
+//!
+//! By default values are redacted: only their lengths are journaled, and
+//! replay writes zero-filled values of the same length.
+//!
+//! ```text
+//! [4 bytes: magic "KVOJ"]
+//! record*: [4 bytes: length u32 LE][wincode-serialized JournalRecord]
+//! ```
+
+use std::cell::Cell;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use wincode::{SchemaRead, SchemaWrite};
+
+use crate::batch::WriteBatch;
+use crate::engine::{Engine, now_millis};
+use crate::types::{DataFileEntry, EntryKind};
+
+pub const JOURNAL_MAGIC: [u8; 4] = *b"KVOJ";
+
+#[derive(SchemaWrite, SchemaRead, Debug, Clone, Copy, PartialEq, Eq)]
+#[wincode(tag_encoding = "u8")]
+pub enum JournalOp {
+    Get,
+    Set,
+    Del,
+    SoftDel,
+    Restore,
+    ExpireSoftDeleted,
+    Compact,
+    /// Opens an atomic group (`apply_batch` or `update_many`); `value_len`
+    /// holds how many `Set`/`Del` records follow in it.
+    Batch,
+}
+
+#[derive(SchemaWrite, SchemaRead, Debug, Clone, PartialEq, Eq)]
+pub struct JournalRecord {
+    pub op: JournalOp,
+    pub key: Vec<u8>,
+    pub value_len: u64,
+    /// The value itself, only with [`ValueLogging::Full`].
+    pub value: Option<Vec<u8>>,
+    /// Time to live of a batched `Set`, in ms.
+    pub ttl_ms: Option<u64>,
+    /// Small per-process id of the calling thread.
+    pub thread: u64,
+    /// ms since the epoch.
+    pub tstamp: i64,
+    /// Tag and id of the `Engine::with_context` context the call ran in.
+    pub context: Option<(String, u64)>,
+}
+
+/// How much of each value the journal keeps.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ValueLogging {
+    /// Lengths only.
+    #[default]
+    LengthOnly,
+    /// Full values, for stores holding nothing sensitive.
+    Full,
+}
+
+/// An append-only journal file; see the module docs.
+pub struct OpJournal {
+    writer: Mutex<BufWriter<File>>,
+    values: ValueLogging,
+    /// First write error; journaling stops after it and `flush` returns it.
+    error: Mutex<Option<io::Error>>,
+}
+
+impl OpJournal {
+    /// Creates (or truncates) the journal at `path`.
+    pub fn create(path: impl AsRef<Path>, values: ValueLogging) -> io::Result<Self> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(&JOURNAL_MAGIC)?;
+        Ok(OpJournal {
+            writer: Mutex::new(writer),
+            values,
+            error: Mutex::new(None),
+        })
+    }
+
+    /// Writes buffered records out, or returns the first error journaling
+    /// hit.
+    pub fn flush(&self) -> io::Result<()> {
+        if let Some(e) = self.error.lock().unwrap().take() {
+            return Err(e);
+        }
+        self.writer.lock().unwrap().flush()
+    }
+
+    pub(crate) fn record(&self, op: JournalOp, key: &[u8], value: Option<&[u8]>) {
+        self.record_ttl(op, key, value, None);
+    }
+
+    fn record_ttl(&self, op: JournalOp, key: &[u8], value: Option<&[u8]>, ttl_ms: Option<u64>) {
+        let record = JournalRecord {
+            op,
+            key: key.to_vec(),
+            value_len: value.map_or(0, |v| v.len() as u64),
+            value: value
+                .filter(|_| self.values == ValueLogging::Full)
+                .map(<[u8]>::to_vec),
+            ttl_ms,
+            thread: thread_id(),
+            tstamp: now_millis(),
+            context: journal_context(),
+        };
+        self.append(&record);
+    }
+
+    /// Journals an atomic group of writes: a `Batch` record, then a `Set`
+    /// or `Del` per entry.
+    pub(crate) fn record_group(&self, entries: &[DataFileEntry]) {
+        self.append(&JournalRecord {
+            op: JournalOp::Batch,
+            key: Vec::new(),
+            value_len: entries.len() as u64,
+            value: None,
+            ttl_ms: None,
+            thread: thread_id(),
+            tstamp: now_millis(),
+            context: journal_context(),
+        });
+        for entry in entries {
+            if entry.kind == EntryKind::Tombstone {
+                self.record(JournalOp::Del, &entry.key, None);
+                continue;
+            }
+            let ttl_ms = entry
+                .expires_at()
+                .map(|at| at.saturating_sub(entry.tstamp).max(0) as u64);
+            let value = entry.clone().into_value();
+            self.record_ttl(JournalOp::Set, &entry.key, value.as_deref(), ttl_ms);
+        }
+    }
+
+    fn append(&self, record: &JournalRecord) {
+        let mut error = self.error.lock().unwrap();
+        if error.is_some() {
+            return;
+        }
+        let result = wincode::serialize(record)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))
+            .and_then(|data| {
+                let mut writer = self.writer.lock().unwrap();
+                writer.write_all(&(data.len() as u32).to_le_bytes())?;
+                writer.write_all(&data)
+            });
+        if let Err(e) = result {
+            *error = Some(e);
+        }
+    }
+}
+
+impl std::fmt::Debug for OpJournal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("OpJournal")
+            .field("values", &self.values)
+            .finish()
+    }
+}
+
+impl Drop for OpJournal {
+    fn drop(&mut self) {
+        let _ = self.writer.get_mut().unwrap().flush();
+    }
+}
+
+fn journal_context() -> Option<(String, u64)> {
+    crate::context::current().map(|context| (context.tag, context.id))
+}
+
+fn thread_id() -> u64 {
+    static NEXT: AtomicU64 = AtomicU64::new(1);
+    thread_local! {
+        static ID: Cell<u64> = const { Cell::new(0) };
+    }
+    ID.with(|id| {
+        if id.get() == 0 {
+            id.set(NEXT.fetch_add(1, Ordering::Relaxed));
+        }
+        id.get()
+    })
+}
+
+/// Reads every record of the journal at `path`. A torn final record is
+/// ignored.
+pub fn read_journal(path: impl AsRef<Path>) -> io::Result<Vec<JournalRecord>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut magic = [0u8; 4];
+    reader.read_exact(&mut magic)?;
+    if magic != JOURNAL_MAGIC {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "not an operation journal",
+        ));
+    }
+
+    let mut records = Vec::new();
+    loop {
+        let mut len_buf = [0u8; 4];
+        let mut data = Vec::new();
+        let read = reader.read_exact(&mut len_buf).and_then(|()| {
+            data.resize(u32::from_le_bytes(len_buf) as usize, 0);
+            reader.read_exact(&mut data)
+        });
+        match read {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(records),
+            Err(e) => return Err(e),
+        }
+        let record = wincode::deserialize(&data)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+        records.push(record);
+    }
+}
+
+/// What [`replay`] did.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ReplayReport {
+    /// Records re-executed, reads included.
+    pub replayed: u64,
+    /// Writes that failed on replay, e.g. a `restore` whose window passed.
+    pub failed: u64,
+}
+
+/// Re-executes the journal at `path` single-threaded against `store`,
+/// which should start empty. Redacted values are replayed as zero bytes of
+/// the journaled length.
+pub fn replay(path: impl AsRef<Path>, store: &Engine) -> io::Result<ReplayReport> {
+    let mut report = ReplayReport::default();
+    let mut records = read_journal(path)?.into_iter();
+    while let Some(record) = records.next() {
+        report.replayed += 1;
+        let result = match record.op {
+            JournalOp::Get => store.get_key(&record.key).map(drop),
+            JournalOp::Set => store.set_key(&record.key, &replay_value(&record)),
+            JournalOp::Del => store.del_key(&record.key),
+            JournalOp::SoftDel => store.soft_del(&record.key),
+            JournalOp::Restore => store.restore(&record.key),
+            JournalOp::ExpireSoftDeleted => store.expire_soft_deleted().map(drop),
+            JournalOp::Compact => store.compact(),
+            JournalOp::Batch => {
+                let mut batch = WriteBatch::new();
+                for op in records.by_ref().take(record.value_len as usize) {
+                    report.replayed += 1;
+                    match (op.op, op.ttl_ms) {
+                        (JournalOp::Del, _) => batch.delete(&op.key),
+                        (_, Some(ttl)) => batch.put_with_ttl(
+                            &op.key,
+                            &replay_value(&op),
+                            Duration::from_millis(ttl),
+                        ),
+                        (_, None) => batch.put(&op.key, &replay_value(&op)),
+                    };
+                }
+                store.apply_batch(&batch)
+            }
+        };
+        if result.is_err() {
+            report.failed += 1;
+        }
+    }
+    Ok(report)
+}
+
+fn replay_value(record: &JournalRecord) -> Vec<u8> {
+    record
+        .value
+        .clone()
+        .unwrap_or_else(|| vec![0; record.value_len as usize])
+}
+
+/// Keys that are live in only one of `expected` and `actual`, or whose
+/// values differ: in full with [`ValueLogging::Full`], by length otherwise.
+pub fn diff(expected: &Engine, actual: &Engine, values: ValueLogging) -> io::Result<Vec<Vec<u8>>> {
+    let mut keys = expected.live_keys();
+    keys.extend(actual.live_keys());
+    keys.sort();
+    keys.dedup();
+
+    let mut differing = Vec::new();
+    for key in keys {
+        let (a, b) = (live_value(expected, &key)?, live_value(actual, &key)?);
+        let same = match values {
+            ValueLogging::Full => a == b,
+            ValueLogging::LengthOnly => a.map(|v| v.len()) == b.map(|v| v.len()),
+        };
+        if !same {
+            differing.push(key);
+        }
+    }
+    Ok(differing)
+}
+
+/// Reads without journaling, so diffing a journaled store leaves its
+/// journal alone.
+fn live_value(store: &Engine, key: &[u8]) -> io::Result<Option<Vec<u8>>> {
+    Ok(store.read_live(key)?.map(|record| record.value))
+}
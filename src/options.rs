@@ -0,0 +1,498 @@
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::clock::{Clock, SystemClock};
+use crate::constants::{
+    DEFAULT_READAHEAD_BYTES, DEFAULT_SHUTDOWN_TIMEOUT, DEFAULT_SOFT_DELETE_WINDOW,
+};
+use crate::context::SetEvent;
+use crate::limits::{Limit, LimitWarning, SoftLimit};
+#[cfg(feature = "oplog-debug")]
+use crate::oplog::OpJournal;
+use crate::retry::RetryPolicy;
+use crate::runtime::KvRuntime;
+use crate::slow_op::SlowOp;
+use crate::storage::{FsStorage, Storage};
+
+/// When appended records are fsynced.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Durability {
+    /// Leave flushing to the OS; call `Engine::sync` to force it.
+    #[default]
+    Buffered,
+    /// A background thread fsyncs every `period` plus up to `jitter`, when
+    /// there were writes since the last sync.
+    Interval { period: Duration, jitter: Duration },
+}
+
+/// What an engine does when a write to its `Options::mirror_path` fails.
+/// Either way mirroring stops until `Engine::reload` copies the data file
+/// over the mirror again.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MirrorFailure {
+    /// Fail the write that hit it, undone in the data file too, and every
+    /// write after it, with `EngineError::MirrorFailed`.
+    Fatal,
+    /// Report it to `Options::on_mirror_error` and carry on with the data
+    /// file alone.
+    #[default]
+    Degrade,
+}
+
+/// Where the compaction threshold in effect came from; see
+/// `Engine::threshold_source`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ThresholdSource {
+    /// `Options::compact_threshold`, for this load only.
+    Options,
+    /// `Engine::set_compact_threshold`, now or in an earlier run.
+    Operator,
+    /// Doubled by a compaction that barely shrank the file.
+    AutoTuned,
+    /// A non-default value in a header that does not say who set it: one
+    /// written before header flags existed, or by a format without them.
+    Unmarked,
+    /// `DEFAULT_COMPACT_THRESHOLD`.
+    #[default]
+    Default,
+}
+
+/// How much `load` checks while replaying the log into the index. Records
+/// carry no checksums, so the deepest check is a full decode of each one.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OpenMode {
+    /// Reads only the fields the index needs and skips over value bytes.
+    /// Damage inside a value goes unnoticed until a read decodes the record.
+    Fast,
+    /// Decodes every record and fails the load at the first one that does
+    /// not decode. A torn tail is truncated.
+    #[default]
+    Standard,
+    /// Decodes every record, as `Engine::verify` does, and keeps going past
+    /// bad ones: they are skipped, counted in the `LoadReport`, and flag the
+    /// store as corrupted.
+    Verify,
+}
+
+/// What a write carrying its own timestamp does when the key's stored value
+/// is newer. A timestamp equal to the stored one is never a conflict.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    /// Write it and make it current regardless.
+    #[default]
+    AlwaysAccept,
+    /// Refuse it with `EngineError::StaleWrite`.
+    RejectOlder,
+    /// Log it as history but leave the newer value current.
+    KeepNewest,
+}
+
+/// What `Engine::rename_prefix` does with a source key whose destination
+/// already exists.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RenameConflict {
+    /// Move it over the existing key.
+    Overwrite,
+    /// Leave both keys as they are.
+    Skip,
+    /// Refuse the whole rename with `EngineError::RenameConflicts`, before
+    /// anything is written.
+    Fail,
+}
+
+/// A user callback stored in [`Options`].
+pub struct Hook<A: ?Sized>(Arc<dyn Fn(&A) + Send + Sync>);
+
+impl<A: ?Sized> Hook<A> {
+    pub fn new(f: impl Fn(&A) + Send + Sync + 'static) -> Self {
+        Hook(Arc::new(f))
+    }
+
+    pub(crate) fn call(&self, arg: &A) {
+        (self.0)(arg)
+    }
+}
+
+impl<A: ?Sized> Clone for Hook<A> {
+    fn clone(&self) -> Self {
+        Hook(Arc::clone(&self.0))
+    }
+}
+
+impl<A: ?Sized> fmt::Debug for Hook<A> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Hook(..)")
+    }
+}
+
+/// Checks a `(key, value)` pair before it is written; see
+/// [`Options::validate`]. An `Err` carries why the value was refused.
+pub struct Validator(Arc<ValidatorFn>);
+
+type ValidatorFn = dyn Fn(&[u8], &[u8]) -> Result<(), String> + Send + Sync;
+
+impl Validator {
+    pub fn new(f: impl Fn(&[u8], &[u8]) -> Result<(), String> + Send + Sync + 'static) -> Self {
+        Validator(Arc::new(f))
+    }
+
+    pub(crate) fn call(&self, key: &[u8], value: &[u8]) -> Result<(), String> {
+        (self.0)(key, value)
+    }
+}
+
+impl Clone for Validator {
+    fn clone(&self) -> Self {
+        Validator(Arc::clone(&self.0))
+    }
+}
+
+impl fmt::Debug for Validator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Validator(..)")
+    }
+}
+
+/// Rewrites the key of every public operation before it reaches the index
+/// or the log; see [`Options::key_transform`]. Its `id` is recorded in the
+/// data file's header, so a store only opens with the transform it was
+/// created with. Ids up to 255 are kept for the transforms built in here;
+/// 0 means no transform and is refused.
+pub struct KeyTransform {
+    id: u32,
+    f: Arc<KeyTransformFn>,
+}
+
+type KeyTransformFn = dyn Fn(&[u8]) -> Vec<u8> + Send + Sync;
+
+impl KeyTransform {
+    /// Id of [`KeyTransform::ascii_lowercase`].
+    pub const ASCII_LOWERCASE: u32 = 1;
+
+    pub fn new(id: u32, f: impl Fn(&[u8]) -> Vec<u8> + Send + Sync + 'static) -> Self {
+        KeyTransform { id, f: Arc::new(f) }
+    }
+
+    /// Lowercases ASCII letters and leaves every other byte as it is.
+    pub fn ascii_lowercase() -> Self {
+        KeyTransform::new(Self::ASCII_LOWERCASE, <[u8]>::to_ascii_lowercase)
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub(crate) fn apply(&self, key: &[u8]) -> Vec<u8> {
+        (self.f)(key)
+    }
+}
+
+impl Clone for KeyTransform {
+    fn clone(&self) -> Self {
+        KeyTransform {
+            id: self.id,
+            f: Arc::clone(&self.f),
+        }
+    }
+}
+
+impl fmt::Debug for KeyTransform {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "KeyTransform({})", self.id)
+    }
+}
+
+/// What `Engine::reclaim_idle` releases. Everything, by default.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReclaimOptions {
+    /// Close the pooled read handles.
+    pub readers: bool,
+    /// Shrink the write queue's and in-flight read table's spare capacity.
+    pub buffers: bool,
+}
+
+impl Default for ReclaimOptions {
+    fn default() -> Self {
+        ReclaimOptions {
+            readers: true,
+            buffers: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// How long a soft-deleted key stays recoverable through `restore`.
+    pub soft_delete_window: Duration,
+    /// How many prior versions of each key to keep readable through
+    /// `previous_versions`. Zero disables history.
+    pub history_depth: usize,
+    /// Auto-compact at this file size, overriding the threshold in the file
+    /// header for this load without writing it there. Like a threshold from
+    /// `Engine::set_compact_threshold`, it is never auto-tuned. `None` uses
+    /// the header's.
+    pub compact_threshold: Option<u64>,
+    /// Compact once this many tombstones have accumulated, regardless of
+    /// file size. `None` disables the trigger.
+    pub tombstone_compact_count: Option<u64>,
+    /// Compact once tombstones make up at least this fraction (0.0..=1.0) of
+    /// the log's bytes, regardless of file size. `None` disables the trigger.
+    pub tombstone_compact_ratio: Option<f64>,
+    /// Grow the data file in steps of this many bytes instead of one record
+    /// at a time, to limit fragmentation. `None` disables pre-allocation.
+    pub preallocate_chunk: Option<u64>,
+    /// Cap on physical reads (`get`, `get_range`, history and dump reads)
+    /// running at once. Writes and compaction are never limited. `None`
+    /// means unlimited.
+    pub max_concurrent_reads: Option<usize>,
+    pub durability: Durability,
+    /// Key prefixes whose live key count and value bytes are maintained
+    /// incrementally; see `Engine::tracked_prefix_stats`.
+    pub tracked_prefixes: Vec<Vec<u8>>,
+    /// Called with any error the background sync thread hits, since there is
+    /// no caller to return it to.
+    pub on_sync_error: Option<Hook<io::Error>>,
+    /// Called with the key and value of every put (`set`, `set_with_tstamp`,
+    /// `update_many`, `apply_batch`, bucket `set`) before anything is
+    /// written; a rejection fails the write with
+    /// `EngineError::ValidationFailed` and writes nothing. Never called for
+    /// deletes or internal records. Bucketed keys are passed without their
+    /// bucket prefix.
+    pub validate: Option<Validator>,
+    /// Validators for individual buckets, by bucket name, used instead of
+    /// `validate` for keys in those buckets.
+    pub bucket_validators: Vec<(Vec<u8>, Validator)>,
+    /// Store values at least this many bytes long once per distinct content:
+    /// `set` writes the value as a blob keyed by its hash and the key as a
+    /// reference to it. Blobs are reclaimed once no live key references them.
+    /// Cannot be combined with `history_depth`. `None` disables dedup.
+    pub dedup_min_value_len: Option<usize>,
+    /// Move the expiry of each `WriteBatch::put_with_ttl` by up to this
+    /// fraction of its TTL either way (0.05 is ±5%), so keys written
+    /// together with one TTL do not all expire in the same instant. The
+    /// offset depends only on the key and the TTL, so a retried write gets
+    /// the same one. 0.0 disables jitter.
+    pub ttl_jitter: f64,
+    /// Record `get` and `set` latencies into the histograms reported by
+    /// `stats()`. Costs two clock reads per call, so it is off by default.
+    pub latency_histograms: bool,
+    /// Report `get`, `set`, and `del` calls taking at least this long to
+    /// `on_slow_op`. `None` disables the check, which then costs nothing.
+    pub slow_op_threshold: Option<Duration>,
+    pub on_slow_op: Option<Hook<SlowOp>>,
+    /// Called after each `set`, `set_indexed`, bucket `set`, and accepted
+    /// `set_with_tstamp`, once the engine's locks are released. Sees the
+    /// `Engine::with_context` context the set ran in.
+    pub on_set: Option<Hook<SetEvent>>,
+    /// Directory for compaction's temporary output, e.g. a larger volume than
+    /// the data file's. When it is on another filesystem the result is
+    /// copied next to the data file before the final rename. `None` writes
+    /// it beside the data file.
+    pub compaction_dir: Option<PathBuf>,
+    /// Abandon an automatic compaction still copying records after this
+    /// long, leaving the data file untouched, so the write that triggered it
+    /// is not held up; counted in `stats().compactions_aborted`. The next
+    /// write to trip a trigger tries again. `compact()` ignores it. `None`
+    /// (the default) never abandons.
+    pub auto_compact_deadline: Option<Duration>,
+    /// Start an automatic compaction at most once per this long, by
+    /// `clock`, whatever the triggers say. A trigger tripped sooner is put
+    /// off, counted in `stats().compactions_deferred`, and checked again by
+    /// the first write once the interval has passed, even a delete. One
+    /// abandoned at `auto_compact_deadline` or short of space does not
+    /// count. `compact()` ignores it. `None` (the default) never holds one back.
+    pub min_compaction_interval: Option<Duration>,
+    /// Refuse writes and compaction once corruption has been detected (a
+    /// record that fails to decode), until `Engine::verify` passes or
+    /// `Engine::acknowledge_corruption` is called. Reads keep working.
+    pub fail_closed: bool,
+    /// Read handles opened up front and kept for reuse; up to twice this
+    /// many are kept once reads have opened more. Zero opens a handle for
+    /// every physical read and closes it afterwards.
+    pub reader_pool_size: usize,
+    /// Bytes `dump`, `export_shm_snapshot`, `verify`, and `audit_scan` read
+    /// from the data file at once, so a pass over the log takes a few large
+    /// reads instead of one per record. Zero reads record by record.
+    pub readahead_bytes: usize,
+    /// Paranoid reads: a pooled read handle left idle for longer than this
+    /// re-reads the header magic, and checks it sees the file at least as
+    /// long as the record, before serving a read; a handle that fails either
+    /// check is discarded and a fresh one opened. For storage that can serve
+    /// stale pages through old handles, such as a SAN after failover.
+    /// `None` (the default) never re-checks.
+    pub paranoid_reads: Option<Duration>,
+    /// Release pooled read handles and spare buffers, as
+    /// `Engine::reclaim_idle` does, once no read or write has touched the
+    /// file for this long; the next one re-acquires them. Checked in the
+    /// background, on `runtime` if set. `None` (the default) never reclaims.
+    pub idle_after: Option<Duration>,
+    /// Time source for `idle_after`, `track_access`, and
+    /// `min_compaction_interval`.
+    pub clock: Arc<dyn Clock>,
+    /// Shared runtime to run background work on and to bound pooled read
+    /// handles across engines. `None` gives the engine its own threads.
+    pub runtime: Option<Arc<KvRuntime>>,
+    /// How long `Engine::close` waits, in all, for the background sync and
+    /// idle threads to stop. Threads still running then are left behind
+    /// and `close` fails with `EngineError::ShutdownTimedOut` once the rest
+    /// of the shutdown is done. Dropping the engine waits for them however
+    /// long they take.
+    pub shutdown_timeout: Duration,
+    /// Most live keys the index may hold. A write that would add a key past
+    /// it fails with `EngineError::IndexFull`; overwrites and deletes still
+    /// work. A log already past the limit still loads.
+    pub max_index_entries: Option<usize>,
+    /// Like `max_index_entries`, but bounding the estimated heap size of the
+    /// live-key index (`Stats::index_bytes`).
+    pub max_index_bytes: Option<u64>,
+    /// Rewrite every key given to a public operation, such as lowercasing
+    /// it or normalizing its Unicode, before it reaches the index or the
+    /// log, so keys differing only in what the transform erases name one
+    /// entry. Prefixes and range bounds are rewritten too, which suits
+    /// transforms that map each character on its own. Scans and exports
+    /// return keys as stored, transformed. The transform's id is written to
+    /// the header of a new file, and a file is refused with
+    /// `EngineError::KeyTransformMismatch` unless loaded with the same one,
+    /// or with none if it was created without. Needs a KVS6 or later file.
+    pub key_transform: Option<KeyTransform>,
+    /// Longest key, in bytes, a put accepts; a longer one fails with
+    /// `EngineError::KeyTooLarge` and writes nothing. Deletes are never
+    /// refused, so a key stored before the limit was set can still be
+    /// removed. Bucketed keys count their bucket prefix. `None` means no
+    /// limit.
+    pub max_key_size: Option<usize>,
+    /// Soft limits on `max_index_entries` and `max_index_bytes`: usage
+    /// crossing one, in either direction, is reported once to
+    /// `on_limit_warning` and shown in `Stats::limit_warnings`. A soft limit
+    /// on a hard limit that is not set is ignored.
+    pub soft_limits: Vec<(Limit, SoftLimit)>,
+    /// Called after the operation that raised or cleared a soft-limit
+    /// warning has released the engine's locks.
+    pub on_limit_warning: Option<Hook<LimitWarning>>,
+    /// Keep the live keys in key order as well, for
+    /// `Engine::first_key_in_range`, `last_key_in_range`, and
+    /// `is_range_empty`. Roughly doubles the index's key memory, which
+    /// `max_index_bytes` counts.
+    pub ordered_index: bool,
+    /// Treat the engine's own keys, such as bucket quotas, as live keys:
+    /// count them in `Stats::live_keys` and write them from
+    /// `Engine::export_sorted_keys`, `export_shm_snapshot`, and `sample`.
+    /// `dump` always writes them, so a restore keeps quotas and swapped
+    /// buckets; `Stats::internal_keys` counts them either way.
+    pub include_internal_keys: bool,
+    /// Keep a 64-bit content hash of every live value in the index, for
+    /// `Engine::value_hash` without reading the data file. Costs a SHA-1 per
+    /// write and makes `load` read whole records even in `OpenMode::Fast`.
+    pub value_hashes: bool,
+    /// Count reads of each key and note when it was last read, for
+    /// `Engine::hottest_keys` and `coldest_keys`. Approximate, held in
+    /// fixed-size sketches in memory (`Stats::access_tracker_bytes`), and
+    /// lost on restart; nothing is written to the log.
+    pub track_access: bool,
+    /// How thoroughly `load` checks the log; see `Engine::load_report`.
+    pub open_mode: OpenMode,
+    /// How `set_with_tstamp` resolves a write older than the stored value.
+    pub conflict_policy: ConflictPolicy,
+    /// How `load_dump` resolves a record older than the stored value.
+    pub import_conflict_policy: ConflictPolicy,
+    /// Claim the store for writing with a new epoch in the header, and fail
+    /// writes with `EngineError::Fenced` once another writer claims a newer
+    /// one. The header is re-read at most this often; `Duration::ZERO`
+    /// checks before every append. Needs a KVS4 or later file.
+    pub fencing: Option<Duration>,
+    /// Never erase a record: compaction is off (`compact()` fails with
+    /// `EngineError::AuditMode` and nothing compacts automatically), so
+    /// `Engine::audit_scan` can replay every write ever accepted. The file
+    /// header records whether every open so far used audit mode; the first
+    /// open without it clears that for good. Needs a KVS6 or later file.
+    pub audit_mode: bool,
+    /// Record this id as the origin of every key this engine writes, so the
+    /// records of several writers can be told apart once they meet in one
+    /// file; see `Engine::set_with_origin` and `KeyStat::origin`. Records
+    /// without an origin take no space for one. Needs a KVS7 or later file.
+    pub writer_id: Option<[u8; 16]>,
+    /// Keep a second copy of the data file here, written through on every
+    /// append and copied over after every compaction, so a store whose data
+    /// file is lost or damaged can be loaded from it instead; see
+    /// `Engine::mirror_status`. Put it on another disk to be of any use.
+    pub mirror_path: Option<PathBuf>,
+    pub mirror_failure: MirrorFailure,
+    /// Called with every error writing, syncing, or copying to the mirror.
+    pub on_mirror_error: Option<Hook<io::Error>>,
+    /// The cold tier: where `Engine::archive` moves values to, leaving a
+    /// small stub in the data file that `get` follows. Put it on cheaper,
+    /// slower storage. A store holding stubs fails to load with
+    /// `EngineError::ArchiveMissing` unless this names their file.
+    pub archive_path: Option<PathBuf>,
+    /// Filesystem backend for every file the engine opens.
+    pub storage: Arc<dyn Storage>,
+    /// Retry reads, writes, and fsyncs on `storage` that fail with a
+    /// transient error. `None` fails them at once.
+    pub io_retry: Option<RetryPolicy>,
+    /// Journal every public read and write to this, for `oplog::replay`.
+    #[cfg(feature = "oplog-debug")]
+    pub op_journal: Option<Arc<OpJournal>>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            soft_delete_window: DEFAULT_SOFT_DELETE_WINDOW,
+            history_depth: 0,
+            compact_threshold: None,
+            tombstone_compact_count: None,
+            tombstone_compact_ratio: None,
+            preallocate_chunk: None,
+            max_concurrent_reads: None,
+            durability: Durability::Buffered,
+            tracked_prefixes: Vec::new(),
+            dedup_min_value_len: None,
+            ttl_jitter: 0.0,
+            latency_histograms: false,
+            on_sync_error: None,
+            validate: None,
+            bucket_validators: Vec::new(),
+            slow_op_threshold: None,
+            on_slow_op: None,
+            on_set: None,
+            compaction_dir: None,
+            fail_closed: false,
+            reader_pool_size: 4,
+            readahead_bytes: DEFAULT_READAHEAD_BYTES,
+            paranoid_reads: None,
+            auto_compact_deadline: None,
+            min_compaction_interval: None,
+            idle_after: None,
+            clock: Arc::new(SystemClock),
+            runtime: None,
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+            max_index_entries: None,
+            max_index_bytes: None,
+            max_key_size: None,
+            key_transform: None,
+            soft_limits: Vec::new(),
+            on_limit_warning: None,
+            ordered_index: false,
+            include_internal_keys: false,
+            value_hashes: false,
+            track_access: false,
+            open_mode: OpenMode::Standard,
+            conflict_policy: ConflictPolicy::AlwaysAccept,
+            import_conflict_policy: ConflictPolicy::KeepNewest,
+            fencing: None,
+            audit_mode: false,
+            writer_id: None,
+            mirror_path: None,
+            mirror_failure: MirrorFailure::Degrade,
+            on_mirror_error: None,
+            archive_path: None,
+            storage: Arc::new(FsStorage),
+            io_retry: None,
+            #[cfg(feature = "oplog-debug")]
+            op_journal: None,
+        }
+    }
+}
@@ -0,0 +1,233 @@
+//! Patch files: a reviewable, textual list of sets and deletes that
+//! `Engine::apply_patch` applies as one write batch.
+//!
+//! A patch is UTF-8 text, one entry per line, fields separated by
+//! whitespace. Keys and values are standard padded base64, with `-` for an
+//! empty value. Blank lines and lines starting with `#` are ignored.
+//!
+//! ```text
+//! kvpatch 1
+//! set <key> <value> [expect=<prior>]
+//! del <key> [expect=<prior>]
+//! ```
+//!
+//! `<prior>` is what the key must hold for the patch to apply: `absent`, or
+//! the 16 hex digits of `Engine::hash_value` of its current value. An entry
+//! without one applies whatever the key holds.
+
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Read, Write};
+
+/// First line of every patch.
+pub const PATCH_HEADER: &str = "kvpatch 1";
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// What a key holds, as far as a patch can tell: nothing, or a value with
+/// this `Engine::hash_value`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Prior {
+    Absent,
+    Hash(u64),
+}
+
+impl fmt::Display for Prior {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Prior::Absent => write!(f, "absent"),
+            Prior::Hash(hash) => write!(f, "{:016x}", hash),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PatchOp {
+    Set(Vec<u8>),
+    Del,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PatchEntry {
+    pub key: Vec<u8>,
+    pub op: PatchOp,
+    /// What the key must hold for the patch to apply, if anything.
+    pub expect: Option<Prior>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Patch {
+    pub entries: Vec<PatchEntry>,
+}
+
+impl Patch {
+    /// Parses a patch. Fails with `InvalidData` naming the first line that
+    /// is not a valid entry.
+    pub fn read(reader: impl Read) -> io::Result<Patch> {
+        let mut lines = BufReader::new(reader).lines();
+        match lines.next().transpose()? {
+            Some(line) if line.trim_end() == PATCH_HEADER => {}
+            _ => {
+                return Err(invalid(format!(
+                    "invalid patch: missing `{}` header",
+                    PATCH_HEADER
+                )));
+            }
+        }
+        let mut entries = Vec::new();
+        for (i, line) in lines.enumerate() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let entry = parse_entry(line)
+                .ok_or_else(|| invalid(format!("invalid patch: line {}: {}", i + 2, line)))?;
+            entries.push(entry);
+        }
+        Ok(Patch { entries })
+    }
+
+    /// Writes the patch in the format `read` parses.
+    pub fn write_to(&self, mut writer: impl Write) -> io::Result<()> {
+        writeln!(writer, "{}", PATCH_HEADER)?;
+        for entry in &self.entries {
+            match &entry.op {
+                PatchOp::Set(value) => write!(
+                    writer,
+                    "set {} {}",
+                    encode_base64(&entry.key),
+                    match value.is_empty() {
+                        true => "-".to_string(),
+                        false => encode_base64(value),
+                    }
+                )?,
+                PatchOp::Del => write!(writer, "del {}", encode_base64(&entry.key))?,
+            }
+            if let Some(expect) = entry.expect {
+                write!(writer, " expect={}", expect)?;
+            }
+            writeln!(writer)?;
+        }
+        writer.flush()
+    }
+}
+
+/// What applying a patch did, or with `dry_run` would have done.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PatchReport {
+    /// Whether the patch was written. False for a dry run, and for a patch
+    /// refused because an entry's expected prior did not match.
+    pub applied: bool,
+    /// One per entry, in patch order.
+    pub outcomes: Vec<PatchOutcome>,
+}
+
+impl PatchReport {
+    /// Entries whose key did not hold what they expected.
+    pub fn mismatches(&self) -> usize {
+        self.outcomes
+            .iter()
+            .filter(|outcome| !outcome.matched)
+            .count()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PatchOutcome {
+    /// The key as stored.
+    pub key: Vec<u8>,
+    /// What the key held when the patch was checked.
+    pub found: Prior,
+    /// Whether `found` is what the entry expected. Entries that expect
+    /// nothing always match.
+    pub matched: bool,
+    pub change: PatchChange,
+}
+
+/// What an entry does to its key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PatchChange {
+    /// A set of a key that held nothing.
+    Created,
+    /// A set of a key that held a value.
+    Replaced,
+    /// A delete of a key that held a value.
+    Deleted,
+    /// A delete of a key that held nothing; nothing is written for it.
+    AlreadyAbsent,
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn parse_entry(line: &str) -> Option<PatchEntry> {
+    let mut fields = line.split_ascii_whitespace();
+    let op = fields.next()?;
+    let key = decode_base64(fields.next()?)?;
+    let op = match op {
+        "set" => match fields.next()? {
+            "-" => PatchOp::Set(Vec::new()),
+            value => PatchOp::Set(decode_base64(value)?),
+        },
+        "del" => PatchOp::Del,
+        _ => return None,
+    };
+    let expect = match fields.next() {
+        Some(field) => Some(parse_prior(field.strip_prefix("expect=")?)?),
+        None => None,
+    };
+    if key.is_empty() || fields.next().is_some() {
+        return None;
+    }
+    Some(PatchEntry { key, op, expect })
+}
+
+fn parse_prior(prior: &str) -> Option<Prior> {
+    match prior {
+        "absent" => Some(Prior::Absent),
+        hash if hash.len() == 16 => u64::from_str_radix(hash, 16).ok().map(Prior::Hash),
+        _ => None,
+    }
+}
+
+fn encode_base64(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let mut group = [0u8; 3];
+        group[..chunk.len()].copy_from_slice(chunk);
+        let bits = u32::from_be_bytes([0, group[0], group[1], group[2]]);
+        for i in 0..4 {
+            match i <= chunk.len() {
+                true => out.push(BASE64[(bits >> (18 - 6 * i)) as usize & 63] as char),
+                false => out.push('='),
+            }
+        }
+    }
+    out
+}
+
+/// Decodes padded standard base64; `None` if `text` is not that.
+fn decode_base64(text: &str) -> Option<Vec<u8>> {
+    let text = text.as_bytes();
+    if !text.len().is_multiple_of(4) {
+        return None;
+    }
+    let mut out = Vec::with_capacity(text.len() / 4 * 3);
+    for (n, chunk) in text.chunks(4).enumerate() {
+        let last = n == text.len() / 4 - 1;
+        let padding = chunk.iter().rev().take_while(|&&c| c == b'=').count();
+        if padding > 2 || (padding > 0 && !last) {
+            return None;
+        }
+        let mut bits = 0u32;
+        for &c in &chunk[..4 - padding] {
+            let digit = BASE64.iter().position(|&b| b == c)? as u32;
+            bits = (bits << 6) | digit;
+        }
+        bits <<= 6 * padding as u32;
+        let bytes = bits.to_be_bytes();
+        out.extend_from_slice(&bytes[1..4 - padding]);
+    }
+    Some(out)
+}
@@ -0,0 +1,45 @@
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// A counting semaphore bounding how many physical reads run at once.
+pub(crate) struct ReadLimiter {
+    available: Mutex<usize>,
+    released: Condvar,
+}
+
+/// Returns its permit to the limiter when dropped.
+pub(crate) struct ReadPermit<'a> {
+    limiter: &'a ReadLimiter,
+}
+
+impl ReadLimiter {
+    pub(crate) fn new(permits: usize) -> Self {
+        ReadLimiter {
+            available: Mutex::new(permits.max(1)),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Blocks until a permit is free. Returns the permit and how long the
+    /// caller waited for it (zero if one was free immediately).
+    pub(crate) fn acquire(&self) -> (ReadPermit<'_>, Duration) {
+        let mut available = self.available.lock().unwrap();
+        let mut waited = Duration::ZERO;
+        if *available == 0 {
+            let start = Instant::now();
+            while *available == 0 {
+                available = self.released.wait(available).unwrap();
+            }
+            waited = start.elapsed();
+        }
+        *available -= 1;
+        (ReadPermit { limiter: self }, waited)
+    }
+}
+
+impl Drop for ReadPermit<'_> {
+    fn drop(&mut self) {
+        *self.limiter.available.lock().unwrap() += 1;
+        self.limiter.released.notify_one();
+    }
+}
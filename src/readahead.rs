@@ -0,0 +1,59 @@
+//! Buffered reads for the scans that visit much of the log: `dump` and
+//! `export_shm_snapshot` read records through a [`Readahead`], and `verify`
+//! and `audit_scan` read the log front to back through a buffer of the
+//! same size, `Options::readahead_bytes`.
+//!
+//! A [`Readahead`] keeps one window of the file. A read inside it costs no
+//! syscall. A read at or a little past its end is taken as part of a
+//! forward sweep and refills the window from there with one large read, so
+//! records overwritten or deleted since they were written are simply
+//! skipped over. Any other read, backwards or a long jump ahead, reads
+//! exactly its record, so a scan in an order unrelated to the file's costs
+//! no more than reading record by record.
+
+use std::io::{self, Read, Seek, SeekFrom};
+
+pub(crate) struct Readahead<F> {
+    file: F,
+    window: Vec<u8>,
+    /// File offset of `window[0]`.
+    start: u64,
+    capacity: usize,
+    /// Where the log ends; a refill never reads past it.
+    end: u64,
+}
+
+impl<F: Read + Seek> Readahead<F> {
+    /// Reads `file` up to `end` in windows of `capacity` bytes; 0 reads
+    /// every record on its own.
+    pub(crate) fn new(file: F, capacity: usize, end: u64) -> Self {
+        Readahead {
+            file,
+            window: Vec::new(),
+            start: 0,
+            capacity,
+            end,
+        }
+    }
+
+    /// The `len` bytes at `pos`.
+    pub(crate) fn read(&mut self, pos: u64, len: u64) -> io::Result<&[u8]> {
+        let window_end = self.start + self.window.len() as u64;
+        if pos >= self.start && pos + len <= window_end {
+            let offset = (pos - self.start) as usize;
+            return Ok(&self.window[offset..offset + len as usize]);
+        }
+
+        let capacity = self.capacity as u64;
+        let forward = pos >= self.start && pos.saturating_sub(window_end) < capacity;
+        let fill = match forward && len <= capacity {
+            true => capacity.min(self.end.saturating_sub(pos)).max(len),
+            false => len,
+        };
+        self.file.seek(SeekFrom::Start(pos))?;
+        self.window.resize(fill as usize, 0);
+        self.file.read_exact(&mut self.window)?;
+        self.start = pos;
+        Ok(&self.window[..len as usize])
+    }
+}
@@ -0,0 +1,216 @@
+use std::collections::BTreeMap;
+use std::ops::{Deref, DerefMut};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use crate::engine::FileHandle;
+use crate::lock_metrics::LockCounters;
+use crate::runtime::KvRuntime;
+use crate::storage::{OpenMode, Storage};
+
+/// Idle read handles kept open between reads, so a `get` usually skips the
+/// `open` call. Handles held here count against the runtime's budget, if any.
+///
+/// Each handle is tagged with the engine generation it was opened under, and
+/// only handed out for reads under the same generation: a lock-free `get`
+/// can race a file swap, and must not go on to use the old file afterwards.
+/// A handle from an older generation is closed when it comes back instead
+/// of being pooled, since it holds a replaced, possibly unlinked, file open.
+pub(crate) struct ReaderPool {
+    /// Each handle with the time it was put back.
+    idle: Mutex<Vec<Idle>>,
+    idle_lock: LockCounters,
+    /// Generation of the file as of the last `reset`; only handles from it
+    /// are pooled. Changed under the `idle` lock.
+    current: AtomicU64,
+    /// Handles open right now, pooled or not.
+    open: Arc<OpenReaders>,
+    /// Handles opened up front and after every file swap.
+    size: usize,
+    runtime: Option<Arc<KvRuntime>>,
+}
+
+struct Idle {
+    reader: Reader,
+    since: Instant,
+}
+
+/// Open handles by generation.
+#[derive(Default)]
+struct OpenReaders(Mutex<BTreeMap<u64, usize>>);
+
+/// A read handle on the data file as it was in one generation, counted in
+/// [`ReaderPool::open_readers`] until it is dropped.
+pub(crate) struct Reader {
+    file: FileHandle,
+    generation: u64,
+    open: Arc<OpenReaders>,
+}
+
+impl Reader {
+    pub(crate) fn generation(&self) -> u64 {
+        self.generation
+    }
+}
+
+impl Deref for Reader {
+    type Target = FileHandle;
+
+    fn deref(&self) -> &FileHandle {
+        &self.file
+    }
+}
+
+impl DerefMut for Reader {
+    fn deref_mut(&mut self) -> &mut FileHandle {
+        &mut self.file
+    }
+}
+
+impl Drop for Reader {
+    fn drop(&mut self) {
+        let mut open = self.open.0.lock().unwrap();
+        if let Some(count) = open.get_mut(&self.generation) {
+            *count -= 1;
+            if *count == 0 {
+                open.remove(&self.generation);
+            }
+        }
+    }
+}
+
+impl ReaderPool {
+    pub(crate) fn new(size: usize, runtime: Option<Arc<KvRuntime>>) -> Self {
+        ReaderPool {
+            idle: Mutex::new(Vec::new()),
+            idle_lock: LockCounters::default(),
+            current: AtomicU64::new(0),
+            open: Arc::new(OpenReaders::default()),
+            size,
+            runtime,
+        }
+    }
+
+    /// Opens a new handle on `path`, counted under `generation`.
+    pub(crate) fn open(
+        &self,
+        storage: &dyn Storage,
+        path: &Path,
+        generation: u64,
+    ) -> std::io::Result<Reader> {
+        let file = storage.open(path, OpenMode::Read)?;
+        *self.open.0.lock().unwrap().entry(generation).or_default() += 1;
+        Ok(Reader {
+            file,
+            generation,
+            open: Arc::clone(&self.open),
+        })
+    }
+
+    /// An idle handle opened under `generation`, and the time it went idle.
+    /// Handles from other generations are closed on the way.
+    pub(crate) fn take(&self, generation: u64) -> Option<(Reader, Instant)> {
+        let mut idle = self.idle_lock.lock(&self.idle);
+        while let Some(entry) = idle.pop() {
+            self.unreserve();
+            if entry.reader.generation == generation {
+                return Some((entry.reader, entry.since));
+            }
+        }
+        None
+    }
+
+    /// Keeps `reader` for reuse, or closes it if it is from a generation
+    /// before the last `reset`, the pool holds twice its size already, or
+    /// the runtime budget is spent.
+    pub(crate) fn put(&self, reader: Reader) {
+        let mut idle = self.idle_lock.lock(&self.idle);
+        if reader.generation == self.current.load(Ordering::Acquire)
+            && idle.len() < self.size * 2
+            && self.reserve()
+        {
+            idle.push(Idle {
+                reader,
+                since: Instant::now(),
+            });
+        }
+    }
+
+    /// Closes every idle handle and opens `size` fresh ones on `path` for
+    /// `generation`, after which handles from earlier generations are closed
+    /// as they come back. Call after the data file is replaced.
+    pub(crate) fn reset(&self, storage: &dyn Storage, path: &Path, generation: u64) {
+        let mut idle = self.idle_lock.lock(&self.idle);
+        self.current.store(generation, Ordering::Release);
+        self.release(&mut idle);
+        while idle.len() < self.size && self.reserve() {
+            match self.open(storage, path, generation) {
+                Ok(reader) => idle.push(Idle {
+                    reader,
+                    since: Instant::now(),
+                }),
+                Err(_) => {
+                    self.unreserve();
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Handles open right now, pooled or serving a read, as
+    /// `(generation, count)` in generation order.
+    pub(crate) fn open_readers(&self) -> Vec<(u64, usize)> {
+        let open = self.open.0.lock().unwrap();
+        open.iter()
+            .map(|(&generation, &count)| (generation, count))
+            .collect()
+    }
+
+    /// Closes every idle handle, returning how many; reads open fresh ones
+    /// as they need them.
+    pub(crate) fn reclaim(&self) -> usize {
+        let mut idle = self.idle_lock.lock(&self.idle);
+        let closed = idle.len();
+        self.release(&mut idle);
+        idle.shrink_to_fit();
+        closed
+    }
+
+    /// Idle handles held right now.
+    pub(crate) fn len(&self) -> usize {
+        self.idle_lock.lock(&self.idle).len()
+    }
+
+    #[cfg(feature = "lock-metrics")]
+    pub(crate) fn lock_stats(&self) -> crate::stats::LockStats {
+        self.idle_lock.snapshot()
+    }
+
+    fn reserve(&self) -> bool {
+        self.runtime
+            .as_ref()
+            .is_none_or(|runtime| runtime.reserve_reader())
+    }
+
+    fn unreserve(&self) {
+        if let Some(runtime) = &self.runtime {
+            runtime.release_readers(1);
+        }
+    }
+
+    fn release(&self, idle: &mut Vec<Idle>) {
+        if let Some(runtime) = &self.runtime {
+            runtime.release_readers(idle.len());
+        }
+        idle.clear();
+    }
+}
+
+impl Drop for ReaderPool {
+    fn drop(&mut self) {
+        let mut idle = std::mem::take(self.idle.get_mut().unwrap());
+        self.release(&mut idle);
+    }
+}
@@ -0,0 +1,227 @@
+//! Retries of transient I/O errors (`Options::io_retry`).
+//!
+//! [`RetryingStorage`] wraps the configured backend and retries failed reads,
+//! writes, and fsyncs whose error kind is on the policy's allow-list, with
+//! exponential backoff. A `write_all` that has already put some of its bytes
+//! down is never retried: the error is returned and the engine's torn-write
+//! handling (the next append overwrites from the old end of the log) deals
+//! with the partial record.
+
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use crate::storage::{CloneMethod, OpenMode, Storage, StorageFile};
+
+/// Longest single backoff, however many attempts have failed.
+const MAX_BACKOFF: Duration = Duration::from_secs(1);
+
+/// How transient I/O errors are retried.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Attempts per call, the first included; 1 disables retries.
+    pub max_attempts: u32,
+    /// Wait before the first retry; each later retry waits twice as long as
+    /// the one before, up to one second.
+    pub backoff: Duration,
+    /// Error kinds worth retrying. Anything else fails at once.
+    pub retry_on: Vec<io::ErrorKind>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 4,
+            backoff: Duration::from_millis(10),
+            retry_on: vec![
+                io::ErrorKind::Interrupted,
+                io::ErrorKind::WouldBlock,
+                io::ErrorKind::TimedOut,
+                io::ErrorKind::ResourceBusy,
+                io::ErrorKind::StaleNetworkFileHandle,
+            ],
+        }
+    }
+}
+
+impl RetryPolicy {
+    fn retries(&self, err: &io::Error) -> bool {
+        self.retry_on.contains(&err.kind())
+    }
+
+    fn delay(&self, retry: u32) -> Duration {
+        self.backoff
+            .saturating_mul(1 << retry.min(16))
+            .min(MAX_BACKOFF.max(self.backoff))
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct RetryCounters {
+    /// Calls repeated after a transient error.
+    pub(crate) retries: AtomicU64,
+    /// Calls that still failed with a transient error after the last attempt.
+    pub(crate) give_ups: AtomicU64,
+}
+
+#[derive(Debug, Clone)]
+struct Retrier {
+    policy: Arc<RetryPolicy>,
+    counters: Arc<RetryCounters>,
+}
+
+impl Retrier {
+    /// Runs `op` until it succeeds, fails with an error that is not
+    /// retried, or runs out of attempts.
+    fn run<T>(&self, mut op: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+        let mut attempt = 1;
+        loop {
+            match op() {
+                Err(e) if self.policy.retries(&e) => {
+                    if attempt >= self.policy.max_attempts {
+                        self.counters.give_ups.fetch_add(1, Ordering::Relaxed);
+                        return Err(e);
+                    }
+                    thread::sleep(self.policy.delay(attempt - 1));
+                    self.counters.retries.fetch_add(1, Ordering::Relaxed);
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+/// A [`Storage`] whose files retry transient errors; see the module docs.
+#[derive(Debug)]
+pub(crate) struct RetryingStorage {
+    inner: Arc<dyn Storage>,
+    retrier: Retrier,
+}
+
+impl RetryingStorage {
+    pub(crate) fn new(
+        inner: Arc<dyn Storage>,
+        policy: RetryPolicy,
+        counters: Arc<RetryCounters>,
+    ) -> Self {
+        RetryingStorage {
+            inner,
+            retrier: Retrier {
+                policy: Arc::new(policy),
+                counters,
+            },
+        }
+    }
+}
+
+impl Storage for RetryingStorage {
+    fn open(&self, path: &Path, mode: OpenMode) -> io::Result<Box<dyn StorageFile>> {
+        Ok(Box::new(RetryingFile {
+            inner: self.inner.open(path, mode)?,
+            retrier: self.retrier.clone(),
+        }))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.inner.rename(from, to)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        self.inner.remove_file(path)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        self.inner.create_dir_all(path)
+    }
+
+    fn sync_dir(&self, path: &Path) -> io::Result<()> {
+        self.retrier.run(|| self.inner.sync_dir(path))
+    }
+
+    fn available_space(&self, path: &Path) -> io::Result<Option<u64>> {
+        self.inner.available_space(path)
+    }
+
+    fn same_filesystem(&self, a: &Path, b: &Path) -> io::Result<Option<bool>> {
+        self.inner.same_filesystem(a, b)
+    }
+
+    fn clone_file(&self, from: &Path, to: &Path, len: u64) -> io::Result<CloneMethod> {
+        self.inner.clone_file(from, to, len)
+    }
+}
+
+struct RetryingFile {
+    inner: Box<dyn StorageFile>,
+    retrier: Retrier,
+}
+
+impl Read for RetryingFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.retrier.run(|| self.inner.read(buf))
+    }
+}
+
+impl Write for RetryingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.retrier.run(|| self.inner.write(buf))
+    }
+
+    /// Retries only until the first byte is down; see the module docs.
+    fn write_all(&mut self, mut buf: &[u8]) -> io::Result<()> {
+        let mut emitted = false;
+        while !buf.is_empty() {
+            let written = if emitted {
+                self.inner.write(buf)?
+            } else {
+                self.write(buf)?
+            };
+            if written == 0 {
+                return Err(io::ErrorKind::WriteZero.into());
+            }
+            emitted = true;
+            buf = &buf[written..];
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl Seek for RetryingFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+impl StorageFile for RetryingFile {
+    fn sync_all(&self) -> io::Result<()> {
+        self.retrier.run(|| self.inner.sync_all())
+    }
+
+    fn set_len(&self, len: u64) -> io::Result<()> {
+        self.inner.set_len(len)
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        self.inner.len()
+    }
+
+    fn allocate(&self, len: u64) -> io::Result<()> {
+        self.inner.allocate(len)
+    }
+
+    fn lock(&self) -> io::Result<()> {
+        self.inner.lock()
+    }
+
+    fn unlock(&self) -> io::Result<()> {
+        self.inner.unlock()
+    }
+}
@@ -0,0 +1,263 @@
+//! A background runtime shared by many engines in one process.
+//!
+//! By default every engine runs its own background thread (for example the
+//! `Durability::Interval` syncer) and keeps its own pool of read handles. With
+//! thousands of engines that adds up to thousands of threads and descriptors.
+//! Engines opened with `Options::runtime` instead schedule their background
+//! work on the runtime's single worker thread, and their pooled read handles
+//! count against one process-wide budget.
+
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// A recurring task. Returns how long to wait before running it again, or
+/// `None` to stop.
+type Job = Box<dyn FnMut() -> Option<Duration> + Send>;
+
+/// Snapshot of a runtime's accounting; see [`KvRuntime::stats`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RuntimeStats {
+    /// Engines currently open on this runtime.
+    pub engines: usize,
+    /// Background threads owned by the runtime. Always 1.
+    pub threads: usize,
+    /// Recurring jobs currently scheduled.
+    pub jobs: usize,
+    /// Idle read handles held by all engines' pools.
+    pub pooled_readers: usize,
+    pub max_pooled_readers: Option<usize>,
+}
+
+pub struct KvRuntime {
+    shared: Arc<Shared>,
+    max_pooled_readers: Option<usize>,
+    pooled_readers: AtomicUsize,
+    engines: AtomicUsize,
+    worker: Option<JoinHandle<()>>,
+}
+
+#[derive(Default)]
+struct Shared {
+    state: Mutex<SchedulerState>,
+    wake: Condvar,
+}
+
+#[derive(Default)]
+struct SchedulerState {
+    queue: BinaryHeap<Reverse<(Instant, u64)>>,
+    jobs: HashMap<u64, Job>,
+    next_id: u64,
+    /// The job the worker is executing, taken out of `jobs` meanwhile.
+    running: Option<u64>,
+    /// Set when the running job is cancelled mid-run.
+    running_cancelled: bool,
+    stop: bool,
+}
+
+impl KvRuntime {
+    /// Starts a runtime whose engines may keep at most `max_pooled_readers`
+    /// idle read handles between them. `None` leaves the pools unbounded
+    /// (each engine still caps its own).
+    pub fn new(max_pooled_readers: Option<usize>) -> io::Result<Arc<Self>> {
+        let shared = Arc::new(Shared::default());
+        let worker_shared = Arc::clone(&shared);
+        let worker = thread::Builder::new()
+            .name("kv-runtime".into())
+            .spawn(move || run(&worker_shared))?;
+
+        Ok(Arc::new(KvRuntime {
+            shared,
+            max_pooled_readers,
+            pooled_readers: AtomicUsize::new(0),
+            engines: AtomicUsize::new(0),
+            worker: Some(worker),
+        }))
+    }
+
+    pub fn stats(&self) -> RuntimeStats {
+        RuntimeStats {
+            engines: self.engines.load(Ordering::Relaxed),
+            threads: 1,
+            jobs: self.shared.state.lock().unwrap().jobs_scheduled(),
+            pooled_readers: self.pooled_readers.load(Ordering::Relaxed),
+            max_pooled_readers: self.max_pooled_readers,
+        }
+    }
+
+    /// Runs `job` on the worker thread after `delay`, and again after each
+    /// delay it returns, until it returns `None` or the handle is dropped.
+    pub(crate) fn schedule(
+        &self,
+        delay: Duration,
+        job: impl FnMut() -> Option<Duration> + Send + 'static,
+    ) -> JobHandle {
+        let mut state = self.shared.state.lock().unwrap();
+        let id = state.next_id;
+        state.next_id += 1;
+        state.jobs.insert(id, Box::new(job));
+        state.queue.push(Reverse((Instant::now() + delay, id)));
+        drop(state);
+        self.shared.wake.notify_all();
+
+        JobHandle {
+            shared: Arc::clone(&self.shared),
+            id,
+            cancelled: false,
+        }
+    }
+
+    /// Counts an engine as open until the returned guard is dropped.
+    pub(crate) fn register_engine(self: &Arc<Self>) -> EngineSlot {
+        self.engines.fetch_add(1, Ordering::Relaxed);
+        EngineSlot {
+            runtime: Arc::clone(self),
+        }
+    }
+
+    /// Claims room for one more idle read handle. Returns false when the
+    /// budget is exhausted, in which case the handle should be closed.
+    pub(crate) fn reserve_reader(&self) -> bool {
+        let Some(max) = self.max_pooled_readers else {
+            self.pooled_readers.fetch_add(1, Ordering::Relaxed);
+            return true;
+        };
+        self.pooled_readers
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| {
+                (n < max).then_some(n + 1)
+            })
+            .is_ok()
+    }
+
+    pub(crate) fn release_readers(&self, n: usize) {
+        self.pooled_readers.fetch_sub(n, Ordering::AcqRel);
+    }
+}
+
+impl Drop for KvRuntime {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().stop = true;
+        self.shared.wake.notify_all();
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+impl std::fmt::Debug for KvRuntime {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("KvRuntime")
+            .field("stats", &self.stats())
+            .finish()
+    }
+}
+
+impl SchedulerState {
+    fn jobs_scheduled(&self) -> usize {
+        self.jobs.len() + usize::from(self.running.is_some() && !self.running_cancelled)
+    }
+}
+
+fn run(shared: &Shared) {
+    let mut state = shared.state.lock().unwrap();
+    loop {
+        if state.stop {
+            return;
+        }
+
+        let Some(&Reverse((due, id))) = state.queue.peek() else {
+            state = shared.wake.wait(state).unwrap();
+            continue;
+        };
+        let now = Instant::now();
+        if due > now {
+            state = shared.wake.wait_timeout(state, due - now).unwrap().0;
+            continue;
+        }
+
+        state.queue.pop();
+        let Some(mut job) = state.jobs.remove(&id) else {
+            continue;
+        };
+        state.running = Some(id);
+        drop(state);
+
+        let next = job();
+
+        state = shared.state.lock().unwrap();
+        if let (Some(delay), false) = (next, state.running_cancelled) {
+            state.jobs.insert(id, job);
+            state.queue.push(Reverse((Instant::now() + delay, id)));
+        }
+        state.running = None;
+        state.running_cancelled = false;
+        shared.wake.notify_all();
+    }
+}
+
+/// Cancels its job when dropped. If the job is running at that moment, the
+/// drop waits for it to finish, so the job never runs after the handle is
+/// gone, unless a bounded [`JobHandle::cancel`] gave up on it first.
+pub(crate) struct JobHandle {
+    shared: Arc<Shared>,
+    id: u64,
+    /// Set by `cancel`, after which dropping does nothing more.
+    cancelled: bool,
+}
+
+impl JobHandle {
+    /// Cancels the job, waiting for a run in progress to finish until
+    /// `deadline` if one is given. Returns whether no run is left in
+    /// progress; a run that outlasts the deadline is still never repeated.
+    /// Only the first call waits.
+    pub(crate) fn cancel(&mut self, deadline: Option<Instant>) -> bool {
+        if std::mem::replace(&mut self.cancelled, true) {
+            return true;
+        }
+        let mut state = self.shared.state.lock().unwrap();
+        if state.jobs.remove(&self.id).is_some() {
+            return true;
+        }
+        if state.running == Some(self.id) {
+            state.running_cancelled = true;
+            while state.running == Some(self.id) {
+                state = match deadline {
+                    None => self.shared.wake.wait(state).unwrap(),
+                    Some(deadline) => {
+                        let now = Instant::now();
+                        if now >= deadline {
+                            return false;
+                        }
+                        self.shared
+                            .wake
+                            .wait_timeout(state, deadline - now)
+                            .unwrap()
+                            .0
+                    }
+                };
+            }
+        }
+        true
+    }
+}
+
+impl Drop for JobHandle {
+    fn drop(&mut self) {
+        self.cancel(None);
+    }
+}
+
+/// Keeps an engine counted in [`RuntimeStats::engines`].
+pub(crate) struct EngineSlot {
+    runtime: Arc<KvRuntime>,
+}
+
+impl Drop for EngineSlot {
+    fn drop(&mut self) {
+        self.runtime.engines.fetch_sub(1, Ordering::Relaxed);
+    }
+}
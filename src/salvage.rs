@@ -0,0 +1,179 @@
+//! Record recovery from a damaged data file, behind
+//! `Engine::ingest_raw_log`. A load also uses [`record_at`] to tell a torn
+//! tail from a damaged length prefix with records after it.
+//!
+//! Where `OpenMode::Verify` can only skip a bad record whose length prefix
+//! is intact, this scan also survives a damaged prefix: past a record that
+//! does not decode it tries the next one its length points at, and failing
+//! that moves forward a byte at a time until records decode again. A
+//! candidate only counts if it re-encodes to exactly the bytes it was read
+//! from, which keeps stray bytes in a damaged region from passing as
+//! records.
+
+use std::collections::HashMap;
+use std::io::{self, Read};
+use std::path::Path;
+
+use crate::codec;
+use crate::constants::{FILE_HEADER_MAGIC, FILE_HEADER_SIZE, FORMAT_VERSION};
+use crate::engine::{Engine, now_millis};
+use crate::format_info;
+use crate::framing;
+use crate::storage::{OpenMode, Storage};
+use crate::types::{DataFileEntry, EntryKind};
+
+/// What a scan got out of a file.
+pub(crate) struct Salvage {
+    /// Records that decoded, with their offsets, in log order.
+    pub(crate) records: Vec<(u64, DataFileEntry)>,
+    /// `[start, end)` byte ranges that decoded as nothing.
+    pub(crate) corrupt_regions: Vec<(u64, u64)>,
+}
+
+/// Reads the file at `path` into memory and recovers every record it can.
+/// The header picks the format; a file without a recognizable one is read
+/// as headerless KVS1 when it looks like that, and otherwise as the
+/// current format with its header counted as corrupt.
+pub(crate) fn scan(storage: &dyn Storage, path: &Path) -> io::Result<Salvage> {
+    let mut bytes = Vec::new();
+    storage
+        .open(path, OpenMode::Read)?
+        .read_to_end(&mut bytes)?;
+    let file_len = bytes.len() as u64;
+
+    let magic = bytes
+        .first_chunk::<{ FILE_HEADER_MAGIC.len() }>()
+        .and_then(|magic| format_info::by_magic(*magic));
+    let mut corrupt_regions = Vec::new();
+    let (format_version, start) = match magic {
+        Some(spec) => (spec.version, spec.header_size),
+        None if format_info::looks_headerless(&bytes, file_len) => (1, 0),
+        None => {
+            let header_size = FILE_HEADER_SIZE.min(file_len);
+            if header_size > 0 {
+                corrupt_regions.push((0, header_size));
+            }
+            (FORMAT_VERSION, header_size)
+        }
+    };
+
+    // Past the last nonzero byte there is only unused pre-allocation.
+    let data_end = bytes.iter().rposition(|&b| b != 0).map_or(0, |i| i + 1) as u64;
+    let mut records = Vec::new();
+    let mut bad_since = None;
+    let mut pos = start;
+    while pos < file_len {
+        if let Some((entry, next)) = record_at(&bytes, pos, format_version) {
+            if let Some(bad) = bad_since.take() {
+                corrupt_regions.push((bad, pos));
+            }
+            records.push((pos, entry));
+            pos = next;
+            continue;
+        }
+        if pos >= data_end {
+            break;
+        }
+        // Only a record's own prefix is worth following: inside a damaged
+        // region, a length read from stray bytes can land on a record past
+        // the next good one.
+        let at_record = bad_since.is_none();
+        bad_since.get_or_insert(pos);
+        pos = match framed_end(&bytes, pos, format_version) {
+            Some(next) if at_record && record_at(&bytes, next, format_version).is_some() => next,
+            _ => pos + 1,
+        };
+    }
+    if let Some(bad) = bad_since {
+        corrupt_regions.push((bad, data_end.max(bad + 1).min(file_len)));
+    }
+
+    Ok(Salvage {
+        records,
+        corrupt_regions,
+    })
+}
+
+/// Where the record at `pos` would end by its length prefix alone.
+fn framed_end(bytes: &[u8], pos: u64, format_version: u8) -> Option<u64> {
+    let mut rest = &bytes[pos as usize..];
+    let (len, prefix_len) = framing::read_len(format_version, &mut rest).ok()??;
+    let end = pos.checked_add(prefix_len)?.checked_add(len)?;
+    (len > 0 && end <= bytes.len() as u64).then_some(end)
+}
+
+/// The record at `pos` and where it ends, if one decodes there and
+/// re-encodes to the same bytes.
+pub(crate) fn record_at(
+    bytes: &[u8],
+    pos: u64,
+    format_version: u8,
+) -> Option<(DataFileEntry, u64)> {
+    let end = framed_end(bytes, pos, format_version)?;
+    let mut rest = &bytes[pos as usize..];
+    let (len, _) = framing::read_len(format_version, &mut rest).ok()??;
+    let data = &bytes[(end - len) as usize..end as usize];
+    let entry = codec::for_version(format_version).decode(data).ok()?;
+    let keyless = matches!(entry.kind, EntryKind::BatchBegin | EntryKind::Epoch);
+    if entry.key.is_empty() != keyless {
+        return None;
+    }
+    let encoded = Engine::encode_entry(format_version, &entry).ok()?;
+    (encoded == data).then_some((entry, end))
+}
+
+/// The record each key ended on, in log order of those records, as the
+/// writes an ingest would make: blob references resolved to the blob's
+/// value as a plain put, soft deletes as tombstones. Also returns how many
+/// records that leaves out: earlier records of the same key, history,
+/// internal records, references to a blob that was not recovered, puts
+/// that have already expired, and keys archived to a cold tier, whose
+/// values are not in the log.
+pub(crate) fn final_writes(records: Vec<(u64, DataFileEntry)>) -> (Vec<DataFileEntry>, u64) {
+    let total = records.len() as u64;
+    let mut blobs = HashMap::new();
+    let mut latest: HashMap<Vec<u8>, (usize, DataFileEntry)> = HashMap::new();
+    for (seq, (_, entry)) in records.into_iter().enumerate() {
+        match entry.kind {
+            EntryKind::Blob => {
+                blobs.insert(entry.key, entry.value.unwrap_or_default());
+            }
+            EntryKind::Put
+            | EntryKind::ExpiringPut
+            | EntryKind::BlobRef
+            | EntryKind::Tombstone
+            | EntryKind::SoftDelete
+            | EntryKind::Archived => {
+                latest.insert(entry.key.clone(), (seq, entry));
+            }
+            EntryKind::StalePut | EntryKind::BatchBegin | EntryKind::Epoch => {}
+        }
+    }
+
+    let now = now_millis();
+    let mut writes: Vec<(usize, DataFileEntry)> = latest
+        .into_values()
+        .filter_map(|(seq, entry)| {
+            let entry = match entry.kind {
+                EntryKind::BlobRef => {
+                    let value = blobs.get(entry.value.as_deref()?)?.clone();
+                    DataFileEntry::put(entry.tstamp, entry.key, value)
+                }
+                EntryKind::SoftDelete => DataFileEntry::tombstone(entry.tstamp, entry.key),
+                EntryKind::ExpiringPut if entry.expires_at().is_some_and(|at| at <= now) => {
+                    return None;
+                }
+                // The value is in an archive file, which is not scanned.
+                EntryKind::Archived => return None,
+                _ => entry,
+            };
+            Some((seq, entry))
+        })
+        .collect();
+    writes.sort_unstable_by_key(|(seq, _)| *seq);
+    let skipped = total - writes.len() as u64;
+    (
+        writes.into_iter().map(|(_, entry)| entry).collect(),
+        skipped,
+    )
+}
@@ -0,0 +1,80 @@
+//! Deterministic key sampling, for building fixtures from real stores.
+//!
+//! Whether a key is picked depends only on the key, the seed, and the
+//! fraction, so repeated runs pick the same keys, and so do a primary and its
+//! replicas.
+
+use std::io;
+use std::vec;
+
+use sha1::{Digest, Sha1};
+
+use crate::dump::DumpRecord;
+use crate::engine::Engine;
+
+/// Returns whether `key` falls in the `fraction` (0.0..=1.0) sample for
+/// `seed`.
+pub fn is_sampled(key: &[u8], fraction: f64, seed: u64) -> bool {
+    let digest = Sha1::new()
+        .chain_update(seed.to_le_bytes())
+        .chain_update(key)
+        .finalize();
+    let hash = u64::from_le_bytes(digest[..8].try_into().unwrap());
+    (hash as f64) < fraction * u64::MAX as f64
+}
+
+/// Sampled live records in ascending key order; see [`Engine::sample`].
+///
+/// Values are read as the iterator advances, so keys deleted in the
+/// meantime are skipped and overwritten keys yield their newer value.
+pub struct Sample<'a> {
+    engine: &'a Engine,
+    keys: vec::IntoIter<Vec<u8>>,
+    max_bytes: Option<u64>,
+    bytes: u64,
+}
+
+impl<'a> Sample<'a> {
+    pub(crate) fn new(engine: &'a Engine, keys: Vec<Vec<u8>>) -> Self {
+        Sample {
+            engine,
+            keys: keys.into_iter(),
+            max_bytes: None,
+            bytes: 0,
+        }
+    }
+
+    /// Skips any record whose key and value would take the total past
+    /// `max_bytes`, so one huge value cannot crowd out the rest.
+    pub fn max_bytes(mut self, max_bytes: u64) -> Self {
+        self.max_bytes = Some(max_bytes);
+        self
+    }
+
+    /// Key and value bytes yielded so far.
+    pub fn sampled_bytes(&self) -> u64 {
+        self.bytes
+    }
+}
+
+impl Iterator for Sample<'_> {
+    type Item = io::Result<DumpRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let key = self.keys.next()?;
+            let record = match self.engine.read_live(&key) {
+                Ok(Some(record)) => record,
+                Ok(None) => continue,
+                Err(e) => return Some(Err(e)),
+            };
+
+            let size = (record.key.len() + record.value.len()) as u64;
+            if self.max_bytes.is_some_and(|max| self.bytes + size > max) {
+                continue;
+            }
+            self.bytes += size;
+            return Some(Ok(record));
+        }
+    }
+}
@@ -0,0 +1,164 @@
+//! The two ways to enumerate keys, each with its guarantees in its type.
+//!
+//! A [`KeysSnapshot`] is owned and point-in-time: it is filled under one
+//! hold of the index lock, so everything in it was live at the same
+//! instant, and nothing the engine does afterwards (writes, compaction,
+//! reloads) changes it. It costs memory for every item up front, and
+//! writers wait while it is taken.
+//!
+//! A [`LiveScan`] streams in ascending key order, holding one page at a
+//! time and blocking writers only while a page is read. It picks up after
+//! each page by key rather than by file offset, so a compaction or reload
+//! between pages neither breaks it nor makes it repeat itself. It is weakly
+//! consistent: a key that is live for the whole scan is yielded exactly
+//! once, with its value as of the page it was read in, while a key written
+//! or deleted during the scan may or may not appear. Reads that fail come
+//! out as `Err` items, after which the scan ends.
+
+use std::io;
+use std::ops::Deref;
+use std::vec;
+
+use crate::engine::Engine;
+
+/// Keys `LiveScan` reads per page unless told otherwise.
+pub const DEFAULT_PAGE_SIZE: usize = 256;
+
+/// An owned, point-in-time list of keys, or of `(key, value)` pairs, in
+/// ascending key order; from [`Engine::keys`] and [`Engine::scan_prefix`].
+/// Safe to hold across any engine activity.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeysSnapshot<T = Vec<u8>> {
+    items: Vec<T>,
+}
+
+impl<T> KeysSnapshot<T> {
+    pub(crate) fn new(items: Vec<T>) -> Self {
+        KeysSnapshot { items }
+    }
+
+    pub fn into_vec(self) -> Vec<T> {
+        self.items
+    }
+}
+
+impl<T> Deref for KeysSnapshot<T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        &self.items
+    }
+}
+
+impl<T> IntoIterator for KeysSnapshot<T> {
+    type Item = T;
+    type IntoIter = vec::IntoIter<T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.items.into_iter()
+    }
+}
+
+impl<'a, T> IntoIterator for &'a KeysSnapshot<T> {
+    type Item = &'a T;
+    type IntoIter = std::slice::Iter<'a, T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.items.iter()
+    }
+}
+
+impl<T: PartialEq> PartialEq<Vec<T>> for KeysSnapshot<T> {
+    fn eq(&self, other: &Vec<T>) -> bool {
+        &self.items == other
+    }
+}
+
+/// A streaming, weakly consistent scan of live raw `(key, value)` pairs
+/// under a prefix, in ascending key order; from [`Engine::scan_live`] and
+/// [`Engine::scan_page`]. See [`crate::scan`] for what it guarantees.
+pub struct LiveScan<'a> {
+    engine: &'a Engine,
+    prefix: Vec<u8>,
+    /// The last key yielded; the next page starts after it.
+    cursor: Option<Vec<u8>>,
+    page: vec::IntoIter<(Vec<u8>, Vec<u8>)>,
+    page_size: usize,
+    /// Items left to yield, for a scan with a limit.
+    remaining: Option<usize>,
+    /// The last page came back short, or a read failed.
+    done: bool,
+}
+
+impl<'a> LiveScan<'a> {
+    pub(crate) fn new(
+        engine: &'a Engine,
+        prefix: &[u8],
+        start_after: Option<&[u8]>,
+        limit: Option<usize>,
+    ) -> Self {
+        LiveScan {
+            engine,
+            prefix: prefix.to_vec(),
+            cursor: start_after.map(<[u8]>::to_vec),
+            page: Vec::new().into_iter(),
+            page_size: DEFAULT_PAGE_SIZE,
+            remaining: limit,
+            done: false,
+        }
+    }
+
+    /// Reads `page_size` keys at a time instead of [`DEFAULT_PAGE_SIZE`]:
+    /// more holds more in memory and keeps writers waiting longer per page,
+    /// fewer takes the index lock more often.
+    pub fn page_size(mut self, page_size: usize) -> Self {
+        self.page_size = page_size.max(1);
+        self
+    }
+
+    /// The last key yielded, to pass as `start_after` to
+    /// [`Engine::scan_page`] for the next page.
+    pub fn cursor(&self) -> Option<&[u8]> {
+        self.cursor.as_deref()
+    }
+
+    /// Reads the page after the cursor.
+    fn fill(&mut self) -> io::Result<()> {
+        let size = match self.remaining {
+            Some(remaining) => remaining.min(self.page_size),
+            None => self.page_size,
+        };
+        let (page, more) = self
+            .engine
+            .live_page(&self.prefix, self.cursor.as_deref(), size)?;
+        self.done = !more;
+        self.page = page.into_iter();
+        Ok(())
+    }
+}
+
+impl Iterator for LiveScan<'_> {
+    type Item = io::Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == Some(0) {
+            return None;
+        }
+        while self.page.as_slice().is_empty() {
+            if self.done {
+                return None;
+            }
+            if let Err(e) = self.fill() {
+                self.done = true;
+                return Some(Err(e));
+            }
+        }
+
+        let (key, value) = self.page.next()?;
+        self.cursor = Some(key.clone());
+        if let Some(remaining) = &mut self.remaining {
+            *remaining -= 1;
+        }
+        Some(Ok((key, value)))
+    }
+}
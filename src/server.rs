@@ -0,0 +1,213 @@
+//! The HTTP front-end served by the `breakout1-kv-store` binary.
+//!
+//! [`app`] builds the whole service: the routes, and in front of all of
+//! them the bearer-token check, which runs before any handler or extractor
+//! sees the request, so a refused request is never parsed.
+
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::dev::{ServiceFactory, ServiceRequest, ServiceResponse};
+use actix_web::http::header;
+use actix_web::middleware::{Next, from_fn};
+use actix_web::{App, HttpRequest, HttpResponse, Responder, web};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::{Engine, EngineError};
+
+/// Failed authentications from one address before it is locked out.
+pub const MAX_AUTH_FAILURES: u32 = 5;
+/// How long a lockout lasts, counted from the last failure.
+pub const AUTH_LOCKOUT: Duration = Duration::from_secs(60);
+
+#[derive(Deserialize)]
+pub struct SetRequest {
+    key: String,
+    value: String,
+}
+
+/// The shared secret every request must carry as `Authorization: Bearer`,
+/// and the failed attempts per client address.
+pub struct Auth {
+    token: Option<Vec<u8>>,
+    failures: Mutex<HashMap<IpAddr, (u32, Instant)>>,
+}
+
+impl Auth {
+    /// Requires `token` on every request; `None` lets every request in.
+    pub fn new(token: Option<Vec<u8>>) -> Self {
+        Auth {
+            token,
+            failures: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// `None` if `http` may go ahead, or the response refusing it: 401
+    /// without the right token, 429 and a closed connection once its
+    /// address has failed `MAX_AUTH_FAILURES` times in a row.
+    fn refuse(&self, http: &HttpRequest) -> Option<HttpResponse> {
+        let token = self.token.as_deref()?;
+        let peer = http.peer_addr().map(|addr| addr.ip());
+        let mut failures = self.failures.lock().unwrap();
+        if let Some(ip) = peer
+            && let Some(&(count, last)) = failures.get(&ip)
+            && count >= MAX_AUTH_FAILURES
+            && last.elapsed() < AUTH_LOCKOUT
+        {
+            let retry_after = AUTH_LOCKOUT.saturating_sub(last.elapsed()).as_secs() + 1;
+            return Some(
+                HttpResponse::TooManyRequests()
+                    .insert_header((header::RETRY_AFTER, retry_after.to_string()))
+                    .force_close()
+                    .body("too many failed authentications"),
+            );
+        }
+
+        let presented = http
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.as_bytes().strip_prefix(b"Bearer "));
+        let challenge = match presented {
+            Some(presented) if constant_time_eq(presented, token) => {
+                if let Some(ip) = peer {
+                    failures.remove(&ip);
+                }
+                return None;
+            }
+            Some(_) => "Bearer error=\"invalid_token\"",
+            None => "Bearer",
+        };
+        if let Some(ip) = peer {
+            let count = match failures.get(&ip) {
+                Some(&(count, last)) if last.elapsed() < AUTH_LOCKOUT => count + 1,
+                _ => 1,
+            };
+            // Expired entries would otherwise pile up, one per address.
+            failures.retain(|_, (_, last)| last.elapsed() < AUTH_LOCKOUT);
+            failures.insert(ip, (count, Instant::now()));
+        }
+        Some(
+            HttpResponse::Unauthorized()
+                .insert_header((header::WWW_AUTHENTICATE, challenge))
+                .body("missing or invalid token"),
+        )
+    }
+}
+
+/// Compares every byte whatever the first difference, so the time taken
+/// tells nothing about how much of a guess was right. Only the length can
+/// leak. `black_box` keeps the optimiser from ending the fold early.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len()
+        && a.iter()
+            .zip(b)
+            .fold(0u8, |diff, (x, y)| std::hint::black_box(diff | (x ^ y)))
+            == 0
+}
+
+/// The server's routes over `engine`, every one behind `auth`.
+pub fn app(
+    engine: web::Data<Engine>,
+    auth: web::Data<Auth>,
+) -> App<
+    impl ServiceFactory<
+        ServiceRequest,
+        Config = (),
+        Response = ServiceResponse<impl MessageBody>,
+        Error = actix_web::Error,
+        InitError = (),
+    >,
+> {
+    App::new()
+        .app_data(engine)
+        .app_data(auth)
+        .wrap(from_fn(require_token))
+        .route("/", web::get().to(home))
+        .route("/set", web::post().to(set_handler))
+        .route("/get/{key}", web::get().to(get_handler))
+        .route("/del/{key}", web::delete().to(del_handler))
+}
+
+/// Answers a request `Auth` refuses itself, before routing.
+async fn require_token(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, actix_web::Error> {
+    let refused = req
+        .app_data::<web::Data<Auth>>()
+        .and_then(|auth| auth.refuse(req.request()));
+    match refused {
+        Some(refused) => Ok(req.into_response(refused)),
+        None => next
+            .call(req)
+            .await
+            .map(ServiceResponse::map_into_boxed_body),
+    }
+}
+
+async fn home() -> impl Responder {
+    HttpResponse::Ok().body("Welcome!")
+}
+
+async fn set_handler(req: web::Json<SetRequest>, engine: web::Data<Engine>) -> impl Responder {
+    let op = engine.set(req.key.as_bytes(), req.value.as_bytes());
+    match op {
+        Ok(_) => HttpResponse::Ok().body("OK"),
+        Err(e) => error_response(e),
+    }
+}
+
+async fn get_handler(
+    http: HttpRequest,
+    req: web::Path<String>,
+    engine: web::Data<Engine>,
+) -> impl Responder {
+    // Answered from the index alone when the client's copy is current.
+    if let Some(tag) = http.headers().get(header::IF_NONE_MATCH)
+        && let Ok(Some(hash)) = engine.value_hash(req.as_bytes())
+        && tag.as_bytes() == etag(hash).as_bytes()
+    {
+        return HttpResponse::NotModified()
+            .insert_header((header::ETAG, etag(hash)))
+            .finish();
+    }
+    let op = engine.get(req.as_bytes());
+    match op {
+        Ok(Some(val)) => HttpResponse::Ok()
+            .insert_header((header::ETAG, etag(Engine::hash_value(&val))))
+            .body(val),
+        Ok(None) => HttpResponse::NotFound().body("Key is not found"),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+async fn del_handler(req: web::Path<String>, engine: web::Data<Engine>) -> impl Responder {
+    let op = engine.del(req.as_bytes());
+    match op {
+        Ok(_) => HttpResponse::Ok().body("OK"),
+        Err(e) => error_response(e),
+    }
+}
+
+fn etag(hash: u64) -> String {
+    format!("\"{:016x}\"", hash)
+}
+
+fn error_response(e: std::io::Error) -> HttpResponse {
+    match EngineError::from_io(&e) {
+        Some(
+            EngineError::EmptyKey | EngineError::ReservedKey | EngineError::KeyTooLarge { .. },
+        ) => HttpResponse::BadRequest().body(e.to_string()),
+        Some(
+            EngineError::IndexFull(_)
+            | EngineError::DiskFull { .. }
+            | EngineError::QuotaExceeded(_),
+        ) => HttpResponse::InsufficientStorage().body(e.to_string()),
+        Some(
+            EngineError::StoreCorrupted(_) | EngineError::Closed | EngineError::MirrorFailed(_),
+        ) => HttpResponse::ServiceUnavailable().body(e.to_string()),
+        _ => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
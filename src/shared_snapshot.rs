@@ -0,0 +1,341 @@
+//! Sealed, read-only snapshots for handing the store to another process on
+//! the same host, written by `Engine::export_shm_snapshot` and read in
+//! place, through a memory map, by [`SnapshotReader`].
+//!
+//! This is an export artifact, not a log: it is never appended to or
+//! loaded as a data file, and a new export replaces it whole. All integers
+//! are little-endian.
+//!
+//! ```text
+//! header (56 bytes):
+//!     [4 bytes: magic "KVSS"][4 bytes: version u32]
+//!     [8 bytes: key count u64]
+//!     [8 bytes: slot count u64, a power of two]
+//!     [8 bytes: table offset u64]
+//!     [8 bytes: data offset u64]
+//!     [8 bytes: file length u64]
+//!     [4 bytes: CRC-32 of the table]
+//!     [4 bytes: CRC-32 of the 52 header bytes before it]
+//! record*, from the data offset:
+//!     [8 bytes: key length u64][8 bytes: value length u64][key][value]
+//! table, at the table offset, of slot count entries:
+//!     [8 bytes: record offset u64, 0 for an empty slot][8 bytes: key hash u64]
+//! ```
+//!
+//! Keys are hashed with 64-bit FNV-1a and placed by linear probing from
+//! `hash & (slots - 1)`; the table is at most half full. Values are not
+//! checksummed, so lookups read them straight from the mapping; a record
+//! that does not fit in the file fails the lookup instead.
+
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+pub const SNAPSHOT_MAGIC: [u8; 4] = *b"KVSS";
+pub const SNAPSHOT_VERSION: u32 = 1;
+pub const SNAPSHOT_HEADER_SIZE: u64 = 56;
+
+const SLOT_SIZE: u64 = 16;
+const RECORD_FIXED_SIZE: u64 = 16;
+
+fn invalid(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.into())
+}
+
+fn key_hash(key: &[u8]) -> u64 {
+    key.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+fn read_u64(bytes: &[u8], at: u64) -> u64 {
+    u64::from_le_bytes(bytes[at as usize..][..8].try_into().unwrap())
+}
+
+/// Streams records into a snapshot file next to `path`, then writes the
+/// table and header and renames it into place.
+pub(crate) struct SnapshotWriter<'a> {
+    path: &'a Path,
+    tmp_path: std::path::PathBuf,
+    out: BufWriter<File>,
+    pos: u64,
+    /// Key hash and record offset of every record so far.
+    slots: Vec<(u64, u64)>,
+}
+
+impl<'a> SnapshotWriter<'a> {
+    pub(crate) fn create(path: &'a Path) -> io::Result<Self> {
+        let tmp_path = path.with_extension("kvss-tmp");
+        let mut out = BufWriter::new(File::create(&tmp_path)?);
+        out.write_all(&[0u8; SNAPSHOT_HEADER_SIZE as usize])?;
+        Ok(SnapshotWriter {
+            path,
+            tmp_path,
+            out,
+            pos: SNAPSHOT_HEADER_SIZE,
+            slots: Vec::new(),
+        })
+    }
+
+    pub(crate) fn write_record(&mut self, key: &[u8], value: &[u8]) -> io::Result<()> {
+        self.out.write_all(&(key.len() as u64).to_le_bytes())?;
+        self.out.write_all(&(value.len() as u64).to_le_bytes())?;
+        self.out.write_all(key)?;
+        self.out.write_all(value)?;
+        self.slots.push((key_hash(key), self.pos));
+        self.pos += RECORD_FIXED_SIZE + key.len() as u64 + value.len() as u64;
+        Ok(())
+    }
+
+    /// Seals the snapshot and returns its key count. The file is synced,
+    /// made read-only, and only then renamed over `path`, so readers never
+    /// see a partial one.
+    pub(crate) fn finish(mut self) -> io::Result<u64> {
+        let count = self.slots.len() as u64;
+        let slot_count = (count * 2).max(1).next_power_of_two();
+        let mut table = vec![0u8; (slot_count * SLOT_SIZE) as usize];
+        for &(hash, offset) in &self.slots {
+            let mut slot = hash & (slot_count - 1);
+            while read_u64(&table, slot * SLOT_SIZE) != 0 {
+                slot = (slot + 1) & (slot_count - 1);
+            }
+            let at = (slot * SLOT_SIZE) as usize;
+            table[at..at + 8].copy_from_slice(&offset.to_le_bytes());
+            table[at + 8..at + 16].copy_from_slice(&hash.to_le_bytes());
+        }
+        let table_offset = self.pos;
+        self.out.write_all(&table)?;
+        let len = table_offset + table.len() as u64;
+
+        let mut header = Vec::with_capacity(SNAPSHOT_HEADER_SIZE as usize);
+        header.extend_from_slice(&SNAPSHOT_MAGIC);
+        header.extend_from_slice(&SNAPSHOT_VERSION.to_le_bytes());
+        header.extend_from_slice(&count.to_le_bytes());
+        header.extend_from_slice(&slot_count.to_le_bytes());
+        header.extend_from_slice(&table_offset.to_le_bytes());
+        header.extend_from_slice(&SNAPSHOT_HEADER_SIZE.to_le_bytes());
+        header.extend_from_slice(&len.to_le_bytes());
+        header.extend_from_slice(&crc32fast::hash(&table).to_le_bytes());
+        let header_crc = crc32fast::hash(&header);
+        header.extend_from_slice(&header_crc.to_le_bytes());
+
+        self.out.flush()?;
+        let file = self.out.get_mut();
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(&header)?;
+        file.sync_all()?;
+        let mut permissions = file.metadata()?.permissions();
+        permissions.set_readonly(true);
+        fs::set_permissions(&self.tmp_path, permissions)?;
+        fs::rename(&self.tmp_path, self.path)?;
+        Ok(count)
+    }
+}
+
+impl Drop for SnapshotWriter<'_> {
+    fn drop(&mut self) {
+        // Gone already after a successful rename.
+        let _ = fs::remove_file(&self.tmp_path);
+    }
+}
+
+/// A snapshot written by `Engine::export_shm_snapshot`, mapped read-only.
+/// Lookups hash the key, probe the table, and return the value as a slice
+/// of the mapping.
+///
+/// The header checksum, and then the table's, are checked when the
+/// snapshot is opened, before the table is used.
+pub struct SnapshotReader {
+    map: Mapping,
+    count: u64,
+    slot_count: u64,
+    table_offset: u64,
+    data_offset: u64,
+}
+
+impl SnapshotReader {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let map = Mapping::open(path.as_ref())?;
+        let bytes = map.bytes();
+        let header_len = SNAPSHOT_HEADER_SIZE as usize;
+        if bytes.len() < header_len {
+            return Err(invalid("snapshot shorter than its header"));
+        }
+        if bytes[..4] != SNAPSHOT_MAGIC {
+            return Err(invalid("snapshot: missing KVSS magic"));
+        }
+        let stored_crc = u32::from_le_bytes(bytes[header_len - 4..header_len].try_into().unwrap());
+        if crc32fast::hash(&bytes[..header_len - 4]) != stored_crc {
+            return Err(invalid("snapshot: header checksum mismatch"));
+        }
+        let version = u32::from_le_bytes(bytes[4..8].try_into().unwrap());
+        if version != SNAPSHOT_VERSION {
+            return Err(invalid(format!(
+                "snapshot: unsupported version {}",
+                version
+            )));
+        }
+
+        let count = read_u64(bytes, 8);
+        let slot_count = read_u64(bytes, 16);
+        let table_offset = read_u64(bytes, 24);
+        let data_offset = read_u64(bytes, 32);
+        let len = read_u64(bytes, 40);
+        let table_end = slot_count
+            .checked_mul(SLOT_SIZE)
+            .and_then(|table_len| table_offset.checked_add(table_len));
+        if len != bytes.len() as u64
+            || !slot_count.is_power_of_two()
+            || count > slot_count / 2
+            || data_offset < SNAPSHOT_HEADER_SIZE
+            || table_offset < data_offset
+            || table_end != Some(len)
+        {
+            return Err(invalid("snapshot: header does not describe the file"));
+        }
+        let table_crc = u32::from_le_bytes(bytes[48..52].try_into().unwrap());
+        if crc32fast::hash(&bytes[table_offset as usize..]) != table_crc {
+            return Err(invalid("snapshot: table checksum mismatch"));
+        }
+
+        Ok(SnapshotReader {
+            map,
+            count,
+            slot_count,
+            table_offset,
+            data_offset,
+        })
+    }
+
+    /// The value stored under `key`, read in place.
+    ///
+    /// The probe visits each slot at most once. A table with no empty slot
+    /// cannot have been written by `export_shm_snapshot`, which keeps it at
+    /// most half full, so a lookup that wraps around fails as corrupt.
+    pub fn get(&self, key: &[u8]) -> io::Result<Option<&[u8]>> {
+        let bytes = self.map.bytes();
+        let hash = key_hash(key);
+        let mut slot = hash & (self.slot_count - 1);
+        for _ in 0..self.slot_count {
+            let at = self.table_offset + slot * SLOT_SIZE;
+            let offset = read_u64(bytes, at);
+            if offset == 0 {
+                return Ok(None);
+            }
+            if read_u64(bytes, at + 8) == hash {
+                let (stored_key, value) = self.record(offset)?;
+                if stored_key == key {
+                    return Ok(Some(value));
+                }
+            }
+            slot = (slot + 1) & (self.slot_count - 1);
+        }
+        Err(invalid("snapshot: table has no empty slot"))
+    }
+
+    /// Keys in the snapshot.
+    pub fn len(&self) -> u64 {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    fn record(&self, offset: u64) -> io::Result<(&[u8], &[u8])> {
+        let bad = || invalid(format!("snapshot: bad record at offset {}", offset));
+        let bytes = self.map.bytes();
+        let fixed_end = offset.checked_add(RECORD_FIXED_SIZE).ok_or_else(bad)?;
+        if offset < self.data_offset || fixed_end > self.table_offset {
+            return Err(bad());
+        }
+        let key_len = read_u64(bytes, offset);
+        let value_len = read_u64(bytes, offset + 8);
+        let value_start = fixed_end.checked_add(key_len).ok_or_else(bad)?;
+        let end = value_start.checked_add(value_len).ok_or_else(bad)?;
+        if end > self.table_offset {
+            return Err(bad());
+        }
+        Ok((
+            &bytes[fixed_end as usize..value_start as usize],
+            &bytes[value_start as usize..end as usize],
+        ))
+    }
+}
+
+/// The snapshot's bytes: mapped on Linux, read into memory elsewhere.
+#[cfg(target_os = "linux")]
+struct Mapping {
+    ptr: *mut libc::c_void,
+    len: usize,
+}
+
+#[cfg(not(target_os = "linux"))]
+struct Mapping {
+    bytes: Vec<u8>,
+}
+
+// The mapping is read-only and owned by this value.
+//
+// It stays valid only while the file keeps its length. The engine never
+// writes to a sealed snapshot: a new export is renamed over it, and this
+// mapping keeps the old inode. But nothing stops another process from
+// truncating the file in place, and reading a mapped page past the new end
+// raises SIGBUS, which kills the reader. Mapping it `MAP_PRIVATE` keeps it
+// from sharing any change back, but does not prevent this, since untouched
+// private pages are still read from the file.
+unsafe impl Send for Mapping {}
+unsafe impl Sync for Mapping {}
+
+impl Mapping {
+    #[cfg(target_os = "linux")]
+    fn open(path: &Path) -> io::Result<Self> {
+        use std::os::fd::AsRawFd;
+
+        let file = File::open(path)?;
+        let len = file.metadata()?.len() as usize;
+        if len == 0 {
+            return Err(invalid("snapshot shorter than its header"));
+        }
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_PRIVATE,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Mapping { ptr, len })
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn open(path: &Path) -> io::Result<Self> {
+        Ok(Mapping {
+            bytes: fs::read(path)?,
+        })
+    }
+
+    #[cfg(target_os = "linux")]
+    fn bytes(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr as *const u8, self.len) }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.ptr, self.len);
+        }
+    }
+}
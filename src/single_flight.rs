@@ -0,0 +1,97 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::{Arc, Condvar, Mutex};
+
+enum State<V> {
+    Pending,
+    Done(V),
+    Abandoned,
+}
+
+struct Call<V> {
+    state: Mutex<State<V>>,
+    done: Condvar,
+}
+
+/// Deduplicates concurrent calls for the same key: the first caller runs the
+/// work, later callers with an identical key wait for and share its result.
+pub(crate) struct SingleFlight<K, V> {
+    calls: Mutex<HashMap<K, Arc<Call<V>>>>,
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> SingleFlight<K, V> {
+    pub(crate) fn new() -> Self {
+        SingleFlight {
+            calls: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Gives back the memory a burst of concurrent calls left allocated.
+    pub(crate) fn shrink(&self) {
+        self.calls.lock().unwrap().shrink_to_fit();
+    }
+
+    /// Returns the result and whether it was shared from another caller.
+    pub(crate) fn run(&self, key: K, work: impl FnOnce() -> V) -> (V, bool) {
+        let (call, leader) = {
+            let mut calls = self.calls.lock().unwrap();
+            match calls.get(&key) {
+                Some(call) => (Arc::clone(call), false),
+                None => {
+                    let call = Arc::new(Call {
+                        state: Mutex::new(State::Pending),
+                        done: Condvar::new(),
+                    });
+                    calls.insert(key.clone(), Arc::clone(&call));
+                    (call, true)
+                }
+            }
+        };
+
+        if leader {
+            let mut guard = LeaderGuard {
+                flight: self,
+                key: Some(key),
+                call: &call,
+            };
+            let value = work();
+            guard.finish(State::Done(value.clone()));
+            return (value, false);
+        }
+
+        let mut state = call.state.lock().unwrap();
+        loop {
+            match &*state {
+                State::Pending => state = call.done.wait(state).unwrap(),
+                State::Done(value) => return (value.clone(), true),
+                // The leader panicked; do the work ourselves rather than hang.
+                State::Abandoned => {
+                    drop(state);
+                    return (work(), false);
+                }
+            }
+        }
+    }
+}
+
+struct LeaderGuard<'a, K: Hash + Eq, V> {
+    flight: &'a SingleFlight<K, V>,
+    key: Option<K>,
+    call: &'a Call<V>,
+}
+
+impl<K: Hash + Eq, V> LeaderGuard<'_, K, V> {
+    fn finish(&mut self, state: State<V>) {
+        if let Some(key) = self.key.take() {
+            self.flight.calls.lock().unwrap().remove(&key);
+            *self.call.state.lock().unwrap() = state;
+            self.call.done.notify_all();
+        }
+    }
+}
+
+impl<K: Hash + Eq, V> Drop for LeaderGuard<'_, K, V> {
+    fn drop(&mut self) {
+        self.finish(State::Abandoned);
+    }
+}
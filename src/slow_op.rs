@@ -0,0 +1,68 @@
+use std::cell::RefCell;
+use std::time::Duration;
+
+use crate::context::OpContext;
+
+/// An operation that took at least `Options::slow_op_threshold`, passed to
+/// `Options::on_slow_op`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SlowOp {
+    pub op: SlowOpKind,
+    pub key_len: usize,
+    pub duration: Duration,
+    pub detail: SlowOpDetail,
+    /// The `Engine::with_context` context the operation ran in.
+    pub context: Option<OpContext>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SlowOpKind {
+    Get,
+    Set,
+    Del,
+}
+
+/// Where a slow operation spent its time. Waits are only measured when the
+/// lock or permit was not immediately available.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct SlowOpDetail {
+    /// Log offset of the record read or written.
+    pub offset: Option<u64>,
+    /// Time spent waiting for the file mutex (writes).
+    pub file_lock_wait: Duration,
+    /// Time spent waiting out a compaction swapping in its output, and
+    /// retrying a read that overlapped the swap (reads).
+    pub index_lock_wait: Duration,
+    /// Time spent waiting for a `max_concurrent_reads` permit.
+    pub read_permit_wait: Duration,
+    /// The reader pool was empty, so a new read handle was opened.
+    pub opened_reader: bool,
+    /// The read was served by another thread's identical in-flight read.
+    pub coalesced: bool,
+    /// The write tripped an automatic compaction, which ran inline.
+    pub auto_compaction: bool,
+}
+
+thread_local! {
+    static CURRENT: RefCell<Option<SlowOpDetail>> = const { RefCell::new(None) };
+}
+
+/// Starts collecting annotations for the operation running on this thread.
+pub(crate) fn begin() {
+    CURRENT.with_borrow_mut(|detail| *detail = Some(SlowOpDetail::default()));
+}
+
+/// Stops collecting and returns what was noted since [`begin`].
+pub(crate) fn end() -> SlowOpDetail {
+    CURRENT.with_borrow_mut(Option::take).unwrap_or_default()
+}
+
+/// Annotates the operation in progress on this thread, if one is traced.
+pub(crate) fn note(f: impl FnOnce(&mut SlowOpDetail)) {
+    CURRENT.with_borrow_mut(|detail| {
+        if let Some(detail) = detail {
+            f(detail);
+        }
+    });
+}
@@ -0,0 +1,49 @@
+//! Epoch-based publication of an immutable value: readers get at the current
+//! version with one atomic load and never block, and a replaced version is
+//! freed once no reader that could have seen it is still pinned.
+
+use crossbeam_epoch::{self as epoch, Atomic, Owned};
+use std::sync::atomic::Ordering;
+
+pub(crate) struct Published<T> {
+    current: Atomic<T>,
+}
+
+impl<T> Published<T> {
+    pub(crate) fn new(value: T) -> Self {
+        Published {
+            current: Atomic::new(value),
+        }
+    }
+
+    /// Runs `f` on the current version. Keep `f` short: versions replaced
+    /// meanwhile are not freed until it returns.
+    pub(crate) fn load<R>(&self, f: impl FnOnce(&T) -> R) -> R {
+        let guard = epoch::pin();
+        let current = self.current.load(Ordering::Acquire, &guard);
+        // SAFETY: `current` is never null, and a version swapped out is only
+        // destroyed after every guard pinned before the swap is dropped.
+        f(unsafe { current.deref() })
+    }
+
+    /// Makes `value` the current version.
+    pub(crate) fn store(&self, value: T) {
+        let guard = epoch::pin();
+        let old = self
+            .current
+            .swap(Owned::new(value), Ordering::AcqRel, &guard);
+        // SAFETY: `old` is unreachable from `current` now, so only readers
+        // already pinned can hold it.
+        unsafe { guard.defer_destroy(old) };
+    }
+}
+
+impl<T> Drop for Published<T> {
+    fn drop(&mut self) {
+        // SAFETY: `&mut self` rules out any reader.
+        unsafe {
+            let guard = epoch::unprotected();
+            drop(self.current.load(Ordering::Relaxed, guard).into_owned());
+        }
+    }
+}
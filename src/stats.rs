@@ -0,0 +1,472 @@
+use std::ops::Range;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use crate::limits::Limit;
+use crate::options::{MirrorFailure, OpenMode};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// Live keys, raw and bucketed; the engine's own too under
+    /// `Options::include_internal_keys`.
+    pub live_keys: usize,
+    /// Live keys that are the engine's own, such as bucket quotas.
+    pub internal_keys: usize,
+    pub soft_deleted_keys: usize,
+    /// Logical end of the log, header included.
+    pub file_size: u64,
+    /// Physical bytes reserved for the data file (equal to `file_size` unless
+    /// pre-allocation is enabled).
+    pub allocated_size: u64,
+    pub compact_threshold: u64,
+    /// Estimated heap bytes of the live-key index, keys included.
+    pub index_bytes: u64,
+    /// Length of the longest live key, bucketed keys included; see
+    /// `Engine::largest_keys` to find it.
+    pub largest_key_len: usize,
+    /// Heap bytes of the `Options::track_access` sketches; 0 without it.
+    pub access_tracker_bytes: u64,
+    /// `Options::max_index_entries` and `max_index_bytes`, to compare
+    /// `live_keys` and `index_bytes` against. The entry limit counts
+    /// internal keys whatever `Options::include_internal_keys` says.
+    pub max_index_entries: Option<usize>,
+    pub max_index_bytes: Option<u64>,
+    /// Limits whose `Options::soft_limits` warning is raised.
+    pub limit_warnings: Vec<Limit>,
+    pub history_entries: usize,
+    pub history_bytes: usize,
+    /// Distinct deduplicated values stored (see `Options::dedup_min_value_len`).
+    pub blobs: usize,
+    /// Live keys whose value is a reference to a shared blob.
+    pub blob_refs: usize,
+    /// Live keys whose value `Engine::archive` moved to the cold tier, and
+    /// the bytes of the archive file holding them, header included; the
+    /// data file's are `file_size`. 0 without `Options::archive_path`.
+    pub archived_keys: usize,
+    pub archive_file_size: u64,
+    /// Bytes of overwritten, deleted, and tombstone records awaiting compaction.
+    pub dead_bytes: u64,
+    pub tombstones: u64,
+    pub tombstone_bytes: u64,
+    pub compactions: u64,
+    /// Automatic compactions abandoned at `Options::auto_compact_deadline`.
+    pub compactions_aborted: u64,
+    /// Trigger hits that found the last automatic compaction less than
+    /// `Options::min_compaction_interval` ago, and did not compact.
+    pub compactions_deferred: u64,
+    /// Physical record reads issued against the data file.
+    pub disk_reads: u64,
+    /// `get` calls answered by sharing another in-flight read of the same record.
+    pub coalesced_reads: u64,
+    /// `get` calls that read the data file themselves, on a pooled read
+    /// handle or on one opened for the read, and those whose value was
+    /// then read from the `Options::archive_path` tier; see
+    /// [`ReadSource`].
+    pub get_pool_hits: u64,
+    pub get_pool_misses: u64,
+    pub archive_gets: u64,
+    /// Reads that had to wait for a slot under `Options::max_concurrent_reads`.
+    pub read_waits: u64,
+    /// Total time those reads spent waiting, in microseconds.
+    pub read_wait_micros: u64,
+    /// Pooled read handles `Options::paranoid_reads` found stale and replaced.
+    pub stale_readers: u64,
+    /// Read handles held in the pool right now.
+    pub pooled_readers: usize,
+    /// The data file's generation, bumped each time compaction or a reload
+    /// replaces it.
+    pub generation: u64,
+    /// Read handles open right now, pooled or serving a read, as
+    /// `(generation, count)` in generation order. A handle from before
+    /// `generation` holds a replaced file open; it is closed as soon as the
+    /// read using it finishes.
+    pub reader_fds: Vec<(u64, usize)>,
+    /// Times idle resources were released, by `Engine::reclaim_idle` or
+    /// `Options::idle_after`, and times a later read or write took them up
+    /// again.
+    pub idle_reclaims: u64,
+    pub idle_reacquires: u64,
+    /// Fsyncs issued by `Engine::sync` or the background sync thread.
+    pub syncs: u64,
+    pub sync_errors: u64,
+    /// Wall-clock time of the last successful sync, in epoch milliseconds.
+    pub last_sync_millis: Option<i64>,
+    /// I/O calls repeated under `Options::io_retry`, and calls that still
+    /// failed with a retryable error once out of attempts.
+    pub io_retries: u64,
+    pub io_retry_give_ups: u64,
+    /// Calls to `get` and `set` since the engine was opened.
+    pub gets: u64,
+    pub sets: u64,
+    /// Appends made to the data file. A write batch, or a group of writes
+    /// committed together, counts once however many records it holds.
+    pub appends: u64,
+    /// Failed writes, syncs, and copies to `Options::mirror_path`, and
+    /// whether mirroring has stopped over one; see `Engine::mirror_status`.
+    pub mirror_failures: u64,
+    pub mirror_stopped: bool,
+    /// Contention on the data file mutex, the index lock, and the reader
+    /// pool's lock since open.
+    #[cfg(feature = "lock-metrics")]
+    pub file_lock: LockStats,
+    #[cfg(feature = "lock-metrics")]
+    pub index_lock: LockStats,
+    #[cfg(feature = "lock-metrics")]
+    pub reader_pool_lock: LockStats,
+    /// Latency distributions since open or the last `reset_latency_stats`;
+    /// empty unless `Options::latency_histograms` is set.
+    pub get_latency: LatencySnapshot,
+    pub set_latency: LatencySnapshot,
+}
+
+/// Where a `get` found its value; from [`crate::Engine::get_traced`]. The
+/// engine keeps no value cache, inline values, or memory maps, so every
+/// value comes from a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadSource {
+    /// Read from the data file, on a pooled read handle if `pool_hit` and
+    /// on one opened for the read otherwise.
+    Disk { pool_hit: bool },
+    /// Shared with a concurrent `get` of the same record, which did the
+    /// read.
+    Coalesced,
+    /// Read from the `Options::archive_path` tier, through the key's stub
+    /// in the data file.
+    Archive,
+}
+
+/// How often one lock was taken and waited for, under the `lock-metrics`
+/// feature.
+#[cfg(feature = "lock-metrics")]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LockStats {
+    pub acquisitions: u64,
+    /// Acquisitions that found the lock held and had to wait.
+    pub contended: u64,
+    /// Total time those waited, in microseconds.
+    pub wait_micros: u64,
+}
+
+/// Live counters for one prefix registered with `Engine::track_prefix` or
+/// `Options::tracked_prefixes`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PrefixStats {
+    pub prefix: Vec<u8>,
+    pub keys: u64,
+    pub value_bytes: u64,
+}
+
+/// Counts for one bucket, from `Bucket::stats`. Bytes are on-disk record
+/// bytes, length prefixes included; deduplicated blobs are shared across
+/// keys and count against no bucket.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BucketStats {
+    /// Live keys, leaving out those whose TTL has passed.
+    pub keys: u64,
+    /// Bytes a compaction would copy: the live keys, their retained
+    /// history, and soft-deleted keys still inside their window.
+    pub live_bytes: u64,
+    /// Bytes a compaction would free: overwritten and deleted records,
+    /// tombstones, and keys past their TTL or soft-delete window.
+    pub dead_bytes: u64,
+}
+
+/// How two key prefixes overlap; see `Engine::key_overlap`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct OverlapReport {
+    /// Live keys under the source and destination prefixes. A key under
+    /// both counts in both.
+    pub src_count: u64,
+    pub dst_count: u64,
+    /// Source keys whose suffix also exists under the destination prefix.
+    pub conflicts: u64,
+}
+
+/// What a compaction would produce right now, computed from the index alone.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CompactionEstimate {
+    /// File size after compaction, header included.
+    pub expected_size: u64,
+    /// Bytes compaction would free (current size minus `expected_size`).
+    pub reclaimable_bytes: u64,
+    /// Records compaction would copy: live keys, retained history, and
+    /// soft-deleted keys still inside their window.
+    pub live_entries: u64,
+}
+
+/// Disk space the store needs now, after a compaction, and at the peak of
+/// one; see [`crate::Engine::peak_disk_forecast`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DiskForecast {
+    /// Bytes the data file occupies, pre-allocated space included.
+    pub current: u64,
+    /// Size of the data file if it were compacted now.
+    pub post_compaction_estimate: u64,
+    /// Most bytes held at once by a compaction run now: the old file plus
+    /// its output, plus a second copy of the output when it is written on
+    /// another filesystem and copied next to the data file.
+    pub peak_during_compaction: u64,
+    /// Whether `Options::compaction_dir` is on another filesystem than the
+    /// data file. `None` without a compaction dir or when the storage cannot
+    /// tell, in which case the peak assumes the extra copy.
+    pub scratch_on_other_filesystem: Option<bool>,
+}
+
+/// What a finished compaction did.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CompactionReport {
+    /// File size just before the swap, including writes made while a
+    /// stepped compaction was running.
+    pub old_size: u64,
+    pub new_size: u64,
+    pub live_keys: usize,
+    /// Records written during a stepped compaction and copied at the end.
+    pub carried_over: u64,
+    /// Bytes of records at the start of the old file that were already
+    /// packed and kept as they were instead of being copied.
+    pub preserved_bytes: u64,
+    /// Bytes of records written to the new file one by one, past the
+    /// preserved ones.
+    pub copied_bytes: u64,
+}
+
+/// Returned by [`crate::Engine::compact_step`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CompactProgress {
+    /// Output bytes written so far, and roughly how many are left to copy
+    /// (not counting writes made since the compaction started).
+    InProgress {
+        copied: u64,
+        remaining_estimate: u64,
+    },
+    Done(CompactionReport),
+}
+
+/// What [`crate::Engine::reload`] found on disk.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReloadReport {
+    /// Records replayed from the log.
+    pub entries_scanned: u64,
+    pub live_keys: usize,
+    /// Bytes past the last complete record that were cut off: a torn write
+    /// or unused pre-allocated space.
+    pub truncated_bytes: u64,
+    pub format_version: u8,
+}
+
+/// What [`crate::Engine::load`] found, from [`crate::Engine::load_report`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LoadReport {
+    pub mode: OpenMode,
+    pub replay: ReloadReport,
+    /// Records that failed to decode and were skipped (`OpenMode::Verify`
+    /// only; the other modes fail the load or do not look).
+    pub corrupt_records: u64,
+}
+
+/// What [`crate::Engine::ingest_raw_log`] got out of a damaged data file.
+/// Every recovered record is counted as exactly one of applied, skipped, or
+/// conflicted.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IngestReport {
+    /// Records that decoded, wherever they were in the file.
+    pub recovered: u64,
+    /// Records written to this store as the current value or deletion of
+    /// their key.
+    pub applied: u64,
+    /// Records not written: superseded by a later record of the same key,
+    /// history and internal records, dangling blob references, expired
+    /// puts, and deletes of keys this store does not hold.
+    pub skipped: u64,
+    /// Records older than this store's value for their key. Written as
+    /// history under `ConflictPolicy::KeepNewest`, dropped under
+    /// `RejectOlder`; `AlwaysAccept` never conflicts.
+    pub conflicted: u64,
+    /// Byte ranges of the file that held nothing recoverable, in order.
+    pub corrupt_regions: Vec<Range<u64>>,
+}
+
+/// Progress of [`crate::Engine::export_sorted_keys_with_progress`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ExportProgress {
+    /// Keys sorted and spilled to run files so far.
+    pub keys_spilled: u64,
+    /// Run files the spill phase has written.
+    pub runs: usize,
+    /// Keys the final merge has written to the output so far.
+    pub keys_written: u64,
+}
+
+/// Sub-buckets per power of two: 8 gives at most 12.5% relative error.
+const SUB_BUCKET_BITS: u32 = 3;
+const SUB_BUCKETS: usize = 1 << SUB_BUCKET_BITS;
+const LATENCY_BUCKETS: usize = (64 - SUB_BUCKET_BITS as usize + 1) * SUB_BUCKETS;
+
+/// HDR-style histogram of nanosecond latencies with fixed log-linear
+/// buckets, updated with relaxed atomics.
+pub(crate) struct LatencyHistogram {
+    buckets: Box<[AtomicU64]>,
+    max_nanos: AtomicU64,
+}
+
+fn bucket_of(nanos: u64) -> usize {
+    if nanos < SUB_BUCKETS as u64 {
+        return nanos as usize;
+    }
+    let msb = 63 - nanos.leading_zeros();
+    let shift = msb - SUB_BUCKET_BITS;
+    let sub = (nanos >> shift) as usize & (SUB_BUCKETS - 1);
+    (shift as usize + 1) * SUB_BUCKETS + sub
+}
+
+/// Largest value that lands in `bucket`.
+fn bucket_upper_bound(bucket: usize) -> u64 {
+    if bucket < SUB_BUCKETS {
+        return bucket as u64;
+    }
+    let shift = (bucket / SUB_BUCKETS - 1) as u32;
+    let sub = (bucket % SUB_BUCKETS) as u64;
+    ((SUB_BUCKETS as u64 + sub) << shift) + ((1u64 << shift) - 1)
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        LatencyHistogram {
+            buckets: (0..LATENCY_BUCKETS).map(|_| AtomicU64::new(0)).collect(),
+            max_nanos: AtomicU64::new(0),
+        }
+    }
+}
+
+impl std::fmt::Debug for LatencyHistogram {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.snapshot().fmt(f)
+    }
+}
+
+impl LatencyHistogram {
+    pub(crate) fn record(&self, elapsed: Duration) {
+        let nanos = elapsed.as_nanos().min(u64::MAX as u128) as u64;
+        self.buckets[bucket_of(nanos)].fetch_add(1, Ordering::Relaxed);
+        self.max_nanos.fetch_max(nanos, Ordering::Relaxed);
+    }
+
+    pub(crate) fn reset(&self) {
+        for bucket in self.buckets.iter() {
+            bucket.store(0, Ordering::Relaxed);
+        }
+        self.max_nanos.store(0, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self) -> LatencySnapshot {
+        LatencySnapshot {
+            counts: self
+                .buckets
+                .iter()
+                .map(|b| b.load(Ordering::Relaxed))
+                .collect(),
+            max_nanos: self.max_nanos.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// A point-in-time copy of a latency histogram.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LatencySnapshot {
+    counts: Vec<u64>,
+    max_nanos: u64,
+}
+
+impl LatencySnapshot {
+    /// Number of recorded operations.
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// Latency at or below which `quantile` (0.0..=1.0) of operations
+    /// completed, accurate to the bucket width (12.5%). Zero when empty.
+    pub fn percentile(&self, quantile: f64) -> Duration {
+        let total = self.count();
+        if total == 0 {
+            return Duration::ZERO;
+        }
+        let rank = ((quantile.clamp(0.0, 1.0) * total as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (bucket, &count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Duration::from_nanos(bucket_upper_bound(bucket).min(self.max_nanos));
+            }
+        }
+        self.max()
+    }
+
+    pub fn p50(&self) -> Duration {
+        self.percentile(0.50)
+    }
+
+    pub fn p90(&self) -> Duration {
+        self.percentile(0.90)
+    }
+
+    pub fn p99(&self) -> Duration {
+        self.percentile(0.99)
+    }
+
+    pub fn max(&self) -> Duration {
+        Duration::from_nanos(self.max_nanos)
+    }
+}
+
+/// Counters updated on the hot paths and copied into [`Stats`].
+#[derive(Debug, Default)]
+pub(crate) struct Metrics {
+    pub(crate) disk_reads: AtomicU64,
+    pub(crate) coalesced_reads: AtomicU64,
+    pub(crate) get_pool_hits: AtomicU64,
+    pub(crate) get_pool_misses: AtomicU64,
+    pub(crate) archive_gets: AtomicU64,
+    pub(crate) compactions: AtomicU64,
+    pub(crate) compactions_aborted: AtomicU64,
+    pub(crate) compactions_deferred: AtomicU64,
+    pub(crate) read_waits: AtomicU64,
+    pub(crate) read_wait_micros: AtomicU64,
+    pub(crate) stale_readers: AtomicU64,
+    pub(crate) gets: AtomicU64,
+    pub(crate) sets: AtomicU64,
+    pub(crate) appends: AtomicU64,
+    pub(crate) get_latency: LatencyHistogram,
+    pub(crate) set_latency: LatencyHistogram,
+}
+
+impl Metrics {
+    pub(crate) fn incr(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add(counter: &AtomicU64, n: u64) {
+        counter.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub(crate) fn get(counter: &AtomicU64) -> u64 {
+        counter.load(Ordering::Relaxed)
+    }
+}
+
+/// The state of the `Options::mirror_path` copy; from
+/// [`crate::Engine::mirror_status`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MirrorStatus {
+    pub path: PathBuf,
+    pub on_failure: MirrorFailure,
+    /// Why mirroring stopped, with the mirror left as of the last write that
+    /// reached it; `None` while it is kept level with the data file.
+    pub stopped: Option<String>,
+    /// Bytes appended and header bytes rewritten on the mirror since load.
+    pub bytes_written: u64,
+    pub failures: u64,
+    /// Times the data file was copied over the mirror: at a load or reload
+    /// that found it out of date, and after every compaction.
+    pub copies: u64,
+}
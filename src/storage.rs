@@ -0,0 +1,235 @@
+use std::fmt::Debug;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, Write};
+use std::path::Path;
+
+/// A file handle opened through a [`Storage`].
+pub trait StorageFile: Read + Write + Seek + Send + Sync {
+    fn sync_all(&self) -> io::Result<()>;
+    fn set_len(&self, len: u64) -> io::Result<()>;
+    fn len(&self) -> io::Result<u64>;
+
+    /// Reserves space so the file is at least `len` bytes long, zero-filled.
+    fn allocate(&self, len: u64) -> io::Result<()> {
+        if self.len()? < len {
+            self.set_len(len)?;
+        }
+        Ok(())
+    }
+
+    fn is_empty(&self) -> io::Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Blocks until this handle holds an exclusive advisory lock on the file.
+    /// The lock is released by [`StorageFile::unlock`] or when the handle is
+    /// closed. Backends without locking may make this a no-op.
+    fn lock(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn unlock(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpenMode {
+    /// Read-only; the file must exist.
+    Read,
+    /// Read and write, creating the file if it is missing.
+    ReadWrite,
+    /// Read and write, creating the file or truncating it to zero length.
+    Truncate,
+}
+
+/// How [`Storage::clone_file`] produced its copy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloneMethod {
+    /// Copy-on-write clone sharing extents with the source (`FICLONE`).
+    Reflink,
+    /// Plain byte-for-byte copy.
+    Copy,
+}
+
+/// Every filesystem operation the engine performs goes through this trait, so
+/// tests and embedders can inject latency, faults, or accounting.
+pub trait Storage: Send + Sync + Debug {
+    fn open(&self, path: &Path, mode: OpenMode) -> io::Result<Box<dyn StorageFile>>;
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+    fn remove_file(&self, path: &Path) -> io::Result<()>;
+    fn create_dir_all(&self, path: &Path) -> io::Result<()>;
+    /// Makes directory entry changes under `path` durable.
+    fn sync_dir(&self, path: &Path) -> io::Result<()>;
+
+    /// Bytes free for new data on the filesystem holding `path`, or `None`
+    /// when the backend cannot tell.
+    fn available_space(&self, _path: &Path) -> io::Result<Option<u64>> {
+        Ok(None)
+    }
+
+    /// Whether directories `a` and `b` are on the same filesystem, or `None`
+    /// when the backend cannot tell.
+    fn same_filesystem(&self, _a: &Path, _b: &Path) -> io::Result<Option<bool>> {
+        Ok(None)
+    }
+
+    /// Writes the first `len` bytes of `from` to a new file at `to` and
+    /// fsyncs it, using the cheapest mechanism available.
+    fn clone_file(&self, from: &Path, to: &Path, len: u64) -> io::Result<CloneMethod> {
+        copy_prefix(self, from, to, len)
+    }
+}
+
+/// Byte-copies the first `len` bytes of `from` to `to` and fsyncs the copy.
+fn copy_prefix<S: Storage + ?Sized>(
+    storage: &S,
+    from: &Path,
+    to: &Path,
+    len: u64,
+) -> io::Result<CloneMethod> {
+    let mut src = storage.open(from, OpenMode::Read)?;
+    let mut dst = storage.open(to, OpenMode::Truncate)?;
+    let copied = io::copy(&mut (&mut src).take(len), &mut dst)?;
+    if copied != len {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "source shorter than requested clone length",
+        ));
+    }
+    dst.flush()?;
+    dst.sync_all()?;
+    Ok(CloneMethod::Copy)
+}
+
+/// The default storage: plain `std::fs` files.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct FsStorage;
+
+impl StorageFile for File {
+    fn sync_all(&self) -> io::Result<()> {
+        File::sync_all(self)
+    }
+
+    fn set_len(&self, len: u64) -> io::Result<()> {
+        File::set_len(self, len)
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+
+    fn lock(&self) -> io::Result<()> {
+        File::lock(self)
+    }
+
+    fn unlock(&self) -> io::Result<()> {
+        File::unlock(self)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn allocate(&self, len: u64) -> io::Result<()> {
+        use std::os::fd::AsRawFd;
+
+        let current = StorageFile::len(self)?;
+        if current >= len {
+            return Ok(());
+        }
+
+        // posix_fallocate reserves real extents; fall back to a sparse
+        // extension on filesystems that do not support it.
+        let ret = unsafe {
+            libc::posix_fallocate(
+                self.as_raw_fd(),
+                current as libc::off_t,
+                (len - current) as libc::off_t,
+            )
+        };
+        match ret {
+            0 => Ok(()),
+            libc::EOPNOTSUPP | libc::EINVAL => File::set_len(self, len),
+            err => Err(io::Error::from_raw_os_error(err)),
+        }
+    }
+}
+
+impl Storage for FsStorage {
+    fn open(&self, path: &Path, mode: OpenMode) -> io::Result<Box<dyn StorageFile>> {
+        let mut options = OpenOptions::new();
+        match mode {
+            OpenMode::Read => options.read(true),
+            OpenMode::ReadWrite => options.read(true).write(true).create(true).truncate(false),
+            OpenMode::Truncate => options.read(true).write(true).create(true).truncate(true),
+        };
+        Ok(Box::new(options.open(path)?))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        std::fs::rename(from, to)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        std::fs::remove_file(path)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        std::fs::create_dir_all(path)
+    }
+
+    #[cfg(unix)]
+    fn sync_dir(&self, path: &Path) -> io::Result<()> {
+        File::open(path)?.sync_all()
+    }
+
+    #[cfg(not(unix))]
+    fn sync_dir(&self, _path: &Path) -> io::Result<()> {
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    fn same_filesystem(&self, a: &Path, b: &Path) -> io::Result<Option<bool>> {
+        use std::os::unix::fs::MetadataExt;
+
+        Ok(Some(
+            std::fs::metadata(a)?.dev() == std::fs::metadata(b)?.dev(),
+        ))
+    }
+
+    #[cfg(target_os = "linux")]
+    fn available_space(&self, path: &Path) -> io::Result<Option<u64>> {
+        use std::ffi::CString;
+        use std::os::unix::ffi::OsStrExt;
+
+        let c_path = CString::new(path.as_os_str().as_bytes())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+        if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Some(stat.f_bavail as u64 * stat.f_frsize as u64))
+    }
+
+    #[cfg(target_os = "linux")]
+    fn clone_file(&self, from: &Path, to: &Path, len: u64) -> io::Result<CloneMethod> {
+        use std::os::fd::AsRawFd;
+
+        let src = File::open(from)?;
+        let dst = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(to)?;
+
+        // Reflinks only work within one filesystem that supports them (btrfs,
+        // XFS, ...); anything else falls back to a byte copy.
+        let ret = unsafe { libc::ioctl(dst.as_raw_fd(), libc::FICLONE, src.as_raw_fd()) };
+        if ret != 0 {
+            drop(dst);
+            return copy_prefix(self, from, to, len);
+        }
+
+        dst.set_len(len)?;
+        dst.sync_all()?;
+        Ok(CloneMethod::Reflink)
+    }
+}
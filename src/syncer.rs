@@ -0,0 +1,204 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::engine::{FileHandle, now_millis};
+use crate::options::Hook;
+use crate::runtime::{JobHandle, KvRuntime};
+use crate::workers::{self, BackgroundWorker};
+
+/// Sync bookkeeping shared by the engine, explicit `Engine::sync` calls, and
+/// the background thread.
+#[derive(Default)]
+pub(crate) struct SyncState {
+    dirty: AtomicBool,
+    pub(crate) syncs: AtomicU64,
+    pub(crate) errors: AtomicU64,
+    /// Milliseconds since the epoch of the last successful sync; 0 if none.
+    pub(crate) last_sync_millis: AtomicI64,
+    stop: Mutex<bool>,
+    wake: Condvar,
+}
+
+impl SyncState {
+    /// Records that the log has bytes not yet known to be on disk. The
+    /// caller must hold the file mutex.
+    pub(crate) fn mark_dirty(&self) {
+        self.dirty.store(true, Ordering::Release);
+    }
+
+    /// Flushes and fsyncs `file` if anything was written since the last
+    /// sync. Returns whether an fsync was issued.
+    ///
+    /// The dirty flag is cleared under the file mutex, so a write that lands
+    /// during the fsync marks the log dirty again and is picked up next time,
+    /// while back-to-back callers never fsync the same bytes twice.
+    pub(crate) fn sync(&self, file: &Mutex<FileHandle>) -> io::Result<bool> {
+        let mut file = file.lock().unwrap();
+        if !self.dirty.swap(false, Ordering::AcqRel) {
+            return Ok(false);
+        }
+
+        let result = file.flush().and_then(|_| file.sync_all());
+        match result {
+            Ok(()) => {
+                self.syncs.fetch_add(1, Ordering::Relaxed);
+                self.last_sync_millis.store(now_millis(), Ordering::Relaxed);
+                Ok(true)
+            }
+            Err(e) => {
+                self.dirty.store(true, Ordering::Release);
+                self.errors.fetch_add(1, Ordering::Relaxed);
+                Err(e)
+            }
+        }
+    }
+}
+
+/// Drives `Durability::Interval`, either on a thread of its own or as a job
+/// on a shared [`KvRuntime`]. Dropping it stops the periodic syncs after a
+/// final one; so does stopping it from a `WorkerSet`.
+pub(crate) struct Syncer {
+    state: Arc<SyncState>,
+    worker: Worker,
+}
+
+enum Worker {
+    Thread(Option<JoinHandle<()>>),
+    Job {
+        job: Option<JobHandle>,
+        file: Arc<Mutex<FileHandle>>,
+        on_error: Option<Hook<io::Error>>,
+    },
+}
+
+impl Syncer {
+    pub(crate) fn spawn(
+        file: Arc<Mutex<FileHandle>>,
+        state: Arc<SyncState>,
+        period: Duration,
+        jitter: Duration,
+        on_error: Option<Hook<io::Error>>,
+    ) -> io::Result<Self> {
+        let thread_state = Arc::clone(&state);
+        let handle = thread::Builder::new()
+            .name("kv-sync".into())
+            .spawn(move || {
+                let state = thread_state;
+                let mut stopped = state.stop.lock().unwrap();
+                loop {
+                    let delay = jittered(period, jitter);
+                    stopped = state
+                        .wake
+                        .wait_timeout_while(stopped, delay, |stop| !*stop)
+                        .unwrap()
+                        .0;
+                    if *stopped {
+                        break;
+                    }
+                    drop(stopped);
+                    report(state.sync(&file), &on_error);
+                    stopped = state.stop.lock().unwrap();
+                }
+                drop(stopped);
+                report(state.sync(&file), &on_error);
+            })?;
+
+        Ok(Syncer {
+            state,
+            worker: Worker::Thread(Some(handle)),
+        })
+    }
+
+    /// Like [`Syncer::spawn`], but runs the periodic syncs on `runtime`'s
+    /// worker thread.
+    pub(crate) fn schedule(
+        runtime: &KvRuntime,
+        file: Arc<Mutex<FileHandle>>,
+        state: Arc<SyncState>,
+        period: Duration,
+        jitter: Duration,
+        on_error: Option<Hook<io::Error>>,
+    ) -> Self {
+        let job_file = Arc::clone(&file);
+        let job_state = Arc::clone(&state);
+        let job_on_error = on_error.clone();
+        let job = runtime.schedule(jittered(period, jitter), move || {
+            report(job_state.sync(&job_file), &job_on_error);
+            Some(jittered(period, jitter))
+        });
+
+        Syncer {
+            state,
+            worker: Worker::Job {
+                job: Some(job),
+                file,
+                on_error,
+            },
+        }
+    }
+}
+
+impl BackgroundWorker for Syncer {
+    fn name(&self) -> &'static str {
+        "kv-sync"
+    }
+
+    fn signal_stop(&mut self) {
+        if let Worker::Thread(_) = self.worker {
+            *self.state.stop.lock().unwrap() = true;
+            self.state.wake.notify_all();
+        }
+    }
+
+    /// The thread syncs one last time before it exits; for a job, that last
+    /// sync is done here once the job is cancelled.
+    fn wait_stopped(&mut self, deadline: Option<Instant>) -> bool {
+        match &mut self.worker {
+            Worker::Thread(handle) => workers::join_until(handle, deadline),
+            Worker::Job {
+                job,
+                file,
+                on_error,
+            } => {
+                let Some(mut job) = job.take() else {
+                    return true;
+                };
+                if !job.cancel(deadline) {
+                    return false;
+                }
+                report(self.state.sync(file), on_error);
+                true
+            }
+        }
+    }
+}
+
+impl Drop for Syncer {
+    fn drop(&mut self) {
+        self.signal_stop();
+        self.wait_stopped(None);
+    }
+}
+
+fn report(result: io::Result<bool>, on_error: &Option<Hook<io::Error>>) {
+    if let (Err(e), Some(hook)) = (result, on_error) {
+        hook.call(&e);
+    }
+}
+
+/// `period` plus a random slice of `jitter`, so engines started together do
+/// not fsync in lockstep.
+fn jittered(period: Duration, jitter: Duration) -> Duration {
+    let jitter_nanos = jitter.as_nanos() as u64;
+    if jitter_nanos == 0 {
+        return period;
+    }
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(now_millis() as u64);
+    period + Duration::from_nanos(hasher.finish() % (jitter_nanos + 1))
+}
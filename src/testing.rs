@@ -0,0 +1,351 @@
+//! Deterministic stores for tests, behind the `testing` feature.
+//!
+//! [`StoreFixture::builder`] writes a store through the real `Engine` API,
+//! so its file is exactly what the current format writes, and then applies
+//! any [`Corruption`]s to it with plain file writes. The same seed always
+//! gives the same keys, values, overwrites, deletes, and record layout; only
+//! the timestamps inside the records differ between builds. The fixture
+//! remembers where each key's current record sits, so a test can aim a
+//! corruption at a record without knowing the record layout itself.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::batch::WriteBatch;
+use crate::constants::FORMAT_VERSION;
+use crate::engine::Engine;
+use crate::framing;
+use crate::options::{ConflictPolicy, Options};
+use crate::types::LogIndex;
+use crate::workload::Rng;
+
+/// Seed a builder uses unless given one.
+pub const DEFAULT_FIXTURE_SEED: u64 = 0x5EED_F1C5;
+
+/// Length of the shared value behind the fixture's `Blob` record.
+const DEDUP_VALUE_LEN: usize = 256;
+
+/// Damage to apply to a store file, with raw writes that bypass the engine.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Corruption {
+    /// Inverts every bit of the byte at this offset. Applying it a second
+    /// time restores the byte.
+    FlipByte(u64),
+    /// Writes these bytes over the file from this offset.
+    Overwrite(u64, Vec<u8>),
+    /// Appends these bytes after the last record, as a write cut short by a
+    /// crash leaves them.
+    TornTail(Vec<u8>),
+    /// Cuts the file to this length.
+    Truncate(u64),
+}
+
+impl Corruption {
+    /// Applies this to the file at `path`, whether or not an engine has it
+    /// open.
+    pub fn apply(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+        match self {
+            Corruption::FlipByte(offset) => {
+                let mut byte = [0u8];
+                file.seek(SeekFrom::Start(*offset))?;
+                file.read_exact(&mut byte)?;
+                file.seek(SeekFrom::Start(*offset))?;
+                file.write_all(&[!byte[0]])?;
+            }
+            Corruption::Overwrite(offset, bytes) => {
+                file.seek(SeekFrom::Start(*offset))?;
+                file.write_all(bytes)?;
+            }
+            Corruption::TornTail(bytes) => {
+                file.seek(SeekFrom::End(0))?;
+                file.write_all(bytes)?;
+            }
+            Corruption::Truncate(len) => file.set_len(*len)?,
+        }
+        file.sync_all()
+    }
+}
+
+/// Settings for a [`StoreFixture`]; from [`StoreFixture::builder`].
+#[derive(Debug, Clone)]
+pub struct StoreFixtureBuilder {
+    keys: usize,
+    overwrite_ratio: f64,
+    deleted_ratio: f64,
+    value_size: Range<usize>,
+    seed: u64,
+    every_kind: bool,
+    corruptions: Vec<Corruption>,
+}
+
+impl StoreFixtureBuilder {
+    /// Writes `n` keys, [`StoreFixture::key`]`(0)` to `key(n - 1)`. 100
+    /// unless set.
+    pub fn keys(mut self, n: usize) -> Self {
+        self.keys = n;
+        self
+    }
+
+    /// Writes a second value to this fraction of the keys, picked by the
+    /// seed, after every key has its first.
+    pub fn overwrite_ratio(mut self, ratio: f64) -> Self {
+        self.overwrite_ratio = ratio;
+        self
+    }
+
+    /// Deletes this fraction of the keys, picked by the seed, after the
+    /// overwrites. A key may be both overwritten and deleted.
+    pub fn deleted_ratio(mut self, ratio: f64) -> Self {
+        self.deleted_ratio = ratio;
+        self
+    }
+
+    /// Draws value lengths from this range. 16..64 unless set.
+    pub fn value_size(mut self, range: Range<usize>) -> Self {
+        self.value_size = range;
+        self
+    }
+
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    /// Writes only the keys' puts and deletes, without the records under
+    /// `fixture/` that exercise every other record kind.
+    pub fn plain(mut self) -> Self {
+        self.every_kind = false;
+        self
+    }
+
+    /// Applies `corruption` once the store is written, after any added
+    /// before it.
+    pub fn corrupt_at(mut self, corruption: Corruption) -> Self {
+        self.corruptions.push(corruption);
+        self
+    }
+
+    /// Writes the store to `path`, replacing any file there.
+    ///
+    /// The keys are written with the engine's default options, one `Put` and
+    /// one `Tombstone` record per write. Unless [`plain`](Self::plain), a
+    /// second load then adds a record of every other kind under `fixture/`:
+    /// a batch holding an `ExpiringPut`, a `SoftDelete`, a `Blob` with two
+    /// `BlobRef`s, a `StalePut`, and the `Epoch` of a fenced writer. There
+    /// is no `Archived` stub, which would need an archive file beside it.
+    /// Nothing compacts while the fixture is written, so the file holds
+    /// every record, and the header keeps the default compaction threshold.
+    pub fn build(self, path: impl AsRef<Path>) -> io::Result<StoreFixture> {
+        let path = path.as_ref();
+        match fs::remove_file(path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        let mut rng = Rng(self.seed);
+        let mut fixture = StoreFixture {
+            path: path.to_owned(),
+            live: BTreeMap::new(),
+            deleted: BTreeSet::new(),
+            overwritten: BTreeSet::new(),
+            records: HashMap::new(),
+            log_len: 0,
+        };
+
+        let no_compaction = Options {
+            compact_threshold: Some(u64::MAX),
+            ..Options::default()
+        };
+        let mut engine = Engine::load_with_options(path, no_compaction.clone())?;
+        for i in 0..self.keys {
+            let value = self.value(&mut rng);
+            fixture.put(&engine, StoreFixture::key(i), value)?;
+        }
+        let mut order: Vec<usize> = (0..self.keys).collect();
+        shuffle(&mut order, &mut rng);
+        for &i in &order[..self.count(self.overwrite_ratio)] {
+            let key = StoreFixture::key(i);
+            let value = self.value(&mut rng);
+            fixture.put(&engine, key.clone(), value)?;
+            fixture.overwritten.insert(key);
+        }
+        shuffle(&mut order, &mut rng);
+        for &i in &order[..self.count(self.deleted_ratio)] {
+            let key = StoreFixture::key(i);
+            engine.del(&key)?;
+            fixture.live.remove(&key);
+            fixture.records.remove(&key);
+            fixture.deleted.insert(key);
+        }
+
+        if self.every_kind {
+            drop(engine);
+            let options = Options {
+                dedup_min_value_len: Some(DEDUP_VALUE_LEN),
+                conflict_policy: ConflictPolicy::KeepNewest,
+                fencing: Some(Duration::from_secs(60)),
+                ..no_compaction
+            };
+            engine = Engine::load_with_options(path, options)?;
+            fixture.write_every_kind(&engine)?;
+        }
+        drop(engine);
+
+        fixture.log_len = fs::metadata(path)?.len();
+        for corruption in &self.corruptions {
+            corruption.apply(path)?;
+        }
+        Ok(fixture)
+    }
+
+    fn value(&self, rng: &mut Rng) -> Vec<u8> {
+        let Range { start, end } = self.value_size;
+        let mut len = start;
+        if end > start {
+            len += (rng.next() % (end - start) as u64) as usize;
+        }
+        (0..len).map(|_| rng.next() as u8).collect()
+    }
+
+    /// How many of the keys `ratio` picks.
+    fn count(&self, ratio: f64) -> usize {
+        (self.keys as f64 * ratio.clamp(0.0, 1.0)).round() as usize
+    }
+}
+
+/// A store written by a [`StoreFixtureBuilder`], and what a clean load of it
+/// must read back.
+#[derive(Debug)]
+pub struct StoreFixture {
+    path: PathBuf,
+    live: BTreeMap<Vec<u8>, Vec<u8>>,
+    deleted: BTreeSet<Vec<u8>>,
+    overwritten: BTreeSet<Vec<u8>>,
+    /// Where each live key's current value was written, for the keys the
+    /// builder put one at a time.
+    records: HashMap<Vec<u8>, LogIndex>,
+    log_len: u64,
+}
+
+impl StoreFixture {
+    pub fn builder() -> StoreFixtureBuilder {
+        StoreFixtureBuilder {
+            keys: 100,
+            overwrite_ratio: 0.0,
+            deleted_ratio: 0.0,
+            value_size: 16..64,
+            seed: DEFAULT_FIXTURE_SEED,
+            every_kind: true,
+            corruptions: Vec::new(),
+        }
+    }
+
+    /// The `i`th key a fixture writes.
+    pub fn key(i: usize) -> Vec<u8> {
+        format!("key-{:06}", i).into_bytes()
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Every key that reads back a value, with the value.
+    pub fn live(&self) -> &BTreeMap<Vec<u8>, Vec<u8>> {
+        &self.live
+    }
+
+    /// Keys that were written and then deleted or soft-deleted.
+    pub fn deleted(&self) -> &BTreeSet<Vec<u8>> {
+        &self.deleted
+    }
+
+    /// Keys written twice, deleted or not.
+    pub fn overwritten(&self) -> &BTreeSet<Vec<u8>> {
+        &self.overwritten
+    }
+
+    /// The file's length before any corruption.
+    pub fn log_len(&self) -> u64 {
+        self.log_len
+    }
+
+    /// Where the record holding `key`'s current value starts, after its
+    /// length prefix, and how long it is. `None` for deleted keys and the
+    /// `fixture/` keys.
+    pub fn record(&self, key: &[u8]) -> Option<&LogIndex> {
+        self.records.get(key)
+    }
+
+    /// Offset of the length prefix in front of `key`'s current record.
+    pub fn prefix_offset(&self, key: &[u8]) -> Option<u64> {
+        let record = self.record(key)?;
+        Some(record.pos - framing::prefix_len(FORMAT_VERSION, record.len))
+    }
+
+    /// Offset of the 8-byte value length inside `key`'s current record.
+    pub fn value_len_offset(&self, key: &[u8]) -> Option<u64> {
+        let record = self.record(key)?;
+        let (value_offset, _) =
+            Engine::value_span(FORMAT_VERSION, key.len() as u64, record.len).ok()?;
+        Some(record.pos + value_offset - 8)
+    }
+
+    /// Offset of the kind tag, the last byte, of `key`'s current record.
+    pub fn kind_offset(&self, key: &[u8]) -> Option<u64> {
+        let record = self.record(key)?;
+        Some(record.pos + record.len - 1)
+    }
+
+    fn put(&mut self, engine: &Engine, key: Vec<u8>, value: Vec<u8>) -> io::Result<()> {
+        let record = engine.set_indexed(&key, &value)?;
+        self.records.insert(key.clone(), record.index);
+        self.live.insert(key, value);
+        Ok(())
+    }
+
+    fn write_every_kind(&mut self, engine: &Engine) -> io::Result<()> {
+        let mut batch = WriteBatch::new();
+        batch.put(b"fixture/batch", b"in a batch");
+        batch.put_with_ttl(
+            b"fixture/ttl",
+            b"expires in a day",
+            Duration::from_secs(24 * 60 * 60),
+        );
+        engine.apply_batch(&batch)?;
+
+        engine.set(b"fixture/soft", b"soft-deleted")?;
+        engine.soft_del(b"fixture/soft")?;
+
+        let shared = vec![0xD5; DEDUP_VALUE_LEN];
+        engine.set(b"fixture/dedup-a", &shared)?;
+        engine.set(b"fixture/dedup-b", &shared)?;
+
+        engine.set(b"fixture/stale", b"current")?;
+        engine.set_with_tstamp(b"fixture/stale", b"stale", 1)?;
+
+        let live: [(&[u8], &[u8]); 5] = [
+            (b"fixture/batch", b"in a batch"),
+            (b"fixture/ttl", b"expires in a day"),
+            (b"fixture/dedup-a", &shared),
+            (b"fixture/dedup-b", &shared),
+            (b"fixture/stale", b"current"),
+        ];
+        for (key, value) in live {
+            self.live.insert(key.to_vec(), value.to_vec());
+        }
+        self.deleted.insert(b"fixture/soft".to_vec());
+        Ok(())
+    }
+}
+
+/// Fisher-Yates, driven by `rng`.
+fn shuffle(items: &mut [usize], rng: &mut Rng) {
+    for i in (1..items.len()).rev() {
+        let j = (rng.next() % (i as u64 + 1)) as usize;
+        items.swap(i, j);
+    }
+}
@@ -1,14 +1,260 @@
 use wincode::{SchemaRead, SchemaWrite};
 
-#[derive(SchemaWrite, SchemaRead, Debug)]
+/// Bytes of expiry timestamp in front of an `ExpiringPut` value.
+pub const EXPIRY_SIZE: usize = 8;
+
+/// Bytes of a record's origin, in records that have one.
+pub const ORIGIN_SIZE: usize = 16;
+
+#[derive(SchemaWrite, SchemaRead, Debug, Clone, Copy, PartialEq, Eq)]
+#[wincode(tag_encoding = "u8")]
+pub enum EntryKind {
+    Put,
+    Tombstone,
+    SoftDelete,
+    /// A deduplicated value stored once; `key` is the content hash.
+    Blob,
+    /// A user key whose value is the `Blob` named by the hash in `value`.
+    BlobRef,
+    /// A `Put` that stops being visible at a deadline: `value` is the expiry
+    /// (ms since the epoch, i64 LE) followed by the value itself.
+    ExpiringPut,
+    /// Opens a write batch; `value` holds the number of records that follow
+    /// in it (u64 LE). Replay applies the batch only once all are present.
+    BatchBegin,
+    /// A `Put` that lost to a newer stored value under
+    /// `ConflictPolicy::KeepNewest`: kept as history, never current.
+    StalePut,
+    /// Opens the records of one writer under `Options::fencing`; `value` is
+    /// its epoch (u64 LE). Never live.
+    Epoch,
+    /// A `Put` whose value `Engine::archive` moved to the archive file:
+    /// `value` is where the archived record sits in it, its offset and
+    /// length (u64 LE each).
+    Archived,
+}
+
+#[derive(Debug, Clone)]
 pub struct DataFileEntry {
     pub tstamp: i64,
     pub key: Vec<u8>,
     pub value: Option<Vec<u8>>,
+    pub kind: EntryKind,
+    /// The writer the record came from: `Options::writer_id`, or the id a
+    /// caller passed to `Engine::set_with_origin`. `None` in records
+    /// written without one, and in every record before KVS7.
+    pub origin: Option<[u8; ORIGIN_SIZE]>,
 }
 
-#[derive(Debug, Clone)]
+impl EntryKind {
+    /// The kind whose tag byte is `tag`.
+    pub fn from_tag(tag: u8) -> Option<EntryKind> {
+        EntryKind::ALL.get(tag as usize).copied()
+    }
+
+    /// Every kind, in tag order.
+    pub const ALL: [EntryKind; 10] = [
+        EntryKind::Put,
+        EntryKind::Tombstone,
+        EntryKind::SoftDelete,
+        EntryKind::Blob,
+        EntryKind::BlobRef,
+        EntryKind::ExpiringPut,
+        EntryKind::BatchBegin,
+        EntryKind::StalePut,
+        EntryKind::Epoch,
+        EntryKind::Archived,
+    ];
+}
+
+impl DataFileEntry {
+    pub fn put(tstamp: i64, key: Vec<u8>, value: Vec<u8>) -> Self {
+        DataFileEntry {
+            tstamp,
+            key,
+            value: Some(value),
+            kind: EntryKind::Put,
+            origin: None,
+        }
+    }
+
+    pub fn expiring_put(tstamp: i64, key: Vec<u8>, value: &[u8], expires_at: i64) -> Self {
+        let mut payload = Vec::with_capacity(EXPIRY_SIZE + value.len());
+        payload.extend_from_slice(&expires_at.to_le_bytes());
+        payload.extend_from_slice(value);
+        DataFileEntry {
+            tstamp,
+            key,
+            value: Some(payload),
+            kind: EntryKind::ExpiringPut,
+            origin: None,
+        }
+    }
+
+    /// The expiry of an `ExpiringPut`.
+    pub fn expires_at(&self) -> Option<i64> {
+        match (self.kind, &self.value) {
+            (EntryKind::ExpiringPut, Some(payload)) if payload.len() >= EXPIRY_SIZE => Some(
+                i64::from_le_bytes(payload[..EXPIRY_SIZE].try_into().unwrap()),
+            ),
+            _ => None,
+        }
+    }
+
+    /// The user-visible value, without an `ExpiringPut`'s expiry prefix.
+    pub fn into_value(self) -> Option<Vec<u8>> {
+        match self.kind {
+            EntryKind::ExpiringPut => self
+                .value
+                .map(|payload| payload.get(EXPIRY_SIZE..).unwrap_or_default().to_vec()),
+            _ => self.value,
+        }
+    }
+
+    pub fn epoch(tstamp: i64, epoch: u64) -> Self {
+        DataFileEntry {
+            tstamp,
+            key: Vec::new(),
+            value: Some(epoch.to_le_bytes().to_vec()),
+            kind: EntryKind::Epoch,
+            origin: None,
+        }
+    }
+
+    /// The epoch of an `Epoch` record.
+    pub fn writer_epoch(&self) -> Option<u64> {
+        match (self.kind, &self.value) {
+            (EntryKind::Epoch, Some(value)) => {
+                Some(u64::from_le_bytes(value.get(..8)?.try_into().ok()?))
+            }
+            _ => None,
+        }
+    }
+
+    pub fn archived(tstamp: i64, key: Vec<u8>, archived_at: &LogIndex) -> Self {
+        let mut value = Vec::with_capacity(16);
+        value.extend_from_slice(&archived_at.pos.to_le_bytes());
+        value.extend_from_slice(&archived_at.len.to_le_bytes());
+        DataFileEntry {
+            tstamp,
+            key,
+            value: Some(value),
+            kind: EntryKind::Archived,
+            origin: None,
+        }
+    }
+
+    /// Where in the archive file an `Archived` record's value sits.
+    pub fn archived_at(&self) -> Option<LogIndex> {
+        match (self.kind, &self.value) {
+            (EntryKind::Archived, Some(value)) => Some(LogIndex {
+                pos: u64::from_le_bytes(value.get(..8)?.try_into().ok()?),
+                len: u64::from_le_bytes(value.get(8..16)?.try_into().ok()?),
+            }),
+            _ => None,
+        }
+    }
+
+    /// This entry with `origin` as its origin.
+    pub fn with_origin(self, origin: Option<[u8; ORIGIN_SIZE]>) -> Self {
+        DataFileEntry { origin, ..self }
+    }
+
+    pub fn tombstone(tstamp: i64, key: Vec<u8>) -> Self {
+        DataFileEntry {
+            tstamp,
+            key,
+            value: None,
+            kind: EntryKind::Tombstone,
+            origin: None,
+        }
+    }
+}
+
+/// Entry layout of `KVS1` files, which predate entry kinds.
+#[derive(SchemaWrite, SchemaRead, Debug)]
+pub struct DataFileEntryV1 {
+    pub tstamp: i64,
+    pub key: Vec<u8>,
+    pub value: Option<Vec<u8>>,
+}
+
+/// Entry layout of `KVS2` to `KVS4` files, which predate origins.
+#[derive(SchemaWrite, SchemaRead, Debug)]
+pub struct DataFileEntryV2 {
+    pub tstamp: i64,
+    pub key: Vec<u8>,
+    pub value: Option<Vec<u8>>,
+    pub kind: EntryKind,
+}
+
+impl From<&DataFileEntry> for DataFileEntryV2 {
+    fn from(entry: &DataFileEntry) -> Self {
+        DataFileEntryV2 {
+            tstamp: entry.tstamp,
+            key: entry.key.clone(),
+            value: entry.value.clone(),
+            kind: entry.kind,
+        }
+    }
+}
+
+impl From<DataFileEntryV2> for DataFileEntry {
+    fn from(entry: DataFileEntryV2) -> Self {
+        DataFileEntry {
+            tstamp: entry.tstamp,
+            key: entry.key,
+            value: entry.value,
+            kind: entry.kind,
+            origin: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct LogIndex {
     pub pos: u64,
     pub len: u64,
 }
+
+/// Where `Engine::set_indexed` wrote a record, for reading it back with
+/// `Engine::get_at_index`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecordRef {
+    pub index: LogIndex,
+    /// The engine's file generation at the write. Compaction and reloads
+    /// replace the file and move to a new generation, after which the
+    /// reference no longer resolves.
+    pub generation: u64,
+}
+
+/// What [`crate::Engine::stat`] knows about a live key without reading
+/// its value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyStat {
+    /// Length of the value `get` would return.
+    pub value_len: u64,
+    /// When the value was written, ms since the epoch.
+    pub tstamp: i64,
+    /// When the key expires, ms since the epoch; `None` without a TTL.
+    pub expires_at: Option<i64>,
+    /// The key's current record. It changes with every write of the key,
+    /// so two equal references mean the value has not changed in between;
+    /// compaction and reloads change it too.
+    pub record: RecordRef,
+    /// The writer the value came from; see [`DataFileEntry::origin`].
+    pub origin: Option<[u8; ORIGIN_SIZE]>,
+}
+
+/// A stored blob and how many live keys reference it.
+#[derive(Debug, Clone)]
+pub struct Blob {
+    pub index: LogIndex,
+    pub refs: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct SoftDeleted {
+    pub index: LogIndex,
+    pub deleted_at: i64,
+}
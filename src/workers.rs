@@ -0,0 +1,98 @@
+//! The engine's background workers, and the order they stop in.
+//!
+//! Every worker an engine starts is registered in its [`WorkerSet`] under a
+//! [`Stage`]. `Engine::close` first stops foreground writes, under the file
+//! mutex, then stops the `Flush` workers, fsyncs, and only then stops the
+//! `Reader` workers, so nothing writes after the final fsync and nothing
+//! that reads is pulled out from under it. All of a stage's workers are
+//! told to stop before any is waited for, and the whole shutdown shares one
+//! deadline: a worker still running when it passes is left to finish on
+//! its own and named in `EngineError::ShutdownTimedOut`.
+
+use std::sync::Mutex;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// How often a bounded wait checks whether a worker thread has exited.
+const POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// When a worker stops relative to the engine's final fsync. Stages stop in
+/// declaration order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum Stage {
+    /// Syncs the log; stopped before the final fsync.
+    Flush,
+    /// Only reads, or releases read resources; stopped after it.
+    Reader,
+}
+
+/// A background thread or runtime job.
+pub(crate) trait BackgroundWorker: Send {
+    /// Thread name, reported when the worker misses the deadline.
+    fn name(&self) -> &'static str;
+
+    /// Asks the worker to stop, without waiting.
+    fn signal_stop(&mut self);
+
+    /// Waits for a signalled worker to stop, until `deadline` if one is
+    /// given. Returns whether it stopped. Either way, dropping the worker
+    /// afterwards does not block.
+    fn wait_stopped(&mut self, deadline: Option<Instant>) -> bool;
+}
+
+#[derive(Default)]
+pub(crate) struct WorkerSet {
+    workers: Mutex<Vec<(Stage, Box<dyn BackgroundWorker>)>>,
+}
+
+impl WorkerSet {
+    pub(crate) fn register(&self, stage: Stage, worker: impl BackgroundWorker + 'static) {
+        self.workers.lock().unwrap().push((stage, Box::new(worker)));
+    }
+
+    /// Stops and removes every worker registered under `stage`. Returns
+    /// the names of those still running at `deadline`.
+    pub(crate) fn stop(&self, stage: Stage, deadline: Option<Instant>) -> Vec<&'static str> {
+        let mut stopping: Vec<_> = {
+            let mut workers = self.workers.lock().unwrap();
+            let (stopping, kept) = std::mem::take(&mut *workers)
+                .into_iter()
+                .partition(|(s, _)| *s == stage);
+            *workers = kept;
+            stopping
+        };
+        for (_, worker) in &mut stopping {
+            worker.signal_stop();
+        }
+        stopping
+            .iter_mut()
+            .filter_map(|(_, worker)| (!worker.wait_stopped(deadline)).then(|| worker.name()))
+            .collect()
+    }
+}
+
+impl Drop for WorkerSet {
+    fn drop(&mut self) {
+        for stage in [Stage::Flush, Stage::Reader] {
+            self.stop(stage, None);
+        }
+    }
+}
+
+/// Waits for the thread in `handle` to exit, until `deadline` if one is
+/// given, and joins it. A thread still running at the deadline is detached.
+pub(crate) fn join_until(handle: &mut Option<JoinHandle<()>>, deadline: Option<Instant>) -> bool {
+    let Some(running) = handle.take() else {
+        return true;
+    };
+    if let Some(deadline) = deadline {
+        while !running.is_finished() {
+            if Instant::now() >= deadline {
+                return false;
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+    let _ = running.join();
+    true
+}
@@ -0,0 +1,380 @@
+//! A small built-in load generator, behind `kv bench`.
+//!
+//! [`run`] drives an engine from several threads with a fixed mix of gets,
+//! sets, and deletes over a bounded key space, then reports throughput,
+//! latency percentiles, the resulting file size, and how many compactions
+//! ran. Keys are drawn uniformly or from a zipfian distribution, where a few
+//! hot keys take most of the traffic; the two stress the index and
+//! compaction differently.
+
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use crate::engine::Engine;
+use crate::stats::{LatencyHistogram, LatencySnapshot};
+
+/// Zipfian skew used by `KeyDistribution::Zipfian` unless one is given,
+/// the YCSB default.
+pub const DEFAULT_ZIPF_EXPONENT: f64 = 0.99;
+
+/// Percentages of gets, sets, and deletes; they add up to 100.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OpMix {
+    pub get: u32,
+    pub set: u32,
+    pub del: u32,
+}
+
+impl Default for OpMix {
+    fn default() -> Self {
+        OpMix {
+            get: 70,
+            set: 25,
+            del: 5,
+        }
+    }
+}
+
+/// Parses `70get/25set/5del`. Parts may come in any order and omitted ones
+/// are 0, but the total must be 100.
+impl FromStr for OpMix {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> io::Result<Self> {
+        let invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid op mix {:?}: expected e.g. 70get/25set/5del", s),
+            )
+        };
+        let mut mix = OpMix {
+            get: 0,
+            set: 0,
+            del: 0,
+        };
+        for part in s.split('/') {
+            let digits = part
+                .find(|c: char| !c.is_ascii_digit())
+                .ok_or_else(invalid)?;
+            let percent: u32 = part[..digits].parse().map_err(|_| invalid())?;
+            match &part[digits..] {
+                "get" => mix.get += percent,
+                "set" => mix.set += percent,
+                "del" => mix.del += percent,
+                _ => return Err(invalid()),
+            }
+        }
+        if mix.get + mix.set + mix.del != 100 {
+            return Err(invalid());
+        }
+        Ok(mix)
+    }
+}
+
+impl fmt::Display for OpMix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}get/{}set/{}del", self.get, self.set, self.del)
+    }
+}
+
+/// How keys are picked from the key space.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KeyDistribution {
+    Uniform,
+    /// Key `i` is picked with probability proportional to `1 / (i + 1)^exponent`.
+    /// The exponent must be in `(0, 1)`.
+    Zipfian {
+        exponent: f64,
+    },
+}
+
+impl FromStr for KeyDistribution {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> io::Result<Self> {
+        match s {
+            "uniform" => Ok(KeyDistribution::Uniform),
+            "zipfian" => Ok(KeyDistribution::Zipfian {
+                exponent: DEFAULT_ZIPF_EXPONENT,
+            }),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "invalid key distribution {:?}: expected uniform or zipfian",
+                    s
+                ),
+            )),
+        }
+    }
+}
+
+/// What [`run`] does.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WorkloadSpec {
+    /// Threads issuing operations; `ops` is split evenly between them.
+    pub threads: usize,
+    /// Operations in total.
+    pub ops: u64,
+    pub mix: OpMix,
+    /// Bytes per value written.
+    pub value_size: usize,
+    /// Distinct keys operated on.
+    pub key_space: u64,
+    pub distribution: KeyDistribution,
+    /// Seeds the key and operation choices, so runs are repeatable.
+    pub seed: u64,
+}
+
+impl Default for WorkloadSpec {
+    fn default() -> Self {
+        WorkloadSpec {
+            threads: 8,
+            ops: 1_000_000,
+            mix: OpMix::default(),
+            value_size: 256,
+            key_space: 1_000_000,
+            distribution: KeyDistribution::Uniform,
+            seed: 0,
+        }
+    }
+}
+
+/// Latency percentiles of one operation type, in microseconds.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct LatencySummary {
+    pub count: u64,
+    pub p50_micros: u64,
+    pub p90_micros: u64,
+    pub p99_micros: u64,
+    pub max_micros: u64,
+}
+
+impl From<&LatencySnapshot> for LatencySummary {
+    fn from(snapshot: &LatencySnapshot) -> Self {
+        LatencySummary {
+            count: snapshot.count(),
+            p50_micros: snapshot.p50().as_micros() as u64,
+            p90_micros: snapshot.p90().as_micros() as u64,
+            p99_micros: snapshot.p99().as_micros() as u64,
+            max_micros: snapshot.max().as_micros() as u64,
+        }
+    }
+}
+
+/// Results of [`run`]. Serializes to JSON for tracking runs in CI.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct WorkloadReport {
+    pub ops: u64,
+    pub elapsed_secs: f64,
+    pub ops_per_sec: f64,
+    /// Gets that found their key.
+    pub get_hits: u64,
+    pub get: LatencySummary,
+    pub set: LatencySummary,
+    pub del: LatencySummary,
+    /// Data file size after the run.
+    pub file_size: u64,
+    /// Compactions that ran during the run.
+    pub compactions: u64,
+}
+
+impl WorkloadReport {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("a report always serializes")
+    }
+}
+
+impl fmt::Display for WorkloadReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "ops:          {}", self.ops)?;
+        writeln!(f, "elapsed:      {:.3}s", self.elapsed_secs)?;
+        writeln!(f, "throughput:   {:.0} ops/s", self.ops_per_sec)?;
+        writeln!(f, "get hits:     {}", self.get_hits)?;
+        writeln!(
+            f,
+            "latency (us)   count      p50      p90      p99      max"
+        )?;
+        for (name, latency) in [("get", &self.get), ("set", &self.set), ("del", &self.del)] {
+            writeln!(
+                f,
+                "  {:<10} {:>7}  {:>7}  {:>7}  {:>7}  {:>7}",
+                name,
+                latency.count,
+                latency.p50_micros,
+                latency.p90_micros,
+                latency.p99_micros,
+                latency.max_micros
+            )?;
+        }
+        writeln!(f, "file size:    {}", self.file_size)?;
+        writeln!(f, "compactions:  {}", self.compactions)
+    }
+}
+
+/// Runs `spec` against `engine` and reports on it. A thread whose operation
+/// fails stops there, and `run` returns the error once the rest finish.
+pub fn run(engine: &Engine, spec: WorkloadSpec) -> io::Result<WorkloadReport> {
+    if spec.threads == 0 || spec.key_space == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "a workload needs at least one thread and one key",
+        ));
+    }
+    let keys = match spec.distribution {
+        KeyDistribution::Uniform => KeyPicker::Uniform(spec.key_space),
+        KeyDistribution::Zipfian { exponent } => {
+            KeyPicker::Zipfian(Zipfian::new(spec.key_space, exponent)?)
+        }
+    };
+    let latencies = [
+        LatencyHistogram::default(),
+        LatencyHistogram::default(),
+        LatencyHistogram::default(),
+    ];
+    let compactions_before = engine.stats().compactions;
+
+    let started = Instant::now();
+    let get_hits = thread::scope(|scope| {
+        let workers: Vec<_> = (0..spec.threads as u64)
+            .map(|t| {
+                let ops =
+                    spec.ops / spec.threads as u64 + u64::from(t < spec.ops % spec.threads as u64);
+                let (spec, keys, latencies) = (&spec, &keys, &latencies);
+                scope.spawn(move || {
+                    let rng = Rng(spec.seed ^ t.wrapping_mul(0x9E37_79B9_7F4A_7C15));
+                    worker(engine, spec, keys, latencies, rng, ops)
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .map(|w| w.join().unwrap())
+            .sum::<io::Result<u64>>()
+    })?;
+    let elapsed = started.elapsed();
+
+    let stats = engine.stats();
+    Ok(WorkloadReport {
+        ops: spec.ops,
+        elapsed_secs: elapsed.as_secs_f64(),
+        ops_per_sec: spec.ops as f64 / elapsed.max(Duration::from_nanos(1)).as_secs_f64(),
+        get_hits,
+        get: (&latencies[0].snapshot()).into(),
+        set: (&latencies[1].snapshot()).into(),
+        del: (&latencies[2].snapshot()).into(),
+        file_size: stats.file_size,
+        compactions: stats.compactions - compactions_before,
+    })
+}
+
+/// One thread's share of the run; returns its get hits.
+fn worker(
+    engine: &Engine,
+    spec: &WorkloadSpec,
+    keys: &KeyPicker,
+    latencies: &[LatencyHistogram; 3],
+    mut rng: Rng,
+    ops: u64,
+) -> io::Result<u64> {
+    let value = vec![b'v'; spec.value_size];
+    let mut hits = 0;
+    for _ in 0..ops {
+        let key = format!("bench:{:012}", keys.pick(&mut rng));
+        let roll = (rng.next() % 100) as u32;
+        let started = Instant::now();
+        let op = if roll < spec.mix.get {
+            hits += u64::from(engine.get(key.as_bytes())?.is_some());
+            0
+        } else if roll < spec.mix.get + spec.mix.set {
+            engine.set(key.as_bytes(), &value)?;
+            1
+        } else {
+            engine.del(key.as_bytes())?;
+            2
+        };
+        latencies[op].record(started.elapsed());
+    }
+    Ok(hits)
+}
+
+/// splitmix64: fast, seedable, and good enough for picking keys.
+pub(crate) struct Rng(pub(crate) u64);
+
+impl Rng {
+    pub(crate) fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in `[0, 1)`.
+    fn next_f64(&mut self) -> f64 {
+        (self.next() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+enum KeyPicker {
+    Uniform(u64),
+    Zipfian(Zipfian),
+}
+
+impl KeyPicker {
+    fn pick(&self, rng: &mut Rng) -> u64 {
+        match self {
+            KeyPicker::Uniform(n) => rng.next() % n,
+            KeyPicker::Zipfian(zipf) => zipf.pick(rng),
+        }
+    }
+}
+
+/// The zipfian generator from Gray et al., "Quickly Generating
+/// Billion-Record Synthetic Databases" (as used by YCSB). Rank 0 is the
+/// hottest key.
+struct Zipfian {
+    items: u64,
+    theta: f64,
+    zeta_n: f64,
+    alpha: f64,
+    eta: f64,
+}
+
+impl Zipfian {
+    fn new(items: u64, theta: f64) -> io::Result<Self> {
+        if !(theta > 0.0 && theta < 1.0) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("zipfian exponent must be in (0, 1), got {}", theta),
+            ));
+        }
+        let zeta = |n: u64| (1..=n).map(|i| 1.0 / (i as f64).powf(theta)).sum::<f64>();
+        let zeta_n = zeta(items);
+        let zeta_2 = zeta(items.min(2));
+        Ok(Zipfian {
+            items,
+            theta,
+            zeta_n,
+            alpha: 1.0 / (1.0 - theta),
+            eta: (1.0 - (2.0 / items as f64).powf(1.0 - theta)) / (1.0 - zeta_2 / zeta_n),
+        })
+    }
+
+    fn pick(&self, rng: &mut Rng) -> u64 {
+        let u = rng.next_f64();
+        let uz = u * self.zeta_n;
+        if uz < 1.0 {
+            return 0;
+        }
+        if uz < 1.0 + 0.5f64.powf(self.theta) {
+            return 1.min(self.items - 1);
+        }
+        let rank = self.items as f64 * (self.eta * u - self.eta + 1.0).powf(self.alpha);
+        (rank as u64).min(self.items - 1)
+    }
+}
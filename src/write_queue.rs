@@ -0,0 +1,153 @@
+//! Write combining for single-record writes.
+//!
+//! Instead of every writer queueing on the file mutex, each one enqueues its
+//! record here. Whichever writer finds no append in progress becomes the
+//! leader: it drains the queue, appends the whole group under one file lock,
+//! and hands every writer in the group its result. Writers arriving
+//! meanwhile wait for the next round, and one of them leads it, so no writer
+//! serves more than one group.
+
+use std::collections::HashMap;
+use std::io;
+use std::mem;
+use std::sync::{Condvar, Mutex};
+
+use crate::types::{DataFileEntry, LogIndex};
+
+/// Where a queued record landed.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Written {
+    /// Offset of the record, past its length prefix.
+    pub(crate) pos: u64,
+    /// File size right after the record.
+    pub(crate) end: u64,
+}
+
+impl From<&LogIndex> for Written {
+    fn from(log_index: &LogIndex) -> Self {
+        Written {
+            pos: log_index.pos,
+            end: log_index.pos + log_index.len,
+        }
+    }
+}
+
+pub(crate) struct WriteQueue {
+    state: Mutex<QueueState>,
+    done: Condvar,
+}
+
+#[derive(Default)]
+struct QueueState {
+    queued: Vec<(u64, DataFileEntry)>,
+    next_ticket: u64,
+    /// A leader is appending a group.
+    leading: bool,
+    /// Writers blocked on `done`; the leader only notifies when there are
+    /// any.
+    waiting: usize,
+    finished: HashMap<u64, io::Result<Written>>,
+}
+
+impl WriteQueue {
+    pub(crate) fn new() -> Self {
+        WriteQueue {
+            state: Mutex::new(QueueState::default()),
+            done: Condvar::new(),
+        }
+    }
+
+    /// Gives back the memory a burst of concurrent writers left allocated.
+    pub(crate) fn shrink(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.queued.shrink_to_fit();
+        state.finished.shrink_to_fit();
+    }
+
+    /// Queues `entry` and returns once it is written. If this call ends up
+    /// leading a round, `append` writes the group and must return one result
+    /// per entry, in order.
+    pub(crate) fn write(
+        &self,
+        entry: DataFileEntry,
+        append: impl FnOnce(&[DataFileEntry]) -> Vec<io::Result<Written>>,
+    ) -> io::Result<Written> {
+        let mut state = self.state.lock().unwrap();
+        let ticket = state.next_ticket;
+        state.next_ticket += 1;
+        state.queued.push((ticket, entry));
+        loop {
+            if let Some(result) = state.finished.remove(&ticket) {
+                return result;
+            }
+            if !state.leading {
+                break;
+            }
+            state.waiting += 1;
+            state = self.done.wait(state).unwrap();
+            state.waiting -= 1;
+        }
+
+        state.leading = true;
+        let (tickets, entries): (Vec<u64>, Vec<DataFileEntry>) =
+            mem::take(&mut state.queued).into_iter().unzip();
+        drop(state);
+
+        let mut round = Round {
+            queue: self,
+            tickets,
+        };
+        let results = append(&entries);
+        round.publish(Some(results), ticket).unwrap()
+    }
+}
+
+/// One leader's group. Publishes the results when finished, or fails the
+/// group if `append` panicked, so the waiting writers never hang.
+struct Round<'a> {
+    queue: &'a WriteQueue,
+    tickets: Vec<u64>,
+}
+
+impl Round<'_> {
+    /// Hands out `results` (all failures when `None`) and ends the round,
+    /// returning the result for ticket `own`.
+    fn publish(
+        &mut self,
+        results: Option<Vec<io::Result<Written>>>,
+        own: u64,
+    ) -> Option<io::Result<Written>> {
+        let tickets = mem::take(&mut self.tickets);
+        let results = match results {
+            Some(results) => results,
+            None => tickets
+                .iter()
+                .map(|_| Err(io::Error::other("write group leader panicked")))
+                .collect(),
+        };
+        assert_eq!(results.len(), tickets.len());
+
+        let mut state = self.queue.state.lock().unwrap_or_else(|e| e.into_inner());
+        let mut own_result = None;
+        for (ticket, result) in tickets.into_iter().zip(results) {
+            if ticket == own {
+                own_result = Some(result);
+            } else {
+                state.finished.insert(ticket, result);
+            }
+        }
+        state.leading = false;
+        if state.waiting > 0 {
+            self.queue.done.notify_all();
+        }
+        own_result
+    }
+}
+
+impl Drop for Round<'_> {
+    fn drop(&mut self) {
+        if !self.tickets.is_empty() {
+            self.publish(None, u64::MAX);
+        }
+    }
+}
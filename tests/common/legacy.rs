@@ -0,0 +1,45 @@
+//! A data file as written before files had a header: KVS1 records, an
+//! 8-byte little-endian length and a wincode `DataFileEntryV1`, from offset
+//! 0. Built here rather than checked in as bytes so the layout stays
+//! readable.
+
+use breakout1_kv_store::types::DataFileEntryV1;
+use std::collections::HashMap;
+
+/// Writes, in order, as the old build would have appended them. A `None`
+/// value is a delete.
+pub const HEADERLESS_WRITES: [(&[u8], Option<&[u8]>); 6] = [
+    (b"alpha", Some(b"1")),
+    (b"beta", Some(b"2")),
+    (b"gamma", Some(b"3")),
+    (b"alpha", Some(b"one")),
+    (b"beta", None),
+    (b"delta", Some(&[0, 0xFF, 7])),
+];
+
+pub fn headerless_file() -> Vec<u8> {
+    let mut bytes = Vec::new();
+    for (tstamp, (key, value)) in HEADERLESS_WRITES.iter().enumerate() {
+        let entry = DataFileEntryV1 {
+            tstamp: tstamp as i64,
+            key: key.to_vec(),
+            value: value.map(<[u8]>::to_vec),
+        };
+        let data = wincode::serialize(&entry).unwrap();
+        bytes.extend_from_slice(&(data.len() as u64).to_le_bytes());
+        bytes.extend_from_slice(&data);
+    }
+    bytes
+}
+
+/// What the store holds once every write in [`HEADERLESS_WRITES`] applies.
+pub fn headerless_contents() -> HashMap<Vec<u8>, Vec<u8>> {
+    let mut contents = HashMap::new();
+    for (key, value) in HEADERLESS_WRITES {
+        match value {
+            Some(value) => contents.insert(key.to_vec(), value.to_vec()),
+            None => contents.remove(key),
+        };
+    }
+    contents
+}
@@ -0,0 +1,445 @@
+#![allow(dead_code)]
+
+pub mod legacy;
+
+use breakout1_kv_store::clock::Clock;
+use breakout1_kv_store::storage::{CloneMethod, FsStorage, OpenMode, Storage, StorageFile};
+use breakout1_kv_store::{Engine, Options};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Deterministic PRNG for randomized tests.
+pub struct XorShift(pub u64);
+
+impl XorShift {
+    pub fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    pub fn bytes(&mut self, max_len: usize) -> Vec<u8> {
+        let len = (self.next() % (max_len as u64 + 1)) as usize;
+        (0..len).map(|_| self.next() as u8).collect()
+    }
+}
+
+/// Opens the store at `path` with `options`, for tests that set options
+/// inline rather than through a fixture of their own.
+pub fn engine_with(path: impl AsRef<Path>, options: Options) -> Engine {
+    Engine::load_with_options(path, options).unwrap()
+}
+
+/// Polls `done` for up to two seconds.
+pub fn wait_for(mut done: impl FnMut() -> bool) -> bool {
+    for _ in 0..200 {
+        if done() {
+            return true;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    false
+}
+
+/// A [`Clock`] that stands still until `advance` moves it.
+#[derive(Debug)]
+pub struct ManualClock {
+    start: Instant,
+    offset: Mutex<Duration>,
+}
+
+impl ManualClock {
+    pub fn new() -> Arc<Self> {
+        Arc::new(ManualClock {
+            start: Instant::now(),
+            offset: Mutex::new(Duration::ZERO),
+        })
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.offset.lock().unwrap() += by;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.start + *self.offset.lock().unwrap()
+    }
+}
+
+/// Shared knobs and counters for [`InstrumentedStorage`].
+#[derive(Debug, Default)]
+pub struct Probe {
+    pub reads: AtomicU64,
+    pub read_delay_ms: AtomicU64,
+    /// How long dropping a file handle takes.
+    pub close_delay_ms: AtomicU64,
+    pub closes_in_flight: AtomicU64,
+    pub reads_in_flight: AtomicU64,
+    pub max_reads_in_flight: AtomicU64,
+    pub syncs: AtomicU64,
+    pub fail_syncs: AtomicBool,
+    /// Fail renames between directories as if they were different
+    /// filesystems.
+    pub cross_device: AtomicBool,
+    pub clones: AtomicU64,
+    /// Free space to report, when `report_free_bytes` is set.
+    pub free_bytes: AtomicU64,
+    pub report_free_bytes: AtomicBool,
+    /// Largest total size of the files in the directories a rename or clone
+    /// touched, measured just before each rename and just after each clone.
+    pub peak_bytes: AtomicU64,
+    /// Injected failures still to come, see [`Probe::inject`].
+    faults: Mutex<Vec<(Fault, u64, io::ErrorKind)>>,
+    /// The next write puts down half its buffer and the one after it fails.
+    pub tear_next_write: AtomicBool,
+    /// Bytes writes may still put down before failing with `StorageFull`,
+    /// see [`Probe::set_space_left`].
+    space_left: Mutex<Option<u64>>,
+    /// A file path and the bytes writes to it may still put down, see
+    /// [`Probe::kill_writes_to`].
+    dying: Mutex<Option<(PathBuf, u64)>>,
+    /// Bumped by [`Probe::failover`]; handles opened before the current
+    /// generation read zeros.
+    generation: AtomicU64,
+    /// Numbers handles in the order they were opened.
+    next_handle: AtomicU64,
+    /// Reads and writes since [`Probe::start_op_log`], or `None` while not
+    /// logging.
+    op_log: Mutex<Option<Vec<FileOp>>>,
+}
+
+/// A read or write through one handle, as [`Probe::take_op_log`] returns it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileOp {
+    /// Which handle, by the order handles were opened in.
+    pub handle: u64,
+    pub path: PathBuf,
+    pub write: bool,
+    /// Where the handle's cursor was when the operation started.
+    pub pos: u64,
+    pub len: u64,
+}
+
+/// An operation [`Probe::inject`] can fail.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    Read,
+    Write,
+    Sync,
+    SetLen,
+}
+
+impl Probe {
+    pub fn reads(&self) -> u64 {
+        self.reads.load(Ordering::SeqCst)
+    }
+
+    pub fn max_reads_in_flight(&self) -> u64 {
+        self.max_reads_in_flight.load(Ordering::SeqCst)
+    }
+
+    pub fn syncs(&self) -> u64 {
+        self.syncs.load(Ordering::SeqCst)
+    }
+
+    pub fn set_fail_syncs(&self, fail: bool) {
+        self.fail_syncs.store(fail, Ordering::SeqCst);
+    }
+
+    pub fn reset(&self) {
+        self.reads.store(0, Ordering::SeqCst);
+        self.max_reads_in_flight.store(0, Ordering::SeqCst);
+    }
+
+    pub fn set_free_bytes(&self, free: Option<u64>) {
+        self.free_bytes.store(free.unwrap_or(0), Ordering::SeqCst);
+        self.report_free_bytes
+            .store(free.is_some(), Ordering::SeqCst);
+    }
+
+    pub fn peak_bytes(&self) -> u64 {
+        self.peak_bytes.load(Ordering::SeqCst)
+    }
+
+    fn record_usage(&self, paths: [&Path; 2]) {
+        let mut dirs: Vec<&Path> = paths.iter().filter_map(|p| p.parent()).collect();
+        dirs.dedup();
+        let bytes = dirs
+            .into_iter()
+            .filter_map(|dir| std::fs::read_dir(dir).ok())
+            .flatten()
+            .filter_map(|entry| entry.ok()?.metadata().ok())
+            .filter(|meta| meta.is_file())
+            .map(|meta| meta.len())
+            .sum();
+        self.peak_bytes.fetch_max(bytes, Ordering::SeqCst);
+    }
+
+    /// Fails the next `count` calls of `op` with `kind`, without touching
+    /// the file.
+    pub fn inject(&self, op: Fault, count: u64, kind: io::ErrorKind) {
+        let mut faults = self.faults.lock().unwrap();
+        faults.retain(|(f, _, _)| *f != op);
+        faults.push((op, count, kind));
+    }
+
+    fn take_fault(&self, op: Fault) -> io::Result<()> {
+        let mut faults = self.faults.lock().unwrap();
+        match faults.iter_mut().find(|(f, n, _)| *f == op && *n > 0) {
+            Some((_, n, kind)) => {
+                *n -= 1;
+                Err(io::Error::new(*kind, format!("injected {:?} failure", op)))
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Lets writes put down `bytes` more bytes, the last of them in a short
+    /// write, and fails every write after that with `StorageFull`, as a
+    /// full filesystem would. `None` lifts the limit.
+    pub fn set_space_left(&self, bytes: Option<u64>) {
+        *self.space_left.lock().unwrap() = bytes;
+    }
+
+    /// Lets writes to files opened at `path` put down `bytes` more bytes,
+    /// the last of them in a short write, and fails every one after that,
+    /// as a disk dying mid-stream would. Other files are untouched.
+    pub fn kill_writes_to(&self, path: &Path, bytes: u64) {
+        *self.dying.lock().unwrap() = Some((path.to_owned(), bytes));
+    }
+
+    /// Simulates a storage failover: every handle open so far serves stale
+    /// (zeroed) pages from now on, while handles opened later read the file.
+    pub fn failover(&self) {
+        self.generation.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub fn set_read_delay(&self, delay: Duration) {
+        self.read_delay_ms
+            .store(delay.as_millis() as u64, Ordering::SeqCst);
+    }
+
+    pub fn set_close_delay(&self, delay: Duration) {
+        self.close_delay_ms
+            .store(delay.as_millis() as u64, Ordering::SeqCst);
+    }
+
+    pub fn closes_in_flight(&self) -> u64 {
+        self.closes_in_flight.load(Ordering::SeqCst)
+    }
+
+    /// Starts logging every read and write, discarding any earlier log.
+    pub fn start_op_log(&self) {
+        *self.op_log.lock().unwrap() = Some(Vec::new());
+    }
+
+    /// Stops logging and returns what was logged.
+    pub fn take_op_log(&self) -> Vec<FileOp> {
+        self.op_log.lock().unwrap().take().unwrap_or_default()
+    }
+}
+
+/// Filesystem storage that counts operations and can inject latency.
+#[derive(Debug, Default, Clone)]
+pub struct InstrumentedStorage {
+    pub probe: Arc<Probe>,
+}
+
+struct InstrumentedFile {
+    inner: Box<dyn StorageFile>,
+    path: PathBuf,
+    probe: Arc<Probe>,
+    generation: u64,
+    handle: u64,
+}
+
+impl Storage for InstrumentedStorage {
+    fn open(&self, path: &Path, mode: OpenMode) -> io::Result<Box<dyn StorageFile>> {
+        Ok(Box::new(InstrumentedFile {
+            inner: FsStorage.open(path, mode)?,
+            path: path.to_owned(),
+            probe: Arc::clone(&self.probe),
+            generation: self.probe.generation.load(Ordering::SeqCst),
+            handle: self.probe.next_handle.fetch_add(1, Ordering::SeqCst),
+        }))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        if self.probe.cross_device.load(Ordering::SeqCst) && from.parent() != to.parent() {
+            return Err(io::Error::from(io::ErrorKind::CrossesDevices));
+        }
+        self.probe.record_usage([from, to]);
+        FsStorage.rename(from, to)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        FsStorage.remove_file(path)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        FsStorage.create_dir_all(path)
+    }
+
+    fn sync_dir(&self, path: &Path) -> io::Result<()> {
+        FsStorage.sync_dir(path)
+    }
+
+    fn available_space(&self, path: &Path) -> io::Result<Option<u64>> {
+        if self.probe.report_free_bytes.load(Ordering::SeqCst) {
+            return Ok(Some(self.probe.free_bytes.load(Ordering::SeqCst)));
+        }
+        FsStorage.available_space(path)
+    }
+
+    fn same_filesystem(&self, a: &Path, b: &Path) -> io::Result<Option<bool>> {
+        if self.probe.cross_device.load(Ordering::SeqCst) && a != b {
+            return Ok(Some(false));
+        }
+        FsStorage.same_filesystem(a, b)
+    }
+
+    fn clone_file(&self, from: &Path, to: &Path, len: u64) -> io::Result<CloneMethod> {
+        self.probe.clones.fetch_add(1, Ordering::SeqCst);
+        let method = FsStorage.clone_file(from, to, len)?;
+        self.probe.record_usage([from, to]);
+        Ok(method)
+    }
+}
+
+impl InstrumentedFile {
+    fn log_op(&mut self, write: bool, len: usize) -> io::Result<()> {
+        if let Some(log) = &mut *self.probe.op_log.lock().unwrap() {
+            log.push(FileOp {
+                handle: self.handle,
+                path: self.path.clone(),
+                write,
+                pos: self.inner.stream_position()?,
+                len: len as u64,
+            });
+        }
+        Ok(())
+    }
+}
+
+impl Read for InstrumentedFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.probe.reads.fetch_add(1, Ordering::SeqCst);
+        self.log_op(false, buf.len())?;
+        let in_flight = self.probe.reads_in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+        self.probe
+            .max_reads_in_flight
+            .fetch_max(in_flight, Ordering::SeqCst);
+
+        let delay = self.probe.read_delay_ms.load(Ordering::SeqCst);
+        if delay > 0 {
+            thread::sleep(Duration::from_millis(delay));
+        }
+        let result = self
+            .probe
+            .take_fault(Fault::Read)
+            .and_then(|()| self.inner.read(buf));
+        if let Ok(n) = result
+            && self.generation < self.probe.generation.load(Ordering::SeqCst)
+        {
+            buf[..n].fill(0);
+        }
+
+        self.probe.reads_in_flight.fetch_sub(1, Ordering::SeqCst);
+        result
+    }
+}
+
+impl Write for InstrumentedFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.probe.take_fault(Fault::Write)?;
+        self.log_op(true, buf.len())?;
+        if buf.len() > 1 && self.probe.tear_next_write.swap(false, Ordering::SeqCst) {
+            self.probe
+                .inject(Fault::Write, 1, io::ErrorKind::WouldBlock);
+            return self.inner.write(&buf[..buf.len() / 2]);
+        }
+        if let Some((path, left)) = &mut *self.probe.dying.lock().unwrap()
+            && *path == self.path
+        {
+            if *left == 0 && !buf.is_empty() {
+                return Err(io::Error::other("injected: device gone"));
+            }
+            let len = buf.len().min(*left as usize);
+            let written = self.inner.write(&buf[..len])?;
+            *left -= written as u64;
+            return Ok(written);
+        }
+        if let Some(left) = &mut *self.probe.space_left.lock().unwrap() {
+            if *left == 0 && !buf.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::StorageFull,
+                    "injected: no space left on device",
+                ));
+            }
+            let len = buf.len().min(*left as usize);
+            let written = self.inner.write(&buf[..len])?;
+            *left -= written as u64;
+            return Ok(written);
+        }
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl Drop for InstrumentedFile {
+    fn drop(&mut self) {
+        let delay = self.probe.close_delay_ms.load(Ordering::SeqCst);
+        if delay > 0 {
+            self.probe.closes_in_flight.fetch_add(1, Ordering::SeqCst);
+            thread::sleep(Duration::from_millis(delay));
+            self.probe.closes_in_flight.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+}
+
+impl Seek for InstrumentedFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+impl StorageFile for InstrumentedFile {
+    fn sync_all(&self) -> io::Result<()> {
+        if self.probe.fail_syncs.load(Ordering::SeqCst) {
+            return Err(io::Error::other("injected fsync failure"));
+        }
+        self.probe.take_fault(Fault::Sync)?;
+        self.probe.syncs.fetch_add(1, Ordering::SeqCst);
+        self.inner.sync_all()
+    }
+
+    fn set_len(&self, len: u64) -> io::Result<()> {
+        self.probe.take_fault(Fault::SetLen)?;
+        self.inner.set_len(len)
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        self.inner.len()
+    }
+
+    fn allocate(&self, len: u64) -> io::Result<()> {
+        self.inner.allocate(len)
+    }
+
+    fn lock(&self) -> io::Result<()> {
+        self.inner.lock()
+    }
+
+    fn unlock(&self) -> io::Result<()> {
+        self.inner.unlock()
+    }
+}
@@ -0,0 +1,253 @@
+mod common;
+
+use breakout1_kv_store::dump::{DUMP_MAGIC, DumpWriter, read_dump, verify_subset};
+use breakout1_kv_store::{Engine, WriteBatch};
+use common::XorShift;
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::NamedTempFile;
+
+fn temp_engine() -> (Engine, NamedTempFile) {
+    let file = NamedTempFile::new().unwrap();
+    let engine = Engine::load(file.path()).unwrap();
+    (engine, file)
+}
+
+#[test]
+fn test_dump_round_trip_random_binary() {
+    for seed in 1..=20u64 {
+        let mut rng = XorShift(seed.wrapping_mul(0x9E37_79B9_7F4A_7C15));
+        let (source, _f1) = temp_engine();
+        let mut expected: HashMap<Vec<u8>, Vec<u8>> = HashMap::new();
+
+        for _ in 0..50 {
+            let key = rng.bytes(32);
+            let value = rng.bytes(256);
+            // A leading 0xFF is reserved for bucketed keys.
+            if key.is_empty() || key[0] == 0xFF {
+                continue;
+            }
+            source.set(&key, &value).unwrap();
+            expected.insert(key, value);
+        }
+
+        let mut buf = Vec::new();
+        let count = source.dump(&mut buf).unwrap();
+        assert_eq!(count, expected.len() as u64);
+
+        let (target, _f2) = temp_engine();
+        assert_eq!(target.load_dump(buf.as_slice()).unwrap(), count);
+        for (key, value) in &expected {
+            assert_eq!(target.get(key).unwrap().as_ref(), Some(value));
+        }
+    }
+}
+
+#[test]
+fn test_dump_skips_deleted_keys() {
+    let (engine, _f) = temp_engine();
+    engine.set(b"keep", b"1").unwrap();
+    engine.set(b"drop", b"2").unwrap();
+    engine.del(b"drop").unwrap();
+
+    let mut buf = Vec::new();
+    engine.dump(&mut buf).unwrap();
+
+    let records = read_dump(buf.as_slice()).unwrap();
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0].key, b"keep".to_vec());
+}
+
+#[test]
+fn test_dump_keeps_empty_values_apart_from_deleted_keys() {
+    let (source, _f1) = temp_engine();
+    source.set(b"empty", b"").unwrap();
+    source.set(b"gone", b"").unwrap();
+    source.del(b"gone").unwrap();
+
+    let mut buf = Vec::new();
+    assert_eq!(source.dump(&mut buf).unwrap(), 1);
+    let records = read_dump(buf.as_slice()).unwrap();
+    assert_eq!(records[0].key, b"empty".to_vec());
+    assert_eq!(records[0].value, Vec::<u8>::new());
+
+    let (target, _f2) = temp_engine();
+    target.load_dump(buf.as_slice()).unwrap();
+    assert_eq!(target.get(b"empty").unwrap(), Some(Vec::new()));
+    assert_eq!(target.get(b"gone").unwrap(), None);
+}
+
+#[test]
+fn test_dump_preserves_tstamp_and_layout() {
+    let mut buf = Vec::new();
+    let mut writer = DumpWriter::new(&mut buf).unwrap();
+    writer.write_record(b"k", b"vv", 42).unwrap();
+    assert_eq!(writer.finish().unwrap(), 1);
+
+    assert_eq!(&buf[0..4], &DUMP_MAGIC);
+    assert_eq!(&buf[4..8], &1u32.to_le_bytes());
+    assert_eq!(&buf[8..16], &27u64.to_le_bytes());
+    assert_eq!(&buf[16..24], &1u64.to_le_bytes());
+    assert_eq!(&buf[24..25], b"k");
+    assert_eq!(&buf[25..33], &2u64.to_le_bytes());
+    assert_eq!(&buf[33..35], b"vv");
+    assert_eq!(&buf[35..43], &42i64.to_le_bytes());
+    assert_eq!(buf.len(), 43 + 8 + 8 + 4);
+
+    let records = read_dump(buf.as_slice()).unwrap();
+    assert_eq!(records[0].tstamp, 42);
+}
+
+#[test]
+fn test_load_dump_rejects_corrupted_trailer() {
+    let (source, _f1) = temp_engine();
+    source.set(b"a", b"1").unwrap();
+    source.set(b"b", b"2").unwrap();
+
+    let mut buf = Vec::new();
+    source.dump(&mut buf).unwrap();
+    let last = buf.len() - 1;
+    buf[last] ^= 0xFF;
+
+    let (target, _f2) = temp_engine();
+    let err = target.load_dump(buf.as_slice()).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    assert_eq!(target.get(b"a").unwrap(), None);
+}
+
+#[test]
+fn test_load_dump_rejects_corrupted_record() {
+    let (source, _f1) = temp_engine();
+    source.set(b"key", b"value").unwrap();
+
+    let mut buf = Vec::new();
+    source.dump(&mut buf).unwrap();
+    buf[20] ^= 0x01;
+
+    let (target, _f2) = temp_engine();
+    assert!(target.load_dump(buf.as_slice()).is_err());
+    assert_eq!(target.get(b"key").unwrap(), None);
+}
+
+#[test]
+fn test_load_dump_rejects_truncated_stream() {
+    let (source, _f1) = temp_engine();
+    source.set(b"key", b"value").unwrap();
+
+    let mut buf = Vec::new();
+    source.dump(&mut buf).unwrap();
+    buf.truncate(buf.len() - 2);
+
+    let (target, _f2) = temp_engine();
+    assert!(target.load_dump(buf.as_slice()).is_err());
+}
+
+#[test]
+fn test_export_subset_is_one_point_in_time_under_heavy_writes() {
+    let (engine, _f) = temp_engine();
+    let engine = Arc::new(engine);
+    let keys: Vec<Vec<u8>> = (0..50u8)
+        .map(|i| [b"user:7:", &[b'a' + i % 26, i][..]].concat())
+        .collect();
+    engine.set(b"user:8:other", b"not exported").unwrap();
+
+    // Each round rewrites every key under the prefix in one batch, and
+    // adds or removes one more, so a torn export would mix rounds.
+    let stop = Arc::new(AtomicBool::new(false));
+    let writer = {
+        let (engine, keys, stop) = (Arc::clone(&engine), keys.clone(), Arc::clone(&stop));
+        thread::spawn(move || {
+            let mut round = 0u32;
+            while !stop.load(Ordering::Relaxed) {
+                let mut batch = WriteBatch::new();
+                for key in &keys {
+                    batch.put(key, &round.to_le_bytes());
+                }
+                match round % 2 {
+                    0 => batch.put(b"user:7:extra", &round.to_le_bytes()),
+                    _ => batch.delete(b"user:7:extra"),
+                };
+                engine.apply_batch(&batch).unwrap();
+                round += 1;
+            }
+        })
+    };
+
+    // An export before the first round would find nothing under the prefix.
+    let start = Instant::now();
+    while !engine.contains_key(&keys[0]).unwrap() {
+        assert!(start.elapsed() < Duration::from_secs(10));
+        thread::yield_now();
+    }
+
+    let mut rounds = BTreeSet::new();
+    while rounds.len() < 5 && start.elapsed() < Duration::from_secs(10) {
+        let mut buf = Vec::new();
+        let report = engine.export_prefix(b"user:7:", &mut buf).unwrap();
+        verify_subset(buf.as_slice(), &report).unwrap();
+
+        let records = read_dump(buf.as_slice()).unwrap();
+        let round = u32::from_le_bytes(records[0].value[..].try_into().unwrap());
+        assert!(records.iter().all(|r| r.key.starts_with(b"user:7:")));
+        assert!(records.iter().all(|r| r.value == round.to_le_bytes()));
+        let expected = keys.len() as u64 + u64::from(round % 2 == 0);
+        assert_eq!(report.keys, expected, "round {}", round);
+        rounds.insert(round);
+    }
+    stop.store(true, Ordering::Relaxed);
+    writer.join().unwrap();
+    assert!(rounds.len() >= 5, "exports saw rounds {:?}", rounds);
+}
+
+#[test]
+fn test_verify_subset_rejects_a_dump_that_does_not_match_its_report() {
+    let (engine, file) = temp_engine();
+    engine.set(b"user:1:name", b"ada").unwrap();
+    engine.set(b"user:1:mail", b"ada@example.com").unwrap();
+    engine.set(b"user:2:name", b"grace").unwrap();
+    engine
+        .bucket(b"user:1:")
+        .unwrap()
+        .set(b"k", b"bucketed")
+        .unwrap();
+
+    let mut buf = Vec::new();
+    let report = engine
+        .export_subset(|key| key.ends_with(b"name"), &mut buf)
+        .unwrap();
+    assert_eq!(report.store, file.path());
+    assert_eq!((report.keys, report.bytes), (2, 30));
+    verify_subset(buf.as_slice(), &report).unwrap();
+    let keys: Vec<_> = read_dump(buf.as_slice())
+        .unwrap()
+        .into_iter()
+        .map(|r| r.key)
+        .collect();
+    assert_eq!(keys, vec![b"user:1:name".to_vec(), b"user:2:name".to_vec()]);
+
+    // A later export of the same subset is read at a later point and no
+    // longer matches the first report.
+    engine.set(b"user:1:name", b"ada!").unwrap();
+    let mut again = Vec::new();
+    let later = engine
+        .export_subset(|key| key.ends_with(b"name"), &mut again)
+        .unwrap();
+    assert!(later.log_end > report.log_end);
+    assert_eq!((later.keys, later.bytes), (2, 31));
+
+    let mut more_keys = report.clone();
+    more_keys.keys += 1;
+    let mut other_crc = report.clone();
+    other_crc.crc ^= 1;
+    for (dump, report) in [(&buf, &more_keys), (&buf, &other_crc), (&again, &report)] {
+        let err = verify_subset(dump.as_slice(), report).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+    let mut prefix = Vec::new();
+    let user1 = engine.export_prefix(b"user:1:", &mut prefix).unwrap();
+    assert_eq!(user1.keys, 2);
+    assert!(engine.export_prefix(&[0xFF], &mut Vec::new()).is_err());
+}
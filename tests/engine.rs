@@ -1,11 +1,29 @@
-use breakout1_kv_store::Engine;
+mod common;
+
 use breakout1_kv_store::constants::{
-    DEFAULT_COMPACT_THRESHOLD, FILE_HEADER_MAGIC, FILE_HEADER_SIZE,
+    DEFAULT_COMPACT_THRESHOLD, FILE_HEADER_MAGIC, FILE_HEADER_MAGIC_V1, FILE_HEADER_MAGIC_V2,
+    FILE_HEADER_SIZE, HEADER_FLAG_THRESHOLD_AUTO,
+};
+use breakout1_kv_store::testing::{Corruption, StoreFixture};
+use breakout1_kv_store::types::{
+    DataFileEntry, DataFileEntryV1, DataFileEntryV2, EntryKind, KeyStat,
 };
+use breakout1_kv_store::{
+    Bucket, CacheInconsistency, CompactProgress, CompactionReport, ConflictPolicy, Durability,
+    Engine, EngineError, Hook, KeyTransform, KvRuntime, Limit, LimitWarning, MirrorFailure,
+    OpContext, OpenMode, Options, OverlapReport, PrefixStats, Quota, ReadSource, ReclaimOptions,
+    RenameConflict, RetryPolicy, SetEvent, SlowOp, SlowOpKind, SoftLimit, ThresholdSource,
+    Validator, WriteBatch,
+};
+use common::{Fault, ManualClock, XorShift, engine_with, legacy, wait_for};
+use std::collections::{BTreeSet, HashMap};
 use std::fs;
 use std::io::{Read, Seek, SeekFrom};
-use std::sync::Arc;
+use std::ops::Bound::{Excluded, Included, Unbounded};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Barrier, Mutex};
 use std::thread;
+use std::time::Duration;
 use tempfile::NamedTempFile;
 
 fn temp_engine() -> (Engine, NamedTempFile) {
@@ -14,6 +32,16 @@ fn temp_engine() -> (Engine, NamedTempFile) {
     (engine, file)
 }
 
+/// Checks that `engine` reads back every key of `fixture` as it was written.
+fn assert_fixture_reads_back(engine: &Engine, fixture: &StoreFixture) {
+    for (key, value) in fixture.live() {
+        assert_eq!(engine.get(key).unwrap().as_ref(), Some(value));
+    }
+    for key in fixture.deleted() {
+        assert_eq!(engine.get(key).unwrap(), None);
+    }
+}
+
 fn read_threshold_from_file(path: &std::path::Path) -> u64 {
     let mut file = fs::OpenOptions::new().read(true).open(path).unwrap();
     file.seek(SeekFrom::Start(0)).unwrap();
@@ -28,6 +56,10 @@ fn read_threshold_from_file(path: &std::path::Path) -> u64 {
 }
 
 fn write_header(path: &std::path::Path, threshold: u64) {
+    write_header_with_flags(path, threshold, 0);
+}
+
+fn write_header_with_flags(path: &std::path::Path, threshold: u64, flags: u64) {
     let mut file = fs::OpenOptions::new()
         .create(true)
         .write(true)
@@ -37,6 +69,9 @@ fn write_header(path: &std::path::Path, threshold: u64) {
     use std::io::Write;
     file.write_all(&FILE_HEADER_MAGIC).unwrap();
     file.write_all(&threshold.to_le_bytes()).unwrap();
+    // Writer epoch and flags.
+    file.write_all(&0u64.to_le_bytes()).unwrap();
+    file.write_all(&flags.to_le_bytes()).unwrap();
     file.flush().unwrap();
 }
 
@@ -140,6 +175,46 @@ fn test_empty_value() {
     assert_eq!(engine.get(b"empty").unwrap(), Some(b"".to_vec()));
 }
 
+#[test]
+fn test_empty_values_never_read_as_deleted_keys() {
+    let file = NamedTempFile::new().unwrap();
+    let options = || Options {
+        history_depth: 2,
+        ..Options::default()
+    };
+    let engine = Engine::load_with_options(file.path(), options()).unwrap();
+    engine.set(b"empty", b"").unwrap();
+    engine.set(b"versions", b"").unwrap();
+    engine.set(b"versions", b"now").unwrap();
+    let mut batch = WriteBatch::new();
+    batch.put(b"batched", b"").put(b"gone", b"");
+    engine.apply_batch(&batch).unwrap();
+    engine
+        .update_many(&[&b"updated"[..], b"gone"], |current| {
+            assert_eq!(current, vec![None, Some(Vec::new())]);
+            vec![Some(Vec::new()), None]
+        })
+        .unwrap();
+
+    let check = |engine: &Engine| {
+        for key in [&b"empty"[..], b"batched", b"updated"] {
+            assert_eq!(engine.get(key).unwrap(), Some(Vec::new()));
+            assert_eq!(engine.get_range(key, 0, 10).unwrap(), Some(Vec::new()));
+        }
+        assert_eq!(engine.get(b"gone").unwrap(), None);
+        assert_eq!(engine.get_range(b"gone", 0, 10).unwrap(), None);
+        assert_eq!(
+            engine.previous_versions(b"versions", 2).unwrap(),
+            vec![Vec::<u8>::new()]
+        );
+    };
+    check(&engine);
+    engine.compact().unwrap();
+    check(&engine);
+    drop(engine);
+    check(&Engine::load_with_options(file.path(), options()).unwrap());
+}
+
 #[test]
 fn test_large_value() {
     let (engine, _f) = temp_engine();
@@ -251,6 +326,25 @@ fn test_threshold_persisted_in_file_header() {
 
 #[test]
 fn test_threshold_doubles_when_compaction_size_unchanged() {
+    let file = NamedTempFile::new().unwrap();
+    let path = file.path().to_owned();
+    let threshold = 308;
+
+    write_header(&path, threshold);
+    let engine = Engine::load(&path).unwrap();
+    engine.set(b"big", &[b'x'; 200]).unwrap();
+    engine.set(b"small", b"0123456789").unwrap();
+    assert_eq!(engine.stats().compactions, 0);
+    // Crosses the threshold with live bytes still under it; compaction
+    // only drops the one overwritten record.
+    engine.set(b"small", b"0123456789").unwrap();
+
+    assert_eq!(engine.stats().compactions, 1);
+    assert_eq!(read_threshold_from_file(&path), threshold * 2);
+}
+
+#[test]
+fn test_live_data_over_threshold_does_not_compact_every_write() {
     let file = NamedTempFile::new().unwrap();
     let path = file.path().to_owned();
     let threshold = 64;
@@ -258,8 +352,31 @@ fn test_threshold_doubles_when_compaction_size_unchanged() {
     write_header(&path, threshold);
     let engine = Engine::load(&path).unwrap();
     engine.set(b"only-key", &vec![b'x'; 256]).unwrap();
+    for i in 0..1000u32 {
+        engine
+            .set(format!("key{}", i).as_bytes(), b"value")
+            .unwrap();
+    }
+    // Nothing is dead, so there is nothing to compact.
+    assert_eq!(engine.stats().compactions, 0);
+    assert_eq!(read_threshold_from_file(&path), threshold);
 
-    assert_eq!(read_threshold_from_file(&path), threshold * 2);
+    // Once rewrites leave the file mostly dead, compaction pays off.
+    for _ in 0..2 {
+        for i in 0..1000u32 {
+            engine
+                .set(format!("key{}", i).as_bytes(), b"value")
+                .unwrap();
+        }
+    }
+    let stats = engine.stats();
+    assert!(
+        (1..=2).contains(&stats.compactions),
+        "{}",
+        stats.compactions
+    );
+    assert!(stats.dead_bytes < stats.file_size / 2);
+    assert_eq!(engine.get(b"key999").unwrap(), Some(b"value".to_vec()));
 }
 
 // ==================== New Multithreading Tests ====================
@@ -487,4 +604,7927 @@ fn test_concurrent_writes_and_deletes() {
 
     engine.set(b"final", b"test").unwrap();
     assert_eq!(engine.get(b"final").unwrap(), Some(b"test".to_vec()));
+    assert_eq!(engine.audit_caches().unwrap(), vec![]);
+}
+
+// ==================== Soft Delete ====================
+
+#[test]
+fn test_soft_del_hides_key_and_restore_brings_it_back() {
+    let file = NamedTempFile::new().unwrap();
+    let engine = engine_with(
+        file.path(),
+        Options {
+            soft_delete_window: Duration::from_secs(60),
+            ..Options::default()
+        },
+    );
+
+    engine.set(b"cfg", b"v1").unwrap();
+    engine.soft_del(b"cfg").unwrap();
+    assert_eq!(engine.get(b"cfg").unwrap(), None);
+
+    let deleted = engine.scan_deleted();
+    assert_eq!(deleted.len(), 1);
+    assert_eq!(deleted[0].0, b"cfg".to_vec());
+
+    engine.restore(b"cfg").unwrap();
+    assert_eq!(engine.get(b"cfg").unwrap(), Some(b"v1".to_vec()));
+    assert!(engine.scan_deleted().is_empty());
+}
+
+#[test]
+fn test_restore_after_window_fails() {
+    let file = NamedTempFile::new().unwrap();
+    let engine = engine_with(
+        file.path(),
+        Options {
+            soft_delete_window: Duration::from_millis(20),
+            ..Options::default()
+        },
+    );
+
+    engine.set(b"k", b"v").unwrap();
+    engine.soft_del(b"k").unwrap();
+    thread::sleep(Duration::from_millis(50));
+
+    let err = engine.restore(b"k").unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+    assert!(engine.scan_deleted().is_empty());
+    assert_eq!(engine.get(b"k").unwrap(), None);
+}
+
+#[test]
+fn test_soft_delete_survives_reload_then_restore() {
+    let file = NamedTempFile::new().unwrap();
+    let path = file.path().to_owned();
+
+    {
+        let engine = engine_with(
+            &path,
+            Options {
+                soft_delete_window: Duration::from_secs(60),
+                ..Options::default()
+            },
+        );
+        engine.set(b"k", b"precious").unwrap();
+        engine.soft_del(b"k").unwrap();
+    }
+
+    let engine = engine_with(
+        &path,
+        Options {
+            soft_delete_window: Duration::from_secs(60),
+            ..Options::default()
+        },
+    );
+    assert_eq!(engine.get(b"k").unwrap(), None);
+    assert_eq!(engine.scan_deleted().len(), 1);
+
+    engine.restore(b"k").unwrap();
+    drop(engine);
+
+    let engine = engine_with(
+        &path,
+        Options {
+            soft_delete_window: Duration::from_secs(60),
+            ..Options::default()
+        },
+    );
+    assert_eq!(engine.get(b"k").unwrap(), Some(b"precious".to_vec()));
+}
+
+#[test]
+fn test_soft_delete_survives_compaction_within_window() {
+    let file = NamedTempFile::new().unwrap();
+    let engine = engine_with(
+        file.path(),
+        Options {
+            soft_delete_window: Duration::from_secs(60),
+            ..Options::default()
+        },
+    );
+
+    engine.set(b"k", b"v").unwrap();
+    engine.soft_del(b"k").unwrap();
+    engine.compact().unwrap();
+
+    engine.restore(b"k").unwrap();
+    assert_eq!(engine.get(b"k").unwrap(), Some(b"v".to_vec()));
+}
+
+#[test]
+fn test_compaction_drops_expired_soft_deletes() {
+    let file = NamedTempFile::new().unwrap();
+    let path = file.path().to_owned();
+
+    {
+        let engine = engine_with(
+            &path,
+            Options {
+                soft_delete_window: Duration::from_millis(20),
+                ..Options::default()
+            },
+        );
+        engine.set(b"k", b"v").unwrap();
+        engine.soft_del(b"k").unwrap();
+        thread::sleep(Duration::from_millis(50));
+        engine.compact().unwrap();
+    }
+
+    let engine = engine_with(
+        &path,
+        Options {
+            soft_delete_window: Duration::from_secs(60),
+            ..Options::default()
+        },
+    );
+    assert!(engine.scan_deleted().is_empty());
+    assert!(engine.restore(b"k").is_err());
+}
+
+#[test]
+fn test_expire_soft_deleted_writes_tombstones() {
+    let file = NamedTempFile::new().unwrap();
+    let path = file.path().to_owned();
+
+    {
+        let engine = engine_with(
+            &path,
+            Options {
+                soft_delete_window: Duration::from_millis(20),
+                ..Options::default()
+            },
+        );
+        engine.set(b"a", b"1").unwrap();
+        engine.soft_del(b"a").unwrap();
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(engine.expire_soft_deleted().unwrap(), 1);
+    }
+
+    let engine = engine_with(
+        &path,
+        Options {
+            soft_delete_window: Duration::from_secs(60),
+            ..Options::default()
+        },
+    );
+    assert!(engine.restore(b"a").is_err());
+}
+
+#[test]
+fn test_set_or_del_after_soft_del_clears_recovery() {
+    let (engine, _f) = temp_engine();
+    engine.set(b"a", b"1").unwrap();
+    engine.soft_del(b"a").unwrap();
+    engine.set(b"a", b"2").unwrap();
+    assert!(engine.restore(b"a").is_err());
+    assert_eq!(engine.get(b"a").unwrap(), Some(b"2".to_vec()));
+
+    engine.soft_del(b"a").unwrap();
+    engine.del(b"a").unwrap();
+    assert!(engine.restore(b"a").is_err());
+    assert_eq!(engine.get(b"a").unwrap(), None);
+}
+
+#[test]
+fn test_soft_del_missing_key_is_noop() {
+    let (engine, _f) = temp_engine();
+    engine.soft_del(b"ghost").unwrap();
+    assert!(engine.scan_deleted().is_empty());
+}
+
+// ==================== Format Versions ====================
+
+#[test]
+fn test_v1_file_is_readable_and_upgraded_by_compaction() {
+    let file = NamedTempFile::new().unwrap();
+    let path = file.path().to_owned();
+
+    {
+        use std::io::Write;
+        let mut f = fs::OpenOptions::new().write(true).open(&path).unwrap();
+        f.write_all(&FILE_HEADER_MAGIC_V1).unwrap();
+        f.write_all(&DEFAULT_COMPACT_THRESHOLD.to_le_bytes())
+            .unwrap();
+        for (key, value) in [
+            (b"a", Some(b"1".to_vec())),
+            (b"b", Some(b"2".to_vec())),
+            (b"a", None),
+        ] {
+            let entry = DataFileEntryV1 {
+                tstamp: 0,
+                key: key.to_vec(),
+                value,
+            };
+            let data = wincode::serialize(&entry).unwrap();
+            f.write_all(&(data.len() as u64).to_le_bytes()).unwrap();
+            f.write_all(&data).unwrap();
+        }
+    }
+
+    let engine = Engine::load(&path).unwrap();
+    assert_eq!(engine.get(b"a").unwrap(), None);
+    assert_eq!(engine.get(b"b").unwrap(), Some(b"2".to_vec()));
+    assert_eq!(engine.get_range(b"b", 0, 10).unwrap(), Some(b"2".to_vec()));
+
+    engine.set(b"c", b"3").unwrap();
+    assert!(engine.soft_del(b"c").is_err());
+
+    let estimate = engine.compaction_estimate();
+    engine.compact().unwrap();
+    assert_eq!(estimate.expected_size, fs::metadata(&path).unwrap().len());
+    let mut magic = [0u8; 4];
+    fs::File::open(&path)
+        .unwrap()
+        .read_exact(&mut magic)
+        .unwrap();
+    assert_eq!(magic, FILE_HEADER_MAGIC);
+
+    engine.soft_del(b"c").unwrap();
+    drop(engine);
+
+    let engine = Engine::load(&path).unwrap();
+    assert_eq!(engine.get(b"b").unwrap(), Some(b"2".to_vec()));
+    assert_eq!(engine.get(b"c").unwrap(), None);
+    engine.restore(b"c").unwrap();
+    assert_eq!(engine.get(b"c").unwrap(), Some(b"3".to_vec()));
+}
+
+// ==================== Version History ====================
+
+#[test]
+fn test_previous_versions_survive_compaction_and_reload() {
+    let file = NamedTempFile::new().unwrap();
+    let path = file.path().to_owned();
+    let expected: Vec<Vec<u8>> = vec![b"v8".to_vec(), b"v7".to_vec(), b"v6".to_vec()];
+
+    {
+        let engine = engine_with(
+            &path,
+            Options {
+                history_depth: 3,
+                ..Options::default()
+            },
+        );
+        for i in 0..10 {
+            engine.set(b"cfg", format!("v{}", i).as_bytes()).unwrap();
+        }
+        assert_eq!(engine.previous_versions(b"cfg", 10).unwrap(), expected);
+
+        engine.compact().unwrap();
+        assert_eq!(engine.previous_versions(b"cfg", 10).unwrap(), expected);
+        assert_eq!(engine.get(b"cfg").unwrap(), Some(b"v9".to_vec()));
+    }
+
+    let engine = engine_with(
+        &path,
+        Options {
+            history_depth: 3,
+            ..Options::default()
+        },
+    );
+    assert_eq!(engine.previous_versions(b"cfg", 10).unwrap(), expected);
+    assert_eq!(
+        engine.previous_versions(b"cfg", 1).unwrap(),
+        vec![b"v8".to_vec()]
+    );
+    assert_eq!(engine.get(b"cfg").unwrap(), Some(b"v9".to_vec()));
+}
+
+#[test]
+fn test_history_disabled_by_default() {
+    let (engine, _f) = temp_engine();
+    engine.set(b"k", b"1").unwrap();
+    engine.set(b"k", b"2").unwrap();
+    assert!(engine.previous_versions(b"k", 5).unwrap().is_empty());
+    assert_eq!(engine.stats().history_entries, 0);
+}
+
+#[test]
+fn test_history_dropped_on_delete() {
+    let file = NamedTempFile::new().unwrap();
+    let engine = engine_with(
+        file.path(),
+        Options {
+            history_depth: 2,
+            ..Options::default()
+        },
+    );
+    engine.set(b"k", b"1").unwrap();
+    engine.set(b"k", b"2").unwrap();
+    engine.del(b"k").unwrap();
+    engine.set(b"k", b"3").unwrap();
+    assert!(engine.previous_versions(b"k", 5).unwrap().is_empty());
+}
+
+#[test]
+fn test_history_memory_in_stats() {
+    let file = NamedTempFile::new().unwrap();
+    let engine = engine_with(
+        file.path(),
+        Options {
+            history_depth: 3,
+            ..Options::default()
+        },
+    );
+    for i in 0..5u32 {
+        engine.set(b"a", &i.to_le_bytes()).unwrap();
+        engine.set(b"b", &i.to_le_bytes()).unwrap();
+    }
+
+    let stats = engine.stats();
+    assert_eq!(stats.live_keys, 2);
+    assert_eq!(stats.history_entries, 6);
+    assert!(stats.history_bytes > 0);
+}
+
+// ==================== Read Coalescing ====================
+
+#[test]
+fn test_concurrent_gets_of_same_key_share_one_read() {
+    let storage = common::InstrumentedStorage::default();
+    let probe = Arc::clone(&storage.probe);
+    let file = NamedTempFile::new().unwrap();
+    let engine = Arc::new(
+        Engine::load_with_options(
+            file.path(),
+            Options {
+                storage: Arc::new(storage),
+                ..Options::default()
+            },
+        )
+        .unwrap(),
+    );
+    engine.set(b"hot", b"value").unwrap();
+
+    probe.reset();
+    probe.set_read_delay(Duration::from_millis(300));
+
+    let threads = 64;
+    let barrier = Arc::new(Barrier::new(threads));
+    let handles: Vec<_> = (0..threads)
+        .map(|_| {
+            let engine = Arc::clone(&engine);
+            let barrier = Arc::clone(&barrier);
+            thread::spawn(move || {
+                barrier.wait();
+                engine.get(b"hot").unwrap()
+            })
+        })
+        .collect();
+
+    for handle in handles {
+        assert_eq!(handle.join().unwrap(), Some(b"value".to_vec()));
+    }
+
+    assert_eq!(probe.reads(), 1);
+    let stats = engine.stats();
+    assert_eq!(stats.disk_reads, 1);
+    assert_eq!(stats.coalesced_reads, threads as u64 - 1);
+}
+
+#[test]
+fn test_sequential_gets_are_not_coalesced() {
+    let (engine, _f) = temp_engine();
+    engine.set(b"k", b"v").unwrap();
+    for _ in 0..3 {
+        assert_eq!(engine.get(b"k").unwrap(), Some(b"v".to_vec()));
+    }
+    let stats = engine.stats();
+    assert_eq!(stats.disk_reads, 3);
+    assert_eq!(stats.coalesced_reads, 0);
+}
+
+// ==================== Read Sources ====================
+
+#[test]
+fn test_get_traced_tells_pooled_handles_from_fresh_ones() {
+    let (engine, _f) = temp_engine();
+    engine.set(b"k", b"v").unwrap();
+    let traced = |key: &[u8]| engine.get_traced(key).unwrap();
+    // Load fills the pool up front; empty it so the first read is cold.
+    engine.reclaim_idle(ReclaimOptions::default());
+
+    // The first read opens a handle and pools it; the next reuses it.
+    let cold = Some((b"v".to_vec(), ReadSource::Disk { pool_hit: false }));
+    let warm = Some((b"v".to_vec(), ReadSource::Disk { pool_hit: true }));
+    assert_eq!(traced(b"k"), cold);
+    assert_eq!(traced(b"k"), warm);
+    assert_eq!(engine.get(b"k").unwrap(), Some(b"v".to_vec()));
+    engine.reclaim_idle(ReclaimOptions::default());
+    assert_eq!(traced(b"k"), cold);
+    assert_eq!(traced(b"missing"), None);
+
+    let stats = engine.stats();
+    assert_eq!((stats.get_pool_hits, stats.get_pool_misses), (2, 2));
+    assert_eq!((stats.coalesced_reads, stats.archive_gets), (0, 0));
+    assert_eq!(stats.disk_reads, 4);
+}
+
+#[test]
+fn test_get_traced_reports_coalesced_and_archived_reads() {
+    let storage = common::InstrumentedStorage::default();
+    let probe = Arc::clone(&storage.probe);
+    let dir = tempfile::tempdir().unwrap();
+    let engine = Arc::new(
+        Engine::load_with_options(
+            dir.path().join("data.db"),
+            Options {
+                storage: Arc::new(storage),
+                ..archiving(dir.path())
+            },
+        )
+        .unwrap(),
+    );
+    engine.set(b"hot", b"value").unwrap();
+    engine.set(b"cold", b"archived").unwrap();
+    assert_eq!(engine.archive_where(|key| key == b"cold").unwrap(), 1);
+
+    probe.set_read_delay(Duration::from_millis(300));
+    let threads = 8;
+    let barrier = Arc::new(Barrier::new(threads));
+    let handles: Vec<_> = (0..threads)
+        .map(|_| {
+            let engine = Arc::clone(&engine);
+            let barrier = Arc::clone(&barrier);
+            thread::spawn(move || {
+                barrier.wait();
+                engine.get_traced(b"hot").unwrap().unwrap()
+            })
+        })
+        .collect();
+    let mut sources: Vec<_> = handles
+        .into_iter()
+        .map(|handle| {
+            let (value, source) = handle.join().unwrap();
+            assert_eq!(value, b"value");
+            source
+        })
+        .collect();
+    sources.retain(|source| *source != ReadSource::Coalesced);
+    assert_eq!(sources.len(), 1);
+    assert!(matches!(sources[0], ReadSource::Disk { .. }));
+    probe.set_read_delay(Duration::ZERO);
+
+    assert_eq!(
+        engine.get_traced(b"cold").unwrap(),
+        Some((b"archived".to_vec(), ReadSource::Archive))
+    );
+    let stats = engine.stats();
+    assert_eq!(stats.coalesced_reads, threads as u64 - 1);
+    assert_eq!(stats.archive_gets, 1);
+}
+
+// ==================== Read Concurrency Limit ====================
+
+fn concurrent_distinct_gets(engine: &Arc<Engine>, threads: usize) {
+    let barrier = Arc::new(Barrier::new(threads));
+    let handles: Vec<_> = (0..threads)
+        .map(|i| {
+            let engine = Arc::clone(engine);
+            let barrier = Arc::clone(&barrier);
+            thread::spawn(move || {
+                barrier.wait();
+                let key = format!("key{}", i);
+                assert_eq!(engine.get(key.as_bytes()).unwrap(), Some(key.into_bytes()));
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+}
+
+#[test]
+fn test_read_limit_caps_reads_in_flight() {
+    let file = NamedTempFile::new().unwrap();
+    let storage = common::InstrumentedStorage::default();
+    let probe = Arc::clone(&storage.probe);
+    let engine = Arc::new(engine_with(
+        file.path(),
+        Options {
+            max_concurrent_reads: Some(4),
+            storage: Arc::new(storage),
+            ..Options::default()
+        },
+    ));
+    let threads = 32;
+    for i in 0..threads {
+        let key = format!("key{}", i);
+        engine.set(key.as_bytes(), key.as_bytes()).unwrap();
+    }
+
+    probe.reset();
+    probe.set_read_delay(Duration::from_millis(20));
+    concurrent_distinct_gets(&engine, threads);
+
+    assert!(probe.max_reads_in_flight() <= 4);
+    assert_eq!(probe.reads(), threads as u64);
+    let stats = engine.stats();
+    assert!(stats.read_waits > 0);
+    assert!(stats.read_wait_micros > 0);
+}
+
+#[test]
+fn test_reads_are_unlimited_by_default() {
+    let file = NamedTempFile::new().unwrap();
+    let storage = common::InstrumentedStorage::default();
+    let probe = Arc::clone(&storage.probe);
+    let engine = Arc::new(engine_with(
+        file.path(),
+        Options {
+            storage: Arc::new(storage),
+            ..Options::default()
+        },
+    ));
+    let threads = 16;
+    for i in 0..threads {
+        let key = format!("key{}", i);
+        engine.set(key.as_bytes(), key.as_bytes()).unwrap();
+    }
+
+    probe.reset();
+    probe.set_read_delay(Duration::from_millis(100));
+    concurrent_distinct_gets(&engine, threads);
+
+    assert!(probe.max_reads_in_flight() > 4);
+    assert_eq!(engine.stats().read_waits, 0);
+}
+
+// ==================== Range Reads ====================
+
+#[test]
+fn test_get_range_reads_slice() {
+    let (engine, _f) = temp_engine();
+    let value: Vec<u8> = (0..=255u8).collect();
+    engine.set(b"arr", &value).unwrap();
+
+    assert_eq!(
+        engine.get_range(b"arr", 0, 4).unwrap(),
+        Some(vec![0, 1, 2, 3])
+    );
+    assert_eq!(
+        engine.get_range(b"arr", 100, 3).unwrap(),
+        Some(vec![100, 101, 102])
+    );
+    assert_eq!(engine.get_range(b"arr", 0, 256).unwrap(), Some(value));
+}
+
+#[test]
+fn test_get_range_clamps_past_value_end() {
+    let (engine, _f) = temp_engine();
+    engine.set(b"k", b"abcdef").unwrap();
+
+    assert_eq!(
+        engine.get_range(b"k", 4, 100).unwrap(),
+        Some(b"ef".to_vec())
+    );
+    assert_eq!(engine.get_range(b"k", 6, 10).unwrap(), Some(Vec::new()));
+    assert_eq!(engine.get_range(b"k", 50, 10).unwrap(), Some(Vec::new()));
+    assert_eq!(
+        engine.get_range(b"k", u64::MAX, u64::MAX).unwrap(),
+        Some(Vec::new())
+    );
+}
+
+#[test]
+fn test_get_range_missing_or_deleted_key_is_none() {
+    let (engine, _f) = temp_engine();
+    assert_eq!(engine.get_range(b"ghost", 0, 1).unwrap(), None);
+
+    engine.set(b"k", b"v").unwrap();
+    engine.del(b"k").unwrap();
+    assert_eq!(engine.get_range(b"k", 0, 1).unwrap(), None);
+}
+
+#[test]
+fn test_get_range_after_overwrite_compaction_and_reload() {
+    let file = NamedTempFile::new().unwrap();
+    let path = file.path().to_owned();
+
+    {
+        let engine = Engine::load(&path).unwrap();
+        engine.set(b"key-with-longer-name", b"old-value").unwrap();
+        engine.set(b"key-with-longer-name", b"0123456789").unwrap();
+        engine.compact().unwrap();
+        assert_eq!(
+            engine.get_range(b"key-with-longer-name", 2, 3).unwrap(),
+            Some(b"234".to_vec())
+        );
+    }
+
+    let engine = Engine::load(&path).unwrap();
+    assert_eq!(
+        engine.get_range(b"key-with-longer-name", 7, 5).unwrap(),
+        Some(b"789".to_vec())
+    );
+}
+
+#[test]
+fn test_get_range_matches_get_for_random_slices() {
+    let (engine, _f) = temp_engine();
+    let value: Vec<u8> = (0..5000u32).map(|i| (i * 31 % 251) as u8).collect();
+    engine.set(&[0x00, 0xFF], &value).unwrap();
+
+    for (offset, len) in [(0, 1), (1, 4999), (4096, 4096), (123, 456), (4999, 1)] {
+        let expected = value
+            .iter()
+            .skip(offset)
+            .take(len)
+            .copied()
+            .collect::<Vec<u8>>();
+        assert_eq!(
+            engine
+                .get_range(&[0x00, 0xFF], offset as u64, len as u64)
+                .unwrap(),
+            Some(expected)
+        );
+    }
+}
+
+// ==================== Dead Bytes and Tombstone Compaction ====================
+
+#[test]
+fn test_tombstone_count_triggers_compaction_under_huge_threshold() {
+    let file = NamedTempFile::new().unwrap();
+    let path = file.path().to_owned();
+    write_header(&path, u64::MAX);
+    let engine = Engine::load_with_options(
+        &path,
+        Options {
+            tombstone_compact_count: Some(10_000),
+            ..Options::default()
+        },
+    )
+    .unwrap();
+
+    let mut peak = 0;
+    for i in 0..20_000u32 {
+        let key = format!("job{}", i);
+        engine.set(key.as_bytes(), b"payload").unwrap();
+        engine.del(key.as_bytes()).unwrap();
+        peak = peak.max(fs::metadata(&path).unwrap().len());
+    }
+
+    let stats = engine.stats();
+    assert!(stats.compactions >= 2);
+    assert!(stats.tombstones < 10_000);
+    assert!(fs::metadata(&path).unwrap().len() < peak);
+    assert_eq!(engine.stats().live_keys, 0);
+}
+
+#[test]
+fn test_tombstone_ratio_triggers_compaction() {
+    let file = NamedTempFile::new().unwrap();
+    let path = file.path().to_owned();
+    write_header(&path, u64::MAX);
+    let engine = Engine::load_with_options(
+        &path,
+        Options {
+            tombstone_compact_ratio: Some(0.5),
+            ..Options::default()
+        },
+    )
+    .unwrap();
+
+    engine.set(b"a", b"1").unwrap();
+    engine.del(b"a").unwrap();
+    assert_eq!(engine.stats().compactions, 0);
+    engine.del(b"b").unwrap();
+    assert_eq!(engine.stats().compactions, 1);
+    assert_eq!(fs::metadata(&path).unwrap().len(), FILE_HEADER_SIZE);
+}
+
+#[test]
+fn test_tombstone_triggers_disabled_by_default() {
+    let file = NamedTempFile::new().unwrap();
+    let path = file.path().to_owned();
+    write_header(&path, u64::MAX);
+    let engine = Engine::load(&path).unwrap();
+
+    for i in 0..100u32 {
+        engine.del(&i.to_le_bytes()).unwrap();
+    }
+    assert_eq!(engine.stats().compactions, 0);
+    assert_eq!(engine.stats().tombstones, 100);
+}
+
+#[test]
+fn test_dead_bytes_tracked_and_reset_by_compaction() {
+    let file = NamedTempFile::new().unwrap();
+    let path = file.path().to_owned();
+    let engine = Engine::load(&path).unwrap();
+
+    engine.set(b"k", b"v1").unwrap();
+    let one_record = engine.stats().file_size - FILE_HEADER_SIZE;
+    engine.set(b"k", b"v2").unwrap();
+    assert_eq!(engine.stats().dead_bytes, one_record);
+
+    engine.del(b"k").unwrap();
+    let stats = engine.stats();
+    assert_eq!(stats.dead_bytes, stats.file_size - FILE_HEADER_SIZE);
+    assert_eq!(stats.tombstones, 1);
+    drop(engine);
+
+    let engine = Engine::load(&path).unwrap();
+    let stats = engine.stats();
+    assert_eq!(stats.dead_bytes, stats.file_size - FILE_HEADER_SIZE);
+    assert_eq!(stats.tombstones, 1);
+
+    engine.compact().unwrap();
+    let stats = engine.stats();
+    assert_eq!(stats.dead_bytes, 0);
+    assert_eq!(stats.tombstones, 0);
+    assert_eq!(stats.tombstone_bytes, 0);
+}
+
+// ==================== Compaction Estimate ====================
+
+#[test]
+fn test_compaction_estimate_matches_actual_compaction() {
+    let file = NamedTempFile::new().unwrap();
+    let path = file.path().to_owned();
+    let engine = Engine::load_with_options(
+        &path,
+        Options {
+            history_depth: 2,
+            ..Options::default()
+        },
+    )
+    .unwrap();
+
+    for i in 0..500u32 {
+        let key = format!("key{}", i % 37);
+        engine
+            .set(key.as_bytes(), &vec![b'x'; (i % 50) as usize])
+            .unwrap();
+        if i % 7 == 0 {
+            engine.del(format!("key{}", i % 11).as_bytes()).unwrap();
+        }
+    }
+    engine.set(b"soft", b"gone-but-recoverable").unwrap();
+    engine.soft_del(b"soft").unwrap();
+
+    let size_before = fs::metadata(&path).unwrap().len();
+    let estimate = engine.compaction_estimate();
+    assert_eq!(fs::metadata(&path).unwrap().len(), size_before);
+
+    engine.compact().unwrap();
+    let size_after = fs::metadata(&path).unwrap().len();
+
+    assert_eq!(estimate.expected_size, size_after);
+    assert_eq!(estimate.reclaimable_bytes, size_before - size_after);
+    let stats = engine.stats();
+    assert_eq!(
+        estimate.live_entries,
+        (stats.live_keys + stats.history_entries + stats.soft_deleted_keys) as u64
+    );
+}
+
+#[test]
+fn test_compaction_estimate_on_empty_store() {
+    let (engine, _f) = temp_engine();
+    let estimate = engine.compaction_estimate();
+    assert_eq!(estimate.expected_size, FILE_HEADER_SIZE);
+    assert_eq!(estimate.reclaimable_bytes, 0);
+    assert_eq!(estimate.live_entries, 0);
+}
+
+// ==================== Pre-allocation ====================
+
+#[test]
+fn test_preallocation_grows_file_in_chunks() {
+    let file = NamedTempFile::new().unwrap();
+    let path = file.path().to_owned();
+    let chunk = 64 * 1024;
+
+    {
+        let engine = engine_with(
+            &path,
+            Options {
+                preallocate_chunk: Some(chunk),
+                ..Options::default()
+            },
+        );
+        for i in 0..100u32 {
+            engine.set(format!("k{}", i).as_bytes(), b"value").unwrap();
+        }
+
+        let physical = fs::metadata(&path).unwrap().len();
+        let stats = engine.stats();
+        assert_eq!(physical, chunk);
+        assert_eq!(stats.allocated_size, chunk);
+        assert!(stats.file_size < physical);
+    }
+
+    let engine = engine_with(
+        &path,
+        Options {
+            preallocate_chunk: Some(chunk),
+            ..Options::default()
+        },
+    );
+    for i in 0..100u32 {
+        assert_eq!(
+            engine.get(format!("k{}", i).as_bytes()).unwrap(),
+            Some(b"value".to_vec())
+        );
+    }
+
+    engine.set(b"after-reload", b"ok").unwrap();
+    drop(engine);
+
+    let engine = Engine::load(&path).unwrap();
+    assert_eq!(engine.get(b"after-reload").unwrap(), Some(b"ok".to_vec()));
+    assert_eq!(engine.stats().live_keys, 101);
+    assert_eq!(fs::metadata(&path).unwrap().len(), engine.stats().file_size);
+}
+
+#[test]
+fn test_compaction_right_sizes_preallocated_file() {
+    let file = NamedTempFile::new().unwrap();
+    let path = file.path().to_owned();
+    let chunk = 64 * 1024;
+    let engine = engine_with(
+        &path,
+        Options {
+            preallocate_chunk: Some(chunk),
+            ..Options::default()
+        },
+    );
+
+    // Grows the file to 15 chunks, nearly all of it overwritten.
+    for i in 0..900u32 {
+        engine.set(b"k", &[i as u8; 1024]).unwrap();
+    }
+    assert_eq!(fs::metadata(&path).unwrap().len(), 15 * chunk);
+
+    // Compaction keeps one chunk past the end of the log, and the writes
+    // after it fill that chunk before allocating again.
+    engine.compact().unwrap();
+    let stats = engine.stats();
+    assert_eq!(fs::metadata(&path).unwrap().len(), stats.file_size + chunk);
+    assert_eq!(stats.allocated_size, stats.file_size + chunk);
+    for i in 0..10u32 {
+        engine.set(format!("after{}", i).as_bytes(), b"v").unwrap();
+    }
+    assert_eq!(fs::metadata(&path).unwrap().len(), stats.file_size + chunk);
+    assert_eq!(engine.get(b"k").unwrap(), Some(vec![131; 1024]));
+
+    // An allocation within a chunk of the compacted log is kept whole.
+    let small = NamedTempFile::new().unwrap();
+    let engine = engine_with(
+        small.path(),
+        Options {
+            preallocate_chunk: Some(1024 * 1024),
+            ..Options::default()
+        },
+    );
+    for i in 0..50u32 {
+        engine.set(b"k", &i.to_le_bytes()).unwrap();
+    }
+    engine.compact().unwrap();
+    assert_eq!(fs::metadata(small.path()).unwrap().len(), 1024 * 1024);
+    assert_eq!(
+        engine.get(b"k").unwrap(),
+        Some(49u32.to_le_bytes().to_vec())
+    );
+    drop(engine);
+    let engine = Engine::load(small.path()).unwrap();
+    assert_eq!(
+        fs::metadata(small.path()).unwrap().len(),
+        engine.stats().file_size
+    );
+}
+
+#[test]
+fn test_shrink_to_fit_releases_preallocated_space() {
+    let file = NamedTempFile::new().unwrap();
+    let path = file.path().to_owned();
+    let chunk = 64 * 1024;
+    let engine = engine_with(
+        &path,
+        Options {
+            preallocate_chunk: Some(chunk),
+            ..Options::default()
+        },
+    );
+    for i in 0..20u32 {
+        engine.set(format!("k{}", i).as_bytes(), b"value").unwrap();
+    }
+    // Within one chunk of the end there is nothing to release.
+    assert_eq!(engine.shrink_to_fit().unwrap(), 0);
+    assert_eq!(fs::metadata(&path).unwrap().len(), chunk);
+
+    // Space reserved past that, here by another tool, is given back.
+    fs::OpenOptions::new()
+        .write(true)
+        .open(&path)
+        .unwrap()
+        .set_len(64 * chunk)
+        .unwrap();
+    let file_size = engine.stats().file_size;
+    assert_eq!(
+        engine.shrink_to_fit().unwrap(),
+        64 * chunk - (file_size + chunk)
+    );
+    assert_eq!(fs::metadata(&path).unwrap().len(), file_size + chunk);
+    assert_eq!(engine.stats().allocated_size, file_size + chunk);
+    assert_eq!(engine.shrink_to_fit().unwrap(), 0);
+
+    // The next appends write into the chunk kept.
+    engine.set(b"after-shrink", b"ok").unwrap();
+    assert_eq!(fs::metadata(&path).unwrap().len(), file_size + chunk);
+    for i in 0..20u32 {
+        assert_eq!(
+            engine.get(format!("k{}", i).as_bytes()).unwrap(),
+            Some(b"value".to_vec())
+        );
+    }
+    drop(engine);
+    let engine = Engine::load(&path).unwrap();
+    assert_eq!(engine.get(b"after-shrink").unwrap(), Some(b"ok".to_vec()));
+    assert_eq!(engine.stats().live_keys, 21);
+}
+
+#[test]
+fn test_torn_tail_is_dropped_on_load() {
+    let file = NamedTempFile::new().unwrap();
+    let mut torn = 500u64.to_le_bytes().to_vec();
+    torn.extend_from_slice(b"partial");
+    let fixture = StoreFixture::builder()
+        .keys(20)
+        .overwrite_ratio(0.3)
+        .deleted_ratio(0.1)
+        .corrupt_at(Corruption::TornTail(torn))
+        .build(file.path())
+        .unwrap();
+    let path = fixture.path();
+
+    {
+        let engine = Engine::load(path).unwrap();
+        assert_eq!(fs::metadata(path).unwrap().len(), fixture.log_len());
+        engine.set(b"next", b"write").unwrap();
+    }
+
+    let engine = Engine::load(path).unwrap();
+    assert_fixture_reads_back(&engine, &fixture);
+    assert_eq!(engine.get(b"next").unwrap(), Some(b"write".to_vec()));
+}
+
+// ==================== Background Sync ====================
+
+#[test]
+fn test_interval_sync_only_after_writes() {
+    let file = NamedTempFile::new().unwrap();
+    let storage = common::InstrumentedStorage::default();
+    let probe = Arc::clone(&storage.probe);
+    let period = Duration::from_millis(20);
+    let engine = engine_with(
+        file.path(),
+        Options {
+            durability: Durability::Interval {
+                period,
+                jitter: period / 4,
+            },
+            storage: Arc::new(storage),
+            ..Options::default()
+        },
+    );
+
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(probe.syncs(), 0);
+    assert_eq!(engine.stats().last_sync_millis, None);
+
+    engine.set(b"k", b"v").unwrap();
+    assert!(wait_for(|| probe.syncs() == 1));
+
+    // Idle periods do not fsync again.
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(probe.syncs(), 1);
+    let stats = engine.stats();
+    assert_eq!(stats.syncs, 1);
+    assert!(stats.last_sync_millis.is_some());
+
+    engine.set(b"k", b"v2").unwrap();
+    assert!(wait_for(|| probe.syncs() == 2));
+}
+
+#[test]
+fn test_explicit_sync_is_not_repeated_by_background_thread() {
+    let file = NamedTempFile::new().unwrap();
+    let storage = common::InstrumentedStorage::default();
+    let probe = Arc::clone(&storage.probe);
+    let period = Duration::from_millis(50);
+    let engine = engine_with(
+        file.path(),
+        Options {
+            durability: Durability::Interval {
+                period,
+                jitter: period / 4,
+            },
+            storage: Arc::new(storage),
+            ..Options::default()
+        },
+    );
+
+    engine.set(b"k", b"v").unwrap();
+    engine.sync().unwrap();
+    assert_eq!(probe.syncs(), 1);
+
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(probe.syncs(), 1);
+}
+
+#[test]
+fn test_background_sync_errors_reach_hook() {
+    let errors = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let hook = {
+        let errors = Arc::clone(&errors);
+        Hook::new(move |e: &std::io::Error| errors.lock().unwrap().push(e.to_string()))
+    };
+    let file = NamedTempFile::new().unwrap();
+    let storage = common::InstrumentedStorage::default();
+    let probe = Arc::clone(&storage.probe);
+    let period = Duration::from_millis(20);
+    let engine = engine_with(
+        file.path(),
+        Options {
+            durability: Durability::Interval {
+                period,
+                jitter: period / 4,
+            },
+            on_sync_error: Some(hook),
+            storage: Arc::new(storage),
+            ..Options::default()
+        },
+    );
+
+    probe.set_fail_syncs(true);
+    engine.set(b"k", b"v").unwrap();
+    assert!(wait_for(|| !errors.lock().unwrap().is_empty()));
+    assert!(errors.lock().unwrap()[0].contains("injected fsync failure"));
+    assert!(engine.stats().sync_errors > 0);
+
+    // A failed sync leaves the log dirty, so the next tick retries.
+    probe.set_fail_syncs(false);
+    assert!(wait_for(|| probe.syncs() == 1));
+}
+
+#[test]
+fn test_drop_stops_sync_thread_with_final_sync() {
+    let file = NamedTempFile::new().unwrap();
+    let storage = common::InstrumentedStorage::default();
+    let probe = Arc::clone(&storage.probe);
+    let period = Duration::from_secs(3600);
+    let engine = engine_with(
+        file.path(),
+        Options {
+            durability: Durability::Interval {
+                period,
+                jitter: period / 4,
+            },
+            storage: Arc::new(storage),
+            ..Options::default()
+        },
+    );
+    engine.set(b"k", b"v").unwrap();
+
+    let start = std::time::Instant::now();
+    drop(engine);
+    assert!(start.elapsed() < Duration::from_secs(5));
+    assert_eq!(probe.syncs(), 1);
+}
+
+// ==================== Tracked Prefixes ====================
+
+fn expected_prefix_stats(
+    model: &HashMap<Vec<u8>, Vec<u8>>,
+    prefixes: &[&[u8]],
+) -> Vec<PrefixStats> {
+    prefixes
+        .iter()
+        .map(|prefix| {
+            let matching: Vec<&Vec<u8>> = model
+                .iter()
+                .filter(|(k, _)| k.starts_with(prefix))
+                .map(|(_, v)| v)
+                .collect();
+            PrefixStats {
+                prefix: prefix.to_vec(),
+                keys: matching.len() as u64,
+                value_bytes: matching.iter().map(|v| v.len() as u64).sum(),
+            }
+        })
+        .collect()
+}
+
+#[test]
+fn test_tracked_prefix_stats_stay_exact() {
+    let prefixes: [&[u8]; 3] = [b"sess:", b"sess:admin:", b"user:"];
+    let file = NamedTempFile::new().unwrap();
+    let path = file.path().to_owned();
+    let options = Options {
+        history_depth: 1,
+        tracked_prefixes: prefixes.iter().map(|p| p.to_vec()).collect(),
+        ..Options::default()
+    };
+    let engine = Engine::load_with_options(&path, options.clone()).unwrap();
+
+    let key_space: Vec<Vec<u8>> = (0..30)
+        .map(|i| {
+            let prefix = ["sess:", "sess:admin:", "user:", "other:"][i % 4];
+            format!("{}{}", prefix, i).into_bytes()
+        })
+        .collect();
+    let mut model: HashMap<Vec<u8>, Vec<u8>> = HashMap::new();
+    let mut rng = XorShift(0x9e3779b97f4a7c15);
+
+    for _ in 0..2_000 {
+        let key = &key_space[(rng.next() % key_space.len() as u64) as usize];
+        match rng.next() % 10 {
+            0..=5 => {
+                let value = rng.bytes(64);
+                engine.set(key, &value).unwrap();
+                model.insert(key.clone(), value);
+            }
+            6 | 7 => {
+                engine.del(key).unwrap();
+                model.remove(key);
+            }
+            8 => {
+                if model.contains_key(key) {
+                    engine.soft_del(key).unwrap();
+                    let value = model.remove(key).unwrap();
+                    if rng.next().is_multiple_of(2) {
+                        engine.restore(key).unwrap();
+                        model.insert(key.clone(), value);
+                    }
+                }
+            }
+            _ => engine.compact().unwrap(),
+        }
+        assert_eq!(
+            engine.tracked_prefix_stats(),
+            expected_prefix_stats(&model, &prefixes)
+        );
+    }
+
+    engine.compact().unwrap();
+    assert_eq!(
+        engine.tracked_prefix_stats(),
+        expected_prefix_stats(&model, &prefixes)
+    );
+
+    drop(engine);
+    let engine = Engine::load_with_options(&path, options).unwrap();
+    assert_eq!(
+        engine.tracked_prefix_stats(),
+        expected_prefix_stats(&model, &prefixes)
+    );
+}
+
+#[test]
+fn test_track_prefix_on_populated_store() {
+    let (engine, _f) = temp_engine();
+    engine.set(b"sess:1", b"abc").unwrap();
+    engine.set(b"sess:2", b"defgh").unwrap();
+    engine.set(b"user:1", b"x").unwrap();
+
+    engine.track_prefix(b"sess:");
+    engine.track_prefix(b"sess:");
+    assert_eq!(
+        engine.tracked_prefix_stats(),
+        vec![PrefixStats {
+            prefix: b"sess:".to_vec(),
+            keys: 2,
+            value_bytes: 8,
+        }]
+    );
+
+    engine.set(b"sess:1", b"a").unwrap();
+    engine.del(b"sess:2").unwrap();
+    engine.set(b"sess:3", b"zz").unwrap();
+    assert_eq!(engine.tracked_prefix_stats()[0].keys, 2);
+    assert_eq!(engine.tracked_prefix_stats()[0].value_bytes, 3);
+}
+
+// ==================== Checkpoints ====================
+
+#[test]
+fn test_checkpoint_is_loadable_and_isolated() {
+    let (engine, _f) = temp_engine();
+    for i in 0..100u32 {
+        engine
+            .set(format!("k{}", i).as_bytes(), &i.to_le_bytes())
+            .unwrap();
+    }
+    engine.del(b"k0").unwrap();
+
+    let dir = tempfile::tempdir().unwrap();
+    let target = dir.path().join("nested");
+    engine.checkpoint(&target).unwrap();
+
+    engine.set(b"k1", b"changed").unwrap();
+    engine.set(b"new", b"after-checkpoint").unwrap();
+    engine.compact().unwrap();
+
+    let entries: Vec<_> = fs::read_dir(&target).unwrap().collect();
+    assert_eq!(entries.len(), 1);
+    let copy = Engine::load(entries[0].as_ref().unwrap().path()).unwrap();
+
+    assert_eq!(copy.stats().live_keys, 99);
+    assert_eq!(copy.get(b"k0").unwrap(), None);
+    assert_eq!(copy.get(b"k1").unwrap(), Some(1u32.to_le_bytes().to_vec()));
+    assert_eq!(copy.get(b"new").unwrap(), None);
+    assert_eq!(engine.get(b"k1").unwrap(), Some(b"changed".to_vec()));
+}
+
+#[test]
+fn test_checkpoint_of_preallocated_store_has_logical_size() {
+    let file = NamedTempFile::new().unwrap();
+    let engine = engine_with(
+        file.path(),
+        Options {
+            preallocate_chunk: Some(1024 * 1024),
+            ..Options::default()
+        },
+    );
+    engine.set(b"k", b"v").unwrap();
+
+    let dir = tempfile::tempdir().unwrap();
+    engine.checkpoint(dir.path()).unwrap();
+
+    let copy_path = dir.path().join(file.path().file_name().unwrap());
+    assert_eq!(
+        fs::metadata(&copy_path).unwrap().len(),
+        engine.stats().file_size
+    );
+    let copy = Engine::load(&copy_path).unwrap();
+    assert_eq!(copy.get(b"k").unwrap(), Some(b"v".to_vec()));
+}
+
+// ==================== Value Dedup ====================
+
+fn blob(seed: u8, len: usize) -> Vec<u8> {
+    (0..len)
+        .map(|i| (i as u8).wrapping_mul(31) ^ seed)
+        .collect()
+}
+
+#[test]
+fn test_dedup_stores_shared_value_once() {
+    let file = NamedTempFile::new().unwrap();
+    let path = file.path().to_owned();
+    let value = blob(7, 256 * 1024);
+
+    {
+        let engine = engine_with(
+            &path,
+            Options {
+                dedup_min_value_len: Some(1024),
+                ..Options::default()
+            },
+        );
+        for i in 0..200u32 {
+            engine.set(format!("file{}", i).as_bytes(), &value).unwrap();
+        }
+        engine.set(b"small", b"inline").unwrap();
+
+        let stats = engine.stats();
+        assert_eq!(stats.blobs, 1);
+        assert_eq!(stats.blob_refs, 200);
+        assert_eq!(stats.live_keys, 201);
+        assert!(stats.file_size < 2 * value.len() as u64);
+
+        assert_eq!(engine.get(b"file42").unwrap(), Some(value.clone()));
+        assert_eq!(
+            engine.get_range(b"file7", 1000, 10).unwrap(),
+            Some(value[1000..1010].to_vec())
+        );
+        assert_eq!(engine.get(b"small").unwrap(), Some(b"inline".to_vec()));
+    }
+
+    let engine = engine_with(
+        &path,
+        Options {
+            dedup_min_value_len: Some(1024),
+            ..Options::default()
+        },
+    );
+    let stats = engine.stats();
+    assert_eq!((stats.blobs, stats.blob_refs), (1, 200));
+    assert_eq!(engine.get(b"file199").unwrap(), Some(value));
+}
+
+#[test]
+fn test_dedup_blob_reclaimed_after_last_reference() {
+    let file = NamedTempFile::new().unwrap();
+    let path = file.path().to_owned();
+    let shared = blob(1, 64 * 1024);
+    let engine = engine_with(
+        &path,
+        Options {
+            dedup_min_value_len: Some(1024),
+            ..Options::default()
+        },
+    );
+
+    for i in 0..10u32 {
+        engine.set(format!("k{}", i).as_bytes(), &shared).unwrap();
+    }
+    for i in 0..9u32 {
+        engine.del(format!("k{}", i).as_bytes()).unwrap();
+    }
+    engine.compact().unwrap();
+    assert_eq!(engine.stats().blobs, 1);
+    assert_eq!(engine.get(b"k9").unwrap(), Some(shared.clone()));
+
+    // Rewriting the last referer with the same content keeps the blob alive;
+    // new content replaces it.
+    engine.set(b"k9", &shared).unwrap();
+    assert_eq!(engine.stats().blobs, 1);
+    let other = blob(2, 64 * 1024);
+    engine.set(b"k9", &other).unwrap();
+    assert_eq!(engine.stats().blobs, 1);
+    assert!(engine.stats().dead_bytes > shared.len() as u64);
+
+    engine.del(b"k9").unwrap();
+    assert_eq!(engine.stats().blobs, 0);
+    engine.compact().unwrap();
+    assert!(engine.stats().file_size < 1024);
+
+    drop(engine);
+    let engine = engine_with(
+        &path,
+        Options {
+            dedup_min_value_len: Some(1024),
+            ..Options::default()
+        },
+    );
+    assert_eq!(engine.stats().blobs, 0);
+    assert_eq!(engine.get(b"k9").unwrap(), None);
+}
+
+#[test]
+fn test_dedup_values_survive_soft_delete_and_dump() {
+    let file = NamedTempFile::new().unwrap();
+    let source = engine_with(
+        file.path(),
+        Options {
+            dedup_min_value_len: Some(1024),
+            ..Options::default()
+        },
+    );
+    let value = blob(3, 4096);
+    source.set(b"a", &value).unwrap();
+    source.set(b"b", &value).unwrap();
+
+    source.soft_del(b"a").unwrap();
+    assert_eq!(source.get(b"b").unwrap(), Some(value.clone()));
+    source.restore(b"a").unwrap();
+    assert_eq!(source.get(b"a").unwrap(), Some(value.clone()));
+
+    let mut out = Vec::new();
+    assert_eq!(source.dump(&mut out).unwrap(), 2);
+    let (restored, _g) = temp_engine();
+    restored.load_dump(&out[..]).unwrap();
+    assert_eq!(restored.get(b"a").unwrap(), Some(value.clone()));
+    assert_eq!(restored.get(b"b").unwrap(), Some(value));
+}
+
+#[test]
+fn test_dedup_rejects_history() {
+    let file = NamedTempFile::new().unwrap();
+    let err = Engine::load_with_options(
+        file.path(),
+        Options {
+            dedup_min_value_len: Some(1),
+            history_depth: 1,
+            ..Options::default()
+        },
+    )
+    .err()
+    .unwrap();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+}
+
+// ==================== Latency Stats ====================
+
+#[test]
+fn test_latency_histograms_match_op_counters() {
+    let file = NamedTempFile::new().unwrap();
+    let engine = Engine::load_with_options(
+        file.path(),
+        Options {
+            latency_histograms: true,
+            ..Options::default()
+        },
+    )
+    .unwrap();
+    for i in 0..300u32 {
+        engine
+            .set(format!("k{}", i % 50).as_bytes(), &i.to_le_bytes())
+            .unwrap();
+        if i % 3 == 0 {
+            engine.get(format!("k{}", i % 70).as_bytes()).unwrap();
+        }
+    }
+
+    let stats = engine.stats();
+    assert_eq!(stats.sets, 300);
+    assert_eq!(stats.gets, 100);
+    assert_eq!(stats.set_latency.count(), stats.sets);
+    assert_eq!(stats.get_latency.count(), stats.gets);
+
+    for latency in [&stats.get_latency, &stats.set_latency] {
+        assert!(latency.p50() <= latency.p90());
+        assert!(latency.p90() <= latency.p99());
+        assert!(latency.p99() <= latency.max());
+        assert!(latency.max() > Duration::ZERO);
+    }
+
+    engine.reset_latency_stats();
+    let stats = engine.stats();
+    assert_eq!(stats.get_latency.count(), 0);
+    assert_eq!(stats.set_latency.max(), Duration::ZERO);
+    assert_eq!(stats.sets, 300);
+}
+
+#[test]
+fn test_latency_histograms_off_by_default() {
+    let (engine, _f) = temp_engine();
+    engine.set(b"k", b"v").unwrap();
+    engine.get(b"k").unwrap();
+
+    let stats = engine.stats();
+    assert_eq!((stats.sets, stats.gets), (1, 1));
+    assert_eq!(stats.get_latency.count(), 0);
+    assert_eq!(stats.set_latency.count(), 0);
+}
+
+#[test]
+fn test_latency_histogram_captures_slow_reads() {
+    let storage = common::InstrumentedStorage::default();
+    let probe = Arc::clone(&storage.probe);
+    let file = NamedTempFile::new().unwrap();
+    let engine = Engine::load_with_options(
+        file.path(),
+        Options {
+            latency_histograms: true,
+            storage: Arc::new(storage),
+            ..Options::default()
+        },
+    )
+    .unwrap();
+    engine.set(b"k", b"v").unwrap();
+
+    for _ in 0..9 {
+        engine.get(b"k").unwrap();
+    }
+    probe.set_read_delay(Duration::from_millis(30));
+    engine.get(b"k").unwrap();
+
+    let latency = engine.stats().get_latency;
+    assert_eq!(latency.count(), 10);
+    assert!(latency.p50() < Duration::from_millis(30));
+    assert!(latency.max() >= Duration::from_millis(30));
+    assert!(latency.percentile(1.0) >= Duration::from_millis(26));
+}
+
+// ==================== Empty Keys ====================
+
+fn assert_empty_key_error(err: std::io::Error) {
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    assert_eq!(EngineError::from_io(&err), Some(&EngineError::EmptyKey));
+}
+
+#[test]
+fn test_empty_key_rejected_by_writes() {
+    let (engine, _f) = temp_engine();
+    engine.set(b"k", b"v").unwrap();
+    let size = engine.stats().file_size;
+
+    assert_empty_key_error(engine.set(b"", b"v").unwrap_err());
+    assert_empty_key_error(engine.del(b"").unwrap_err());
+
+    assert_eq!(engine.stats().file_size, size);
+    assert_eq!(engine.stats().live_keys, 1);
+}
+
+#[test]
+fn test_empty_key_rejected_by_reads() {
+    let (engine, _f) = temp_engine();
+    engine.set(b"k", b"v").unwrap();
+
+    assert_empty_key_error(engine.get(b"").unwrap_err());
+    assert_empty_key_error(engine.get_range(b"", 0, 1).unwrap_err());
+    assert_empty_key_error(engine.previous_versions(b"", 1).unwrap_err());
+    assert_empty_key_error(engine.contains_key(b"").unwrap_err());
+    assert_empty_key_error(engine.stat(b"").unwrap_err());
+    // An empty prefix still covers every key.
+    assert_eq!(engine.scan_prefix(b"").unwrap().len(), 1);
+}
+
+#[test]
+fn test_dump_with_empty_key_rejected_before_writing() {
+    let mut bytes = Vec::new();
+    {
+        let mut writer = breakout1_kv_store::dump::DumpWriter::new(&mut bytes).unwrap();
+        writer.write_record(b"fine", b"v", 1).unwrap();
+        writer.write_record(b"", b"v", 2).unwrap();
+        writer.finish().unwrap();
+    }
+
+    let (engine, _f) = temp_engine();
+    assert_empty_key_error(engine.load_dump(&bytes[..]).unwrap_err());
+    assert_eq!(engine.get(b"fine").unwrap(), None);
+}
+
+// ==================== Threshold Header ====================
+
+#[test]
+fn test_set_compact_threshold_persists() {
+    let file = NamedTempFile::new().unwrap();
+    let path = file.path().to_owned();
+    {
+        let engine = Engine::load(&path).unwrap();
+        engine.set(b"k", b"v").unwrap();
+        engine.set_compact_threshold(12_345_678).unwrap();
+        assert_eq!(engine.stats().compact_threshold, 12_345_678);
+        assert_eq!(read_threshold_from_file(&path), 12_345_678);
+    }
+    let engine = Engine::load(&path).unwrap();
+    assert_eq!(engine.stats().compact_threshold, 12_345_678);
+    assert_eq!(engine.get(b"k").unwrap(), Some(b"v".to_vec()));
+}
+
+#[test]
+fn test_threshold_changes_race_compaction() {
+    let file = NamedTempFile::new().unwrap();
+    let path = file.path().to_owned();
+    let engine = Arc::new(Engine::load(&path).unwrap());
+
+    let threads: Vec<_> = (0..4u64)
+        .map(|t| {
+            let engine = Arc::clone(&engine);
+            thread::spawn(move || {
+                let mut rng = XorShift(t + 1);
+                for i in 0..200u64 {
+                    match t {
+                        0 => engine
+                            .set_compact_threshold(1_000_000 + rng.next() % 1_000_000)
+                            .unwrap(),
+                        1 => engine.compact().unwrap(),
+                        _ => engine
+                            .set(format!("k{}", i % 20).as_bytes(), &rng.bytes(64))
+                            .unwrap(),
+                    }
+                }
+            })
+        })
+        .collect();
+    for handle in threads {
+        handle.join().unwrap();
+    }
+
+    let threshold = engine.stats().compact_threshold;
+    assert_eq!(read_threshold_from_file(&path), threshold);
+    drop(engine);
+    assert_eq!(
+        Engine::load(&path).unwrap().stats().compact_threshold,
+        threshold
+    );
+}
+
+/// Runs a fresh store with a 308-byte threshold through one compaction that
+/// barely shrinks it, as in
+/// `test_threshold_doubles_when_compaction_size_unchanged`.
+fn barely_shrinking_compaction(engine: &Engine) {
+    engine.set(b"big", &[b'x'; 200]).unwrap();
+    engine.set(b"small", b"0123456789").unwrap();
+    engine.set(b"small", b"0123456789").unwrap();
+    assert_eq!(engine.stats().compactions, 1);
+}
+
+fn assert_threshold(engine: &Engine, threshold: u64, source: ThresholdSource) {
+    assert_eq!(
+        (engine.stats().compact_threshold, engine.threshold_source()),
+        (threshold, source)
+    );
+}
+
+#[test]
+fn test_threshold_precedence_across_restarts() {
+    let states: [(&str, u64, ThresholdSource); 4] = [
+        ("fresh", DEFAULT_COMPACT_THRESHOLD, ThresholdSource::Default),
+        ("operator", 777_777, ThresholdSource::Operator),
+        ("auto-tuned", 616, ThresholdSource::AutoTuned),
+        ("unmarked", 308, ThresholdSource::Unmarked),
+    ];
+    for (state, persisted, persisted_source) in states {
+        for option in [None, Some(5_000)] {
+            let file = NamedTempFile::new().unwrap();
+            let path = file.path().to_owned();
+            match state {
+                "operator" => Engine::load(&path)
+                    .unwrap()
+                    .set_compact_threshold(persisted)
+                    .unwrap(),
+                "auto-tuned" => {
+                    write_header_with_flags(&path, persisted, HEADER_FLAG_THRESHOLD_AUTO)
+                }
+                "unmarked" => write_header(&path, persisted),
+                _ => {}
+            }
+            let options = Options {
+                compact_threshold: option,
+                ..Options::default()
+            };
+            let (threshold, source) = match option {
+                Some(threshold) => (threshold, ThresholdSource::Options),
+                None => (persisted, persisted_source),
+            };
+
+            let engine = Engine::load_with_options(&path, options.clone()).unwrap();
+            assert_threshold(&engine, threshold, source);
+            engine.set(b"k", b"v").unwrap();
+            engine.reload().unwrap();
+            assert_threshold(&engine, threshold, source);
+            drop(engine);
+            // The option never reaches the header.
+            assert_eq!(read_threshold_from_file(&path), persisted, "{state}");
+
+            let engine = Engine::load_with_options(&path, options).unwrap();
+            assert_threshold(&engine, threshold, source);
+            drop(engine);
+            let engine = Engine::load(&path).unwrap();
+            assert_threshold(&engine, persisted, persisted_source);
+        }
+    }
+}
+
+#[test]
+fn test_pinned_thresholds_are_never_auto_tuned() {
+    for pinned in [ThresholdSource::Options, ThresholdSource::Operator] {
+        let file = NamedTempFile::new().unwrap();
+        let path = file.path().to_owned();
+        write_header(&path, 308);
+        let options = Options {
+            compact_threshold: (pinned == ThresholdSource::Options).then_some(308),
+            ..Options::default()
+        };
+        let engine = Engine::load_with_options(&path, options.clone()).unwrap();
+        if pinned == ThresholdSource::Operator {
+            engine.set_compact_threshold(308).unwrap();
+        }
+        barely_shrinking_compaction(&engine);
+        assert_threshold(&engine, 308, pinned);
+        drop(engine);
+
+        let engine = Engine::load_with_options(&path, options).unwrap();
+        assert_threshold(&engine, 308, pinned);
+        drop(engine);
+        // The option left the header's unmarked threshold as it was.
+        let expected = match pinned {
+            ThresholdSource::Options => ThresholdSource::Unmarked,
+            _ => pinned,
+        };
+        assert_threshold(&Engine::load(&path).unwrap(), 308, expected);
+    }
+}
+
+#[test]
+fn test_auto_tuned_threshold_is_marked_until_an_operator_sets_one() {
+    let file = NamedTempFile::new().unwrap();
+    let path = file.path().to_owned();
+    write_header(&path, 308);
+    let engine = Engine::load(&path).unwrap();
+    assert_threshold(&engine, 308, ThresholdSource::Unmarked);
+    barely_shrinking_compaction(&engine);
+    assert_threshold(&engine, 616, ThresholdSource::AutoTuned);
+    drop(engine);
+
+    let engine = Engine::load(&path).unwrap();
+    assert_threshold(&engine, 616, ThresholdSource::AutoTuned);
+    engine.set_compact_threshold(616).unwrap();
+    assert_threshold(&engine, 616, ThresholdSource::Operator);
+    drop(engine);
+    assert_threshold(
+        &Engine::load(&path).unwrap(),
+        616,
+        ThresholdSource::Operator,
+    );
+}
+
+#[test]
+fn test_load_with_threshold_writes_and_keeps_the_threshold() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("data.db");
+    {
+        let engine = Engine::load_with_threshold(&path, 4096).unwrap();
+        assert_eq!(engine.stats().compact_threshold, 4096);
+        assert_eq!(engine.threshold_source(), ThresholdSource::Operator);
+        engine.set(b"k", b"v").unwrap();
+    }
+    assert_eq!(read_threshold_from_file(&path), 4096);
+    let engine = Engine::load(&path).unwrap();
+    assert_eq!(engine.stats().compact_threshold, 4096);
+    assert_eq!(engine.threshold_source(), ThresholdSource::Operator);
+    drop(engine);
+
+    // An existing file's threshold is overridden, and stays overridden.
+    drop(Engine::load_with_threshold(&path, 8192).unwrap());
+    let engine = Engine::load(&path).unwrap();
+    assert_eq!(engine.stats().compact_threshold, 8192);
+    assert_eq!(engine.get(b"k").unwrap(), Some(b"v".to_vec()));
+    drop(engine);
+
+    let err = Engine::load_with_threshold(&path, 0).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    assert_eq!(read_threshold_from_file(&path), 8192);
+    let engine = Engine::load_with_threshold(&path, 1).unwrap();
+    assert_eq!(engine.stats().compact_threshold, FILE_HEADER_SIZE);
+
+    // A file the load refuses keeps the threshold it had.
+    let transformed = dir.path().join("transformed.db");
+    drop(engine_with(
+        &transformed,
+        Options {
+            key_transform: Some(KeyTransform::ascii_lowercase()),
+            ..Options::default()
+        },
+    ));
+    let err = Engine::load_with_threshold(&transformed, 4096).unwrap_err();
+    assert!(matches!(
+        EngineError::from_io(&err),
+        Some(EngineError::KeyTransformMismatch { .. })
+    ));
+    assert_eq!(
+        read_threshold_from_file(&transformed),
+        DEFAULT_COMPACT_THRESHOLD
+    );
+}
+
+#[test]
+fn test_load_with_max_threshold_never_auto_compacts() {
+    let file = NamedTempFile::new().unwrap();
+    let engine = Engine::load_with_threshold(file.path(), u64::MAX).unwrap();
+    let value = vec![7u8; 4096];
+    for round in 0..4 {
+        for i in 0..100u32 {
+            engine.set(&i.to_be_bytes(), &value).unwrap();
+        }
+        assert_eq!(
+            engine.get(&7u32.to_be_bytes()).unwrap(),
+            Some(value.clone()),
+            "{}",
+            round
+        );
+    }
+    let stats = engine.stats();
+    assert!(stats.file_size > DEFAULT_COMPACT_THRESHOLD);
+    assert_eq!(stats.compactions, 0);
+    assert_eq!(stats.compact_threshold, u64::MAX);
+}
+
+#[test]
+fn test_header_rewrites_never_share_a_handle_with_records() {
+    let storage = common::InstrumentedStorage::default();
+    let probe = Arc::clone(&storage.probe);
+    let file = NamedTempFile::new().unwrap();
+    let engine = Arc::new(
+        Engine::load_with_options(
+            file.path(),
+            Options {
+                storage: Arc::new(storage),
+                // Every append re-reads the header epoch.
+                fencing: Some(Duration::ZERO),
+                ..Options::default()
+            },
+        )
+        .unwrap(),
+    );
+    probe.start_op_log();
+
+    // Threshold rewrites race sets and gets, across a compaction and a
+    // reload that each replace the writer's handle.
+    let writers: Vec<_> = (0..4u32)
+        .map(|t| {
+            let engine = Arc::clone(&engine);
+            thread::spawn(move || {
+                for i in 0..200u32 {
+                    let key = format!("t{}-{}", t, i % 20);
+                    engine.set(key.as_bytes(), &i.to_le_bytes()).unwrap();
+                    assert_eq!(
+                        engine.get(key.as_bytes()).unwrap(),
+                        Some(i.to_le_bytes().to_vec())
+                    );
+                }
+            })
+        })
+        .collect();
+    for round in 0..20u64 {
+        engine.set_compact_threshold((1 << 20) + round).unwrap();
+        if round == 10 {
+            engine.compact().unwrap();
+        }
+        if round == 15 {
+            engine.reload().unwrap();
+        }
+    }
+    for writer in writers {
+        writer.join().unwrap();
+    }
+    engine.set_compact_threshold(1 << 21).unwrap();
+
+    let ops: Vec<_> = probe
+        .take_op_log()
+        .into_iter()
+        .filter(|op| op.path == file.path())
+        .collect();
+    let header_handles: BTreeSet<u64> = ops
+        .iter()
+        .filter(|op| op.write && op.pos < FILE_HEADER_SIZE)
+        .map(|op| op.handle)
+        .collect();
+    assert!(!header_handles.is_empty());
+    // A handle that rewrote the header never read or wrote a record.
+    for op in ops.iter().filter(|op| header_handles.contains(&op.handle)) {
+        assert!(op.pos + op.len <= FILE_HEADER_SIZE, "{:?}", op);
+    }
+    assert!(ops.iter().any(|op| !op.write && op.pos >= FILE_HEADER_SIZE));
+    assert!(ops.iter().any(|op| op.write && op.pos >= FILE_HEADER_SIZE));
+    assert_eq!(engine.stats().compact_threshold, 1 << 21);
+}
+
+// ==================== Reload ====================
+
+#[test]
+fn test_reload_picks_up_external_writes() {
+    let (engine, file) = temp_engine();
+    engine.set(b"a", b"1").unwrap();
+    engine.set(b"b", b"2").unwrap();
+
+    {
+        let other = Engine::load(file.path()).unwrap();
+        other.set(b"a", b"changed").unwrap();
+        other.del(b"b").unwrap();
+        other.set(b"c", b"3").unwrap();
+    }
+    assert_eq!(engine.get(b"c").unwrap(), None);
+
+    let report = engine.reload().unwrap();
+    assert_eq!(report.entries_scanned, 5);
+    assert_eq!(report.live_keys, 2);
+    assert_eq!(report.truncated_bytes, 0);
+    assert_eq!(engine.get(b"a").unwrap(), Some(b"changed".to_vec()));
+    assert_eq!(engine.get(b"b").unwrap(), None);
+    assert_eq!(engine.get(b"c").unwrap(), Some(b"3".to_vec()));
+
+    engine.set(b"d", b"4").unwrap();
+    drop(engine);
+    let reopened = Engine::load(file.path()).unwrap();
+    assert_eq!(reopened.get(b"c").unwrap(), Some(b"3".to_vec()));
+    assert_eq!(reopened.get(b"d").unwrap(), Some(b"4".to_vec()));
+}
+
+#[test]
+fn test_reload_after_file_replaced() {
+    let (engine, file) = temp_engine();
+    engine.set(b"old", b"x").unwrap();
+
+    let replacement = NamedTempFile::new().unwrap();
+    {
+        let other = Engine::load(replacement.path()).unwrap();
+        other.set(b"new", b"y").unwrap();
+    }
+    fs::copy(replacement.path(), file.path()).unwrap();
+
+    engine.reload().unwrap();
+    assert_eq!(engine.get(b"old").unwrap(), None);
+    assert_eq!(engine.get(b"new").unwrap(), Some(b"y".to_vec()));
+}
+
+#[test]
+fn test_reload_truncates_torn_tail() {
+    let file = NamedTempFile::new().unwrap();
+    let fixture = StoreFixture::builder().keys(10).build(file.path()).unwrap();
+    let engine = Engine::load(fixture.path()).unwrap();
+    assert_eq!(engine.stats().file_size, fixture.log_len());
+
+    Corruption::TornTail(vec![0xAB; 13])
+        .apply(fixture.path())
+        .unwrap();
+
+    let report = engine.reload().unwrap();
+    assert_eq!(report.truncated_bytes, 13);
+    assert_eq!(engine.stats().file_size, fixture.log_len());
+    assert_eq!(
+        fs::metadata(fixture.path()).unwrap().len(),
+        fixture.log_len()
+    );
+    assert_fixture_reads_back(&engine, &fixture);
+}
+
+#[test]
+fn test_reload_during_concurrent_reads() {
+    let file = NamedTempFile::new().unwrap();
+    let engine = Arc::new(Engine::load(file.path()).unwrap());
+    for i in 0..50 {
+        engine
+            .set(format!("k{}", i).as_bytes(), format!("v{}", i).as_bytes())
+            .unwrap();
+    }
+
+    let readers: Vec<_> = (0..4)
+        .map(|_| {
+            let engine = Arc::clone(&engine);
+            thread::spawn(move || {
+                for round in 0..200 {
+                    let i = round % 50;
+                    let value = engine.get(format!("k{}", i).as_bytes()).unwrap();
+                    assert_eq!(value, Some(format!("v{}", i).into_bytes()));
+                }
+            })
+        })
+        .collect();
+    for _ in 0..20 {
+        engine.reload().unwrap();
+    }
+    for handle in readers {
+        handle.join().unwrap();
+    }
+}
+
+// ==================== Multi-key Updates ====================
+
+fn balance(value: &Option<Vec<u8>>) -> u64 {
+    u64::from_le_bytes(value.as_deref().unwrap().try_into().unwrap())
+}
+
+#[test]
+fn test_update_many_concurrent_transfers_keep_total() {
+    const ACCOUNTS: u64 = 8;
+    let file = NamedTempFile::new().unwrap();
+    let engine = Arc::new(Engine::load(file.path()).unwrap());
+    let accounts: Vec<Vec<u8>> = (0..ACCOUNTS)
+        .map(|i| format!("acct{}", i).into_bytes())
+        .collect();
+    for account in &accounts {
+        engine.set(account, &1000u64.to_le_bytes()).unwrap();
+    }
+
+    let threads: Vec<_> = (0..4u64)
+        .map(|t| {
+            let engine = Arc::clone(&engine);
+            let accounts = accounts.clone();
+            thread::spawn(move || {
+                let mut rng = XorShift(t + 1);
+                for _ in 0..300 {
+                    let from = (rng.next() % ACCOUNTS) as usize;
+                    let to =
+                        (from + 1 + (rng.next() % (ACCOUNTS - 1)) as usize) % ACCOUNTS as usize;
+                    let amount = rng.next() % 50;
+                    engine
+                        .update_many(&[&accounts[from], &accounts[to]], |values| {
+                            let (a, b) = (balance(&values[0]), balance(&values[1]));
+                            let moved = amount.min(a);
+                            vec![
+                                Some((a - moved).to_le_bytes().to_vec()),
+                                Some((b + moved).to_le_bytes().to_vec()),
+                            ]
+                        })
+                        .unwrap();
+                }
+            })
+        })
+        .collect();
+    for handle in threads {
+        handle.join().unwrap();
+    }
+
+    let total: u64 = accounts
+        .iter()
+        .map(|account| balance(&engine.get(account).unwrap()))
+        .sum();
+    assert_eq!(total, 1000 * ACCOUNTS);
+}
+
+#[test]
+fn test_update_many_excludes_plain_writes() {
+    let file = NamedTempFile::new().unwrap();
+    let engine = Arc::new(Engine::load(file.path()).unwrap());
+    engine.set(b"counter", &0u64.to_le_bytes()).unwrap();
+
+    let threads: Vec<_> = (0..4)
+        .map(|t| {
+            let engine = Arc::clone(&engine);
+            thread::spawn(move || {
+                for _ in 0..200 {
+                    if t == 0 {
+                        engine.set(b"other", b"noise").unwrap();
+                        continue;
+                    }
+                    engine
+                        .update_many(&[b"counter"], |values| {
+                            vec![Some((balance(&values[0]) + 1).to_le_bytes().to_vec())]
+                        })
+                        .unwrap();
+                }
+            })
+        })
+        .collect();
+    for handle in threads {
+        handle.join().unwrap();
+    }
+
+    assert_eq!(balance(&engine.get(b"counter").unwrap()), 600);
+}
+
+#[test]
+fn test_update_many_deletes_and_persists() {
+    let (engine, file) = temp_engine();
+    engine.set(b"a", b"1").unwrap();
+    engine.set(b"b", b"2").unwrap();
+
+    engine
+        .update_many(&[b"a", b"b", b"c"], |values| {
+            assert_eq!(values, [Some(b"1".to_vec()), Some(b"2".to_vec()), None]);
+            vec![None, Some(b"22".to_vec()), Some(b"3".to_vec())]
+        })
+        .unwrap();
+
+    drop(engine);
+    let engine = Engine::load(file.path()).unwrap();
+    assert_eq!(engine.get(b"a").unwrap(), None);
+    assert_eq!(engine.get(b"b").unwrap(), Some(b"22".to_vec()));
+    assert_eq!(engine.get(b"c").unwrap(), Some(b"3".to_vec()));
+}
+
+#[test]
+fn test_update_many_rejects_wrong_value_count() {
+    let (engine, _file) = temp_engine();
+    engine.set(b"a", b"1").unwrap();
+    let size = engine.stats().file_size;
+
+    let err = engine
+        .update_many(&[b"a", b"b"], |_| vec![Some(b"x".to_vec())])
+        .unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    assert_eq!(engine.stats().file_size, size);
+    assert_eq!(engine.get(b"a").unwrap(), Some(b"1".to_vec()));
+
+    let err = engine
+        .update_many(&[b"a", b""], |v| v.to_vec())
+        .unwrap_err();
+    assert!(matches!(
+        EngineError::from_io(&err),
+        Some(EngineError::EmptyKey)
+    ));
+}
+
+// ==================== Sampling ====================
+
+fn sampled_keys(engine: &Engine, fraction: f64, seed: u64) -> Vec<Vec<u8>> {
+    engine
+        .sample(fraction, seed)
+        .unwrap()
+        .map(|record| record.unwrap().key)
+        .collect()
+}
+
+#[test]
+fn test_sample_is_deterministic_and_sized() {
+    let (engine, _file) = temp_engine();
+    for i in 0..5000 {
+        engine
+            .set(format!("key{}", i).as_bytes(), format!("v{}", i).as_bytes())
+            .unwrap();
+    }
+
+    let first = sampled_keys(&engine, 0.1, 42);
+    assert!((400..600).contains(&first.len()), "{} keys", first.len());
+    assert!(first.windows(2).all(|w| w[0] < w[1]));
+    assert_eq!(sampled_keys(&engine, 0.1, 42), first);
+    assert_ne!(sampled_keys(&engine, 0.1, 43), first);
+
+    assert!(sampled_keys(&engine, 0.0, 42).is_empty());
+    assert_eq!(sampled_keys(&engine, 1.0, 42).len(), 5000);
+    assert!(engine.sample(1.5, 42).is_err());
+}
+
+#[test]
+fn test_sample_picks_same_keys_across_stores() {
+    let (primary, _f1) = temp_engine();
+    let (replica, _f2) = temp_engine();
+    for i in 0..1000 {
+        let key = format!("user:{}", i);
+        primary.set(key.as_bytes(), b"primary").unwrap();
+        replica.set(key.as_bytes(), b"replica").unwrap();
+    }
+    replica.compact().unwrap();
+
+    assert_eq!(
+        sampled_keys(&primary, 0.05, 7),
+        sampled_keys(&replica, 0.05, 7)
+    );
+}
+
+#[test]
+fn test_sample_max_bytes_skips_oversized_values() {
+    let (engine, _file) = temp_engine();
+    engine.set(b"a", &[b'x'; 10]).unwrap();
+    engine.set(b"b", &vec![b'y'; 10_000]).unwrap();
+    engine.set(b"c", &[b'z'; 10]).unwrap();
+
+    let mut sample = engine.sample(1.0, 0).unwrap().max_bytes(100);
+    let keys: Vec<Vec<u8>> = sample.by_ref().map(|r| r.unwrap().key).collect();
+    assert_eq!(keys, [b"a".to_vec(), b"c".to_vec()]);
+    assert_eq!(sample.sampled_bytes(), 22);
+}
+
+// ==================== Fail-closed Corruption ====================
+
+/// Flips the kind tag of `key`'s record in `fixture`; applying it again
+/// restores the tag.
+fn flip_kind(fixture: &StoreFixture, key: &[u8]) -> Corruption {
+    Corruption::FlipByte(fixture.kind_offset(key).unwrap())
+}
+
+fn is_store_corrupted(err: &std::io::Error) -> bool {
+    matches!(
+        EngineError::from_io(err),
+        Some(EngineError::StoreCorrupted(_))
+    )
+}
+
+#[test]
+fn test_fail_closed_refuses_writes_until_verify() {
+    let file = NamedTempFile::new().unwrap();
+    let fixture = StoreFixture::builder()
+        .keys(2)
+        .plain()
+        .build(file.path())
+        .unwrap();
+    let engine = engine_with(
+        fixture.path(),
+        Options {
+            fail_closed: true,
+            ..Options::default()
+        },
+    );
+    let (a, k) = (StoreFixture::key(0), StoreFixture::key(1));
+
+    let flip = flip_kind(&fixture, &k);
+    flip.apply(fixture.path()).unwrap();
+    let err = engine.get(&k).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    assert!(engine.corruption().is_some());
+
+    assert!(is_store_corrupted(&engine.set(b"b", b"2").unwrap_err()));
+    assert!(is_store_corrupted(&engine.del(&a).unwrap_err()));
+    assert!(is_store_corrupted(&engine.compact().unwrap_err()));
+    assert_eq!(engine.get(&a).unwrap().as_ref(), fixture.live().get(&a));
+
+    assert!(is_store_corrupted(&engine.verify().unwrap_err()));
+    assert!(engine.set(b"b", b"2").is_err());
+
+    flip.apply(fixture.path()).unwrap();
+    assert_eq!(engine.verify().unwrap(), 2);
+    assert_eq!(engine.corruption(), None);
+    engine.set(b"b", b"2").unwrap();
+    assert_fixture_reads_back(&engine, &fixture);
+}
+
+#[test]
+fn test_acknowledge_corruption_reallows_writes() {
+    let file = NamedTempFile::new().unwrap();
+    let fixture = StoreFixture::builder().keys(5).build(file.path()).unwrap();
+    let engine = engine_with(
+        fixture.path(),
+        Options {
+            fail_closed: true,
+            ..Options::default()
+        },
+    );
+    flip_kind(&fixture, &StoreFixture::key(3))
+        .apply(fixture.path())
+        .unwrap();
+
+    assert!(is_store_corrupted(&engine.verify().unwrap_err()));
+    assert!(engine.set(b"x", b"1").is_err());
+
+    assert!(engine.acknowledge_corruption().is_some());
+    engine.set(b"x", b"1").unwrap();
+    assert_eq!(engine.get(b"x").unwrap(), Some(b"1".to_vec()));
+}
+
+#[test]
+fn test_corruption_recorded_without_fail_closed() {
+    let file = NamedTempFile::new().unwrap();
+    let fixture = StoreFixture::builder().keys(5).build(file.path()).unwrap();
+    let engine = Engine::load(fixture.path()).unwrap();
+    let key = StoreFixture::key(3);
+    flip_kind(&fixture, &key).apply(fixture.path()).unwrap();
+
+    assert!(engine.get(&key).is_err());
+    assert!(engine.corruption().is_some());
+    engine.set(b"x", b"1").unwrap();
+}
+
+// ==================== Compaction Scratch Dir ====================
+
+fn fill_with_garbage(engine: &Engine) {
+    for round in 0..5 {
+        for i in 0..50 {
+            engine
+                .set(
+                    format!("k{}", i).as_bytes(),
+                    format!("v{}-{}", i, round).as_bytes(),
+                )
+                .unwrap();
+        }
+    }
+}
+
+fn assert_compacted_contents(engine: &Engine) {
+    for i in 0..50 {
+        assert_eq!(
+            engine.get(format!("k{}", i).as_bytes()).unwrap(),
+            Some(format!("v{}-4", i).into_bytes())
+        );
+    }
+}
+
+#[test]
+fn test_compaction_dir_same_filesystem() {
+    let dir = tempfile::tempdir().unwrap();
+    let scratch = dir.path().join("scratch");
+    let storage = common::InstrumentedStorage::default();
+    let engine = engine_with(
+        dir.path().join("data.db"),
+        Options {
+            compaction_dir: Some(scratch.clone()),
+            storage: Arc::new(storage.clone()),
+            ..Options::default()
+        },
+    );
+    fill_with_garbage(&engine);
+
+    let before = engine.stats().file_size;
+    engine.compact().unwrap();
+    assert!(engine.stats().file_size < before);
+    assert_eq!(storage.probe.clones.load(Ordering::SeqCst), 0);
+    assert_eq!(fs::read_dir(&scratch).unwrap().count(), 0);
+    assert!(!dir.path().join("data.tmp").exists());
+    assert_compacted_contents(&engine);
+
+    drop(engine);
+    let engine = engine_with(
+        dir.path().join("data.db"),
+        Options {
+            compaction_dir: Some(scratch.clone()),
+            ..Options::default()
+        },
+    );
+    assert_compacted_contents(&engine);
+}
+
+#[test]
+fn test_compaction_dir_cross_filesystem_copies() {
+    let dir = tempfile::tempdir().unwrap();
+    let scratch = tempfile::tempdir().unwrap();
+    let storage = common::InstrumentedStorage::default();
+    let engine = engine_with(
+        dir.path().join("data.db"),
+        Options {
+            compaction_dir: Some(scratch.path().to_path_buf()),
+            storage: Arc::new(storage.clone()),
+            ..Options::default()
+        },
+    );
+    storage.probe.cross_device.store(true, Ordering::SeqCst);
+    fill_with_garbage(&engine);
+
+    engine.compact().unwrap();
+    assert_eq!(storage.probe.clones.load(Ordering::SeqCst), 1);
+    assert_eq!(fs::read_dir(scratch.path()).unwrap().count(), 0);
+    assert!(!dir.path().join("data.tmp").exists());
+    assert_compacted_contents(&engine);
+
+    drop(engine);
+    let engine = engine_with(
+        dir.path().join("data.db"),
+        Options {
+            compaction_dir: Some(scratch.path().to_path_buf()),
+            ..Options::default()
+        },
+    );
+    assert_compacted_contents(&engine);
+}
+
+#[test]
+fn test_compaction_refused_without_scratch_space() {
+    let dir = tempfile::tempdir().unwrap();
+    let scratch = dir.path().join("scratch");
+    let storage = common::InstrumentedStorage::default();
+    let engine = engine_with(
+        dir.path().join("data.db"),
+        Options {
+            compaction_dir: Some(scratch.clone()),
+            storage: Arc::new(storage.clone()),
+            ..Options::default()
+        },
+    );
+    fill_with_garbage(&engine);
+    let size = engine.stats().file_size;
+
+    storage.probe.set_free_bytes(Some(16));
+    let err = engine.compact().unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::StorageFull);
+    assert_eq!(engine.stats().file_size, size);
+    assert_eq!(engine.stats().compactions, 0);
+    assert_compacted_contents(&engine);
+
+    // Auto-compaction is postponed rather than failing the write.
+    engine.set_compact_threshold(FILE_HEADER_SIZE).unwrap();
+    engine.set(b"k0", b"v0-4").unwrap();
+    assert_eq!(engine.stats().compactions, 0);
+
+    storage.probe.set_free_bytes(None);
+    engine.compact().unwrap();
+    assert_eq!(engine.stats().compactions, 1);
+}
+
+// ==================== Slow Operations ====================
+
+#[test]
+fn test_slow_get_reports_detail() {
+    let file = NamedTempFile::new().unwrap();
+    let storage = common::InstrumentedStorage::default();
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let sink = Arc::clone(&seen);
+    let engine = engine_with(
+        file.path(),
+        Options {
+            slow_op_threshold: Some(Duration::from_millis(20)),
+            on_slow_op: Some(Hook::new(move |op: &SlowOp| {
+                sink.lock().unwrap().push(op.clone())
+            })),
+            reader_pool_size: 0,
+            storage: Arc::new(storage.clone()),
+            ..Options::default()
+        },
+    );
+    engine.set(b"slow-key", b"value").unwrap();
+    assert!(seen.lock().unwrap().is_empty());
+
+    storage.probe.set_read_delay(Duration::from_millis(30));
+    engine.get(b"slow-key").unwrap();
+
+    let seen = seen.lock().unwrap();
+    assert_eq!(seen.len(), 1);
+    let op = &seen[0];
+    assert_eq!(op.op, SlowOpKind::Get);
+    assert_eq!(op.key_len, 8);
+    assert!(op.duration >= Duration::from_millis(20));
+    assert!(op.detail.opened_reader);
+    // One-byte varint length prefix in front of the first record.
+    assert_eq!(op.detail.offset, Some(FILE_HEADER_SIZE + 1));
+    assert!(!op.detail.auto_compaction);
+}
+
+#[test]
+fn test_slow_set_reports_auto_compaction() {
+    let file = NamedTempFile::new().unwrap();
+    let storage = common::InstrumentedStorage::default();
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let sink = Arc::clone(&seen);
+    let engine = engine_with(
+        file.path(),
+        Options {
+            slow_op_threshold: Some(Duration::from_millis(20)),
+            on_slow_op: Some(Hook::new(move |op: &SlowOp| {
+                sink.lock().unwrap().push(op.clone())
+            })),
+            reader_pool_size: 0,
+            storage: Arc::new(storage.clone()),
+            ..Options::default()
+        },
+    );
+    for i in 0..5 {
+        engine.set(b"k", format!("v{}", i).as_bytes()).unwrap();
+    }
+    engine.set_compact_threshold(FILE_HEADER_SIZE).unwrap();
+
+    storage.probe.set_read_delay(Duration::from_millis(30));
+    engine.set(b"k", b"last").unwrap();
+
+    let seen = seen.lock().unwrap();
+    assert_eq!(seen.len(), 1);
+    assert_eq!(seen[0].op, SlowOpKind::Set);
+    assert!(seen[0].detail.auto_compaction);
+    assert_eq!(engine.stats().compactions, 1);
+}
+
+#[test]
+fn test_slow_write_reports_file_lock_wait() {
+    let file = NamedTempFile::new().unwrap();
+    let storage = common::InstrumentedStorage::default();
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let sink = Arc::clone(&seen);
+    let engine = engine_with(
+        file.path(),
+        Options {
+            slow_op_threshold: Some(Duration::from_millis(20)),
+            on_slow_op: Some(Hook::new(move |op: &SlowOp| {
+                sink.lock().unwrap().push(op.clone())
+            })),
+            reader_pool_size: 0,
+            storage: Arc::new(storage.clone()),
+            ..Options::default()
+        },
+    );
+    let engine = Arc::new(engine);
+    for round in 0..2 {
+        for i in 0..20 {
+            engine.set(format!("k{}", i).as_bytes(), &[round]).unwrap();
+        }
+    }
+
+    // Every key has a stale copy, so the compaction copies record by record
+    // (rather than cloning a packed file) through slow storage, holding the
+    // file mutex.
+    storage.probe.set_read_delay(Duration::from_millis(5));
+    let compactor = {
+        let engine = Arc::clone(&engine);
+        thread::spawn(move || engine.compact().unwrap())
+    };
+    thread::sleep(Duration::from_millis(20));
+    engine.del(b"k0").unwrap();
+    compactor.join().unwrap();
+
+    let seen = seen.lock().unwrap();
+    let del = seen.iter().find(|op| op.op == SlowOpKind::Del).unwrap();
+    assert!(del.detail.file_lock_wait >= Duration::from_millis(10));
+}
+
+// ==================== Write Batches ====================
+
+#[test]
+fn test_batch_applies_mixed_operations() {
+    let (engine, file) = temp_engine();
+    engine.set(b"gone", b"x").unwrap();
+
+    let mut batch = WriteBatch::new();
+    let empty_size = batch.encoded_size();
+    batch
+        .put(b"a", b"1")
+        .put_with_ttl(b"b", b"2", Duration::from_secs(3600))
+        .delete(b"gone");
+    assert_eq!(batch.len(), 3);
+    assert!(batch.encoded_size() > empty_size);
+
+    let before = engine.stats().file_size;
+    engine.apply_batch(&batch).unwrap();
+    assert_eq!(
+        engine.stats().file_size - before,
+        batch.encoded_size() as u64
+    );
+    assert_eq!(engine.get(b"a").unwrap(), Some(b"1".to_vec()));
+    assert_eq!(engine.get(b"b").unwrap(), Some(b"2".to_vec()));
+    assert_eq!(engine.get_range(b"b", 0, 10).unwrap(), Some(b"2".to_vec()));
+    assert_eq!(engine.get(b"gone").unwrap(), None);
+
+    batch.clear();
+    assert!(batch.is_empty());
+    assert_eq!(batch.encoded_size(), empty_size);
+    batch.put(b"a", b"again");
+    engine.apply_batch(&batch).unwrap();
+
+    drop(engine);
+    let engine = Engine::load(file.path()).unwrap();
+    assert_eq!(engine.get(b"a").unwrap(), Some(b"again".to_vec()));
+    assert_eq!(engine.get(b"b").unwrap(), Some(b"2".to_vec()));
+    assert_eq!(engine.get(b"gone").unwrap(), None);
+}
+
+#[test]
+fn test_batch_rejects_empty_keys_and_ignores_empty_batch() {
+    let (engine, _file) = temp_engine();
+    let size = engine.stats().file_size;
+    engine.apply_batch(&WriteBatch::new()).unwrap();
+
+    let mut batch = WriteBatch::new();
+    batch.put(b"ok", b"1").put(b"", b"2");
+    let err = engine.apply_batch(&batch).unwrap_err();
+    assert!(matches!(
+        EngineError::from_io(&err),
+        Some(EngineError::EmptyKey)
+    ));
+    assert_eq!(engine.stats().file_size, size);
+    assert_eq!(engine.get(b"ok").unwrap(), None);
+}
+
+#[test]
+fn test_batch_indexed_returns_a_reference_per_op_and_appends_once() {
+    let (engine, _file) = temp_engine();
+    engine.set(b"gone", b"x").unwrap();
+
+    let mut batch = WriteBatch::new();
+    for i in 0..1000 {
+        batch.put(format!("k{}", i).as_bytes(), b"v");
+    }
+    batch.delete(b"gone");
+    let appends = engine.stats().appends;
+    let refs = engine.apply_batch_indexed(&batch).unwrap();
+    assert_eq!(engine.stats().appends, appends + 1);
+
+    assert_eq!(refs.len(), 1001);
+    let entry = engine.get_at_index(&refs[999]).unwrap();
+    assert_eq!(entry.key, b"k999");
+    assert_eq!(entry.kind, EntryKind::Put);
+    let entry = engine.get_at_index(&refs[1000]).unwrap();
+    assert_eq!(entry.key, b"gone");
+    assert_eq!(entry.kind, EntryKind::Tombstone);
+
+    let appends = engine.stats().appends;
+    for i in 0..10 {
+        engine.set(format!("k{}", i).as_bytes(), b"w").unwrap();
+    }
+    assert_eq!(engine.stats().appends, appends + 10);
+    assert!(
+        engine
+            .apply_batch_indexed(&WriteBatch::new())
+            .unwrap()
+            .is_empty()
+    );
+}
+
+#[test]
+fn test_batch_ttl_expires_and_compacts_away() {
+    let (engine, file) = temp_engine();
+    let mut batch = WriteBatch::new();
+    batch
+        .put_with_ttl(b"short", b"v", Duration::from_millis(50))
+        .put_with_ttl(b"long", b"v", Duration::from_secs(3600))
+        .put_with_ttl(b"reset", b"v", Duration::from_millis(50));
+    engine.apply_batch(&batch).unwrap();
+    engine.set(b"reset", b"plain").unwrap();
+    assert_eq!(engine.get(b"short").unwrap(), Some(b"v".to_vec()));
+
+    thread::sleep(Duration::from_millis(80));
+    assert_eq!(engine.get(b"short").unwrap(), None);
+    assert_eq!(engine.get_range(b"short", 0, 1).unwrap(), None);
+    assert_eq!(engine.get(b"long").unwrap(), Some(b"v".to_vec()));
+    assert_eq!(engine.get(b"reset").unwrap(), Some(b"plain".to_vec()));
+
+    engine.compact().unwrap();
+    assert_eq!(engine.stats().live_keys, 2);
+    drop(engine);
+    let engine = Engine::load(file.path()).unwrap();
+    assert_eq!(engine.get(b"short").unwrap(), None);
+    assert_eq!(engine.get(b"long").unwrap(), Some(b"v".to_vec()));
+}
+
+#[test]
+fn test_batch_cut_in_half_is_not_applied() {
+    let (engine, file) = temp_engine();
+    engine.set(b"before", b"1").unwrap();
+    engine.set(b"k0", b"old").unwrap();
+    let size_before = engine.stats().file_size;
+
+    let mut batch = WriteBatch::new();
+    for i in 0..10 {
+        batch.put(format!("k{}", i).as_bytes(), b"new");
+    }
+    engine.apply_batch(&batch).unwrap();
+    let size_after = engine.stats().file_size;
+    drop(engine);
+
+    // Simulate a crash halfway through the append, once leaving a short
+    // file and once leaving zeroed pre-allocated space behind.
+    let cut = size_before + (size_after - size_before) / 2;
+    for zero_fill in [false, true] {
+        let copy = NamedTempFile::new().unwrap();
+        let mut data = fs::read(file.path()).unwrap();
+        data.truncate(cut as usize);
+        if zero_fill {
+            data.resize(size_after as usize, 0);
+        }
+        fs::write(copy.path(), &data).unwrap();
+
+        let engine = Engine::load(copy.path()).unwrap();
+        assert_eq!(engine.stats().file_size, size_before);
+        assert_eq!(engine.get(b"before").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(engine.get(b"k0").unwrap(), Some(b"old".to_vec()));
+        for i in 1..10 {
+            assert_eq!(engine.get(format!("k{}", i).as_bytes()).unwrap(), None);
+        }
+    }
+
+    let engine = Engine::load(file.path()).unwrap();
+    for i in 0..10 {
+        assert_eq!(
+            engine.get(format!("k{}", i).as_bytes()).unwrap(),
+            Some(b"new".to_vec())
+        );
+    }
+}
+
+#[test]
+fn test_batch_with_repeated_keys_matches_ops_applied_one_by_one() {
+    let (collapsing, collapsing_file) = temp_engine();
+    let (every_op, _f2) = temp_engine();
+    let (one_by_one, _f3) = temp_engine();
+    let mut rng = XorShift(0xBA7C);
+
+    for _ in 0..50 {
+        let mut batch = WriteBatch::new();
+        let mut last_op = HashMap::new();
+        for i in 0..20 {
+            let key = format!("k{}", rng.next() % 6).into_bytes();
+            match rng.next() % 3 {
+                0 => {
+                    batch.delete(&key);
+                    one_by_one.del(&key).unwrap();
+                }
+                _ => {
+                    let value = rng.bytes(32);
+                    batch.put(&key, &value);
+                    one_by_one.set(&key, &value).unwrap();
+                }
+            }
+            last_op.insert(key, i);
+        }
+
+        let refs = collapsing.apply_batch_indexed(&batch).unwrap();
+        assert_eq!(refs.len(), 20);
+        // A superseded op reports the record that was written in its place.
+        for (key, &last) in &last_op {
+            let entry = collapsing.get_at_index(&refs[last]).unwrap();
+            assert_eq!(&entry.key, key);
+        }
+        assert_eq!(
+            refs.iter()
+                .map(|r| r.index.pos)
+                .collect::<BTreeSet<_>>()
+                .len(),
+            last_op.len()
+        );
+
+        every_op.apply_batch(batch.dedup(false)).unwrap();
+        let expected = one_by_one.scan_prefix(b"").unwrap();
+        assert_eq!(collapsing.scan_prefix(b"").unwrap(), expected);
+        assert_eq!(every_op.scan_prefix(b"").unwrap(), expected);
+    }
+    assert!(collapsing.stats().file_size < every_op.stats().file_size);
+
+    let expected = one_by_one.scan_prefix(b"").unwrap();
+    drop(collapsing);
+    let reloaded = Engine::load(collapsing_file.path()).unwrap();
+    assert_eq!(reloaded.scan_prefix(b"").unwrap(), expected);
+}
+
+#[test]
+fn test_batch_writes_only_the_last_op_per_key_unless_told_otherwise() {
+    let options = Options {
+        audit_mode: true,
+        ..Options::default()
+    };
+    let file = NamedTempFile::new().unwrap();
+    let engine = Engine::load_with_options(file.path(), options).unwrap();
+
+    let mut batch = WriteBatch::new();
+    batch.put(b"k", b"v1").delete(b"k").put(b"k", b"v2");
+    engine.apply_batch(&batch).unwrap();
+    assert_eq!(engine.get(b"k").unwrap(), Some(b"v2".to_vec()));
+    let kinds = |engine: &Engine| -> Vec<EntryKind> {
+        engine
+            .audit_scan()
+            .unwrap()
+            .map(|record| record.unwrap().entry.kind)
+            .collect()
+    };
+    assert_eq!(kinds(&engine), [EntryKind::BatchBegin, EntryKind::Put]);
+
+    batch.dedup(false);
+    engine.apply_batch(&batch).unwrap();
+    assert_eq!(engine.get(b"k").unwrap(), Some(b"v2".to_vec()));
+    assert_eq!(
+        kinds(&engine)[2..],
+        [
+            EntryKind::BatchBegin,
+            EntryKind::Put,
+            EntryKind::Tombstone,
+            EntryKind::Put
+        ]
+    );
+
+    batch.clear();
+    batch.put(b"k", b"v3").delete(b"k");
+    batch.dedup(true);
+    engine.apply_batch(&batch).unwrap();
+    assert_eq!(engine.get(b"k").unwrap(), None);
+    assert_eq!(kinds(&engine).last(), Some(&EntryKind::Tombstone));
+}
+
+// ==================== TTL Jitter ====================
+
+const JITTER_TTL: Duration = Duration::from_secs(1000);
+
+/// Each TTL'd key's expiry offset from its unjittered expiry, in ms, as
+/// written to the log by the latest write of the key.
+fn expiry_offsets(engine: &Engine) -> HashMap<Vec<u8>, i64> {
+    engine
+        .audit_scan()
+        .unwrap()
+        .map(Result::unwrap)
+        .filter(|record| record.entry.kind == EntryKind::ExpiringPut)
+        .map(|record| {
+            let at = record.entry.expires_at().unwrap();
+            let offset = at - record.entry.tstamp - JITTER_TTL.as_millis() as i64;
+            (record.entry.key, offset)
+        })
+        .collect()
+}
+
+fn put_sessions(engine: &Engine) {
+    let mut batch = WriteBatch::new();
+    for i in 0..200 {
+        batch.put_with_ttl(format!("session-{i}").as_bytes(), b"s", JITTER_TTL);
+    }
+    engine.apply_batch(&batch).unwrap();
+}
+
+#[test]
+fn test_ttl_jitter_spreads_expiries_within_bounds() {
+    let dir = tempfile::tempdir().unwrap();
+    let engine = engine_with(
+        dir.path().join("data.db"),
+        Options {
+            ttl_jitter: 0.05,
+            ..Options::default()
+        },
+    );
+    put_sessions(&engine);
+
+    let bound = (JITTER_TTL.as_millis() / 20) as i64;
+    let offsets = expiry_offsets(&engine);
+    assert_eq!(offsets.len(), 200);
+    assert!(offsets.values().all(|offset| offset.abs() <= bound));
+    let min = *offsets.values().min().unwrap();
+    let max = *offsets.values().max().unwrap();
+    assert!(max - min > bound, "offsets span only {min}..{max}");
+
+    for (key, offset) in &offsets {
+        let ttl = engine.ttl(key).unwrap().unwrap().as_millis() as i64;
+        let expected = JITTER_TTL.as_millis() as i64 + offset;
+        assert!(
+            ttl <= expected && ttl > expected - 60_000,
+            "{ttl} vs {expected}"
+        );
+    }
+}
+
+#[test]
+fn test_ttl_jitter_is_the_same_for_every_write_of_a_key() {
+    let dir = tempfile::tempdir().unwrap();
+    let engine = engine_with(
+        dir.path().join("a.db"),
+        Options {
+            ttl_jitter: 0.05,
+            ..Options::default()
+        },
+    );
+    put_sessions(&engine);
+    let first = expiry_offsets(&engine);
+    put_sessions(&engine);
+    assert_eq!(expiry_offsets(&engine), first);
+
+    // Another store, in another load, picks the same offsets.
+    let other = engine_with(
+        dir.path().join("b.db"),
+        Options {
+            ttl_jitter: 0.05,
+            ..Options::default()
+        },
+    );
+    put_sessions(&other);
+    assert_eq!(expiry_offsets(&other), first);
+
+    // An unjittered store writes the TTL as given.
+    let plain = Engine::load(dir.path().join("c.db")).unwrap();
+    put_sessions(&plain);
+    assert!(expiry_offsets(&plain).values().all(|&offset| offset == 0));
+
+    // A plain put still clears the expiry entirely.
+    engine.set(b"session-7", b"kept").unwrap();
+    assert_eq!(engine.ttl(b"session-7").unwrap(), None);
+    assert_eq!(engine.ttl(b"absent").unwrap(), None);
+    assert!(engine.ttl(b"session-8").unwrap().is_some());
+
+    let options = Options {
+        ttl_jitter: 1.5,
+        ..Options::default()
+    };
+    let err = Engine::load_with_options(dir.path().join("d.db"), options).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+}
+
+// ==================== Key Stat ====================
+
+/// `engine.stat(key)`, checking that it read nothing from the data file.
+fn stat_without_reads(engine: &Engine, key: &[u8]) -> Option<KeyStat> {
+    let reads = engine.stats().disk_reads;
+    let stat = engine.stat(key).unwrap();
+    assert_eq!(engine.stats().disk_reads, reads);
+    stat
+}
+
+#[test]
+fn test_stat_follows_overwrites_and_deletes() {
+    let (engine, _f) = temp_engine();
+    assert_eq!(stat_without_reads(&engine, b"k"), None);
+
+    engine.set_with_tstamp(b"k", b"hello", 1_000).unwrap();
+    let first = stat_without_reads(&engine, b"k").unwrap();
+    assert_eq!(
+        (first.value_len, first.tstamp, first.expires_at),
+        (5, 1_000, None)
+    );
+    assert_eq!(engine.get_at_index(&first.record).unwrap().key, b"k");
+
+    engine.set_with_tstamp(b"k", &[7; 300], 2_000).unwrap();
+    let second = stat_without_reads(&engine, b"k").unwrap();
+    assert_eq!((second.value_len, second.tstamp), (300, 2_000));
+    assert_ne!(second.record, first.record);
+
+    engine.set(b"empty", b"").unwrap();
+    assert_eq!(stat_without_reads(&engine, b"empty").unwrap().value_len, 0);
+
+    engine.del(b"k").unwrap();
+    assert_eq!(stat_without_reads(&engine, b"k"), None);
+    assert_engine_error(
+        engine.stat(&[0xFF, 0]).unwrap_err(),
+        EngineError::ReservedKey,
+    );
+}
+
+#[test]
+fn test_stat_reports_expiry_and_hides_expired_keys() {
+    let (engine, _f) = temp_engine();
+    let before = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as i64;
+    let mut batch = WriteBatch::new();
+    batch
+        .put_with_ttl(b"short", b"v", Duration::from_millis(50))
+        .put_with_ttl(b"long", b"value", Duration::from_secs(3600));
+    engine.apply_batch(&batch).unwrap();
+
+    let long = stat_without_reads(&engine, b"long").unwrap();
+    assert_eq!(long.value_len, 5);
+    assert!(long.tstamp >= before);
+    assert!(long.expires_at.unwrap() >= before + 3_600_000);
+    assert!(stat_without_reads(&engine, b"short").is_some());
+
+    thread::sleep(Duration::from_millis(80));
+    assert_eq!(stat_without_reads(&engine, b"short"), None);
+    engine.set(b"long", b"plain").unwrap();
+    assert_eq!(
+        stat_without_reads(&engine, b"long").unwrap().expires_at,
+        None
+    );
+}
+
+#[test]
+fn test_stat_survives_compaction_and_reload() {
+    let (engine, file) = temp_engine();
+    engine.set_with_tstamp(b"k", &[1; 100], 1_000).unwrap();
+    engine.set_with_tstamp(b"k", &[2; 40], 2_000).unwrap();
+    let mut batch = WriteBatch::new();
+    batch.put_with_ttl(b"ttl", b"abc", Duration::from_secs(3600));
+    engine.apply_batch(&batch).unwrap();
+    let before = (
+        stat_without_reads(&engine, b"k").unwrap(),
+        stat_without_reads(&engine, b"ttl").unwrap(),
+    );
+    let unmoved = |stat: &KeyStat| (stat.value_len, stat.tstamp, stat.expires_at);
+
+    engine.compact().unwrap();
+    let compacted = stat_without_reads(&engine, b"k").unwrap();
+    assert_eq!(unmoved(&compacted), (40, 2_000, None));
+    assert_ne!(compacted.record.generation, before.0.record.generation);
+    assert_eq!(
+        unmoved(&stat_without_reads(&engine, b"ttl").unwrap()),
+        unmoved(&before.1)
+    );
+    drop(engine);
+
+    for mode in [OpenMode::Standard, OpenMode::Fast] {
+        let options = Options {
+            open_mode: mode,
+            ..Options::default()
+        };
+        let engine = Engine::load_with_options(file.path(), options).unwrap();
+        assert_eq!(
+            unmoved(&stat_without_reads(&engine, b"k").unwrap()),
+            unmoved(&before.0)
+        );
+        assert_eq!(
+            unmoved(&stat_without_reads(&engine, b"ttl").unwrap()),
+            unmoved(&before.1)
+        );
+    }
+}
+
+// ==================== Contains Key ====================
+
+#[test]
+fn test_contains_key_answers_like_get_without_reading() {
+    let (engine, _f) = temp_engine();
+    engine.set(b"k", b"v").unwrap();
+    engine.set(b"empty", b"").unwrap();
+    engine.set(b"gone", b"v").unwrap();
+    engine.del(b"gone").unwrap();
+    let mut batch = WriteBatch::new();
+    batch.put_with_ttl(b"short", b"v", Duration::from_millis(50));
+    engine.apply_batch(&batch).unwrap();
+
+    let reads = engine.stats().disk_reads;
+    assert!(engine.contains_key(b"k").unwrap());
+    assert!(engine.contains_key(b"empty").unwrap());
+    assert!(engine.contains_key(b"short").unwrap());
+    assert!(!engine.contains_key(b"gone").unwrap());
+    assert!(!engine.contains_key(b"never").unwrap());
+    assert_eq!(engine.stats().disk_reads, reads);
+
+    thread::sleep(Duration::from_millis(80));
+    assert!(!engine.contains_key(b"short").unwrap());
+    assert_eq!(engine.get(b"short").unwrap(), None);
+    assert_engine_error(
+        engine.contains_key(&[0xFF, 0]).unwrap_err(),
+        EngineError::ReservedKey,
+    );
+    engine.close().unwrap();
+    assert!(engine.contains_key(b"k").is_err());
+}
+
+#[test]
+fn test_contains_key_agrees_with_get_under_concurrent_writers() {
+    const KEYS: u32 = 2_000;
+    let (engine, _f) = temp_engine();
+    let engine = Arc::new(engine);
+    let key = |i: u32| format!("key{:05}", i).into_bytes();
+
+    // While keys are only being set, one seen by the earlier of two reads
+    // must be seen by the later; while they are only being deleted, one
+    // seen by the later must have been seen by the earlier. Each pair is
+    // read both ways round.
+    for adding in [true, false] {
+        let writer = {
+            let engine = Arc::clone(&engine);
+            thread::spawn(move || {
+                for i in 0..KEYS {
+                    if adding {
+                        engine.set(&key(i), b"v").unwrap();
+                    } else {
+                        engine.del(&key(i)).unwrap();
+                    }
+                }
+            })
+        };
+        let mut rng = XorShift(7);
+        let mut pairs = 0;
+        while !writer.is_finished() || pairs == 0 {
+            let k = key(rng.next() as u32 % KEYS);
+            let contains = || engine.contains_key(&k).unwrap();
+            let gets = || engine.get(&k).unwrap().is_some();
+            for (earlier, later) in [(contains(), gets()), (gets(), contains())] {
+                let consistent = if adding {
+                    !earlier || later
+                } else {
+                    !later || earlier
+                };
+                assert!(
+                    consistent,
+                    "{} read {:?} then {:?}",
+                    k.escape_ascii(),
+                    earlier,
+                    later
+                );
+            }
+            pairs += 1;
+        }
+        writer.join().unwrap();
+    }
+    assert!((0..KEYS).all(|i| !engine.contains_key(&key(i)).unwrap()));
+}
+
+// ==================== Key Count ====================
+
+#[test]
+fn test_len_follows_deletes_overwrites_reload_and_compaction() {
+    let (engine, file) = temp_engine();
+    assert!(engine.is_empty());
+    for i in 0..10 {
+        engine.set(format!("k{}", i).as_bytes(), b"v").unwrap();
+    }
+    assert_eq!(engine.len(), 10);
+
+    engine.set(b"k0", b"overwritten").unwrap();
+    assert_eq!(engine.len(), 10);
+    engine.del(b"k1").unwrap();
+    engine.del(b"k1").unwrap();
+    engine.del(b"never").unwrap();
+    assert_eq!(engine.len(), 9);
+
+    engine.compact().unwrap();
+    assert_eq!(engine.len(), 9);
+    assert_eq!(engine.len(), engine.stats().live_keys);
+    drop(engine);
+
+    let engine = Engine::load(file.path()).unwrap();
+    assert_eq!(engine.len(), 9);
+    for i in 0..10 {
+        engine.del(format!("k{}", i).as_bytes()).unwrap();
+    }
+    assert!(engine.is_empty());
+    engine.compact().unwrap();
+    drop(engine);
+    assert!(Engine::load(file.path()).unwrap().is_empty());
+}
+
+#[test]
+fn test_len_leaves_out_expired_keys_like_contains_key() {
+    let (engine, _f) = temp_engine();
+    let mut batch = WriteBatch::new();
+    batch
+        .put_with_ttl(b"short", b"v", Duration::from_millis(50))
+        .put_with_ttl(b"long", b"v", Duration::from_secs(3600));
+    engine.apply_batch(&batch).unwrap();
+    engine.set(b"plain", b"v").unwrap();
+    assert_eq!(engine.len(), 3);
+
+    thread::sleep(Duration::from_millis(80));
+    assert!(!engine.contains_key(b"short").unwrap());
+    assert_eq!(engine.len(), 2);
+    // Stats still count it until compaction drops it.
+    assert_eq!(engine.stats().live_keys, 3);
+    engine.compact().unwrap();
+    assert_eq!(engine.len(), 2);
+    assert_eq!(engine.stats().live_keys, 2);
+}
+
+#[test]
+fn test_len_counts_concurrent_writers() {
+    const THREADS: usize = 8;
+    const KEYS: usize = 500;
+    let (engine, _f) = temp_engine();
+    let engine = Arc::new(engine);
+
+    let writers: Vec<_> = (0..THREADS)
+        .map(|t| {
+            let engine = Arc::clone(&engine);
+            thread::spawn(move || {
+                for i in 0..KEYS {
+                    engine
+                        .set(format!("t{}:{}", t, i).as_bytes(), b"v")
+                        .unwrap();
+                }
+            })
+        })
+        .collect();
+    // Nothing is deleted, so the count only grows.
+    let mut seen = 0;
+    while writers.iter().any(|w| !w.is_finished()) {
+        let len = engine.len();
+        assert!(
+            len >= seen && len <= THREADS * KEYS,
+            "{} after {}",
+            len,
+            seen
+        );
+        seen = len;
+    }
+    for writer in writers {
+        writer.join().unwrap();
+    }
+    assert_eq!(engine.len(), THREADS * KEYS);
+}
+
+// ==================== Key Transform ====================
+
+fn lowercase_options() -> Options {
+    Options {
+        key_transform: Some(KeyTransform::ascii_lowercase()),
+        ..Options::default()
+    }
+}
+
+#[test]
+fn test_key_transform_folds_case_on_every_operation() {
+    let file = NamedTempFile::new().unwrap();
+    let engine = Engine::load_with_options(file.path(), lowercase_options()).unwrap();
+    engine.set(b"Alice", b"1").unwrap();
+    assert_eq!(engine.get(b"ALICE").unwrap(), Some(b"1".to_vec()));
+    assert!(engine.contains_key(b"alice").unwrap());
+    engine.set(b"ALICE", b"2").unwrap();
+    assert_eq!(engine.len(), 1);
+
+    let mut batch = WriteBatch::new();
+    batch.put(b"Bob", b"3").put(b"BOB", b"4").delete(b"Carol");
+    engine.apply_batch(&batch).unwrap();
+    assert_eq!(engine.get(b"bob").unwrap(), Some(b"4".to_vec()));
+    engine
+        .update_many(&[b"ALICE".as_slice(), b"bob"], |current| {
+            current
+                .iter()
+                .map(|v| v.clone().map(|v| [v, b"!".to_vec()].concat()))
+                .collect()
+        })
+        .unwrap();
+    assert_eq!(engine.get(b"Alice").unwrap(), Some(b"2!".to_vec()));
+
+    // Scans take transformed prefixes and return keys as stored.
+    let scanned: Vec<Vec<u8>> = engine
+        .scan_prefix(b"AL")
+        .unwrap()
+        .iter()
+        .map(|(key, _)| key.clone())
+        .collect();
+    assert_eq!(scanned, vec![b"alice".to_vec()]);
+
+    let bucket = engine.bucket(b"users").unwrap();
+    bucket.set(b"Dave", b"5").unwrap();
+    assert_eq!(bucket.get(b"DAVE").unwrap(), Some(b"5".to_vec()));
+    assert_eq!(bucket.scan_prefix(b"DA").unwrap()[0].0, b"dave");
+
+    engine.del(b"aLiCe").unwrap();
+    assert_eq!(engine.get(b"alice").unwrap(), None);
+    drop(engine);
+
+    let engine = Engine::load_with_options(file.path(), lowercase_options()).unwrap();
+    assert_eq!(engine.get(b"BOB").unwrap(), Some(b"4!".to_vec()));
+    // "bob", and "dave" in the users bucket.
+    assert_eq!(engine.len(), 2);
+}
+
+#[test]
+fn test_key_transform_is_recorded_in_the_header_and_checked_on_load() {
+    let file = NamedTempFile::new().unwrap();
+    let engine = Engine::load_with_options(file.path(), lowercase_options()).unwrap();
+    engine.set(b"Key", b"v").unwrap();
+    engine.compact().unwrap();
+    drop(engine);
+    let description = Engine::describe_format_of(file.path()).unwrap();
+    assert_eq!(
+        description.key_transform,
+        Some(KeyTransform::ASCII_LOWERCASE)
+    );
+
+    // Without the transform, or with another one, lookups would miss.
+    assert_engine_error(
+        Engine::load(file.path()).unwrap_err(),
+        EngineError::KeyTransformMismatch {
+            stored: KeyTransform::ASCII_LOWERCASE,
+            configured: 0,
+        },
+    );
+    let other = Options {
+        key_transform: Some(KeyTransform::new(300, |key| key.to_ascii_uppercase())),
+        ..Options::default()
+    };
+    assert_engine_error(
+        Engine::load_with_options(file.path(), other).unwrap_err(),
+        EngineError::KeyTransformMismatch {
+            stored: KeyTransform::ASCII_LOWERCASE,
+            configured: 300,
+        },
+    );
+    let none = Options {
+        key_transform: Some(KeyTransform::new(0, <[u8]>::to_vec)),
+        ..Options::default()
+    };
+    let err = Engine::load_with_options(file.path(), none).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    let engine = Engine::load_with_options(file.path(), lowercase_options()).unwrap();
+    assert_eq!(engine.get(b"KEY").unwrap(), Some(b"v".to_vec()));
+    drop(engine);
+
+    // A store created without a transform refuses one, as do files too old
+    // to record it.
+    let (plain, plain_file) = temp_engine();
+    plain.set(b"Key", b"v").unwrap();
+    drop(plain);
+    assert_engine_error(
+        Engine::load_with_options(plain_file.path(), lowercase_options()).unwrap_err(),
+        EngineError::KeyTransformMismatch {
+            stored: 0,
+            configured: KeyTransform::ASCII_LOWERCASE,
+        },
+    );
+    let dir = tempfile::tempdir().unwrap();
+    let v2 = dir.path().join("v2.db");
+    write_v2_file(&v2, DEFAULT_COMPACT_THRESHOLD, &[]);
+    let err = Engine::load_with_options(&v2, lowercase_options()).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::Unsupported);
+}
+
+// ==================== Buckets ====================
+
+fn assert_engine_error(err: std::io::Error, expected: EngineError) {
+    assert_eq!(EngineError::from_io(&err), Some(&expected));
+}
+
+/// The raw key a bucketed key is stored under, as a raw caller would forge it.
+fn forged_key(bucket: &[u8], key: &[u8]) -> Vec<u8> {
+    [&[0xFF, bucket.len() as u8][..], bucket, key].concat()
+}
+
+#[test]
+fn test_bucket_keys_are_isolated_from_each_other_and_raw() {
+    let (engine, _f) = temp_engine();
+    let a = engine.bucket(b"a").unwrap();
+    let ab = engine.bucket(b"ab").unwrap();
+
+    // "a" + "bx" and "ab" + "x" would collide under plain concatenation.
+    a.set(b"bx", b"from-a").unwrap();
+    ab.set(b"x", b"from-ab").unwrap();
+    engine.set(b"abx", b"raw").unwrap();
+
+    assert_eq!(a.get(b"bx").unwrap(), Some(b"from-a".to_vec()));
+    assert_eq!(ab.get(b"x").unwrap(), Some(b"from-ab".to_vec()));
+    assert_eq!(engine.get(b"abx").unwrap(), Some(b"raw".to_vec()));
+    assert_eq!(a.get(b"x").unwrap(), None);
+    assert_eq!(ab.get(b"bx").unwrap(), None);
+
+    assert_eq!(
+        a.scan_prefix(b"").unwrap(),
+        vec![(b"bx".to_vec(), b"from-a".to_vec())]
+    );
+    assert_eq!(
+        engine.scan_prefix(b"").unwrap(),
+        vec![(b"abx".to_vec(), b"raw".to_vec())]
+    );
+    assert_eq!(engine.raw_keys_excluding_buckets(), vec![b"abx".to_vec()]);
+
+    ab.del(b"x").unwrap();
+    assert_eq!(a.get(b"bx").unwrap(), Some(b"from-a".to_vec()));
+    assert_eq!(engine.get(b"abx").unwrap(), Some(b"raw".to_vec()));
+}
+
+#[test]
+fn test_raw_api_rejects_keys_mimicking_bucket_keys() {
+    let (engine, _f) = temp_engine();
+    let bucket = engine.bucket(b"users").unwrap();
+    bucket.set(b"alice", b"secret").unwrap();
+    let forged = forged_key(b"users", b"alice");
+
+    assert_engine_error(
+        engine.set(&forged, b"overwrite").unwrap_err(),
+        EngineError::ReservedKey,
+    );
+    assert_engine_error(engine.get(&forged).unwrap_err(), EngineError::ReservedKey);
+    assert_engine_error(engine.del(&forged).unwrap_err(), EngineError::ReservedKey);
+    assert_engine_error(
+        engine.scan_prefix(&[0xFF]).unwrap_err(),
+        EngineError::ReservedKey,
+    );
+    assert_engine_error(
+        engine.delete_prefix(&forged[..3]).unwrap_err(),
+        EngineError::ReservedKey,
+    );
+    assert_engine_error(
+        engine.update_many(&[&forged], |_| vec![None]).unwrap_err(),
+        EngineError::ReservedKey,
+    );
+    let mut batch = WriteBatch::new();
+    batch.put(&forged, b"overwrite");
+    assert_engine_error(
+        engine.apply_batch(&batch).unwrap_err(),
+        EngineError::ReservedKey,
+    );
+
+    assert_eq!(bucket.get(b"alice").unwrap(), Some(b"secret".to_vec()));
+}
+
+#[test]
+fn test_raw_delete_prefix_leaves_buckets_alone() {
+    let (engine, _f) = temp_engine();
+    let names: [&[u8]; 3] = [b"x", b"xy", b"y"];
+    for name in names {
+        let bucket = engine.bucket(name).unwrap();
+        bucket.set(b"k1", name).unwrap();
+        bucket.set(b"k2", name).unwrap();
+    }
+    engine.set(b"k1", b"raw").unwrap();
+    engine.set(b"\x01x", b"raw").unwrap();
+
+    // An empty prefix matches every raw key but no bucketed one.
+    assert_eq!(engine.delete_prefix(b"").unwrap(), 2);
+    assert!(engine.raw_keys_excluding_buckets().is_empty());
+
+    assert_eq!(
+        engine.bucket(b"x").unwrap().delete_prefix(b"k1").unwrap(),
+        1
+    );
+    for name in names {
+        let bucket = engine.bucket(name).unwrap();
+        let expected_k1 = (name != b"x").then(|| name.to_vec());
+        assert_eq!(bucket.get(b"k1").unwrap(), expected_k1);
+        assert_eq!(bucket.get(b"k2").unwrap(), Some(name.to_vec()));
+    }
+    assert_eq!(engine.stats().live_keys, 5);
+}
+
+#[test]
+fn test_bucket_scan_prefix_does_not_leak_across_buckets() {
+    let (engine, file) = temp_engine();
+    let mut rng = XorShift(0xB0C4E7);
+    let names: [&[u8]; 4] = [b"a", b"aa", b"a\x01", b"\x02a"];
+    let mut expected: HashMap<Vec<u8>, HashMap<Vec<u8>, Vec<u8>>> = HashMap::new();
+
+    for _ in 0..200 {
+        let name = names[rng.next() as usize % names.len()];
+        let key = rng.bytes(6);
+        if key.is_empty() {
+            continue;
+        }
+        let value = rng.bytes(16);
+        engine.bucket(name).unwrap().set(&key, &value).unwrap();
+        expected
+            .entry(name.to_vec())
+            .or_default()
+            .insert(key, value);
+    }
+    engine.compact().unwrap();
+    let engine = Engine::load(file.path()).unwrap();
+
+    for name in names {
+        let bucket = engine.bucket(name).unwrap();
+        let mut want: Vec<_> = expected
+            .get(name)
+            .map(|m| m.clone().into_iter().collect())
+            .unwrap_or_default();
+        want.sort();
+        assert_eq!(bucket.scan_prefix(b"").unwrap(), want);
+    }
+    assert!(engine.scan_prefix(b"").unwrap().is_empty());
+}
+
+#[test]
+fn test_bucket_name_validation() {
+    let (engine, _f) = temp_engine();
+    for name in [&b""[..], b"a\xFFb", &[b'n'; 256]] {
+        let err = engine.bucket(name).unwrap_err();
+        assert!(matches!(
+            EngineError::from_io(&err),
+            Some(EngineError::InvalidBucketName(_))
+        ));
+    }
+    assert!(engine.bucket(&[b'n'; 255]).is_ok());
+
+    let bucket = engine.bucket(b"b").unwrap();
+    assert_engine_error(bucket.set(b"", b"v").unwrap_err(), EngineError::EmptyKey);
+}
+
+#[test]
+fn test_bucket_stats_attribute_dead_bytes_to_the_churning_bucket() {
+    let (engine, file) = temp_engine();
+    let hot = engine.bucket(b"hot").unwrap();
+    let cold = engine.bucket(b"cold").unwrap();
+    for i in 0..10u8 {
+        cold.set(&[i], &[i; 32]).unwrap();
+        for round in 0..5u8 {
+            hot.set(&[i], &[round; 32]).unwrap();
+        }
+    }
+    for i in 0..3u8 {
+        hot.del(&[i]).unwrap();
+    }
+
+    let (hot_stats, cold_stats) = (hot.stats(), cold.stats());
+    assert_eq!(hot_stats.keys, 7);
+    assert_eq!(cold_stats.keys, 10);
+    assert_eq!(cold_stats.dead_bytes, 0);
+    assert!(hot_stats.dead_bytes > 4 * hot_stats.live_bytes);
+    assert_eq!(hot_stats.dead_bytes, engine.stats().dead_bytes);
+    assert_eq!(
+        FILE_HEADER_SIZE + hot_stats.live_bytes + hot_stats.dead_bytes + cold_stats.live_bytes,
+        engine.stats().file_size
+    );
+
+    // Replaying the log attributes the same bytes.
+    drop(hot);
+    drop(cold);
+    drop(engine);
+    let engine = Engine::load(file.path()).unwrap();
+    let (hot, cold) = (
+        engine.bucket(b"hot").unwrap(),
+        engine.bucket(b"cold").unwrap(),
+    );
+    assert_eq!(hot.stats(), hot_stats);
+    assert_eq!(cold.stats(), cold_stats);
+
+    engine.compact().unwrap();
+    assert_eq!(hot.stats().dead_bytes, 0);
+    assert_eq!(hot.stats().live_bytes, hot_stats.live_bytes);
+
+    assert_eq!(cold.clear().unwrap(), 10);
+    let cleared = cold.stats();
+    assert_eq!((cleared.keys, cleared.live_bytes), (0, 0));
+    assert!(cleared.dead_bytes > cold_stats.live_bytes);
+    assert_eq!(hot.stats().dead_bytes, 0);
+}
+
+fn quota_usage(bucket: &Bucket) -> (u64, u64) {
+    let usage = bucket.usage();
+    (usage.keys, usage.value_bytes)
+}
+
+fn is_quota_exceeded(err: &std::io::Error) -> bool {
+    err.kind() == std::io::ErrorKind::QuotaExceeded
+        && matches!(
+            EngineError::from_io(err),
+            Some(EngineError::QuotaExceeded(_))
+        )
+}
+
+#[test]
+fn test_bucket_quota_refuses_growth_and_deletes_free_it_at_once() {
+    let (engine, _f) = temp_engine();
+    let bucket = engine.bucket(b"tenant").unwrap();
+    bucket.set(b"a", &[1; 40]).unwrap();
+    let quota = Quota {
+        max_keys: Some(3),
+        max_bytes: Some(100),
+    };
+    bucket.set_quota(quota).unwrap();
+    assert_eq!(bucket.quota(), Some(quota));
+    assert_eq!(quota_usage(&bucket), (1, 40));
+
+    bucket.set(b"b", &[2; 40]).unwrap();
+    assert!(is_quota_exceeded(&bucket.set(b"c", &[3; 40]).unwrap_err()));
+    assert_eq!(bucket.get(b"c").unwrap(), None);
+    // An overwrite counts only the difference.
+    bucket.set(b"b", &[2; 60]).unwrap();
+    assert!(is_quota_exceeded(&bucket.set(b"b", &[2; 61]).unwrap_err()));
+    // Other buckets and raw keys have no quota.
+    engine
+        .bucket(b"other")
+        .unwrap()
+        .set(b"c", &[3; 400])
+        .unwrap();
+    engine.set(b"c", &[3; 400]).unwrap();
+
+    // The deleted value is still on disk, but no longer counts.
+    bucket.del(b"b").unwrap();
+    assert_eq!(quota_usage(&bucket), (1, 40));
+    assert!(bucket.stats().dead_bytes > 0);
+    bucket.set(b"c", &[3; 40]).unwrap();
+    bucket.set(b"d", &[4; 10]).unwrap();
+    assert!(is_quota_exceeded(&bucket.set(b"e", b"x").unwrap_err()));
+
+    // Under a lowered quota nothing is dropped, and shrinking still works.
+    bucket
+        .set_quota(Quota {
+            max_keys: Some(1),
+            max_bytes: None,
+        })
+        .unwrap();
+    bucket.set(b"c", b"smaller").unwrap();
+    assert!(is_quota_exceeded(&bucket.set(b"e", b"x").unwrap_err()));
+    assert_eq!(quota_usage(&bucket), (3, 57));
+
+    bucket.clear_quota().unwrap();
+    assert_eq!(bucket.quota(), None);
+    bucket.set(b"e", b"x").unwrap();
+}
+
+#[test]
+fn test_bucket_quota_and_usage_survive_compaction_and_reload() {
+    let file = NamedTempFile::new().unwrap();
+    let quota = Quota {
+        max_keys: Some(10),
+        max_bytes: Some(1000),
+    };
+    {
+        let engine = Engine::load(file.path()).unwrap();
+        let bucket = engine.bucket(b"tenant").unwrap();
+        bucket.set_quota(quota).unwrap();
+        for i in 0..10u8 {
+            bucket.set(&[i], &[i; 50]).unwrap();
+        }
+        for i in 0..5u8 {
+            bucket.set(&[i], &[i; 80]).unwrap();
+        }
+        bucket.del(&[9]).unwrap();
+    }
+
+    let engine = Engine::load(file.path()).unwrap();
+    let bucket = engine.bucket(b"tenant").unwrap();
+    assert_eq!(bucket.quota(), Some(quota));
+    assert_eq!(quota_usage(&bucket), (9, 650 - 50));
+    // The quota lives under an internal key no listing shows.
+    assert!(engine.keys().is_empty());
+    assert_eq!(bucket.scan_prefix(b"").unwrap().len(), 9);
+
+    engine.compact().unwrap();
+    assert_eq!(bucket.quota(), Some(quota));
+    assert_eq!(quota_usage(&bucket), (9, 600));
+    bucket.set(&[9], &[9; 50]).unwrap();
+    assert!(is_quota_exceeded(&bucket.set(&[10], b"x").unwrap_err()));
+
+    engine.reload().unwrap();
+    assert_eq!(quota_usage(&bucket), (10, 650));
+    assert!(is_quota_exceeded(&bucket.set(&[10], b"x").unwrap_err()));
+    bucket.set(&[0], &[0; 430]).unwrap();
+    assert_eq!(quota_usage(&bucket), (10, 1000));
+    assert!(is_quota_exceeded(&bucket.set(&[1], &[1; 81]).unwrap_err()));
+}
+
+/// Every pair in `bucket`, as `(key, value)` strings.
+fn bucket_contents(bucket: &Bucket) -> Vec<(String, String)> {
+    bucket
+        .scan_prefix(b"")
+        .unwrap()
+        .into_iter()
+        .map(|(k, v)| (String::from_utf8(k).unwrap(), String::from_utf8(v).unwrap()))
+        .collect()
+}
+
+fn config(version: &str) -> Vec<(String, String)> {
+    (0..20)
+        .map(|i| (format!("setting-{:02}", i), format!("{}-{}", version, i)))
+        .collect()
+}
+
+fn write_config(bucket: &Bucket, pairs: &[(String, String)]) {
+    for (key, value) in pairs {
+        bucket.set(key.as_bytes(), value.as_bytes()).unwrap();
+    }
+}
+
+#[test]
+fn test_swap_buckets_exchanges_contents_and_keeps_quotas_by_name() {
+    let file = NamedTempFile::new().unwrap();
+    let engine = Engine::load(file.path()).unwrap();
+    let live = engine.bucket(b"live").unwrap();
+    let staging = engine.bucket(b"staging").unwrap();
+    write_config(&live, &config("v1"));
+    write_config(&staging, &config("v2"));
+    staging.set(b"only-in-v2", b"new").unwrap();
+    live.set_quota(Quota {
+        max_keys: Some(25),
+        max_bytes: None,
+    })
+    .unwrap();
+
+    engine.swap_buckets(b"live", b"staging").unwrap();
+    let mut v2 = config("v2");
+    v2.push(("only-in-v2".to_string(), "new".to_string()));
+    v2.sort();
+    // Handles taken before the swap follow it.
+    assert_eq!(bucket_contents(&live), v2);
+    assert_eq!(bucket_contents(&staging), config("v1"));
+    assert_eq!(live.get(b"only-in-v2").unwrap(), Some(b"new".to_vec()));
+    assert_eq!(staging.get(b"only-in-v2").unwrap(), None);
+    assert_eq!(live.stats().keys, 21);
+    assert_eq!(
+        quota_usage(&live),
+        (21, v2.iter().map(|(_, v)| v.len() as u64).sum())
+    );
+
+    // The quota stayed with "live", now over the 21 keys it holds.
+    assert_eq!(live.quota().unwrap().max_keys, Some(25));
+    assert_eq!(staging.quota(), None);
+    for i in 0..4 {
+        live.set(format!("extra-{}", i).as_bytes(), b"x").unwrap();
+    }
+    assert!(is_quota_exceeded(
+        &live.set(b"one-too-many", b"x").unwrap_err()
+    ));
+    staging.set(b"unlimited", b"x").unwrap();
+    staging.del(b"unlimited").unwrap();
+
+    // Swapping back restores both, with no mapping left behind.
+    engine.swap_buckets(b"staging", b"live").unwrap();
+    assert_eq!(bucket_contents(&live).len(), 20);
+    assert_eq!(live.get(b"setting-00").unwrap(), Some(b"v1-0".to_vec()));
+    engine.swap_buckets(b"live", b"staging").unwrap();
+
+    // A third bucket joins the rotation.
+    let old = engine.bucket(b"old").unwrap();
+    engine.swap_buckets(b"old", b"staging").unwrap();
+    assert_eq!(bucket_contents(&old), config("v1"));
+    assert!(bucket_contents(&staging).is_empty());
+
+    let check = |engine: &Engine| {
+        let live = engine.bucket(b"live").unwrap();
+        assert_eq!(live.get(b"setting-07").unwrap(), Some(b"v2-7".to_vec()));
+        assert_eq!(live.stats().keys, 25);
+        assert_eq!(live.quota().unwrap().max_keys, Some(25));
+        assert_eq!(
+            bucket_contents(&engine.bucket(b"old").unwrap()),
+            config("v1")
+        );
+        assert!(bucket_contents(&engine.bucket(b"staging").unwrap()).is_empty());
+        assert!(engine.keys().is_empty());
+    };
+    check(&engine);
+    engine.compact().unwrap();
+    check(&engine);
+    drop(engine);
+    check(&Engine::load(file.path()).unwrap());
+}
+
+#[test]
+fn test_swap_buckets_readers_see_one_side_whole() {
+    let (engine, _f) = temp_engine();
+    write_config(&engine.bucket(b"live").unwrap(), &config("v1"));
+    write_config(&engine.bucket(b"staging").unwrap(), &config("v2"));
+    let (v1, v2) = (config("v1"), config("v2"));
+    let done = AtomicBool::new(false);
+
+    thread::scope(|s| {
+        for _ in 0..4 {
+            s.spawn(|| {
+                let live = engine.bucket(b"live").unwrap();
+                let mut reads = 0;
+                while !done.load(Ordering::SeqCst) || reads == 0 {
+                    let contents = bucket_contents(&live);
+                    assert!(contents == v1 || contents == v2, "mixed read");
+                    // A single read sees one version too.
+                    let value = live.get(b"setting-03").unwrap().unwrap();
+                    assert!(value == b"v1-3" || value == b"v2-3");
+                    reads += 1;
+                }
+            });
+        }
+        for _ in 0..200 {
+            engine.swap_buckets(b"live", b"staging").unwrap();
+        }
+        done.store(true, Ordering::SeqCst);
+    });
+    // An even number of swaps.
+    assert_eq!(bucket_contents(&engine.bucket(b"live").unwrap()), v1);
+}
+
+#[test]
+fn test_half_written_swap_recovers_unswapped() {
+    let file = NamedTempFile::new().unwrap();
+    let engine = Engine::load(file.path()).unwrap();
+    write_config(&engine.bucket(b"live").unwrap(), &config("v1"));
+    write_config(&engine.bucket(b"staging").unwrap(), &config("v2"));
+    let before_swap = engine.stats().file_size;
+    engine.swap_buckets(b"live", b"staging").unwrap();
+    let after_swap = engine.stats().file_size;
+    drop(engine);
+
+    // A crash partway through the swap's one write leaves some of its
+    // records; replay drops the incomplete batch and both buckets are as
+    // they were.
+    for len in [
+        after_swap - 1,
+        (before_swap + after_swap) / 2,
+        before_swap + 1,
+    ] {
+        let torn = NamedTempFile::new().unwrap();
+        fs::copy(file.path(), torn.path()).unwrap();
+        Corruption::Truncate(len).apply(torn.path()).unwrap();
+        let engine = Engine::load(torn.path()).unwrap();
+        assert_eq!(
+            engine.load_report().replay.truncated_bytes,
+            len - before_swap
+        );
+        let live = engine.bucket(b"live").unwrap();
+        let staging = engine.bucket(b"staging").unwrap();
+        assert_eq!(bucket_contents(&live), config("v1"));
+        assert_eq!(bucket_contents(&staging), config("v2"));
+
+        // The store carries on from there.
+        engine.swap_buckets(b"live", b"staging").unwrap();
+        assert_eq!(bucket_contents(&live), config("v2"));
+    }
+
+    let engine = Engine::load(file.path()).unwrap();
+    assert_eq!(
+        bucket_contents(&engine.bucket(b"live").unwrap()),
+        config("v2")
+    );
+}
+
+// ==================== Internal Keys ====================
+
+/// A log holding `keys` as plain puts, as a build from before 0xFF was
+/// reserved could have written it.
+fn write_log_with_keys(path: &std::path::Path, keys: &[Vec<u8>]) {
+    use breakout1_kv_store::format::{self, FORMAT_VERSION, Header};
+    let mut bytes = Vec::new();
+    let header = Header {
+        format_version: FORMAT_VERSION,
+        compact_threshold: DEFAULT_COMPACT_THRESHOLD,
+        epoch: 0,
+        flags: 0,
+    };
+    format::write_header(&mut bytes, &header).unwrap();
+    for key in keys {
+        let record = DataFileEntry::put(1_700_000_000_000, key.clone(), b"v".to_vec());
+        format::write_record(&mut bytes, FORMAT_VERSION, &record).unwrap();
+    }
+    fs::write(path, bytes).unwrap();
+}
+
+#[test]
+fn test_internal_keys_are_refused_by_every_raw_write_and_read() {
+    let (engine, _f) = temp_engine();
+    engine
+        .bucket(b"b")
+        .unwrap()
+        .set_quota(Quota {
+            max_keys: Some(1),
+            max_bytes: None,
+        })
+        .unwrap();
+    let internal = [
+        vec![0xFF, 0],
+        [&[0xFF, 0][..], b"quota/b"].concat(),
+        [&[0xFF, 0][..], b"ns/b"].concat(),
+        [&[0xFF, 0][..], b"lease/x"].concat(),
+    ];
+    for key in &internal {
+        assert_engine_error(engine.set(key, b"x").unwrap_err(), EngineError::ReservedKey);
+        assert_engine_error(engine.del(key).unwrap_err(), EngineError::ReservedKey);
+        assert_engine_error(engine.get(key).unwrap_err(), EngineError::ReservedKey);
+        assert_engine_error(
+            engine.scan_prefix(key).unwrap_err(),
+            EngineError::ReservedKey,
+        );
+        let mut batch = WriteBatch::new();
+        batch.put(b"ok", b"v").delete(key);
+        assert_engine_error(
+            engine.apply_batch(&batch).unwrap_err(),
+            EngineError::ReservedKey,
+        );
+        assert_engine_error(
+            engine
+                .update_many(&[key.as_slice()], |_| vec![None])
+                .unwrap_err(),
+            EngineError::ReservedKey,
+        );
+    }
+    assert_eq!(engine.get(b"ok").unwrap(), None);
+    assert!(engine.bucket(b"").is_err());
+    assert_eq!(engine.stats().internal_keys, 1);
+}
+
+#[test]
+fn test_internal_keys_stay_out_of_counts_and_exports_unless_included() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("data.db");
+    let quota = Quota {
+        max_keys: Some(10),
+        max_bytes: None,
+    };
+    let engine = Engine::load(&path).unwrap();
+    engine.set(b"raw", b"v").unwrap();
+    let bucket = engine.bucket(b"b").unwrap();
+    bucket.set(b"k", b"v").unwrap();
+    bucket.set_quota(quota).unwrap();
+    engine.swap_buckets(b"b", b"c").unwrap();
+
+    let stats = engine.stats();
+    assert_eq!((stats.live_keys, stats.internal_keys), (2, 3));
+    let mut exported = Vec::new();
+    assert_eq!(engine.export_sorted_keys(&mut exported, 4096).unwrap(), 2);
+    assert!(
+        parse_exported_keys(&exported)
+            .iter()
+            .all(|key| !key.starts_with(&[0xFF, 0]))
+    );
+    let snapshot = dir.path().join("snap");
+    assert_eq!(engine.export_shm_snapshot(&snapshot).unwrap(), 2);
+    assert_eq!(engine.sample(1.0, 0).unwrap().count(), 2);
+    assert_eq!(engine.keys().len(), 1);
+
+    // Dumps keep them, so a restored store keeps the quota and the swap.
+    let mut dump = Vec::new();
+    assert_eq!(engine.dump(&mut dump).unwrap(), 5);
+    let restored_path = dir.path().join("restored.db");
+    Engine::load(&restored_path)
+        .unwrap()
+        .load_dump(dump.as_slice())
+        .unwrap();
+    let restored = Engine::load(&restored_path).unwrap();
+    assert_eq!(
+        restored.bucket(b"c").unwrap().get(b"k").unwrap(),
+        Some(b"v".to_vec())
+    );
+    assert_eq!(restored.bucket(b"c").unwrap().quota(), None);
+    assert_eq!(restored.bucket(b"b").unwrap().quota(), Some(quota));
+    drop(engine);
+
+    let options = Options {
+        include_internal_keys: true,
+        ..Options::default()
+    };
+    let engine = Engine::load_with_options(&path, options).unwrap();
+    let stats = engine.stats();
+    assert_eq!((stats.live_keys, stats.internal_keys), (5, 3));
+    assert_eq!(engine.export_sorted_keys(Vec::new(), 4096).unwrap(), 5);
+    assert_eq!(engine.export_shm_snapshot(&snapshot).unwrap(), 5);
+    assert_eq!(engine.keys().len(), 1);
+}
+
+#[test]
+fn test_stray_reserved_keys_are_refused_on_load_dump_and_ingest() {
+    let dir = tempfile::tempdir().unwrap();
+    let strays = [
+        vec![0xFF],
+        vec![0xFF, 0, b'x'],
+        [&[0xFF, 0][..], b"lease/x"].concat(),
+        vec![0xFF, 5, b'a'],
+        vec![0xFF, 2, 0xFF, b'a', b'k'],
+    ];
+    let target = Engine::load(dir.path().join("target.db")).unwrap();
+    for (i, stray) in strays.iter().enumerate() {
+        let expected = EngineError::StrayReservedKey(stray.clone());
+        let path = dir.path().join(format!("old-{}.db", i));
+        write_log_with_keys(&path, &[b"fine".to_vec(), stray.clone()]);
+        let err = Engine::load(&path).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert_engine_error(err, expected.clone());
+
+        assert_engine_error(
+            target
+                .ingest_raw_log(&path, ConflictPolicy::KeepNewest)
+                .unwrap_err(),
+            expected.clone(),
+        );
+
+        let mut bytes = Vec::new();
+        let mut dump = breakout1_kv_store::dump::DumpWriter::new(&mut bytes).unwrap();
+        dump.write_record(b"fine", b"v", 1).unwrap();
+        dump.write_record(stray, b"v", 1).unwrap();
+        dump.finish().unwrap();
+        assert_engine_error(target.load_dump(bytes.as_slice()).unwrap_err(), expected);
+    }
+    assert_eq!(target.get(b"fine").unwrap(), None);
+
+    // Keys the engine itself writes under the prefix load as before.
+    let path = dir.path().join("well-formed.db");
+    write_log_with_keys(
+        &path,
+        &[
+            vec![0xFF, 1, b'a', b'k'],
+            vec![0xFF, 1, b'a'],
+            [&[0xFF, 0][..], b"ns/a"].concat(),
+        ],
+    );
+    let engine = Engine::load(&path).unwrap();
+    assert_eq!(engine.stats().internal_keys, 1);
+}
+
+// ==================== Stepped Compaction ====================
+
+fn churn(engine: &Engine, seed: u64) {
+    let mut rng = XorShift(seed);
+    for _ in 0..400 {
+        let key = format!("k{}", rng.next() % 100);
+        match rng.next() % 4 {
+            0 => engine.del(key.as_bytes()).unwrap(),
+            _ => engine.set(key.as_bytes(), &rng.bytes(64)).unwrap(),
+        }
+    }
+}
+
+fn step_to_done(engine: &Engine, mut between_steps: impl FnMut(u32)) -> (CompactionReport, u32) {
+    for steps in 1.. {
+        match engine.compact_step(Duration::ZERO).unwrap() {
+            CompactProgress::Done(report) => return (report, steps),
+            CompactProgress::InProgress { .. } => between_steps(steps),
+        }
+    }
+    unreachable!()
+}
+
+#[test]
+fn test_compact_step_matches_single_shot_compact() {
+    let (stepped, _f1) = temp_engine();
+    let (single, _f2) = temp_engine();
+    churn(&stepped, 7);
+    churn(&single, 7);
+
+    single.compact().unwrap();
+    let (report, steps) = step_to_done(&stepped, |_| {});
+
+    assert!(steps > 10, "expected many steps, got {}", steps);
+    assert_eq!(report.carried_over, 0);
+    assert_eq!(report.new_size, single.stats().file_size);
+    assert_eq!(stepped.stats().file_size, single.stats().file_size);
+    assert_eq!(stepped.stats().compactions, 1);
+    assert_eq!(
+        stepped.scan_prefix(b"").unwrap(),
+        single.scan_prefix(b"").unwrap()
+    );
+}
+
+#[test]
+fn test_compact_step_carries_over_writes_between_steps() {
+    let (engine, file) = temp_engine();
+    churn(&engine, 11);
+    let mut expected: HashMap<Vec<u8>, Vec<u8>> =
+        engine.scan_prefix(b"").unwrap().into_iter().collect();
+
+    let (report, _) = step_to_done(&engine, |step| {
+        let key = format!("k{}", step % 120).into_bytes();
+        if step % 3 == 0 {
+            engine.del(&key).unwrap();
+            expected.remove(&key);
+        } else {
+            let value = format!("step-{}", step).into_bytes();
+            engine.set(&key, &value).unwrap();
+            expected.insert(key, value);
+        }
+    });
+    assert!(report.carried_over > 0);
+
+    let mut expected: Vec<_> = expected.into_iter().collect();
+    expected.sort();
+    assert_eq!(engine.scan_prefix(b"").unwrap(), expected);
+    drop(engine);
+    let engine = Engine::load(file.path()).unwrap();
+    assert_eq!(engine.scan_prefix(b"").unwrap(), expected);
+}
+
+#[test]
+fn test_compact_abort_removes_output() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("data.db");
+    let engine = Engine::load(&path).unwrap();
+    churn(&engine, 3);
+    let size = engine.stats().file_size;
+
+    assert!(!engine.compact_abort().unwrap());
+    assert!(matches!(
+        engine.compact_step(Duration::ZERO).unwrap(),
+        CompactProgress::InProgress { copied, .. } if copied > 0
+    ));
+    assert!(path.with_extension("step").exists());
+
+    assert!(engine.compact_abort().unwrap());
+    assert!(!path.with_extension("step").exists());
+    assert_eq!(engine.stats().file_size, size);
+    assert_eq!(engine.stats().compactions, 0);
+
+    // A later stepped compaction starts from scratch.
+    let (report, _) = step_to_done(&engine, |_| {});
+    assert_eq!(report.old_size, size);
+}
+
+#[test]
+fn test_compact_step_restarts_after_file_is_replaced() {
+    let (engine, _f) = temp_engine();
+    churn(&engine, 5);
+    engine.compact_step(Duration::ZERO).unwrap();
+
+    engine.set(b"k1", b"after-full-compact").unwrap();
+    engine.compact().unwrap();
+    engine.set(b"k2", b"after-swap").unwrap();
+
+    step_to_done(&engine, |_| {});
+    assert_eq!(engine.stats().compactions, 2);
+    assert_eq!(
+        engine.get(b"k1").unwrap(),
+        Some(b"after-full-compact".to_vec())
+    );
+    assert_eq!(engine.get(b"k2").unwrap(), Some(b"after-swap".to_vec()));
+}
+
+// ==================== Packed Prefix ====================
+
+#[test]
+fn test_compaction_keeps_a_packed_prefix_and_copies_only_the_tail() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("data.db");
+    let options = Options {
+        compact_threshold: Some(u64::MAX),
+        ..Options::default()
+    };
+    let engine = Engine::load_with_options(&path, options).unwrap();
+    for i in 0..100 {
+        engine
+            .set(format!("clean-{i:03}").as_bytes(), &[i as u8; 64])
+            .unwrap();
+    }
+    // A log of nothing but live records is already packed.
+    let (report, _) = step_to_done(&engine, |_| {});
+    assert_eq!(report.preserved_bytes, report.new_size - FILE_HEADER_SIZE);
+    assert_eq!(report.copied_bytes, 0);
+    let packed = fs::read(&path).unwrap();
+
+    for round in 0..3 {
+        for i in 0..10 {
+            let value = format!("dirty-{i}-{round}");
+            engine
+                .set(format!("dirty-{i}").as_bytes(), value.as_bytes())
+                .unwrap();
+        }
+    }
+    engine.del(b"dirty-9").unwrap();
+    let (report, _) = step_to_done(&engine, |_| {});
+    assert_eq!(
+        report.preserved_bytes,
+        packed.len() as u64 - FILE_HEADER_SIZE
+    );
+    assert_eq!(
+        report.preserved_bytes + report.copied_bytes,
+        report.new_size - FILE_HEADER_SIZE
+    );
+    assert!(report.copied_bytes > 0);
+    assert!(report.copied_bytes < report.preserved_bytes / 10);
+
+    let compacted = fs::read(&path).unwrap();
+    assert_eq!(
+        compacted[FILE_HEADER_SIZE as usize..packed.len()],
+        packed[FILE_HEADER_SIZE as usize..]
+    );
+    let check = |engine: &Engine| {
+        assert_eq!(engine.get(b"clean-042").unwrap(), Some(vec![42; 64]));
+        assert_eq!(engine.get(b"dirty-3").unwrap(), Some(b"dirty-3-2".to_vec()));
+        assert_eq!(engine.get(b"dirty-9").unwrap(), None);
+        assert_eq!(engine.stats().live_keys, 109);
+    };
+    check(&engine);
+
+    // Overwriting a key ends the prefix at its old record, halfway through
+    // the equally sized clean ones.
+    engine.set(b"clean-050", b"moved").unwrap();
+    let (report, _) = step_to_done(&engine, |_| {});
+    assert_eq!(
+        report.preserved_bytes,
+        (packed.len() as u64 - FILE_HEADER_SIZE) / 2
+    );
+    drop(engine);
+    check(&Engine::load(&path).unwrap());
+}
+
+// ==================== Index Limits ====================
+
+fn assert_index_full(err: std::io::Error) {
+    assert_eq!(err.kind(), std::io::ErrorKind::OutOfMemory);
+    assert!(matches!(
+        EngineError::from_io(&err),
+        Some(EngineError::IndexFull(_))
+    ));
+}
+
+#[test]
+fn test_max_index_entries_rejects_new_keys_until_some_are_deleted() {
+    let file = NamedTempFile::new().unwrap();
+    let options = Options {
+        max_index_entries: Some(10),
+        ..Options::default()
+    };
+    let engine = Engine::load_with_options(file.path(), options).unwrap();
+    for i in 0..10 {
+        engine.set(format!("k{}", i).as_bytes(), b"v").unwrap();
+    }
+    let size = engine.stats().file_size;
+
+    assert_index_full(engine.set(b"k10", b"v").unwrap_err());
+    assert_eq!(engine.stats().file_size, size);
+    assert_eq!(engine.get(b"k10").unwrap(), None);
+
+    // Overwrites and deletes of existing keys are unaffected.
+    engine.set(b"k0", b"v2").unwrap();
+    engine.del(b"k1").unwrap();
+    engine.del(b"k2").unwrap();
+    engine.set(b"k10", b"v").unwrap();
+
+    let mut batch = WriteBatch::new();
+    batch.put(b"k11", b"v").put(b"k12", b"v");
+    assert_index_full(engine.apply_batch(&batch).unwrap_err());
+    assert_eq!(engine.get(b"k11").unwrap(), None);
+    // One slot is left, so a single new key fits.
+    batch.clear();
+    batch.put(b"k11", b"v");
+    engine.apply_batch(&batch).unwrap();
+
+    let stats = engine.stats();
+    assert_eq!(stats.live_keys, 10);
+    assert_eq!(stats.max_index_entries, Some(10));
+}
+
+#[test]
+fn test_max_index_bytes_tracks_key_sizes() {
+    let file = NamedTempFile::new().unwrap();
+    let engine = Engine::load(file.path()).unwrap();
+    engine.set(&[b'a'; 100], b"v").unwrap();
+    let one_key = engine.stats().index_bytes;
+    assert!(one_key > 100);
+    engine.set(&[b'a'; 100], b"overwrite").unwrap();
+    assert_eq!(engine.stats().index_bytes, one_key);
+    engine.del(&[b'a'; 100]).unwrap();
+    assert_eq!(engine.stats().index_bytes, 0);
+    drop(engine);
+
+    let options = Options {
+        max_index_bytes: Some(one_key * 2),
+        ..Options::default()
+    };
+    let engine = Engine::load_with_options(file.path(), options).unwrap();
+    engine.set(&[b'b'; 100], b"v").unwrap();
+    engine.set(&[b'c'; 100], b"v").unwrap();
+    assert_index_full(engine.set(&[b'd'; 100], b"v").unwrap_err());
+    // Even a one-byte key costs more than the key bytes themselves.
+    assert_index_full(engine.set(&[b'd'; 1], b"v").unwrap_err());
+
+    engine.del(&[b'b'; 100]).unwrap();
+    engine.set(&[b'd'; 100], b"v").unwrap();
+    assert_eq!(engine.stats().index_bytes, one_key * 2);
+
+    drop(engine);
+    let engine = Engine::load(file.path()).unwrap();
+    assert_eq!(engine.stats().index_bytes, one_key * 2);
+}
+
+// ==================== Concurrent Load ====================
+
+#[test]
+fn test_concurrent_load_of_fresh_path_initializes_once() {
+    for round in 0..20 {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data.db");
+        let barrier = Arc::new(Barrier::new(16));
+
+        let handles: Vec<_> = (0..16)
+            .map(|i| {
+                let path = path.clone();
+                let barrier = Arc::clone(&barrier);
+                thread::spawn(move || {
+                    barrier.wait();
+                    let engine = Engine::load(&path).unwrap();
+                    // One loader moves on at once; a late header write from
+                    // any other would reset this threshold.
+                    if i == 0 {
+                        engine.set_compact_threshold(4242 + round).unwrap();
+                        engine.set(b"winner", b"1").unwrap();
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        assert_eq!(read_threshold_from_file(&path), 4242 + round);
+        let engine = Engine::load(&path).unwrap();
+        assert_eq!(engine.get(b"winner").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(engine.verify().unwrap(), 1);
+    }
+}
+
+#[test]
+fn test_load_waits_for_header_initialization_in_progress() {
+    use std::io::Write;
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("data.db");
+
+    // Another initializer holds the lock and has written half a header.
+    let mut initializer = fs::File::create(&path).unwrap();
+    initializer.lock().unwrap();
+    initializer.write_all(&FILE_HEADER_MAGIC).unwrap();
+
+    let loader = {
+        let path = path.clone();
+        thread::spawn(move || Engine::load(&path).map(|engine| engine.stats().compact_threshold))
+    };
+    thread::sleep(Duration::from_millis(50));
+    assert!(!loader.is_finished());
+
+    initializer.write_all(&777u64.to_le_bytes()).unwrap();
+    initializer.write_all(&0u64.to_le_bytes()).unwrap();
+    initializer.write_all(&0u64.to_le_bytes()).unwrap();
+    initializer.unlock().unwrap();
+    assert_eq!(loader.join().unwrap().unwrap(), 777);
+}
+
+// ==================== Disk Forecast ====================
+
+#[test]
+fn test_peak_disk_forecast_brackets_compaction_usage() {
+    let dir = tempfile::tempdir().unwrap();
+    let storage = common::InstrumentedStorage::default();
+    let options = Options {
+        storage: Arc::new(storage.clone()),
+        ..Options::default()
+    };
+    let engine = Engine::load_with_options(dir.path().join("data.db"), options).unwrap();
+    fill_with_garbage(&engine);
+
+    let forecast = engine.peak_disk_forecast().unwrap();
+    assert_eq!(forecast.current, engine.stats().file_size);
+    assert_eq!(forecast.scratch_on_other_filesystem, None);
+    assert!(forecast.post_compaction_estimate < forecast.current);
+
+    engine.compact().unwrap();
+    let peak = storage.probe.peak_bytes();
+    assert!(forecast.current < peak, "{} < {}", forecast.current, peak);
+    assert!(peak <= forecast.peak_during_compaction);
+    assert_eq!(engine.stats().file_size, forecast.post_compaction_estimate);
+
+    let forecast = engine.peak_disk_forecast().unwrap();
+    assert_eq!(forecast.post_compaction_estimate, forecast.current);
+}
+
+#[test]
+fn test_peak_disk_forecast_counts_cross_filesystem_copy() {
+    let dir = tempfile::tempdir().unwrap();
+    let scratch = tempfile::tempdir().unwrap();
+    let storage = common::InstrumentedStorage::default();
+    let engine = engine_with(
+        dir.path().join("data.db"),
+        Options {
+            compaction_dir: Some(scratch.path().to_path_buf()),
+            storage: Arc::new(storage.clone()),
+            ..Options::default()
+        },
+    );
+    storage.probe.cross_device.store(true, Ordering::SeqCst);
+    fill_with_garbage(&engine);
+
+    let forecast = engine.peak_disk_forecast().unwrap();
+    assert_eq!(forecast.scratch_on_other_filesystem, Some(true));
+    assert_eq!(
+        forecast.peak_during_compaction,
+        forecast.current + 2 * forecast.post_compaction_estimate
+    );
+
+    engine.compact().unwrap();
+    // The output briefly exists both in the scratch dir and beside the
+    // data file.
+    let peak = storage.probe.peak_bytes();
+    assert!(peak > forecast.current + forecast.post_compaction_estimate);
+    assert!(peak <= forecast.peak_during_compaction);
+
+    storage.probe.cross_device.store(false, Ordering::SeqCst);
+    assert_eq!(
+        engine
+            .peak_disk_forecast()
+            .unwrap()
+            .scratch_on_other_filesystem,
+        Some(false)
+    );
+}
+
+// ==================== Varint Framing ====================
+
+/// Writes a KVS2 file: the v2 magic, `threshold`, then `entries` with fixed
+/// 8-byte length prefixes.
+fn write_v2_file(path: &std::path::Path, threshold: u64, entries: &[DataFileEntry]) {
+    use std::io::Write;
+    let mut f = fs::File::create(path).unwrap();
+    f.write_all(&FILE_HEADER_MAGIC_V2).unwrap();
+    f.write_all(&threshold.to_le_bytes()).unwrap();
+    for entry in entries {
+        let data = wincode::serialize(&DataFileEntryV2::from(entry)).unwrap();
+        f.write_all(&(data.len() as u64).to_le_bytes()).unwrap();
+        f.write_all(&data).unwrap();
+    }
+}
+
+fn read_magic(path: &std::path::Path) -> [u8; 4] {
+    let mut magic = [0u8; 4];
+    fs::File::open(path)
+        .unwrap()
+        .read_exact(&mut magic)
+        .unwrap();
+    magic
+}
+
+#[test]
+fn test_v2_file_is_readable_writable_and_upgraded_by_compaction() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("v2.db");
+    write_v2_file(
+        &path,
+        DEFAULT_COMPACT_THRESHOLD,
+        &[
+            DataFileEntry::put(1, b"a".to_vec(), b"1".to_vec()),
+            DataFileEntry::put(2, b"b".to_vec(), vec![7u8; 300]),
+            DataFileEntry::tombstone(3, b"a".to_vec()),
+        ],
+    );
+
+    let engine = Engine::load(&path).unwrap();
+    assert_eq!(engine.get(b"a").unwrap(), None);
+    assert_eq!(engine.get(b"b").unwrap(), Some(vec![7u8; 300]));
+    engine.set(b"c", b"3").unwrap();
+    engine.soft_del(b"c").unwrap();
+    // Batches are pre-encoded in KVS3 framing.
+    let mut batch = WriteBatch::new();
+    batch.put(b"d", b"4");
+    assert!(engine.apply_batch(&batch).is_err());
+    drop(engine);
+
+    let engine = Engine::load(&path).unwrap();
+    assert_eq!(read_magic(&path), FILE_HEADER_MAGIC_V2);
+    assert_eq!(engine.get(b"c").unwrap(), None);
+    engine.restore(b"c").unwrap();
+
+    let estimate = engine.compaction_estimate();
+    engine.compact().unwrap();
+    assert_eq!(estimate.expected_size, fs::metadata(&path).unwrap().len());
+    assert_eq!(read_magic(&path), FILE_HEADER_MAGIC);
+
+    let mut batch = WriteBatch::new();
+    batch.put(b"d", b"4");
+    engine.apply_batch(&batch).unwrap();
+    drop(engine);
+
+    let engine = Engine::load(&path).unwrap();
+    assert_eq!(engine.get(b"a").unwrap(), None);
+    assert_eq!(engine.get(b"b").unwrap(), Some(vec![7u8; 300]));
+    assert_eq!(engine.get(b"c").unwrap(), Some(b"3".to_vec()));
+    assert_eq!(engine.get(b"d").unwrap(), Some(b"4".to_vec()));
+}
+
+#[test]
+fn test_varint_prefixes_of_every_width_survive_reload() {
+    let (engine, f) = temp_engine();
+    // One-, two-, three- and four-byte prefixes.
+    let sizes = [0usize, 100, 200, 20_000, 3_000_000];
+    for (i, size) in sizes.iter().enumerate() {
+        engine.set(&[b'k', i as u8], &vec![i as u8; *size]).unwrap();
+    }
+    drop(engine);
+
+    let engine = Engine::load(f.path()).unwrap();
+    for (i, size) in sizes.iter().enumerate() {
+        assert_eq!(
+            engine.get(&[b'k', i as u8]).unwrap(),
+            Some(vec![i as u8; *size])
+        );
+    }
+    assert_eq!(engine.verify().unwrap(), sizes.len() as u64);
+}
+
+#[test]
+fn test_varint_framing_saves_space_on_small_entries() {
+    const ENTRIES: u64 = 100_000;
+    let dir = tempfile::tempdir().unwrap();
+    let v2_path = dir.path().join("v2.db");
+    let v3_path = dir.path().join("v3.db");
+    // Auto-compaction would upgrade the v2 file, so keep it out of reach.
+    write_v2_file(&v2_path, u64::MAX, &[]);
+    write_header(&v3_path, u64::MAX);
+
+    let mut sizes = Vec::new();
+    for path in [&v2_path, &v3_path] {
+        let engine = Engine::load(path).unwrap();
+        for i in 0..ENTRIES {
+            engine.set(&i.to_be_bytes(), &(!i).to_be_bytes()).unwrap();
+        }
+        sizes.push(engine.stats().file_size);
+    }
+    let (v2_size, v3_size) = (sizes[0], sizes[1]);
+    assert_eq!(read_magic(&v2_path), FILE_HEADER_MAGIC_V2);
+    assert_eq!(read_magic(&v3_path), FILE_HEADER_MAGIC);
+
+    // Every record is under 128 bytes, so each prefix shrinks from 8 bytes
+    // to 1. The current header is 16 bytes longer, for the writer epoch
+    // and flags.
+    assert_eq!(v2_size - v3_size, ENTRIES * 7 - 16);
+    let saved = (v2_size - v3_size) as f64 / v2_size as f64;
+    assert!(saved > 0.1, "saved {:.1}%", saved * 100.0);
+}
+
+// ==================== Write Combining ====================
+
+#[test]
+fn test_combined_writes_on_disjoint_keys_all_land() {
+    let (engine, f) = temp_engine();
+    let engine = Arc::new(engine);
+    let barrier = Arc::new(Barrier::new(8));
+    let handles: Vec<_> = (0..8u32)
+        .map(|t| {
+            let engine = Arc::clone(&engine);
+            let barrier = Arc::clone(&barrier);
+            thread::spawn(move || {
+                barrier.wait();
+                for i in 0..500u32 {
+                    let key = format!("t{}_k{}", t, i);
+                    engine.set(key.as_bytes(), &i.to_le_bytes()).unwrap();
+                    if i % 5 == 0 {
+                        engine.del(key.as_bytes()).unwrap();
+                    }
+                }
+            })
+        })
+        .collect();
+    for h in handles {
+        h.join().unwrap();
+    }
+    let records = engine.verify().unwrap();
+    assert_eq!(records, 8 * (500 + 100));
+    drop(engine);
+
+    let engine = Engine::load(f.path()).unwrap();
+    for t in 0..8u32 {
+        for i in 0..500u32 {
+            let expected = (i % 5 != 0).then(|| i.to_le_bytes().to_vec());
+            let key = format!("t{}_k{}", t, i);
+            assert_eq!(engine.get(key.as_bytes()).unwrap(), expected);
+        }
+    }
+}
+
+#[test]
+fn test_combined_writes_refuse_only_keys_past_index_limit() {
+    let f = NamedTempFile::new().unwrap();
+    let options = Options {
+        max_index_entries: Some(100),
+        ..Options::default()
+    };
+    let engine = Arc::new(Engine::load_with_options(f.path(), options).unwrap());
+    let barrier = Arc::new(Barrier::new(8));
+    let handles: Vec<_> = (0..8u32)
+        .map(|t| {
+            let engine = Arc::clone(&engine);
+            let barrier = Arc::clone(&barrier);
+            thread::spawn(move || {
+                barrier.wait();
+                let mut stored = 0;
+                for i in 0..50u32 {
+                    match engine.set(format!("t{}_k{}", t, i).as_bytes(), b"v") {
+                        Ok(()) => stored += 1,
+                        Err(e) => assert_eq!(
+                            EngineError::from_io(&e),
+                            Some(&EngineError::IndexFull(
+                                "101 live keys would exceed max_index_entries (100)".to_string()
+                            ))
+                        ),
+                    }
+                }
+                stored
+            })
+        })
+        .collect();
+    let stored: u32 = handles.into_iter().map(|h| h.join().unwrap()).sum();
+    assert_eq!(stored, 100);
+    assert_eq!(engine.stats().live_keys, 100);
+}
+
+// ==================== Open Modes ====================
+
+fn open_with(path: &std::path::Path, mode: OpenMode) -> std::io::Result<Engine> {
+    let options = Options {
+        open_mode: mode,
+        dedup_min_value_len: Some(100),
+        ..Options::default()
+    };
+    Engine::load_with_options(path, options)
+}
+
+#[test]
+fn test_fast_open_builds_the_same_index() {
+    let f = NamedTempFile::new().unwrap();
+    {
+        let engine = open_with(f.path(), OpenMode::Standard).unwrap();
+        engine.set(b"small", b"v").unwrap();
+        engine.set(b"large", &[7u8; 5000]).unwrap();
+        engine.set(b"shared-1", &[9u8; 300]).unwrap();
+        engine.set(b"shared-2", &[9u8; 300]).unwrap();
+        engine.set(b"gone", b"x").unwrap();
+        engine.del(b"gone").unwrap();
+        engine.set(b"soft", &[1u8; 80]).unwrap();
+        engine.soft_del(b"soft").unwrap();
+        let mut batch = WriteBatch::new();
+        batch
+            .put_with_ttl(b"live-ttl", &[2u8; 90], Duration::from_secs(3600))
+            .put_with_ttl(b"dead-ttl", b"x", Duration::ZERO)
+            .delete(b"small");
+        engine.apply_batch(&batch).unwrap();
+    }
+
+    let standard = open_with(f.path(), OpenMode::Standard).unwrap();
+    let fast = open_with(f.path(), OpenMode::Fast).unwrap();
+    assert_eq!(fast.load_report().mode, OpenMode::Fast);
+    assert_eq!(fast.load_report().replay, standard.load_report().replay);
+    assert_eq!(fast.stats(), standard.stats());
+    assert_eq!(fast.scan_deleted(), standard.scan_deleted());
+    for key in [
+        &b"small"[..],
+        b"large",
+        b"shared-1",
+        b"shared-2",
+        b"gone",
+        b"soft",
+        b"live-ttl",
+        b"dead-ttl",
+    ] {
+        assert_eq!(fast.get(key).unwrap(), standard.get(key).unwrap());
+    }
+    assert_eq!(fast.get(b"live-ttl").unwrap(), Some(vec![2u8; 90]));
+    fast.restore(b"soft").unwrap();
+    assert_eq!(fast.get(b"soft").unwrap(), Some(vec![1u8; 80]));
+}
+
+#[test]
+fn test_corrupted_value_caught_by_verify_but_not_fast() {
+    let f = NamedTempFile::new().unwrap();
+    let fixture = StoreFixture::builder()
+        .keys(3)
+        .plain()
+        .build(f.path())
+        .unwrap();
+    let [a, b, c] = [0, 1, 2].map(StoreFixture::key);
+    let value = |key: &Vec<u8>| fixture.live().get(key).cloned();
+    // Point b's value length past the end of its record.
+    let value_len_at = fixture.value_len_offset(&b).unwrap();
+    Corruption::Overwrite(value_len_at, 1000u64.to_le_bytes().to_vec())
+        .apply(f.path())
+        .unwrap();
+
+    assert!(Engine::load(f.path()).is_err());
+
+    let fast = open_with(f.path(), OpenMode::Fast).unwrap();
+    assert_eq!(fast.load_report().corrupt_records, 0);
+    assert_eq!(fast.corruption(), None);
+    assert_eq!(fast.get(&a).unwrap(), value(&a));
+    assert_eq!(fast.get(&c).unwrap(), value(&c));
+    // The read path decodes the record and catches it.
+    assert!(fast.get(&b).is_err());
+    assert!(fast.corruption().is_some());
+    assert!(fast.verify().is_err());
+    drop(fast);
+
+    let verified = open_with(f.path(), OpenMode::Verify).unwrap();
+    let report = verified.load_report();
+    assert_eq!(report.mode, OpenMode::Verify);
+    assert_eq!(report.corrupt_records, 1);
+    assert_eq!(report.replay.entries_scanned, 2);
+    assert!(verified.corruption().unwrap().contains("record at offset"));
+    assert_eq!(verified.get(&a).unwrap(), value(&a));
+    assert_eq!(verified.get(&b).unwrap(), None);
+    assert_eq!(verified.get(&c).unwrap(), value(&c));
+}
+
+#[test]
+fn test_bad_length_prefix_before_later_records_fails_every_mode() {
+    for prefix in [
+        // A varint that overflows, a zero length, and one past EOF.
+        vec![0xFF; 10],
+        vec![0],
+        vec![0xFF, 0xFF, 0xFF, 0x7F],
+    ] {
+        let f = NamedTempFile::new().unwrap();
+        let fixture = StoreFixture::builder()
+            .keys(5)
+            .plain()
+            .build(f.path())
+            .unwrap();
+        let at = fixture.prefix_offset(&StoreFixture::key(1)).unwrap();
+        Corruption::Overwrite(at, prefix).apply(f.path()).unwrap();
+
+        for mode in [OpenMode::Fast, OpenMode::Standard, OpenMode::Verify] {
+            let err = open_with(f.path(), mode).unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidData, "{:?}", mode);
+        }
+        // Nothing was truncated.
+        assert_eq!(fs::metadata(f.path()).unwrap().len(), fixture.log_len());
+    }
+}
+
+// ==================== Sorted Key Export ====================
+
+fn parse_exported_keys(mut bytes: &[u8]) -> Vec<Vec<u8>> {
+    let mut keys = Vec::new();
+    while !bytes.is_empty() {
+        let len = u64::from_le_bytes(bytes[..8].try_into().unwrap()) as usize;
+        keys.push(bytes[8..8 + len].to_vec());
+        bytes = &bytes[8 + len..];
+    }
+    keys
+}
+
+fn dir_entries(dir: &std::path::Path) -> Vec<String> {
+    let mut names: Vec<String> = fs::read_dir(dir)
+        .unwrap()
+        .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+        .collect();
+    names.sort();
+    names
+}
+
+#[test]
+fn test_export_sorted_keys_with_tiny_chunks() {
+    const KEYS: u64 = 300_000;
+    let dir = tempfile::tempdir().unwrap();
+    let engine = Engine::load(dir.path().join("data.db")).unwrap();
+    let mut rng = XorShift(0x5EED);
+    let mut expected = Vec::with_capacity(KEYS as usize);
+    let mut batch = WriteBatch::new();
+    for i in 0..KEYS {
+        // Scrambled, variable-length keys so insertion order says nothing.
+        let mut key = (rng.next() % 1_000_000_007).to_string().into_bytes();
+        key.extend_from_slice(&i.to_le_bytes()[..(i % 3) as usize + 1]);
+        batch.put(&key, b"");
+        expected.push(key);
+        if batch.len() == 1000 {
+            engine.apply_batch(&batch).unwrap();
+            batch.clear();
+        }
+    }
+    expected.sort();
+    expected.dedup();
+
+    let mut out = Vec::new();
+    let mut reports = Vec::new();
+    let written = engine
+        .export_sorted_keys_with_progress(&mut out, 4096, |p| reports.push(*p))
+        .unwrap();
+
+    assert_eq!(written, expected.len() as u64);
+    assert_eq!(parse_exported_keys(&out), expected);
+    let last = reports.last().unwrap();
+    assert_eq!(last.keys_spilled, written);
+    assert_eq!(last.keys_written, written);
+    assert!(last.runs > 64, "{} runs", last.runs);
+    assert!(
+        reports
+            .windows(2)
+            .all(|w| w[0].keys_written <= w[1].keys_written)
+    );
+    assert_eq!(dir_entries(dir.path()), vec!["data.db".to_string()]);
+}
+
+#[test]
+fn test_export_sorted_keys_cleans_up_after_a_failed_write() {
+    struct FailingWriter(usize);
+    impl std::io::Write for FailingWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            if self.0 < buf.len() {
+                return Err(std::io::Error::other("sink full"));
+            }
+            self.0 -= buf.len();
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let dir = tempfile::tempdir().unwrap();
+    let engine = Engine::load(dir.path().join("data.db")).unwrap();
+    for i in 0..2000u32 {
+        engine.set(format!("key-{}", i).as_bytes(), b"v").unwrap();
+    }
+
+    let err = engine
+        .export_sorted_keys(FailingWriter(1000), 512)
+        .unwrap_err();
+    assert_eq!(err.to_string(), "sink full");
+    assert_eq!(dir_entries(dir.path()), vec!["data.db".to_string()]);
+    assert!(engine.export_sorted_keys(Vec::new(), 0).is_err());
+}
+
+#[test]
+fn test_exports_of_an_unchanged_store_are_byte_identical() {
+    let mut exports = Vec::new();
+    for ordered_index in [false, true] {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data.db");
+        let options = || Options {
+            ordered_index,
+            ..Options::default()
+        };
+        let engine = Engine::load_with_options(&path, options()).unwrap();
+        let mut rng = XorShift(0xD1FF);
+        for i in 0..5000u64 {
+            let key = format!("{:x}-{}", rng.next(), i);
+            engine.set(key.as_bytes(), &i.to_le_bytes()).unwrap();
+        }
+        engine.set(b"gone", b"x").unwrap();
+        engine.del(b"gone").unwrap();
+
+        let export = |engine: &Engine| {
+            let mut keys = Vec::new();
+            engine.export_sorted_keys(&mut keys, 4096).unwrap();
+            let mut dump = Vec::new();
+            engine.dump(&mut dump).unwrap();
+            (keys, dump)
+        };
+        let first = export(&engine);
+        // A reload rebuilds the index, so a hash index iterates differently.
+        drop(engine);
+        let engine = Engine::load_with_options(&path, options()).unwrap();
+        let second = export(&engine);
+        assert!(first == second, "ordered_index: {}", ordered_index);
+        assert_eq!(dir_entries(dir.path()), vec!["data.db".to_string()]);
+        exports.push(first.0);
+    }
+    assert_eq!(exports[0], exports[1]);
+}
+
+// ==================== Conflict Policy ====================
+
+#[test]
+fn test_always_accept_takes_older_writes() {
+    let f = NamedTempFile::new().unwrap();
+    let engine = engine_with(
+        f.path(),
+        Options {
+            conflict_policy: ConflictPolicy::AlwaysAccept,
+            history_depth: 4,
+            ..Options::default()
+        },
+    );
+    assert!(engine.set_with_tstamp(b"k", b"new", 200).unwrap());
+    assert!(engine.set_with_tstamp(b"k", b"old", 100).unwrap());
+    assert_eq!(engine.get(b"k").unwrap(), Some(b"old".to_vec()));
+    assert!(engine.set_with_tstamp(b"k", b"tie", 100).unwrap());
+    assert_eq!(engine.get(b"k").unwrap(), Some(b"tie".to_vec()));
+}
+
+#[test]
+fn test_reject_older_refuses_stale_writes() {
+    let f = NamedTempFile::new().unwrap();
+    let engine = engine_with(
+        f.path(),
+        Options {
+            conflict_policy: ConflictPolicy::RejectOlder,
+            history_depth: 4,
+            ..Options::default()
+        },
+    );
+    assert!(engine.set_with_tstamp(b"k", b"new", 200).unwrap());
+
+    let err = engine.set_with_tstamp(b"k", b"old", 100).unwrap_err();
+    assert_eq!(
+        EngineError::from_io(&err),
+        Some(&EngineError::StaleWrite {
+            stored: 200,
+            attempted: 100
+        })
+    );
+    assert_eq!(engine.get(b"k").unwrap(), Some(b"new".to_vec()));
+
+    // Ties and newer writes go through.
+    assert!(engine.set_with_tstamp(b"k", b"tie", 200).unwrap());
+    assert_eq!(engine.get(b"k").unwrap(), Some(b"tie".to_vec()));
+    assert!(engine.set_with_tstamp(b"k", b"newer", 300).unwrap());
+    assert_eq!(engine.get(b"k").unwrap(), Some(b"newer".to_vec()));
+
+    // A deleted key has nothing to conflict with.
+    engine.del(b"k").unwrap();
+    assert!(engine.set_with_tstamp(b"k", b"older", 50).unwrap());
+    assert_eq!(engine.get(b"k").unwrap(), Some(b"older".to_vec()));
+}
+
+#[test]
+fn test_keep_newest_logs_stale_writes_as_history() {
+    let f = NamedTempFile::new().unwrap();
+    {
+        let engine = engine_with(
+            f.path(),
+            Options {
+                conflict_policy: ConflictPolicy::KeepNewest,
+                history_depth: 4,
+                ..Options::default()
+            },
+        );
+        assert!(engine.set_with_tstamp(b"k", b"new", 200).unwrap());
+        assert!(!engine.set_with_tstamp(b"k", b"old", 100).unwrap());
+        assert_eq!(engine.get(b"k").unwrap(), Some(b"new".to_vec()));
+        assert_eq!(
+            engine.previous_versions(b"k", 4).unwrap(),
+            vec![b"old".to_vec()]
+        );
+
+        assert!(engine.set_with_tstamp(b"k", b"tie", 200).unwrap());
+        assert_eq!(engine.get(b"k").unwrap(), Some(b"tie".to_vec()));
+    }
+
+    // The outcome survives a reload, and a compaction and reload.
+    let engine = engine_with(
+        f.path(),
+        Options {
+            conflict_policy: ConflictPolicy::KeepNewest,
+            history_depth: 4,
+            ..Options::default()
+        },
+    );
+    assert_eq!(engine.get(b"k").unwrap(), Some(b"tie".to_vec()));
+    assert_eq!(
+        engine.previous_versions(b"k", 4).unwrap(),
+        vec![b"new".to_vec(), b"old".to_vec()]
+    );
+    engine.compact().unwrap();
+    drop(engine);
+    let engine = engine_with(
+        f.path(),
+        Options {
+            conflict_policy: ConflictPolicy::KeepNewest,
+            history_depth: 4,
+            ..Options::default()
+        },
+    );
+    assert_eq!(engine.get(b"k").unwrap(), Some(b"tie".to_vec()));
+    assert_eq!(
+        engine.previous_versions(b"k", 4).unwrap(),
+        vec![b"new".to_vec(), b"old".to_vec()]
+    );
+}
+
+#[test]
+fn test_load_dump_keeps_newer_values_by_default() {
+    let source_file = NamedTempFile::new().unwrap();
+    let source = Engine::load(source_file.path()).unwrap();
+    source.set_with_tstamp(b"a", b"dumped", 100).unwrap();
+    source.set_with_tstamp(b"b", b"dumped", 100).unwrap();
+    let mut buf = Vec::new();
+    source.dump(&mut buf).unwrap();
+
+    let (target, _f) = temp_engine();
+    target.set_with_tstamp(b"a", b"local", 200).unwrap();
+    target.set_with_tstamp(b"b", b"local", 50).unwrap();
+    assert_eq!(target.load_dump(buf.as_slice()).unwrap(), 2);
+    assert_eq!(target.get(b"a").unwrap(), Some(b"local".to_vec()));
+    assert_eq!(target.get(b"b").unwrap(), Some(b"dumped".to_vec()));
+}
+
+// ==================== IO Retry ====================
+
+#[test]
+fn test_transient_io_errors_are_retried_until_they_clear() {
+    let f = NamedTempFile::new().unwrap();
+    let storage = common::InstrumentedStorage::default();
+    let engine = engine_with(
+        f.path(),
+        Options {
+            io_retry: Some(RetryPolicy {
+                max_attempts: 4,
+                backoff: Duration::from_millis(1),
+                ..RetryPolicy::default()
+            }),
+            storage: Arc::new(storage.clone()),
+            ..Options::default()
+        },
+    );
+
+    storage
+        .probe
+        .inject(Fault::Write, 3, std::io::ErrorKind::WouldBlock);
+    engine.set(b"k", b"v").unwrap();
+    assert_eq!(engine.stats().io_retries, 3);
+
+    storage
+        .probe
+        .inject(Fault::Read, 2, std::io::ErrorKind::TimedOut);
+    assert_eq!(engine.get(b"k").unwrap(), Some(b"v".to_vec()));
+    storage
+        .probe
+        .inject(Fault::Sync, 1, std::io::ErrorKind::StaleNetworkFileHandle);
+    engine.sync().unwrap();
+
+    let stats = engine.stats();
+    assert_eq!(stats.io_retries, 6);
+    assert_eq!(stats.io_retry_give_ups, 0);
+}
+
+#[test]
+fn test_io_retry_gives_up_after_max_attempts_and_on_other_errors() {
+    let f = NamedTempFile::new().unwrap();
+    let storage = common::InstrumentedStorage::default();
+    let engine = engine_with(
+        f.path(),
+        Options {
+            io_retry: Some(RetryPolicy {
+                max_attempts: 4,
+                backoff: Duration::from_millis(1),
+                ..RetryPolicy::default()
+            }),
+            storage: Arc::new(storage.clone()),
+            ..Options::default()
+        },
+    );
+
+    storage
+        .probe
+        .inject(Fault::Write, 4, std::io::ErrorKind::WouldBlock);
+    let err = engine.set(b"k", b"v").unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::WouldBlock);
+    let stats = engine.stats();
+    assert_eq!((stats.io_retries, stats.io_retry_give_ups), (3, 1));
+
+    // Not on the allow-list: no retry at all.
+    storage
+        .probe
+        .inject(Fault::Write, 1, std::io::ErrorKind::PermissionDenied);
+    let err = engine.set(b"k", b"v").unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::PermissionDenied);
+    assert_eq!(engine.stats().io_retries, 3);
+
+    engine.set(b"k", b"v").unwrap();
+    assert_eq!(engine.get(b"k").unwrap(), Some(b"v".to_vec()));
+
+    // Without a policy the first failure is final.
+    let g = NamedTempFile::new().unwrap();
+    let storage = common::InstrumentedStorage::default();
+    let options = Options {
+        storage: Arc::new(storage.clone()),
+        ..Options::default()
+    };
+    let plain = Engine::load_with_options(g.path(), options).unwrap();
+    storage
+        .probe
+        .inject(Fault::Write, 1, std::io::ErrorKind::WouldBlock);
+    assert!(plain.set(b"k", b"v").is_err());
+    assert_eq!(plain.stats().io_retries, 0);
+}
+
+#[test]
+fn test_torn_write_is_not_retried() {
+    let f = NamedTempFile::new().unwrap();
+    {
+        let storage = common::InstrumentedStorage::default();
+        let engine = engine_with(
+            f.path(),
+            Options {
+                io_retry: Some(RetryPolicy {
+                    max_attempts: 4,
+                    backoff: Duration::from_millis(1),
+                    ..RetryPolicy::default()
+                }),
+                storage: Arc::new(storage.clone()),
+                ..Options::default()
+            },
+        );
+        engine.set(b"before", b"1").unwrap();
+        storage.probe.tear_next_write.store(true, Ordering::SeqCst);
+        let err = engine.set(b"torn", &[7u8; 200]).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::WouldBlock);
+        let stats = engine.stats();
+        assert_eq!((stats.io_retries, stats.io_retry_give_ups), (0, 0));
+        assert_eq!(engine.get(b"torn").unwrap(), None);
+
+        // The next append overwrites the partial record.
+        engine.set(b"after", b"2").unwrap();
+    }
+    let engine = Engine::load(f.path()).unwrap();
+    assert_eq!(engine.get(b"before").unwrap(), Some(b"1".to_vec()));
+    assert_eq!(engine.get(b"torn").unwrap(), None);
+    assert_eq!(engine.get(b"after").unwrap(), Some(b"2".to_vec()));
+}
+
+// ==================== Paranoid Reads ====================
+
+#[test]
+fn test_paranoid_reads_replace_stale_pooled_handles() {
+    let f = NamedTempFile::new().unwrap();
+    let storage = common::InstrumentedStorage::default();
+    let engine = engine_with(
+        f.path(),
+        Options {
+            paranoid_reads: Some(Duration::from_millis(20)),
+            storage: Arc::new(storage.clone()),
+            ..Options::default()
+        },
+    );
+    engine.set(b"k", b"v").unwrap();
+    assert_eq!(engine.get(b"k").unwrap(), Some(b"v".to_vec()));
+
+    storage.probe.failover();
+    thread::sleep(Duration::from_millis(30));
+    for _ in 0..3 {
+        assert_eq!(engine.get(b"k").unwrap(), Some(b"v".to_vec()));
+    }
+    // One stale handle was replaced; the fresh one went back to the pool
+    // and was reused while still recently active.
+    assert_eq!(engine.stats().stale_readers, 1);
+    assert!(engine.corruption().is_none());
+}
+
+#[test]
+fn test_recently_used_handles_skip_revalidation() {
+    let f = NamedTempFile::new().unwrap();
+    let storage = common::InstrumentedStorage::default();
+    let engine = engine_with(
+        f.path(),
+        Options {
+            paranoid_reads: Some(Duration::from_secs(3600)),
+            storage: Arc::new(storage.clone()),
+            ..Options::default()
+        },
+    );
+    engine.set(b"k", b"v").unwrap();
+    storage.probe.reset();
+    engine.get(b"k").unwrap();
+    // Just the record read, no header read.
+    assert_eq!(storage.probe.reads(), 1);
+
+    // Without revalidation a stale handle serves stale bytes.
+    storage.probe.failover();
+    assert_ne!(engine.get(b"k").ok().flatten(), Some(b"v".to_vec()));
+    assert_eq!(engine.stats().stale_readers, 0);
+}
+
+// ==================== Maintenance Mode ====================
+
+fn assert_read_only(err: std::io::Error) {
+    assert_eq!(EngineError::from_io(&err), Some(&EngineError::ReadOnlyMode));
+}
+
+#[test]
+fn test_read_only_mode_refuses_writes_until_lifted() {
+    let (engine, _f) = temp_engine();
+    engine.set(b"k", b"v").unwrap();
+    engine.set_read_only(true);
+    assert!(engine.is_read_only());
+
+    assert_read_only(engine.set(b"k", b"v2").unwrap_err());
+    assert_read_only(engine.del(b"k").unwrap_err());
+    assert_read_only(engine.compact().unwrap_err());
+    assert_eq!(engine.get(b"k").unwrap(), Some(b"v".to_vec()));
+
+    engine.set_read_only(false);
+    engine.set(b"k", b"v2").unwrap();
+    engine.compact().unwrap();
+    assert_eq!(engine.get(b"k").unwrap(), Some(b"v2".to_vec()));
+}
+
+#[test]
+fn test_no_write_lands_after_drain_writes() {
+    let (engine, _f) = temp_engine();
+    let engine = Arc::new(engine);
+    let stop = Arc::new(AtomicBool::new(false));
+
+    let writers: Vec<_> = (0..4)
+        .map(|t| {
+            let (engine, stop) = (Arc::clone(&engine), Arc::clone(&stop));
+            thread::spawn(move || {
+                let mut acked = Vec::new();
+                let mut refused = 0;
+                for i in 0.. {
+                    if stop.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    let key = format!("w{}-{}", t, i).into_bytes();
+                    match engine.set(&key, b"v") {
+                        Ok(()) => acked.push(key),
+                        Err(e) => {
+                            assert_read_only(e);
+                            refused += 1;
+                        }
+                    }
+                }
+                (acked, refused)
+            })
+        })
+        .collect();
+
+    thread::sleep(Duration::from_millis(20));
+    engine.set_read_only(true);
+    assert!(engine.drain_writes(Duration::from_secs(5)));
+    let drained = engine.stats();
+    thread::sleep(Duration::from_millis(20));
+    stop.store(true, Ordering::SeqCst);
+
+    let mut acked = 0;
+    let mut refused = 0;
+    for writer in writers {
+        let (keys, r) = writer.join().unwrap();
+        for key in &keys {
+            assert_eq!(engine.get(key).unwrap(), Some(b"v".to_vec()));
+        }
+        acked += keys.len();
+        refused += r;
+    }
+    // Every acknowledged write landed before the drain returned, and
+    // nothing reached the log after it.
+    let after = engine.stats();
+    assert_eq!(after.file_size, drained.file_size);
+    assert_eq!(after.live_keys, acked);
+    assert!(acked > 0 && refused > 0);
+
+    engine.set_read_only(false);
+    engine.set(b"after", b"v").unwrap();
+}
+
+// ==================== Validation ====================
+
+fn json_validator(calls: Arc<AtomicU64>) -> Validator {
+    Validator::new(move |_key, value| {
+        calls.fetch_add(1, Ordering::SeqCst);
+        serde_json::from_slice::<serde_json::Value>(value)
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    })
+}
+
+fn assert_validation_failed(err: std::io::Error) {
+    assert!(
+        matches!(
+            EngineError::from_io(&err),
+            Some(EngineError::ValidationFailed(_))
+        ),
+        "{}",
+        err
+    );
+}
+
+/// Whether `needle` appears anywhere in the raw bytes of the log.
+fn log_contains(path: &std::path::Path, needle: &[u8]) -> bool {
+    fs::read(path)
+        .unwrap()
+        .windows(needle.len())
+        .any(|window| window == needle)
+}
+
+const GARBAGE: &[u8] = b"{not json: GARBAGE-MARK";
+
+#[test]
+fn test_validator_keeps_bad_values_out_of_the_log() {
+    let f = NamedTempFile::new().unwrap();
+    let calls = Arc::new(AtomicU64::new(0));
+    let options = Options {
+        validate: Some(json_validator(Arc::clone(&calls))),
+        ..Options::default()
+    };
+    let engine = Engine::load_with_options(f.path(), options).unwrap();
+
+    engine.set(b"good", br#"{"a": 1}"#).unwrap();
+    assert_validation_failed(engine.set(b"bad", GARBAGE).unwrap_err());
+    assert_validation_failed(engine.set_with_tstamp(b"bad", GARBAGE, 1).unwrap_err());
+    assert_validation_failed(
+        engine
+            .update_many(&[b"good", b"bad"], |_| {
+                vec![Some(b"[1, 2]".to_vec()), Some(GARBAGE.to_vec())]
+            })
+            .unwrap_err(),
+    );
+    let mut batch = WriteBatch::new();
+    batch
+        .put(b"also-good", b"true")
+        .put_with_ttl(b"bad", GARBAGE, Duration::from_secs(60));
+    assert_validation_failed(engine.apply_batch(&batch).unwrap_err());
+
+    // Deletes are never validated.
+    let before = calls.load(Ordering::SeqCst);
+    engine.del(b"good").unwrap();
+    assert_eq!(calls.load(Ordering::SeqCst), before);
+
+    assert!(!log_contains(f.path(), b"GARBAGE-MARK"));
+    assert_eq!(engine.get(b"bad").unwrap(), None);
+    assert_eq!(engine.get(b"also-good").unwrap(), None);
+    assert_eq!(engine.stats().live_keys, 0);
+}
+
+#[test]
+fn test_bucket_validators_override_the_store_validator() {
+    let f = NamedTempFile::new().unwrap();
+    let calls = Arc::new(AtomicU64::new(0));
+    let options = Options {
+        bucket_validators: vec![(b"docs".to_vec(), json_validator(Arc::clone(&calls)))],
+        ..Options::default()
+    };
+    let engine = Engine::load_with_options(f.path(), options).unwrap();
+
+    let docs = engine.bucket(b"docs").unwrap();
+    docs.set(b"d", br#"{"ok": true}"#).unwrap();
+    assert_validation_failed(docs.set(b"d", GARBAGE).unwrap_err());
+    assert!(!log_contains(f.path(), b"GARBAGE-MARK"));
+
+    // Other buckets and raw keys have no validator here.
+    engine.bucket(b"blobs").unwrap().set(b"b", GARBAGE).unwrap();
+    engine.set(b"raw", GARBAGE).unwrap();
+    assert_eq!(calls.load(Ordering::SeqCst), 2);
+    assert_eq!(docs.get(b"d").unwrap(), Some(br#"{"ok": true}"#.to_vec()));
+}
+
+// ==================== Fencing ====================
+
+fn header_epoch(engine: &Engine) -> Option<u64> {
+    engine.describe_format().unwrap().writer_epoch
+}
+
+fn assert_fenced(err: std::io::Error, epoch: u64, current: u64) {
+    assert_eq!(
+        EngineError::from_io(&err),
+        Some(&EngineError::Fenced { epoch, current })
+    );
+}
+
+#[test]
+fn test_new_writer_fences_the_old_one() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("data.db");
+    let old = engine_with(
+        &path,
+        Options {
+            fencing: Some(Duration::ZERO),
+            ..Options::default()
+        },
+    );
+    old.set(b"k", b"old").unwrap();
+    assert_eq!(header_epoch(&old), Some(1));
+
+    let new = engine_with(
+        &path,
+        Options {
+            fencing: Some(Duration::ZERO),
+            ..Options::default()
+        },
+    );
+    assert_eq!(header_epoch(&new), Some(2));
+    assert_fenced(old.set(b"k", b"stale").unwrap_err(), 1, 2);
+    assert_fenced(old.del(b"k").unwrap_err(), 1, 2);
+    // Reads are not fenced.
+    assert_eq!(old.get(b"k").unwrap(), Some(b"old".to_vec()));
+
+    new.set(b"k", b"new").unwrap();
+    // Loading without fencing claims nothing.
+    let reader = Engine::load(&path).unwrap();
+    assert_eq!(reader.get(b"k").unwrap(), Some(b"new".to_vec()));
+    assert_eq!(header_epoch(&reader), Some(2));
+    new.set(b"k", b"newer").unwrap();
+    new.compact().unwrap();
+    assert_eq!(header_epoch(&new), Some(2));
+    assert_eq!(new.get(b"k").unwrap(), Some(b"newer".to_vec()));
+}
+
+#[test]
+fn test_external_takeover_is_caught_by_the_next_check() {
+    use std::io::Write;
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("data.db");
+    let engine = engine_with(
+        &path,
+        Options {
+            fencing: Some(Duration::from_secs(3600)),
+            ..Options::default()
+        },
+    );
+    engine.set(b"k", b"v").unwrap();
+
+    // Another host bumps the epoch in the header.
+    let mut file = fs::OpenOptions::new().write(true).open(&path).unwrap();
+    file.seek(SeekFrom::Start(12)).unwrap();
+    file.write_all(&7u64.to_le_bytes()).unwrap();
+    drop(file);
+
+    // Between checks writes still go through; header writes always check.
+    engine.set(b"k2", b"v").unwrap();
+    assert_fenced(engine.set_compact_threshold(1 << 20).unwrap_err(), 1, 7);
+    assert_fenced(engine.set(b"k3", b"v").unwrap_err(), 1, 7);
+    assert_fenced(engine.compact().unwrap_err(), 1, 7);
+    assert_eq!(engine.get(b"k").unwrap(), Some(b"v".to_vec()));
+}
+
+#[test]
+fn test_load_flags_records_appended_by_a_fenced_writer() {
+    use std::io::Write;
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("data.db");
+    engine_with(
+        &path,
+        Options {
+            fencing: Some(Duration::ZERO),
+            ..Options::default()
+        },
+    )
+    .set(b"a", b"1")
+    .unwrap();
+    engine_with(
+        &path,
+        Options {
+            fencing: Some(Duration::ZERO),
+            ..Options::default()
+        },
+    )
+    .set(b"b", b"2")
+    .unwrap();
+    assert!(Engine::load(&path).unwrap().corruption().is_none());
+
+    // Epoch 1 appending after epoch 2 took over.
+    let mut file = fs::OpenOptions::new().append(true).open(&path).unwrap();
+    for entry in [
+        DataFileEntry::epoch(0, 1),
+        DataFileEntry::put(0, b"c".to_vec(), b"3".to_vec()),
+    ] {
+        let data = wincode::serialize(&DataFileEntryV2::from(&entry)).unwrap();
+        file.write_all(&[data.len() as u8]).unwrap();
+        file.write_all(&data).unwrap();
+    }
+    drop(file);
+
+    let engine = Engine::load(&path).unwrap();
+    assert!(engine.corruption().unwrap().contains("fenced writer"));
+    assert_eq!(engine.get(b"b").unwrap(), Some(b"2".to_vec()));
+}
+
+#[test]
+fn test_fencing_needs_a_current_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("v2.db");
+    write_v2_file(
+        &path,
+        DEFAULT_COMPACT_THRESHOLD,
+        &[DataFileEntry::put(1, b"a".to_vec(), b"1".to_vec())],
+    );
+    let err = Engine::load_with_options(
+        &path,
+        Options {
+            fencing: Some(Duration::ZERO),
+            ..Options::default()
+        },
+    )
+    .err()
+    .unwrap();
+    assert_eq!(err.kind(), std::io::ErrorKind::Unsupported);
+
+    Engine::load(&path).unwrap().compact().unwrap();
+    let engine = engine_with(
+        &path,
+        Options {
+            fencing: Some(Duration::ZERO),
+            ..Options::default()
+        },
+    );
+    assert_eq!(header_epoch(&engine), Some(1));
+    assert_eq!(engine.get(b"a").unwrap(), Some(b"1".to_vec()));
+}
+
+// ==================== Lock-Free Reads ====================
+
+/// Every value written under `key` starts with it, so a read served from
+/// the wrong record or the wrong file shows up as a mismatch.
+fn stamped(key: &[u8], round: u32) -> Vec<u8> {
+    let mut value = key.to_vec();
+    value.extend_from_slice(format!(":{}:", round).as_bytes());
+    value.resize(key.len() + 64, b'.');
+    value
+}
+
+fn stress_reads_during_compaction(options: Options) {
+    let file = NamedTempFile::new().unwrap();
+    let engine = Arc::new(Engine::load_with_options(file.path(), options).unwrap());
+    let keys: Vec<Vec<u8>> = (0..64).map(|i| format!("key{}", i).into_bytes()).collect();
+    for key in &keys {
+        engine.set(key, &stamped(key, 0)).unwrap();
+    }
+    let done = Arc::new(AtomicBool::new(false));
+    let reads = Arc::new(AtomicU64::new(0));
+
+    let mut handles = vec![];
+    for seed in 0..4u64 {
+        let (engine, keys, done, reads) = (
+            Arc::clone(&engine),
+            keys.clone(),
+            Arc::clone(&done),
+            Arc::clone(&reads),
+        );
+        handles.push(thread::spawn(move || {
+            let mut rng = XorShift(seed + 1);
+            while !done.load(Ordering::Relaxed) {
+                let key = &keys[rng.next() as usize % keys.len()];
+                if let Some(value) = engine.get(key).unwrap() {
+                    assert!(value.starts_with(key), "{:?} read {:?}", key, value);
+                    assert_eq!(value[key.len()], b':');
+                }
+                reads.fetch_add(1, Ordering::Relaxed);
+            }
+        }));
+    }
+    for seed in 0..2u64 {
+        let (engine, keys, done) = (Arc::clone(&engine), keys.clone(), Arc::clone(&done));
+        handles.push(thread::spawn(move || {
+            let mut rng = XorShift(seed + 100);
+            let mut round = 1;
+            while !done.load(Ordering::Relaxed) {
+                let key = &keys[rng.next() as usize % keys.len()];
+                match rng.next() % 4 {
+                    0 => engine.del(key).unwrap(),
+                    _ => engine.set(key, &stamped(key, round)).unwrap(),
+                }
+                round += 1;
+            }
+        }));
+    }
+
+    let mut compactions = 0;
+    // A worker only exits early by panicking.
+    while (compactions < 30 || reads.load(Ordering::Relaxed) < 10_000)
+        && !handles.iter().any(|h| h.is_finished())
+    {
+        engine.compact().unwrap();
+        compactions += 1;
+    }
+    done.store(true, Ordering::Relaxed);
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    assert_eq!(engine.corruption(), None);
+    assert!(engine.stats().compactions >= 30);
+    for key in &keys {
+        if let Some(value) = engine.get(key).unwrap() {
+            assert!(value.starts_with(key));
+        }
+    }
+    engine.verify().unwrap();
+    assert_eq!(engine.audit_caches().unwrap(), vec![]);
+    let live = engine.stats().live_keys;
+    drop(engine);
+    let engine = Engine::load(file.path()).unwrap();
+    assert_eq!(engine.stats().live_keys, live);
+}
+
+#[test]
+fn test_gets_stay_consistent_across_compaction_swaps() {
+    stress_reads_during_compaction(Options {
+        reader_pool_size: 2,
+        ..Options::default()
+    });
+}
+
+#[test]
+fn test_gets_of_deduplicated_values_stay_consistent_across_compaction_swaps() {
+    stress_reads_during_compaction(Options {
+        dedup_min_value_len: Some(1),
+        ..Options::default()
+    });
+}
+
+#[test]
+fn test_derived_index_structures_stay_consistent_across_compaction_swaps() {
+    stress_reads_during_compaction(Options {
+        ordered_index: true,
+        value_hashes: true,
+        tracked_prefixes: vec![b"key1".to_vec()],
+        history_depth: 2,
+        ..Options::default()
+    });
+}
+
+#[test]
+fn test_get_sees_every_acknowledged_write() {
+    let (engine, _f) = temp_engine();
+    let engine = Arc::new(engine);
+
+    // A write is published before `set` returns, so a reader told about it
+    // afterwards must see it, compactions or not.
+    let (tx, rx) = std::sync::mpsc::channel::<u32>();
+    let reader = {
+        let engine = Arc::clone(&engine);
+        thread::spawn(move || {
+            for i in rx {
+                let key = format!("k{}", i);
+                assert_eq!(
+                    engine.get(key.as_bytes()).unwrap(),
+                    Some(stamped(key.as_bytes(), i))
+                );
+            }
+        })
+    };
+    for i in 0..2000u32 {
+        let key = format!("k{}", i);
+        engine
+            .set(key.as_bytes(), &stamped(key.as_bytes(), i))
+            .unwrap();
+        tx.send(i).unwrap();
+        if i % 100 == 0 {
+            engine.compact().unwrap();
+        }
+    }
+    drop(tx);
+    reader.join().unwrap();
+}
+
+// ==================== Auto-Compaction Deadline ====================
+
+#[test]
+fn test_auto_compaction_past_deadline_is_abandoned() {
+    let storage = common::InstrumentedStorage::default();
+    let probe = Arc::clone(&storage.probe);
+    let file = NamedTempFile::new().unwrap();
+    let engine = Engine::load_with_options(
+        file.path(),
+        Options {
+            storage: Arc::new(storage),
+            auto_compact_deadline: Some(Duration::from_millis(50)),
+            ..Options::default()
+        },
+    )
+    .unwrap();
+    for round in 0..3 {
+        for i in 0..40 {
+            let value = format!("{}:{}", i, round).repeat(20);
+            engine
+                .set(format!("key{}", i).as_bytes(), value.as_bytes())
+                .unwrap();
+        }
+    }
+    let size = engine.stats().file_size;
+    engine.set_compact_threshold(size).unwrap();
+
+    // Every record copy now takes 10ms: the compaction this set trips
+    // cannot finish in time, so it gives up and the set goes through.
+    probe.set_read_delay(Duration::from_millis(10));
+    engine.set(b"trigger", b"1").unwrap();
+    probe.set_read_delay(Duration::ZERO);
+
+    let stats = engine.stats();
+    assert_eq!(stats.compactions, 0);
+    assert_eq!(stats.compactions_aborted, 1);
+    assert!(stats.file_size > size);
+    assert!(!file.path().with_extension("tmp").exists());
+    assert_eq!(engine.get(b"trigger").unwrap(), Some(b"1".to_vec()));
+    for i in 0..40 {
+        assert_eq!(
+            engine.get(format!("key{}", i).as_bytes()).unwrap(),
+            Some(format!("{}:2", i).repeat(20).into_bytes())
+        );
+    }
+
+    // An explicit compaction runs to completion however long it takes.
+    probe.set_read_delay(Duration::from_millis(10));
+    engine.compact().unwrap();
+    probe.set_read_delay(Duration::ZERO);
+    let stats = engine.stats();
+    assert_eq!((stats.compactions, stats.compactions_aborted), (1, 1));
+    assert!(stats.file_size < size);
+    drop(engine);
+    let engine = Engine::load(file.path()).unwrap();
+    assert_eq!(engine.stats().live_keys, 41);
+}
+
+// ==================== Minimum Compaction Interval ====================
+
+#[test]
+fn test_min_compaction_interval_caps_auto_compactions() {
+    let file = NamedTempFile::new().unwrap();
+    let clock = ManualClock::new();
+    let interval = Duration::from_secs(10);
+    let engine = Engine::load_with_options(
+        file.path(),
+        Options {
+            compact_threshold: Some(4096),
+            min_compaction_interval: Some(interval),
+            clock: clock.clone(),
+            ..Options::default()
+        },
+    )
+    .unwrap();
+    // Overwrites of one key: every record but the last is dead, so the
+    // size trigger trips again every few dozen writes.
+    let mut n = 0u32;
+    let mut overwrite = || {
+        n += 1;
+        engine.set(b"hot", &[n as u8; 100]).unwrap();
+    };
+
+    // With the clock standing still, one compaction and no more.
+    for _ in 0..2000 {
+        overwrite();
+    }
+    let stats = engine.stats();
+    assert_eq!(stats.compactions, 1);
+    assert!(stats.compactions_deferred > 1000);
+    assert!(stats.file_size > 100_000);
+
+    // With time passing, each one starts at least an interval after the
+    // last.
+    let mut elapsed = Duration::ZERO;
+    let mut last_start = Duration::ZERO;
+    let mut compactions = 1;
+    for _ in 0..160 {
+        clock.advance(interval / 8);
+        elapsed += interval / 8;
+        for _ in 0..50 {
+            overwrite();
+            let now = engine.stats().compactions;
+            if now > compactions {
+                assert_eq!(now, compactions + 1);
+                assert!(elapsed - last_start >= interval);
+                (compactions, last_start) = (now, elapsed);
+            }
+        }
+    }
+    assert_eq!(compactions, 21);
+
+    // A need put off is picked up by the first write after the interval,
+    // even one that would not trip a trigger itself.
+    engine.set(b"other", b"v").unwrap();
+    let deferred = engine.stats().compactions_deferred;
+    while engine.stats().compactions_deferred == deferred {
+        overwrite();
+    }
+    clock.advance(interval);
+    let size = engine.stats().file_size;
+    engine.del(b"other").unwrap();
+    let stats = engine.stats();
+    assert_eq!(stats.compactions, 22);
+    assert!(stats.file_size < size);
+
+    // An explicit compaction does not wait for the interval.
+    overwrite();
+    engine.compact().unwrap();
+    assert_eq!(engine.stats().compactions, 23);
+    assert_eq!(engine.get(b"hot").unwrap(), Some(vec![n as u8; 100]));
+    assert_eq!(engine.get(b"other").unwrap(), None);
+}
+
+#[test]
+fn test_abandoned_auto_compaction_does_not_start_the_interval() {
+    let storage = common::InstrumentedStorage::default();
+    let probe = Arc::clone(&storage.probe);
+    let file = NamedTempFile::new().unwrap();
+    let engine = engine_with(
+        file.path(),
+        Options {
+            storage: Arc::new(storage),
+            auto_compact_deadline: Some(Duration::from_millis(50)),
+            min_compaction_interval: Some(Duration::from_secs(3600)),
+            clock: ManualClock::new(),
+            ..Options::default()
+        },
+    );
+    let write_rounds = |rounds: usize| {
+        for round in 0..rounds {
+            for i in 0..40 {
+                let value = format!("{}:{}", i, round).repeat(20);
+                engine
+                    .set(format!("key{}", i).as_bytes(), value.as_bytes())
+                    .unwrap();
+            }
+        }
+    };
+    write_rounds(3);
+    engine
+        .set_compact_threshold(engine.stats().file_size)
+        .unwrap();
+
+    // Too slow to finish by the deadline: abandoned, and not counted as a
+    // start, so the next write compacts with the clock standing still.
+    probe.set_read_delay(Duration::from_millis(10));
+    engine.set(b"trigger", b"1").unwrap();
+    probe.set_read_delay(Duration::ZERO);
+    let stats = engine.stats();
+    assert_eq!((stats.compactions, stats.compactions_aborted), (0, 1));
+    assert_eq!(stats.compactions_deferred, 0);
+
+    engine.set(b"trigger", b"2").unwrap();
+    assert_eq!(engine.stats().compactions, 1);
+
+    // The compaction that went through does start the interval.
+    write_rounds(3);
+    let stats = engine.stats();
+    assert_eq!(stats.compactions, 1);
+    assert!(stats.compactions_deferred > 0);
+    assert_eq!(engine.get(b"trigger").unwrap(), Some(b"2".to_vec()));
+}
+
+// ==================== Idle Reclamation ====================
+
+#[test]
+fn test_reclaim_idle_closes_pooled_readers() {
+    let file = NamedTempFile::new().unwrap();
+    let options = Options {
+        reader_pool_size: 2,
+        ..Options::default()
+    };
+    let engine = Engine::load_with_options(file.path(), options).unwrap();
+    engine.set(b"key", b"value").unwrap();
+    assert_eq!(engine.stats().pooled_readers, 2);
+
+    let keep_readers = ReclaimOptions {
+        readers: false,
+        ..ReclaimOptions::default()
+    };
+    assert_eq!(engine.reclaim_idle(keep_readers), 0);
+    assert_eq!(engine.stats().pooled_readers, 2);
+
+    assert_eq!(engine.reclaim_idle(ReclaimOptions::default()), 2);
+    let stats = engine.stats();
+    assert_eq!(stats.pooled_readers, 0);
+    assert_eq!((stats.idle_reclaims, stats.idle_reacquires), (2, 0));
+
+    assert_eq!(engine.get(b"key").unwrap(), Some(b"value".to_vec()));
+    let stats = engine.stats();
+    assert_eq!(stats.pooled_readers, 1);
+    assert_eq!(stats.idle_reacquires, 1);
+    assert_eq!(engine.get(b"key").unwrap(), Some(b"value".to_vec()));
+    assert_eq!(engine.stats().idle_reacquires, 1);
+}
+
+#[test]
+fn test_idle_after_reclaims_once_untouched_long_enough() {
+    let file = NamedTempFile::new().unwrap();
+    let clock = ManualClock::new();
+    let options = Options {
+        reader_pool_size: 2,
+        idle_after: Some(Duration::from_millis(40)),
+        clock: clock.clone(),
+        ..Options::default()
+    };
+    let engine = Engine::load_with_options(file.path(), options).unwrap();
+    engine.set(b"key", b"value").unwrap();
+    assert_eq!(engine.get(b"key").unwrap(), Some(b"value".to_vec()));
+
+    // Real time passing does not count, only the engine's clock.
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(engine.stats().idle_reclaims, 0);
+    assert_eq!(engine.stats().pooled_readers, 2);
+
+    clock.advance(Duration::from_millis(40));
+    assert!(wait_for(|| engine.stats().idle_reclaims == 1));
+    assert_eq!(engine.stats().pooled_readers, 0);
+
+    // Staying idle does not reclaim again.
+    clock.advance(Duration::from_secs(60));
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(engine.stats().idle_reclaims, 1);
+
+    assert_eq!(engine.get(b"key").unwrap(), Some(b"value".to_vec()));
+    let stats = engine.stats();
+    assert_eq!(stats.idle_reacquires, 1);
+    assert_eq!(stats.pooled_readers, 1);
+
+    // A touch restarts the wait.
+    clock.advance(Duration::from_millis(20));
+    engine.set(b"key", b"other").unwrap();
+    clock.advance(Duration::from_millis(30));
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(engine.stats().idle_reclaims, 1);
+    clock.advance(Duration::from_millis(10));
+    assert!(wait_for(|| engine.stats().idle_reclaims == 2));
+    assert_eq!(engine.get(b"key").unwrap(), Some(b"other".to_vec()));
+}
+
+// ==================== Record References ====================
+
+#[test]
+fn test_set_indexed_reference_reads_back_until_compaction() {
+    let file = NamedTempFile::new().unwrap();
+    let engine = Engine::load(file.path()).unwrap();
+    engine.set(b"other", b"x").unwrap();
+    let first = engine.set_indexed(b"key", b"v1").unwrap();
+    let second = engine.set_indexed(b"key", b"v2").unwrap();
+    assert!(second.index.pos > first.index.pos);
+    assert_eq!(first.generation, second.generation);
+
+    // An overwritten record is still there to read by reference.
+    let entry = engine.get_at_index(&first).unwrap();
+    assert_eq!(entry.key, b"key");
+    assert_eq!(entry.value, Some(b"v1".to_vec()));
+    assert_eq!(entry.kind, EntryKind::Put);
+    assert_eq!(
+        engine.get_at_index(&second).unwrap().value,
+        Some(b"v2".to_vec())
+    );
+    assert_eq!(engine.get(b"key").unwrap(), Some(b"v2".to_vec()));
+
+    engine.compact().unwrap();
+    for record in [&first, &second] {
+        let err = engine.get_at_index(record).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+        assert!(matches!(
+            EngineError::from_io(&err),
+            Some(EngineError::RecordMoved { generation, current })
+                if *generation == record.generation && current > generation
+        ));
+    }
+
+    let moved = engine.set_indexed(b"key", b"v3").unwrap();
+    assert!(moved.generation > second.generation);
+    assert_eq!(
+        engine.get_at_index(&moved).unwrap().value,
+        Some(b"v3".to_vec())
+    );
+}
+
+#[test]
+fn test_set_indexed_checks_keys_and_values_like_set() {
+    let file = NamedTempFile::new().unwrap();
+    let options = Options {
+        validate: Some(Validator::new(|_, value| match value.is_empty() {
+            true => Err("empty".into()),
+            false => Ok(()),
+        })),
+        ..Options::default()
+    };
+    let engine = Engine::load_with_options(file.path(), options).unwrap();
+    let err = engine.set_indexed(b"", b"v").unwrap_err();
+    assert_eq!(EngineError::from_io(&err), Some(&EngineError::EmptyKey));
+    let err = engine.set_indexed(b"key", b"").unwrap_err();
+    assert!(matches!(
+        EngineError::from_io(&err),
+        Some(EngineError::ValidationFailed(_))
+    ));
+    assert_eq!(engine.stats().live_keys, 0);
+}
+
+// ==================== Audit Mode ====================
+
+#[test]
+fn test_audit_mode_keeps_every_record_across_reloads() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("data.db");
+    let engine = engine_with(
+        &path,
+        Options {
+            audit_mode: true,
+            ..Options::default()
+        },
+    );
+    // Any size-based trigger would fire on every write.
+    engine.set_compact_threshold(FILE_HEADER_SIZE).unwrap();
+    for i in 0..50u32 {
+        engine.set(b"key", &i.to_le_bytes()).unwrap();
+    }
+    engine.del(b"key").unwrap();
+    engine.set(b"other", b"x").unwrap();
+
+    let err = engine.compact().unwrap_err();
+    assert_eq!(EngineError::from_io(&err), Some(&EngineError::AuditMode));
+    let err = engine.compact_step(Duration::from_secs(1)).unwrap_err();
+    assert_eq!(EngineError::from_io(&err), Some(&EngineError::AuditMode));
+    assert_eq!(engine.stats().compactions, 0);
+    drop(engine);
+
+    let engine = engine_with(
+        &path,
+        Options {
+            audit_mode: true,
+            ..Options::default()
+        },
+    );
+    engine.reload().unwrap();
+    let records: Vec<_> = engine.audit_scan().unwrap().map(Result::unwrap).collect();
+    assert_eq!(records.len(), 52);
+    for (i, record) in records.iter().enumerate() {
+        assert_eq!(record.seq, i as u64);
+    }
+    for (i, record) in records[..50].iter().enumerate() {
+        assert_eq!(record.entry.key, b"key");
+        assert_eq!(record.entry.value, Some((i as u32).to_le_bytes().to_vec()));
+        assert_eq!(record.entry.kind, EntryKind::Put);
+    }
+    assert_eq!(records[50].entry.kind, EntryKind::Tombstone);
+    assert_eq!(records[51].entry.key, b"other");
+    assert!(records.windows(2).all(|w| w[0].index.pos < w[1].index.pos));
+    assert_eq!(engine.get(b"key").unwrap(), None);
+}
+
+#[test]
+fn test_audit_only_flag_is_cleared_by_the_first_normal_open() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("data.db");
+    let audit_only = || Engine::describe_format_of(&path).unwrap().audit_only;
+
+    let engine = engine_with(
+        &path,
+        Options {
+            audit_mode: true,
+            ..Options::default()
+        },
+    );
+    engine.set(b"key", b"1").unwrap();
+    drop(engine);
+    assert_eq!(audit_only(), Some(true));
+    drop(engine_with(
+        &path,
+        Options {
+            audit_mode: true,
+            ..Options::default()
+        },
+    ));
+    assert_eq!(audit_only(), Some(true));
+
+    let engine = Engine::load(&path).unwrap();
+    assert_eq!(audit_only(), Some(false));
+    engine.compact().unwrap();
+    drop(engine);
+
+    // Audit mode again does not bring the guarantee back.
+    let engine = engine_with(
+        &path,
+        Options {
+            audit_mode: true,
+            ..Options::default()
+        },
+    );
+    assert_eq!(audit_only(), Some(false));
+    assert_eq!(engine.get(b"key").unwrap(), Some(b"1".to_vec()));
+    drop(engine);
+
+    // Older files have no flags to record it in.
+    let v2_path = dir.path().join("v2.db");
+    write_v2_file(&v2_path, DEFAULT_COMPACT_THRESHOLD, &[]);
+    let err = Engine::load_with_options(
+        &v2_path,
+        Options {
+            audit_mode: true,
+            ..Options::default()
+        },
+    )
+    .err()
+    .unwrap();
+    assert_eq!(err.kind(), std::io::ErrorKind::Unsupported);
+}
+
+#[test]
+fn test_audit_scan_outside_audit_mode_sees_what_compaction_kept() {
+    let (engine, _f) = temp_engine();
+    engine.set(b"key", b"1").unwrap();
+    engine.set(b"key", b"2").unwrap();
+    assert_eq!(engine.audit_scan().unwrap().count(), 2);
+    engine.compact().unwrap();
+    let records: Vec<_> = engine.audit_scan().unwrap().map(Result::unwrap).collect();
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0].entry.value, Some(b"2".to_vec()));
+}
+
+// ==================== Key Ranges ====================
+
+#[test]
+fn test_key_ranges_on_empty_and_single_key_stores() {
+    let file = NamedTempFile::new().unwrap();
+    let engine = engine_with(
+        file.path(),
+        Options {
+            ordered_index: true,
+            ..Options::default()
+        },
+    );
+    assert_eq!(engine.first_key_in_range(..).unwrap(), None);
+    assert_eq!(engine.last_key_in_range(..).unwrap(), None);
+    assert!(engine.is_range_empty(..).unwrap());
+
+    engine.set(b"m", b"1").unwrap();
+    let m: &[u8] = b"m";
+    assert_eq!(engine.first_key_in_range(..).unwrap(), Some(b"m".to_vec()));
+    assert_eq!(engine.last_key_in_range(..).unwrap(), Some(b"m".to_vec()));
+    assert!(!engine.is_range_empty((Included(m), Included(m))).unwrap());
+    assert!(engine.is_range_empty((Excluded(m), Unbounded)).unwrap());
+    assert!(engine.is_range_empty((Unbounded, Excluded(m))).unwrap());
+    assert!(engine.is_range_empty((Included(m), Excluded(m))).unwrap());
+    assert!(engine.is_range_empty((Excluded(m), Excluded(m))).unwrap());
+    // An inverted range is empty rather than a panic.
+    let z: &[u8] = b"z";
+    assert!(engine.is_range_empty((Included(z), Included(m))).unwrap());
+}
+
+#[test]
+fn test_key_ranges_straddle_deleted_keys() {
+    let file = NamedTempFile::new().unwrap();
+    let engine = engine_with(
+        file.path(),
+        Options {
+            ordered_index: true,
+            ..Options::default()
+        },
+    );
+    for key in [b"a", b"b", b"c", b"d", b"e"] {
+        engine.set(key, b"v").unwrap();
+    }
+    engine.del(b"b").unwrap();
+    engine.del(b"c").unwrap();
+    engine.soft_del(b"d").unwrap();
+    engine.bucket(b"tenant").unwrap().set(b"k", b"v").unwrap();
+
+    let (a, b, d, e): (&[u8], &[u8], &[u8], &[u8]) = (b"a", b"b", b"d", b"e");
+    assert_eq!(
+        engine.first_key_in_range((Excluded(a), Unbounded)).unwrap(),
+        Some(b"e".to_vec())
+    );
+    assert_eq!(
+        engine.last_key_in_range((Unbounded, Excluded(e))).unwrap(),
+        Some(b"a".to_vec())
+    );
+    assert!(engine.is_range_empty((Included(b), Included(d))).unwrap());
+    // Bucketed keys sort last but never show up.
+    assert_eq!(engine.last_key_in_range(..).unwrap(), Some(b"e".to_vec()));
+    assert_eq!(
+        engine
+            .last_key_in_range((Included(e), Included(&[0xFF; 8][..])))
+            .unwrap(),
+        Some(b"e".to_vec())
+    );
+
+    engine.restore(b"d").unwrap();
+    assert_eq!(
+        engine.first_key_in_range((Included(b), Unbounded)).unwrap(),
+        Some(b"d".to_vec())
+    );
+    engine.set(b"c", b"again").unwrap();
+    assert_eq!(
+        engine.first_key_in_range((Included(b), Unbounded)).unwrap(),
+        Some(b"c".to_vec())
+    );
+
+    // Rebuilt on load and carried through compaction.
+    engine.compact().unwrap();
+    assert_eq!(
+        engine.first_key_in_range((Excluded(a), Unbounded)).unwrap(),
+        Some(b"c".to_vec())
+    );
+    drop(engine);
+    let engine = engine_with(
+        file.path(),
+        Options {
+            ordered_index: true,
+            ..Options::default()
+        },
+    );
+    assert!(
+        engine
+            .is_range_empty((Excluded(a), Excluded(b"c".as_slice())))
+            .unwrap()
+    );
+    assert_eq!(engine.last_key_in_range(..).unwrap(), Some(b"e".to_vec()));
+}
+
+#[test]
+fn test_key_ranges_need_the_ordered_index() {
+    let (engine, _f) = temp_engine();
+    engine.set(b"a", b"1").unwrap();
+    let err = engine.first_key_in_range(..).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::Unsupported);
+}
+
+// ==================== Value Hashes ====================
+
+#[test]
+fn test_value_hash_tracks_overwrites_without_reading() {
+    let file = NamedTempFile::new().unwrap();
+    let engine = engine_with(
+        file.path(),
+        Options {
+            value_hashes: true,
+            dedup_min_value_len: Some(100),
+            open_mode: OpenMode::Fast,
+            ..Options::default()
+        },
+    );
+    engine.set(b"k", b"one").unwrap();
+    let reads = engine.stats().disk_reads;
+
+    let one = engine.value_hash(b"k").unwrap();
+    assert_eq!(one, Some(Engine::hash_value(b"one")));
+    engine.set(b"k", b"two").unwrap();
+    let two = engine.value_hash(b"k").unwrap();
+    assert_eq!(two, Some(Engine::hash_value(b"two")));
+    assert_ne!(one, two);
+
+    let mut batch = WriteBatch::new();
+    batch.put_with_ttl(b"ttl", b"short", Duration::from_millis(30));
+    engine.apply_batch(&batch).unwrap();
+    assert_eq!(
+        engine.value_hash(b"ttl").unwrap(),
+        Some(Engine::hash_value(b"short"))
+    );
+    engine.del(b"k").unwrap();
+    assert_eq!(engine.value_hash(b"k").unwrap(), None);
+    assert_eq!(engine.value_hash(b"missing").unwrap(), None);
+    thread::sleep(Duration::from_millis(50));
+    assert_eq!(engine.value_hash(b"ttl").unwrap(), None);
+    assert_eq!(engine.stats().disk_reads, reads);
+}
+
+#[test]
+fn test_value_hash_survives_compaction_and_reload() {
+    let file = NamedTempFile::new().unwrap();
+    let engine = engine_with(
+        file.path(),
+        Options {
+            value_hashes: true,
+            dedup_min_value_len: Some(100),
+            open_mode: OpenMode::Fast,
+            ..Options::default()
+        },
+    );
+    let big = vec![7u8; 200];
+    engine.set(b"inline", b"v1").unwrap();
+    engine.set(b"inline", b"v2").unwrap();
+    engine.set(b"deduped", &big).unwrap();
+    engine.set(b"deduped-too", &big).unwrap();
+    let mut batch = WriteBatch::new();
+    batch.put(b"batched", b"b").delete(b"deduped-too");
+    engine.apply_batch(&batch).unwrap();
+
+    let expected = [
+        (b"inline".as_slice(), Some(Engine::hash_value(b"v2"))),
+        (b"deduped", Some(Engine::hash_value(&big))),
+        (b"deduped-too", None),
+        (b"batched", Some(Engine::hash_value(b"b"))),
+    ];
+    let check = |engine: &Engine| {
+        let reads = engine.stats().disk_reads;
+        for (key, hash) in &expected {
+            assert_eq!(engine.value_hash(key).unwrap(), *hash);
+        }
+        assert_eq!(engine.stats().disk_reads, reads);
+    };
+    check(&engine);
+    engine.compact().unwrap();
+    check(&engine);
+    drop(engine);
+
+    let engine = engine_with(
+        file.path(),
+        Options {
+            value_hashes: true,
+            dedup_min_value_len: Some(100),
+            open_mode: OpenMode::Fast,
+            ..Options::default()
+        },
+    );
+    check(&engine);
+}
+
+#[test]
+fn test_value_hash_reads_the_value_without_the_option() {
+    let (engine, _file) = temp_engine();
+    engine.set(b"k", b"v").unwrap();
+    let reads = engine.stats().disk_reads;
+    assert_eq!(
+        engine.value_hash(b"k").unwrap(),
+        Some(Engine::hash_value(b"v"))
+    );
+    assert!(engine.stats().disk_reads > reads);
+    assert_eq!(engine.value_hash(b"missing").unwrap(), None);
+}
+
+// ==================== Soft Limits ====================
+
+#[test]
+fn test_soft_limit_warns_once_per_crossing() {
+    let file = NamedTempFile::new().unwrap();
+    let warnings = Arc::new(Mutex::new(Vec::new()));
+    let seen = Arc::clone(&warnings);
+    let engine = engine_with(
+        file.path(),
+        Options {
+            max_index_entries: Some(10),
+            soft_limits: vec![(
+                Limit::IndexEntries,
+                SoftLimit {
+                    warn_at: 0.8,
+                    clear_at: 0.6,
+                },
+            )],
+            on_limit_warning: Some(Hook::new(move |w: &LimitWarning| {
+                seen.lock().unwrap().push(w.clone())
+            })),
+            ..Options::default()
+        },
+    );
+    let key = |i: usize| format!("k{:02}", i).into_bytes();
+    let raised = |current| LimitWarning {
+        limit: Limit::IndexEntries,
+        current,
+        max: 10,
+        active: true,
+    };
+
+    for i in 0..7 {
+        engine.set(&key(i), b"v").unwrap();
+    }
+    assert!(warnings.lock().unwrap().is_empty());
+    assert!(engine.stats().limit_warnings.is_empty());
+
+    for i in 7..10 {
+        engine.set(&key(i), b"v").unwrap();
+    }
+    let err = engine.set(&key(10), b"v").unwrap_err();
+    assert!(matches!(
+        EngineError::from_io(&err),
+        Some(EngineError::IndexFull(_))
+    ));
+    // Overwrites near the boundary change nothing.
+    engine.set(&key(0), b"w").unwrap();
+    assert_eq!(*warnings.lock().unwrap(), vec![raised(8)]);
+    assert_eq!(engine.stats().limit_warnings, vec![Limit::IndexEntries]);
+
+    // Still active down to the clear mark, cleared below it.
+    for i in (6..10).rev() {
+        engine.del(&key(i)).unwrap();
+    }
+    assert_eq!(warnings.lock().unwrap().len(), 1);
+    engine.del(&key(5)).unwrap();
+    assert_eq!(
+        warnings.lock().unwrap()[1],
+        LimitWarning {
+            limit: Limit::IndexEntries,
+            current: 5,
+            max: 10,
+            active: false,
+        }
+    );
+    assert!(engine.stats().limit_warnings.is_empty());
+
+    for i in 5..8 {
+        engine.set(&key(i), b"v").unwrap();
+    }
+    assert_eq!(warnings.lock().unwrap().len(), 3);
+    assert_eq!(warnings.lock().unwrap()[2], raised(8));
+}
+
+#[test]
+fn test_soft_limit_checked_on_load_and_validated() {
+    let file = NamedTempFile::new().unwrap();
+    {
+        let engine = Engine::load(file.path()).unwrap();
+        for i in 0..9 {
+            engine.set(format!("k{}", i).as_bytes(), b"v").unwrap();
+        }
+    }
+    let warnings = Arc::new(Mutex::new(Vec::new()));
+    let seen = Arc::clone(&warnings);
+    let engine = engine_with(
+        file.path(),
+        Options {
+            max_index_entries: Some(10),
+            soft_limits: vec![(
+                Limit::IndexEntries,
+                SoftLimit {
+                    warn_at: 0.8,
+                    clear_at: 0.6,
+                },
+            )],
+            on_limit_warning: Some(Hook::new(move |w: &LimitWarning| {
+                seen.lock().unwrap().push(w.clone())
+            })),
+            ..Options::default()
+        },
+    );
+    assert_eq!(warnings.lock().unwrap().len(), 1);
+    assert!(warnings.lock().unwrap()[0].active);
+    assert_eq!(engine.stats().limit_warnings, vec![Limit::IndexEntries]);
+    drop(engine);
+
+    let options = Options {
+        max_index_entries: Some(10),
+        soft_limits: vec![(
+            Limit::IndexEntries,
+            SoftLimit {
+                warn_at: 0.5,
+                clear_at: 0.5,
+            },
+        )],
+        ..Options::default()
+    };
+    let err = Engine::load_with_options(file.path(), options)
+        .err()
+        .unwrap();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+}
+
+// ==================== Prefix Rename ====================
+
+fn seed_namespaces(engine: &Engine) {
+    for (key, value) in [
+        (b"v1:".as_slice(), b"bare".as_slice()),
+        (b"v1:a", b"1"),
+        (b"v1:b", b"2"),
+        (b"v1:c", b"3"),
+        (b"v2:b", b"old"),
+        (b"v2:z", b"keep"),
+        (b"other", b"x"),
+    ] {
+        engine.set(key, value).unwrap();
+    }
+}
+
+#[test]
+fn test_key_overlap_counts_conflicting_suffixes() {
+    let (engine, _file) = temp_engine();
+    seed_namespaces(&engine);
+    assert_eq!(
+        engine.key_overlap(b"v1:", b"v2:").unwrap(),
+        OverlapReport {
+            src_count: 4,
+            dst_count: 2,
+            conflicts: 1,
+        }
+    );
+    // The bare prefix is a key under itself, with an empty suffix.
+    engine.set(b"v2:", b"bare too").unwrap();
+    assert_eq!(engine.key_overlap(b"v1:", b"v2:").unwrap().conflicts, 2);
+    // With one prefix under the other, "v1:a" has the suffix "1:a", which
+    // "v1:1:a" also has under "v1:".
+    engine.set(b"v1:1:a", b"nested").unwrap();
+    assert_eq!(
+        engine.key_overlap(b"v", b"v1:").unwrap(),
+        OverlapReport {
+            src_count: 8,
+            dst_count: 5,
+            conflicts: 1,
+        }
+    );
+}
+
+#[test]
+fn test_rename_prefix_overwrites_skips_or_fails() {
+    let (engine, _file) = temp_engine();
+    seed_namespaces(&engine);
+    let err = engine
+        .rename_prefix(b"v1:", b"v2:", RenameConflict::Fail)
+        .unwrap_err();
+    assert!(matches!(
+        EngineError::from_io(&err),
+        Some(EngineError::RenameConflicts(1))
+    ));
+    assert_eq!(engine.get(b"v1:a").unwrap(), Some(b"1".to_vec()));
+    assert_eq!(engine.get(b"v2:a").unwrap(), None);
+
+    assert_eq!(
+        engine
+            .rename_prefix(b"v1:", b"v2:", RenameConflict::Skip)
+            .unwrap(),
+        3
+    );
+    assert_eq!(engine.get(b"v2:").unwrap(), Some(b"bare".to_vec()));
+    assert_eq!(engine.get(b"v2:a").unwrap(), Some(b"1".to_vec()));
+    assert_eq!(engine.get(b"v2:b").unwrap(), Some(b"old".to_vec()));
+    assert_eq!(engine.get(b"v1:b").unwrap(), Some(b"2".to_vec()));
+    assert_eq!(engine.get(b"v1:a").unwrap(), None);
+
+    assert_eq!(
+        engine
+            .rename_prefix(b"v1:", b"v2:", RenameConflict::Overwrite)
+            .unwrap(),
+        1
+    );
+    assert_eq!(engine.get(b"v2:b").unwrap(), Some(b"2".to_vec()));
+    assert_eq!(engine.get(b"v2:z").unwrap(), Some(b"keep".to_vec()));
+    assert_eq!(engine.get(b"other").unwrap(), Some(b"x".to_vec()));
+    assert_eq!(engine.key_overlap(b"v1:", b"v2:").unwrap().src_count, 0);
+
+    let err = engine
+        .rename_prefix(b"v2:", b"v2:", RenameConflict::Overwrite)
+        .unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+}
+
+#[test]
+fn test_rename_prefix_between_nested_prefixes() {
+    let (engine, file) = temp_engine();
+    for key in [b"a".as_slice(), b"ab", b"abb", b"ac"] {
+        engine.set(key, key).unwrap();
+    }
+    let mut batch = WriteBatch::new();
+    batch.put_with_ttl(b"ad", b"ttl", Duration::from_secs(3600));
+    engine.apply_batch(&batch).unwrap();
+
+    // Every key moves one "b" deeper without clobbering the next.
+    assert_eq!(
+        engine
+            .rename_prefix(b"a", b"ab", RenameConflict::Overwrite)
+            .unwrap(),
+        5
+    );
+    let expect = |engine: &Engine, pairs: &[(&[u8], &[u8])]| {
+        let mut keys = engine.raw_keys_excluding_buckets();
+        keys.sort();
+        let mut expected: Vec<Vec<u8>> = pairs.iter().map(|(k, _)| k.to_vec()).collect();
+        expected.sort();
+        assert_eq!(keys, expected);
+        for (key, value) in pairs {
+            assert_eq!(engine.get(key).unwrap(), Some(value.to_vec()));
+        }
+    };
+    let deeper: &[(&[u8], &[u8])] = &[
+        (b"ab", b"a"),
+        (b"abb", b"ab"),
+        (b"abbb", b"abb"),
+        (b"abc", b"ac"),
+        (b"abd", b"ttl"),
+    ];
+    expect(&engine, deeper);
+
+    // And back out again, shortest first.
+    assert_eq!(
+        engine
+            .rename_prefix(b"ab", b"a", RenameConflict::Overwrite)
+            .unwrap(),
+        5
+    );
+    let original: &[(&[u8], &[u8])] = &[
+        (b"a", b"a"),
+        (b"ab", b"ab"),
+        (b"abb", b"abb"),
+        (b"ac", b"ac"),
+        (b"ad", b"ttl"),
+    ];
+    expect(&engine, original);
+    drop(engine);
+    let engine = Engine::load(file.path()).unwrap();
+    expect(&engine, original);
+}
+
+// ==================== Legacy Migration ====================
+
+#[test]
+fn test_migrate_legacy_headerless_file_end_to_end() {
+    let file = NamedTempFile::new().unwrap();
+    let path = file.path().to_owned();
+    fs::write(&path, legacy::headerless_file()).unwrap();
+
+    let err = Engine::load(&path).err().unwrap();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    assert!(err.to_string().contains("migrate_legacy"));
+
+    let records = Engine::migrate_legacy(&path).unwrap();
+    assert_eq!(records, legacy::HEADERLESS_WRITES.len() as u64);
+    let mut magic = [0u8; 4];
+    fs::File::open(&path)
+        .unwrap()
+        .read_exact(&mut magic)
+        .unwrap();
+    assert_eq!(magic, FILE_HEADER_MAGIC);
+
+    let engine = Engine::load(&path).unwrap();
+    let expected = legacy::headerless_contents();
+    assert_eq!(engine.stats().live_keys, expected.len());
+    for (key, value) in &expected {
+        assert_eq!(engine.get(key).unwrap().as_ref(), Some(value));
+    }
+    assert_eq!(engine.get(b"beta").unwrap(), None);
+    engine.set(b"epsilon", b"5").unwrap();
+}
+
+#[test]
+fn test_migrate_legacy_refuses_a_torn_file_and_leaves_it_alone() {
+    let file = NamedTempFile::new().unwrap();
+    let path = file.path().to_owned();
+    let mut bytes = legacy::headerless_file();
+    bytes.pop();
+    fs::write(&path, &bytes).unwrap();
+
+    let err = Engine::migrate_legacy(&path).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    assert!(err.to_string().contains("not a headerless data file"));
+    assert_eq!(fs::read(&path).unwrap(), bytes);
+}
+
+#[test]
+fn test_migrate_legacy_refuses_files_with_a_header() {
+    let (engine, file) = temp_engine();
+    engine.set(b"k", b"v").unwrap();
+    drop(engine);
+    let before = fs::read(file.path()).unwrap();
+
+    let err = Engine::migrate_legacy(file.path()).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    assert!(err.to_string().contains("already has a KVS header"));
+    assert_eq!(fs::read(file.path()).unwrap(), before);
+
+    let empty = NamedTempFile::new().unwrap();
+    assert!(Engine::migrate_legacy(empty.path()).is_err());
+}
+
+// ==================== Operation Context ====================
+
+#[test]
+fn test_context_reaches_on_set() {
+    let file = NamedTempFile::new().unwrap();
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let sink = Arc::clone(&seen);
+    let options = Options {
+        on_set: Some(Hook::new(move |event: &SetEvent| {
+            sink.lock().unwrap().push(event.clone())
+        })),
+        ..Options::default()
+    };
+    let engine = Engine::load_with_options(file.path(), options).unwrap();
+
+    engine.set(b"plain", b"v").unwrap();
+    let outer = OpContext::new("req-checkout ✓", u64::MAX);
+    let inner = OpContext::new("inner", 7);
+    let returned = engine.with_context(outer.clone(), || {
+        engine.set(b"outer", b"value").unwrap();
+        engine.with_context(inner.clone(), || {
+            engine.bucket(b"b").unwrap().set(b"inner", b"vv").unwrap();
+        });
+        assert!(engine.set_with_tstamp(b"stamped", b"s", 1).unwrap());
+        engine.set_indexed(b"indexed", b"i").unwrap();
+        42
+    });
+    assert_eq!(returned, 42);
+    engine.set(b"after", b"v").unwrap();
+
+    let seen = seen.lock().unwrap();
+    let contexts: Vec<_> = seen
+        .iter()
+        .map(|event| (event.value_len, event.context.clone()))
+        .collect();
+    assert_eq!(
+        contexts,
+        vec![
+            (1, None),
+            (5, Some(outer.clone())),
+            (2, Some(inner)),
+            (1, Some(outer.clone())),
+            (1, Some(outer)),
+            (1, None),
+        ]
+    );
+    assert_eq!(seen[3].key, b"stamped");
+}
+
+#[test]
+fn test_context_reaches_forced_slow_op() {
+    let file = NamedTempFile::new().unwrap();
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let sink = Arc::clone(&seen);
+    let engine = engine_with(
+        file.path(),
+        Options {
+            slow_op_threshold: Some(Duration::ZERO),
+            on_slow_op: Some(Hook::new(move |op: &SlowOp| {
+                sink.lock().unwrap().push(op.clone())
+            })),
+            reader_pool_size: 0,
+            ..Options::default()
+        },
+    );
+    let context = OpContext::new("batch-import", 1234);
+    engine.with_context(context.clone(), || {
+        engine.set(b"k", b"v").unwrap();
+        engine.get(b"k").unwrap();
+    });
+    engine.del(b"k").unwrap();
+
+    let seen = seen.lock().unwrap();
+    assert_eq!(seen.len(), 3);
+    assert_eq!(seen[0].op, SlowOpKind::Set);
+    assert_eq!(seen[0].context, Some(context.clone()));
+    assert_eq!(seen[1].op, SlowOpKind::Get);
+    assert_eq!(seen[1].context, Some(context));
+    assert_eq!(seen[2].context, None);
+}
+
+#[test]
+fn test_context_is_restored_after_a_panic() {
+    let file = NamedTempFile::new().unwrap();
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let sink = Arc::clone(&seen);
+    let engine = engine_with(
+        file.path(),
+        Options {
+            slow_op_threshold: Some(Duration::ZERO),
+            on_slow_op: Some(Hook::new(move |op: &SlowOp| {
+                sink.lock().unwrap().push(op.clone())
+            })),
+            reader_pool_size: 0,
+            ..Options::default()
+        },
+    );
+    let panicked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        engine.with_context(OpContext::new("doomed", 1), || panic!("boom"))
+    }));
+    assert!(panicked.is_err());
+    engine.set(b"k", b"v").unwrap();
+    assert_eq!(seen.lock().unwrap()[0].context, None);
+}
+
+// ==================== Disk Full ====================
+
+fn assert_disk_full(err: std::io::Error, rolled_back: bool) {
+    assert_eq!(err.kind(), std::io::ErrorKind::StorageFull);
+    assert_eq!(
+        EngineError::from_io(&err),
+        Some(&EngineError::DiskFull { rolled_back })
+    );
+}
+
+#[test]
+fn test_disk_full_at_every_byte_of_an_append_rolls_back() {
+    let file = NamedTempFile::new().unwrap();
+    let storage = common::InstrumentedStorage::default();
+    let engine = engine_with(
+        file.path(),
+        Options {
+            storage: Arc::new(storage.clone()),
+            ..Options::default()
+        },
+    );
+    engine.set(b"before", b"kept").unwrap();
+
+    let mut budget = 0;
+    loop {
+        let len = fs::metadata(file.path()).unwrap().len();
+        storage.probe.set_space_left(Some(budget));
+        let result = engine.set(b"big", &[7u8; 300]);
+        storage.probe.set_space_left(None);
+        if result.is_ok() {
+            break;
+        }
+        assert_disk_full(result.unwrap_err(), true);
+        assert_eq!(fs::metadata(file.path()).unwrap().len(), len);
+
+        let fresh = Engine::load(file.path()).unwrap();
+        assert_eq!(fresh.load_report().replay.truncated_bytes, 0);
+        assert_eq!(fresh.get(b"before").unwrap(), Some(b"kept".to_vec()));
+        assert_eq!(fresh.get(b"big").unwrap(), None);
+        drop(fresh);
+
+        engine.set(b"after", &budget.to_le_bytes()).unwrap();
+        budget += 1;
+    }
+    assert!(budget > 300);
+
+    drop(engine);
+    let engine = Engine::load(file.path()).unwrap();
+    assert_eq!(engine.get(b"big").unwrap(), Some(vec![7u8; 300]));
+    assert_eq!(
+        engine.get(b"after").unwrap(),
+        Some((budget - 1u64).to_le_bytes().to_vec())
+    );
+}
+
+#[test]
+fn test_disk_full_in_a_batch_applies_none_of_it() {
+    let file = NamedTempFile::new().unwrap();
+    let storage = common::InstrumentedStorage::default();
+    let engine = engine_with(
+        file.path(),
+        Options {
+            storage: Arc::new(storage.clone()),
+            ..Options::default()
+        },
+    );
+    let mut batch = WriteBatch::new();
+    batch.put(b"a", b"1").put(b"b", b"2").delete(b"c");
+
+    storage.probe.set_space_left(Some(10));
+    assert_disk_full(engine.apply_batch(&batch).unwrap_err(), true);
+    storage.probe.set_space_left(None);
+    assert_eq!(engine.get(b"a").unwrap(), None);
+
+    engine.apply_batch(&batch).unwrap();
+    drop(engine);
+    let engine = Engine::load(file.path()).unwrap();
+    assert_eq!(engine.get(b"b").unwrap(), Some(b"2".to_vec()));
+}
+
+#[test]
+fn test_disk_full_without_truncate_refuses_writes_until_reload() {
+    let file = NamedTempFile::new().unwrap();
+    let storage = common::InstrumentedStorage::default();
+    let engine = engine_with(
+        file.path(),
+        Options {
+            storage: Arc::new(storage.clone()),
+            ..Options::default()
+        },
+    );
+    engine.set(b"before", b"kept").unwrap();
+
+    storage.probe.set_space_left(Some(5));
+    storage
+        .probe
+        .inject(Fault::SetLen, 3, std::io::ErrorKind::Other);
+    assert_disk_full(engine.set(b"big", &[1u8; 100]).unwrap_err(), false);
+    storage.probe.set_space_left(None);
+
+    // The torn record is still on disk; nothing lands after it.
+    assert_disk_full(engine.set(b"other", b"v").unwrap_err(), false);
+    assert_disk_full(engine.del(b"before").unwrap_err(), false);
+    assert_eq!(engine.get(b"before").unwrap(), Some(b"kept".to_vec()));
+
+    let report = engine.reload().unwrap();
+    assert_eq!(report.truncated_bytes, 5);
+    engine.set(b"other", b"v").unwrap();
+
+    drop(engine);
+    let engine = Engine::load(file.path()).unwrap();
+    assert_eq!(engine.load_report().replay.truncated_bytes, 0);
+    assert_eq!(engine.get(b"before").unwrap(), Some(b"kept".to_vec()));
+    assert_eq!(engine.get(b"other").unwrap(), Some(b"v".to_vec()));
+    assert_eq!(engine.get(b"big").unwrap(), None);
+}
+
+// ==================== Pending Tombstones ====================
+
+fn pending_keys(engine: &Engine) -> Vec<Vec<u8>> {
+    engine
+        .pending_tombstones()
+        .unwrap()
+        .map(|t| t.unwrap().0)
+        .collect()
+}
+
+#[test]
+fn test_pending_tombstones_reports_deletes_of_dead_keys() {
+    let (engine, _f) = temp_engine();
+    let start = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as i64;
+    engine.set(b"a", b"1").unwrap();
+    engine.del(b"a").unwrap();
+    engine.set(b"b", b"1").unwrap();
+    engine.del(b"b").unwrap();
+    engine.set(b"b", b"2").unwrap();
+    engine.del(b"never-set").unwrap();
+    engine.set(b"d", b"1").unwrap();
+    engine.set(b"d", b"2").unwrap();
+    engine.del(b"d").unwrap();
+    engine.del(b"d").unwrap();
+    engine.set(b"live", b"1").unwrap();
+
+    let pending: Vec<(Vec<u8>, i64)> = engine
+        .pending_tombstones()
+        .unwrap()
+        .map(Result::unwrap)
+        .collect();
+    let keys: Vec<&[u8]> = pending.iter().map(|(k, _)| k.as_slice()).collect();
+    assert_eq!(keys, [&b"a"[..], b"never-set", b"d", b"d"]);
+    assert!(pending.windows(2).all(|w| w[0].1 <= w[1].1));
+    assert!(pending[0].1 >= start);
+
+    engine.compact().unwrap();
+    assert!(pending_keys(&engine).is_empty());
+
+    engine.del(b"live").unwrap();
+    engine.set(b"a", b"again").unwrap();
+    assert_eq!(pending_keys(&engine), [b"live".to_vec()]);
+}
+
+#[test]
+fn test_pending_tombstones_stops_at_length_at_creation() {
+    let (engine, _f) = temp_engine();
+    engine.set(b"x", b"1").unwrap();
+    engine.del(b"x").unwrap();
+    engine.del(b"y").unwrap();
+
+    let mut pending = engine.pending_tombstones().unwrap();
+    engine.del(b"z").unwrap();
+    // Liveness is checked as records are reached.
+    engine.set(b"y", b"back").unwrap();
+    assert_eq!(pending.next().unwrap().unwrap().0, b"x");
+    assert!(pending.next().is_none());
+}
+
+// ==================== Raw Log Ingest ====================
+
+#[test]
+fn test_ingest_raw_log_salvages_around_corruption() {
+    let source = NamedTempFile::new().unwrap();
+    let fixture = StoreFixture::builder()
+        .keys(40)
+        .overwrite_ratio(0.1)
+        .deleted_ratio(0.1)
+        .value_size(40..41)
+        .plain()
+        .build(source.path())
+        .unwrap();
+    // Live keys written once, far enough apart that their damage never
+    // merges into one region.
+    let damaged_keys: Vec<Vec<u8>> = fixture
+        .live()
+        .keys()
+        .filter(|key| !fixture.overwritten().contains(*key))
+        .step_by(6)
+        .take(4)
+        .cloned()
+        .collect();
+    let mut damaged = vec![
+        fixture.value_len_offset(&damaged_keys[0]).unwrap(),
+        fixture.prefix_offset(&damaged_keys[1]).unwrap(),
+        fixture.value_len_offset(&damaged_keys[2]).unwrap(),
+        fixture.prefix_offset(&damaged_keys[3]).unwrap(),
+    ];
+    for corruption in [
+        Corruption::Overwrite(0, vec![0xFF; 4]),
+        // Value lengths pointing past the end of their records.
+        Corruption::Overwrite(damaged[0], 1000u64.to_le_bytes().to_vec()),
+        Corruption::Overwrite(damaged[2], 1000u64.to_le_bytes().to_vec()),
+        // A length prefix that frames the wrong number of bytes.
+        Corruption::Overwrite(damaged[1], vec![5]),
+        // A run of garbage from the start of a record into its key.
+        Corruption::Overwrite(damaged[3], vec![0xEE; 20]),
+    ] {
+        corruption.apply(source.path()).unwrap();
+    }
+    assert!(Engine::load(source.path()).is_err());
+
+    let (engine, _f) = temp_engine();
+    let report = engine
+        .ingest_raw_log(source.path(), ConflictPolicy::KeepNewest)
+        .unwrap();
+    // One record per key, overwrite, and delete, less the damaged ones.
+    let records = 40 + fixture.overwritten().len() + fixture.deleted().len();
+    let applied = fixture.live().len() - damaged_keys.len();
+    assert_eq!(report.recovered, (records - damaged_keys.len()) as u64);
+    assert_eq!(report.applied, applied as u64);
+    assert_eq!(report.skipped, report.recovered - report.applied);
+    assert_eq!(report.conflicted, 0);
+    assert_eq!(report.corrupt_regions.len(), 5);
+    assert_eq!(report.corrupt_regions[0], 0..FILE_HEADER_SIZE);
+    damaged.sort();
+    for (region, offset) in report.corrupt_regions[1..].iter().zip(damaged) {
+        assert!(region.contains(&offset), "{:?} misses {}", region, offset);
+    }
+
+    assert_eq!(engine.stats().live_keys, applied);
+    for key in fixture.live().keys().chain(fixture.deleted()) {
+        let expected = if damaged_keys.contains(key) {
+            None
+        } else {
+            fixture.live().get(key).cloned()
+        };
+        assert_eq!(engine.get(key).unwrap(), expected);
+    }
+}
+
+#[test]
+fn test_ingest_raw_log_resolves_conflicts_by_timestamp() {
+    let source = NamedTempFile::new().unwrap();
+    {
+        let engine = Engine::load(source.path()).unwrap();
+        engine.set_with_tstamp(b"a", b"1", 100).unwrap();
+        engine.set_with_tstamp(b"b", b"2", 100).unwrap();
+        engine.set_with_tstamp(b"c", b"3", 300).unwrap();
+    }
+    let (engine, _f) = temp_engine();
+    engine.set_with_tstamp(b"a", b"newer", 200).unwrap();
+    engine.set_with_tstamp(b"b", b"older", 50).unwrap();
+
+    let report = engine
+        .ingest_raw_log(source.path(), ConflictPolicy::KeepNewest)
+        .unwrap();
+    assert_eq!((report.applied, report.conflicted), (2, 1));
+    assert!(report.corrupt_regions.is_empty());
+    assert_eq!(engine.get(b"a").unwrap(), Some(b"newer".to_vec()));
+    assert_eq!(engine.get(b"b").unwrap(), Some(b"2".to_vec()));
+    assert_eq!(engine.get(b"c").unwrap(), Some(b"3".to_vec()));
+
+    let report = engine
+        .ingest_raw_log(source.path(), ConflictPolicy::RejectOlder)
+        .unwrap();
+    assert_eq!((report.applied, report.conflicted), (2, 1));
+    assert_eq!(engine.get(b"a").unwrap(), Some(b"newer".to_vec()));
+
+    let report = engine
+        .ingest_raw_log(source.path(), ConflictPolicy::AlwaysAccept)
+        .unwrap();
+    assert_eq!((report.applied, report.conflicted), (3, 0));
+    assert_eq!(engine.get(b"a").unwrap(), Some(b"1".to_vec()));
+}
+
+// ==================== Scan Guarantees ====================
+
+#[test]
+fn test_keys_snapshot_is_unaffected_by_later_writes() {
+    let (engine, _f) = temp_engine();
+    for i in 0..200 {
+        engine
+            .set(format!("k{:03}", i).as_bytes(), b"before")
+            .unwrap();
+    }
+    let keys = engine.keys();
+    let pairs = engine.scan_prefix(b"k").unwrap();
+
+    for i in 0..200 {
+        let key = format!("k{:03}", i);
+        match i % 2 {
+            0 => engine.del(key.as_bytes()).unwrap(),
+            _ => engine.set(key.as_bytes(), b"after").unwrap(),
+        }
+    }
+    engine.set(b"k999", b"new").unwrap();
+    engine.compact().unwrap();
+
+    assert_eq!(keys.len(), 200);
+    assert!(keys.windows(2).all(|w| w[0] < w[1]));
+    assert_eq!(pairs.len(), 200);
+    assert!(pairs.iter().all(|(_, value)| value == b"before"));
+    assert_eq!(engine.keys().len(), 101);
+}
+
+#[test]
+fn test_keys_lists_exactly_the_live_keys_across_compaction() {
+    let (engine, _f) = temp_engine();
+    for i in 0..1000 {
+        engine.set(format!("k{:04}", i).as_bytes(), b"v1").unwrap();
+    }
+    // Deletes and stale versions of the survivors, both for compaction to
+    // drop.
+    for i in 0..1000 {
+        let key = format!("k{:04}", i);
+        match i % 2 {
+            0 => engine.del(key.as_bytes()).unwrap(),
+            _ => engine.set(key.as_bytes(), b"v2").unwrap(),
+        }
+    }
+    let expected: BTreeSet<Vec<u8>> = (0..1000)
+        .filter(|i| i % 2 == 1)
+        .map(|i| format!("k{:04}", i).into_bytes())
+        .collect();
+    let listed = |engine: &Engine| engine.keys().into_iter().collect::<BTreeSet<_>>();
+    assert_eq!(listed(&engine), expected);
+    engine.compact().unwrap();
+    assert_eq!(engine.keys().len(), 500);
+    assert_eq!(listed(&engine), expected);
+
+    // The snapshot holds no lock, so the walk can delete what it visits.
+    for key in engine.keys() {
+        engine.del(&key).unwrap();
+    }
+    assert!(engine.keys().is_empty());
+}
+
+#[test]
+fn test_scan_reads_every_live_pair_while_compaction_swaps_the_file() {
+    let (engine, _f) = temp_engine();
+    assert!(engine.scan().unwrap().is_empty());
+
+    // Every fifth value is empty.
+    let stable = |i: u32| {
+        (
+            format!("k{:03}", i).into_bytes(),
+            vec![i as u8; i as usize % 5],
+        )
+    };
+    for i in 0..300 {
+        let (key, value) = stable(i);
+        engine.set(&key, &value).unwrap();
+    }
+    for i in (0..300).step_by(3) {
+        engine.del(&stable(i).0).unwrap();
+    }
+    let expected: Vec<_> = (0..300).filter(|i| i % 3 != 0).map(stable).collect();
+    assert_eq!(engine.scan().unwrap().into_vec(), expected);
+
+    // Rewriting the stable keys with the same values moves their records,
+    // so every compaction gives them new offsets.
+    let engine = Arc::new(engine);
+    let stop = Arc::new(AtomicBool::new(false));
+    let churn = {
+        let engine = Arc::clone(&engine);
+        let stop = Arc::clone(&stop);
+        let expected = expected.clone();
+        thread::spawn(move || {
+            let mut n = 0u32;
+            while !stop.load(Ordering::SeqCst) {
+                for (key, value) in &expected {
+                    engine.set(key, value).unwrap();
+                }
+                let key = format!("c{:02}", n % 50);
+                engine.set(key.as_bytes(), key.as_bytes()).unwrap();
+                engine.compact().unwrap();
+                n += 1;
+            }
+            n
+        })
+    };
+    for _ in 0..50 {
+        let (stable, churned): (Vec<_>, Vec<_>) = engine
+            .scan()
+            .unwrap()
+            .into_iter()
+            .partition(|(key, _)| key.starts_with(b"k"));
+        assert_eq!(stable, expected);
+        assert!(churned.iter().all(|(key, value)| key == value));
+    }
+    stop.store(true, Ordering::SeqCst);
+    assert!(churn.join().unwrap() > 0);
+}
+
+#[test]
+fn test_scan_prefix_matches_raw_key_bytes() {
+    let (engine, _f) = temp_engine();
+    engine.set(b"user:1", b"alice").unwrap();
+    engine.set(b"user:2", b"bob").unwrap();
+    engine.set(b"order:1", b"book").unwrap();
+    engine.set(b"user:3", b"carol").unwrap();
+    engine.del(b"user:3").unwrap();
+    engine.set(b"users", b"not under user:").unwrap();
+    engine.set(&[0x00, 0xFF, 0x01], b"binary").unwrap();
+    engine.set(&[0x00, 0xFE], b"near miss").unwrap();
+
+    assert_eq!(
+        engine.scan_prefix(b"user:").unwrap().into_vec(),
+        [
+            (b"user:1".to_vec(), b"alice".to_vec()),
+            (b"user:2".to_vec(), b"bob".to_vec()),
+        ]
+    );
+    assert_eq!(
+        engine.scan_prefix(&[0x00, 0xFF]).unwrap().into_vec(),
+        [(vec![0x00, 0xFF, 0x01], b"binary".to_vec())]
+    );
+    assert_eq!(engine.scan_prefix(b"").unwrap(), engine.scan().unwrap());
+    assert_eq!(engine.scan_prefix(b"").unwrap().len(), 6);
+    assert!(engine.scan_prefix(b"user:3").unwrap().is_empty());
+
+    // Compaction drops the tombstone without bringing the key back.
+    engine.compact().unwrap();
+    assert_eq!(engine.scan_prefix(b"user:").unwrap().len(), 2);
+}
+
+/// Scans `engine` with `scan_live` and with `scan_page` while another
+/// thread churns and compacts, checking what `LiveScan` promises: keys in
+/// strictly ascending order, and every key live throughout yielded once.
+fn check_live_scan_under_churn(options: Options) {
+    let file = NamedTempFile::new().unwrap();
+    let engine = Arc::new(Engine::load_with_options(file.path(), options).unwrap());
+    for i in 0..300 {
+        engine
+            .set(format!("s{:03}", i).as_bytes(), b"stable")
+            .unwrap();
+        engine.set(format!("c{:03}", i).as_bytes(), b"v0").unwrap();
+    }
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let churn = {
+        let engine = Arc::clone(&engine);
+        let stop = Arc::clone(&stop);
+        thread::spawn(move || {
+            let mut round = 0;
+            while !stop.load(Ordering::Relaxed) {
+                round += 1;
+                for i in 0..300 {
+                    let key = format!("c{:03}", i);
+                    match (i + round) % 3 {
+                        0 => engine.del(key.as_bytes()).unwrap(),
+                        _ => engine
+                            .set(key.as_bytes(), format!("v{}", round).as_bytes())
+                            .unwrap(),
+                    }
+                }
+                engine.compact().unwrap();
+            }
+        })
+    };
+
+    let check = |pairs: Vec<(Vec<u8>, Vec<u8>)>| {
+        assert!(pairs.windows(2).all(|w| w[0].0 < w[1].0));
+        let stable: Vec<_> = pairs.iter().filter(|(key, _)| key[0] == b's').collect();
+        assert_eq!(stable.len(), 300);
+        assert!(stable.iter().all(|(_, value)| value == b"stable"));
+        assert!(
+            pairs
+                .iter()
+                .filter(|(key, _)| key[0] == b'c')
+                .all(|(_, value)| value[0] == b'v')
+        );
+    };
+    for _ in 0..5 {
+        let scan = engine.scan_live(b"").unwrap().page_size(16);
+        check(scan.collect::<Result<_, _>>().unwrap());
+
+        let mut pairs = Vec::new();
+        let mut cursor = None;
+        loop {
+            let mut page = engine.scan_page(b"", cursor.as_deref(), 50).unwrap();
+            let before = pairs.len();
+            for pair in page.by_ref() {
+                pairs.push(pair.unwrap());
+            }
+            if pairs.len() == before {
+                break;
+            }
+            cursor = page.cursor().map(<[u8]>::to_vec);
+        }
+        check(pairs);
+    }
+
+    stop.store(true, Ordering::Relaxed);
+    churn.join().unwrap();
+    let mut seen = 0;
+    let count = engine.for_each(b"s", |_, value| {
+        assert_eq!(value, b"stable");
+        seen += 1;
+    });
+    assert_eq!(count.unwrap(), 300);
+    assert_eq!(seen, 300);
+}
+
+#[test]
+fn test_live_scan_guarantees_hold_under_churn() {
+    check_live_scan_under_churn(Options::default());
+}
+
+#[test]
+fn test_live_scan_guarantees_hold_under_churn_with_ordered_index() {
+    check_live_scan_under_churn(Options {
+        ordered_index: true,
+        ..Options::default()
+    });
+}
+
+// ==================== Reader Pool ====================
+
+/// Descriptors this process holds on `path` after it was unlinked.
+#[cfg(target_os = "linux")]
+fn deleted_fds_of(path: &std::path::Path) -> usize {
+    let target = format!("{} (deleted)", path.display());
+    fs::read_dir("/proc/self/fd")
+        .unwrap()
+        .filter_map(|entry| fs::read_link(entry.ok()?.path()).ok())
+        .filter(|link| link.to_string_lossy() == target)
+        .count()
+}
+
+#[test]
+fn test_compactions_under_concurrent_reads_leave_no_stale_readers() {
+    // A `NamedTempFile` keeps its own handle on the file compaction replaces.
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("data.db");
+    let engine = Arc::new(Engine::load(&path).unwrap());
+    for i in 0..100u8 {
+        engine.set(&[b'k', i], &[i; 64]).unwrap();
+    }
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let readers: Vec<_> = (0..4u64)
+        .map(|t| {
+            let engine = Arc::clone(&engine);
+            let stop = Arc::clone(&stop);
+            thread::spawn(move || {
+                let mut rng = XorShift(t + 1);
+                while !stop.load(Ordering::Relaxed) {
+                    let i = (rng.next() % 100) as u8;
+                    assert_eq!(engine.get(&[b'k', i]).unwrap(), Some(vec![i; 64]));
+                }
+            })
+        })
+        .collect();
+    for round in 0..30u8 {
+        engine.set(&[b'k', round], &[round; 64]).unwrap();
+        engine.compact().unwrap();
+    }
+    stop.store(true, Ordering::Relaxed);
+    for reader in readers {
+        reader.join().unwrap();
+    }
+
+    let stats = engine.stats();
+    assert!(stats.generation >= 30);
+    assert!(
+        stats
+            .reader_fds
+            .iter()
+            .all(|&(generation, _)| generation == stats.generation),
+        "{:?} at generation {}",
+        stats.reader_fds,
+        stats.generation
+    );
+    let open: usize = stats.reader_fds.iter().map(|&(_, count)| count).sum();
+    assert_eq!(open, stats.pooled_readers);
+    #[cfg(target_os = "linux")]
+    assert_eq!(deleted_fds_of(&path), 0);
+}
+
+// ==================== Close ====================
+
+fn assert_closed<T: std::fmt::Debug>(result: std::io::Result<T>) {
+    assert_engine_error(result.unwrap_err(), EngineError::Closed);
+}
+
+#[test]
+fn test_close_fails_every_later_call_on_other_threads() {
+    let file = NamedTempFile::new().unwrap();
+    let options = Options {
+        durability: Durability::Interval {
+            period: Duration::from_secs(60),
+            jitter: Duration::ZERO,
+        },
+        ..Options::default()
+    };
+    let engine = Arc::new(Engine::load_with_options(file.path(), options).unwrap());
+    engine.set(b"kept", b"synced on close").unwrap();
+
+    // Channels rather than barriers, so a thread that panics ends the test
+    // instead of leaving the other waiting forever.
+    let (ready_tx, ready) = std::sync::mpsc::channel();
+    let (closed, closed_rx) = std::sync::mpsc::channel();
+    let user = {
+        let engine = Arc::clone(&engine);
+        thread::spawn(move || {
+            assert_eq!(
+                engine.get(b"kept").unwrap(),
+                Some(b"synced on close".to_vec())
+            );
+            ready_tx.send(()).unwrap();
+            closed_rx.recv_timeout(Duration::from_secs(10)).unwrap();
+            assert!(engine.is_closed());
+            assert_closed(engine.get(b"kept"));
+            assert_closed(engine.get(b"missing"));
+            assert_closed(engine.set(b"late", b"write"));
+            assert_closed(engine.del(b"kept"));
+            assert_closed(engine.bucket(b"b").unwrap().set(b"k", b"v"));
+            assert_closed(engine.compact());
+            assert_closed(engine.sync());
+            assert_closed(engine.reload());
+            // Answers from memory keep working.
+            assert_eq!(engine.keys(), vec![b"kept".to_vec()]);
+        })
+    };
+    ready.recv_timeout(Duration::from_secs(10)).unwrap();
+    // The sync thread is asleep for the rest of its period; close must wake
+    // it rather than wait the period out.
+    let start = std::time::Instant::now();
+    engine.close().unwrap();
+    assert!(start.elapsed() < Duration::from_secs(5));
+    closed.send(()).unwrap();
+    user.join().unwrap();
+
+    let size = engine.stats().file_size;
+    drop(engine);
+    let engine = Engine::load(file.path()).unwrap();
+    assert_eq!(engine.stats().file_size, size);
+    assert_eq!(
+        engine.get(b"kept").unwrap(),
+        Some(b"synced on close".to_vec())
+    );
+}
+
+#[test]
+fn test_close_twice_and_concurrently_is_a_no_op() {
+    let (engine, _f) = temp_engine();
+    engine.set(b"k", b"v").unwrap();
+    let engine = Arc::new(engine);
+
+    let closers: Vec<_> = (0..4)
+        .map(|_| {
+            let engine = Arc::clone(&engine);
+            thread::spawn(move || engine.close())
+        })
+        .collect();
+    for closer in closers {
+        closer.join().unwrap().unwrap();
+    }
+    engine.close().unwrap();
+    assert!(engine.is_closed());
+    assert_closed(engine.get(b"k"));
+    assert_eq!(engine.stats().pooled_readers, 0);
+}
+
+/// Writers and readers hammer an engine running every background worker
+/// until `close` stops them; every write that returned `Ok` must survive.
+fn close_under_load(runtime: Option<Arc<KvRuntime>>) {
+    let file = NamedTempFile::new().unwrap();
+    let options = Options {
+        durability: Durability::Interval {
+            period: Duration::from_millis(1),
+            jitter: Duration::from_millis(1),
+        },
+        idle_after: Some(Duration::from_millis(1)),
+        runtime,
+        ..Options::default()
+    };
+    let engine = Arc::new(Engine::load_with_options(file.path(), options).unwrap());
+
+    let writers: Vec<_> = (0..4u8)
+        .map(|t| {
+            let engine = Arc::clone(&engine);
+            thread::spawn(move || {
+                let mut acked = Vec::new();
+                for i in 0u32.. {
+                    let key = [&[t][..], &i.to_be_bytes()].concat();
+                    match engine.set(&key, &i.to_le_bytes()) {
+                        Ok(()) => acked.push((key, i)),
+                        Err(e) => {
+                            assert_engine_error(e, EngineError::Closed);
+                            return acked;
+                        }
+                    }
+                }
+                unreachable!()
+            })
+        })
+        .collect();
+    let reader = {
+        let engine = Arc::clone(&engine);
+        thread::spawn(move || {
+            while !engine.is_closed() {
+                if let Err(e) = engine.get(&[0, 0, 0, 0, 0]) {
+                    assert_engine_error(e, EngineError::Closed);
+                }
+                thread::sleep(Duration::from_millis(2));
+            }
+        })
+    };
+
+    thread::sleep(Duration::from_millis(50));
+    engine.close().unwrap();
+    let acked: Vec<_> = writers
+        .into_iter()
+        .flat_map(|writer| writer.join().unwrap())
+        .collect();
+    reader.join().unwrap();
+    assert!(!acked.is_empty());
+    drop(engine);
+
+    let engine = Engine::load(file.path()).unwrap();
+    assert_eq!(engine.stats().live_keys, acked.len());
+    for (key, i) in acked {
+        assert_eq!(engine.get(&key).unwrap(), Some(i.to_le_bytes().to_vec()));
+    }
+}
+
+#[test]
+fn test_close_under_load_stops_every_worker_without_losing_writes() {
+    close_under_load(None);
+    close_under_load(Some(KvRuntime::new(None).unwrap()));
+}
+
+#[test]
+fn test_close_names_workers_that_outlast_the_shutdown_timeout() {
+    let storage = common::InstrumentedStorage::default();
+    let probe = Arc::clone(&storage.probe);
+    let file = NamedTempFile::new().unwrap();
+    let engine = Engine::load_with_options(
+        file.path(),
+        Options {
+            storage: Arc::new(storage),
+            idle_after: Some(Duration::from_millis(20)),
+            shutdown_timeout: Duration::from_millis(20),
+            ..Options::default()
+        },
+    )
+    .unwrap();
+    engine.set(b"k", b"acknowledged").unwrap();
+    engine.get(b"k").unwrap();
+
+    // The idle thread gets stuck closing the pooled read handle.
+    probe.set_close_delay(Duration::from_millis(500));
+    assert!(wait_for(|| probe.closes_in_flight() > 0));
+    let err = engine.close().unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+    assert_engine_error(err, EngineError::ShutdownTimedOut("kv-idle".into()));
+    assert!(engine.is_closed());
+    assert_closed(engine.get(b"k"));
+    engine.close().unwrap();
+
+    probe.set_close_delay(Duration::ZERO);
+    drop(engine);
+    let engine = Engine::load(file.path()).unwrap();
+    assert_eq!(engine.get(b"k").unwrap(), Some(b"acknowledged".to_vec()));
+}
+
+// ==================== Readahead ====================
+
+/// Reads of the data file `dump`, `export_shm_snapshot`, and `verify` take
+/// over 2000 keys, 500 of them overwritten, with `readahead_bytes`.
+fn full_scan_reads(readahead_bytes: usize) -> u64 {
+    let storage = common::InstrumentedStorage::default();
+    let probe = Arc::clone(&storage.probe);
+    let file = NamedTempFile::new().unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let engine = Engine::load_with_options(
+        file.path(),
+        Options {
+            storage: Arc::new(storage),
+            readahead_bytes,
+            ..Options::default()
+        },
+    )
+    .unwrap();
+    for i in 0..2000u32 {
+        engine.set(&i.to_be_bytes(), &[1; 100]).unwrap();
+    }
+    for i in (0..2000u32).step_by(4) {
+        engine.set(&i.to_be_bytes(), &[2; 100]).unwrap();
+    }
+
+    probe.reset();
+    let mut dump = Vec::new();
+    assert_eq!(engine.dump(&mut dump).unwrap(), 2000);
+    let snapshot = dir.path().join("snapshot");
+    assert_eq!(engine.export_shm_snapshot(&snapshot).unwrap(), 2000);
+    assert_eq!(engine.verify().unwrap(), 2500);
+    let reads = probe.reads();
+
+    // Both ways read the same records.
+    let (restored, _f) = temp_engine();
+    restored.load_dump(&dump[..]).unwrap();
+    assert_eq!(
+        restored.get(&0u32.to_be_bytes()).unwrap(),
+        Some(vec![2; 100])
+    );
+    assert_eq!(
+        restored.get(&1u32.to_be_bytes()).unwrap(),
+        Some(vec![1; 100])
+    );
+    reads
+}
+
+#[test]
+fn test_full_scans_read_ahead_instead_of_record_by_record() {
+    let record_by_record = full_scan_reads(0);
+    assert!(record_by_record >= 2000 + 2000 + 2500, "{record_by_record}");
+    let read_ahead = full_scan_reads(Options::default().readahead_bytes);
+    assert!(read_ahead < 20, "{read_ahead}");
+}
+
+// ==================== Access Tracking ====================
+
+#[test]
+fn test_access_tracking_finds_hot_and_cold_keys() {
+    let file = NamedTempFile::new().unwrap();
+    let clock = ManualClock::new();
+    let options = Options {
+        track_access: true,
+        clock: clock.clone(),
+        ..Options::default()
+    };
+    let engine = Engine::load_with_options(file.path(), options).unwrap();
+    let key = |i: u32| format!("key{:03}", i).into_bytes();
+    for i in 0..200 {
+        engine.set(&key(i), b"v").unwrap();
+    }
+    let file_size = engine.stats().file_size;
+
+    // A skewed workload: five hot keys, a warm tail, and keys never read.
+    let mut rng = XorShift(11);
+    let mut reads: Vec<u32> = (0..5)
+        .flat_map(|i| vec![i; 1000 - 100 * i as usize])
+        .collect();
+    reads.extend((5..100).flat_map(|i| vec![i; 5]));
+    for n in (1..reads.len()).rev() {
+        reads.swap(n, (rng.next() % (n as u64 + 1)) as usize);
+    }
+    for &i in &reads {
+        engine.get(&key(i)).unwrap();
+    }
+    clock.advance(Duration::from_secs(3600));
+    for i in 50..100 {
+        engine.get(&key(i)).unwrap();
+    }
+
+    let hottest = engine.hottest_keys(5).unwrap();
+    let hot: Vec<Vec<u8>> = hottest.iter().map(|(k, _)| k.clone()).collect();
+    assert_eq!(hot, (0..5).map(key).collect::<Vec<_>>());
+    for (i, (_, count)) in hottest.iter().enumerate() {
+        assert!(*count >= 1000 - 100 * i as u64);
+    }
+
+    let coldest = engine
+        .coldest_keys(1000, Duration::from_secs(1800))
+        .unwrap();
+    assert_eq!(coldest.len(), 150);
+    let (never, stale) = coldest.split_at(100);
+    assert!(
+        never
+            .iter()
+            .all(|(k, idle)| idle.is_none() && *k >= key(100))
+    );
+    assert!(
+        stale.iter().all(|(k, idle)| {
+            *k < key(50) && idle.is_some_and(|d| d >= Duration::from_secs(3600))
+        })
+    );
+
+    // Tracking is in memory only.
+    let stats = engine.stats();
+    assert_eq!(stats.file_size, file_size);
+    assert!(stats.access_tracker_bytes > 0);
+    drop(engine);
+    let engine = Engine::load_with_options(
+        file.path(),
+        Options {
+            track_access: true,
+            ..Options::default()
+        },
+    )
+    .unwrap();
+    assert!(engine.hottest_keys(5).unwrap().is_empty());
+}
+
+#[test]
+fn test_access_queries_need_track_access() {
+    let (engine, _f) = temp_engine();
+    engine.set(b"k", b"v").unwrap();
+    engine.get(b"k").unwrap();
+    let err = engine.hottest_keys(1).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::Unsupported);
+    assert!(engine.coldest_keys(1, Duration::ZERO).is_err());
+    assert_eq!(engine.stats().access_tracker_bytes, 0);
+}
+
+// ==================== Store Fixture ====================
+
+/// Kind tags of every record in the current-format file at `path`.
+fn record_kinds(path: &std::path::Path) -> BTreeSet<u8> {
+    let bytes = fs::read(path).unwrap();
+    let mut pos = FILE_HEADER_SIZE as usize;
+    let mut kinds = BTreeSet::new();
+    while pos < bytes.len() {
+        let (mut len, mut shift) = (0usize, 0);
+        loop {
+            let byte = bytes[pos];
+            pos += 1;
+            len |= ((byte & 0x7F) as usize) << shift;
+            shift += 7;
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+        pos += len;
+        kinds.insert(bytes[pos - 1]);
+    }
+    kinds
+}
+
+#[test]
+fn test_fixture_is_deterministic_and_reads_back() {
+    let build = |seed| {
+        let file = NamedTempFile::new().unwrap();
+        let fixture = StoreFixture::builder()
+            .keys(200)
+            .overwrite_ratio(0.3)
+            .deleted_ratio(0.1)
+            .value_size(64..4096)
+            .seed(seed)
+            .build(file.path())
+            .unwrap();
+        (fixture, file)
+    };
+    let (first, _f1) = build(7);
+    let (second, _f2) = build(7);
+    let (other, _f3) = build(8);
+
+    assert_eq!(first.live(), second.live());
+    assert_eq!(first.deleted(), second.deleted());
+    assert_eq!(first.overwritten(), second.overwritten());
+    assert_eq!(first.log_len(), second.log_len());
+    assert_ne!(first.live(), other.live());
+    assert_eq!(first.overwritten().len(), 60);
+    // 20 keys and `fixture/soft`.
+    assert_eq!(first.deleted().len(), 21);
+
+    let engine = Engine::load(first.path()).unwrap();
+    assert_eq!(engine.stats().live_keys, first.live().len());
+    assert_fixture_reads_back(&engine, &first);
+    // Nothing compacted while it was written, and the threshold is back.
+    assert_eq!(engine.stats().compact_threshold, DEFAULT_COMPACT_THRESHOLD);
+}
+
+#[test]
+fn test_fixture_writes_every_record_kind() {
+    let file = NamedTempFile::new().unwrap();
+    let fixture = StoreFixture::builder()
+        .keys(10)
+        .deleted_ratio(0.2)
+        .build(file.path())
+        .unwrap();
+    // An `Archived` stub would tie the fixture to an archive file.
+    let all: BTreeSet<u8> = EntryKind::ALL
+        .iter()
+        .filter(|&&kind| kind != EntryKind::Archived)
+        .map(|&kind| kind as u8)
+        .collect();
+    assert_eq!(record_kinds(fixture.path()), all);
+
+    let plain = NamedTempFile::new().unwrap();
+    let fixture = StoreFixture::builder()
+        .keys(10)
+        .deleted_ratio(0.2)
+        .plain()
+        .build(plain.path())
+        .unwrap();
+    let puts_and_deletes = [EntryKind::Put as u8, EntryKind::Tombstone as u8];
+    assert_eq!(
+        record_kinds(fixture.path()),
+        BTreeSet::from(puts_and_deletes)
+    );
+}
+
+// ==================== Mirroring ====================
+
+/// Loads a copy of the mirror at `mirror`, leaving the mirror itself as the
+/// engine writing it sees it.
+fn load_mirror_copy(mirror: &std::path::Path) -> Engine {
+    let copy = mirror.with_extension("copy");
+    fs::copy(mirror, &copy).unwrap();
+    Engine::load(&copy).unwrap()
+}
+
+fn mirror_key(i: u32) -> Vec<u8> {
+    format!("k{:03}", i).into_bytes()
+}
+
+#[test]
+fn test_mirror_killed_mid_stream_stays_loadable() {
+    let dir = tempfile::tempdir().unwrap();
+    let primary = dir.path().join("data.db");
+    let mirror = dir.path().join("mirror.db");
+    let storage = common::InstrumentedStorage::default();
+    let errors = Arc::new(AtomicU64::new(0));
+    let seen = Arc::clone(&errors);
+    let options = Options {
+        storage: Arc::new(storage.clone()),
+        mirror_path: Some(mirror.clone()),
+        on_mirror_error: Some(Hook::new(move |_: &std::io::Error| {
+            seen.fetch_add(1, Ordering::SeqCst);
+        })),
+        ..Options::default()
+    };
+    let engine = Engine::load_with_options(&primary, options).unwrap();
+    for i in 0..50 {
+        engine.set(&mirror_key(i), &[i as u8; 40]).unwrap();
+    }
+    assert_eq!(fs::read(&primary).unwrap(), fs::read(&mirror).unwrap());
+
+    // The mirror's disk dies partway into some later record.
+    storage.probe.kill_writes_to(&mirror, 1000);
+    for i in 50..100 {
+        engine.set(&mirror_key(i), &[i as u8; 40]).unwrap();
+        // At every point the mirror loads, holding the writes up to where
+        // it died.
+        let copy = load_mirror_copy(&mirror);
+        let held = copy.keys().len() as u32;
+        assert!((50..=i + 1).contains(&held));
+        for j in 0..held {
+            assert_eq!(copy.get(&mirror_key(j)).unwrap(), Some(vec![j as u8; 40]));
+        }
+        assert_eq!(copy.get(&mirror_key(held)).unwrap(), None);
+    }
+
+    let status = engine.mirror_status().unwrap();
+    assert!(status.stopped.is_some());
+    assert_eq!(status.failures, 1);
+    assert_eq!(errors.load(Ordering::SeqCst), 1);
+    let stats = engine.stats();
+    assert!(stats.mirror_stopped);
+    assert_eq!(stats.mirror_failures, 1);
+    assert_eq!(engine.keys().len(), 100);
+
+    // A reload copies the data file over the mirror and resumes it.
+    storage.probe.kill_writes_to(&mirror, u64::MAX);
+    engine.reload().unwrap();
+    engine.set(b"after", b"reload").unwrap();
+    let status = engine.mirror_status().unwrap();
+    assert_eq!(status.stopped, None);
+    assert_eq!(status.copies, 2);
+    assert_eq!(fs::read(&primary).unwrap(), fs::read(&mirror).unwrap());
+}
+
+#[test]
+fn test_fatal_mirror_failure_refuses_writes_until_reload() {
+    let dir = tempfile::tempdir().unwrap();
+    let primary = dir.path().join("data.db");
+    let mirror = dir.path().join("mirror.db");
+    let storage = common::InstrumentedStorage::default();
+    let options = Options {
+        storage: Arc::new(storage.clone()),
+        mirror_path: Some(mirror.clone()),
+        mirror_failure: MirrorFailure::Fatal,
+        ..Options::default()
+    };
+    let engine = Engine::load_with_options(&primary, options).unwrap();
+    engine.set(b"before", b"kept").unwrap();
+    let len = fs::metadata(&primary).unwrap().len();
+
+    storage.probe.kill_writes_to(&mirror, 10);
+    let is_mirror_failed = |err: std::io::Error| {
+        matches!(
+            EngineError::from_io(&err),
+            Some(EngineError::MirrorFailed(_))
+        )
+    };
+    assert!(is_mirror_failed(
+        engine.set(b"lost", &[1u8; 100]).unwrap_err()
+    ));
+    assert_eq!(fs::metadata(&primary).unwrap().len(), len);
+    assert_eq!(engine.get(b"lost").unwrap(), None);
+    assert!(is_mirror_failed(engine.del(b"before").unwrap_err()));
+
+    storage.probe.kill_writes_to(&mirror, u64::MAX);
+    engine.reload().unwrap();
+    engine.set(b"after", b"reload").unwrap();
+    engine.set(b"before", b"overwritten").unwrap();
+    engine.compact().unwrap();
+    assert_eq!(fs::read(&primary).unwrap(), fs::read(&mirror).unwrap());
+    drop(engine);
+
+    // The mirror stands in for a lost data file.
+    fs::remove_file(&primary).unwrap();
+    let engine = Engine::load(&mirror).unwrap();
+    assert_eq!(
+        engine.get(b"before").unwrap(),
+        Some(b"overwritten".to_vec())
+    );
+    assert_eq!(engine.get(b"after").unwrap(), Some(b"reload".to_vec()));
+    assert_eq!(engine.get(b"lost").unwrap(), None);
+}
+
+// ==================== Archiving ====================
+
+fn archiving(dir: &std::path::Path) -> Options {
+    Options {
+        archive_path: Some(dir.join("cold.db")),
+        ..Options::default()
+    }
+}
+
+#[test]
+fn test_archived_values_read_through_and_a_rewrite_promotes_them() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("data.db");
+    let engine = Engine::load_with_options(&path, archiving(dir.path())).unwrap();
+    for i in 0..20u8 {
+        engine.set(&[b'c', i], &[i; 500]).unwrap();
+        engine.set(&[b'h', i], &[i; 500]).unwrap();
+    }
+    let mut batch = WriteBatch::new();
+    batch.put_with_ttl(b"cttl", b"has a ttl", Duration::from_secs(3600));
+    engine.apply_batch(&batch).unwrap();
+
+    let moved = engine.archive_where(|key| key[0] == b'c').unwrap();
+    assert_eq!(moved, 20);
+    assert_eq!(engine.archive_where(|key| key[0] == b'c').unwrap(), 0);
+    let stats = engine.stats();
+    assert_eq!(stats.archived_keys, 20);
+    assert!(stats.archive_file_size > 20 * 500);
+    let hot_size = stats.file_size;
+
+    // Reads follow the stubs, scans included.
+    assert_eq!(engine.get(&[b'c', 7]).unwrap(), Some(vec![7; 500]));
+    assert_eq!(engine.get(&[b'h', 7]).unwrap(), Some(vec![7; 500]));
+    assert_eq!(
+        engine.get_range(&[b'c', 3], 490, 100).unwrap(),
+        Some(vec![3; 10])
+    );
+    assert_eq!(engine.get(b"cttl").unwrap(), Some(b"has a ttl".to_vec()));
+    let scanned = engine.scan_prefix(b"c").unwrap();
+    assert_eq!(scanned.len(), 21);
+
+    // Compaction drops the moved values and keeps the stubs.
+    engine.compact().unwrap();
+    let stats = engine.stats();
+    assert!(stats.file_size < hot_size - 20 * 500);
+    assert_eq!(stats.archived_keys, 20);
+    assert_eq!(engine.get(&[b'c', 19]).unwrap(), Some(vec![19; 500]));
+
+    // Writing a key again brings it back; deleting it drops the stub.
+    engine.set(&[b'c', 0], b"hot again").unwrap();
+    engine.del(&[b'c', 1]).unwrap();
+    assert_eq!(engine.stats().archived_keys, 18);
+    assert_eq!(engine.get(&[b'c', 0]).unwrap(), Some(b"hot again".to_vec()));
+    assert_eq!(engine.get(&[b'c', 1]).unwrap(), None);
+    engine.compact().unwrap();
+    assert_eq!(engine.get(&[b'c', 0]).unwrap(), Some(b"hot again".to_vec()));
+    assert_eq!(engine.get(&[b'c', 2]).unwrap(), Some(vec![2; 500]));
+}
+
+#[test]
+fn test_archive_moves_only_values_older_than_the_cutoff() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("data.db");
+    let engine = Engine::load_with_options(&path, archiving(dir.path())).unwrap();
+    let day_ago = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as i64
+        - 24 * 60 * 60 * 1000;
+    engine.set_with_tstamp(b"old", b"cold", day_ago).unwrap();
+    engine.set(b"new", b"warm").unwrap();
+
+    assert_eq!(engine.archive(Duration::from_secs(3600)).unwrap(), 1);
+    assert_eq!(engine.stats().archived_keys, 1);
+    assert_eq!(engine.get(b"old").unwrap(), Some(b"cold".to_vec()));
+    assert_eq!(engine.get(b"new").unwrap(), Some(b"warm".to_vec()));
+
+    let unset = Engine::load(dir.path().join("other.db")).unwrap();
+    let err = unset.archive(Duration::ZERO).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+}
+
+#[test]
+fn test_archived_store_reloads_only_with_its_archive_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("data.db");
+    let engine = Engine::load_with_options(&path, archiving(dir.path())).unwrap();
+    engine.set(b"cold", b"archived").unwrap();
+    engine.set(b"hot", b"kept").unwrap();
+    engine.archive_where(|key| key == b"cold").unwrap();
+    engine.reload().unwrap();
+    assert_eq!(engine.get(b"cold").unwrap(), Some(b"archived".to_vec()));
+    drop(engine);
+
+    for mode in [OpenMode::Standard, OpenMode::Fast] {
+        let options = Options {
+            open_mode: mode,
+            ..archiving(dir.path())
+        };
+        let engine = Engine::load_with_options(&path, options).unwrap();
+        assert_eq!(engine.stats().archived_keys, 1);
+        assert_eq!(engine.get(b"cold").unwrap(), Some(b"archived".to_vec()));
+        assert_eq!(engine.get(b"hot").unwrap(), Some(b"kept".to_vec()));
+    }
+
+    let is_archive_missing = |err: std::io::Error| {
+        matches!(
+            EngineError::from_io(&err),
+            Some(EngineError::ArchiveMissing(_))
+        )
+    };
+    assert!(is_archive_missing(Engine::load(&path).err().unwrap()));
+    fs::rename(dir.path().join("cold.db"), dir.path().join("moved.db")).unwrap();
+    let err = Engine::load_with_options(&path, archiving(dir.path()))
+        .err()
+        .unwrap();
+    assert!(err.to_string().contains("cold.db"));
+    assert!(is_archive_missing(err));
+}
+
+// ==================== Cache Audit ====================
+
+#[test]
+fn test_audit_caches_is_clean_after_every_kind_of_write() {
+    // Dedup and history cannot be combined, so each gets a run of its own.
+    let base = || Options {
+        ordered_index: true,
+        value_hashes: true,
+        tracked_prefixes: vec![b"key-00".to_vec()],
+        ..Options::default()
+    };
+    for options in [
+        Options {
+            dedup_min_value_len: Some(256),
+            ..base()
+        },
+        Options {
+            history_depth: 2,
+            ..base()
+        },
+    ] {
+        let file = NamedTempFile::new().unwrap();
+        StoreFixture::builder()
+            .keys(50)
+            .overwrite_ratio(0.3)
+            .deleted_ratio(0.2)
+            .build(file.path())
+            .unwrap();
+        let engine = Engine::load_with_options(file.path(), options).unwrap();
+        assert_eq!(engine.audit_caches().unwrap(), vec![]);
+
+        engine.bucket(b"a").unwrap().set(b"k", b"in a").unwrap();
+        engine.bucket(b"b").unwrap().set(b"k", b"in b").unwrap();
+        engine
+            .bucket(b"a")
+            .unwrap()
+            .set_quota(Quota {
+                max_keys: Some(10),
+                max_bytes: None,
+            })
+            .unwrap();
+        engine.swap_buckets(b"a", b"b").unwrap();
+        engine.set(b"key-000001", &[0xD5; 256]).unwrap();
+        engine.del(b"key-000002").unwrap();
+        assert_eq!(engine.audit_caches().unwrap(), vec![]);
+
+        engine.compact().unwrap();
+        assert_eq!(engine.audit_caches().unwrap(), vec![]);
+    }
+}
+
+#[test]
+fn test_audit_caches_catches_a_stale_value_hash() {
+    let file = NamedTempFile::new().unwrap();
+    let fixture = StoreFixture::builder()
+        .keys(10)
+        .plain()
+        .build(file.path())
+        .unwrap();
+    let options = Options {
+        value_hashes: true,
+        ..Options::default()
+    };
+    let engine = Engine::load_with_options(file.path(), options).unwrap();
+    assert_eq!(engine.audit_caches().unwrap(), vec![]);
+
+    // Changing the value behind the engine's back leaves its cached hash
+    // stale, as a missed invalidation would.
+    let key = StoreFixture::key(3);
+    let value_at = fixture.value_len_offset(&key).unwrap() + 8;
+    Corruption::FlipByte(value_at).apply(file.path()).unwrap();
+    let cached = Engine::hash_value(&fixture.live()[&key]);
+    let mut changed = fixture.live()[&key].clone();
+    changed[0] = !changed[0];
+    assert_eq!(
+        engine.audit_caches().unwrap(),
+        vec![CacheInconsistency::ValueHash {
+            key,
+            cached,
+            on_disk: Engine::hash_value(&changed),
+        }]
+    );
 }
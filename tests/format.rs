@@ -0,0 +1,144 @@
+use breakout1_kv_store::constants::DEFAULT_COMPACT_THRESHOLD;
+use breakout1_kv_store::format::{self, FORMAT_VERSION, Header, Record};
+use breakout1_kv_store::format_info::VERSIONS;
+use breakout1_kv_store::types::EntryKind;
+use breakout1_kv_store::{Engine, WriteBatch};
+use std::fs;
+use std::io::{self, Cursor};
+use tempfile::tempdir;
+
+fn put(key: &[u8], value: &[u8]) -> Record {
+    Record {
+        tstamp: 1_700_000_000_000,
+        key: key.to_vec(),
+        value: Some(value.to_vec()),
+        kind: EntryKind::Put,
+        origin: None,
+    }
+}
+
+/// Every record of a log, read with the public functions.
+fn read_all(bytes: &[u8]) -> (Header, Vec<Record>) {
+    let mut r = Cursor::new(bytes);
+    let header = format::read_header(&mut r).unwrap();
+    let mut records = Vec::new();
+    while let Some(record) = format::read_record(&mut r, header.format_version).unwrap() {
+        records.push(record);
+    }
+    (header, records)
+}
+
+#[test]
+fn test_rewriting_an_engine_log_is_byte_identical() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("data.db");
+    let engine = Engine::load(&path).unwrap();
+    engine.set(b"a", b"1").unwrap();
+    engine.set(b"b", &vec![7u8; 300]).unwrap();
+    engine.del(b"a").unwrap();
+    let mut batch = WriteBatch::new();
+    batch.put(b"c", b"3");
+    batch.delete(b"b");
+    engine.apply_batch(&batch).unwrap();
+    engine.compact().unwrap();
+    engine.set(b"d", b"after compaction").unwrap();
+    let size = engine.stats().file_size;
+    drop(engine);
+
+    let bytes = fs::read(&path).unwrap();
+    let bytes = &bytes[..size as usize];
+    let (header, records) = read_all(bytes);
+    assert_eq!(header.format_version, FORMAT_VERSION);
+    assert_eq!(header.size(), format::FILE_HEADER_SIZE);
+    assert!(records.iter().any(|r| r.key == b"c"));
+    assert_eq!(records.last().unwrap().key, b"d");
+
+    let mut rewritten = Vec::new();
+    format::write_header(&mut rewritten, &header).unwrap();
+    for record in &records {
+        let n = format::write_record(&mut rewritten, header.format_version, record).unwrap();
+        assert!(n > 0);
+    }
+    assert_eq!(rewritten, bytes);
+}
+
+#[test]
+fn test_logs_written_with_the_format_module_load() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("data.db");
+    let mut bytes = Vec::new();
+    let header = Header {
+        format_version: FORMAT_VERSION,
+        compact_threshold: DEFAULT_COMPACT_THRESHOLD,
+        epoch: 0,
+        flags: 0,
+    };
+    format::write_header(&mut bytes, &header).unwrap();
+    for record in [put(b"k", b"old"), put(b"k", b"new"), put(b"gone", b"x")] {
+        format::write_record(&mut bytes, FORMAT_VERSION, &record).unwrap();
+    }
+    let tombstone = Record {
+        value: None,
+        kind: EntryKind::Tombstone,
+        ..put(b"gone", b"")
+    };
+    format::write_record(&mut bytes, FORMAT_VERSION, &tombstone).unwrap();
+    fs::write(&path, &bytes).unwrap();
+
+    let engine = Engine::load(&path).unwrap();
+    assert_eq!(engine.get(b"k").unwrap(), Some(b"new".to_vec()));
+    assert_eq!(engine.get(b"gone").unwrap(), None);
+    engine.set(b"more", b"appended").unwrap();
+    let size = engine.stats().file_size;
+    drop(engine);
+
+    let on_disk = fs::read(&path).unwrap();
+    assert_eq!(on_disk[..bytes.len()], bytes[..]);
+    let (read_back, records) = read_all(&on_disk[..size as usize]);
+    assert_eq!(read_back, header);
+    let last = records.last().unwrap();
+    assert_eq!(last.key, b"more");
+    assert_eq!(last.value.as_deref(), Some(b"appended".as_slice()));
+}
+
+#[test]
+fn test_records_round_trip_in_every_version() {
+    for spec in VERSIONS {
+        let mut bytes = Vec::new();
+        let header = Header {
+            format_version: spec.version,
+            compact_threshold: 4096,
+            epoch: 0,
+            flags: 0,
+        };
+        format::write_header(&mut bytes, &header).unwrap();
+        assert_eq!(bytes.len() as u64, spec.header_size, "KVS{}", spec.version);
+        let value = vec![spec.version; 200];
+        format::write_record(&mut bytes, spec.version, &put(b"key", &value)).unwrap();
+
+        let (read_back, records) = read_all(&bytes);
+        assert_eq!(read_back, header);
+        assert_eq!(records.len(), 1, "KVS{}", spec.version);
+        assert_eq!(records[0].key, b"key");
+        assert_eq!(records[0].value.as_deref(), Some(value.as_slice()));
+
+        // A torn record and pre-allocated zeros both end the log.
+        let mut torn = Cursor::new(&bytes[spec.header_size as usize..bytes.len() - 1]);
+        assert!(
+            format::read_record(&mut torn, spec.version)
+                .unwrap()
+                .is_none()
+        );
+        let mut zeros = Cursor::new(vec![0u8; 64]);
+        assert!(
+            format::read_record(&mut zeros, spec.version)
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    let err = format::write_record(&mut Vec::new(), 99, &put(b"k", b"v")).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    let err = format::read_header(&mut Cursor::new(b"NOPE\0\0\0\0\0\0\0\0")).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+}
@@ -0,0 +1,430 @@
+use breakout1_kv_store::Engine;
+use breakout1_kv_store::constants::{
+    FILE_HEADER_MAGIC, FILE_HEADER_MAGIC_V1, FILE_HEADER_MAGIC_V2, FILE_HEADER_MAGIC_V3,
+    FILE_HEADER_MAGIC_V4, FILE_HEADER_MAGIC_V5, FILE_HEADER_MAGIC_V6, FILE_HEADER_SIZE,
+    FORMAT_VERSION,
+};
+use breakout1_kv_store::format_info::{Codec, DetectedFormat, Framing, VERSIONS};
+use breakout1_kv_store::types::{DataFileEntry, DataFileEntryV1, DataFileEntryV2, EntryKind};
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use tempfile::tempdir;
+
+/// Writes a fixture: `magic`, a threshold, a zero writer epoch from KVS4
+/// on, zero flags from KVS6 on, and `records`, framed with 8-byte lengths
+/// before KVS3 and one-byte varints from it on.
+fn write_fixture(path: &Path, magic: Option<[u8; 4]>, records: &[Vec<u8>]) {
+    let mut f = fs::File::create(path).unwrap();
+    let modern = [
+        FILE_HEADER_MAGIC_V4,
+        FILE_HEADER_MAGIC_V5,
+        FILE_HEADER_MAGIC_V6,
+        FILE_HEADER_MAGIC,
+    ]
+    .map(Some);
+    let flagged = [FILE_HEADER_MAGIC_V6, FILE_HEADER_MAGIC].map(Some);
+    if let Some(magic) = magic {
+        f.write_all(&magic).unwrap();
+        f.write_all(&4096u64.to_le_bytes()).unwrap();
+    }
+    if modern.contains(&magic) {
+        f.write_all(&0u64.to_le_bytes()).unwrap();
+    }
+    if flagged.contains(&magic) {
+        f.write_all(&0u64.to_le_bytes()).unwrap();
+    }
+    for data in records {
+        if magic == Some(FILE_HEADER_MAGIC_V3) || modern.contains(&magic) {
+            f.write_all(&[data.len() as u8]).unwrap();
+        } else {
+            f.write_all(&(data.len() as u64).to_le_bytes()).unwrap();
+        }
+        f.write_all(data).unwrap();
+    }
+}
+
+fn v1_record() -> Vec<u8> {
+    wincode::serialize(&DataFileEntryV1 {
+        tstamp: 1,
+        key: b"k".to_vec(),
+        value: Some(b"v".to_vec()),
+    })
+    .unwrap()
+}
+
+const LEGACY_HEADER: &str = "\
+header:
+     0  magic              4 ASCII bytes
+     4  compact_threshold  u64 LE
+records start:     12
+";
+
+const EPOCH_HEADER: &str = "\
+header:
+     0  magic              4 ASCII bytes
+     4  compact_threshold  u64 LE
+    12  writer_epoch       u64 LE
+records start:     20
+";
+
+const CURRENT_HEADER: &str = "\
+header:
+     0  magic              4 ASCII bytes
+     4  compact_threshold  u64 LE
+    12  writer_epoch       u64 LE
+    20  flags              u64 LE, bit 0 audit-only, bits 32-63 key transform id
+records start:     28
+";
+
+const RECORD_PREFIX: &str = "\
+record:
+     0  tstamp             i64 LE, ms since the epoch
+     8  key                u64 LE length, then bytes
+   var  value              u8 tag (0 none, 1 some), then u64 LE length and bytes
+";
+
+const ORIGIN_RECORD_PREFIX: &str = "\
+record:
+     0  tstamp             i64 LE, ms since the epoch
+     8  key                u64 LE length, then bytes
+   var  value              u8 flags (bit 0 some, bit 1 origin), then u64 LE length and bytes
+   var  origin             16 bytes, only with flag bit 1
+";
+
+const ALL_KINDS: &str = "   var  kind               u8 EntryKind tag, last byte of the record
+entry kinds:       Put, Tombstone, SoftDelete, Blob, BlobRef, ExpiringPut, BatchBegin, StalePut, Epoch, Archived
+checksum:          none
+compression:       none
+";
+
+#[test]
+fn test_describes_every_supported_version() {
+    let dir = tempdir().unwrap();
+    let v1 = dir.path().join("v1.db");
+    write_fixture(&v1, Some(FILE_HEADER_MAGIC_V1), &[v1_record()]);
+    let v2 = dir.path().join("v2.db");
+    let entry = DataFileEntry::put(1, b"k".to_vec(), b"v".to_vec());
+    write_fixture(
+        &v2,
+        Some(FILE_HEADER_MAGIC_V2),
+        &[wincode::serialize(&DataFileEntryV2::from(&entry)).unwrap()],
+    );
+    let v3 = dir.path().join("v3.db");
+    write_fixture(
+        &v3,
+        Some(FILE_HEADER_MAGIC_V3),
+        &[wincode::serialize(&DataFileEntryV2::from(&entry)).unwrap()],
+    );
+    let v4 = dir.path().join("v4.db");
+    write_fixture(
+        &v4,
+        Some(FILE_HEADER_MAGIC_V4),
+        &[wincode::serialize(&DataFileEntryV2::from(&entry)).unwrap()],
+    );
+    let v5 = dir.path().join("v5.db");
+    write_fixture(
+        &v5,
+        Some(FILE_HEADER_MAGIC_V5),
+        &[explicit_record(1, b"k", Some(b"v"), EntryKind::Put)],
+    );
+    let v6 = dir.path().join("v6.db");
+    write_fixture(
+        &v6,
+        Some(FILE_HEADER_MAGIC_V6),
+        &[explicit_record(1, b"k", Some(b"v"), EntryKind::Put)],
+    );
+    let v7 = dir.path().join("v7.db");
+    let engine = Engine::load(&v7).unwrap();
+    engine.set(b"k", b"v").unwrap();
+
+    let size = |path: &Path| fs::metadata(path).unwrap().len();
+    assert_eq!(
+        Engine::describe_format_of(&v1).unwrap().to_string(),
+        format!(
+            "format:            KVS1 (supported)\n\
+             file size:         {}\n\
+             magic:             KVS1\n\
+             compact threshold: 4096\n\
+             {}record framing:    fixed u64 LE length\n\
+             record codec:      wincode\n\
+             {}entry kinds:       Put, Tombstone\n\
+             checksum:          none\n\
+             compression:       none\n",
+            size(&v1),
+            LEGACY_HEADER,
+            RECORD_PREFIX
+        )
+    );
+    assert_eq!(
+        Engine::describe_format_of(&v2).unwrap().to_string(),
+        format!(
+            "format:            KVS2 (supported)\n\
+             file size:         {}\n\
+             magic:             KVS2\n\
+             compact threshold: 4096\n\
+             {}record framing:    fixed u64 LE length\n\
+             record codec:      wincode\n\
+             {}{}",
+            size(&v2),
+            LEGACY_HEADER,
+            RECORD_PREFIX,
+            ALL_KINDS
+        )
+    );
+    assert_eq!(
+        Engine::describe_format_of(&v3).unwrap().to_string(),
+        format!(
+            "format:            KVS3 (supported)\n\
+             file size:         {}\n\
+             magic:             KVS3\n\
+             compact threshold: 4096\n\
+             {}record framing:    LEB128 varint length\n\
+             record codec:      wincode\n\
+             {}{}",
+            size(&v3),
+            LEGACY_HEADER,
+            RECORD_PREFIX,
+            ALL_KINDS
+        )
+    );
+    assert_eq!(
+        Engine::describe_format_of(&v4).unwrap().to_string(),
+        format!(
+            "format:            KVS4 (supported)\n\
+             file size:         {}\n\
+             magic:             KVS4\n\
+             compact threshold: 4096\n\
+             writer epoch:      0\n\
+             {}record framing:    LEB128 varint length\n\
+             record codec:      wincode\n\
+             {}{}",
+            size(&v4),
+            EPOCH_HEADER,
+            RECORD_PREFIX,
+            ALL_KINDS
+        )
+    );
+    assert_eq!(
+        Engine::describe_format_of(&v5).unwrap().to_string(),
+        format!(
+            "format:            KVS5 (supported)\n\
+             file size:         {}\n\
+             magic:             KVS5\n\
+             compact threshold: 4096\n\
+             writer epoch:      0\n\
+             {}record framing:    LEB128 varint length\n\
+             record codec:      explicit little-endian\n\
+             {}{}",
+            size(&v5),
+            EPOCH_HEADER,
+            RECORD_PREFIX,
+            ALL_KINDS
+        )
+    );
+    assert_eq!(
+        Engine::describe_format_of(&v6).unwrap().to_string(),
+        format!(
+            "format:            KVS6 (supported)\n\
+             file size:         {}\n\
+             magic:             KVS6\n\
+             compact threshold: 4096\n\
+             writer epoch:      0\n\
+             audit only:        false\n\
+             key transform:     none\n\
+             {}record framing:    LEB128 varint length\n\
+             record codec:      explicit little-endian\n\
+             {}{}",
+            size(&v6),
+            CURRENT_HEADER,
+            RECORD_PREFIX,
+            ALL_KINDS
+        )
+    );
+    let description = engine.describe_format().unwrap();
+    assert_eq!(
+        description.to_string(),
+        format!(
+            "format:            KVS7 (supported, current)\n\
+             file size:         {}\n\
+             magic:             KVS7\n\
+             compact threshold: 1048576\n\
+             writer epoch:      0\n\
+             audit only:        false\n\
+             key transform:     none\n\
+             {}record framing:    LEB128 varint length\n\
+             record codec:      explicit little-endian\n\
+             {}{}",
+            size(&v7),
+            CURRENT_HEADER,
+            ORIGIN_RECORD_PREFIX,
+            ALL_KINDS
+        )
+    );
+    assert_eq!(description.framing, Some(Framing::Leb128Varint));
+    assert_eq!(description.codec, Some(Codec::ExplicitLe));
+
+    // The table covers every version up to the current one.
+    let versions: Vec<u8> = VERSIONS.iter().map(|spec| spec.version).collect();
+    assert_eq!(versions, (1..=FORMAT_VERSION).collect::<Vec<_>>());
+}
+
+#[test]
+fn test_names_files_it_cannot_open() {
+    let dir = tempdir().unwrap();
+    let newer = dir.path().join("newer.db");
+    fs::write(&newer, b"KVS9\0\0\0\0\0\0\0\0").unwrap();
+    let headerless = dir.path().join("headerless.db");
+    write_fixture(&headerless, None, &[v1_record(), v1_record()]);
+    let garbage = dir.path().join("garbage.db");
+    fs::write(&garbage, b"not a store at all").unwrap();
+    let empty = dir.path().join("empty.db");
+    fs::write(&empty, b"").unwrap();
+
+    for (path, detected) in [
+        (&newer, DetectedFormat::Newer { version: 9 }),
+        (&headerless, DetectedFormat::Headerless),
+        (&garbage, DetectedFormat::Unrecognized),
+        (&empty, DetectedFormat::Unrecognized),
+    ] {
+        let description = Engine::describe_format_of(path).unwrap();
+        assert_eq!(description.detected, detected, "{}", path.display());
+        assert!(description.header.is_empty());
+        assert!(description.framing.is_none());
+        assert!(Engine::load(path).is_err() || path == &empty);
+    }
+    assert_eq!(
+        Engine::describe_format_of(&headerless).unwrap().to_string(),
+        format!(
+            "format:            headerless log from before KVS1 (cannot open; see migrate_legacy)\n\
+             file size:         {}\n\
+             magic:             {}\n",
+            fs::metadata(&headerless).unwrap().len(),
+            [v1_record().len() as u8, 0, 0, 0].escape_ascii()
+        )
+    );
+}
+
+/// The bytes `ExplicitCodec` documents for an entry, built by hand.
+fn explicit_record(tstamp: i64, key: &[u8], value: Option<&[u8]>, kind: EntryKind) -> Vec<u8> {
+    let mut record = tstamp.to_le_bytes().to_vec();
+    record.extend_from_slice(&(key.len() as u64).to_le_bytes());
+    record.extend_from_slice(key);
+    match value {
+        Some(value) => {
+            record.push(1);
+            record.extend_from_slice(&(value.len() as u64).to_le_bytes());
+            record.extend_from_slice(value);
+        }
+        None => record.push(0),
+    }
+    record.push(kind as u8);
+    record
+}
+
+#[test]
+fn test_current_records_follow_the_explicit_layout() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("data.db");
+    let engine = Engine::load(&path).unwrap();
+    engine.set_with_tstamp(b"key", b"value", 0x0102).unwrap();
+    drop(engine);
+
+    let data = fs::read(&path).unwrap();
+    assert_eq!(&data[..4], b"KVS7");
+    #[rustfmt::skip]
+    let expected: &[u8] = &[
+        34,                                     // varint length
+        0x02, 0x01, 0, 0, 0, 0, 0, 0,           // tstamp
+        3, 0, 0, 0, 0, 0, 0, 0, b'k', b'e', b'y', // key
+        1, 5, 0, 0, 0, 0, 0, 0, 0,              // value flag and length
+        b'v', b'a', b'l', b'u', b'e',           // value
+        0,                                      // kind: Put
+    ];
+    assert_eq!(&data[FILE_HEADER_SIZE as usize..], expected);
+
+    // Byte for byte what wincode produced for KVS4.
+    let entry = DataFileEntry::put(0x0102, b"key".to_vec(), b"value".to_vec());
+    assert_eq!(
+        wincode::serialize(&DataFileEntryV2::from(&entry)).unwrap(),
+        &expected[1..]
+    );
+
+    // An origin sets bit 1 of the value flag and sits before the kind.
+    let engine = Engine::load(&path).unwrap();
+    engine.set_with_origin(b"o", b"", [0xAB; 16]).unwrap();
+    drop(engine);
+    let data = fs::read(&path).unwrap();
+    let record = &data[data.len() - 44..];
+    assert_eq!(record[0], 43); // varint length
+    #[rustfmt::skip]
+    let expected: &[u8] = &[
+        1, 0, 0, 0, 0, 0, 0, 0, b'o',           // key
+        3, 0, 0, 0, 0, 0, 0, 0, 0,              // value flags and length
+    ];
+    assert_eq!(&record[9..27], expected);
+    assert_eq!(&record[27..43], &[0xAB; 16]); // origin
+    assert_eq!(record[43], 0); // kind: Put
+}
+
+#[test]
+fn test_loads_hand_written_records() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("data.db");
+    let mut expiring = i64::MAX.to_le_bytes().to_vec();
+    expiring.extend_from_slice(b"later");
+    write_fixture(
+        &path,
+        Some(FILE_HEADER_MAGIC),
+        &[
+            explicit_record(1, b"a", Some(b"1"), EntryKind::Put),
+            explicit_record(2, b"b", Some(b"2"), EntryKind::Put),
+            explicit_record(3, b"a", None, EntryKind::Tombstone),
+            explicit_record(4, b"c", Some(&expiring), EntryKind::ExpiringPut),
+        ],
+    );
+
+    let engine = Engine::load(&path).unwrap();
+    assert_eq!(engine.get(b"a").unwrap(), None);
+    assert_eq!(engine.get(b"b").unwrap(), Some(b"2".to_vec()));
+    assert_eq!(engine.get(b"c").unwrap(), Some(b"later".to_vec()));
+    assert_eq!(engine.verify().unwrap(), 4);
+
+    // An unknown value flag is corruption, not a guess.
+    let mut bad = explicit_record(5, b"d", None, EntryKind::Put);
+    bad[8 + 8 + 1] = 7;
+    write_fixture(&path, Some(FILE_HEADER_MAGIC), &[bad]);
+    assert!(Engine::load(&path).is_err());
+}
+
+#[test]
+fn test_compaction_migrates_wincode_records() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("data.db");
+    let records: Vec<Vec<u8>> = (0..5)
+        .map(|i| {
+            let entry = DataFileEntry::put(i, format!("k{}", i).into_bytes(), vec![b'v'; 8]);
+            wincode::serialize(&DataFileEntryV2::from(&entry)).unwrap()
+        })
+        .collect();
+    write_fixture(&path, Some(FILE_HEADER_MAGIC_V4), &records);
+
+    let engine = Engine::load(&path).unwrap();
+    engine.set(b"k9", b"new").unwrap();
+    engine.compact().unwrap();
+    assert_eq!(
+        engine.describe_format().unwrap().codec,
+        Some(Codec::ExplicitLe)
+    );
+    for i in 0..5 {
+        assert_eq!(
+            engine.get(format!("k{}", i).as_bytes()).unwrap(),
+            Some(vec![b'v'; 8])
+        );
+    }
+    drop(engine);
+
+    let engine = Engine::load(&path).unwrap();
+    assert_eq!(engine.stats().live_keys, 6);
+    assert_eq!(engine.get(b"k9").unwrap(), Some(b"new".to_vec()));
+}
@@ -0,0 +1,118 @@
+use breakout1_kv_store::constants::LARGEST_KEYS_PREFIX;
+use breakout1_kv_store::{Engine, EngineError, Options};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tempfile::NamedTempFile;
+
+/// The system allocator, counting the bytes currently allocated so tests
+/// can see how many copies of a key an operation makes.
+struct Counting;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+        unsafe { System.dealloc(ptr, layout) }
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+/// Held by every test, so one test's allocations never show up in
+/// another's measurements.
+static SERIAL: Mutex<()> = Mutex::new(());
+
+const MB: usize = 1 << 20;
+
+/// Keys of 2, 3, and 4 MB, each starting with its own byte.
+fn huge_keys() -> Vec<Vec<u8>> {
+    (2..=4u8).map(|n| vec![b'a' + n; n as usize * MB]).collect()
+}
+
+#[test]
+fn test_huge_keys_are_counted_and_copied_once_per_snapshot() {
+    let _serial = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+    let file = NamedTempFile::new().unwrap();
+    let engine = Engine::load(file.path()).unwrap();
+    let keys = huge_keys();
+    let key_bytes: usize = keys.iter().map(Vec::len).sum();
+    for key in &keys {
+        engine.set(key, b"v").unwrap();
+        // Overwrites must not leave extra copies behind.
+        engine.set(key, b"w").unwrap();
+    }
+
+    let stats = engine.stats();
+    assert_eq!(stats.largest_key_len, 4 * MB);
+    // The index map and the read view each hold one copy.
+    assert!(stats.index_bytes >= 2 * key_bytes as u64);
+    assert!(stats.index_bytes < 2 * key_bytes as u64 + 4096);
+
+    let largest = engine.largest_keys(2);
+    assert_eq!(
+        largest,
+        vec![
+            (4 * MB, vec![b'e'; LARGEST_KEYS_PREFIX]),
+            (3 * MB, vec![b'd'; LARGEST_KEYS_PREFIX]),
+        ]
+    );
+
+    let before = ALLOCATED.load(Ordering::Relaxed);
+    let snapshot = engine.keys();
+    let grown = ALLOCATED.load(Ordering::Relaxed) - before;
+    assert_eq!(snapshot.len(), 3);
+    assert!(grown >= key_bytes && grown < key_bytes + MB, "{grown}");
+    drop(snapshot);
+
+    engine.compact().unwrap();
+    assert_eq!(engine.stats().largest_key_len, 4 * MB);
+    assert_eq!(engine.get(&keys[2]).unwrap(), Some(b"w".to_vec()));
+
+    for key in &keys {
+        engine.del(key).unwrap();
+    }
+    let stats = engine.stats();
+    assert_eq!(stats.largest_key_len, 0);
+    assert!(stats.index_bytes < 4096);
+}
+
+#[test]
+fn test_max_key_size_refuses_puts_but_not_deletes() {
+    let _serial = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+    let file = NamedTempFile::new().unwrap();
+    let key = vec![b'k'; 2 * MB];
+    Engine::load(file.path())
+        .unwrap()
+        .set(&key, b"stored before the limit")
+        .unwrap();
+
+    let options = Options {
+        max_key_size: Some(MB),
+        ..Options::default()
+    };
+    let engine = Engine::load_with_options(file.path(), options).unwrap();
+    let size_before = engine.stats().file_size;
+    let err = engine.set(&key, b"again").unwrap_err();
+    assert_eq!(
+        EngineError::from_io(&err),
+        Some(&EngineError::KeyTooLarge {
+            len: 2 * MB,
+            max: MB
+        })
+    );
+    assert!(engine.bucket(b"b").unwrap().set(&key, b"v").is_err());
+    assert_eq!(engine.stats().file_size, size_before);
+
+    engine.del(&key).unwrap();
+    assert_eq!(engine.get(&key).unwrap(), None);
+    assert_eq!(engine.stats().largest_key_len, 0);
+    engine.set(&key[..MB], b"at the limit").unwrap();
+}
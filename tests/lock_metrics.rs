@@ -0,0 +1,105 @@
+#![cfg(feature = "lock-metrics")]
+
+use breakout1_kv_store::{Engine, Options, WriteBatch};
+use std::sync::{Arc, Barrier};
+use std::thread;
+use std::time::Duration;
+use tempfile::NamedTempFile;
+
+#[test]
+fn test_single_thread_never_contends() {
+    let file = NamedTempFile::new().unwrap();
+    let engine = Engine::load(file.path()).unwrap();
+    for i in 0..200 {
+        let key = format!("k{}", i);
+        engine.set(key.as_bytes(), b"v").unwrap();
+        assert_eq!(engine.get(key.as_bytes()).unwrap(), Some(b"v".to_vec()));
+    }
+    engine.scan_prefix(b"k1").unwrap();
+    engine.compact().unwrap();
+
+    let stats = engine.stats();
+    for lock in [&stats.file_lock, &stats.index_lock, &stats.reader_pool_lock] {
+        assert!(lock.acquisitions > 0);
+        assert_eq!(lock.contended, 0);
+        assert_eq!(lock.wait_micros, 0);
+    }
+}
+
+#[test]
+fn test_write_storm_contends() {
+    const THREADS: usize = 8;
+    let file = NamedTempFile::new().unwrap();
+    let engine = Arc::new(Engine::load(file.path()).unwrap());
+    let barrier = Arc::new(Barrier::new(THREADS));
+
+    let handles: Vec<_> = (0..THREADS)
+        .map(|t| {
+            let engine = Arc::clone(&engine);
+            let barrier = Arc::clone(&barrier);
+            thread::spawn(move || {
+                barrier.wait();
+                let mut batch = WriteBatch::new();
+                for i in 0..500 {
+                    batch.clear();
+                    batch
+                        .put(format!("t{}:{}", t, i).as_bytes(), &[0u8; 64])
+                        .put(format!("t{}:last", t).as_bytes(), b"v");
+                    engine.apply_batch(&batch).unwrap();
+                    engine.scan_prefix(format!("t{}:1", t).as_bytes()).unwrap();
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    let stats = engine.stats();
+    assert!(stats.file_lock.contended > 0);
+    assert!(stats.file_lock.contended <= stats.file_lock.acquisitions);
+    assert!(stats.index_lock.contended > 0);
+}
+
+/// Index lock acquisitions taken by deleting 50 keys set beforehand.
+fn index_locks_for_deletes(engine: &Engine) -> u64 {
+    let before = engine.stats().index_lock.acquisitions;
+    for i in 0..50 {
+        engine.del(format!("d{}", i).as_bytes()).unwrap();
+    }
+    engine.stats().index_lock.acquisitions - before
+}
+
+#[test]
+fn test_auto_compaction_check_takes_no_index_lock() {
+    let plain_file = NamedTempFile::new().unwrap();
+    let plain = Engine::load(plain_file.path()).unwrap();
+
+    // Once a compaction is put off, every write checks the size trigger,
+    // deletes included.
+    let deferred_file = NamedTempFile::new().unwrap();
+    let deferred = Engine::load_with_options(
+        deferred_file.path(),
+        Options {
+            compact_threshold: Some(4096),
+            min_compaction_interval: Some(Duration::from_secs(3600)),
+            ..Options::default()
+        },
+    )
+    .unwrap();
+    for engine in [&plain, &deferred] {
+        for i in 0..50 {
+            engine.set(format!("d{}", i).as_bytes(), b"v").unwrap();
+        }
+    }
+    for i in 0..2000u32 {
+        deferred.set(b"hot", &i.to_le_bytes().repeat(25)).unwrap();
+    }
+    assert_eq!(deferred.stats().compactions, 1);
+    assert!(deferred.stats().compactions_deferred > 0);
+
+    assert_eq!(
+        index_locks_for_deletes(&deferred),
+        index_locks_for_deletes(&plain)
+    );
+}
@@ -0,0 +1,186 @@
+#![cfg(feature = "oplog-debug")]
+
+mod common;
+
+use breakout1_kv_store::oplog::{self, JournalOp, OpJournal, ValueLogging};
+use breakout1_kv_store::{Engine, OpContext, Options, WriteBatch};
+use common::XorShift;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tempfile::tempdir;
+
+fn journaled(path: &Path, journal: &Arc<OpJournal>) -> Engine {
+    let options = Options {
+        op_journal: Some(Arc::clone(journal)),
+        ..Options::default()
+    };
+    Engine::load_with_options(path, options).unwrap()
+}
+
+/// Random sets, deletes, reads, soft deletes, restores, batches, and bucket
+/// writes from four threads.
+fn random_workload(engine: &Arc<Engine>) {
+    let handles: Vec<_> = (0..4u64)
+        .map(|t| {
+            let engine = Arc::clone(engine);
+            thread::spawn(move || {
+                let mut rng = XorShift(0x9E37_79B9 + t);
+                let bucket = engine.bucket(b"b").unwrap();
+                for _ in 0..400 {
+                    let key = format!("k{}", rng.next() % 64).into_bytes();
+                    let value = rng.bytes(40);
+                    match rng.next() % 8 {
+                        0 | 1 => engine.set(&key, &value).unwrap(),
+                        2 => engine.del(&key).unwrap(),
+                        3 => drop(engine.get(&key).unwrap()),
+                        4 => engine.soft_del(&key).unwrap(),
+                        5 => drop(engine.restore(&key)),
+                        6 => {
+                            let mut batch = WriteBatch::new();
+                            batch
+                                .put(&key, &value)
+                                .put_with_ttl(b"ttl", &value, Duration::from_secs(3600))
+                                .delete(b"k0");
+                            engine.apply_batch(&batch).unwrap();
+                        }
+                        _ => bucket.set(&key, &value).unwrap(),
+                    }
+                }
+            })
+        })
+        .collect();
+    for h in handles {
+        h.join().unwrap();
+    }
+}
+
+#[test]
+fn test_replay_reproduces_redacted_workload() {
+    let dir = tempdir().unwrap();
+    let journal_path = dir.path().join("ops.journal");
+    let journal = Arc::new(OpJournal::create(&journal_path, ValueLogging::default()).unwrap());
+    let engine = Arc::new(journaled(&dir.path().join("data.db"), &journal));
+    random_workload(&engine);
+    engine
+        .update_many(&[b"m1", b"m2"], |_| vec![Some(b"one".to_vec()), None])
+        .unwrap();
+    engine.compact().unwrap();
+    journal.flush().unwrap();
+
+    let records = oplog::read_journal(&journal_path).unwrap();
+    assert!(records.iter().all(|r| r.value.is_none()));
+    assert!(records.iter().any(|r| r.op == JournalOp::Batch));
+    assert!(records.iter().any(|r| r.op == JournalOp::Get));
+
+    let fresh = Engine::load(dir.path().join("replayed.db")).unwrap();
+    let report = oplog::replay(&journal_path, &fresh).unwrap();
+    assert_eq!(report.replayed, records.len() as u64);
+    assert_eq!(report.failed, 0);
+    assert_eq!(
+        oplog::diff(&engine, &fresh, ValueLogging::LengthOnly).unwrap(),
+        Vec::<Vec<u8>>::new()
+    );
+    assert!(!engine.raw_keys_excluding_buckets().is_empty());
+}
+
+#[test]
+fn test_replay_with_full_values_matches_exactly_and_diff_finds_damage() {
+    let dir = tempdir().unwrap();
+    let journal_path = dir.path().join("ops.journal");
+    let journal = Arc::new(OpJournal::create(&journal_path, ValueLogging::Full).unwrap());
+    let engine = Arc::new(journaled(&dir.path().join("data.db"), &journal));
+    random_workload(&engine);
+    journal.flush().unwrap();
+
+    let fresh = Engine::load(dir.path().join("replayed.db")).unwrap();
+    oplog::replay(&journal_path, &fresh).unwrap();
+    assert!(
+        oplog::diff(&engine, &fresh, ValueLogging::Full)
+            .unwrap()
+            .is_empty()
+    );
+
+    // Writes that bypass the journal show up as differences.
+    let damaged = Engine::load(dir.path().join("damaged.db")).unwrap();
+    oplog::replay(&journal_path, &damaged).unwrap();
+    damaged.set(b"k1", b"not what the journal says").unwrap();
+    damaged.set(b"stray", b"x").unwrap();
+    assert_eq!(
+        oplog::diff(&fresh, &damaged, ValueLogging::Full).unwrap(),
+        vec![b"k1".to_vec(), b"stray".to_vec()]
+    );
+}
+
+#[test]
+fn test_journal_redacts_values_by_default() {
+    let dir = tempdir().unwrap();
+    let journal_path = dir.path().join("ops.journal");
+    let journal = Arc::new(OpJournal::create(&journal_path, ValueLogging::default()).unwrap());
+    let engine = journaled(&dir.path().join("data.db"), &journal);
+    engine.set(b"user", b"hunter2-secret").unwrap();
+    journal.flush().unwrap();
+
+    let bytes = fs::read(&journal_path).unwrap();
+    assert!(!bytes.windows(7).any(|w| w == b"hunter2"));
+    let records = oplog::read_journal(&journal_path).unwrap();
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0].op, JournalOp::Set);
+    assert_eq!(records[0].key, b"user");
+    assert_eq!(records[0].value_len, 14);
+}
+
+#[test]
+fn test_replay_keeps_empty_values_apart_from_deleted_keys() {
+    for values in [ValueLogging::LengthOnly, ValueLogging::Full] {
+        let dir = tempdir().unwrap();
+        let journal_path = dir.path().join("ops.journal");
+        let journal = Arc::new(OpJournal::create(&journal_path, values).unwrap());
+        let engine = journaled(&dir.path().join("data.db"), &journal);
+        engine.set(b"empty", b"").unwrap();
+        engine.set(b"gone", b"").unwrap();
+        engine.del(b"gone").unwrap();
+        let mut batch = WriteBatch::new();
+        batch.put(b"batched", b"").put(b"unbatched", b"x");
+        batch.delete(b"unbatched");
+        engine.apply_batch(&batch).unwrap();
+        journal.flush().unwrap();
+
+        let fresh = Engine::load(dir.path().join("replayed.db")).unwrap();
+        assert_eq!(oplog::replay(&journal_path, &fresh).unwrap().failed, 0);
+        for key in [&b"empty"[..], b"batched"] {
+            assert_eq!(fresh.get(key).unwrap(), Some(Vec::new()), "{:?}", values);
+        }
+        for key in [&b"gone"[..], b"unbatched"] {
+            assert_eq!(fresh.get(key).unwrap(), None, "{:?}", values);
+        }
+        assert!(oplog::diff(&engine, &fresh, values).unwrap().is_empty());
+    }
+}
+
+#[test]
+fn test_journal_records_op_context() {
+    let dir = tempdir().unwrap();
+    let journal_path = dir.path().join("ops.journal");
+    let journal = Arc::new(OpJournal::create(&journal_path, ValueLogging::default()).unwrap());
+    let engine = journaled(&dir.path().join("data.db"), &journal);
+    engine.with_context(OpContext::new("req", 99), || {
+        engine.set(b"k", b"v").unwrap();
+        engine.del(b"k").unwrap();
+    });
+    engine.set(b"k", b"v").unwrap();
+    journal.flush().unwrap();
+
+    let records = oplog::read_journal(&journal_path).unwrap();
+    let contexts: Vec<_> = records.iter().map(|r| r.context.clone()).collect();
+    assert_eq!(
+        contexts,
+        vec![
+            Some(("req".to_string(), 99)),
+            Some(("req".to_string(), 99)),
+            None
+        ]
+    );
+}
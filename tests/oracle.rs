@@ -0,0 +1,207 @@
+//! Randomized concurrent workloads checked against an oracle of
+//! acknowledged writes: whatever mix of sets, deletes, batches,
+//! compactions, and reloads ran concurrently, `get` must return for every
+//! key the value of its last acknowledged write, and nothing after an
+//! acknowledged delete.
+//!
+//! Operations on one key are ordered by a lock per key, held from before
+//! the call until the oracle is updated with its result, so the oracle
+//! knows exactly what each key must read back. Operations on different
+//! keys, compactions, and reloads all still race each other freely.
+
+mod common;
+
+use breakout1_kv_store::{Engine, Options, WriteBatch};
+use common::XorShift;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::Duration;
+use tempfile::NamedTempFile;
+
+const KEYS: usize = 48;
+const WRITERS: u64 = 4;
+const OPS_PER_WRITER: u64 = 1_500;
+
+/// What each key must read back, behind the lock that orders its writes.
+struct Oracle {
+    slots: Vec<Mutex<Option<Vec<u8>>>>,
+}
+
+impl Oracle {
+    fn new() -> Self {
+        Oracle {
+            slots: (0..KEYS).map(|_| Mutex::new(None)).collect(),
+        }
+    }
+
+    fn lock(&self, i: usize) -> MutexGuard<'_, Option<Vec<u8>>> {
+        self.slots[i].lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Checks `get` of every key against the oracle.
+    fn check_all(&self, engine: &Engine) {
+        for i in 0..KEYS {
+            let expected = self.lock(i);
+            assert_eq!(engine.get(&key(i)).unwrap(), *expected, "key {}", i);
+        }
+    }
+}
+
+fn key(i: usize) -> Vec<u8> {
+    format!("key{:02}", i).into_bytes()
+}
+
+/// A value naming its key, writer, and op, so a stale or misplaced one
+/// shows where it came from, padded to a random length. One in four is
+/// instead one of a few values shared across keys, for deduplication.
+fn value(rng: &mut XorShift, i: usize, writer: u64, op: u64) -> Vec<u8> {
+    if rng.next().is_multiple_of(4) {
+        return vec![b'0' + (rng.next() % 4) as u8; 64];
+    }
+    let mut value = format!("key{:02}/w{}/op{}:", i, writer, op).into_bytes();
+    value.extend(rng.bytes(200));
+    value
+}
+
+fn writer(engine: &Engine, oracle: &Oracle, writer: u64) {
+    let mut rng = XorShift(writer * 7919 + 1);
+    for op in 0..OPS_PER_WRITER {
+        let i = rng.next() as usize % KEYS;
+        match rng.next() % 10 {
+            0..=5 => {
+                let mut slot = oracle.lock(i);
+                let value = value(&mut rng, i, writer, op);
+                engine.set(&key(i), &value).unwrap();
+                *slot = Some(value);
+            }
+            6 | 7 => {
+                let mut slot = oracle.lock(i);
+                engine.del(&key(i)).unwrap();
+                *slot = None;
+            }
+            8 => {
+                let mut slot = oracle.lock(i);
+                engine.soft_del(&key(i)).unwrap();
+                *slot = None;
+            }
+            _ => {
+                // Two keys in one batch, locked in key order so writers
+                // never wait on each other in a cycle.
+                let j = rng.next() as usize % KEYS;
+                if i == j {
+                    continue;
+                }
+                let (lo, hi) = (i.min(j), i.max(j));
+                let (mut slot_lo, mut slot_hi) = (oracle.lock(lo), oracle.lock(hi));
+                let value = value(&mut rng, lo, writer, op);
+                let mut batch = WriteBatch::new();
+                batch.put(&key(lo), &value);
+                batch.delete(&key(hi));
+                engine.apply_batch(&batch).unwrap();
+                *slot_lo = Some(value);
+                *slot_hi = None;
+            }
+        }
+    }
+}
+
+fn run_oracle(options: Options) {
+    let file = NamedTempFile::new().unwrap();
+    let engine = Arc::new(Engine::load_with_options(file.path(), options.clone()).unwrap());
+    let oracle = Arc::new(Oracle::new());
+    let done = Arc::new(AtomicBool::new(false));
+    let checks = Arc::new(AtomicU64::new(0));
+
+    let mut writers = vec![];
+    for w in 0..WRITERS {
+        let (engine, oracle) = (Arc::clone(&engine), Arc::clone(&oracle));
+        writers.push(thread::spawn(move || writer(&engine, &oracle, w)));
+    }
+    let mut others = vec![];
+    for seed in 0..2u64 {
+        let (engine, oracle, done, checks) = (
+            Arc::clone(&engine),
+            Arc::clone(&oracle),
+            Arc::clone(&done),
+            Arc::clone(&checks),
+        );
+        others.push(thread::spawn(move || {
+            let mut rng = XorShift(seed + 100);
+            while !done.load(Ordering::Relaxed) {
+                let i = rng.next() as usize % KEYS;
+                let expected = oracle.lock(i);
+                assert_eq!(engine.get(&key(i)).unwrap(), *expected, "key {}", i);
+                drop(expected);
+                checks.fetch_add(1, Ordering::Relaxed);
+            }
+        }));
+    }
+    {
+        let (engine, done) = (Arc::clone(&engine), Arc::clone(&done));
+        others.push(thread::spawn(move || {
+            let mut rng = XorShift(200);
+            while !done.load(Ordering::Relaxed) {
+                match rng.next() % 2 {
+                    0 => {
+                        engine.compact().unwrap();
+                    }
+                    _ => {
+                        engine.compact_step(Duration::from_micros(200)).unwrap();
+                    }
+                }
+            }
+        }));
+    }
+
+    // Reload while everything else runs, until the writers are through. A
+    // worker only exits early by panicking.
+    while !writers.iter().all(|w| w.is_finished()) && !others.iter().any(|o| o.is_finished()) {
+        engine.reload().unwrap();
+        thread::sleep(Duration::from_millis(20));
+    }
+    for handle in writers {
+        handle.join().unwrap();
+    }
+    done.store(true, Ordering::Relaxed);
+    for handle in others {
+        handle.join().unwrap();
+    }
+
+    assert!(checks.load(Ordering::Relaxed) > 0);
+    assert_eq!(engine.corruption(), None);
+    oracle.check_all(&engine);
+    engine.reload().unwrap();
+    oracle.check_all(&engine);
+    engine.compact().unwrap();
+    oracle.check_all(&engine);
+    assert_eq!(engine.audit_caches().unwrap(), vec![]);
+    drop(engine);
+
+    let engine = Engine::load_with_options(file.path(), options).unwrap();
+    oracle.check_all(&engine);
+}
+
+#[test]
+fn test_oracle_holds_under_concurrent_writes_compactions_and_reloads() {
+    run_oracle(Options::default());
+}
+
+#[test]
+fn test_oracle_holds_with_auto_compaction_and_history() {
+    run_oracle(Options {
+        compact_threshold: Some(32 * 1024),
+        history_depth: 2,
+        ..Options::default()
+    });
+}
+
+#[test]
+fn test_oracle_holds_with_deduplicated_values() {
+    run_oracle(Options {
+        dedup_min_value_len: Some(1),
+        value_hashes: true,
+        ordered_index: true,
+        ..Options::default()
+    });
+}
@@ -0,0 +1,278 @@
+use breakout1_kv_store::types::EntryKind;
+use breakout1_kv_store::{ConflictPolicy, Engine, Hook, OpenMode, Options, SetEvent, WriteBatch};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tempfile::NamedTempFile;
+
+const WRITER_A: [u8; 16] = *b"writer-a........";
+const WRITER_B: [u8; 16] = *b"writer-b........";
+
+fn writer(path: &Path, writer_id: [u8; 16]) -> Engine {
+    Engine::load_with_options(
+        path,
+        Options {
+            writer_id: Some(writer_id),
+            ..Options::default()
+        },
+    )
+    .unwrap()
+}
+
+fn origin(engine: &Engine, key: &[u8]) -> Option<[u8; 16]> {
+    engine.stat(key).unwrap().unwrap().origin
+}
+
+/// Origin of the last record of each key in the log.
+fn logged_origins(engine: &Engine) -> HashMap<Vec<u8>, Option<[u8; 16]>> {
+    engine
+        .audit_scan()
+        .unwrap()
+        .map(|record| record.unwrap().entry)
+        .filter(|entry| entry.kind != EntryKind::BatchBegin)
+        .map(|entry| (entry.key, entry.origin))
+        .collect()
+}
+
+#[test]
+fn test_records_carry_their_writer() {
+    let file = NamedTempFile::new().unwrap();
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let sink = Arc::clone(&seen);
+    let engine = Engine::load_with_options(
+        file.path(),
+        Options {
+            writer_id: Some(WRITER_A),
+            on_set: Some(Hook::new(move |event: &SetEvent| {
+                sink.lock().unwrap().push(event.clone())
+            })),
+            ..Options::default()
+        },
+    )
+    .unwrap();
+
+    engine.set(b"own", b"hello world").unwrap();
+    engine
+        .set_with_origin(b"relayed", b"from b", WRITER_B)
+        .unwrap();
+    engine.del(b"own").unwrap();
+    engine.set(b"own", b"again").unwrap();
+
+    assert_eq!(origin(&engine, b"own"), Some(WRITER_A));
+    assert_eq!(origin(&engine, b"relayed"), Some(WRITER_B));
+    // The origin is not part of the value.
+    assert_eq!(engine.get(b"relayed").unwrap(), Some(b"from b".to_vec()));
+    assert_eq!(engine.stat(b"relayed").unwrap().unwrap().value_len, 6);
+    assert_eq!(
+        engine.get_range(b"relayed", 2, 100).unwrap(),
+        Some(b"om b".to_vec())
+    );
+    let origins: Vec<_> = seen
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|event| (event.key.clone(), event.origin))
+        .collect();
+    assert_eq!(
+        origins,
+        [
+            (b"own".to_vec(), Some(WRITER_A)),
+            (b"relayed".to_vec(), Some(WRITER_B)),
+            (b"own".to_vec(), Some(WRITER_A)),
+        ]
+    );
+    // Deletes are stamped too.
+    let tombstone = engine
+        .audit_scan()
+        .unwrap()
+        .map(|record| record.unwrap().entry)
+        .find(|entry| entry.kind == EntryKind::Tombstone)
+        .unwrap();
+    assert_eq!(tombstone.origin, Some(WRITER_A));
+    drop(engine);
+
+    for open_mode in [OpenMode::Fast, OpenMode::Standard] {
+        let engine = Engine::load_with_options(
+            file.path(),
+            Options {
+                open_mode,
+                ..Options::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(origin(&engine, b"own"), Some(WRITER_A));
+        assert_eq!(origin(&engine, b"relayed"), Some(WRITER_B));
+        assert_eq!(engine.get(b"own").unwrap(), Some(b"again".to_vec()));
+        // Without a writer id of its own, its writes carry none.
+        engine.set(b"local", b"v").unwrap();
+        assert_eq!(origin(&engine, b"local"), None);
+        // And overwriting a key drops the origin of the old value.
+        engine.set(b"relayed", b"local now").unwrap();
+        assert_eq!(origin(&engine, b"relayed"), None);
+        engine
+            .set_with_origin(b"relayed", b"from b", WRITER_B)
+            .unwrap();
+    }
+}
+
+#[test]
+fn test_records_without_an_origin_keep_their_size() {
+    let plain = NamedTempFile::new().unwrap();
+    let stamped = NamedTempFile::new().unwrap();
+    let engine = Engine::load(plain.path()).unwrap();
+    engine.set(b"key", b"value").unwrap();
+    let plain_len = fs::metadata(plain.path()).unwrap().len();
+
+    let engine = writer(stamped.path(), WRITER_A);
+    engine.set(b"key", b"value").unwrap();
+    let stamped_len = fs::metadata(stamped.path()).unwrap().len();
+    assert_eq!(stamped_len, plain_len + 16);
+}
+
+#[test]
+fn test_compaction_keeps_origins() {
+    let file = NamedTempFile::new().unwrap();
+    let engine = writer(file.path(), WRITER_A);
+    for i in 0..50u32 {
+        engine.set(format!("k{:02}", i).as_bytes(), b"v1").unwrap();
+    }
+    engine.set_with_origin(b"k07", b"v2", WRITER_B).unwrap();
+    engine
+        .apply_batch(
+            WriteBatch::new()
+                .put(b"batched", b"b")
+                .put_with_ttl(b"expiring", b"t", Duration::from_secs(3600))
+                .delete(b"k00"),
+        )
+        .unwrap();
+
+    engine.compact().unwrap();
+    assert_eq!(origin(&engine, b"k01"), Some(WRITER_A));
+    assert_eq!(origin(&engine, b"k07"), Some(WRITER_B));
+    assert_eq!(origin(&engine, b"batched"), Some(WRITER_A));
+    assert_eq!(origin(&engine, b"expiring"), Some(WRITER_A));
+    assert_eq!(engine.get(b"expiring").unwrap(), Some(b"t".to_vec()));
+    assert!(engine.ttl(b"expiring").unwrap().is_some());
+    assert_eq!(engine.get(b"k00").unwrap(), None);
+
+    let logged = logged_origins(&engine);
+    assert_eq!(logged.len(), 51);
+    assert_eq!(logged[&b"k07"[..]], Some(WRITER_B));
+    assert!(
+        logged
+            .iter()
+            .all(|(key, origin)| key == b"k07" || *origin == Some(WRITER_A))
+    );
+    drop(engine);
+
+    let engine = Engine::load(file.path()).unwrap();
+    assert_eq!(origin(&engine, b"k07"), Some(WRITER_B));
+    assert_eq!(origin(&engine, b"expiring"), Some(WRITER_A));
+}
+
+#[test]
+fn test_compaction_rewrites_stale_puts_and_keeps_their_origin() {
+    let file = NamedTempFile::new().unwrap();
+    let options = || Options {
+        writer_id: Some(WRITER_A),
+        conflict_policy: ConflictPolicy::KeepNewest,
+        history_depth: 4,
+        ..Options::default()
+    };
+    let engine = Engine::load_with_options(file.path(), options()).unwrap();
+    assert!(engine.set_with_tstamp(b"k", b"new", 200).unwrap());
+    assert!(!engine.set_with_tstamp(b"k", b"old", 100).unwrap());
+    engine
+        .set_with_origin(b"relayed", b"from b", WRITER_B)
+        .unwrap();
+    assert_eq!(&fs::read(file.path()).unwrap()[..4], b"KVS7");
+    let kinds = |engine: &Engine| -> Vec<(EntryKind, Option<[u8; 16]>)> {
+        engine
+            .audit_scan()
+            .unwrap()
+            .map(|record| record.unwrap().entry)
+            .filter(|entry| entry.key == b"k")
+            .map(|entry| (entry.kind, entry.origin))
+            .collect()
+    };
+    assert_eq!(
+        kinds(&engine),
+        [
+            (EntryKind::Put, Some(WRITER_A)),
+            (EntryKind::StalePut, Some(WRITER_A)),
+        ]
+    );
+
+    // The stale write becomes history, written ahead of the live value as
+    // a plain put, and both records keep their origin.
+    engine.compact().unwrap();
+    assert_eq!(
+        kinds(&engine),
+        [
+            (EntryKind::Put, Some(WRITER_A)),
+            (EntryKind::Put, Some(WRITER_A)),
+        ]
+    );
+    for engine in [
+        engine,
+        Engine::load_with_options(file.path(), options()).unwrap(),
+    ] {
+        assert_eq!(engine.get(b"k").unwrap(), Some(b"new".to_vec()));
+        assert_eq!(
+            engine.previous_versions(b"k", 4).unwrap(),
+            vec![b"old".to_vec()]
+        );
+        assert_eq!(origin(&engine, b"k"), Some(WRITER_A));
+        assert_eq!(origin(&engine, b"relayed"), Some(WRITER_B));
+        assert_eq!(engine.get(b"relayed").unwrap(), Some(b"from b".to_vec()));
+    }
+}
+
+#[test]
+fn test_replica_tells_writers_apart() {
+    let a = NamedTempFile::new().unwrap();
+    let b = NamedTempFile::new().unwrap();
+    let target = NamedTempFile::new().unwrap();
+    let from_a = writer(a.path(), WRITER_A);
+    let from_b = writer(b.path(), WRITER_B);
+    from_a.set(b"shared", b"a wrote this").unwrap();
+    from_a.set(b"only-a", b"1").unwrap();
+    from_b.set(b"only-b", b"2").unwrap();
+    from_b.set(b"shared", b"b wrote this").unwrap();
+
+    // Replays both logs into one store, oldest source first, as a replica
+    // applying their change feeds would.
+    let replica = Engine::load(target.path()).unwrap();
+    for source in [&from_a, &from_b] {
+        for record in source.audit_scan().unwrap() {
+            let entry = record.unwrap().entry;
+            replica
+                .set_with_origin(&entry.key, &entry.value.unwrap(), entry.origin.unwrap())
+                .unwrap();
+        }
+    }
+
+    assert_eq!(origin(&replica, b"only-a"), Some(WRITER_A));
+    assert_eq!(origin(&replica, b"only-b"), Some(WRITER_B));
+    assert_eq!(origin(&replica, b"shared"), Some(WRITER_B));
+    assert_eq!(
+        replica.get(b"shared").unwrap(),
+        Some(b"b wrote this".to_vec())
+    );
+    let shared: Vec<_> = replica
+        .audit_scan()
+        .unwrap()
+        .map(|record| record.unwrap().entry)
+        .filter(|entry| entry.key == b"shared")
+        .map(|entry| (entry.value.unwrap(), entry.origin))
+        .collect();
+    assert_eq!(
+        shared,
+        [
+            (b"a wrote this".to_vec(), Some(WRITER_A)),
+            (b"b wrote this".to_vec(), Some(WRITER_B)),
+        ]
+    );
+}
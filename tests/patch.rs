@@ -0,0 +1,241 @@
+mod common;
+
+use breakout1_kv_store::patch::{
+    PATCH_HEADER, Patch, PatchChange, PatchEntry, PatchOp, PatchOutcome, Prior,
+};
+use breakout1_kv_store::{Engine, Options};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tempfile::NamedTempFile;
+
+fn temp_engine() -> (Engine, NamedTempFile) {
+    let file = NamedTempFile::new().unwrap();
+    let engine = Engine::load(file.path()).unwrap();
+    (engine, file)
+}
+
+fn contents(engine: &Engine) -> HashMap<Vec<u8>, Vec<u8>> {
+    engine
+        .keys()
+        .into_iter()
+        .map(|key| {
+            let value = engine.get(&key).unwrap().unwrap();
+            (key, value)
+        })
+        .collect()
+}
+
+fn text(patch: &Patch) -> Vec<u8> {
+    let mut out = Vec::new();
+    patch.write_to(&mut out).unwrap();
+    out
+}
+
+fn hash(value: &[u8]) -> Prior {
+    Prior::Hash(Engine::hash_value(value))
+}
+
+#[test]
+fn test_patch_round_trips_through_text() {
+    let patch = Patch {
+        entries: vec![
+            PatchEntry {
+                key: b"plain".to_vec(),
+                op: PatchOp::Set(b"value".to_vec()),
+                expect: None,
+            },
+            PatchEntry {
+                key: vec![0, 1, 0xFE, b' ', b'\n'],
+                op: PatchOp::Set(Vec::new()),
+                expect: Some(Prior::Absent),
+            },
+            PatchEntry {
+                key: b"gone".to_vec(),
+                op: PatchOp::Del,
+                expect: Some(hash(b"old")),
+            },
+        ],
+    };
+    let written = text(&patch);
+    let written = String::from_utf8(written).unwrap();
+    assert!(written.starts_with(PATCH_HEADER));
+    assert_eq!(written.lines().count(), 4);
+    assert_eq!(Patch::read(written.as_bytes()).unwrap(), patch);
+
+    // Comments and blank lines are skipped.
+    let annotated = written.replace('\n', "\n# reviewed\n\n");
+    assert_eq!(Patch::read(annotated.as_bytes()).unwrap(), patch);
+
+    for bad in [
+        "set cGxhaW4= dmFsdWU=\n",
+        "kvpatch 1\nput cGxhaW4= dmFsdWU=\n",
+        "kvpatch 1\nset cGxhaW4\n",
+        "kvpatch 1\nset cGxhaW4= dmFsdWU= expect=12\n",
+        "kvpatch 1\ndel cGxhaW4= expect=absent extra\n",
+    ] {
+        let err = Patch::read(bad.as_bytes()).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData, "{}", bad);
+    }
+}
+
+#[test]
+fn test_diff_to_patch_turns_one_store_into_another() {
+    let (engine, _f) = temp_engine();
+    let (target, _g) = temp_engine();
+    for i in 0..100u32 {
+        let key = format!("k{:03}", i);
+        engine.set(key.as_bytes(), b"same").unwrap();
+        match i % 4 {
+            0 => {}
+            1 => target.set(key.as_bytes(), b"changed").unwrap(),
+            _ => target.set(key.as_bytes(), b"same").unwrap(),
+        }
+    }
+    target.set(b"new", b"").unwrap();
+
+    let patch = engine.diff_to_patch(&target).unwrap();
+    // 25 deletes, 25 changes, and one new key.
+    assert_eq!(patch.entries.len(), 51);
+    assert!(patch.entries.iter().all(|entry| entry.expect.is_some()));
+
+    let report = engine.apply_patch(text(&patch).as_slice(), false).unwrap();
+    assert!(report.applied);
+    assert_eq!(report.mismatches(), 0);
+    assert_eq!(contents(&engine), contents(&target));
+    assert!(engine.diff_to_patch(&target).unwrap().entries.is_empty());
+
+    // The same patch again finds the store already changed.
+    let report = engine.apply_patch(text(&patch).as_slice(), false).unwrap();
+    assert!(!report.applied);
+    assert_eq!(report.mismatches(), 51);
+}
+
+#[test]
+fn test_dry_run_reports_every_entry_without_writing() {
+    let (engine, _f) = temp_engine();
+    engine.set(b"a", b"1").unwrap();
+    engine.set(b"b", b"2").unwrap();
+    let patch = format!(
+        "{}\nset YQ== MTE= expect={}\nset Yw== Mw== expect=absent\ndel Yg==\ndel ZA==\n",
+        PATCH_HEADER,
+        hash(b"1")
+    );
+
+    let report = engine.apply_patch(patch.as_bytes(), true).unwrap();
+    assert!(!report.applied);
+    let outcomes: Vec<_> = report
+        .outcomes
+        .iter()
+        .map(|outcome| (outcome.key.as_slice(), outcome.change, outcome.matched))
+        .collect();
+    assert_eq!(
+        outcomes,
+        [
+            (&b"a"[..], PatchChange::Replaced, true),
+            (b"c", PatchChange::Created, true),
+            (b"b", PatchChange::Deleted, true),
+            (b"d", PatchChange::AlreadyAbsent, true),
+        ]
+    );
+    assert_eq!(report.outcomes[2].found, hash(b"2"));
+    let before: HashMap<_, _> = [
+        (b"a".to_vec(), b"1".to_vec()),
+        (b"b".to_vec(), b"2".to_vec()),
+    ]
+    .into_iter()
+    .collect();
+    assert_eq!(contents(&engine), before);
+
+    let report = engine.apply_patch(patch.as_bytes(), false).unwrap();
+    assert!(report.applied);
+    let after: HashMap<_, _> = [
+        (b"a".to_vec(), b"11".to_vec()),
+        (b"c".to_vec(), b"3".to_vec()),
+    ]
+    .into_iter()
+    .collect();
+    assert_eq!(contents(&engine), after);
+}
+
+#[test]
+fn test_mismatched_prior_refuses_the_whole_patch_unless_forced() {
+    let (engine, _f) = temp_engine();
+    engine.set(b"a", b"1").unwrap();
+    engine.set(b"b", b"moved on").unwrap();
+    let patch = format!(
+        "{}\nset YQ== MTE= expect={}\nset Yg== MjI= expect={}\nset Yw== Mw== expect=absent\n",
+        PATCH_HEADER,
+        hash(b"1"),
+        hash(b"2")
+    );
+
+    let report = engine.apply_patch(patch.as_bytes(), false).unwrap();
+    assert!(!report.applied);
+    assert_eq!(report.mismatches(), 1);
+    assert_eq!(
+        report.outcomes[1],
+        PatchOutcome {
+            key: b"b".to_vec(),
+            found: hash(b"moved on"),
+            matched: false,
+            change: PatchChange::Replaced,
+        }
+    );
+    assert_eq!(engine.get(b"a").unwrap(), Some(b"1".to_vec()));
+    assert_eq!(engine.get(b"c").unwrap(), None);
+
+    let report = engine.apply_patch_forced(patch.as_bytes(), false).unwrap();
+    assert!(report.applied);
+    assert_eq!(report.mismatches(), 1);
+    assert_eq!(engine.get(b"a").unwrap(), Some(b"11".to_vec()));
+    assert_eq!(engine.get(b"b").unwrap(), Some(b"22".to_vec()));
+    assert_eq!(engine.get(b"c").unwrap(), Some(b"3".to_vec()));
+
+    // A key named twice is ambiguous, forced or not.
+    let twice = format!("{}\nset YQ== MQ==\ndel YQ==\n", PATCH_HEADER);
+    let err = engine
+        .apply_patch_forced(twice.as_bytes(), false)
+        .unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    assert_eq!(engine.get(b"a").unwrap(), Some(b"11".to_vec()));
+}
+
+#[test]
+fn test_patch_cut_off_mid_write_is_not_applied() {
+    let file = NamedTempFile::new().unwrap();
+    let storage = common::InstrumentedStorage::default();
+    let engine = Engine::load_with_options(
+        file.path(),
+        Options {
+            storage: Arc::new(storage.clone()),
+            ..Options::default()
+        },
+    )
+    .unwrap();
+    for i in 0..20u32 {
+        engine
+            .set(format!("k{:02}", i).as_bytes(), b"before")
+            .unwrap();
+    }
+    let before = contents(&engine);
+
+    let (target, _g) = temp_engine();
+    for i in 10..40u32 {
+        target
+            .set(format!("k{:02}", i).as_bytes(), &[i as u8; 1024])
+            .unwrap();
+    }
+    let patch = text(&engine.diff_to_patch(&target).unwrap());
+
+    // The disk dies a third of the way through the batch.
+    storage.probe.kill_writes_to(file.path(), 10 * 1024);
+    assert!(engine.apply_patch(patch.as_slice(), false).is_err());
+    assert_eq!(contents(&engine), before);
+    drop(engine);
+
+    let engine = Engine::load(file.path()).unwrap();
+    assert_eq!(contents(&engine), before);
+    let report = engine.apply_patch(patch.as_slice(), false).unwrap();
+    assert!(report.applied);
+    assert_eq!(contents(&engine), contents(&target));
+}
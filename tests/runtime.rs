@@ -0,0 +1,187 @@
+mod common;
+
+use breakout1_kv_store::{Durability, Engine, KvRuntime, Options};
+use common::{ManualClock, wait_for};
+use std::fs;
+use std::sync::Arc;
+use std::time::Duration;
+use tempfile::tempdir;
+
+fn runtime_options(runtime: &Arc<KvRuntime>, reader_pool_size: usize) -> Options {
+    Options {
+        runtime: Some(Arc::clone(runtime)),
+        reader_pool_size,
+        durability: Durability::Interval {
+            period: Duration::from_secs(60),
+            jitter: Duration::ZERO,
+        },
+        ..Options::default()
+    }
+}
+
+fn open_fds() -> usize {
+    fs::read_dir("/proc/self/fd").unwrap().count()
+}
+
+fn threads() -> usize {
+    fs::read_dir("/proc/self/task").unwrap().count()
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_many_idle_engines_share_one_thread() {
+    const ENGINES: usize = 5_000;
+    let dir = tempdir().unwrap();
+    let runtime = KvRuntime::new(None).unwrap();
+    let fds_before = open_fds();
+    let threads_before = threads();
+
+    let engines: Vec<Engine> = (0..ENGINES)
+        .map(|i| {
+            Engine::load_with_options(
+                dir.path().join(format!("tenant-{}.db", i)),
+                runtime_options(&runtime, 0),
+            )
+            .unwrap()
+        })
+        .collect();
+
+    let stats = runtime.stats();
+    assert_eq!(stats.engines, ENGINES);
+    assert_eq!(stats.jobs, ENGINES);
+    assert_eq!(stats.threads, 1);
+    assert_eq!(stats.pooled_readers, 0);
+
+    // One writer handle each; the slack covers other tests in this binary.
+    let fds = open_fds() - fds_before;
+    assert!((ENGINES..ENGINES + 32).contains(&fds), "{} fds", fds);
+    assert!(threads() < threads_before + 8);
+
+    engines[7].set(b"k", b"v").unwrap();
+    assert_eq!(engines[7].get(b"k").unwrap(), Some(b"v".to_vec()));
+    assert_eq!(runtime.stats().pooled_readers, 0);
+
+    drop(engines);
+    let stats = runtime.stats();
+    assert_eq!(stats.engines, 0);
+    assert_eq!(stats.jobs, 0);
+}
+
+#[test]
+fn test_runtime_runs_interval_sync() {
+    let dir = tempdir().unwrap();
+    let runtime = KvRuntime::new(None).unwrap();
+    let options = Options {
+        durability: Durability::Interval {
+            period: Duration::from_millis(10),
+            jitter: Duration::ZERO,
+        },
+        ..runtime_options(&runtime, 4)
+    };
+    let engines: Vec<Engine> = (0..3)
+        .map(|i| {
+            Engine::load_with_options(dir.path().join(format!("{}.db", i)), options.clone())
+                .unwrap()
+        })
+        .collect();
+
+    for engine in &engines {
+        engine.set(b"k", b"v").unwrap();
+    }
+    assert!(wait_for(|| engines.iter().all(|e| e.stats().syncs >= 1)));
+}
+
+#[test]
+fn test_dropping_engine_cancels_its_job() {
+    let dir = tempdir().unwrap();
+    let runtime = KvRuntime::new(None).unwrap();
+    let path = dir.path().join("db");
+
+    let engine = Engine::load_with_options(&path, runtime_options(&runtime, 4)).unwrap();
+    engine.set(b"k", b"v").unwrap();
+    assert_eq!(engine.stats().syncs, 0);
+    drop(engine);
+    assert_eq!(runtime.stats().jobs, 0);
+
+    let engine = Engine::load(&path).unwrap();
+    assert_eq!(engine.get(b"k").unwrap(), Some(b"v".to_vec()));
+}
+
+#[test]
+fn test_pooled_readers_respect_runtime_budget() {
+    let dir = tempdir().unwrap();
+    let runtime = KvRuntime::new(Some(3)).unwrap();
+
+    let engines: Vec<Engine> = (0..2)
+        .map(|i| {
+            Engine::load_with_options(
+                dir.path().join(format!("{}.db", i)),
+                runtime_options(&runtime, 4),
+            )
+            .unwrap()
+        })
+        .collect();
+    assert_eq!(runtime.stats().pooled_readers, 3);
+
+    for engine in &engines {
+        for i in 0..20u32 {
+            engine.set(&i.to_le_bytes(), b"value").unwrap();
+        }
+        engine.compact().unwrap();
+        for i in 0..20u32 {
+            assert_eq!(
+                engine.get(&i.to_le_bytes()).unwrap(),
+                Some(b"value".to_vec())
+            );
+        }
+        assert!(runtime.stats().pooled_readers <= 3);
+    }
+
+    drop(engines);
+    assert_eq!(runtime.stats().pooled_readers, 0);
+}
+
+#[test]
+fn test_zero_reader_pool_without_runtime() {
+    let dir = tempdir().unwrap();
+    let options = Options {
+        reader_pool_size: 0,
+        ..Options::default()
+    };
+    let engine = Engine::load_with_options(dir.path().join("db"), options).unwrap();
+    engine.set(b"a", b"1").unwrap();
+    engine.compact().unwrap();
+    assert_eq!(engine.get(b"a").unwrap(), Some(b"1".to_vec()));
+    engine.reload().unwrap();
+    assert_eq!(engine.get(b"a").unwrap(), Some(b"1".to_vec()));
+}
+
+#[test]
+fn test_idle_engines_return_pooled_readers_to_the_runtime() {
+    let dir = tempdir().unwrap();
+    let runtime = KvRuntime::new(None).unwrap();
+    let clock = ManualClock::new();
+    let engines: Vec<Engine> = (0..3)
+        .map(|i| {
+            let options = Options {
+                idle_after: Some(Duration::from_secs(300)),
+                clock: clock.clone(),
+                ..runtime_options(&runtime, 2)
+            };
+            let engine =
+                Engine::load_with_options(dir.path().join(format!("{}.db", i)), options).unwrap();
+            engine.set(b"k", b"v").unwrap();
+            engine
+        })
+        .collect();
+    assert_eq!(runtime.stats().pooled_readers, 6);
+
+    engines[0].get(b"k").unwrap();
+    clock.advance(Duration::from_secs(300));
+    assert!(wait_for(|| runtime.stats().pooled_readers == 0));
+    for engine in &engines {
+        assert_eq!(engine.stats().idle_reclaims, 1);
+        assert_eq!(engine.get(b"k").unwrap(), Some(b"v".to_vec()));
+    }
+    assert_eq!(runtime.stats().pooled_readers, 3);
+}
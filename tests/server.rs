@@ -0,0 +1,301 @@
+//! The HTTP server: its app service in process, and the binary over real
+//! sockets.
+
+use actix_web::http::{StatusCode, header};
+use actix_web::test as actix_test;
+use actix_web::web;
+use breakout1_kv_store::Engine;
+use breakout1_kv_store::server::{self, Auth, MAX_AUTH_FAILURES};
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::Duration;
+use tempfile::{NamedTempFile, TempDir, tempdir};
+
+const TOKEN: &str = "s3cret-token";
+
+/// The engine and auth the app service runs on: a fresh store, requiring
+/// `TOKEN`.
+fn app_data(file: &NamedTempFile) -> (web::Data<Engine>, web::Data<Auth>) {
+    (
+        web::Data::new(Engine::load(file.path()).unwrap()),
+        web::Data::new(Auth::new(Some(TOKEN.as_bytes().to_vec()))),
+    )
+}
+
+/// `request` from `peer`, carrying `token` if given.
+fn from(
+    peer: &str,
+    token: Option<&str>,
+    request: actix_test::TestRequest,
+) -> actix_test::TestRequest {
+    let request = request.peer_addr(peer.parse().unwrap());
+    match token {
+        Some(token) => request.insert_header((header::AUTHORIZATION, format!("Bearer {token}"))),
+        None => request,
+    }
+}
+
+/// A server process on a free port, killed on drop.
+struct Server {
+    child: Child,
+    addr: SocketAddr,
+    _dir: TempDir,
+}
+
+impl Server {
+    fn start(token: Option<&str>) -> Server {
+        let dir = tempdir().unwrap();
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let mut command = Command::new(env!("CARGO_BIN_EXE_breakout1-kv-store"));
+        command
+            .env("KV_DATA", dir.path().join("data.db"))
+            .env("KV_ADDR", addr.to_string())
+            .env_remove("KV_AUTH_TOKEN")
+            .stdout(Stdio::null())
+            .stderr(Stdio::null());
+        if let Some(token) = token {
+            command.env("KV_AUTH_TOKEN", token);
+        }
+        let child = command.spawn().unwrap();
+        let server = Server {
+            child,
+            addr,
+            _dir: dir,
+        };
+        for _ in 0..500 {
+            if TcpStream::connect(addr).is_ok() {
+                return server;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("server never listened on {}", addr);
+    }
+
+    /// Sends one request on a connection of its own and returns the status
+    /// and the whole response.
+    fn request(&self, method: &str, path: &str, token: Option<&str>, body: &str) -> (u16, String) {
+        let mut stream = TcpStream::connect(self.addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        let mut request = format!(
+            "{method} {path} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\nContent-Type: application/json\r\nContent-Length: {}\r\n",
+            self.addr,
+            body.len()
+        );
+        if let Some(token) = token {
+            request.push_str(&format!("Authorization: Bearer {token}\r\n"));
+        }
+        request.push_str("\r\n");
+        request.push_str(body);
+        stream.write_all(request.as_bytes()).unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let status = response
+            .split(' ')
+            .nth(1)
+            .and_then(|status| status.parse().ok())
+            .unwrap_or_else(|| panic!("bad response {:?}", response));
+        (status, response)
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+fn set_body(key: &str, value: &str) -> String {
+    format!(r#"{{"key": "{key}", "value": "{value}"}}"#)
+}
+
+#[actix_web::test]
+async fn test_service_refuses_requests_without_the_token() {
+    let file = NamedTempFile::new().unwrap();
+    let (engine, auth) = app_data(&file);
+    let app = actix_test::init_service(server::app(engine, auth)).await;
+    let set = || {
+        actix_test::TestRequest::post()
+            .uri("/set")
+            .set_json(serde_json::json!({"key": "k", "value": "v"}))
+    };
+
+    let missing =
+        actix_test::call_service(&app, from("10.0.0.1:1", None, set()).to_request()).await;
+    assert_eq!(missing.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(
+        missing.headers().get(header::WWW_AUTHENTICATE).unwrap(),
+        "Bearer"
+    );
+    let wrong = actix_test::call_service(
+        &app,
+        from("10.0.0.1:1", Some("s3cret-tokem"), set()).to_request(),
+    )
+    .await;
+    assert_eq!(wrong.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(
+        wrong.headers().get(header::WWW_AUTHENTICATE).unwrap(),
+        "Bearer error=\"invalid_token\""
+    );
+
+    // The check runs before the body is parsed: a malformed body without
+    // the token is refused, not rejected as bad JSON.
+    let malformed = || {
+        actix_test::TestRequest::post()
+            .uri("/set")
+            .insert_header((header::CONTENT_TYPE, "application/json"))
+            .set_payload("{not json")
+    };
+    let refused =
+        actix_test::call_service(&app, from("10.0.0.2:1", None, malformed()).to_request()).await;
+    assert_eq!(refused.status(), StatusCode::UNAUTHORIZED);
+    let parsed = actix_test::call_service(
+        &app,
+        from("10.0.0.2:1", Some(TOKEN), malformed()).to_request(),
+    )
+    .await;
+    assert_eq!(parsed.status(), StatusCode::BAD_REQUEST);
+
+    let get = actix_test::TestRequest::get().uri("/get/k");
+    let response =
+        actix_test::call_service(&app, from("10.0.0.3:1", Some(TOKEN), get).to_request()).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[actix_web::test]
+async fn test_service_serves_requests_with_the_token() {
+    let file = NamedTempFile::new().unwrap();
+    let (engine, auth) = app_data(&file);
+    let app = actix_test::init_service(server::app(engine, auth)).await;
+    let call = |request| {
+        actix_test::call_service(&app, from("10.0.0.1:1", Some(TOKEN), request).to_request())
+    };
+
+    assert_eq!(
+        call(actix_test::TestRequest::get().uri("/")).await.status(),
+        200
+    );
+    let set = actix_test::TestRequest::post()
+        .uri("/set")
+        .set_json(serde_json::json!({"key": "k", "value": "v"}));
+    assert_eq!(call(set).await.status(), StatusCode::OK);
+    let got = call(actix_test::TestRequest::get().uri("/get/k")).await;
+    assert_eq!(got.status(), StatusCode::OK);
+    assert_eq!(actix_test::read_body(got).await, "v");
+    let del = actix_test::TestRequest::delete().uri("/del/k");
+    assert_eq!(call(del).await.status(), StatusCode::OK);
+    let gone = call(actix_test::TestRequest::get().uri("/get/k")).await;
+    assert_eq!(gone.status(), StatusCode::NOT_FOUND);
+}
+
+#[actix_web::test]
+async fn test_service_locks_out_after_repeated_failures() {
+    let file = NamedTempFile::new().unwrap();
+    let (engine, auth) = app_data(&file);
+    let app = actix_test::init_service(server::app(engine, auth)).await;
+    let home = |peer, token| {
+        actix_test::call_service(
+            &app,
+            from(peer, token, actix_test::TestRequest::get().uri("/")).to_request(),
+        )
+    };
+
+    for _ in 0..MAX_AUTH_FAILURES {
+        assert_eq!(home("10.0.0.1:1", Some("guess")).await.status(), 401);
+    }
+    // Even the right token is refused now, and only from that address.
+    let locked = home("10.0.0.1:2", Some(TOKEN)).await;
+    assert_eq!(locked.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert!(locked.headers().contains_key(header::RETRY_AFTER));
+    assert_eq!(home("10.0.0.2:1", Some(TOKEN)).await.status(), 200);
+}
+
+#[test]
+fn test_requests_without_the_token_are_refused() {
+    let server = Server::start(Some(TOKEN));
+
+    let (status, response) = server.request("GET", "/get/k", None, "");
+    assert_eq!(status, 401);
+    assert!(
+        response.contains("www-authenticate: Bearer\r\n"),
+        "{response}"
+    );
+
+    let (status, response) = server.request("POST", "/set", Some("wrong"), &set_body("k", "v"));
+    assert_eq!(status, 401);
+    assert!(response.contains("invalid_token"), "{response}");
+    // Same length, last byte off.
+    let (status, _) = server.request("DELETE", "/del/k", Some("s3cret-tokem"), "");
+    assert_eq!(status, 401);
+
+    // Nothing refused was carried out.
+    let (status, _) = server.request("GET", "/get/k", Some(TOKEN), "");
+    assert_eq!(status, 404);
+}
+
+#[test]
+fn test_requests_with_the_token_succeed() {
+    let server = Server::start(Some(TOKEN));
+
+    assert_eq!(server.request("GET", "/", Some(TOKEN), "").0, 200);
+    let (status, _) = server.request("POST", "/set", Some(TOKEN), &set_body("k", "v"));
+    assert_eq!(status, 200);
+    let (status, response) = server.request("GET", "/get/k", Some(TOKEN), "");
+    assert_eq!(status, 200);
+    assert!(response.ends_with("\r\n\r\nv"), "{response}");
+    assert_eq!(server.request("DELETE", "/del/k", Some(TOKEN), "").0, 200);
+    assert_eq!(server.request("GET", "/get/k", Some(TOKEN), "").0, 404);
+}
+
+#[test]
+fn test_repeated_failures_lock_the_client_out() {
+    let server = Server::start(Some(TOKEN));
+    // A success in between starts the count over.
+    for _ in 0..4 {
+        assert_eq!(server.request("GET", "/", Some("guess"), "").0, 401);
+    }
+    assert_eq!(server.request("GET", "/", Some(TOKEN), "").0, 200);
+    for _ in 0..5 {
+        assert_eq!(server.request("GET", "/", None, "").0, 401);
+    }
+
+    // Even the right token is refused now.
+    let (status, response) = server.request("GET", "/", Some(TOKEN), "");
+    assert_eq!(status, 429);
+    assert!(response.contains("retry-after: "), "{response}");
+}
+
+#[test]
+fn test_no_token_configured_needs_no_authentication() {
+    let server = Server::start(None);
+    let (status, _) = server.request("POST", "/set", None, &set_body("k", "v"));
+    assert_eq!(status, 200);
+    let (status, response) = server.request("GET", "/get/k", Some("anything"), "");
+    assert_eq!(status, 200);
+    assert!(response.ends_with("\r\n\r\nv"), "{response}");
+}
+
+#[test]
+fn test_empty_values_are_found_and_deleted_keys_are_not() {
+    let server = Server::start(None);
+    for key in ["empty", "gone"] {
+        let (status, _) = server.request("POST", "/set", None, &set_body(key, ""));
+        assert_eq!(status, 200);
+    }
+    let (status, _) = server.request("DELETE", "/del/gone", None, "");
+    assert_eq!(status, 200);
+
+    let (status, response) = server.request("GET", "/get/empty", None, "");
+    assert_eq!(status, 200);
+    assert!(response.ends_with("\r\n\r\n"), "{response}");
+    let (status, _) = server.request("GET", "/get/gone", None, "");
+    assert_eq!(status, 404);
+}
@@ -0,0 +1,111 @@
+use breakout1_kv_store::Engine;
+use breakout1_kv_store::workload::{self, KeyDistribution, OpMix, WorkloadSpec};
+use tempfile::tempdir;
+
+fn tiny_spec(distribution: KeyDistribution) -> WorkloadSpec {
+    WorkloadSpec {
+        threads: 3,
+        ops: 1_000,
+        mix: "60get/30set/10del".parse().unwrap(),
+        value_size: 64,
+        key_space: 100,
+        distribution,
+        seed: 7,
+    }
+}
+
+#[test]
+fn test_runs_a_tiny_workload_end_to_end() {
+    let dir = tempdir().unwrap();
+    let engine = Engine::load(dir.path().join("data.db")).unwrap();
+    engine.set_compact_threshold(16 * 1024).unwrap();
+
+    for distribution in [
+        KeyDistribution::Uniform,
+        KeyDistribution::Zipfian { exponent: 0.99 },
+    ] {
+        let report = workload::run(&engine, tiny_spec(distribution)).unwrap();
+        assert_eq!(report.ops, 1_000);
+        assert_eq!(
+            report.get.count + report.set.count + report.del.count,
+            1_000
+        );
+        // The mix is drawn per op, so only roughly 60/30/10.
+        assert!((450..750).contains(&report.get.count), "{:?}", report);
+        assert!((200..400).contains(&report.set.count), "{:?}", report);
+        assert!(report.get_hits > 0 && report.get_hits <= report.get.count);
+        assert!(report.get.p50_micros <= report.get.p99_micros);
+        assert!(report.get.p99_micros <= report.get.max_micros);
+        assert!(report.ops_per_sec > 0.0);
+        assert!(report.compactions > 0, "{:?}", report);
+        assert_eq!(report.file_size, engine.stats().file_size);
+    }
+
+    let json = workload::run(&engine, tiny_spec(KeyDistribution::Uniform))
+        .unwrap()
+        .to_json();
+    for field in [
+        "\"ops\": 1000",
+        "\"ops_per_sec\"",
+        "\"p99_micros\"",
+        "\"file_size\"",
+        "\"compactions\"",
+    ] {
+        assert!(json.contains(field), "{} missing from {}", field, json);
+    }
+}
+
+#[test]
+fn test_zipfian_keys_concentrate_on_a_few_hot_keys() {
+    let dir = tempdir().unwrap();
+    let only_sets = WorkloadSpec {
+        threads: 1,
+        mix: "100set".parse().unwrap(),
+        key_space: 10_000,
+        ..tiny_spec(KeyDistribution::Uniform)
+    };
+    let uniform = Engine::load(dir.path().join("uniform.db")).unwrap();
+    workload::run(&uniform, only_sets.clone()).unwrap();
+    let zipfian = Engine::load(dir.path().join("zipfian.db")).unwrap();
+    workload::run(
+        &zipfian,
+        WorkloadSpec {
+            distribution: KeyDistribution::Zipfian { exponent: 0.99 },
+            ..only_sets
+        },
+    )
+    .unwrap();
+
+    // 1000 uniform picks from 10000 keys rarely repeat; zipfian ones do.
+    assert!(uniform.stats().live_keys > 900);
+    assert!(zipfian.stats().live_keys < 600);
+}
+
+#[test]
+fn test_rejects_bad_specs() {
+    assert_eq!(
+        "70get/25set/5del".parse::<OpMix>().unwrap(),
+        OpMix {
+            get: 70,
+            set: 25,
+            del: 5
+        }
+    );
+    for bad in ["70get/20set", "70get/30put", "get/100set", ""] {
+        assert!(bad.parse::<OpMix>().is_err(), "{}", bad);
+    }
+    assert!("pareto".parse::<KeyDistribution>().is_err());
+
+    let dir = tempdir().unwrap();
+    let engine = Engine::load(dir.path().join("data.db")).unwrap();
+    for spec in [
+        WorkloadSpec {
+            threads: 0,
+            ..tiny_spec(KeyDistribution::Uniform)
+        },
+        tiny_spec(KeyDistribution::Zipfian { exponent: 1.0 }),
+    ] {
+        let err = workload::run(&engine, spec).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    }
+}